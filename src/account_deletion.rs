@@ -0,0 +1,501 @@
+//! Self-serve deletion of everything a pubkey authored.
+//!
+//! A user requests deletion over NIP-98 authenticated HTTP and polls the job
+//! until it completes. In every scope the job first takes the user out of
+//! their groups with relay-signed 9001 events, so 39001/39002 are regenerated
+//! rather than deleted, then deletes the events they authored in chunks.
+//! Group management events (creations, membership and metadata changes,
+//! invites, deletions) are kept: group state is rebuilt from them, and they're
+//! the group's moderation history. Groups where the user is the last admin
+//! keep them and are listed in the report instead of failing the job.
+//! Finished jobs are appended to an audit log, one JSON line each, and can be
+//! polled for [`FINISHED_JOB_RETENTION`] afterwards.
+
+use crate::group::{Group, KIND_GROUP_DELETE_9008};
+use crate::groups::{Groups, KIND_GROUP_REMOVE_USER_9001};
+use crate::utils::{apply_store_commands, scope_name};
+use crate::RelayDatabase;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Events deleted per database round trip
+pub const DELETION_CHUNK_SIZE: usize = 500;
+
+/// How long a finished job's status is kept, in seconds
+pub const FINISHED_JOB_RETENTION: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionState {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct GroupRef {
+    pub scope: String,
+    pub group_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeletionStatus {
+    pub id: String,
+    pub pubkey: String,
+    pub state: DeletionState,
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub events_deleted: usize,
+    /// Groups the user was removed from
+    pub groups_left: Vec<GroupRef>,
+    /// Groups where the user is the only admin and was kept
+    pub last_admin_groups: Vec<GroupRef>,
+}
+
+#[derive(Debug)]
+pub struct AccountDeletions {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    audit_log: Option<PathBuf>,
+    jobs: DashMap<String, DeletionStatus>,
+}
+
+impl AccountDeletions {
+    pub fn new(
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+        audit_log: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            groups,
+            database,
+            relay_keys,
+            audit_log,
+            jobs: DashMap::new(),
+        }
+    }
+
+    /// Starts deleting the pubkey's data, or returns its job if one is running
+    pub fn start(self: &Arc<Self>, pubkey: PublicKey) -> DeletionStatus {
+        if let Some(running) = self
+            .jobs
+            .iter()
+            .find(|job| job.pubkey == pubkey.to_hex() && job.state == DeletionState::Running)
+        {
+            return running.clone();
+        }
+        self.evict_finished();
+
+        let status = DeletionStatus {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            pubkey: pubkey.to_hex(),
+            state: DeletionState::Running,
+            error: None,
+            started_at: Timestamp::now().as_u64(),
+            finished_at: None,
+            events_deleted: 0,
+            groups_left: Vec::new(),
+            last_admin_groups: Vec::new(),
+        };
+        self.jobs.insert(status.id.clone(), status.clone());
+
+        let deletions = Arc::clone(self);
+        let id = status.id.clone();
+        tokio::spawn(async move {
+            let result = deletions.run(&id, &pubkey).await;
+            deletions.finish(&id, result);
+        });
+
+        status
+    }
+
+    pub fn status(&self, id: &str) -> Option<DeletionStatus> {
+        self.jobs.get(id).map(|job| job.clone())
+    }
+
+    /// Forgets jobs that finished more than [`FINISHED_JOB_RETENTION`] ago,
+    /// the audit log keeps their record
+    fn evict_finished(&self) {
+        let now = Timestamp::now().as_u64();
+        self.jobs.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished_at| now.saturating_sub(finished_at) < FINISHED_JOB_RETENTION)
+        });
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut DeletionStatus)) {
+        if let Some(mut job) = self.jobs.get_mut(id) {
+            f(&mut job);
+        }
+    }
+
+    async fn run(&self, id: &str, pubkey: &PublicKey) -> Result<()> {
+        let scopes = self
+            .database
+            .list_scopes()
+            .await
+            .map_err(|e| anyhow!("Failed to list scopes: {e}"))?;
+
+        for scope in &scopes {
+            self.leave_groups(id, pubkey, scope).await?;
+            self.delete_authored_events(id, pubkey, scope).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the user from every group of the scope, except where they're
+    /// the last admin
+    async fn leave_groups(&self, id: &str, pubkey: &PublicKey, scope: &Scope) -> Result<()> {
+        for group_id in self.groups.list_groups_in_scope(scope) {
+            let group_ref = GroupRef {
+                scope: scope_name(scope).to_string(),
+                group_id: group_id.clone(),
+            };
+            let last_admin = match self.groups.get_group(scope, &group_id) {
+                Some(group) if group.is_member(pubkey) => {
+                    group.is_admin(pubkey) && group.admin_pubkeys().len() == 1
+                }
+                _ => continue,
+            };
+            if last_admin {
+                warn!(
+                    "Keeping {} as the last admin of group {} while deleting their data",
+                    pubkey, group_id
+                );
+                self.update(id, |job| job.last_admin_groups.push(group_ref));
+                continue;
+            }
+
+            let removal = EventBuilder::new(KIND_GROUP_REMOVE_USER_9001, "")
+                .tags([
+                    Tag::custom(TagKind::h(), [group_id.clone()]),
+                    Tag::public_key(*pubkey),
+                ])
+                .sign_with_keys(&self.relay_keys)?;
+            let commands = self.groups.handle_remove_user(Box::new(removal), scope)?;
+            apply_store_commands(&self.database, &self.relay_keys, commands).await?;
+            self.update(id, |job| job.groups_left.push(group_ref));
+        }
+        Ok(())
+    }
+
+    /// Deletes the events the user authored in the scope, newest first,
+    /// keeping group management events
+    async fn delete_authored_events(
+        &self,
+        id: &str,
+        pubkey: &PublicKey,
+        scope: &Scope,
+    ) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut until = None;
+
+        loop {
+            let mut filter = Filter::new().author(*pubkey).limit(DELETION_CHUNK_SIZE);
+            if let Some(until) = until {
+                filter = filter.until(until);
+            }
+            let events = self.database.query(vec![filter], scope).await?;
+
+            let fresh: Vec<&Event> = events
+                .iter()
+                .filter(|event| seen.insert(event.id))
+                .collect();
+            if fresh.is_empty() {
+                return Ok(());
+            }
+            until = fresh.iter().map(|event| event.created_at).min();
+
+            let ids: Vec<EventId> = fresh
+                .into_iter()
+                .filter(|event| !is_group_history(event.kind))
+                .map(|event| event.id)
+                .collect();
+            if ids.is_empty() {
+                continue;
+            }
+            let deleted = ids.len();
+            self.database.delete(Filter::new().ids(ids), scope).await?;
+            self.update(id, |job| job.events_deleted += deleted);
+        }
+    }
+
+    fn finish(&self, id: &str, result: Result<()>) {
+        self.update(id, |job| {
+            job.finished_at = Some(Timestamp::now().as_u64());
+            match result {
+                Ok(()) => job.state = DeletionState::Completed,
+                Err(e) => {
+                    job.state = DeletionState::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        });
+
+        let Some(status) = self.status(id) else {
+            return;
+        };
+        info!(
+            "Account deletion {} for {} {:?}: {} events deleted, left {} groups, kept as last admin in {}",
+            status.id,
+            status.pubkey,
+            status.state,
+            status.events_deleted,
+            status.groups_left.len(),
+            status.last_admin_groups.len()
+        );
+
+        if let Some(path) = &self.audit_log {
+            let appended = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| {
+                    writeln!(
+                        file,
+                        "{}",
+                        serde_json::to_string(&status).unwrap_or_default()
+                    )
+                });
+            if let Err(e) = appended {
+                error!("Failed to write account deletion audit record: {}", e);
+            }
+        }
+    }
+}
+
+/// Events group state is rebuilt from, which outlive their author's account
+fn is_group_history(kind: Kind) -> bool {
+    Group::is_group_management_kind(kind) || kind == KIND_GROUP_DELETE_9008
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_MEMBERS_39002,
+    };
+    use crate::test_utils::{create_test_event, setup_test};
+    use std::time::Duration;
+
+    async fn apply(
+        database: &RelayDatabase,
+        relay_keys: &Keys,
+        commands: Result<Vec<crate::StoreCommand>, relay_builder::Error>,
+    ) {
+        apply_store_commands(database, relay_keys, commands.unwrap())
+            .await
+            .unwrap();
+    }
+
+    async fn create_group(
+        groups: &Groups,
+        database: &RelayDatabase,
+        relay_keys: &Keys,
+        scope: &Scope,
+        creator: &Keys,
+        group_id: &str,
+    ) {
+        let create = create_test_event(
+            creator,
+            KIND_GROUP_CREATE_9007.as_u16(),
+            vec![Tag::custom(TagKind::h(), [group_id])],
+        )
+        .await;
+        apply(
+            database,
+            relay_keys,
+            groups.handle_group_create(Box::new(create), scope).await,
+        )
+        .await;
+    }
+
+    async fn join_and_post(
+        groups: &Groups,
+        database: &RelayDatabase,
+        relay_keys: &Keys,
+        scope: &Scope,
+        admin: &Keys,
+        user: &Keys,
+        group_id: &str,
+    ) {
+        let add = create_test_event(
+            admin,
+            KIND_GROUP_ADD_USER_9000.as_u16(),
+            vec![
+                Tag::custom(TagKind::h(), [group_id]),
+                Tag::public_key(user.public_key()),
+            ],
+        )
+        .await;
+        apply(
+            database,
+            relay_keys,
+            groups.handle_put_user(Box::new(add), scope),
+        )
+        .await;
+
+        for author in [user, admin] {
+            let message =
+                create_test_event(author, 9, vec![Tag::custom(TagKind::h(), [group_id])]).await;
+            apply(
+                database,
+                relay_keys,
+                groups.handle_group_content(Box::new(message), scope),
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deletes_user_data_across_groups_and_scopes() {
+        let (tmp_dir, database, relay_keys) = setup_test().await;
+        let admin = Keys::generate();
+        let user = Keys::generate();
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let default_scope = Scope::Default;
+        let other_scope = Scope::named("other").unwrap();
+
+        for (scope, group_id) in [(&default_scope, "first"), (&other_scope, "second")] {
+            create_group(&groups, &database, &relay_keys, scope, &admin, group_id).await;
+            join_and_post(
+                &groups,
+                &database,
+                &relay_keys,
+                scope,
+                &admin,
+                &user,
+                group_id,
+            )
+            .await;
+        }
+        // The user's own group, where nobody else can take over
+        create_group(&groups, &database, &relay_keys, &other_scope, &user, "own").await;
+        let profile = create_test_event(&user, 0, vec![]).await;
+        database.save_event(&profile, &default_scope).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let audit_log = tmp_dir.path().join("account_deletions.jsonl");
+        let deletions = Arc::new(AccountDeletions::new(
+            groups.clone(),
+            database.clone(),
+            relay_keys.clone(),
+            Some(audit_log.clone()),
+        ));
+        let started = deletions.start(user.public_key());
+
+        let mut status = started.clone();
+        for _ in 0..100 {
+            status = deletions.status(&started.id).unwrap();
+            if status.state != DeletionState::Running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status.state, DeletionState::Completed);
+        // Profile and two messages; the creation of their own group stays
+        assert_eq!(status.events_deleted, 3);
+        assert_eq!(status.groups_left.len(), 2);
+        assert_eq!(
+            status.last_admin_groups,
+            [GroupRef {
+                scope: "other".to_string(),
+                group_id: "own".to_string(),
+            }]
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        for (scope, group_id) in [(&default_scope, "first"), (&other_scope, "second")] {
+            let authored = database
+                .query(vec![Filter::new().author(user.public_key())], scope)
+                .await
+                .unwrap();
+            assert!(authored.is_empty());
+
+            // Other members' messages stay
+            let messages = database
+                .query(vec![Filter::new().kind(Kind::Custom(9))], scope)
+                .await
+                .unwrap();
+            assert_eq!(messages.len(), 1);
+
+            // The members list is regenerated, not deleted
+            assert!(!groups
+                .get_group(scope, group_id)
+                .unwrap()
+                .is_member(&user.public_key()));
+            let members = database
+                .query(
+                    vec![Filter::new()
+                        .kind(KIND_GROUP_MEMBERS_39002)
+                        .identifier(group_id)],
+                    scope,
+                )
+                .await
+                .unwrap();
+            let members = members.first().unwrap();
+            assert!(members.tags.public_keys().all(|p| *p != user.public_key()));
+        }
+
+        // Group history outlives the account
+        let creation = database
+            .query(
+                vec![Filter::new()
+                    .author(user.public_key())
+                    .kind(KIND_GROUP_CREATE_9007)],
+                &other_scope,
+            )
+            .await
+            .unwrap();
+        assert_eq!(creation.len(), 1);
+
+        let audit = std::fs::read_to_string(audit_log).unwrap();
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit.contains(&started.id));
+    }
+
+    #[tokio::test]
+    async fn test_finished_jobs_are_evicted() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let deletions = Arc::new(AccountDeletions::new(groups, database, relay_keys, None));
+
+        let old = deletions.start(Keys::generate().public_key());
+        let recent = deletions.start(Keys::generate().public_key());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        deletions.update(&old.id, |job| {
+            job.finished_at = Some(Timestamp::now().as_u64() - FINISHED_JOB_RETENTION - 1);
+        });
+
+        deletions.start(Keys::generate().public_key());
+        assert!(deletions.status(&old.id).is_none());
+        assert!(deletions.status(&recent.id).is_some());
+    }
+}
@@ -0,0 +1,331 @@
+//! Adaptive admission control for inbound events.
+//!
+//! [`AdmissionController`] keeps a global budget of accepted events per
+//! second and adjusts it AIMD style: while events are processed quickly the
+//! budget grows by `increase_step` every `adjust_interval`, and as soon as the
+//! smoothed processing latency exceeds `target_latency` or more than
+//! `max_queue_depth` events are in progress, it is multiplied by
+//! `decrease_factor`. The budget always stays between `floor` and `ceiling`,
+//! so the same settings fit a relay on NVMe and one on a small VPS.
+//!
+//! relay_builder writes events after the processor returns, out of reach of
+//! middlewares, so the signal isn't the LMDB write itself: the processing
+//! latency is measured around the rest of the middleware chain and the
+//! processor for each admitted EVENT, and the queue depth is the number of
+//! those still in progress.
+//! The middleware sits at the front of the chain: events over the budget
+//! are answered with [`OVERLOADED_MESSAGE`] before their signature is
+//! verified. Events signed by the relay itself are never shed.
+
+use crate::config::AdmissionSettings;
+use crate::load_shedding::OVERLOADED_MESSAGE;
+use crate::metrics;
+use crate::rejections::{ReasonCode, Rejections};
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Weight of the newest sample in the processing latency moving average.
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Debug)]
+struct Bucket {
+    budget: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    adjusted_at: Instant,
+}
+
+/// Global events/sec budget, driven by event processing latency and queue depth.
+#[derive(Debug)]
+pub struct AdmissionController {
+    settings: AdmissionSettings,
+    bucket: Mutex<Bucket>,
+    latency_ewma_us: AtomicU64,
+    queue_depth: AtomicUsize,
+}
+
+impl AdmissionController {
+    pub fn new(settings: AdmissionSettings) -> Self {
+        let now = Instant::now();
+        let budget = settings.ceiling;
+        metrics::admission_budget().set(budget);
+        Self {
+            settings,
+            bucket: Mutex::new(Bucket {
+                budget,
+                tokens: budget,
+                refilled_at: now,
+                adjusted_at: now,
+            }),
+            latency_ewma_us: AtomicU64::new(0),
+            queue_depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of events per second currently admitted.
+    pub fn budget(&self) -> f64 {
+        self.bucket.lock().budget
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Smoothed processing latency of recent events.
+    pub fn processing_latency(&self) -> Duration {
+        Duration::from_micros(self.latency_ewma_us.load(Ordering::Relaxed))
+    }
+
+    /// Takes one event from the budget, returning false if it should be shed.
+    pub fn try_admit(&self) -> bool {
+        self.try_admit_at(Instant::now())
+    }
+
+    fn try_admit_at(&self, now: Instant) -> bool {
+        if !self.settings.enabled {
+            return true;
+        }
+
+        let mut bucket = self.bucket.lock();
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * bucket.budget).min(bucket.budget);
+        bucket.refilled_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Records the processing latency of one event, and adjusts the budget if
+    /// `adjust_interval` has passed since the last adjustment.
+    pub fn record_processing(&self, latency: Duration) {
+        let sample = latency.as_micros().min(u64::MAX as u128) as f64;
+        let previous = self.latency_ewma_us.load(Ordering::Relaxed) as f64;
+        let updated = if previous == 0.0 {
+            sample
+        } else {
+            previous + LATENCY_EWMA_ALPHA * (sample - previous)
+        };
+        self.latency_ewma_us
+            .store(updated as u64, Ordering::Relaxed);
+
+        let due = {
+            let bucket = self.bucket.lock();
+            bucket.adjusted_at.elapsed() >= self.settings.adjust_interval
+        };
+        if due {
+            self.adjust(self.processing_latency(), self.queue_depth());
+        }
+    }
+
+    /// Applies one AIMD step for the given signal and returns the new budget.
+    pub fn adjust(&self, latency: Duration, queue_depth: usize) -> f64 {
+        let settings = &self.settings;
+        let congested = latency > settings.target_latency || queue_depth > settings.max_queue_depth;
+
+        let mut bucket = self.bucket.lock();
+        let previous = bucket.budget;
+        bucket.budget = if congested {
+            (previous * settings.decrease_factor).max(settings.floor)
+        } else {
+            (previous + settings.increase_step).min(settings.ceiling)
+        };
+        bucket.tokens = bucket.tokens.min(bucket.budget);
+        bucket.adjusted_at = Instant::now();
+
+        if congested && bucket.budget < previous {
+            info!(
+                "Admission budget lowered from {:.0} to {:.0} events/s (processing latency {:?}, queue depth {})",
+                previous, bucket.budget, latency, queue_depth
+            );
+        }
+        metrics::admission_budget().set(bucket.budget);
+        bucket.budget
+    }
+
+    fn enter(&self) -> ProcessingGuard<'_> {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+        ProcessingGuard {
+            controller: self,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Tracks one admitted event until the rest of the chain is done with it.
+struct ProcessingGuard<'a> {
+    controller: &'a AdmissionController,
+    started: Instant,
+}
+
+impl Drop for ProcessingGuard<'_> {
+    fn drop(&mut self) {
+        self.controller.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.controller.record_processing(self.started.elapsed());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdmissionMiddleware {
+    controller: Arc<AdmissionController>,
+    relay_pubkey: PublicKey,
+    rejections: Option<Arc<Rejections>>,
+}
+
+impl AdmissionMiddleware {
+    pub fn new(controller: Arc<AdmissionController>, relay_pubkey: PublicKey) -> Self {
+        Self {
+            controller,
+            relay_pubkey,
+            rejections: None,
+        }
+    }
+
+    /// Records the events shed
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+}
+
+impl NostrMiddleware<()> for AdmissionMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(ClientMessage::Event(event)) = &ctx.message else {
+            return ctx.next().await;
+        };
+        if event.pubkey == self.relay_pubkey {
+            return ctx.next().await;
+        }
+
+        if !self.controller.try_admit() {
+            debug!(
+                "[{}] Shedding event {} over the admission budget",
+                ctx.connection_id, event.id
+            );
+            metrics::admission_shed().increment(1);
+            if let Some(rejections) = &self.rejections {
+                rejections.record_event(
+                    event,
+                    ReasonCode::Custom("overloaded"),
+                    OVERLOADED_MESSAGE,
+                );
+            }
+            ctx.send_message(RelayMessage::ok(event.id, false, OVERLOADED_MESSAGE))?;
+            return Ok(());
+        }
+
+        let _guard = self.controller.enter();
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_settings() -> AdmissionSettings {
+        AdmissionSettings {
+            enabled: true,
+            floor: 10.0,
+            ceiling: 1000.0,
+            target_latency: Duration::from_millis(5),
+            max_queue_depth: 100,
+            increase_step: 100.0,
+            decrease_factor: 0.5,
+            adjust_interval: Duration::ZERO,
+        }
+    }
+
+    /// A processor that takes longer while it is slowed down
+    struct SlowProcessor {
+        slowed: bool,
+    }
+
+    impl SlowProcessor {
+        async fn process(&self, controller: &AdmissionController) {
+            let _guard = controller.enter();
+            if self.slowed {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_budget_converges_down_and_recovers() {
+        let controller = AdmissionController::new(test_settings());
+        let mut processor = SlowProcessor { slowed: true };
+        assert_eq!(controller.budget(), 1000.0);
+
+        let mut previous = controller.budget();
+        for _ in 0..10 {
+            processor.process(&controller).await;
+            let budget = controller.budget();
+            assert!(budget <= previous);
+            previous = budget;
+        }
+        assert_eq!(controller.budget(), 10.0);
+        assert!(controller.processing_latency() > Duration::from_millis(5));
+
+        // Once events are fast again the moving average decays and the
+        // budget climbs back additively to the ceiling
+        processor.slowed = false;
+        for _ in 0..40 {
+            processor.process(&controller).await;
+        }
+        assert_eq!(controller.budget(), 1000.0);
+        assert_eq!(controller.queue_depth(), 0);
+    }
+
+    #[test]
+    fn test_queue_depth_lowers_budget() {
+        let controller = AdmissionController::new(test_settings());
+        assert_eq!(controller.adjust(Duration::ZERO, 101), 500.0);
+        assert_eq!(controller.adjust(Duration::ZERO, 100), 600.0);
+    }
+
+    #[test]
+    fn test_events_over_budget_are_shed() {
+        let controller = AdmissionController::new(AdmissionSettings {
+            floor: 5.0,
+            ..test_settings()
+        });
+        for _ in 0..10 {
+            controller.adjust(Duration::from_secs(1), 0);
+        }
+        assert_eq!(controller.budget(), 5.0);
+
+        let now = Instant::now();
+        let admitted = (0..20).filter(|_| controller.try_admit_at(now)).count();
+        assert_eq!(admitted, 5);
+
+        // Tokens refill at the budget rate
+        let later = now + Duration::from_millis(400);
+        assert!(controller.try_admit_at(later));
+        assert!(controller.try_admit_at(later));
+        assert!(!controller.try_admit_at(later));
+    }
+
+    #[test]
+    fn test_disabled_admits_everything() {
+        let controller = AdmissionController::new(AdmissionSettings {
+            enabled: false,
+            ..test_settings()
+        });
+        controller.adjust(Duration::from_secs(1), 1000);
+        let now = Instant::now();
+        assert!((0..10_000).all(|_| controller.try_admit_at(now)));
+    }
+}
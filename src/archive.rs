@@ -0,0 +1,520 @@
+//! Near-real-time copy of accepted events to archive relays.
+//!
+//! Every event the groups processor stores, including relay-signed 39xxx
+//! state events, is republished to each configured archive relay. NIP-70
+//! protected events are left out, only their author may publish them. Each relay
+//! gets its own connection and its own bounded queue, so a slow or unreachable
+//! archive only delays itself. Undelivered events stay queued and are retried;
+//! when a queue is full its oldest event is dropped (and counted). Queues are
+//! written to disk on shutdown and picked up again on the next start.
+
+use crate::config::ArchiveSettings;
+use crate::groups::Groups;
+use crate::metrics;
+use crate::utils::scope_name;
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::StoreCommand;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+#[derive(Debug)]
+struct RelayQueue {
+    url: String,
+    events: Mutex<VecDeque<Event>>,
+    notify: Notify,
+}
+
+impl RelayQueue {
+    fn front(&self) -> Option<Event> {
+        self.events.lock().front().cloned()
+    }
+
+    /// Removes the delivered event, unless it was already dropped to make room
+    fn delivered(&self, event_id: EventId) {
+        let mut events = self.events.lock();
+        if events.front().is_some_and(|event| event.id == event_id) {
+            events.pop_front();
+        }
+        metrics::archive_queue_depth(&self.url).set(events.len() as f64);
+    }
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    keys: Keys,
+    groups: Arc<Groups>,
+    exclude_private_groups: bool,
+    exclude_scopes: HashSet<String>,
+    queue_capacity: usize,
+    queues: Vec<Arc<RelayQueue>>,
+}
+
+impl Archive {
+    /// Creates an archive with one queue per relay, without connecting.
+    pub fn new(settings: &ArchiveSettings, keys: Keys, groups: Arc<Groups>) -> Self {
+        let queues = settings
+            .relays
+            .iter()
+            .map(|url| {
+                Arc::new(RelayQueue {
+                    url: url.clone(),
+                    events: Mutex::new(VecDeque::new()),
+                    notify: Notify::new(),
+                })
+            })
+            .collect();
+
+        Self {
+            keys,
+            groups,
+            exclude_private_groups: settings.exclude_private_groups,
+            exclude_scopes: settings.exclude_scopes.iter().cloned().collect(),
+            queue_capacity: settings.queue_capacity.max(1),
+            queues,
+        }
+    }
+
+    /// Restores the queues saved on the last shutdown and starts one sender
+    /// per archive relay.
+    pub async fn start(
+        settings: &ArchiveSettings,
+        keys: Keys,
+        groups: Arc<Groups>,
+        queue_path: PathBuf,
+        cancellation_token: CancellationToken,
+    ) -> Result<Arc<Self>> {
+        let archive = Arc::new(Self::new(settings, keys.clone(), groups));
+        match archive.restore_queues(&queue_path) {
+            Ok(0) => {}
+            Ok(restored) => info!("Restored {} queued archive events", restored),
+            Err(e) => warn!("Failed to restore archive queues: {}", e),
+        }
+
+        let mut senders = Vec::new();
+        for queue in &archive.queues {
+            let client = ClientBuilder::default().signer(keys.clone()).build();
+            client.add_relay(queue.url.as_str()).await?;
+            client.connect().await;
+
+            senders.push(tokio::spawn(run_sender(
+                client,
+                queue.clone(),
+                settings.retry_interval,
+                cancellation_token.clone(),
+            )));
+        }
+        info!("Archiving accepted events to {} relays", senders.len());
+
+        let saver = archive.clone();
+        tokio::spawn(async move {
+            cancellation_token.cancelled().await;
+            for sender in senders {
+                let _ = sender.await;
+            }
+            if let Err(e) = saver.save_queues(&queue_path) {
+                warn!("Failed to save archive queues: {}", e);
+            }
+        });
+
+        Ok(archive)
+    }
+
+    /// Queues the events produced by the given store commands for every
+    /// archive relay.
+    pub fn archive(&self, commands: &[StoreCommand]) {
+        for command in commands {
+            let (event, scope) = match command {
+                StoreCommand::SaveSignedEvent(event, scope, _) => ((**event).clone(), scope),
+                StoreCommand::SaveUnsignedEvent(unsigned, scope, _) => {
+                    match unsigned.clone().sign_with_keys(&self.keys) {
+                        Ok(event) => (event, scope),
+                        Err(e) => {
+                            warn!("Failed to sign state event for archiving: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            };
+
+            if self.is_excluded(&event, scope) {
+                debug!("Not archiving excluded event {}", event.id);
+                continue;
+            }
+
+            for queue in &self.queues {
+                self.enqueue(queue, event.clone());
+            }
+        }
+    }
+
+    fn is_excluded(&self, event: &Event, scope: &Scope) -> bool {
+        // Ephemeral events are never stored, so there is nothing to archive
+        if event.kind.is_ephemeral()
+            || event.is_protected()
+            || self.exclude_scopes.contains(scope_name(scope))
+        {
+            return true;
+        }
+
+        self.exclude_private_groups
+            && self
+                .groups
+                .find_group_from_event(event, scope)
+                .is_some_and(|group| group.metadata.private)
+    }
+
+    fn enqueue(&self, queue: &RelayQueue, event: Event) {
+        {
+            let mut events = queue.events.lock();
+            if events.len() >= self.queue_capacity {
+                events.pop_front();
+                warn!(
+                    "Archive queue for {} is full, dropping oldest event",
+                    queue.url
+                );
+                metrics::archive_dropped(&queue.url).increment(1);
+            }
+            events.push_back(event);
+            metrics::archive_queue_depth(&queue.url).set(events.len() as f64);
+        }
+        queue.notify.notify_one();
+    }
+
+    /// Number of events waiting to be delivered to each archive relay
+    pub fn pending(&self) -> HashMap<String, usize> {
+        self.queues
+            .iter()
+            .map(|queue| (queue.url.clone(), queue.events.lock().len()))
+            .collect()
+    }
+
+    fn save_queues(&self, path: &Path) -> Result<()> {
+        let mut pending: HashMap<String, Vec<Event>> = HashMap::new();
+        for queue in &self.queues {
+            let events = queue.events.lock();
+            if !events.is_empty() {
+                pending.insert(queue.url.clone(), events.iter().cloned().collect());
+            }
+        }
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        std::fs::write(path, serde_json::to_vec(&pending)?)?;
+        info!(
+            "Saved {} undelivered archive events",
+            pending.values().map(Vec::len).sum::<usize>()
+        );
+        Ok(())
+    }
+
+    /// Loads queues saved by a previous run, returning how many events were
+    /// restored. Queues of relays that are no longer configured are discarded.
+    fn restore_queues(&self, path: &Path) -> Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let mut saved: HashMap<String, Vec<Event>> = serde_json::from_slice(&std::fs::read(path)?)?;
+        std::fs::remove_file(path)?;
+
+        let mut restored = 0;
+        for queue in &self.queues {
+            for event in saved.remove(&queue.url).unwrap_or_default() {
+                self.enqueue(queue, event);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// Delivers one relay's queue in order, retrying the head until it's accepted.
+async fn run_sender(
+    client: Client,
+    queue: Arc<RelayQueue>,
+    retry_interval: Duration,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        let Some(event) = queue.front() else {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = queue.notify.notified() => continue,
+            }
+        };
+
+        let delivered = match client.send_event_to([queue.url.as_str()], &event).await {
+            Ok(output) if output.failed.is_empty() => true,
+            Ok(output) => {
+                debug!(
+                    "Archive relay {} rejected event {}: {:?}",
+                    queue.url, event.id, output.failed
+                );
+                false
+            }
+            Err(e) => {
+                debug!(
+                    "Archiving event {} to {} failed: {}",
+                    event.id, queue.url, e
+                );
+                false
+            }
+        };
+
+        if delivered {
+            queue.delivered(event.id);
+            metrics::archived_events(&queue.url).increment(1);
+            metrics::archive_lag_seconds(&queue.url).set(
+                Timestamp::now()
+                    .as_u64()
+                    .saturating_sub(event.created_at.as_u64()) as f64,
+            );
+            continue;
+        }
+
+        tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            _ = tokio::time::sleep(retry_interval) => {}
+        }
+    }
+
+    client.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_event, create_test_keys, setup_test};
+    use futures_util::{SinkExt, StreamExt};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// Minimal archive relay: rejects the first `outage` events it receives,
+    /// then accepts everything and reports the accepted ids.
+    async fn mock_archive_relay(outage: usize) -> (String, mpsc::UnboundedReceiver<EventId>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (accepted_tx, accepted_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut rejected = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(text))) = ws.next().await {
+                    let Ok(ClientMessage::Event(event)) = ClientMessage::from_json(text.as_str())
+                    else {
+                        continue;
+                    };
+
+                    let reply = if rejected < outage {
+                        rejected += 1;
+                        RelayMessage::ok(event.id, false, "error: archive unavailable")
+                    } else {
+                        let _ = accepted_tx.send(event.id);
+                        RelayMessage::ok(event.id, true, "")
+                    };
+                    ws.send(Message::Text(reply.as_json().into()))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        (url, accepted_rx)
+    }
+
+    fn settings(relays: Vec<String>) -> ArchiveSettings {
+        ArchiveSettings {
+            relays,
+            exclude_private_groups: false,
+            exclude_scopes: vec![],
+            queue_capacity: 10,
+            retry_interval: Duration::from_millis(50),
+        }
+    }
+
+    async fn test_groups(relay_keys: &Keys) -> (TempDir, Arc<Groups>) {
+        let (tmp_dir, database, _) = setup_test().await;
+        let groups = Groups::load_groups(
+            database,
+            relay_keys.public_key(),
+            "wss://test.relay.com".to_string(),
+        )
+        .await
+        .unwrap();
+        (tmp_dir, Arc::new(groups))
+    }
+
+    async fn next_accepted(accepted: &mut mpsc::UnboundedReceiver<EventId>) -> EventId {
+        tokio::time::timeout(Duration::from_secs(10), accepted.recv())
+            .await
+            .expect("archive relay received nothing")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_and_state_events() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (tmp_dir, groups) = test_groups(&relay_keys).await;
+        let (url, mut accepted) = mock_archive_relay(0).await;
+        let token = CancellationToken::new();
+
+        let archive = Archive::start(
+            &settings(vec![url]),
+            relay_keys.clone(),
+            groups,
+            tmp_dir.path().join("archive_queue.json"),
+            token.clone(),
+        )
+        .await
+        .unwrap();
+
+        let event = create_test_event(&member_keys, 11, vec![]).await;
+        let unsigned = EventBuilder::new(Kind::Custom(39000), "").build(relay_keys.public_key());
+        archive.archive(&[
+            StoreCommand::SaveSignedEvent(Box::new(event.clone()), Scope::Default, None),
+            StoreCommand::SaveUnsignedEvent(unsigned, Scope::Default, None),
+        ]);
+
+        assert_eq!(next_accepted(&mut accepted).await, event.id);
+        assert_ne!(next_accepted(&mut accepted).await, event.id);
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_retries_after_outage() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (tmp_dir, groups) = test_groups(&relay_keys).await;
+        let (url, mut accepted) = mock_archive_relay(3).await;
+        let token = CancellationToken::new();
+
+        let archive = Archive::start(
+            &settings(vec![url.clone()]),
+            relay_keys,
+            groups,
+            tmp_dir.path().join("archive_queue.json"),
+            token.clone(),
+        )
+        .await
+        .unwrap();
+
+        let first = create_test_event(&member_keys, 11, vec![]).await;
+        let second = create_test_event(&member_keys, 12, vec![]).await;
+        archive.archive(&[
+            StoreCommand::SaveSignedEvent(Box::new(first.clone()), Scope::Default, None),
+            StoreCommand::SaveSignedEvent(Box::new(second.clone()), Scope::Default, None),
+        ]);
+
+        // Delivered in order once the relay recovers
+        assert_eq!(next_accepted(&mut accepted).await, first.id);
+        assert_eq!(next_accepted(&mut accepted).await, second.id);
+        assert_eq!(archive.pending()[&url], 0);
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_private_groups_can_be_excluded() {
+        let (relay_keys, admin_keys, member_keys) = create_test_keys().await;
+        let (_tmp_dir, groups) = test_groups(&relay_keys).await;
+
+        for (group_id, private) in [("private_group", true), ("public_group", false)] {
+            let create = create_test_event(
+                &admin_keys,
+                9007,
+                vec![Tag::custom(TagKind::h(), [group_id])],
+            )
+            .await;
+            groups
+                .handle_group_create(Box::new(create), &Scope::Default)
+                .await
+                .unwrap();
+            groups
+                .get_group_mut(&Scope::Default, group_id)
+                .unwrap()
+                .metadata
+                .private = private;
+        }
+
+        let mut settings = settings(vec!["wss://archive.example.com".to_string()]);
+        settings.exclude_private_groups = true;
+        settings.exclude_scopes = vec!["internal".to_string()];
+        let archive = Archive::new(&settings, relay_keys, groups);
+
+        let private_event = create_test_event(
+            &member_keys,
+            11,
+            vec![Tag::custom(TagKind::h(), ["private_group"])],
+        )
+        .await;
+        let public_event = create_test_event(
+            &member_keys,
+            11,
+            vec![Tag::custom(TagKind::h(), ["public_group"])],
+        )
+        .await;
+        let internal_event = create_test_event(&member_keys, 1, vec![]).await;
+        let protected_event = create_test_event(
+            &member_keys,
+            11,
+            vec![
+                Tag::custom(TagKind::h(), ["public_group"]),
+                Tag::protected(),
+            ],
+        )
+        .await;
+
+        archive.archive(&[
+            StoreCommand::SaveSignedEvent(Box::new(protected_event), Scope::Default, None),
+            StoreCommand::SaveSignedEvent(Box::new(private_event), Scope::Default, None),
+            StoreCommand::SaveSignedEvent(Box::new(public_event.clone()), Scope::Default, None),
+            StoreCommand::SaveSignedEvent(
+                Box::new(internal_event),
+                Scope::named("internal").unwrap(),
+                None,
+            ),
+        ]);
+
+        let queue = archive.queues[0].events.lock();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].id, public_event.id);
+    }
+
+    #[tokio::test]
+    async fn test_queues_survive_restart() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (tmp_dir, groups) = test_groups(&relay_keys).await;
+        let path = tmp_dir.path().join("archive_queue.json");
+        let url = "wss://archive.example.com".to_string();
+
+        let archive = Archive::new(
+            &settings(vec![url.clone()]),
+            relay_keys.clone(),
+            groups.clone(),
+        );
+        let event = create_test_event(&member_keys, 11, vec![]).await;
+        archive.archive(&[StoreCommand::SaveSignedEvent(
+            Box::new(event.clone()),
+            Scope::Default,
+            None,
+        )]);
+        archive.save_queues(&path).unwrap();
+
+        let restarted = Archive::new(&settings(vec![url.clone()]), relay_keys, groups);
+        assert_eq!(restarted.restore_queues(&path).unwrap(), 1);
+        assert_eq!(restarted.queues[0].front().unwrap().id, event.id);
+        assert!(!path.exists());
+    }
+}
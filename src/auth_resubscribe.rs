@@ -0,0 +1,143 @@
+//! Tells clients to re-send REQs that were made before NIP-42 AUTH.
+//!
+//! A subscription to a private group made before authenticating only gets
+//! what an anonymous reader may see. Re-running its historical query after
+//! AUTH has to happen inside relay_builder's subscription service, so this
+//! middleware does the next best thing: it remembers the subscription ids
+//! opened while unauthenticated and, with the first message it handles on the
+//! authenticated connection, sends a single NOTICE naming the subscriptions
+//! to re-send. Subscriptions closed in the meantime are forgotten.
+
+use dashmap::DashMap;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Prefix of the NOTICE sent after AUTH
+pub const RESUBSCRIBE_NOTICE_PREFIX: &str =
+    "auth-changed: re-send REQ to receive events now visible to you:";
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthResubscribeMiddleware {
+    enabled: bool,
+    /// Subscription ids opened before AUTH, per connection
+    pending: Arc<DashMap<String, BTreeSet<String>>>,
+}
+
+impl AuthResubscribeMiddleware {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            pending: Arc::default(),
+        }
+    }
+
+    fn record(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        self.pending
+            .entry(connection_id.to_string())
+            .or_default()
+            .insert(subscription_id.to_string());
+    }
+
+    fn forget(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        if let Some(mut subscriptions) = self.pending.get_mut(connection_id) {
+            subscriptions.remove(subscription_id.as_str());
+        }
+    }
+
+    /// Takes the connection's pre-auth subscriptions, returning the NOTICE to send
+    fn take_notice(&self, connection_id: &str) -> Option<String> {
+        let (_, subscriptions) = self.pending.remove(connection_id)?;
+        if subscriptions.is_empty() {
+            return None;
+        }
+
+        let ids: Vec<&str> = subscriptions.iter().map(String::as_str).collect();
+        Some(format!("{} {}", RESUBSCRIBE_NOTICE_PREFIX, ids.join(", ")))
+    }
+}
+
+impl NostrMiddleware<()> for AuthResubscribeMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if !self.enabled {
+            return ctx.next().await;
+        }
+
+        let connection_id = ctx.connection_id.to_string();
+        let authed = ctx.state.read().await.authed_pubkey.is_some();
+
+        match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id, ..
+            })
+            | Some(ClientMessage::ReqMultiFilter {
+                subscription_id, ..
+            }) if !authed => self.record(&connection_id, subscription_id),
+            Some(ClientMessage::Close(subscription_id)) => {
+                self.forget(&connection_id, subscription_id)
+            }
+            _ => {}
+        }
+
+        // The first message handled after AUTH flushes the notice, whether the
+        // AUTH itself went through this middleware or was answered before it
+        if authed {
+            if let Some(notice) = self.take_notice(&connection_id) {
+                debug!(
+                    "[{}] Asking client to re-send pre-auth subscriptions",
+                    connection_id
+                );
+                ctx.send_message(RelayMessage::notice(notice))?;
+            }
+        }
+
+        ctx.next().await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        self.pending.remove(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notice_lists_open_pre_auth_subscriptions() {
+        let middleware = AuthResubscribeMiddleware::new(true);
+        middleware.record("conn-1", &SubscriptionId::new("private-chat"));
+        middleware.record("conn-1", &SubscriptionId::new("feed"));
+        middleware.record("conn-1", &SubscriptionId::new("closed-early"));
+        middleware.forget("conn-1", &SubscriptionId::new("closed-early"));
+        middleware.record("conn-2", &SubscriptionId::new("other"));
+
+        let notice = middleware.take_notice("conn-1").unwrap();
+        assert_eq!(
+            notice,
+            format!("{RESUBSCRIBE_NOTICE_PREFIX} feed, private-chat")
+        );
+
+        // Sent once per connection
+        assert!(middleware.take_notice("conn-1").is_none());
+        assert!(middleware.take_notice("conn-2").is_some());
+    }
+
+    #[test]
+    fn test_no_notice_when_everything_was_closed() {
+        let middleware = AuthResubscribeMiddleware::new(true);
+        middleware.record("conn-1", &SubscriptionId::new("feed"));
+        middleware.forget("conn-1", &SubscriptionId::new("feed"));
+
+        assert!(middleware.take_notice("conn-1").is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{bail, Result};
+use clap::Parser;
+use groups_relay::groups::{ADDRESSABLE_EVENT_KINDS, KIND_GROUP_METADATA_39000};
+use groups_relay::RelayDatabase;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "import_group",
+    version = "0.1.0",
+    about = "Import an existing NIP-29 group, with its state and content, from another relay"
+)]
+struct Args {
+    /// Path to the database
+    #[arg(short, long)]
+    db_path: String,
+
+    /// Relay to import the group from
+    #[arg(long)]
+    from: String,
+
+    /// Id of the group to import
+    #[arg(short, long)]
+    group_id: String,
+
+    /// Subdomain scope to import into (default scope when omitted)
+    #[arg(short, long)]
+    scope: Option<String>,
+
+    /// Private key (hex or nsec) used to authenticate to the source relay,
+    /// needed for private groups
+    #[arg(short = 'k', long)]
+    private_key: Option<String>,
+
+    /// Import into a group that already exists locally
+    #[arg(long, default_value = "false")]
+    merge: bool,
+
+    /// Seconds to wait for the source relay to return events
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+
+    /// Dry run mode - don't actually save events
+    #[arg(short = 'n', long, default_value = "false")]
+    dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+struct ImportStats {
+    imported: usize,
+    already_stored: usize,
+    invalid_signature: usize,
+    failed: usize,
+    by_kind: BTreeMap<u16, usize>,
+}
+
+fn setup_tracing() {
+    use tracing_subscriber::{fmt, EnvFilter};
+
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    fmt()
+        .with_env_filter(env_filter)
+        .with_timer(fmt::time::SystemTime)
+        .with_target(true)
+        .with_thread_ids(false)
+        .with_thread_names(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_level(true)
+        .init();
+}
+
+async fn group_exists(database: &RelayDatabase, scope: &Scope, group_id: &str) -> Result<bool> {
+    let filter = vec![Filter::new()
+        .kind(KIND_GROUP_METADATA_39000)
+        .identifier(group_id)
+        .limit(1)];
+    Ok(!database.query(filter, scope).await?.is_empty())
+}
+
+async fn is_stored(database: &RelayDatabase, scope: &Scope, event_id: EventId) -> Result<bool> {
+    let filter = vec![Filter::new().id(event_id).limit(1)];
+    Ok(!database.query(filter, scope).await?.is_empty())
+}
+
+async fn fetch_group_events(args: &Args) -> Result<Vec<Event>> {
+    let keys = match &args.private_key {
+        Some(key) => Keys::parse(key)?,
+        None => Keys::generate(),
+    };
+    let client = ClientBuilder::default().signer(keys).build();
+    client.add_relay(RelayUrl::parse(&args.from)?).await?;
+    client.connect().await;
+
+    let timeout = Duration::from_secs(args.timeout);
+
+    // Relay-generated state: metadata, admins, members and roles
+    let state_filter = Filter::new()
+        .kinds(ADDRESSABLE_EVENT_KINDS)
+        .identifier(&args.group_id);
+    // Moderation events, join/leave requests and content all carry the h tag
+    let group_filter =
+        Filter::new().custom_tag(SingleLetterTag::lowercase(Alphabet::H), &args.group_id);
+
+    let mut events: Vec<Event> = client
+        .fetch_events(state_filter, timeout)
+        .await?
+        .into_iter()
+        .collect();
+    events.extend(client.fetch_events(group_filter, timeout).await?);
+
+    client.disconnect().await;
+
+    // Oldest first, so moderation events are stored in the order they happened
+    let mut seen = HashSet::new();
+    events.retain(|event| seen.insert(event.id));
+    events.sort_by_key(|event| event.created_at);
+    Ok(events)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    setup_tracing();
+
+    let args = Args::parse();
+
+    let scope = match &args.scope {
+        Some(name) => Scope::named(name)?,
+        None => Scope::Default,
+    };
+
+    info!("Starting import_group tool");
+    info!("Database path: {}", args.db_path);
+    info!("Source relay: {}", args.from);
+    info!("Group id: {}", args.group_id);
+    info!("Scope: {:?}", scope);
+    info!("Dry run: {}", args.dry_run);
+
+    let database = Arc::new(RelayDatabase::new(&args.db_path).await?);
+
+    if group_exists(&database, &scope, &args.group_id).await? && !args.merge {
+        bail!(
+            "Group {} already exists in scope {:?}, pass --merge to import into it",
+            args.group_id,
+            scope
+        );
+    }
+
+    let events = fetch_group_events(&args).await?;
+    info!("Fetched {} events from {}", events.len(), args.from);
+
+    if !events
+        .iter()
+        .any(|event| event.kind == KIND_GROUP_METADATA_39000)
+    {
+        warn!(
+            "No metadata event found for group {}, the relay will not load it as a managed group",
+            args.group_id
+        );
+    }
+
+    let mut stats = ImportStats::default();
+
+    for event in events {
+        if event.verify().is_err() {
+            warn!("Skipping event {} with an invalid signature", event.id);
+            stats.invalid_signature += 1;
+            continue;
+        }
+
+        if is_stored(&database, &scope, event.id).await? {
+            stats.already_stored += 1;
+            continue;
+        }
+
+        if args.dry_run {
+            info!(
+                "DRY RUN: Would import event {} (kind {})",
+                event.id, event.kind
+            );
+        } else if let Err(e) = database.save_event(&event, &scope).await {
+            warn!("Error saving event {}: {}", event.id, e);
+            stats.failed += 1;
+            continue;
+        }
+
+        stats.imported += 1;
+        *stats.by_kind.entry(event.kind.as_u16()).or_default() += 1;
+    }
+
+    info!("Import complete!");
+    info!("Events imported: {}", stats.imported);
+    for (kind, count) in &stats.by_kind {
+        info!("  kind {}: {}", kind, count);
+    }
+    info!("Already stored: {}", stats.already_stored);
+    info!("Invalid signatures: {}", stats.invalid_signature);
+    info!("Errors: {}", stats.failed);
+
+    if args.dry_run {
+        info!("This was a dry run - no changes were made");
+    } else {
+        info!("Restart the relay to load the imported group");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,392 @@
+use anyhow::{bail, Context, Result};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use groups_relay::config;
+use groups_relay::relay_admin::{
+    compact_database, export_events, import_events, move_group, rotate_key, summarize, AdminTarget,
+    GroupAction, GroupSummary, MetadataChanges,
+};
+use groups_relay::RelayDatabase;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "relay-admin",
+    version = "0.1.0",
+    about = "Manage groups on a groups relay, signing with the relay admin key"
+)]
+struct Args {
+    /// Path to config directory
+    #[arg(short, long, default_value = "config")]
+    config_dir: String,
+
+    /// Override the relay WebSocket URL to publish to
+    #[arg(short, long)]
+    relay_url: Option<String>,
+
+    /// Write directly to the database instead of publishing to the running relay
+    #[arg(long)]
+    local: bool,
+
+    /// Subdomain scope to manage with --local (default scope when omitted)
+    #[arg(short, long)]
+    scope: Option<String>,
+
+    /// Print JSON instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Group management
+    #[command(subcommand)]
+    Group(GroupCommand),
+    /// Re-sign every group's state with the active relay key, replacing the
+    /// events signed by `previous_relay_secret_keys` (requires --local)
+    RotateKey,
+    /// Write the events of --scope as JSON lines (requires --local)
+    Export {
+        /// File to write the events to
+        #[arg(long)]
+        out: PathBuf,
+        /// Only export these kinds
+        #[arg(long)]
+        kind: Vec<u16>,
+        /// Only export events created at or after this unix timestamp
+        #[arg(long)]
+        since: Option<u64>,
+    },
+    /// Store the JSON-lines events of a file into --scope, verifying their
+    /// signatures and skipping the ones already stored (requires --local)
+    Import { file: PathBuf },
+    /// Rewrite the database without its free pages (requires --local, with
+    /// the relay stopped)
+    Compact,
+}
+
+#[derive(Subcommand, Debug)]
+enum GroupCommand {
+    /// List all groups
+    List,
+    /// Show a group with its admins and members
+    Show { group_id: String },
+    /// Create a group owned by the relay
+    Create { group_id: String },
+    /// Add a member, or change their role
+    AddMember {
+        group_id: String,
+        pubkey: String,
+        /// Role to give the member, e.g. admin
+        #[arg(long)]
+        role: Option<String>,
+    },
+    /// Remove a member
+    RemoveMember { group_id: String, pubkey: String },
+    /// Change group metadata, leaving unset fields as they are
+    SetMetadata(SetMetadataArgs),
+    /// Delete a group and its events
+    Delete { group_id: String },
+    /// Move a group and its events to another scope (requires --local)
+    Move {
+        group_id: String,
+        /// Scope the group is in, "default" for the main domain
+        #[arg(long)]
+        from: String,
+        /// Scope to move the group to, "default" for the main domain
+        #[arg(long)]
+        to: String,
+        /// Only count the events that would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct SetMetadataArgs {
+    group_id: String,
+    #[arg(long)]
+    name: Option<String>,
+    #[arg(long)]
+    about: Option<String>,
+    #[arg(long, conflicts_with = "private")]
+    public: bool,
+    #[arg(long)]
+    private: bool,
+    #[arg(long, conflicts_with = "closed")]
+    open: bool,
+    #[arg(long)]
+    closed: bool,
+    #[arg(long, conflicts_with = "nonbroadcast")]
+    broadcast: bool,
+    #[arg(long)]
+    nonbroadcast: bool,
+}
+
+impl SetMetadataArgs {
+    fn changes(&self) -> MetadataChanges {
+        let either = |yes: bool, no: bool| (yes || no).then_some(yes);
+        MetadataChanges {
+            name: self.name.clone(),
+            about: self.about.clone(),
+            private: either(self.private, self.public),
+            closed: either(self.closed, self.open),
+            broadcast: either(self.broadcast, self.nonbroadcast),
+        }
+    }
+}
+
+fn parse_pubkey(pubkey: &str) -> Result<PublicKey> {
+    PublicKey::parse(pubkey).with_context(|| format!("Invalid public key: {pubkey}"))
+}
+
+fn parse_scope(name: &str) -> Result<Scope> {
+    if name == "default" {
+        return Ok(Scope::Default);
+    }
+    Scope::named(name).with_context(|| format!("Invalid scope: {name}"))
+}
+
+fn print_table(groups: &[GroupSummary]) {
+    println!(
+        "{:<24} {:<24} {:<8} {:<7} {:<9} {:>6} {:>7}",
+        "ID", "NAME", "ACCESS", "JOIN", "BROADCAST", "ADMINS", "MEMBERS"
+    );
+    for group in groups {
+        println!(
+            "{:<24} {:<24} {:<8} {:<7} {:<9} {:>6} {:>7}",
+            group.id,
+            group.name,
+            if group.private { "private" } else { "public" },
+            if group.closed { "closed" } else { "open" },
+            if group.broadcast { "yes" } else { "no" },
+            group.admins.len(),
+            group.members.len()
+        );
+    }
+}
+
+fn print_group(group: &GroupSummary) {
+    print_table(std::slice::from_ref(group));
+    if let Some(about) = &group.about {
+        println!("\nAbout: {about}");
+    }
+    println!("\nAdmins:");
+    for admin in &group.admins {
+        println!("  {admin}");
+    }
+    println!("\nMembers:");
+    for member in &group.members {
+        println!("  {member}");
+    }
+}
+
+async fn maintenance(args: &Args, db_path: &str, keys: &Keys) -> Result<()> {
+    if let Command::Compact = args.command {
+        let (before, after) = compact_database(Path::new(db_path))?;
+        if args.json {
+            println!(
+                "{}",
+                serde_json::json!({ "before": before, "after": after })
+            );
+        } else {
+            println!("Compacted {db_path} from {before} to {after} bytes");
+        }
+        return Ok(());
+    }
+
+    let scope = match &args.scope {
+        Some(name) => parse_scope(name)?,
+        None => Scope::Default,
+    };
+    let database = RelayDatabase::new(db_path).await?;
+    match &args.command {
+        Command::Export { out, kind, since } => {
+            let mut filter = Filter::new();
+            if !kind.is_empty() {
+                filter = filter.kinds(kind.iter().copied().map(Kind::from));
+            }
+            if let Some(since) = since {
+                filter = filter.since(Timestamp::from(*since));
+            }
+            let mut writer = BufWriter::new(File::create(out)?);
+            let written = export_events(&database, &scope, filter, &mut writer).await?;
+            if args.json {
+                println!("{}", serde_json::json!({ "events": written }));
+            } else {
+                println!("Exported {written} events to {}", out.display());
+            }
+        }
+        Command::Import { file } => {
+            let reader = BufReader::new(
+                File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+            );
+            let report = import_events(&database, keys, &scope, reader).await?;
+            if args.json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                println!(
+                    "Imported {} events, skipped {} duplicates and {} invalid",
+                    report.imported, report.duplicates, report.invalid
+                );
+            }
+        }
+        _ => unreachable!("not a maintenance command"),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = config::Config::new(&args.config_dir).context("Failed to load configuration")?;
+    let settings = config
+        .get_settings()
+        .context("Failed to get relay settings")?;
+    let keys = settings.relay_keys()?;
+    let relay_url = args.relay_url.clone().unwrap_or(settings.relay_url.clone());
+
+    if let Command::RotateKey = args.command {
+        if !args.local {
+            bail!("rotate-key rewrites every scope, stop the relay and use --local");
+        }
+        let database = Arc::new(RelayDatabase::new(&settings.db_path).await?);
+        let rotated = rotate_key(database, &settings.admin_keys()?, &relay_url).await?;
+        if args.json {
+            println!("{}", serde_json::json!({ "groups": rotated }));
+        } else {
+            println!(
+                "Re-issued the state of {rotated} groups signed by {}",
+                keys.public_key().to_hex()
+            );
+        }
+        return Ok(());
+    }
+
+    if let Command::Export { .. } | Command::Import { .. } | Command::Compact = &args.command {
+        if !args.local {
+            bail!("maintenance commands work on the database, stop the relay and use --local");
+        }
+        return maintenance(&args, &settings.db_path, &keys).await;
+    }
+
+    if let Command::Group(GroupCommand::Move {
+        group_id,
+        from,
+        to,
+        dry_run,
+    }) = &args.command
+    {
+        if !args.local {
+            bail!("moving a group rewrites two scopes, stop the relay and use --local");
+        }
+        let (from, to) = (parse_scope(from)?, parse_scope(to)?);
+        let database = Arc::new(RelayDatabase::new(&settings.db_path).await?);
+        let moved = move_group(
+            database,
+            &settings.admin_keys()?,
+            &relay_url,
+            group_id,
+            &from,
+            &to,
+            *dry_run,
+        )
+        .await?;
+        if args.json {
+            println!(
+                "{}",
+                serde_json::json!({ "events": moved, "dry_run": dry_run })
+            );
+        } else if *dry_run {
+            println!("Would move group {group_id} with {moved} events");
+        } else {
+            println!("Moved group {group_id} with {moved} events");
+        }
+        return Ok(());
+    }
+
+    let target = if args.local {
+        let scope = match &args.scope {
+            Some(name) => Scope::named(name)?,
+            None => Scope::Default,
+        };
+        let database = Arc::new(RelayDatabase::new(&settings.db_path).await?);
+        AdminTarget::local(database, &keys, &relay_url, scope).await?
+    } else {
+        if args.scope.is_some() {
+            bail!("--scope only applies with --local, use the subdomain in --relay-url instead");
+        }
+        AdminTarget::remote(&relay_url, &keys).await?
+    };
+
+    let action = match args.command {
+        Command::Group(GroupCommand::List) => {
+            let groups = summarize(&target.group_events(None).await?);
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&groups)?);
+            } else {
+                print_table(&groups);
+            }
+            None
+        }
+        Command::Group(GroupCommand::Show { group_id }) => {
+            let groups = summarize(&target.group_events(Some(&group_id)).await?);
+            let Some(group) = groups.first() else {
+                bail!("Group {group_id} not found");
+            };
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(group)?);
+            } else {
+                print_group(group);
+            }
+            None
+        }
+        Command::Group(GroupCommand::Create { group_id }) => Some(GroupAction::Create { group_id }),
+        Command::Group(GroupCommand::AddMember {
+            group_id,
+            pubkey,
+            role,
+        }) => Some(GroupAction::AddMember {
+            group_id,
+            pubkey: parse_pubkey(&pubkey)?,
+            role,
+        }),
+        Command::Group(GroupCommand::RemoveMember { group_id, pubkey }) => {
+            Some(GroupAction::RemoveMember {
+                group_id,
+                pubkey: parse_pubkey(&pubkey)?,
+            })
+        }
+        Command::Group(GroupCommand::SetMetadata(metadata)) => Some(GroupAction::SetMetadata {
+            group_id: metadata.group_id.clone(),
+            changes: metadata.changes(),
+        }),
+        Command::Group(GroupCommand::Delete { group_id }) => Some(GroupAction::Delete { group_id }),
+        Command::RotateKey
+        | Command::Export { .. }
+        | Command::Import { .. }
+        | Command::Compact
+        | Command::Group(GroupCommand::Move { .. }) => {
+            unreachable!("handled before connecting")
+        }
+    };
+
+    if let Some(action) = action {
+        let event_id = target.apply(&keys, &action).await?;
+        if args.json {
+            println!("{}", serde_json::json!({ "event_id": event_id.to_hex() }));
+        } else {
+            println!("Applied event {}", event_id.to_hex());
+        }
+    }
+
+    target.disconnect().await;
+    Ok(())
+}
@@ -0,0 +1,412 @@
+//! Scoped tokens for group bots.
+//!
+//! A group admin issues a token bound to the group, a set of
+//! [`BotCapability`]s and an expiry through the HTTP API. Tokens live on the
+//! [`Group`](crate::group::Group) and are persisted hashed in a relay-signed
+//! 39011 event that is never served to clients, so the token itself is only
+//! shown once.
+//!
+//! A bot presents its token in a `bot-token` tag of its NIP-42 AUTH event.
+//! While that connection is open, the bot key's events in the token's group
+//! are judged by the token's capabilities alone, whatever the key's
+//! membership. Tokens are looked up on every event, so revoking one, or its
+//! expiry, takes effect on open connections right away.
+
+use crate::config::BotTokenSettings;
+use crate::groups::{BotCapability, BotToken, GroupError, Groups};
+use crate::utils::{apply_store_commands, scope_name};
+use crate::RelayDatabase;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use relay_builder::StoreCommand;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+pub const BOT_TOKEN_TAG: &str = "bot-token";
+
+/// A token as listed to admins, without its hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BotTokenInfo {
+    pub id: String,
+    pub capabilities: BTreeSet<BotCapability>,
+    pub expires_at: u64,
+    pub issued_by: String,
+}
+
+impl From<&BotToken> for BotTokenInfo {
+    fn from(token: &BotToken) -> Self {
+        Self {
+            id: token.id.clone(),
+            capabilities: token.capabilities.clone(),
+            expires_at: token.expires_at.as_u64(),
+            issued_by: token.issued_by.to_hex(),
+        }
+    }
+}
+
+/// The SHA-256 a token is stored as
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug)]
+pub struct BotTokens {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    settings: BotTokenSettings,
+    /// The key that authenticated on each connection and the hashes of the
+    /// tokens it presented
+    sessions: DashMap<String, (PublicKey, Vec<String>)>,
+}
+
+impl BotTokens {
+    pub fn new(
+        settings: &BotTokenSettings,
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            groups,
+            database,
+            relay_keys,
+            settings: settings.clone(),
+            sessions: DashMap::new(),
+        })
+    }
+
+    /// Issues a token for the caller's group, returning it and the token
+    pub async fn issue(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        capabilities: BTreeSet<BotCapability>,
+        lifetime: Duration,
+    ) -> Result<(BotToken, String), GroupError> {
+        if capabilities.is_empty() {
+            return Err(GroupError::ValidationFailed(
+                "A bot token needs at least one capability".to_string(),
+            ));
+        }
+        if lifetime.is_zero() || lifetime > self.settings.max_lifetime {
+            return Err(GroupError::ValidationFailed(format!(
+                "Bot tokens must expire within {}s",
+                self.settings.max_lifetime.as_secs()
+            )));
+        }
+
+        let secret = hex::encode(rand::random::<[u8; 32]>());
+        let hash = hash_token(&secret);
+        let token = BotToken {
+            id: hash[..16].to_string(),
+            hash,
+            capabilities,
+            expires_at: Timestamp::now() + lifetime,
+            issued_by: *caller,
+        };
+
+        let commands = self.groups.add_bot_token(
+            scope,
+            group_id,
+            caller,
+            token.clone(),
+            self.settings.max_per_group,
+        )?;
+        self.persist(commands).await?;
+
+        info!(
+            "Issued bot token {} for group {} in scope {}",
+            token.id,
+            group_id,
+            scope_name(scope)
+        );
+        Ok((token, secret))
+    }
+
+    pub async fn revoke(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        token_id: &str,
+    ) -> Result<(), GroupError> {
+        let commands = self
+            .groups
+            .revoke_bot_token(scope, group_id, caller, token_id)?;
+        self.persist(commands).await
+    }
+
+    async fn persist(&self, commands: Vec<StoreCommand>) -> Result<(), GroupError> {
+        apply_store_commands(&self.database, &self.relay_keys, commands)
+            .await
+            .map_err(GroupError::Internal)
+    }
+
+    /// Records the tokens carried by a connection's AUTH event. The event's
+    /// signature is checked so a token can't be bound to someone else's key.
+    pub fn present(&self, connection_id: &str, auth: &Event) {
+        let hashes: Vec<String> = auth
+            .tags
+            .iter()
+            .filter_map(|tag| match tag.as_slice() {
+                [name, token, ..] if name == BOT_TOKEN_TAG => Some(hash_token(token)),
+                _ => None,
+            })
+            .collect();
+        if hashes.is_empty() || auth.verify().is_err() {
+            return;
+        }
+
+        debug!(
+            "[{}] {} presented {} bot tokens",
+            connection_id,
+            auth.pubkey,
+            hashes.len()
+        );
+        self.sessions
+            .insert(connection_id.to_string(), (auth.pubkey, hashes));
+    }
+
+    pub fn end_session(&self, connection_id: &str) {
+        self.sessions.remove(connection_id);
+    }
+
+    /// What the pubkey may do in the group through the tokens it presented
+    /// on open connections, None when it holds no valid token for the group
+    pub fn capabilities(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        pubkey: &PublicKey,
+    ) -> Option<BTreeSet<BotCapability>> {
+        let hashes: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|session| session.value().0 == *pubkey)
+            .flat_map(|session| session.value().1.clone())
+            .collect();
+        if hashes.is_empty() {
+            return None;
+        }
+
+        let group = self.groups.get_group(scope, group_id)?;
+        let now = Timestamp::now();
+        let mut capabilities = BTreeSet::new();
+        let mut found = false;
+        for hash in &hashes {
+            if let Some(granted) = group.bot_capabilities(hash, now) {
+                capabilities.extend(granted.iter().copied());
+                found = true;
+            }
+        }
+        found.then_some(capabilities)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BotTokenMiddleware {
+    tokens: Arc<BotTokens>,
+}
+
+impl BotTokenMiddleware {
+    pub fn new(tokens: Arc<BotTokens>) -> Self {
+        Self { tokens }
+    }
+}
+
+impl NostrMiddleware<()> for BotTokenMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if let Some(ClientMessage::Auth(auth)) = &ctx.message {
+            self.tokens.present(&ctx.connection_id.to_string(), auth);
+        }
+        ctx.next().await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        self.tokens.end_session(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{KIND_GROUP_CREATE_9007, KIND_GROUP_DELETE_EVENT_9005};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::RwLock;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[tokio::test]
+    async fn test_bot_token_capabilities_expiry_and_revocation() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let bot_tokens = BotTokens::new(
+            &BotTokenSettings::default(),
+            groups.clone(),
+            database,
+            relay_keys.clone(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_bot_tokens(bot_tokens.clone());
+        let (admin, bot) = (Keys::generate(), Keys::generate());
+        let scope = Scope::Default;
+        let context = |keys: &Keys| EventContext {
+            authed_pubkey: Some(keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["bot_group"]);
+        let sign = |keys: &Keys, kind: Kind, tags: Vec<Tag>| {
+            EventBuilder::new(kind, "")
+                .tags(tags)
+                .sign_with_keys(keys)
+                .unwrap()
+        };
+        let auth = |tokens: &[&str]| {
+            EventBuilder::auth("challenge", RelayUrl::parse("wss://test.relay").unwrap())
+                .tags(
+                    tokens
+                        .iter()
+                        .map(|token| Tag::custom(TagKind::custom(BOT_TOKEN_TAG), [*token])),
+                )
+                .sign_with_keys(&bot)
+                .unwrap()
+        };
+
+        let create = sign(&admin, KIND_GROUP_CREATE_9007, vec![h_tag.clone()]);
+        let close = sign(
+            &admin,
+            Kind::Custom(9002),
+            vec![
+                h_tag.clone(),
+                Tag::custom(TagKind::custom("private"), &[] as &[String]),
+                Tag::custom(TagKind::custom("closed"), &[] as &[String]),
+            ],
+        );
+        for event in [create, close] {
+            processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context(&admin))
+                .await
+                .unwrap();
+        }
+
+        // Without a token the bot is an outsider of the closed group
+        let post = sign(&bot, Kind::Custom(9), vec![h_tag.clone()]);
+        assert!(processor
+            .handle_event(post.clone(), Arc::new(RwLock::new(())), &context(&bot))
+            .await
+            .is_err());
+
+        // Only admins issue tokens
+        let post_only = BTreeSet::from([BotCapability::PostContent]);
+        assert!(matches!(
+            bot_tokens
+                .issue(
+                    &scope,
+                    "bot_group",
+                    &bot.public_key(),
+                    post_only.clone(),
+                    HOUR
+                )
+                .await,
+            Err(GroupError::PermissionDenied(_))
+        ));
+        let (token, secret) = bot_tokens
+            .issue(&scope, "bot_group", &admin.public_key(), post_only, HOUR)
+            .await
+            .unwrap();
+        bot_tokens.present("bot-conn", &auth(&[&secret]));
+
+        // Posting is allowed without joining, deleting isn't granted
+        let commands = processor
+            .handle_event(post.clone(), Arc::new(RwLock::new(())), &context(&bot))
+            .await
+            .unwrap();
+        assert!(matches!(
+            commands.as_slice(),
+            [StoreCommand::SaveSignedEvent(saved, _, _)] if saved.id == post.id
+        ));
+        let group = groups.get_group(&scope, "bot_group").unwrap();
+        assert!(!group.is_member(&bot.public_key()));
+        drop(group);
+        let delete = sign(
+            &bot,
+            KIND_GROUP_DELETE_EVENT_9005,
+            vec![h_tag.clone(), Tag::event(post.id)],
+        );
+        assert!(processor
+            .handle_event(delete.clone(), Arc::new(RwLock::new(())), &context(&bot))
+            .await
+            .is_err());
+
+        // A second token adds the delete capability on another connection
+        let (_, delete_secret) = bot_tokens
+            .issue(
+                &scope,
+                "bot_group",
+                &admin.public_key(),
+                BTreeSet::from([BotCapability::DeleteEvents]),
+                HOUR,
+            )
+            .await
+            .unwrap();
+        bot_tokens.present("bot-conn-2", &auth(&[&delete_secret]));
+        let commands = processor
+            .handle_event(delete, Arc::new(RwLock::new(())), &context(&bot))
+            .await
+            .unwrap();
+        assert!(commands
+            .iter()
+            .any(|command| matches!(command, StoreCommand::DeleteEvents(_, _, _))));
+        bot_tokens.end_session("bot-conn-2");
+
+        // Tokens stop working when they expire
+        let group = groups.get_group(&scope, "bot_group").unwrap();
+        let hash = hash_token(&secret);
+        assert!(group
+            .bot_capabilities(&hash, token.expires_at - HOUR)
+            .is_some());
+        assert!(group.bot_capabilities(&hash, token.expires_at).is_none());
+        drop(group);
+
+        // Revoking takes effect on the open connection
+        bot_tokens
+            .revoke(&scope, "bot_group", &admin.public_key(), &token.id)
+            .await
+            .unwrap();
+        let another = sign(&bot, Kind::Custom(9), vec![h_tag]);
+        assert!(processor
+            .handle_event(another, Arc::new(RwLock::new(())), &context(&bot))
+            .await
+            .is_err());
+        assert_eq!(
+            bot_tokens.capabilities(&scope, "bot_group", &bot.public_key()),
+            None
+        );
+    }
+}
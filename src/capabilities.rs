@@ -0,0 +1,237 @@
+//! Client capability negotiation for extension features.
+//!
+//! Clients declare the extensions they understand with a REQ on the reserved
+//! [`CAPS_SUBSCRIPTION_ID`], listing capability names separated by spaces in
+//! the filter's `search` field:
+//!
+//! ```json
+//! ["REQ", "_caps", {"search": "truncation_notice delta_members"}]
+//! ```
+//!
+//! The relay stores the recognized set for the connection and answers with a
+//! CLOSED listing what it accepted. Unknown names are ignored. Extension
+//! features consult the connection's [`Capabilities`] and keep vanilla NIP-01
+//! behavior for clients that never declared them.
+
+use dashmap::DashMap;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use std::sync::Arc;
+use tracing::debug;
+
+pub const CAPS_SUBSCRIPTION_ID: &str = "_caps";
+
+/// Set of extension features a client declared support for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// NOTICE when a REQ limit was capped by the relay
+    pub const TRUNCATION_NOTICE: Capabilities = Capabilities(1 << 0);
+    /// Incremental member list updates
+    pub const DELTA_MEMBERS: Capabilities = Capabilities(1 << 1);
+    /// Subscriptions that can resume after a reconnect
+    pub const RESUMABLE_SUBSCRIPTIONS: Capabilities = Capabilities(1 << 2);
+
+    const NAMES: [(&'static str, Capabilities); 3] = [
+        ("truncation_notice", Self::TRUNCATION_NOTICE),
+        ("delta_members", Self::DELTA_MEMBERS),
+        ("resumable_subscriptions", Self::RESUMABLE_SUBSCRIPTIONS),
+    ];
+
+    /// Parses capability names, ignoring the ones this relay doesn't know.
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut capabilities = Self::NONE;
+        for name in names {
+            if let Some((_, capability)) = Self::NAMES.iter().find(|(n, _)| *n == name) {
+                capabilities.insert(*capability);
+            }
+        }
+        capabilities
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, capability)| self.contains(*capability))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// Declared capabilities per connection, keyed by connection id.
+#[derive(Debug, Default)]
+pub struct CapabilityRegistry {
+    connections: DashMap<String, Capabilities>,
+}
+
+impl CapabilityRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capabilities of a connection; vanilla NIP-01 if it never declared any.
+    pub fn get(&self, connection_id: &str) -> Capabilities {
+        self.connections
+            .get(connection_id)
+            .map(|capabilities| *capabilities)
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, connection_id: &str, capabilities: Capabilities) {
+        self.connections
+            .insert(connection_id.to_string(), capabilities);
+    }
+
+    pub fn remove(&self, connection_id: &str) {
+        self.connections.remove(connection_id);
+    }
+}
+
+/// Builds the truncation NOTICE for a REQ whose limits the relay capped, if
+/// the client asked for it.
+pub fn truncation_notice(
+    capabilities: Capabilities,
+    subscription_id: &SubscriptionId,
+    filters: &[Filter],
+    max_limit: usize,
+) -> Option<String> {
+    if !capabilities.contains(Capabilities::TRUNCATION_NOTICE) {
+        return None;
+    }
+
+    filters
+        .iter()
+        .any(|filter| filter.limit.is_some_and(|limit| limit > max_limit))
+        .then(|| format!("truncated: {subscription_id} limit capped to {max_limit}"))
+}
+
+#[derive(Debug, Clone)]
+pub struct CapabilitiesMiddleware {
+    registry: Arc<CapabilityRegistry>,
+}
+
+impl CapabilitiesMiddleware {
+    pub fn new(registry: Arc<CapabilityRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+impl NostrMiddleware<()> for CapabilitiesMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(ClientMessage::Req {
+            subscription_id,
+            filter,
+        }) = &ctx.message
+        else {
+            return ctx.next().await;
+        };
+
+        if subscription_id.as_str() != CAPS_SUBSCRIPTION_ID {
+            return ctx.next().await;
+        }
+
+        let capabilities = Capabilities::from_names(
+            filter
+                .search
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace(),
+        );
+        let connection_id = ctx.connection_id.to_string();
+        debug!(
+            "[{}] Client declared capabilities: {:?}",
+            connection_id,
+            capabilities.names()
+        );
+        self.registry.set(&connection_id, capabilities);
+
+        let subscription_id = subscription_id.clone().into_owned();
+        ctx.send_message(RelayMessage::closed(
+            subscription_id,
+            format!("caps: {}", capabilities.names().join(" ")),
+        ))?;
+        Ok(())
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        self.registry.remove(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_capabilities_are_ignored() {
+        let capabilities =
+            Capabilities::from_names(["truncation_notice", "teleportation", "delta_members"]);
+
+        assert!(capabilities.contains(Capabilities::TRUNCATION_NOTICE));
+        assert!(capabilities.contains(Capabilities::DELTA_MEMBERS));
+        assert!(!capabilities.contains(Capabilities::RESUMABLE_SUBSCRIPTIONS));
+        assert_eq!(
+            capabilities.names(),
+            vec!["truncation_notice", "delta_members"]
+        );
+        assert!(Capabilities::from_names(["teleportation"]).is_empty());
+    }
+
+    #[test]
+    fn test_registry_defaults_to_vanilla() {
+        let registry = CapabilityRegistry::new();
+        assert!(registry.get("conn-1").is_empty());
+
+        registry.set("conn-1", Capabilities::TRUNCATION_NOTICE);
+        assert!(registry
+            .get("conn-1")
+            .contains(Capabilities::TRUNCATION_NOTICE));
+        assert!(registry.get("conn-2").is_empty());
+
+        registry.remove("conn-1");
+        assert!(registry.get("conn-1").is_empty());
+    }
+
+    #[test]
+    fn test_truncation_notice_only_for_declaring_clients() {
+        let sub_id = SubscriptionId::new("feed");
+        let filters = vec![Filter::new().kind(Kind::TextNote).limit(1000)];
+
+        assert_eq!(
+            truncation_notice(Capabilities::NONE, &sub_id, &filters, 500),
+            None
+        );
+        assert_eq!(
+            truncation_notice(Capabilities::TRUNCATION_NOTICE, &sub_id, &filters, 500),
+            Some("truncated: feed limit capped to 500".to_string())
+        );
+
+        // Nothing to report when the limit fits
+        let small = vec![Filter::new().kind(Kind::TextNote).limit(10)];
+        assert_eq!(
+            truncation_notice(Capabilities::TRUNCATION_NOTICE, &sub_id, &small, 500),
+            None
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Waiting for store commands to be committed.
+//!
+//! The processor returns store commands that relay_builder writes after
+//! `handle_event`, so anything acting on them right away also acts on events
+//! whose save fails. [`wait_for_commit`] polls the database until the saved
+//! events show up and returns them as stored, with relay-generated state
+//! events signed by the relay. Events still missing after
+//! [`COMMIT_TIMEOUT`] are left out: their save failed, or a newer version of
+//! a replaceable event took their place.
+
+use crate::RelayDatabase;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How long saved events are waited for
+pub const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const FIRST_POLL: Duration = Duration::from_millis(10);
+const MAX_POLL: Duration = Duration::from_millis(250);
+
+/// Waits for the events the commands save, returning a `SaveSignedEvent`
+/// command for each one found stored, in the commands' order.
+pub async fn wait_for_commit(
+    database: &RelayDatabase,
+    commands: &[StoreCommand],
+) -> Vec<StoreCommand> {
+    wait_for_commit_within(database, commands, COMMIT_TIMEOUT).await
+}
+
+async fn wait_for_commit_within(
+    database: &RelayDatabase,
+    commands: &[StoreCommand],
+    timeout: Duration,
+) -> Vec<StoreCommand> {
+    let mut pending: Vec<(EventId, Scope)> = Vec::new();
+    for command in commands {
+        match command {
+            StoreCommand::SaveSignedEvent(event, scope, _) => {
+                pending.push((event.id, scope.clone()));
+            }
+            // The relay signs them with its keys, the id doesn't depend on
+            // the signature
+            StoreCommand::SaveUnsignedEvent(unsigned, scope, _) => {
+                let mut unsigned = unsigned.clone();
+                unsigned.ensure_id();
+                if let Some(id) = unsigned.id {
+                    pending.push((id, scope.clone()));
+                }
+            }
+            StoreCommand::DeleteEvents(..) => {}
+        }
+    }
+
+    let order = pending.clone();
+    let mut stored: HashMap<EventId, Event> = HashMap::new();
+    let started = tokio::time::Instant::now();
+    let mut poll = FIRST_POLL;
+    while !pending.is_empty() {
+        tokio::time::sleep(poll).await;
+        poll = poll.saturating_mul(2).min(MAX_POLL);
+
+        let mut by_scope: HashMap<&Scope, Vec<EventId>> = HashMap::new();
+        for (id, scope) in &pending {
+            by_scope.entry(scope).or_default().push(*id);
+        }
+        for (scope, ids) in by_scope {
+            match database.query(vec![Filter::new().ids(ids)], scope).await {
+                Ok(events) => {
+                    for event in events {
+                        stored.insert(event.id, event);
+                    }
+                }
+                Err(e) => warn!("Failed to look up saved events: {}", e),
+            }
+        }
+        pending.retain(|(id, _)| !stored.contains_key(id));
+
+        if !pending.is_empty() && started.elapsed() >= timeout {
+            debug!("{} saved events never showed up, skipping", pending.len());
+            break;
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|(id, scope)| {
+            let event = stored.remove(&id)?;
+            Some(StoreCommand::SaveSignedEvent(Box::new(event), scope, None))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_event, setup_test};
+    use crate::utils::apply_store_commands;
+
+    #[tokio::test]
+    async fn test_returns_only_committed_events() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let author = Keys::generate();
+        let saved = create_test_event(&author, 9, vec![]).await;
+        let never_saved = create_test_event(&author, 11, vec![]).await;
+        let state = EventBuilder::new(Kind::Custom(39000), "").build(relay_keys.public_key());
+
+        let commands = || {
+            vec![
+                StoreCommand::SaveSignedEvent(Box::new(saved.clone()), Scope::Default, None),
+                StoreCommand::SaveUnsignedEvent(state.clone(), Scope::Default, None),
+            ]
+        };
+        apply_store_commands(&database, &relay_keys, commands())
+            .await
+            .unwrap();
+
+        let mut waited = commands();
+        waited.push(StoreCommand::SaveSignedEvent(
+            Box::new(never_saved),
+            Scope::Default,
+            None,
+        ));
+        let committed =
+            wait_for_commit_within(&database, &waited, Duration::from_millis(300)).await;
+
+        let events: Vec<&Event> = committed
+            .iter()
+            .filter_map(|command| match command {
+                StoreCommand::SaveSignedEvent(event, _, _) => Some(&**event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].id, saved.id);
+        assert_eq!(events[1].pubkey, relay_keys.public_key());
+        assert!(events[1].verify().is_ok());
+    }
+}
@@ -12,15 +12,108 @@ const CONFIG_SEPARATOR: &str = "__";
 #[derive(Debug, Deserialize)]
 pub struct RelaySettings {
     pub relay_secret_key: String,
+    /// Keys the relay was rotated away from, still recognized as the relay
+    #[serde(default)]
+    pub previous_relay_secret_keys: Vec<String>,
     pub local_addr: String,
     pub relay_url: String,
     pub db_path: String,
+    /// `text`, or `json` for log collectors like Loki
+    #[serde(default)]
+    pub log_format: LogFormat,
     #[serde(default)]
     pub websocket: WebSocketSettings,
     #[serde(default = "default_max_limit")]
     pub max_limit: usize,
     #[serde(default = "default_max_subscriptions")]
     pub max_subscriptions: usize,
+    #[serde(default = "default_max_filters_per_req")]
+    pub max_filters_per_req: usize,
+    #[serde(default = "default_max_filter_ids")]
+    pub max_filter_ids: usize,
+    #[serde(default = "default_max_event_size")]
+    pub max_event_size: usize,
+    #[serde(default = "default_max_event_tags")]
+    pub max_event_tags: usize,
+    #[serde(default = "default_max_content_length")]
+    pub max_content_length: usize,
+    /// Maximum number of tags on group member additions and removals
+    #[serde(default = "default_max_member_tags")]
+    pub max_member_tags: usize,
+    /// Membership changes to a group within this window of the last one
+    /// share a single regeneration of its admins and members lists.
+    /// Zero regenerates them on every change.
+    #[serde(with = "humantime_serde", default = "default_membership_state_window")]
+    pub membership_state_window: Duration,
+    /// Group changes in a scope within this window of the last one share a
+    /// single regeneration of its group directory. Zero disables the
+    /// directory event; `/api/directory` is always served.
+    #[serde(with = "humantime_serde", default = "default_directory_window")]
+    pub directory_window: Duration,
+    /// How often loaded groups are checked for missing or stale state
+    /// events, which are then regenerated. Zero disables the check.
+    #[serde(with = "humantime_serde", default = "default_state_check_interval")]
+    pub state_check_interval: Duration,
+    /// Past names of a group kept in its metadata, oldest dropped first
+    #[serde(default = "default_max_previous_names")]
+    pub max_previous_names: usize,
+    #[serde(default)]
+    pub load_shedding: LoadSheddingSettings,
+    #[serde(default)]
+    pub admission: AdmissionSettings,
+    #[serde(default)]
+    pub group_metrics: GroupMetricsSettings,
+    #[serde(default)]
+    pub bot_tokens: BotTokenSettings,
+    /// Send a NOTICE after AUTH listing subscriptions opened before it
+    #[serde(default)]
+    pub resubscribe_notice_after_auth: bool,
+    /// Narrow group content REQs without `#h` to the groups the client can read
+    #[serde(default)]
+    pub query_pushdown: bool,
+    /// Suggest an updated kind 10009 group list to users who join or leave
+    #[serde(default)]
+    pub group_list_sync: bool,
+    /// Set `since` on REQs an authenticated user repeats after reconnecting
+    #[serde(default)]
+    pub replay_hints: bool,
+    /// Personal kinds, like profiles and relay lists, accepted without a group
+    #[serde(default = "default_allowed_personal_kinds")]
+    pub allowed_personal_kinds: Vec<u16>,
+    /// Support NIP-17 direct messages: accept kind 10050 DM relay lists and
+    /// only serve gift wraps to their recipients
+    #[serde(default)]
+    pub private_messages: bool,
+    /// Start in read-only mode, rejecting every EVENT
+    #[serde(default)]
+    pub read_only: bool,
+    /// Relay ephemeral events (kinds 20000-29999) to live subscribers
+    #[serde(default = "default_allow_ephemeral_events")]
+    pub allow_ephemeral_events: bool,
+    #[serde(default)]
+    pub posting_policy: Option<PostingPolicySettings>,
+    #[serde(default)]
+    pub replication: Option<ReplicationSettings>,
+    #[serde(default)]
+    pub archive: Option<ArchiveSettings>,
+    #[serde(default)]
+    pub webhook: Option<WebhookSettings>,
+    #[serde(default)]
+    pub group_creation_limit: Option<GroupCreationLimitSettings>,
+    #[serde(default)]
+    pub group_webhooks: Option<GroupWebhookSettings>,
+    #[serde(default)]
+    pub profile: Option<RelayProfileSettings>,
+    #[serde(default)]
+    pub spam_filter: Option<SpamFilterSettings>,
+    #[serde(default)]
+    pub scope_policy: ScopeSettings,
+    #[serde(default)]
+    pub paid_group_creation: Option<PaymentSettings>,
+    #[serde(default)]
+    pub storage_quotas: Option<QuotaSettings>,
+    #[serde(default)]
+    pub group_validation: GroupValidationConfig,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -33,6 +126,546 @@ pub struct WebSocketSettings {
     pub max_connections: Option<usize>,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoadSheddingSettings {
+    #[serde(default = "default_load_shedding_enabled")]
+    pub enabled: bool,
+    /// Smoothed EVENT processing latency at which shedding starts
+    #[serde(with = "humantime_serde", default = "default_latency_threshold")]
+    pub latency_threshold: Duration,
+    /// Number of concurrently processed EVENTs at which shedding starts
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Delay applied to authenticated publishes at the critical level
+    #[serde(with = "humantime_serde", default = "default_authed_publish_delay")]
+    pub authed_publish_delay: Duration,
+    /// Fraction of a level's threshold the load must drop below to leave it
+    #[serde(default = "default_exit_ratio")]
+    pub exit_ratio: f64,
+}
+
+impl Default for LoadSheddingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_load_shedding_enabled(),
+            latency_threshold: default_latency_threshold(),
+            max_in_flight: default_max_in_flight(),
+            authed_publish_delay: default_authed_publish_delay(),
+            exit_ratio: default_exit_ratio(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdmissionSettings {
+    #[serde(default = "default_admission_enabled")]
+    pub enabled: bool,
+    /// Lowest events/sec budget the controller backs off to
+    #[serde(default = "default_admission_floor")]
+    pub floor: f64,
+    /// Highest events/sec budget, and the budget at startup
+    #[serde(default = "default_admission_ceiling")]
+    pub ceiling: f64,
+    /// Smoothed event processing latency above which the budget is lowered
+    #[serde(with = "humantime_serde", default = "default_admission_target_latency")]
+    pub target_latency: Duration,
+    /// Events in progress above which the budget is lowered
+    #[serde(default = "default_admission_max_queue_depth")]
+    pub max_queue_depth: usize,
+    /// Events/sec added to the budget per healthy interval
+    #[serde(default = "default_admission_increase_step")]
+    pub increase_step: f64,
+    /// Factor the budget is multiplied by per congested interval
+    #[serde(default = "default_admission_decrease_factor")]
+    pub decrease_factor: f64,
+    #[serde(
+        with = "humantime_serde",
+        default = "default_admission_adjust_interval"
+    )]
+    pub adjust_interval: Duration,
+}
+
+impl Default for AdmissionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_admission_enabled(),
+            floor: default_admission_floor(),
+            ceiling: default_admission_ceiling(),
+            target_latency: default_admission_target_latency(),
+            max_queue_depth: default_admission_max_queue_depth(),
+            increase_step: default_admission_increase_step(),
+            decrease_factor: default_admission_decrease_factor(),
+            adjust_interval: default_admission_adjust_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PostingPolicySettings {
+    /// Rules file (YAML, JSON or TOML)
+    pub path: String,
+    /// Public policy document, advertised in NIP-11 and in rejections
+    pub url: Option<String>,
+    #[serde(with = "humantime_serde", default = "default_policy_reload_interval")]
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GroupMetricsSettings {
+    /// Report per-group metrics at all
+    #[serde(default = "default_group_metrics_enabled")]
+    pub enabled: bool,
+    /// Only the busiest groups are reported, to bound label cardinality
+    #[serde(default = "default_group_metrics_top_n")]
+    pub top_n: usize,
+}
+
+impl Default for GroupMetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_group_metrics_enabled(),
+            top_n: default_group_metrics_top_n(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BotTokenSettings {
+    /// Unexpired tokens each group can hold
+    #[serde(default = "default_max_bot_tokens_per_group")]
+    pub max_per_group: usize,
+    /// Longest lifetime an admin can give a token
+    #[serde(with = "humantime_serde", default = "default_max_bot_token_lifetime")]
+    pub max_lifetime: Duration,
+}
+
+fn default_max_bot_tokens_per_group() -> usize {
+    5
+}
+
+fn default_max_bot_token_lifetime() -> Duration {
+    Duration::from_secs(90 * 24 * 60 * 60)
+}
+
+impl Default for BotTokenSettings {
+    fn default() -> Self {
+        Self {
+            max_per_group: default_max_bot_tokens_per_group(),
+            max_lifetime: default_max_bot_token_lifetime(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReplicationSettings {
+    /// Peer relay URLs events are pushed to
+    pub peers: Vec<String>,
+    /// Scopes to replicate ("default" for the root domain), empty for all
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Pubkeys peer relays publish with; their events are not forwarded back
+    #[serde(default)]
+    pub peer_pubkeys: Vec<String>,
+    #[serde(default = "default_outbox_capacity")]
+    pub outbox_capacity: usize,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(with = "humantime_serde", default = "default_retry_backoff")]
+    pub retry_backoff: Duration,
+    /// How long forwarded and received event ids are remembered on disk to
+    /// break mirror loops across restarts
+    #[serde(with = "humantime_serde", default = "default_seen_horizon")]
+    pub seen_horizon: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ArchiveSettings {
+    /// Archive relay URLs every accepted event is republished to
+    pub relays: Vec<String>,
+    /// Don't archive events of private groups
+    #[serde(default)]
+    pub exclude_private_groups: bool,
+    /// Scopes not archived at all ("default" for the root domain)
+    #[serde(default)]
+    pub exclude_scopes: Vec<String>,
+    /// Undelivered events kept per relay before the oldest are dropped
+    #[serde(default = "default_outbox_capacity")]
+    pub queue_capacity: usize,
+    #[serde(with = "humantime_serde", default = "default_retry_backoff")]
+    pub retry_interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSettings {
+    /// Endpoint receiving group change payloads
+    pub url: String,
+    /// Secret used to sign payloads with HMAC-SHA256
+    pub secret: String,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_webhook_queue_capacity")]
+    pub queue_capacity: usize,
+    #[serde(with = "humantime_serde", default = "default_retry_backoff")]
+    pub retry_backoff: Duration,
+}
+
+/// The relay's kind 0 profile, published with a kind 10002 relay list
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RelayProfileSettings {
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub picture: Option<String>,
+    pub website: Option<String>,
+    /// Relays the profile is also sent to, so clients can discover the relay
+    #[serde(default)]
+    pub indexer_relays: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GroupWebhookSettings {
+    /// Webhooks each group can register
+    #[serde(default = "default_max_webhooks_per_group")]
+    pub max_per_group: usize,
+    /// Events delivered per group and minute, the rest is dropped
+    #[serde(default = "default_webhook_events_per_minute")]
+    pub max_events_per_minute: u32,
+    /// Consecutive failed deliveries after which a webhook is disabled
+    #[serde(default = "default_webhook_disable_after_failures")]
+    pub disable_after_failures: u32,
+    #[serde(default = "default_group_webhook_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_webhook_queue_capacity")]
+    pub queue_capacity: usize,
+    #[serde(with = "humantime_serde", default = "default_retry_backoff")]
+    pub retry_backoff: Duration,
+    /// Deliveries in flight at once, so a slow endpoint doesn't hold up the rest
+    #[serde(default = "default_webhook_concurrent_deliveries")]
+    pub max_concurrent_deliveries: usize,
+    /// Hosts webhooks may point at even though they resolve to loopback or
+    /// private addresses, empty by default
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+fn default_webhook_concurrent_deliveries() -> usize {
+    16
+}
+
+fn default_max_webhooks_per_group() -> usize {
+    3
+}
+
+fn default_webhook_events_per_minute() -> u32 {
+    60
+}
+
+fn default_webhook_disable_after_failures() -> u32 {
+    10
+}
+
+fn default_group_webhook_max_retries() -> u32 {
+    2
+}
+
+/// What happens to a group message the spam filter flags
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamAction {
+    /// Answer with `rate-limited:` or `invalid:`
+    #[default]
+    Reject,
+    /// Store it, visible only to its author and the group admins
+    ShadowMute,
+}
+
+/// Relay-wide spam limits for group messages, 0 turns a check off. Groups can
+/// override each with a metadata tag, see `spam_filter`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SpamFilterSettings {
+    /// Messages a pubkey may post to a group within `window`
+    #[serde(default = "default_spam_max_messages")]
+    pub max_messages: usize,
+    #[serde(with = "humantime_serde", default = "default_spam_window")]
+    pub window: Duration,
+    /// Times the same content may be posted to a group within `duplicate_window`
+    #[serde(default = "default_spam_max_duplicates")]
+    pub max_duplicates: usize,
+    #[serde(with = "humantime_serde", default = "default_spam_duplicate_window")]
+    pub duplicate_window: Duration,
+    /// Pubkeys a message may mention
+    #[serde(default = "default_spam_max_mentions")]
+    pub max_mentions: usize,
+    /// Links a message may contain
+    #[serde(default = "default_spam_max_links")]
+    pub max_links: usize,
+    #[serde(default)]
+    pub action: SpamAction,
+}
+
+fn default_spam_max_messages() -> usize {
+    20
+}
+
+fn default_spam_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_spam_max_duplicates() -> usize {
+    3
+}
+
+fn default_spam_duplicate_window() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_spam_max_mentions() -> usize {
+    10
+}
+
+fn default_spam_max_links() -> usize {
+    5
+}
+
+impl Default for SpamFilterSettings {
+    fn default() -> Self {
+        Self {
+            max_messages: default_spam_max_messages(),
+            window: default_spam_window(),
+            max_duplicates: default_spam_max_duplicates(),
+            duplicate_window: default_spam_duplicate_window(),
+            max_mentions: default_spam_max_mentions(),
+            max_links: default_spam_max_links(),
+            action: SpamAction::default(),
+        }
+    }
+}
+
+/// Fee charged for creating groups, and optionally invites, checked by
+/// `payments::CallbackPaymentVerifier`
+#[derive(Debug, Deserialize, Clone)]
+pub struct PaymentSettings {
+    /// Fee advertised in NIP-11, in `unit`
+    pub amount: u64,
+    #[serde(default = "default_fee_unit")]
+    pub unit: String,
+    /// Where clients pay, advertised in NIP-11 and used when the callback
+    /// returns no invoice
+    pub payments_url: Option<String>,
+    /// Endpoint of the payment bridge asked whether a pubkey paid
+    pub callback_url: Option<String>,
+    /// Pubkeys that never pay
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    /// Charge for invites (9009) too
+    #[serde(default)]
+    pub invites: bool,
+}
+
+fn default_fee_unit() -> String {
+    "msats".to_string()
+}
+
+/// Storage limits of each scope, enforced by `quotas::StorageQuotas`
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct QuotaSettings {
+    #[serde(default)]
+    pub max_events_per_scope: Option<u64>,
+    /// Estimated from the size of the events' JSON
+    #[serde(default)]
+    pub max_bytes_per_scope: Option<u64>,
+}
+
+/// Which subdomains get a scope of their own
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeMode {
+    /// Every subdomain
+    #[default]
+    Open,
+    /// Only the listed subdomains
+    Allowlist,
+    /// The listed and stored subdomains, and those a provisioner creates a
+    /// group in
+    Claimable,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ScopeSettings {
+    #[serde(default)]
+    pub mode: ScopeMode,
+    /// Subdomains served in `allowlist` and `claimable` modes
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Pubkeys that can claim a new subdomain in `claimable` mode
+    #[serde(default)]
+    pub provisioners: Vec<String>,
+}
+
+/// What the group validation does with an event failing a check
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    /// No checks
+    #[default]
+    Off,
+    /// Log and count violations, but accept the event
+    LogOnly,
+    /// Reject violations with an `invalid:` OK
+    Enforce,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GroupValidationConfig {
+    #[serde(default)]
+    pub mode: ValidationMode,
+    /// Events of kinds not listed below need an `h` tag
+    #[serde(default = "default_require_h_tag")]
+    pub require_h_tag: bool,
+    /// Kinds accepted without a group, besides the built-in and personal ones
+    #[serde(default)]
+    pub allowed_kinds_without_group: Vec<Kind>,
+    #[serde(default = "default_max_h_tags")]
+    pub max_h_tags: usize,
+    /// Addressable events in a group need a `d` tag naming the same group
+    #[serde(default = "default_reject_mismatched_d_tag")]
+    pub reject_mismatched_d_tag: bool,
+}
+
+impl Default for GroupValidationConfig {
+    fn default() -> Self {
+        Self {
+            mode: ValidationMode::default(),
+            require_h_tag: default_require_h_tag(),
+            allowed_kinds_without_group: Vec::new(),
+            max_h_tags: default_max_h_tags(),
+            reject_mismatched_d_tag: default_reject_mismatched_d_tag(),
+        }
+    }
+}
+
+fn default_require_h_tag() -> bool {
+    true
+}
+
+fn default_max_h_tags() -> usize {
+    1
+}
+
+fn default_reject_mismatched_d_tag() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GroupCreationLimitSettings {
+    /// Groups a pubkey may create per scope within `window`
+    pub max_groups: u32,
+    #[serde(with = "humantime_serde", default = "default_group_creation_window")]
+    pub window: Duration,
+    /// How often window counters are written to disk
+    #[serde(with = "humantime_serde", default = "default_window_flush_interval")]
+    pub flush_interval: Duration,
+}
+
+fn default_group_creation_window() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_window_flush_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_webhook_queue_capacity() -> usize {
+    1000
+}
+
+fn default_outbox_capacity() -> usize {
+    10_000
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_retry_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_seen_horizon() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_policy_reload_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_load_shedding_enabled() -> bool {
+    false
+}
+
+fn default_admission_enabled() -> bool {
+    true
+}
+
+fn default_admission_floor() -> f64 {
+    50.0
+}
+
+fn default_admission_ceiling() -> f64 {
+    5000.0
+}
+
+fn default_admission_target_latency() -> Duration {
+    Duration::from_millis(50)
+}
+
+fn default_admission_max_queue_depth() -> usize {
+    500
+}
+
+fn default_admission_increase_step() -> f64 {
+    50.0
+}
+
+fn default_admission_decrease_factor() -> f64 {
+    0.7
+}
+
+fn default_admission_adjust_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_group_metrics_enabled() -> bool {
+    true
+}
+
+fn default_group_metrics_top_n() -> usize {
+    50
+}
+
+fn default_latency_threshold() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_max_in_flight() -> usize {
+    1000
+}
+
+fn default_authed_publish_delay() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_exit_ratio() -> f64 {
+    0.8
+}
+
 fn default_max_connection_duration() -> Option<Duration> {
     Some(Duration::from_secs(10 * 60)) // 10 minutes default
 }
@@ -45,6 +678,14 @@ fn default_max_connections() -> Option<usize> {
     Some(1000) // Default max connections
 }
 
+fn default_allow_ephemeral_events() -> bool {
+    true
+}
+
+fn default_allowed_personal_kinds() -> Vec<u16> {
+    vec![0, 3, 10002]
+}
+
 fn default_max_limit() -> usize {
     500 // Default/maximum limit for queries
 }
@@ -53,12 +694,61 @@ fn default_max_subscriptions() -> usize {
     50 // Default max subscriptions per connection
 }
 
+fn default_max_filters_per_req() -> usize {
+    10 // Default max filters in a single REQ
+}
+
+fn default_max_filter_ids() -> usize {
+    500 // Default max ids in a single filter
+}
+
+fn default_max_event_size() -> usize {
+    128 * 1024 // Default max serialized event size in bytes
+}
+
+fn default_max_event_tags() -> usize {
+    2000 // Default max tags in a single event
+}
+
+fn default_max_content_length() -> usize {
+    64 * 1024 // Default max content length in bytes
+}
+
+fn default_max_member_tags() -> usize {
+    10_000 // Default max members added or removed by a single event
+}
+
+fn default_membership_state_window() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_directory_window() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_state_check_interval() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+fn default_max_previous_names() -> usize {
+    10
+}
+
 impl RelaySettings {
     pub fn relay_keys(&self) -> Result<Keys, anyhow::Error> {
         let secret_key = SecretKey::from_hex(&self.relay_secret_key)?;
         Ok(Keys::new(secret_key))
     }
 
+    /// Every key recognized as the relay, the active signing key first
+    pub fn admin_keys(&self) -> Result<Vec<Keys>, anyhow::Error> {
+        let mut keys = vec![self.relay_keys()?];
+        for secret_key in &self.previous_relay_secret_keys {
+            keys.push(Keys::new(SecretKey::from_hex(secret_key)?));
+        }
+        Ok(keys)
+    }
+
     pub fn relay_url(&self) -> Result<RelayUrl, anyhow::Error> {
         Ok(RelayUrl::parse(&self.relay_url)?)
     }
@@ -109,6 +799,12 @@ impl Config {
         Ok(Config { config })
     }
 
+    /// The log format, read on its own because logging starts before the
+    /// rest of the settings are loaded
+    pub fn log_format(&self) -> LogFormat {
+        self.config.get("relay.log_format").unwrap_or_default()
+    }
+
     pub fn get_settings(&self) -> Result<RelaySettings, ConfigError> {
         let settings: RelaySettings = self.config.get("relay")?;
         // Only log non-sensitive WebSocket settings
@@ -130,6 +826,40 @@ pub struct Settings {
     pub db_path: String,
     pub max_limit: usize,
     pub max_subscriptions: usize,
+    pub max_filters_per_req: usize,
+    pub max_filter_ids: usize,
+    pub max_event_size: usize,
+    pub max_event_tags: usize,
+    pub max_content_length: usize,
+    pub max_member_tags: usize,
+    pub membership_state_window: Duration,
+    pub directory_window: Duration,
+    pub state_check_interval: Duration,
+    pub max_previous_names: usize,
+    pub load_shedding: LoadSheddingSettings,
+    pub admission: AdmissionSettings,
+    pub group_metrics: GroupMetricsSettings,
+    pub bot_tokens: BotTokenSettings,
+    pub resubscribe_notice_after_auth: bool,
+    pub query_pushdown: bool,
+    pub group_list_sync: bool,
+    pub replay_hints: bool,
+    pub allowed_personal_kinds: Vec<u16>,
+    pub private_messages: bool,
+    pub read_only: bool,
+    pub allow_ephemeral_events: bool,
+    pub posting_policy: Option<PostingPolicySettings>,
+    pub replication: Option<ReplicationSettings>,
+    pub archive: Option<ArchiveSettings>,
+    pub webhook: Option<WebhookSettings>,
+    pub group_creation_limit: Option<GroupCreationLimitSettings>,
+    pub group_webhooks: Option<GroupWebhookSettings>,
+    pub profile: Option<RelayProfileSettings>,
+    pub spam_filter: Option<SpamFilterSettings>,
+    pub scope_policy: ScopeSettings,
+    pub paid_group_creation: Option<PaymentSettings>,
+    pub storage_quotas: Option<QuotaSettings>,
+    pub group_validation: GroupValidationConfig,
 }
 
 pub use nostr_sdk::Keys;
@@ -0,0 +1,409 @@
+//! Per-connection protocol statistics.
+//!
+//! Counts the messages a client sends over the lifetime of its connection
+//! and logs a single summary line when it disconnects, so a session can be
+//! reconstructed after an incident. Live summaries are included in the
+//! connection's introspection report.
+//!
+//! Each connection gets a short correlation id when its first message
+//! arrives. It stays the same until the connection closes and is attached
+//! to the debug line logged for every inbound message and to the summary,
+//! so a support ticket quoting it can be matched to the logs.
+//!
+//! Sessions also record the connection's scope and authenticated pubkey as
+//! of its last message, so `GET /admin/connections` can list every live
+//! connection with its subscriptions, and the `connections_by_scope` gauge
+//! counts them per scope.
+//!
+//! Outbound messages (OK results, events sent), the outgoing queue, the
+//! remote address and the close reason are handled by relay_builder's
+//! connection loop and aren't visible to middlewares, so the summary only
+//! covers what the client sent. A connection shows up once it sends its
+//! first message.
+
+use crate::introspection::SubscriptionRegistry;
+use crate::metrics;
+use crate::utils::scope_name;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, info, Level};
+
+/// What a client sent over one connection
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionSummary {
+    pub correlation_id: String,
+    pub events: u64,
+    pub reqs: u64,
+    pub closes: u64,
+    pub auths: u64,
+    pub other: u64,
+    pub bytes_in: u64,
+    pub duration_secs: u64,
+}
+
+/// A live connection, for `GET /admin/connections`
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub connection_id: String,
+    pub scope: Option<String>,
+    pub authed_pubkey: Option<String>,
+    /// Filters of each open subscription, as JSON
+    pub subscriptions: BTreeMap<String, Vec<String>>,
+    #[serde(flatten)]
+    pub summary: ConnectionSummary,
+}
+
+#[derive(Debug)]
+struct Session {
+    started_at: Instant,
+    summary: ConnectionSummary,
+    scope: Option<String>,
+    authed_pubkey: Option<PublicKey>,
+}
+
+/// Running summaries, keyed by connection id
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    sessions: DashMap<String, Session>,
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a message received on the connection. The session starts with
+    /// its first message.
+    pub fn record(&self, connection_id: &str, message: &ClientMessage) {
+        let mut session = self
+            .sessions
+            .entry(connection_id.to_string())
+            .or_insert_with(|| Session {
+                started_at: Instant::now(),
+                summary: ConnectionSummary {
+                    correlation_id: new_correlation_id(),
+                    ..Default::default()
+                },
+                scope: None,
+                authed_pubkey: None,
+            });
+        let summary = &mut session.summary;
+
+        match message {
+            ClientMessage::Event(_) => summary.events += 1,
+            ClientMessage::Req { .. } | ClientMessage::ReqMultiFilter { .. } => summary.reqs += 1,
+            ClientMessage::Close(_) => summary.closes += 1,
+            ClientMessage::Auth(_) => summary.auths += 1,
+            _ => summary.other += 1,
+        }
+        summary.bytes_in += message.as_json().len() as u64;
+    }
+
+    /// Records who the connection is. The scope is set once, as it can't
+    /// change for a connection.
+    pub fn identify(&self, connection_id: &str, scope: &Scope, authed_pubkey: Option<PublicKey>) {
+        let Some(mut session) = self.sessions.get_mut(connection_id) else {
+            return;
+        };
+        if session.scope.is_none() {
+            let scope = scope_name(scope).to_string();
+            metrics::connections_by_scope(&scope).increment(1.0);
+            session.scope = Some(scope);
+        }
+        session.authed_pubkey = authed_pubkey;
+    }
+
+    /// Every live connection with its open subscriptions, longest
+    /// connected first
+    pub fn connections(&self, subscriptions: &SubscriptionRegistry) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .sessions
+            .iter()
+            .map(|session| {
+                let mut summary = session.summary.clone();
+                summary.duration_secs = session.started_at.elapsed().as_secs();
+                ConnectionInfo {
+                    connection_id: session.key().clone(),
+                    scope: session.scope.clone(),
+                    authed_pubkey: session.authed_pubkey.map(|pubkey| pubkey.to_hex()),
+                    subscriptions: subscriptions
+                        .subscriptions(session.key())
+                        .into_iter()
+                        .map(|(id, filters)| {
+                            (id, filters.iter().map(|filter| filter.as_json()).collect())
+                        })
+                        .collect(),
+                    summary,
+                }
+            })
+            .collect();
+        connections.sort_by(|a, b| {
+            b.summary
+                .duration_secs
+                .cmp(&a.summary.duration_secs)
+                .then_with(|| a.connection_id.cmp(&b.connection_id))
+        });
+        connections
+    }
+
+    /// The correlation id of a live connection
+    pub fn correlation_id(&self, connection_id: &str) -> Option<String> {
+        self.sessions
+            .get(connection_id)
+            .map(|session| session.summary.correlation_id.clone())
+    }
+
+    /// The summary of a live connection
+    pub fn summary(&self, connection_id: &str) -> Option<ConnectionSummary> {
+        self.sessions.get(connection_id).map(|session| {
+            let mut summary = session.summary.clone();
+            summary.duration_secs = session.started_at.elapsed().as_secs();
+            summary
+        })
+    }
+
+    /// Ends the session and returns its final summary
+    pub fn finish(&self, connection_id: &str) -> Option<ConnectionSummary> {
+        let summary = self.summary(connection_id);
+        if let Some((_, session)) = self.sessions.remove(connection_id) {
+            if let Some(scope) = &session.scope {
+                metrics::connections_by_scope(scope).decrement(1.0);
+            }
+        }
+        summary
+    }
+}
+
+/// Six hex digits, enough to tell concurrent connections apart in logs
+fn new_correlation_id() -> String {
+    format!("{:06x}", rand::random::<u32>() & 0xff_ffff)
+}
+
+/// Message type, subscription id and event of an inbound message, for logs
+fn describe(message: &ClientMessage) -> (&'static str, Option<String>, Option<&Event>) {
+    match message {
+        ClientMessage::Event(event) => ("EVENT", None, Some(&**event)),
+        ClientMessage::Req {
+            subscription_id, ..
+        }
+        | ClientMessage::ReqMultiFilter {
+            subscription_id, ..
+        } => ("REQ", Some(subscription_id.to_string()), None),
+        ClientMessage::Close(subscription_id) => ("CLOSE", Some(subscription_id.to_string()), None),
+        ClientMessage::Auth(event) => ("AUTH", None, Some(&**event)),
+        _ => ("OTHER", None, None),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionStatsMiddleware {
+    stats: Arc<ConnectionStats>,
+}
+
+impl ConnectionStatsMiddleware {
+    pub fn new(stats: Arc<ConnectionStats>) -> Self {
+        Self { stats }
+    }
+}
+
+impl NostrMiddleware<()> for ConnectionStatsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(message) = &ctx.message else {
+            return ctx.next().await;
+        };
+        let connection_id = ctx.connection_id.to_string();
+        self.stats.record(&connection_id, message);
+        {
+            let state = ctx.state.read().await;
+            self.stats
+                .identify(&connection_id, state.subdomain(), state.authed_pubkey);
+        }
+
+        if !tracing::enabled!(Level::DEBUG) {
+            return ctx.next().await;
+        }
+
+        let (message_type, subscription_id, event) = describe(message);
+        let event = event.map(|event| (event.id, event.kind.as_u16()));
+        let correlation_id = self
+            .stats
+            .correlation_id(&connection_id)
+            .unwrap_or_default();
+        let (scope, authed_pubkey) = {
+            let state = ctx.state.read().await;
+            (
+                format!("{:?}", state.subdomain()),
+                state.authed_pubkey.map(|pubkey| pubkey.to_hex()),
+            )
+        };
+
+        let result = ctx.next().await;
+        debug!(
+            connection_id = %connection_id,
+            correlation_id = %correlation_id,
+            message_type,
+            subscription_id = subscription_id.as_deref(),
+            event_id = event.map(|(id, _)| id.to_hex()).as_deref(),
+            event_kind = event.map(|(_, kind)| kind),
+            scope = %scope,
+            authed_pubkey = authed_pubkey.as_deref(),
+            outcome = match &result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {e}"),
+            },
+            "Inbound message"
+        );
+        result
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        let connection_id = ctx.connection_id.to_string();
+        let Some(summary) = self.stats.finish(&connection_id) else {
+            return Ok(());
+        };
+
+        info!(
+            connection_id = %connection_id,
+            correlation_id = %summary.correlation_id,
+            events = summary.events,
+            reqs = summary.reqs,
+            closes = summary.closes,
+            auths = summary.auths,
+            other = summary.other,
+            bytes_in = summary.bytes_in,
+            duration_secs = summary.duration_secs,
+            "Connection closed"
+        );
+        metrics::connection_session_duration().record(summary.duration_secs as f64);
+        metrics::connection_session_bytes_in().record(summary.bytes_in as f64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_counts_a_scripted_session() {
+        let stats = ConnectionStats::new();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let auth = EventBuilder::auth("challenge", RelayUrl::parse("wss://test.relay").unwrap())
+            .sign_with_keys(&keys)
+            .unwrap();
+        let session = [
+            ClientMessage::auth(auth),
+            ClientMessage::req(SubscriptionId::new("feed"), Filter::new().limit(10)),
+            ClientMessage::event(event.clone()),
+            ClientMessage::event(event),
+            ClientMessage::close(SubscriptionId::new("feed")),
+        ];
+
+        let mut bytes_in = 0;
+        for message in &session {
+            stats.record("conn-1", message);
+            bytes_in += message.as_json().len() as u64;
+        }
+        stats.record("conn-2", &session[1]);
+
+        let live = stats.summary("conn-1").unwrap();
+        assert_eq!(
+            live,
+            ConnectionSummary {
+                correlation_id: live.correlation_id.clone(),
+                events: 2,
+                reqs: 1,
+                closes: 1,
+                auths: 1,
+                other: 0,
+                bytes_in,
+                duration_secs: 0,
+            }
+        );
+
+        assert_eq!(stats.finish("conn-1"), Some(live));
+        assert_eq!(stats.summary("conn-1"), None);
+        assert_eq!(stats.summary("conn-2").unwrap().reqs, 1);
+    }
+
+    #[test]
+    fn test_correlation_id_is_stable_for_a_connection() {
+        let stats = ConnectionStats::new();
+        let req = ClientMessage::req(SubscriptionId::new("feed"), Filter::new().limit(10));
+        let close = ClientMessage::close(SubscriptionId::new("feed"));
+
+        stats.record("conn-1", &req);
+        let id = stats.correlation_id("conn-1").unwrap();
+        assert_eq!(id.len(), 6);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+
+        for message in [&req, &close, &req] {
+            stats.record("conn-1", message);
+            assert_eq!(stats.correlation_id("conn-1"), Some(id.clone()));
+        }
+        assert_eq!(stats.finish("conn-1").unwrap().correlation_id, id);
+
+        // A later connection reusing the id starts a new session
+        assert_eq!(stats.correlation_id("conn-1"), None);
+        stats.record("conn-1", &req);
+        assert!(stats.correlation_id("conn-1").is_some());
+    }
+
+    #[test]
+    fn test_connections_lists_live_sessions() {
+        let stats = ConnectionStats::new();
+        let subscriptions = SubscriptionRegistry::new();
+        let keys = Keys::generate();
+        let scope = Scope::named("team").unwrap();
+        let req = ClientMessage::req(SubscriptionId::new("feed"), Filter::new().limit(10));
+        let auth = EventBuilder::auth("challenge", RelayUrl::parse("wss://test.relay").unwrap())
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        // One connection authenticates and subscribes, the other only reads
+        stats.record("conn-1", &ClientMessage::auth(auth));
+        stats.identify("conn-1", &scope, None);
+        stats.record("conn-1", &req);
+        stats.identify("conn-1", &scope, Some(keys.public_key()));
+        subscriptions.add(
+            "conn-1",
+            &SubscriptionId::new("feed"),
+            vec![Filter::new().kind(Kind::Custom(9))],
+        );
+        stats.record("conn-2", &req);
+        stats.identify("conn-2", &Scope::Default, None);
+
+        let connections = stats.connections(&subscriptions);
+        assert_eq!(connections.len(), 2);
+        let conn = |id: &str| connections.iter().find(|c| c.connection_id == id).unwrap();
+        assert_eq!(conn("conn-1").scope.as_deref(), Some("team"));
+        assert_eq!(
+            conn("conn-1").authed_pubkey,
+            Some(keys.public_key().to_hex())
+        );
+        assert_eq!(conn("conn-1").summary.auths, 1);
+        assert_eq!(conn("conn-1").subscriptions["feed"].len(), 1);
+        assert_eq!(conn("conn-2").scope.as_deref(), Some("default"));
+        assert_eq!(conn("conn-2").authed_pubkey, None);
+        assert!(conn("conn-2").subscriptions.is_empty());
+
+        stats.finish("conn-1");
+        stats.finish("conn-2");
+        assert!(stats.connections(&subscriptions).is_empty());
+    }
+}
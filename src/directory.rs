@@ -0,0 +1,428 @@
+//! A relay-curated directory of each scope's public groups.
+//!
+//! Groups can only be joined by clients that already know their id. The
+//! [`GroupDirectory`] keeps one relay-signed addressable event per scope,
+//! [`KIND_GROUP_DIRECTORY_39100`] with `d` = `directory`, listing every
+//! public, non-archived group as a tag:
+//!
+//! ```text
+//! ["group", <id>, <name>, <about>, <member count>, <picture>]
+//! ```
+//!
+//! Private groups never appear. `GET /api/directory` serves the same entries
+//! as JSON, optionally narrowed with `?q=`.
+//!
+//! Creations, deletions, metadata edits and membership changes regenerate
+//! the directory of their scope. The first change within a window stores it
+//! along with the event; later ones mark the scope dirty and a flusher writes
+//! it once per window, straight to the database. Every scope is also
+//! rewritten hourly, so a directory never stays stale for long.
+
+use crate::group::{
+    Group, KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_DELETE_9008,
+    KIND_GROUP_DIRECTORY_39100, KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_REMOVE_USER_9001,
+    KIND_GROUP_USER_JOIN_REQUEST_9021, KIND_GROUP_USER_LEAVE_REQUEST_9022,
+};
+use crate::groups::Groups;
+use crate::read_only::ReadOnlyMode;
+use crate::utils::apply_store_commands;
+use crate::RelayDatabase;
+use anyhow::Result;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// The `d` tag of every directory event
+pub const DIRECTORY_IDENTIFIER: &str = "directory";
+
+/// How often every scope's directory is rewritten, changed or not
+pub const DIRECTORY_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Kinds whose acceptance can change the directory of their scope
+const LISTING_KINDS: [Kind; 7] = [
+    KIND_GROUP_CREATE_9007,
+    KIND_GROUP_DELETE_9008,
+    KIND_GROUP_EDIT_METADATA_9002,
+    KIND_GROUP_ADD_USER_9000,
+    KIND_GROUP_REMOVE_USER_9001,
+    KIND_GROUP_USER_JOIN_REQUEST_9021,
+    KIND_GROUP_USER_LEAVE_REQUEST_9022,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DirectoryEntry {
+    pub id: String,
+    pub name: String,
+    pub about: Option<String>,
+    pub picture: Option<String>,
+    pub member_count: usize,
+}
+
+impl DirectoryEntry {
+    fn from_group(group: &Group) -> Self {
+        Self {
+            id: group.id.clone(),
+            name: group.metadata.name.clone(),
+            about: group.metadata.about.clone(),
+            picture: group.metadata.picture.clone(),
+            member_count: group.members.len(),
+        }
+    }
+
+    /// Whether the name or about contains the lowercase query
+    fn matches(&self, query: &str) -> bool {
+        self.name.to_lowercase().contains(query)
+            || self
+                .about
+                .as_ref()
+                .is_some_and(|about| about.to_lowercase().contains(query))
+    }
+
+    fn to_tag(&self) -> Tag {
+        Tag::custom(
+            TagKind::custom("group"),
+            [
+                self.id.clone(),
+                self.name.clone(),
+                self.about.clone().unwrap_or_default(),
+                self.member_count.to_string(),
+                self.picture.clone().unwrap_or_default(),
+            ],
+        )
+    }
+}
+
+/// The public, non-archived groups of a scope by id, only those whose name or
+/// about contains the query, ignoring case, when there is one
+pub fn directory_entries(
+    groups: &Groups,
+    scope: &Scope,
+    query: Option<&str>,
+) -> Vec<DirectoryEntry> {
+    let query = query
+        .map(str::trim)
+        .filter(|query| !query.is_empty())
+        .map(str::to_lowercase);
+
+    let mut entries: Vec<DirectoryEntry> = groups
+        .iter()
+        .filter(|entry| &entry.key().0 == scope)
+        .filter_map(|entry| {
+            let group = entry.value().read();
+            (!group.metadata.private && !group.metadata.archived)
+                .then(|| DirectoryEntry::from_group(&group))
+        })
+        .filter(|entry| query.as_deref().is_none_or(|query| entry.matches(query)))
+        .collect();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    entries
+}
+
+pub fn generate_directory_event(
+    entries: &[DirectoryEntry],
+    relay_pubkey: PublicKey,
+) -> UnsignedEvent {
+    let mut tags = vec![Tag::identifier(DIRECTORY_IDENTIFIER)];
+    tags.extend(entries.iter().map(DirectoryEntry::to_tag));
+    UnsignedEvent::new(
+        relay_pubkey,
+        Timestamp::now(),
+        KIND_GROUP_DIRECTORY_39100,
+        tags,
+        "",
+    )
+}
+
+pub struct GroupDirectory {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    window: Duration,
+    read_only: Option<Arc<ReadOnlyMode>>,
+    /// When each scope's directory was last written
+    regenerated_at: DashMap<Scope, Instant>,
+    /// Scopes whose directory may have changed since it was last written
+    dirty: DashMap<Scope, ()>,
+}
+
+impl std::fmt::Debug for GroupDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupDirectory")
+            .field("window", &self.window)
+            .field("dirty", &self.dirty.len())
+            .finish()
+    }
+}
+
+impl GroupDirectory {
+    pub fn new(
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+        window: Duration,
+    ) -> Self {
+        Self {
+            groups,
+            database,
+            relay_keys,
+            window,
+            read_only: None,
+            regenerated_at: DashMap::new(),
+            dirty: DashMap::new(),
+        }
+    }
+
+    /// Hold dirty scopes back while the relay is read-only
+    pub fn with_read_only(mut self, read_only: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// The current directory of a scope
+    pub fn directory_event(&self, scope: &Scope) -> UnsignedEvent {
+        let entries = directory_entries(&self.groups, scope, None);
+        generate_directory_event(&entries, self.groups.relay_pubkey)
+    }
+
+    /// Adds the scope's regenerated directory to the commands of an accepted
+    /// event that can change it, or marks the scope dirty if its directory
+    /// was written less than a window ago.
+    pub fn update(&self, kind: Kind, scope: &Scope, commands: &mut Vec<StoreCommand>) {
+        if !LISTING_KINDS.contains(&kind) {
+            return;
+        }
+        let recent = self
+            .regenerated_at
+            .get(scope)
+            .is_some_and(|at| at.elapsed() < self.window);
+        if recent {
+            self.dirty.insert(scope.clone(), ());
+            return;
+        }
+
+        self.regenerated_at.insert(scope.clone(), Instant::now());
+        commands.push(StoreCommand::SaveUnsignedEvent(
+            self.directory_event(scope),
+            scope.clone(),
+            None,
+        ));
+    }
+
+    /// Writes the directory of every dirty scope, returning how many were
+    /// written.
+    pub async fn flush(&self) -> Result<usize> {
+        if self
+            .read_only
+            .as_ref()
+            .is_some_and(|mode| mode.is_enabled())
+        {
+            return Ok(0);
+        }
+
+        let scopes: Vec<Scope> = self.dirty.iter().map(|entry| entry.key().clone()).collect();
+        let mut flushed = 0;
+
+        for scope in scopes {
+            self.dirty.remove(&scope);
+            self.regenerated_at.insert(scope.clone(), Instant::now());
+            let commands = vec![StoreCommand::SaveUnsignedEvent(
+                self.directory_event(&scope),
+                scope.clone(),
+                None,
+            )];
+            if let Err(e) = apply_store_commands(&self.database, &self.relay_keys, commands).await {
+                // Try again on the next flush
+                self.dirty.insert(scope, ());
+                return Err(e);
+            }
+            flushed += 1;
+        }
+
+        Ok(flushed)
+    }
+
+    fn mark_all_dirty(&self) {
+        for scope in self.groups.get_all_scopes() {
+            self.dirty.insert(scope, ());
+        }
+    }
+
+    /// Flushes dirty scopes every window and once more on shutdown, and
+    /// rewrites every scope once all groups are loaded and then hourly.
+    pub fn spawn_flusher(self: Arc<Self>, cancellation_token: CancellationToken) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.window);
+            let mut refreshed_at: Option<Instant> = None;
+            loop {
+                let shutting_down = tokio::select! {
+                    _ = cancellation_token.cancelled() => true,
+                    _ = ticker.tick() => false,
+                };
+
+                if self.groups.is_loaded()
+                    && refreshed_at.is_none_or(|at| at.elapsed() >= DIRECTORY_REFRESH_INTERVAL)
+                {
+                    self.mark_all_dirty();
+                    refreshed_at = Some(Instant::now());
+                }
+                match self.flush().await {
+                    Ok(0) => {}
+                    Ok(flushed) => debug!("Wrote the group directory of {} scopes", flushed),
+                    Err(e) => warn!("Failed to write the group directory: {}", e),
+                }
+                if shutting_down {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::RwLock;
+
+    fn names(event: &Event) -> Vec<String> {
+        event
+            .tags
+            .filter(TagKind::custom("group"))
+            .map(|tag| tag.as_slice()[2].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_directory_lists_public_groups_only() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let directory = Arc::new(GroupDirectory::new(
+            groups.clone(),
+            database.clone(),
+            relay_keys.clone(),
+            Duration::from_secs(60),
+        ));
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_directory(directory.clone());
+        let admin = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let anonymous = EventContext {
+            authed_pubkey: None,
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let send = |kind: Kind, tags: Vec<Tag>| {
+            let event = EventBuilder::new(kind, "")
+                .tags(tags)
+                .sign_with_keys(&admin)
+                .unwrap();
+            processor.handle_event(event, Arc::new(RwLock::new(())), &context)
+        };
+        let flag = |name: &str| Tag::custom(TagKind::custom(name), Vec::<String>::new());
+        let stored_directory = || async {
+            database
+                .query(
+                    vec![Filter::new().kind(KIND_GROUP_DIRECTORY_39100)],
+                    &Scope::Default,
+                )
+                .await
+                .unwrap()
+                .first()
+                .cloned()
+                .unwrap()
+        };
+
+        for (id, name, public) in [
+            ("rust", "Rust", true),
+            ("cooking", "Cooking", true),
+            ("secret", "Secret", false),
+        ] {
+            let h = Tag::custom(TagKind::h(), [id]);
+            let commands = send(KIND_GROUP_CREATE_9007, vec![h.clone()]).await.unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+            let mut tags = vec![
+                h,
+                Tag::custom(TagKind::custom("name"), [name]),
+                Tag::custom(TagKind::custom("about"), [format!("All about {id}")]),
+            ];
+            if public {
+                tags.push(flag("public"));
+            }
+            let commands = send(KIND_GROUP_EDIT_METADATA_9002, tags).await.unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+        }
+
+        // Changes within the window are written by the flusher
+        assert_eq!(directory.flush().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let listed = stored_directory().await;
+        assert_eq!(listed.pubkey, relay_keys.public_key());
+        assert_eq!(listed.tags.identifier(), Some(DIRECTORY_IDENTIFIER));
+        assert_eq!(names(&listed), vec!["Cooking", "Rust"]);
+        assert!(processor
+            .can_see_event(&listed, Arc::new(RwLock::new(())), &anonymous)
+            .unwrap());
+
+        // The HTTP entries match, and can be searched
+        let entries = directory_entries(&groups, &Scope::Default, None);
+        assert_eq!(
+            entries.iter().map(|entry| &entry.id).collect::<Vec<_>>(),
+            vec!["cooking", "rust"]
+        );
+        assert_eq!(entries[0].member_count, 1);
+        let found = directory_entries(&groups, &Scope::Default, Some("ABOUT RUST"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, "rust");
+        assert!(directory_entries(&groups, &Scope::Default, Some("secret")).is_empty());
+
+        // Renaming regenerates the entry, with a newer timestamp
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        send(
+            KIND_GROUP_EDIT_METADATA_9002,
+            vec![
+                Tag::custom(TagKind::h(), ["rust"]),
+                Tag::custom(TagKind::custom("name"), ["Rustaceans"]),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(directory.flush().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(
+            names(&stored_directory().await),
+            vec!["Cooking", "Rustaceans"]
+        );
+
+        // Clients can't publish a directory of their own
+        assert!(send(
+            KIND_GROUP_DIRECTORY_39100,
+            vec![Tag::identifier(DIRECTORY_IDENTIFIER)]
+        )
+        .await
+        .is_err());
+    }
+}
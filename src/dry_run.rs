@@ -0,0 +1,440 @@
+//! Dry runs of group management events.
+//!
+//! A management event carrying a `dry-run` tag is validated and applied to a
+//! copy of its group instead of the group itself. Nothing is stored; the
+//! relay rejects the event with an `error:` message describing what it would
+//! change, or the error it would fail with. A dry-run group creation is
+//! checked against the loaded groups only, not against the stored events of
+//! deleted or unmanaged groups with the same id.
+
+use crate::groups::{
+    Group, Groups, KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_CREATE_INVITE_9009,
+    KIND_GROUP_DEFINE_ROLES_9003, KIND_GROUP_DELETE_9008, KIND_GROUP_DELETE_EVENT_9005,
+    KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_REMOVE_USER_9001, KIND_GROUP_SET_ROLES_9006,
+};
+use crate::StoreCommand;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+pub const DRY_RUN_TAG: &str = "dry-run";
+
+/// Moderation kinds a `dry-run` tag is honored on, so it is never ignored
+const MODERATION_KINDS: [Kind; 9] = [
+    KIND_GROUP_ADD_USER_9000,
+    KIND_GROUP_REMOVE_USER_9001,
+    KIND_GROUP_EDIT_METADATA_9002,
+    KIND_GROUP_DEFINE_ROLES_9003,
+    KIND_GROUP_DELETE_EVENT_9005,
+    KIND_GROUP_SET_ROLES_9006,
+    KIND_GROUP_CREATE_9007,
+    KIND_GROUP_DELETE_9008,
+    KIND_GROUP_CREATE_INVITE_9009,
+];
+
+/// Whether the event is a moderation event asking for a dry run
+pub fn is_dry_run(event: &Event) -> bool {
+    MODERATION_KINDS.contains(&event.kind)
+        && event.tags.iter().any(|tag| {
+            tag.as_slice()
+                .first()
+                .is_some_and(|name| name == DRY_RUN_TAG)
+        })
+}
+
+/// What applying a management event would change in its group
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GroupDiff {
+    pub group_id: String,
+    pub added: BTreeSet<PublicKey>,
+    pub removed: BTreeSet<PublicKey>,
+    /// Roles before and after, for members that stay
+    pub role_changes: BTreeMap<PublicKey, (BTreeSet<String>, BTreeSet<String>)>,
+    pub metadata_changes: Vec<String>,
+    /// Kinds of the state events that would be regenerated
+    pub state_events: BTreeSet<u16>,
+    /// Number of event deletions that would run
+    pub deletions: usize,
+    /// Number of invites that would be created
+    pub invites: usize,
+}
+
+impl GroupDiff {
+    pub fn between(before: &Group, after: &Group, commands: &[StoreCommand]) -> Self {
+        let roles = |group: &Group, pubkey: &PublicKey| -> BTreeSet<String> {
+            group.members[pubkey]
+                .roles
+                .iter()
+                .map(|role| role.name().to_string())
+                .collect()
+        };
+
+        let mut diff = Self {
+            group_id: before.id.clone(),
+            invites: after
+                .invites
+                .keys()
+                .filter(|code| !before.invites.contains_key(*code))
+                .count(),
+            ..Default::default()
+        };
+        for pubkey in after.members.keys() {
+            if !before.members.contains_key(pubkey) {
+                diff.added.insert(*pubkey);
+            }
+        }
+        for pubkey in before.members.keys() {
+            if !after.members.contains_key(pubkey) {
+                diff.removed.insert(*pubkey);
+                continue;
+            }
+            let (old, new) = (roles(before, pubkey), roles(after, pubkey));
+            if old != new {
+                diff.role_changes.insert(*pubkey, (old, new));
+            }
+        }
+
+        let (old_meta, new_meta) = (&before.metadata, &after.metadata);
+        let mut changed = |field: &str, old: String, new: String| {
+            if old != new {
+                diff.metadata_changes
+                    .push(format!("{field}: {old:?} -> {new:?}"));
+            }
+        };
+        changed("name", old_meta.name.clone(), new_meta.name.clone());
+        changed(
+            "about",
+            old_meta.about.clone().unwrap_or_default(),
+            new_meta.about.clone().unwrap_or_default(),
+        );
+        changed(
+            "picture",
+            old_meta.picture.clone().unwrap_or_default(),
+            new_meta.picture.clone().unwrap_or_default(),
+        );
+        changed(
+            "private",
+            old_meta.private.to_string(),
+            new_meta.private.to_string(),
+        );
+        changed(
+            "closed",
+            old_meta.closed.to_string(),
+            new_meta.closed.to_string(),
+        );
+        changed(
+            "broadcast",
+            old_meta.is_broadcast.to_string(),
+            new_meta.is_broadcast.to_string(),
+        );
+
+        for command in commands {
+            match command {
+                StoreCommand::SaveUnsignedEvent(event, _, _) => {
+                    diff.state_events.insert(event.kind.as_u16());
+                }
+                StoreCommand::DeleteEvents(_, _, _) => diff.deletions += 1,
+                StoreCommand::SaveSignedEvent(_, _, _) => {}
+            }
+        }
+
+        diff
+    }
+}
+
+impl fmt::Display for GroupDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pubkeys = |pubkeys: &BTreeSet<PublicKey>| {
+            pubkeys
+                .iter()
+                .map(|pubkey| pubkey.to_hex())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let mut changes = Vec::new();
+        if !self.added.is_empty() {
+            changes.push(format!(
+                "add {} members ({})",
+                self.added.len(),
+                pubkeys(&self.added)
+            ));
+        }
+        if !self.removed.is_empty() {
+            changes.push(format!(
+                "remove {} members ({})",
+                self.removed.len(),
+                pubkeys(&self.removed)
+            ));
+        }
+        for (pubkey, (old, new)) in &self.role_changes {
+            changes.push(format!("change roles of {pubkey} from {old:?} to {new:?}"));
+        }
+        for change in &self.metadata_changes {
+            changes.push(format!("set {change}"));
+        }
+        if self.deletions > 0 {
+            changes.push(format!("run {} deletions", self.deletions));
+        }
+        if self.invites > 0 {
+            changes.push(format!("add {} invites", self.invites));
+        }
+        if !self.state_events.is_empty() {
+            let kinds: Vec<String> = self.state_events.iter().map(u16::to_string).collect();
+            changes.push(format!("regenerate {}", kinds.join(", ")));
+        }
+
+        if changes.is_empty() {
+            write!(f, "dry-run: group {} would not change", self.group_id)
+        } else {
+            write!(
+                f,
+                "dry-run: group {} would {}",
+                self.group_id,
+                changes.join("; ")
+            )
+        }
+    }
+}
+
+/// Applies the event to a copy of its group and returns the difference
+pub fn simulate(groups: &Groups, event: &Event, scope: &Scope) -> Result<GroupDiff, Error> {
+    let relay_keys = groups.relay_keys();
+    if event.kind == KIND_GROUP_CREATE_9007 {
+        if groups.find_group_from_event(event, scope).is_some() {
+            return Err(Error::notice("dry-run: group already exists"));
+        }
+        let after = Group::new(event, scope.clone())?;
+        let commands = after
+            .generate_all_state_events(relay_keys, &groups.relay_url)?
+            .into_iter()
+            .map(|e| StoreCommand::SaveUnsignedEvent(e, scope.clone(), None))
+            .collect::<Vec<_>>();
+        return Ok(GroupDiff::between(
+            &Group::new_with_id(after.id.clone()),
+            &after,
+            &commands,
+        ));
+    }
+
+    let before = groups
+        .find_group_from_event(event, scope)
+        .map(|group| group.value().clone())
+        .ok_or_else(|| Error::notice("dry-run: group not found"))?;
+    let mut after = before.clone();
+    let event = Box::new(event.clone());
+
+    let commands = match event.kind {
+        k if k == KIND_GROUP_ADD_USER_9000 => after.add_members_from_event(event, relay_keys)?,
+        k if k == KIND_GROUP_REMOVE_USER_9001 => after.remove_members(event, relay_keys)?,
+        k if k == KIND_GROUP_EDIT_METADATA_9002 => {
+            after.set_metadata(&event, relay_keys)?;
+            after
+                .generate_metadata_events(relay_keys, &groups.relay_url)
+                .into_iter()
+                .map(|e| StoreCommand::SaveUnsignedEvent(e, scope.clone(), None))
+                .collect()
+        }
+        k if k == KIND_GROUP_DEFINE_ROLES_9003 => after.define_roles(event, relay_keys)?,
+        k if k == KIND_GROUP_DELETE_EVENT_9005 => after.delete_event_request(event, relay_keys)?,
+        k if k == KIND_GROUP_SET_ROLES_9006 => after.set_roles(event, relay_keys)?,
+        k if k == KIND_GROUP_DELETE_9008 => {
+            let commands = before.delete_group_request(event, relay_keys)?;
+            after.members.clear();
+            commands
+        }
+        k if k == KIND_GROUP_CREATE_INVITE_9009 => {
+            after.create_invite(&event, relay_keys)?;
+            Vec::new()
+        }
+        kind => {
+            return Err(Error::notice(format!(
+                "dry-run: kind {kind} can't be simulated, nothing was stored"
+            )))
+        }
+    };
+
+    Ok(GroupDiff::between(&before, &after, &commands))
+}
+
+/// The message answering a dry run: the changes, or why the event would fail
+pub fn describe(groups: &Groups, event: &Event, scope: &Scope) -> String {
+    match simulate(groups, event, scope) {
+        Ok(diff) => diff.to_string(),
+        Err(e) => format!("dry-run: would fail: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_dry_run_removal_describes_diff_without_changing_state() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database,
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let admin = Keys::generate();
+        let members: Vec<Keys> = (0..4).map(|_| Keys::generate()).collect();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["dry_run_group"]);
+        let sign = |kind: Kind, tags: Vec<Tag>| {
+            EventBuilder::new(kind, "")
+                .tags(tags)
+                .sign_with_keys(&admin)
+                .unwrap()
+        };
+
+        let create = sign(KIND_GROUP_CREATE_9007, vec![h_tag.clone()]);
+        let mut add_tags = vec![h_tag.clone()];
+        add_tags.extend(
+            members
+                .iter()
+                .map(|keys| Tag::public_key(keys.public_key())),
+        );
+        let add = sign(KIND_GROUP_ADD_USER_9000, add_tags);
+        for event in [create, add] {
+            processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+        }
+
+        let removed: BTreeSet<PublicKey> =
+            members[..3].iter().map(|keys| keys.public_key()).collect();
+        let mut remove_tags = vec![
+            h_tag,
+            Tag::custom(TagKind::custom(DRY_RUN_TAG), &[] as &[String]),
+        ];
+        remove_tags.extend(removed.iter().map(|pubkey| Tag::public_key(*pubkey)));
+        let remove = sign(KIND_GROUP_REMOVE_USER_9001, remove_tags);
+        assert!(is_dry_run(&remove));
+
+        let diff = simulate(&groups, &remove, &Scope::Default).unwrap();
+        assert_eq!(diff.removed, removed);
+        assert!(diff.added.is_empty());
+        assert!(diff.role_changes.is_empty());
+        assert_eq!(diff.state_events, BTreeSet::from([39002]));
+        let notice = diff.to_string();
+        assert!(notice.contains("remove 3 members"), "{notice}");
+
+        // Through the processor the diff is the rejection, nothing is stored
+        // and the group is unchanged
+        let error = processor
+            .handle_event(remove, Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().ends_with(&format!("error: {notice}")),
+            "{error}"
+        );
+        let group = groups.get_group(&Scope::Default, "dry_run_group").unwrap();
+        for keys in &members {
+            assert!(group.value().is_member(&keys.public_key()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_dry_run_kind_is_simulated() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Groups::load_groups(
+            database,
+            relay_keys.public_key(),
+            "wss://test.relay".to_string(),
+        )
+        .await
+        .unwrap();
+        let admin = Keys::generate();
+        let member = Keys::generate();
+        let h_tag = Tag::custom(TagKind::h(), ["dry_run_group"]);
+        let dry_run_tag = Tag::custom(TagKind::custom(DRY_RUN_TAG), &[] as &[String]);
+        let sign = |kind: Kind, tags: Vec<Tag>| {
+            EventBuilder::new(kind, "")
+                .tags(tags)
+                .sign_with_keys(&admin)
+                .unwrap()
+        };
+
+        let create = sign(
+            KIND_GROUP_CREATE_9007,
+            vec![h_tag.clone(), dry_run_tag.clone()],
+        );
+        let diff = simulate(&groups, &create, &Scope::Default).unwrap();
+        assert_eq!(diff.added, BTreeSet::from([admin.public_key()]));
+        assert!(diff.state_events.contains(&39000));
+        assert!(groups.get_group(&Scope::Default, "dry_run_group").is_none());
+
+        let create = sign(KIND_GROUP_CREATE_9007, vec![h_tag.clone()]);
+        groups
+            .handle_group_create(Box::new(create.clone()), &Scope::Default)
+            .await
+            .unwrap();
+        let add = sign(
+            KIND_GROUP_ADD_USER_9000,
+            vec![h_tag.clone(), Tag::public_key(member.public_key())],
+        );
+        groups
+            .handle_put_user(Box::new(add), &Scope::Default)
+            .unwrap();
+
+        let recreate = sign(
+            KIND_GROUP_CREATE_9007,
+            vec![h_tag.clone(), dry_run_tag.clone()],
+        );
+        assert_eq!(
+            describe(&groups, &recreate, &Scope::Default),
+            format!(
+                "dry-run: would fail: {}",
+                Error::notice("dry-run: group already exists")
+            )
+        );
+
+        let delete = sign(
+            KIND_GROUP_DELETE_EVENT_9005,
+            vec![h_tag.clone(), dry_run_tag.clone(), Tag::event(create.id)],
+        );
+        assert_eq!(
+            simulate(&groups, &delete, &Scope::Default)
+                .unwrap()
+                .deletions,
+            1
+        );
+
+        let invite = sign(
+            KIND_GROUP_CREATE_INVITE_9009,
+            vec![
+                h_tag,
+                dry_run_tag,
+                Tag::custom(TagKind::custom("code"), ["secret"]),
+            ],
+        );
+        let diff = simulate(&groups, &invite, &Scope::Default).unwrap();
+        assert_eq!(diff.invites, 1);
+        assert_eq!(
+            diff.to_string(),
+            "dry-run: group dry_run_group would add 1 invites"
+        );
+        let group = groups.get_group(&Scope::Default, "dry_run_group").unwrap();
+        assert!(group.value().invites.is_empty());
+        assert!(group.value().is_member(&member.public_key()));
+    }
+}
@@ -0,0 +1,282 @@
+//! Early answers to events the relay already stores.
+//!
+//! Clients republish their events after reconnecting, and running those
+//! through the processor again can regenerate group state or fail with errors
+//! like "User is already a member". [`DuplicateEventsMiddleware`] looks the id
+//! up in the event's scope before the processor sees it, and answers a stored
+//! event with an `OK true` and a `duplicate:` message instead. Only events
+//! whose id and signature check out, and that the sender can read, are
+//! answered early, so the answer never confirms that an event of a group
+//! the sender can't read exists.
+//!
+//! Ids found stored are kept in a bounded cache keyed by scope, so a burst of
+//! republished events only reaches the database once per id. Only ids found
+//! in the database are cached: a rejected event can be sent again once
+//! whatever rejected it is fixed. An event whose save is still queued when
+//! it's sent again goes through the processor as before.
+
+use crate::groups_event_processor::GroupsRelayProcessor;
+use crate::utils::scope_name;
+use crate::RelayDatabase;
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use relay_builder::{EventContext, EventProcessor};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+pub const DUPLICATE_EVENT: &str = "duplicate: already have this event";
+
+/// Stored ids remembered across all scopes
+pub const DUPLICATE_CACHE_SIZE: usize = 50_000;
+
+#[derive(Debug)]
+pub struct StoredEvents {
+    database: Arc<RelayDatabase>,
+    cache: Mutex<LruCache<(Scope, EventId), ()>>,
+}
+
+impl StoredEvents {
+    pub fn new(database: Arc<RelayDatabase>, capacity: usize) -> Self {
+        Self {
+            database,
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("capacity is non-zero"),
+            )),
+        }
+    }
+
+    /// Whether the event is stored in the scope. A failed lookup counts as
+    /// not stored, leaving the event to the processor.
+    pub async fn contains(&self, scope: &Scope, id: &EventId) -> bool {
+        let key = (scope.clone(), *id);
+        if self.cache.lock().get(&key).is_some() {
+            return true;
+        }
+
+        match self
+            .database
+            .query(vec![Filter::new().id(*id).limit(1)], scope)
+            .await
+        {
+            Ok(events) if !events.is_empty() => {
+                self.cache.lock().put(key, ());
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!(
+                    "Failed to look up event {} in scope {}: {}",
+                    id,
+                    scope_name(scope),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DuplicateEventsMiddleware {
+    stored: Arc<StoredEvents>,
+    processor: Arc<GroupsRelayProcessor>,
+}
+
+impl DuplicateEventsMiddleware {
+    pub fn new(stored: Arc<StoredEvents>, processor: Arc<GroupsRelayProcessor>) -> Self {
+        Self { stored, processor }
+    }
+
+    /// Whether the sender may be told the event is stored: it has to be
+    /// the genuine event, and one they could read anyway
+    fn can_confirm(&self, event: &Event, scope: &Scope, authed_pubkey: Option<PublicKey>) -> bool {
+        if event.verify().is_err() {
+            return false;
+        }
+        let context = EventContext {
+            authed_pubkey,
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: *self.processor.relay_pubkey(),
+        };
+        self.processor
+            .can_see_event(event, Arc::new(RwLock::new(())), &context)
+            .unwrap_or(false)
+    }
+}
+
+impl NostrMiddleware<()> for DuplicateEventsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(ClientMessage::Event(event)) = &ctx.message else {
+            return ctx.next().await;
+        };
+        let (scope, authed_pubkey) = {
+            let state = ctx.state.read().await;
+            (state.subdomain().clone(), state.authed_pubkey)
+        };
+
+        // Anything else goes on to the processor, which answers it the same
+        // way whether or not the id is stored
+        if self.stored.contains(&scope, &event.id).await
+            && self.can_confirm(event, &scope, authed_pubkey)
+        {
+            debug!(
+                "[{}] Event {} is already stored in scope {}",
+                ctx.connection_id,
+                event.id,
+                scope_name(&scope)
+            );
+            ctx.send_message(RelayMessage::ok(event.id, true, DUPLICATE_EVENT))?;
+            return Ok(());
+        }
+
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_MEMBERS_39002,
+        KIND_GROUP_USER_JOIN_REQUEST_9021,
+    };
+    use crate::test_utils::{group_event, setup_test, TestRelay};
+    use std::time::Duration;
+
+    async fn start_relay() -> (tempfile::TempDir, TestRelay, Arc<StoredEvents>) {
+        let (tmp_dir, database, keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                keys.public_key(),
+                "ws://127.0.0.1".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups, keys.public_key());
+        let stored = Arc::new(StoredEvents::new(database.clone(), 16));
+        let middleware =
+            DuplicateEventsMiddleware::new(stored.clone(), Arc::new(processor.clone()));
+        let relay = TestRelay::start_with_middleware(processor, middleware, database, keys)
+            .await
+            .unwrap();
+        (tmp_dir, relay, stored)
+    }
+
+    async fn member_list(database: &RelayDatabase) -> Option<EventId> {
+        database
+            .query(
+                vec![Filter::new().kind(KIND_GROUP_MEMBERS_39002)],
+                &Scope::Default,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .next()
+            .map(|event| event.id)
+    }
+
+    #[tokio::test]
+    async fn test_republished_events_skip_the_processor() {
+        let (_tmp_dir, relay, stored) = start_relay().await;
+        let admin = Keys::generate();
+        let joiner = Keys::generate();
+        let h = Tag::custom(TagKind::h(), ["open_group"]);
+
+        let create = group_event(&admin, KIND_GROUP_CREATE_9007, "open_group", "");
+        let open = EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "")
+            .tags([
+                h.clone(),
+                Tag::custom(TagKind::custom("open"), &[] as &[String]),
+            ])
+            .sign_with_keys(&admin)
+            .unwrap();
+        let join = group_event(&joiner, KIND_GROUP_USER_JOIN_REQUEST_9021, "open_group", "");
+        let message = group_event(&joiner, Kind::Custom(9), "open_group", "hello");
+
+        let mut admin_client = relay.connect().await.unwrap();
+        admin_client.auth_as(&admin).await.unwrap();
+        admin_client.publish(&create).await.unwrap();
+        admin_client.publish(&open).await.unwrap();
+        let mut joiner_client = relay.connect().await.unwrap();
+        joiner_client.auth_as(&joiner).await.unwrap();
+        joiner_client.publish(&join).await.unwrap();
+        joiner_client.publish(&message).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let members = member_list(&relay.database).await;
+        assert!(members.is_some());
+
+        // The second submissions are answered as duplicates, without
+        // regenerating the member list
+        for event in [&join, &message] {
+            joiner_client.send_event(event).await.unwrap();
+            assert_eq!(
+                joiner_client.expect_ok(event.id).await.unwrap(),
+                (true, DUPLICATE_EVENT.to_string())
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(member_list(&relay.database).await, members);
+
+        // Ids are looked up in the event's scope
+        let other = Scope::named("other").unwrap();
+        assert!(!stored.contains(&other, &message.id).await);
+        assert!(stored.contains(&Scope::Default, &message.id).await);
+    }
+
+    #[tokio::test]
+    async fn test_private_and_forged_events_are_not_confirmed() {
+        let (_tmp_dir, relay, _stored) = start_relay().await;
+        let admin = Keys::generate();
+        let mut admin_client = relay.connect().await.unwrap();
+        admin_client.auth_as(&admin).await.unwrap();
+        // Groups are private unless made public
+        admin_client
+            .publish(&group_event(&admin, KIND_GROUP_CREATE_9007, "secret", ""))
+            .await
+            .unwrap();
+        let message = group_event(&admin, Kind::Custom(9), "secret", "hush");
+        admin_client.publish(&message).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // An outsider resending the id learns nothing from the answer
+        let mut outsider = relay.connect().await.unwrap();
+        outsider.send_event(&message).await.unwrap();
+        let (_, answer) = outsider.expect_ok(message.id).await.unwrap();
+        assert_ne!(answer, DUPLICATE_EVENT);
+
+        // Nor does anyone with a stored id and someone else's signature
+        let other = group_event(&admin, Kind::Custom(9), "secret", "other");
+        let forged = Event::new(
+            message.id,
+            message.pubkey,
+            message.created_at,
+            message.kind,
+            message.tags.clone(),
+            "forged",
+            other.sig,
+        );
+        admin_client.send_event(&forged).await.unwrap();
+        let (_, answer) = admin_client.expect_ok(forged.id).await.unwrap();
+        assert_ne!(answer, DUPLICATE_EVENT);
+
+        // The member gets the early answer
+        admin_client.send_event(&message).await.unwrap();
+        assert_eq!(
+            admin_client.expect_ok(message.id).await.unwrap(),
+            (true, DUPLICATE_EVENT.to_string())
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::group::GroupError;
+use crate::rejections::ReasonCode;
 use anyhow::Result;
 use nostr_database::DatabaseError;
 use nostr_sdk::client::Error as NostrSdkError;
@@ -36,6 +38,18 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("invalid: {message}"))]
+    Invalid {
+        message: String,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("rate-limited: {message}"))]
+    RateLimited {
+        message: String,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Internal error: {message}"))]
     Internal {
         message: String,
@@ -91,6 +105,86 @@ impl Error {
             backtrace: Backtrace::capture(),
         }
     }
+
+    /// A malformed event: wrong kind, missing or bad tags
+    pub fn invalid<S: Into<String>>(message: S) -> Self {
+        Error::Invalid {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    pub fn rate_limited<S: Into<String>>(message: S) -> Self {
+        Error::RateLimited {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// The message sent to the client, with exactly one NIP-01 prefix
+    pub fn client_message(&self) -> String {
+        match self {
+            Error::Notice { message, .. } => format!("error: {message}"),
+            Error::AuthRequired { message, .. } => format!("auth-required: {message}"),
+            Error::Restricted { message, .. } => format!("restricted: {message}"),
+            Error::Duplicate { message, .. } => format!("duplicate: {message}"),
+            Error::Invalid { message, .. } => format!("invalid: {message}"),
+            Error::RateLimited { message, .. } => format!("rate-limited: {message}"),
+            Error::Internal { .. } | Error::NostrSdk { .. } => "error: internal error".to_string(),
+        }
+    }
+}
+
+/// NIP-01 prefixed rejections `relay_builder::Error` has no constructor for
+///
+/// relay_builder adds the `restricted:`, `auth-required:` and `duplicate:`
+/// prefixes itself but sends notices verbatim, so these put the prefix in the
+/// notice. Messages never carry a prefix of their own.
+pub trait Rejection {
+    /// A malformed event: wrong kind, missing or bad tags
+    fn invalid<S: Into<String>>(message: S) -> Self;
+
+    fn rate_limited<S: Into<String>>(message: S) -> Self;
+
+    /// A rejection that isn't about the event itself
+    fn failed<S: Into<String>>(message: S) -> Self;
+
+    /// An action the author has to pay for first
+    fn payment_required<S: Into<String>>(message: S) -> Self;
+
+    /// Why the error turns an event down, from its variant or from the
+    /// prefix the constructors above put in a notice
+    fn reason_code(&self) -> ReasonCode;
+}
+
+impl Rejection for relay_builder::Error {
+    fn invalid<S: Into<String>>(message: S) -> Self {
+        relay_builder::Error::notice(format!("invalid: {}", message.into()))
+    }
+
+    fn rate_limited<S: Into<String>>(message: S) -> Self {
+        relay_builder::Error::notice(format!("rate-limited: {}", message.into()))
+    }
+
+    fn failed<S: Into<String>>(message: S) -> Self {
+        relay_builder::Error::notice(format!("error: {}", message.into()))
+    }
+
+    fn payment_required<S: Into<String>>(message: S) -> Self {
+        relay_builder::Error::notice(format!("payment-required: {}", message.into()))
+    }
+
+    fn reason_code(&self) -> ReasonCode {
+        match self {
+            relay_builder::Error::AuthRequired { .. } => ReasonCode::AuthRequired,
+            relay_builder::Error::Restricted { .. } => ReasonCode::Restricted,
+            relay_builder::Error::Duplicate { .. } => ReasonCode::Duplicate,
+            // Errors tied to a specific event, like a missing group
+            relay_builder::Error::EventError { .. } => ReasonCode::Invalid,
+            relay_builder::Error::Notice { message, .. } => ReasonCode::from_prefix(message),
+            _ => ReasonCode::Internal,
+        }
+    }
 }
 
 impl From<NostrSdkError> for Error {
@@ -122,6 +216,21 @@ impl From<NostrSdkError> for Error {
     }
 }
 
+/// Same mapping as into `relay_builder::Error`, so [`Error::client_message`]
+/// shows what the client receives
+impl From<GroupError> for Error {
+    fn from(error: GroupError) -> Self {
+        match error {
+            GroupError::NotFound(message) | GroupError::ValidationFailed(message) => {
+                Error::invalid(message)
+            }
+            GroupError::PermissionDenied(message) => Error::restricted(message),
+            GroupError::InvalidState(message) => Error::notice(message),
+            GroupError::Internal(error) => Error::internal(error.to_string()),
+        }
+    }
+}
+
 impl From<DatabaseError> for Error {
     fn from(error: DatabaseError) -> Self {
         Error::Internal {
@@ -142,50 +251,11 @@ impl Error {
         state: &mut NostrConnectionState,
         subscription_id: SubscriptionId,
     ) -> Vec<RelayMessage<'static>> {
+        self.log();
+        let closed = RelayMessage::closed(subscription_id, Cow::Owned(self.client_message()));
         match self {
-            Error::Notice { message, .. } => {
-                warn!("Notice: {}", message);
-                vec![RelayMessage::closed(
-                    subscription_id,
-                    Cow::Owned(message.clone()),
-                )]
-            }
-            Error::AuthRequired { message, .. } => {
-                warn!("Auth required: {}", message);
-                let challenge_event = state.get_challenge_event();
-                vec![
-                    challenge_event,
-                    RelayMessage::closed(subscription_id, Cow::Owned(message.clone())),
-                ]
-            }
-            Error::Restricted { message, .. } => {
-                warn!("Restricted: {}", message);
-                vec![RelayMessage::closed(
-                    subscription_id,
-                    Cow::Owned(message.clone()),
-                )]
-            }
-            Error::Duplicate { message, .. } => {
-                warn!("Duplicate: {}", message);
-                vec![RelayMessage::closed(
-                    subscription_id,
-                    Cow::Owned(message.clone()),
-                )]
-            }
-            Error::Internal { message, .. } => {
-                error!("Internal error: {}", message);
-                vec![RelayMessage::closed(
-                    subscription_id,
-                    Cow::Owned("Internal error".to_string()),
-                )]
-            }
-            Error::NostrSdk { message, .. } => {
-                error!("Nostr SDK error: {}", message);
-                vec![RelayMessage::closed(
-                    subscription_id,
-                    Cow::Owned("Nostr SDK error".to_string()),
-                )]
-            }
+            Error::AuthRequired { .. } => vec![state.get_challenge_event(), closed],
+            _ => vec![closed],
         }
     }
 
@@ -194,49 +264,18 @@ impl Error {
         state: &mut NostrConnectionState,
         event_id: EventId,
     ) -> Vec<RelayMessage<'static>> {
+        self.log();
+        let ok = RelayMessage::ok(event_id, false, Cow::Owned(self.client_message()));
         match self {
-            Error::Notice { message, .. } => {
-                vec![RelayMessage::ok(
-                    event_id,
-                    false,
-                    Cow::Owned(message.clone()),
-                )]
-            }
-            Error::AuthRequired { message, .. } => {
-                let challenge_event = state.get_challenge_event();
-                let msg = format!("auth-required: {message}");
-                vec![
-                    challenge_event,
-                    RelayMessage::ok(event_id, false, Cow::Owned(msg)),
-                ]
-            }
-            Error::Restricted { message, .. } => {
-                let msg = format!("restricted: {message}");
-                vec![RelayMessage::ok(event_id, false, Cow::Owned(msg))]
-            }
-            Error::Duplicate { message, .. } => {
-                vec![RelayMessage::ok(
-                    event_id,
-                    false,
-                    Cow::Owned(message.clone()),
-                )]
-            }
-            Error::Internal { message, .. } => {
-                error!("Internal error: {}", message);
-                vec![RelayMessage::ok(
-                    event_id,
-                    false,
-                    Cow::Owned("error: Internal error".to_string()),
-                )]
-            }
-            Error::NostrSdk { message, .. } => {
-                error!("Nostr SDK error: {}", message);
-                vec![RelayMessage::ok(
-                    event_id,
-                    false,
-                    Cow::Owned("error: Internal error".to_string()),
-                )]
-            }
+            Error::AuthRequired { .. } => vec![state.get_challenge_event(), ok],
+            _ => vec![ok],
+        }
+    }
+
+    fn log(&self) {
+        match self {
+            Error::Internal { .. } | Error::NostrSdk { .. } => error!("{}", self),
+            _ => warn!("{}", self),
         }
     }
 
@@ -268,3 +307,61 @@ impl Error {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_errors_have_one_prefix() {
+        let cases = [
+            (
+                GroupError::NotFound("Group g not found".to_string()),
+                "invalid: Group g not found",
+            ),
+            (
+                GroupError::PermissionDenied("Only admins can do that".to_string()),
+                "restricted: Only admins can do that",
+            ),
+            (
+                GroupError::ValidationFailed("Invalid webhook URL".to_string()),
+                "invalid: Invalid webhook URL",
+            ),
+            (
+                GroupError::InvalidState("Group is being moved".to_string()),
+                "error: Group is being moved",
+            ),
+            (
+                GroupError::Internal(anyhow::anyhow!("disk full")),
+                "error: internal error",
+            ),
+        ];
+
+        for (group_error, expected) in cases {
+            assert_eq!(Error::from(group_error).client_message(), expected);
+        }
+    }
+
+    #[test]
+    fn test_client_messages_have_one_prefix() {
+        let cases = [
+            (Error::notice("boom"), "error: boom"),
+            (Error::auth_required("sign in"), "auth-required: sign in"),
+            (
+                Error::restricted("members only"),
+                "restricted: members only",
+            ),
+            (
+                Error::duplicate("already a member"),
+                "duplicate: already a member",
+            ),
+            (Error::invalid("missing h tag"), "invalid: missing h tag"),
+            (Error::rate_limited("slow down"), "rate-limited: slow down"),
+            (Error::internal("disk full"), "error: internal error"),
+        ];
+
+        for (error, expected) in cases {
+            assert_eq!(error.client_message(), expected);
+        }
+    }
+}
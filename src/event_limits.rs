@@ -0,0 +1,250 @@
+//! Per-event size limits and signatures.
+//!
+//! EVENT messages whose serialized event is larger than `max_event_size`,
+//! that carry more than `max_tags` tags, or whose content is longer than
+//! `max_content_length` bytes are answered with an OK false before they reach
+//! validation or the database. Events within the limits then have their id
+//! and signature checked, so forged events are turned down, and counted as
+//! rejections, before anything else looks at them.
+//!
+//! Adding or removing members (kinds 9000 and 9001) takes one `p` tag per
+//! member, so an admin importing a community can list up to `max_member_tags`
+//! members in a single event, and the size limit grows to fit them.
+//!
+//! Frame size, non-UTF8 and malformed JSON frames are handled by
+//! websocket_builder before a message is parsed, so they aren't visible here.
+
+use crate::groups::{KIND_GROUP_ADD_USER_9000, KIND_GROUP_REMOVE_USER_9001};
+use crate::rejections::{ReasonCode, Rejections};
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::Arc;
+use tracing::debug;
+
+pub const EVENT_TOO_LARGE: &str = "invalid: event too large";
+pub const TOO_MANY_TAGS: &str = "invalid: too many tags";
+pub const CONTENT_TOO_LONG: &str = "invalid: content too long";
+pub const INVALID_SIGNATURE: &str = "invalid: event signature or id is invalid";
+
+/// Room left for each member of a membership change, enough for a `p` tag
+/// with a relay hint and role.
+const MEMBER_TAG_SIZE: usize = 160;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EventLimits {
+    pub max_event_size: usize,
+    pub max_tags: usize,
+    pub max_content_length: usize,
+    /// Tags allowed on kinds 9000 and 9001
+    pub max_member_tags: usize,
+}
+
+impl EventLimits {
+    /// Checks an event against the limits, returning the OK reason on failure.
+    pub fn check(&self, event: &Event) -> Result<(), &'static str> {
+        let membership =
+            event.kind == KIND_GROUP_ADD_USER_9000 || event.kind == KIND_GROUP_REMOVE_USER_9001;
+        let (max_tags, max_event_size) = if membership {
+            (
+                self.max_member_tags.max(self.max_tags),
+                self.max_event_size
+                    .max(self.max_member_tags.saturating_mul(MEMBER_TAG_SIZE)),
+            )
+        } else {
+            (self.max_tags, self.max_event_size)
+        };
+
+        if event.tags.len() > max_tags {
+            return Err(TOO_MANY_TAGS);
+        }
+
+        if event.content.len() > self.max_content_length {
+            return Err(CONTENT_TOO_LONG);
+        }
+
+        if event.as_json().len() > max_event_size {
+            return Err(EVENT_TOO_LARGE);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventLimitsMiddleware {
+    limits: EventLimits,
+    rejections: Option<Arc<Rejections>>,
+}
+
+impl EventLimitsMiddleware {
+    pub fn new(limits: EventLimits) -> Self {
+        Self {
+            limits,
+            rejections: None,
+        }
+    }
+
+    /// Records the events turned down
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+}
+
+impl NostrMiddleware<()> for EventLimitsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(ClientMessage::Event(event)) = &ctx.message else {
+            return ctx.next().await;
+        };
+
+        let checked = self
+            .limits
+            .check(event)
+            .and_then(|()| event.verify().map_err(|_| INVALID_SIGNATURE));
+        if let Err(reason) = checked {
+            debug!(
+                "[{}] Rejecting event {}: {}",
+                ctx.connection_id, event.id, reason
+            );
+            if let Some(rejections) = &self.rejections {
+                rejections.record_event(event, ReasonCode::Invalid, reason);
+            }
+            ctx.send_message(RelayMessage::ok(event.id, false, reason))?;
+            return Ok(());
+        }
+
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_with_content(keys: &Keys, content: &str) -> Event {
+        EventBuilder::text_note(content)
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_event_size_limit_boundary() {
+        let keys = Keys::generate();
+        let size = note_with_content(&keys, "").as_json().len() + 100;
+        let limits = EventLimits {
+            max_event_size: size,
+            max_tags: 10,
+            max_content_length: 1000,
+            max_member_tags: 10,
+        };
+
+        // Signatures and ids have a fixed length, so only the content varies
+        let at_limit = note_with_content(&keys, &"a".repeat(100));
+        assert_eq!(at_limit.as_json().len(), size);
+        assert_eq!(limits.check(&at_limit), Ok(()));
+
+        let above_limit = note_with_content(&keys, &"a".repeat(101));
+        assert_eq!(limits.check(&above_limit), Err(EVENT_TOO_LARGE));
+    }
+
+    #[test]
+    fn test_tag_count_and_content_length_limits() {
+        let keys = Keys::generate();
+        let limits = EventLimits {
+            max_event_size: 64 * 1024,
+            max_tags: 2,
+            max_content_length: 10,
+            max_member_tags: 2,
+        };
+
+        let tagged = EventBuilder::text_note("hi")
+            .tags((0..3).map(|i| Tag::hashtag(format!("tag{i}"))))
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(limits.check(&tagged), Err(TOO_MANY_TAGS));
+
+        let long = note_with_content(&keys, "01234567890");
+        assert_eq!(limits.check(&long), Err(CONTENT_TOO_LONG));
+        assert_eq!(
+            limits.check(&note_with_content(&keys, "0123456789")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_membership_changes_allow_member_tags() {
+        let keys = Keys::generate();
+        let limits = EventLimits {
+            max_event_size: 4 * 1024,
+            max_tags: 10,
+            max_content_length: 1000,
+            max_member_tags: 1000,
+        };
+        let members = || (0..500).map(|_| Tag::public_key(Keys::generate().public_key()));
+
+        let import = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+            .tag(Tag::custom(TagKind::h(), ["group"]))
+            .tags(members())
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert!(import.as_json().len() > limits.max_event_size);
+        assert_eq!(limits.check(&import), Ok(()));
+
+        let note = EventBuilder::text_note("hi")
+            .tags(members())
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(limits.check(&note), Err(TOO_MANY_TAGS));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signatures_are_rejected_and_counted() {
+        use crate::groups::Groups;
+        use crate::groups_event_processor::GroupsRelayProcessor;
+        use crate::test_utils::{setup_test, TestRelay};
+
+        let (_tmp_dir, database, keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                keys.public_key(),
+                "ws://127.0.0.1".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups, keys.public_key());
+        let rejections = Arc::new(Rejections::default());
+        let middleware = EventLimitsMiddleware::new(EventLimits {
+            max_event_size: 64 * 1024,
+            max_tags: 100,
+            max_content_length: 8 * 1024,
+            max_member_tags: 100,
+        })
+        .with_rejections(rejections.clone());
+        let relay = TestRelay::start_with_middleware(processor, middleware, database, keys)
+            .await
+            .unwrap();
+
+        // Content changed after signing, so the id no longer matches
+        let mut forged = note_with_content(&Keys::generate(), "signed");
+        forged.content = "changed".to_string();
+        let mut client = relay.connect().await.unwrap();
+        client.send_event(&forged).await.unwrap();
+        assert_eq!(
+            client.expect_ok(forged.id).await.unwrap(),
+            (false, INVALID_SIGNATURE.to_string())
+        );
+
+        assert_eq!(rejections.count(ReasonCode::Invalid, Kind::TextNote), 1);
+        let recent = rejections.recent();
+        assert_eq!(recent[0].event_id, forged.id.to_hex());
+        assert_eq!(recent[0].message, INVALID_SIGNATURE);
+    }
+}
@@ -0,0 +1,214 @@
+//! Garbage collection of per-group state left behind by deleted groups.
+//!
+//! Deleting a group removes it and its events, but state kept about the
+//! group elsewhere, like activity counters and webhook rate windows, stays
+//! until the relay restarts. A periodic pass looks up which groups those
+//! structures still reference and forgets the ones that no longer exist,
+//! handling at most `batch_size` groups per tick so a large backlog never
+//! stalls the runtime.
+//!
+//! Invites and read indexes live in the groups themselves, or are dropped
+//! when a group's events are deleted, so they need no collection.
+
+use crate::groups::Groups;
+use crate::metrics;
+use nostr_lmdb::Scope;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+pub const GC_INTERVAL: Duration = Duration::from_secs(60);
+pub const GC_BATCH_SIZE: usize = 500;
+
+/// State kept about groups outside of [`Groups`]
+pub trait GroupState: Send + Sync {
+    /// Name used in logs and as the metric label
+    fn name(&self) -> &'static str;
+
+    /// Groups this state has entries for
+    fn group_keys(&self) -> Vec<(Scope, String)>;
+
+    /// Removes the entries of a group, returning how many were removed
+    fn forget_group(&self, scope: &Scope, group_id: &str) -> usize;
+}
+
+pub struct GroupGc {
+    groups: Arc<Groups>,
+    states: Vec<Arc<dyn GroupState>>,
+    batch_size: usize,
+    /// Groups found missing in the last scan, not yet collected
+    pending: Mutex<VecDeque<(Scope, String)>>,
+}
+
+impl GroupGc {
+    pub fn new(groups: Arc<Groups>, batch_size: usize) -> Self {
+        Self {
+            groups,
+            states: Vec::new(),
+            batch_size: batch_size.max(1),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn with_state(mut self, state: Arc<dyn GroupState>) -> Self {
+        self.states.push(state);
+        self
+    }
+
+    /// Collects up to `batch_size` missing groups, scanning for more once
+    /// the previous scan has been worked off. Returns the entries removed.
+    pub fn tick(&self) -> usize {
+        // Until every group is loaded, a missing group may just not be loaded
+        if !self.groups.is_loaded() {
+            return 0;
+        }
+
+        let mut pending = self.pending.lock();
+        if pending.is_empty() {
+            let mut seen = HashSet::new();
+            for state in &self.states {
+                for (scope, group_id) in state.group_keys() {
+                    if self.groups.get_group(&scope, &group_id).is_none()
+                        && seen.insert((scope.clone(), group_id.clone()))
+                    {
+                        pending.push_back((scope, group_id));
+                    }
+                }
+            }
+        }
+
+        let mut removed: BTreeMap<&'static str, usize> = BTreeMap::new();
+        for _ in 0..self.batch_size {
+            let Some((scope, group_id)) = pending.pop_front() else {
+                break;
+            };
+            // Created again since the scan
+            if self.groups.get_group(&scope, &group_id).is_some() {
+                continue;
+            }
+            for state in &self.states {
+                let count = state.forget_group(&scope, &group_id);
+                if count > 0 {
+                    *removed.entry(state.name()).or_default() += count;
+                }
+            }
+        }
+
+        for (name, count) in &removed {
+            metrics::gc_removed_entries(*name).increment(*count as u64);
+        }
+        let total = removed.values().sum();
+        if total > 0 {
+            info!(
+                "Collected {} entries of deleted groups ({:?}), {} groups left",
+                total,
+                removed,
+                pending.len()
+            );
+        } else {
+            debug!("Nothing to collect, {} groups left", pending.len());
+        }
+        total
+    }
+
+    /// Runs a tick every `interval` until cancelled.
+    pub fn spawn(self: Arc<Self>, interval: Duration, cancellation_token: CancellationToken) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        self.tick();
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{GroupMetricsSettings, GroupWebhookSettings};
+    use crate::group_metrics::GroupMetrics;
+    use crate::group_webhooks::GroupWebhooks;
+    use crate::test_utils::{create_test_event, setup_test};
+    use crate::StoreCommand;
+    use nostr_sdk::prelude::*;
+
+    #[tokio::test]
+    async fn test_gc_forgets_state_of_deleted_groups() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let group_metrics = Arc::new(GroupMetrics::new(&GroupMetricsSettings {
+            enabled: true,
+            top_n: 10,
+        }));
+        let webhook_settings: GroupWebhookSettings = serde_json::from_str("{}").unwrap();
+        let group_webhooks = GroupWebhooks::start(
+            &webhook_settings,
+            groups.clone(),
+            database,
+            relay_keys.clone(),
+            CancellationToken::new(),
+        )
+        .unwrap();
+
+        let group_ids = ["kept", "gone_1", "gone_2", "gone_3"];
+        let mut stored = Vec::new();
+        for group_id in group_ids {
+            let h_tag = Tag::custom(TagKind::h(), [group_id]);
+            let create = create_test_event(&relay_keys, 9007, vec![h_tag.clone()]).await;
+            groups
+                .handle_group_create(Box::new(create), &Scope::Default)
+                .await
+                .unwrap();
+            let message = create_test_event(&relay_keys, 9, vec![h_tag]).await;
+            stored.push(StoreCommand::SaveSignedEvent(
+                Box::new(message),
+                Scope::Default,
+                None,
+            ));
+            assert!(group_webhooks.within_rate(&Scope::Default, group_id));
+        }
+        group_metrics.record_stored(&stored);
+        for group_id in &group_ids[1..] {
+            let delete = create_test_event(
+                &relay_keys,
+                9008,
+                vec![Tag::custom(TagKind::h(), [*group_id])],
+            )
+            .await;
+            groups
+                .handle_delete_group(Box::new(delete), &Scope::Default)
+                .unwrap();
+        }
+
+        let gc = GroupGc::new(groups, 2)
+            .with_state(group_metrics.clone())
+            .with_state(group_webhooks.clone());
+        assert_eq!(group_metrics.group_keys().len(), 4);
+        assert_eq!(group_webhooks.group_keys().len(), 4);
+
+        // Bounded: two groups per tick, each with a counter and a rate window
+        assert_eq!(gc.tick(), 4);
+        assert_eq!(gc.tick(), 2);
+        assert_eq!(gc.tick(), 0);
+
+        let kept = vec![(Scope::Default, "kept".to_string())];
+        assert_eq!(group_metrics.group_keys(), kept);
+        assert_eq!(group_webhooks.group_keys(), kept);
+    }
+}
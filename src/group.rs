@@ -1,9 +1,11 @@
+use crate::error::Rejection;
+use crate::relay_keys::RelayIdentity;
 use crate::StoreCommand;
 use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
 use relay_builder::Error;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::str::FromStr;
 use strum::{Display, EnumIter, IntoEnumIterator};
 use tracing::{debug, error, info, warn};
@@ -25,11 +27,11 @@ pub enum GroupError {
 impl From<GroupError> for Error {
     fn from(err: GroupError) -> Self {
         match err {
-            GroupError::NotFound(msg) => Error::notice(msg),
+            GroupError::NotFound(msg) => Error::invalid(msg),
             GroupError::PermissionDenied(msg) => Error::restricted(msg),
-            GroupError::ValidationFailed(msg) => Error::notice(msg),
-            GroupError::InvalidState(msg) => Error::notice(msg),
-            GroupError::Internal(err) => Error::notice(err.to_string()),
+            GroupError::ValidationFailed(msg) => Error::invalid(msg),
+            GroupError::InvalidState(msg) => Error::failed(msg),
+            GroupError::Internal(err) => Error::internal(err.to_string()),
         }
     }
 }
@@ -50,6 +52,7 @@ pub const KIND_CLAIM_28934: Kind = Kind::Custom(28934);
 
 // MLS Related
 pub const KIND_GIFT_WRAP: Kind = Kind::GiftWrap;
+pub const KIND_DM_RELAYS_10050: Kind = Kind::Custom(10050); // User -> All: Relays to send NIP-17 direct messages to
 pub const KIND_MLS_KEY_PACKAGE: Kind = Kind::MlsKeyPackage;
 
 pub const KIND_PUSH_REGISTRATION_3079: Kind = Kind::Custom(3079);
@@ -64,8 +67,10 @@ pub const KIND_GROUP_ADD_USER_9000: Kind = Kind::Custom(9000); // Admin/Relay ->
 pub const KIND_GROUP_REMOVE_USER_9001: Kind = Kind::Custom(9001); // Admin/Relay -> Relay: Remove user from group
 pub const KIND_GROUP_EDIT_METADATA_9002: Kind = Kind::Custom(9002); // Admin/Relay -> Relay: Edit group metadata
 pub const KIND_GROUP_DELETE_EVENT_9005: Kind = Kind::Custom(9005); // Admin/Relay -> Relay: Delete specific event
+pub const KIND_GROUP_DEFINE_ROLES_9003: Kind = Kind::Custom(9003); // Admin/Relay -> Relay: Define custom roles and their permissions
 pub const KIND_GROUP_SET_ROLES_9006: Kind = Kind::Custom(9006); // Admin/Relay -> Relay: Set roles for group. This was removed but at least 0xchat uses it
 pub const KIND_GROUP_CREATE_INVITE_9009: Kind = Kind::Custom(9009); // Admin/Relay -> Relay: Create invite for closed group
+pub const KIND_GROUP_ANNOTATION_9030: Kind = Kind::Custom(9030); // Admin -> Admins: Internal note on a member or event
 
 pub const KIND_GROUP_USER_JOIN_REQUEST_9021: Kind = Kind::Custom(9021); // User -> Relay: Request to join group
 pub const KIND_GROUP_USER_LEAVE_REQUEST_9022: Kind = Kind::Custom(9022); // User -> Relay: Request to leave group
@@ -74,6 +79,77 @@ pub const KIND_GROUP_METADATA_39000: Kind = Kind::Custom(39000); // Relay -> All
 pub const KIND_GROUP_ADMINS_39001: Kind = Kind::Custom(39001); // Relay -> All: List of group admins
 pub const KIND_GROUP_MEMBERS_39002: Kind = Kind::Custom(39002); // Relay -> All: List of group members
 pub const KIND_GROUP_ROLES_39003: Kind = Kind::Custom(39003); // Relay -> All: Supported roles in group
+pub const KIND_GROUP_JOIN_REQUESTS_39004: Kind = Kind::Custom(39004); // Relay -> Admins: Pending join requests
+pub const KIND_GROUP_INVITE_REDEMPTIONS_39005: Kind = Kind::Custom(39005); // Relay -> Admins: Who joined with which invite
+pub const KIND_GROUP_MODERATION_39006: Kind = Kind::Custom(39006); // Relay -> Admins: Muted and banned pubkeys
+pub const KIND_GROUP_WEBHOOKS_39010: Kind = Kind::Custom(39010); // Relay -> Relay: Group webhook registrations, never served
+pub const KIND_GROUP_BOT_TOKENS_39011: Kind = Kind::Custom(39011); // Relay -> Relay: Hashed bot tokens, never served
+pub const KIND_GROUP_LIST_SUGGESTION_39012: Kind = Kind::Custom(39012); // Relay -> Member: Suggested kind 10009 list after a join or leave
+pub const KIND_GROUP_SPAM_MUTE_39013: Kind = Kind::Custom(39013); // Relay -> Relay: A shadow-muted message, never served
+pub const KIND_GROUP_DIRECTORY_39100: Kind = Kind::Custom(39100); // Relay -> All: Public groups of a scope
+
+pub const KIND_GROUP_MESSAGE_9: Kind = Kind::Custom(9); // Member/Relay -> Members: Chat message, the relay sends welcome notes as one
+pub const KIND_GROUP_EMOJI_SET_30030: Kind = Kind::Custom(30030); // Admin -> All: Group custom emoji set (NIP-30 emoji tags)
+
+/// Relay-generated state a group is loaded from
+pub const GROUP_STATE_KINDS: [Kind; 6] = [
+    KIND_GROUP_METADATA_39000,
+    KIND_GROUP_ADMINS_39001,
+    KIND_GROUP_MEMBERS_39002,
+    KIND_GROUP_ROLES_39003,
+    KIND_GROUP_WEBHOOKS_39010,
+    KIND_GROUP_BOT_TOKENS_39011,
+];
+
+/// Every kind the relay generates, all addressed by a `d` tag rather than `h`
+pub const RELAY_GENERATED_KINDS: [Kind; 12] = [
+    KIND_GROUP_METADATA_39000,
+    KIND_GROUP_ADMINS_39001,
+    KIND_GROUP_MEMBERS_39002,
+    KIND_GROUP_ROLES_39003,
+    KIND_GROUP_JOIN_REQUESTS_39004,
+    KIND_GROUP_INVITE_REDEMPTIONS_39005,
+    KIND_GROUP_MODERATION_39006,
+    KIND_GROUP_WEBHOOKS_39010,
+    KIND_GROUP_BOT_TOKENS_39011,
+    KIND_GROUP_LIST_SUGGESTION_39012,
+    KIND_GROUP_SPAM_MUTE_39013,
+    KIND_GROUP_DIRECTORY_39100,
+];
+
+/// Rejection reason for new content, join requests and invites in an
+/// archived group
+pub const GROUP_ARCHIVED: &str = "group is archived";
+
+/// Rejection reason for join requests and content from a banned pubkey
+pub const GROUP_BANNED: &str = "you are banned from this group";
+
+/// Rejection reason for content from a muted pubkey
+pub const GROUP_MUTED: &str = "you are muted in this group";
+
+/// Marks a relay-authored note telling a requester their join request was
+/// declined, its value is the reason given by the admin
+pub const JOIN_REJECTED_TAG: &str = "join_rejected";
+
+/// Upper bound on the number of emoji in a group emoji set
+pub const MAX_GROUP_EMOJIS: usize = 200;
+
+/// Relay-wide ceiling for a group's `max_content_length`, in characters
+pub const MAX_GROUP_CONTENT_LENGTH: usize = 64 * 1024;
+
+/// Relay-wide ceiling for a group's `max_media_urls`
+pub const MAX_GROUP_MEDIA_URLS: usize = 50;
+
+/// Past names kept in a group's metadata unless configured otherwise
+pub const MAX_PREVIOUS_NAMES: usize = 10;
+
+/// Maximum length of a group slug
+pub const MAX_SLUG_LENGTH: usize = 64;
+
+pub const SLUG_IN_USE: &str = "slug already in use";
+
+/// Rejection reason for content of a kind outside the group's kind policy
+pub const KIND_NOT_PERMITTED: &str = "kind not permitted in this group";
 
 pub const ADDRESSABLE_EVENT_KINDS: [Kind; 4] = [
     KIND_GROUP_METADATA_39000,
@@ -99,14 +175,16 @@ pub const NON_GROUP_ALLOWED_KINDS: [Kind; 14] = [
     KIND_PUSH_DEREGISTRATION_3080,
 ];
 
-pub const ALL_GROUP_KINDS_EXCEPT_DELETE_AND_ADDRESSABLE: [Kind; 10] = [
+pub const ALL_GROUP_KINDS_EXCEPT_DELETE_AND_ADDRESSABLE: [Kind; 12] = [
     KIND_GROUP_CREATE_9007,
     KIND_GROUP_ADD_USER_9000,
     KIND_GROUP_REMOVE_USER_9001,
     KIND_GROUP_EDIT_METADATA_9002,
+    KIND_GROUP_DEFINE_ROLES_9003,
     KIND_GROUP_DELETE_EVENT_9005,
     KIND_GROUP_SET_ROLES_9006,
     KIND_GROUP_CREATE_INVITE_9009,
+    KIND_GROUP_ANNOTATION_9030,
     KIND_GROUP_USER_JOIN_REQUEST_9021,
     KIND_GROUP_USER_LEAVE_REQUEST_9022,
     KIND_CLAIM_28934,
@@ -123,6 +201,36 @@ pub struct GroupMetadata {
     pub closed: bool,
     /// Broadcast = only admins can publish content events (except join/leave)
     pub is_broadcast: bool,
+    /// Coordinate of the group's emoji set, linked from the metadata `a` tag
+    #[serde(default)]
+    pub emoji_set: Option<String>,
+    /// Maximum content length of messages, in characters
+    #[serde(default)]
+    pub max_content_length: Option<usize>,
+    /// Maximum number of URLs in a message's content
+    #[serde(default)]
+    pub max_media_urls: Option<usize>,
+    /// Note the relay posts to each new member when they join
+    #[serde(default)]
+    pub welcome: Option<String>,
+    /// Archived = readable as before, but no new content or members
+    #[serde(default)]
+    pub archived: bool,
+    /// Content kinds accepted in the group, any when empty
+    #[serde(default)]
+    pub allowed_kinds: Vec<Kind>,
+    /// Content kinds rejected in the group
+    #[serde(default)]
+    pub denied_kinds: Vec<Kind>,
+    /// Storage quota of the group in events, only set by the relay operator
+    #[serde(default)]
+    pub max_events: Option<u64>,
+    /// Names the group had before, oldest first, with when they were changed
+    #[serde(default)]
+    pub previous_names: Vec<(String, Timestamp)>,
+    /// Name for links that survives renames, unique within the scope
+    #[serde(default)]
+    pub slug: Option<String>,
     /// Store any unknown tags for preservation
     pub unknown_tags: Vec<Tag>,
 }
@@ -136,10 +244,31 @@ impl GroupMetadata {
             private: true,
             closed: true,
             is_broadcast: false,
+            emoji_set: None,
+            max_content_length: None,
+            max_media_urls: None,
+            welcome: None,
+            archived: false,
+            allowed_kinds: Vec::new(),
+            denied_kinds: Vec::new(),
+            max_events: None,
+            previous_names: Vec::new(),
+            slug: None,
             unknown_tags: Vec::new(),
         }
     }
 
+    /// Whether the kind policy lets content of a kind in. Management kinds
+    /// (9000-9009, joins and leaves) are never restricted.
+    pub fn permits_kind(&self, kind: Kind) -> bool {
+        let management = (9000..=9009).contains(&kind.as_u16())
+            || kind == KIND_GROUP_USER_JOIN_REQUEST_9021
+            || kind == KIND_GROUP_USER_LEAVE_REQUEST_9022;
+        management
+            || (!self.denied_kinds.contains(&kind)
+                && (self.allowed_kinds.is_empty() || self.allowed_kinds.contains(&kind)))
+    }
+
     /// Apply event tags to update metadata fields.
     pub fn apply_tags(&mut self, event: &Event) {
         let mut found_tags = std::collections::HashMap::new();
@@ -170,11 +299,48 @@ impl GroupMetadata {
                         "closed" => self.closed = true,
                         "broadcast" => self.is_broadcast = true,
                         "nonbroadcast" => self.is_broadcast = false,
+                        "archived" => self.archived = true,
+                        "unarchived" => self.archived = false,
+                        // Limits are clamped to the relay maximums, 0 removes them
+                        "max_content_length" => {
+                            if let Some(limit) = tag.content().and_then(|c| c.parse().ok()) {
+                                self.max_content_length =
+                                    (limit > 0).then(|| limit.min(MAX_GROUP_CONTENT_LENGTH));
+                            }
+                        }
+                        "max_media_urls" => {
+                            if let Some(limit) = tag.content().and_then(|c| c.parse().ok()) {
+                                self.max_media_urls =
+                                    (limit > 0).then(|| limit.min(MAX_GROUP_MEDIA_URLS));
+                            }
+                        }
+                        "max_events" => {
+                            if let Some(limit) = tag.content().and_then(|c| c.parse().ok()) {
+                                self.max_events = (limit > 0).then_some(limit);
+                            }
+                        }
+                        // A kind list replaces the previous one, without values it's cleared
+                        "allowed_kinds" => self.allowed_kinds = parse_kinds(tag),
+                        "denied_kinds" => self.denied_kinds = parse_kinds(tag),
                         "name" => {
                             if let Some(content) = tag.content() {
                                 self.name = content.to_string();
                             }
                         }
+                        // Checked by the caller, an empty slug removes it
+                        "slug" => {
+                            if let Some(content) = tag.content() {
+                                self.slug = (!content.is_empty()).then(|| content.to_string());
+                            }
+                        }
+                        // Only read from the relay's metadata event
+                        "previous_name" => {}
+                        // An empty welcome turns it off
+                        "welcome" => {
+                            if let Some(content) = tag.content() {
+                                self.welcome = (!content.is_empty()).then(|| content.to_string());
+                            }
+                        }
                         "d" => {} // Identifier tag, ignore
                         "h" => {} // Group ID tag, ignore
                         _ => {
@@ -188,6 +354,13 @@ impl GroupMetadata {
                     use nostr_sdk::Alphabet;
                     match single.character {
                         Alphabet::H | Alphabet::D => {} // Group ID and identifier tags, ignore
+                        Alphabet::A
+                            if tag.content().is_some_and(|c| {
+                                c.starts_with(&format!("{}:", KIND_GROUP_EMOJI_SET_30030.as_u16()))
+                            }) =>
+                        {
+                            self.emoji_set = tag.content().map(str::to_string);
+                        }
                         _ => {
                             // All other single-letter tags are unknown (including 'g')
                             found_tags.insert(tag.kind(), tag.clone());
@@ -206,6 +379,62 @@ impl GroupMetadata {
             .retain(|tag| !found_tags.contains_key(&tag.kind()));
         self.unknown_tags.extend(found_tags.into_values());
     }
+
+    /// The slug an event sets, empty when it removes it. Slugs are lowercase
+    /// letters, digits and dashes.
+    pub fn slug_tag(event: &Event) -> Result<Option<&str>, Error> {
+        let Some(slug) = event
+            .tags
+            .find(TagKind::custom("slug"))
+            .and_then(|tag| tag.content())
+        else {
+            return Ok(None);
+        };
+
+        if slug.len() > MAX_SLUG_LENGTH
+            || !slug
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(Error::invalid(format!(
+                "Slugs are up to {MAX_SLUG_LENGTH} lowercase letters, digits and dashes"
+            )));
+        }
+        Ok(Some(slug))
+    }
+
+    /// Drops the oldest previous names beyond `max`
+    pub fn truncate_previous_names(&mut self, max: usize) {
+        let excess = self.previous_names.len().saturating_sub(max);
+        self.previous_names.drain(..excess);
+    }
+}
+
+/// The `["previous_name", <name>, <changed at>]` tags of a metadata event
+fn previous_names(event: &Event) -> Vec<(String, Timestamp)> {
+    event
+        .tags
+        .filter(TagKind::custom("previous_name"))
+        .filter_map(|tag| {
+            let values = tag.as_slice();
+            let name = values.get(1)?;
+            let changed_at = values.get(2)?.parse::<u64>().ok()?;
+            Some((name.clone(), Timestamp::from(changed_at)))
+        })
+        .collect()
+}
+
+/// The kinds listed in a kind policy tag, sorted, skipping invalid values
+fn parse_kinds(tag: &Tag) -> Vec<Kind> {
+    let mut kinds: Vec<u16> = tag
+        .as_slice()
+        .iter()
+        .skip(1)
+        .filter_map(|value| value.parse().ok())
+        .collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+    kinds.into_iter().map(Kind::from).collect()
 }
 
 #[derive(Display, Debug, Clone, Serialize, Deserialize, EnumIter, PartialEq, Eq, Hash)]
@@ -216,6 +445,11 @@ pub enum GroupRole {
 }
 
 impl GroupRole {
+    /// Role name as used in tags
+    pub fn name(&self) -> &str {
+        self.as_tuple().0
+    }
+
     fn as_tuple(&self) -> (&str, &str) {
         match self {
             GroupRole::Admin => ("admin", "Can edit metadata and manage users"),
@@ -223,35 +457,117 @@ impl GroupRole {
             GroupRole::Custom(name) => (name, "Custom role"),
         }
     }
+
+    /// The role as it would be parsed from its name, turning custom roles
+    /// named like a built-in one or one of its aliases into that role
+    pub fn normalized(&self) -> GroupRole {
+        match self {
+            GroupRole::Custom(name) => GroupRole::from_str(name).unwrap_or_else(|_| self.clone()),
+            role => role.clone(),
+        }
+    }
 }
 
 impl FromStr for GroupRole {
     type Err = Error;
 
+    /// Role names are case-insensitive, and the aliases clients use for the
+    /// admin and moderator roles map to the same role
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = s.trim().to_lowercase();
-        if s.is_empty() {
-            return Ok(GroupRole::Member);
-        }
-
         match s.as_str() {
-            "admin" => Ok(GroupRole::Admin),
-            "member" => Ok(GroupRole::Member),
-            custom if custom.trim().is_empty() => Ok(GroupRole::Member),
+            "" | "member" => Ok(GroupRole::Member),
+            "admin" | "administrator" | "owner" => Ok(GroupRole::Admin),
+            "mod" | "moderator" => Ok(GroupRole::Custom("moderator".to_string())),
             custom => Ok(GroupRole::Custom(custom.to_string())),
         }
     }
 }
 
+/// Roles named by an event's `["role", <name>]` tags
+fn role_tags(event: &Event) -> HashSet<GroupRole> {
+    event
+        .tags
+        .filter(TagKind::custom("role"))
+        .filter_map(|tag| tag.content())
+        .map(|role| GroupRole::from_str(role).unwrap_or(GroupRole::Member))
+        .collect()
+}
+
+/// Group actions a role grants. Admins always hold every permission and
+/// plain members none; custom roles get theirs from role definitions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RolePermissions(u8);
+
+impl RolePermissions {
+    pub const NONE: RolePermissions = RolePermissions(0);
+    pub const EDIT_METADATA: RolePermissions = RolePermissions(1 << 0);
+    /// Add and remove non-admin members, which includes approving joins
+    pub const MANAGE_MEMBERS: RolePermissions = RolePermissions(1 << 1);
+    pub const DELETE_EVENTS: RolePermissions = RolePermissions(1 << 2);
+    pub const CREATE_INVITES: RolePermissions = RolePermissions(1 << 3);
+    pub const POST_IN_BROADCAST: RolePermissions = RolePermissions(1 << 4);
+    pub const ALL: RolePermissions = RolePermissions(0b1_1111);
+
+    const NAMES: [(&'static str, RolePermissions); 5] = [
+        ("edit_metadata", Self::EDIT_METADATA),
+        ("manage_members", Self::MANAGE_MEMBERS),
+        ("delete_events", Self::DELETE_EVENTS),
+        ("create_invites", Self::CREATE_INVITES),
+        ("post_in_broadcast", Self::POST_IN_BROADCAST),
+    ];
+
+    /// Parses permission names, failing on names this relay doesn't know.
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, Error> {
+        let mut permissions = Self::NONE;
+        for name in names {
+            let Some((_, permission)) = Self::NAMES.iter().find(|(n, _)| *n == name) else {
+                return Err(Error::invalid(format!("Unknown role permission: {name}")));
+            };
+            permissions.insert(*permission);
+        }
+        Ok(permissions)
+    }
+
+    pub fn contains(&self, other: RolePermissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: RolePermissions) {
+        self.0 |= other.0;
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, permission)| self.contains(*permission))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupMember {
     pub pubkey: PublicKey,
     pub roles: HashSet<GroupRole>,
+    /// When the member joined. Members loaded from stored state events use the
+    /// state event's timestamp, as the original join time isn't kept there.
+    #[serde(default = "Timestamp::now")]
+    pub joined_at: Timestamp,
 }
 
 impl GroupMember {
     pub fn new(pubkey: PublicKey, roles: HashSet<GroupRole>) -> Self {
-        Self { pubkey, roles }
+        Self {
+            pubkey,
+            roles,
+            joined_at: Timestamp::now(),
+        }
+    }
+
+    pub fn with_joined_at(mut self, joined_at: Timestamp) -> Self {
+        self.joined_at = joined_at;
+        self
     }
 
     pub fn is(&self, role: GroupRole) -> bool {
@@ -262,6 +578,7 @@ impl GroupMember {
         Self {
             pubkey,
             roles: HashSet::from([GroupRole::Admin]),
+            joined_at: Timestamp::now(),
         }
     }
 
@@ -269,38 +586,51 @@ impl GroupMember {
         Self {
             pubkey,
             roles: HashSet::from([GroupRole::Member]),
+            joined_at: Timestamp::now(),
         }
     }
-}
-
-impl TryFrom<&Tag> for GroupMember {
-    type Error = Error;
 
-    fn try_from(tag: &Tag) -> Result<Self, Error> {
+    /// Reads a member from a `p` tag listing its roles after the pubkey.
+    ///
+    /// Some clients, like 0xchat, put a relay hint or an empty string there
+    /// and name the roles in separate `["role", <name>]` tags of the event.
+    /// Those are passed as `event_roles` and apply when the `p` tag lists no
+    /// role of its own.
+    pub fn from_p_tag(tag: &Tag, event_roles: &HashSet<GroupRole>) -> Result<Self, Error> {
         if tag.kind() != TagKind::p() {
-            return Err(Error::notice("Invalid tag kind"));
+            return Err(Error::invalid("Invalid tag kind"));
         }
 
-        let [_, pubkey, roles @ ..] = tag.as_slice() else {
-            return Err(Error::notice("Invalid tag format"));
+        let [_, pubkey, values @ ..] = tag.as_slice() else {
+            return Err(Error::invalid("Invalid tag format"));
         };
 
-        let pubkey = PublicKey::parse(pubkey).map_err(|_| Error::notice("Invalid pubkey"))?;
+        let pubkey = PublicKey::parse(pubkey).map_err(|_| Error::invalid("Invalid pubkey"))?;
 
-        if roles.is_empty() {
-            return Ok(Self {
-                pubkey,
-                roles: HashSet::from([GroupRole::Member]),
-            });
+        let roles: HashSet<GroupRole> = values
+            .iter()
+            .map(|value| value.trim())
+            .filter(|value| {
+                !value.is_empty() && !value.starts_with("ws://") && !value.starts_with("wss://")
+            })
+            .map(GroupRole::from_str)
+            .collect::<Result<_, _>>()?;
+
+        if !roles.is_empty() {
+            Ok(Self::new(pubkey, roles))
+        } else if !event_roles.is_empty() {
+            Ok(Self::new(pubkey, event_roles.clone()))
+        } else {
+            Ok(Self::new_member(pubkey))
         }
+    }
+}
 
-        Ok(Self {
-            pubkey,
-            roles: roles
-                .iter()
-                .map(|role| GroupRole::from_str(role))
-                .collect::<Result<_, _>>()?,
-        })
+impl TryFrom<&Tag> for GroupMember {
+    type Error = Error;
+
+    fn try_from(tag: &Tag) -> Result<Self, Error> {
+        Self::from_p_tag(tag, &HashSet::new())
     }
 }
 
@@ -309,7 +639,9 @@ pub struct Invite {
     pub event_id: EventId,
     pub roles: HashSet<GroupRole>,
     pub reusable: bool,
-    pub redeemed_by: Option<(PublicKey, Timestamp)>,
+    /// Who joined with the invite and when, oldest first
+    #[serde(default)]
+    pub redemptions: Vec<(PublicKey, Timestamp)>,
 }
 
 impl Invite {
@@ -318,24 +650,24 @@ impl Invite {
             event_id,
             roles,
             reusable: false,
-            redeemed_by: None,
+            redemptions: Vec::new(),
         }
     }
 
     pub fn can_use(&self) -> bool {
-        self.reusable || self.redeemed_by.is_none()
+        self.reusable || self.redemptions.is_empty()
+    }
+
+    /// The redeemer of a single-use invite
+    pub fn redeemed_by(&self) -> Option<&(PublicKey, Timestamp)> {
+        if self.reusable {
+            return None;
+        }
+        self.redemptions.first()
     }
 
     pub fn mark_used(&mut self, pubkey: PublicKey, timestamp: Timestamp) {
-        // println!("[mark_used] Marking invite as used, reusable={}", self.reusable);
-        if !self.reusable {
-            // println!("[mark_used] Setting redeemed_by for single-use invite");
-            self.redeemed_by = Some((pubkey, timestamp));
-        } // else {
-          // println!("[mark_used] Not setting redeemed_by for reusable invite");
-          // }
-          // println!("[mark_used] After marking: reusable={}, redeemed_by={:?}",
-          //          self.reusable, self.redeemed_by.as_ref().map(|(pk, _)| pk.to_string()));
+        self.redemptions.push((pubkey, timestamp));
     }
 }
 
@@ -343,6 +675,130 @@ fn default_scope() -> Scope {
     Scope::Default
 }
 
+/// Counts URLs in message content by their scheme prefix
+pub fn count_urls(content: &str) -> usize {
+    content.matches("https://").count() + content.matches("http://").count()
+}
+
+/// Whether an event is a relay-authored note declining a join request
+pub fn is_rejection_note(event: &Event, relay_pubkey: &impl RelayIdentity) -> bool {
+    event.kind == KIND_GROUP_MESSAGE_9
+        && relay_pubkey.is_relay(&event.pubkey)
+        && event
+            .tags
+            .find(TagKind::custom(JOIN_REJECTED_TAG))
+            .is_some()
+}
+
+/// Mutes and bans of a group, each until an optional timestamp.
+///
+/// A 9000 with a `mute` tag mutes the tagged pubkeys without touching their
+/// membership, and a 9001 with a `ban` tag bans them as it removes them.
+/// Either takes an `expiration` tag for when it ends, which also expires the
+/// moderation event itself (NIP-40). A plain 9000 lifts both. Muted pubkeys
+/// can read but not post, banned ones can't ask to join again. Expiry is
+/// checked when the entry is read, nothing sweeps expired entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Moderation {
+    #[serde(default)]
+    pub mutes: HashMap<PublicKey, Option<Timestamp>>,
+    #[serde(default)]
+    pub bans: HashMap<PublicKey, Option<Timestamp>>,
+}
+
+impl Moderation {
+    fn active(entries: &HashMap<PublicKey, Option<Timestamp>>, pubkey: &PublicKey) -> bool {
+        entries
+            .get(pubkey)
+            .is_some_and(|until| until.is_none_or(|until| until > Timestamp::now()))
+    }
+
+    pub fn is_muted(&self, pubkey: &PublicKey) -> bool {
+        Self::active(&self.mutes, pubkey)
+    }
+
+    pub fn is_banned(&self, pubkey: &PublicKey) -> bool {
+        Self::active(&self.bans, pubkey)
+    }
+
+    /// Lifts the mute and ban of a pubkey, returning whether it had any
+    pub fn lift(&mut self, pubkey: &PublicKey) -> bool {
+        let unmuted = self.mutes.remove(pubkey).is_some();
+        let unbanned = self.bans.remove(pubkey).is_some();
+        unmuted || unbanned
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mutes.is_empty() && self.bans.is_empty()
+    }
+}
+
+/// When the mute or ban of a moderation event ends, from its `expiration` tag
+fn moderation_expiration(event: &Event) -> Option<Timestamp> {
+    event
+        .tags
+        .find(TagKind::Expiration)
+        .and_then(|tag| tag.content())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Timestamp::from)
+}
+
+/// An endpoint registered by a group admin that receives the group's events.
+///
+/// The signing secret is derived from the relay key and never stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupWebhook {
+    pub id: String,
+    pub url: String,
+    /// Kinds delivered, every kind but annotations when empty
+    #[serde(default)]
+    pub kinds: Vec<u16>,
+    /// The admin acknowledged that private group content leaves the relay
+    #[serde(default)]
+    pub allow_private: bool,
+    /// Turned off by the relay after repeated delivery failures
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl GroupWebhook {
+    pub fn matches(&self, kind: Kind) -> bool {
+        if self.kinds.is_empty() {
+            kind != KIND_GROUP_ANNOTATION_9030
+        } else {
+            self.kinds.contains(&kind.as_u16())
+        }
+    }
+}
+
+/// What a bot token lets its holder do in a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotCapability {
+    /// Post content events, without being or becoming a member
+    PostContent,
+    /// Delete the group's events with kind 9005 requests
+    DeleteEvents,
+}
+
+/// A token issued by a group admin for a bot.
+///
+/// Only the SHA-256 of the token is kept, the token is shown once at issue.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BotToken {
+    pub id: String,
+    pub hash: String,
+    pub capabilities: BTreeSet<BotCapability>,
+    pub expires_at: Timestamp,
+    pub issued_by: PublicKey,
+}
+
+impl BotToken {
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at <= now
+    }
+}
+
 /// A Nostr group that implements NIP-29 group management.
 ///
 /// Groups have the following key characteristics:
@@ -359,6 +815,18 @@ pub struct Group {
     pub join_requests: HashSet<PublicKey>,
     pub invites: HashMap<String, Invite>,
     pub roles: HashSet<GroupRole>,
+    /// Permissions of custom roles, keyed by role name
+    #[serde(default)]
+    pub role_permissions: HashMap<String, RolePermissions>,
+    #[serde(default)]
+    pub webhooks: Vec<GroupWebhook>,
+    #[serde(default)]
+    pub bot_tokens: Vec<BotToken>,
+    /// Members the welcome note was already sent to
+    #[serde(default)]
+    pub welcomed: HashSet<PublicKey>,
+    #[serde(default)]
+    pub moderation: Moderation,
     pub created_at: Timestamp,
     pub updated_at: Timestamp,
     #[serde(skip, default = "default_scope")]
@@ -374,6 +842,11 @@ impl Default for Group {
             join_requests: HashSet::new(),
             invites: HashMap::new(),
             roles: HashSet::new(),
+            role_permissions: HashMap::new(),
+            webhooks: Vec::new(),
+            bot_tokens: Vec::new(),
+            welcomed: HashSet::new(),
+            moderation: Moderation::default(),
             created_at: Timestamp::now(),
             updated_at: Timestamp::now(),
             scope: Scope::Default,
@@ -426,6 +899,7 @@ impl std::fmt::Debug for Group {
         writeln!(f, "    private: {},", self.metadata.private)?;
         writeln!(f, "    closed: {},", self.metadata.closed)?;
         writeln!(f, "    is_broadcast: {},", self.metadata.is_broadcast)?;
+        writeln!(f, "    archived: {},", self.metadata.archived)?;
         writeln!(f, "  }},")?;
         writeln!(f, "  members: {{")?;
         for (pubkey, member) in &self.members {
@@ -508,6 +982,11 @@ impl Group {
             join_requests: HashSet::new(),
             invites: HashMap::new(),
             roles: HashSet::new(),
+            role_permissions: HashMap::new(),
+            webhooks: Vec::new(),
+            bot_tokens: Vec::new(),
+            welcomed: HashSet::new(),
+            moderation: Moderation::default(),
             created_at: Timestamp::now(),
             updated_at: Timestamp::now(),
             scope: Scope::Default,
@@ -516,15 +995,12 @@ impl Group {
 
     pub fn new(event: &Event, scope: Scope) -> Result<Self, Error> {
         if event.kind != KIND_GROUP_CREATE_9007 {
-            return Err(Error::event_error(
-                "Invalid event kind for group creation",
-                event.id,
-            ));
+            return Err(Error::invalid("Invalid event kind for group creation"));
         }
 
         let mut group = Self::from(event);
         if group.id.is_empty() {
-            return Err(Error::event_error("Group ID not found", event.id));
+            return Err(Error::invalid("Group ID not found"));
         }
 
         // Set the scope for this group
@@ -543,10 +1019,10 @@ impl Group {
     pub fn delete_group_request(
         &self,
         delete_group_request_event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if delete_group_request_event.kind != KIND_GROUP_DELETE_9008 {
-            return Err(Error::notice("Invalid event kind for delete group"));
+            return Err(Error::invalid("Invalid event kind for delete group"));
         }
 
         self.can_delete_group(relay_pubkey, &delete_group_request_event)?;
@@ -568,17 +1044,13 @@ impl Group {
     pub fn delete_event_request(
         &mut self,
         delete_request_event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if delete_request_event.kind != KIND_GROUP_DELETE_EVENT_9005 {
-            return Err(Error::notice("Invalid event kind for delete event"));
+            return Err(Error::invalid("Invalid event kind for delete event"));
         }
 
-        // Get the event IDs from the tags
-        let event_ids: Vec<_> = delete_request_event.tags.event_ids().copied().collect();
-        if event_ids.is_empty() {
-            return Err(Error::notice("No event IDs found in delete request"));
-        }
+        let event_ids = Self::delete_request_ids(&delete_request_event)?;
 
         // For deletion events, we use the event's pubkey since it's signed
         // No need for NIP-42 authentication - the signature proves identity
@@ -590,6 +1062,35 @@ impl Group {
             "event",
         )?;
 
+        Ok(self.delete_events(delete_request_event, event_ids))
+    }
+
+    /// Runs a kind 9005 request of a bot whose token grants
+    /// [`BotCapability::DeleteEvents`], without role checks
+    pub fn bot_delete_event_request(
+        &mut self,
+        delete_request_event: Box<Event>,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        if delete_request_event.kind != KIND_GROUP_DELETE_EVENT_9005 {
+            return Err(Error::invalid("Invalid event kind for delete event"));
+        }
+        let event_ids = Self::delete_request_ids(&delete_request_event)?;
+        Ok(self.delete_events(delete_request_event, event_ids))
+    }
+
+    fn delete_request_ids(delete_request_event: &Event) -> Result<Vec<EventId>, Error> {
+        let event_ids: Vec<_> = delete_request_event.tags.event_ids().copied().collect();
+        if event_ids.is_empty() {
+            return Err(Error::invalid("No event IDs found in delete request"));
+        }
+        Ok(event_ids)
+    }
+
+    fn delete_events(
+        &mut self,
+        delete_request_event: Box<Event>,
+        event_ids: Vec<EventId>,
+    ) -> Vec<StoreCommand> {
         // We may be deleting invites, remove them from memory too.
         let codes_to_remove: Vec<_> = self
             .invites
@@ -609,19 +1110,52 @@ impl Group {
 
         let filter = Filter::new().ids(event_ids);
 
-        Ok(vec![
+        vec![
             StoreCommand::DeleteEvents(filter, self.scope.clone(), None),
             StoreCommand::SaveSignedEvent(delete_request_event, self.scope.clone(), None),
-        ])
+        ]
+    }
+
+    /// Picks which of the group's events referenced by a NIP-09 deletion its
+    /// author may delete: their own, or any of them for the relay and members
+    /// allowed to delete events. Referencing an event the author can't see is
+    /// rejected, so deletions can't be used to probe private groups.
+    pub fn deletion_targets(
+        &self,
+        deletion: &Event,
+        targets: &[Event],
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<Vec<EventId>, Error> {
+        let author = deletion.pubkey;
+        let can_moderate = relay_pubkey.is_relay(&author)
+            || self.has_permission(&author, RolePermissions::DELETE_EVENTS);
+
+        let mut event_ids = Vec::new();
+        for target in targets {
+            if !self
+                .can_see_event(&Some(author), relay_pubkey, target)
+                .unwrap_or(false)
+            {
+                return Err(Error::restricted(
+                    "Cannot delete events in a group you can't see",
+                ));
+            }
+
+            if target.pubkey == author || can_moderate {
+                event_ids.push(target.id);
+            }
+        }
+
+        Ok(event_ids)
     }
 
     pub fn add_members_from_event(
         &mut self,
         members_event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if members_event.kind != KIND_GROUP_ADD_USER_9000 {
-            return Err(Error::notice("Invalid event kind for add members"));
+            return Err(Error::invalid("Invalid event kind for add members"));
         }
 
         if !self.can_edit_members(&members_event.pubkey, relay_pubkey) {
@@ -630,24 +1164,64 @@ impl Group {
                 members_event.pubkey
             );
 
-            return Err(Error::notice(
+            return Err(Error::restricted(
                 "User is not authorized to add users to this group",
             ));
         }
 
-        let group_members = members_event
+        if members_event.tags.find(TagKind::custom("mute")).is_some() {
+            return self.mute_members(members_event, relay_pubkey);
+        }
+
+        let group_members: Vec<_> = members_event
             .tags
             .filter(TagKind::p())
             .map(GroupMember::try_from)
-            .filter_map(Result::ok);
+            .filter_map(Result::ok)
+            .collect();
+
+        if !self.can_manage_admins(&members_event.pubkey, relay_pubkey)
+            && group_members
+                .iter()
+                .any(|member| member.is(GroupRole::Admin) || self.is_admin(&member.pubkey))
+        {
+            return Err(Error::restricted("Only admins can change admin membership"));
+        }
 
-        self.add_members(group_members)?;
+        let new_members: Vec<PublicKey> = group_members
+            .iter()
+            .map(|member| member.pubkey)
+            .filter(|pubkey| !self.members.contains_key(pubkey))
+            .collect();
+        let approved = new_members
+            .iter()
+            .any(|pubkey| self.join_requests.contains(pubkey));
+        let lifted = group_members.iter().any(|member| {
+            self.moderation.mutes.contains_key(&member.pubkey)
+                || self.moderation.bans.contains_key(&member.pubkey)
+        });
+        self.add_members(group_members.into_iter())?;
 
         let mut events = vec![StoreCommand::SaveSignedEvent(
             members_event,
             self.scope.clone(),
             None,
         )];
+        if approved {
+            events.push(StoreCommand::SaveUnsignedEvent(
+                self.generate_join_requests_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ));
+        }
+        if lifted {
+            events.push(StoreCommand::SaveUnsignedEvent(
+                self.generate_moderation_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ));
+        }
+        events.extend(self.welcome_commands(new_members, relay_pubkey));
         let admins_event = self.generate_admins_event(relay_pubkey)?;
         events.push(StoreCommand::SaveUnsignedEvent(
             admins_event,
@@ -664,12 +1238,43 @@ impl Group {
         Ok(events)
     }
 
+    /// Mutes the pubkeys tagged in a 9000 carrying a `mute` tag, leaving
+    /// their membership and roles as they are
+    fn mute_members(
+        &mut self,
+        mute_event: Box<Event>,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let targets: Vec<PublicKey> = mute_event.tags.public_keys().copied().collect();
+        if targets.iter().any(|pubkey| self.is_admin(pubkey))
+            && !self.can_manage_admins(&mute_event.pubkey, relay_pubkey)
+        {
+            return Err(Error::restricted("Only admins can mute admins"));
+        }
+
+        let until = moderation_expiration(&mute_event);
+        for pubkey in targets {
+            self.moderation.mutes.insert(pubkey, until);
+        }
+        self.update_state();
+
+        Ok(vec![
+            StoreCommand::SaveSignedEvent(mute_event, self.scope.clone(), None),
+            StoreCommand::SaveUnsignedEvent(
+                self.generate_moderation_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ),
+        ])
+    }
+
     pub fn add_members(
         &mut self,
         group_members: impl Iterator<Item = GroupMember>,
     ) -> Result<(), Error> {
-        for member in group_members {
+        for mut member in group_members {
             self.join_requests.remove(&member.pubkey);
+            self.moderation.lift(&member.pubkey);
 
             // If the member exists, check if we're removing the last admin
             if let Some(existing) = self.members.get(&member.pubkey) {
@@ -678,8 +1283,11 @@ impl Group {
                     && existing.roles.contains(&GroupRole::Admin)
                     && !member.roles.contains(&GroupRole::Admin)
                 {
-                    return Err(Error::notice("Notice: Cannot unset last admin role"));
+                    return Err(Error::invalid("Cannot unset last admin role"));
                 }
+
+                // Role changes don't reset the join date
+                member.joined_at = existing.joined_at;
             }
 
             self.members.insert(member.pubkey, member);
@@ -717,7 +1325,7 @@ impl Group {
     /// Validate that the group has at least one admin, return error if not
     pub fn validate_has_admin(&self) -> Result<(), Error> {
         if !self.has_admin() {
-            return Err(Error::notice("Group must have at least one admin"));
+            return Err(Error::invalid("Group must have at least one admin"));
         }
         Ok(())
     }
@@ -725,10 +1333,10 @@ impl Group {
     pub fn remove_members(
         &mut self,
         members_event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if members_event.kind != KIND_GROUP_REMOVE_USER_9001 {
-            return Err(Error::notice("Invalid event kind for remove members"));
+            return Err(Error::invalid("Invalid event kind for remove members"));
         }
 
         if !self.can_edit_members(&members_event.pubkey, relay_pubkey) {
@@ -736,13 +1344,21 @@ impl Group {
                 "User {} is not authorized to remove users from this group",
                 members_event.pubkey
             );
-            return Err(Error::notice(
+            return Err(Error::restricted(
                 "User is not authorized to remove users from this group",
             ));
         }
 
         let admins = self.admin_pubkeys();
         let mut removed_admins = false;
+        let ban = members_event.tags.find(TagKind::custom("ban")).is_some();
+        let until = moderation_expiration(&members_event);
+        let reason = members_event
+            .tags
+            .find(TagKind::custom("reason"))
+            .and_then(|t| t.content())
+            .map(str::to_string);
+        let mut rejected = Vec::new();
 
         for tag in members_event.tags.filter(TagKind::p()) {
             let member = GroupMember::try_from(tag)?;
@@ -750,11 +1366,24 @@ impl Group {
 
             // Exit early if this removal would remove the last admin.
             if admins.len() == 1 && admins.contains(&removed_pubkey) {
-                return Err(Error::notice("Cannot remove last admin"));
+                return Err(Error::invalid("Cannot remove last admin"));
+            }
+
+            if admins.contains(&removed_pubkey)
+                && !self.can_manage_admins(&members_event.pubkey, relay_pubkey)
+            {
+                return Err(Error::restricted("Only admins can remove admins"));
+            }
+
+            if ban {
+                self.moderation.bans.insert(removed_pubkey, until);
             }
 
-            // Skip if the member doesn't exist.
+            // Removing a pubkey that only asked to join rejects its request
             if !self.members.contains_key(&removed_pubkey) {
+                if self.join_requests.remove(&removed_pubkey) {
+                    rejected.push(removed_pubkey);
+                }
                 continue;
             }
 
@@ -792,67 +1421,180 @@ impl Group {
             self.scope.clone(),
             None,
         ));
+        if ban {
+            events.push(StoreCommand::SaveUnsignedEvent(
+                self.generate_moderation_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ));
+        }
+        if !rejected.is_empty() {
+            events.push(StoreCommand::SaveUnsignedEvent(
+                self.generate_join_requests_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ));
+            for requester in rejected {
+                let note = self.generate_rejection_note(requester, reason.as_deref(), relay_pubkey);
+                events.push(StoreCommand::SaveUnsignedEvent(
+                    note,
+                    self.scope.clone(),
+                    None,
+                ));
+            }
+        }
 
         Ok(events)
     }
 
-    pub fn set_metadata(&mut self, event: &Event, relay_pubkey: &PublicKey) -> Result<(), Error> {
+    pub fn set_metadata(
+        &mut self,
+        event: &Event,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<(), Error> {
         if event.kind != KIND_GROUP_EDIT_METADATA_9002 {
-            return Err(Error::notice("Invalid event kind for set metadata"));
+            return Err(Error::invalid("Invalid event kind for set metadata"));
         }
 
         if !self.can_edit_metadata(&event.pubkey, relay_pubkey) {
-            return Err(Error::notice("User cannot edit metadata"));
+            return Err(Error::restricted("User cannot edit metadata"));
+        }
+
+        if event.tags.find(TagKind::custom("max_events")).is_some()
+            && !relay_pubkey.is_relay(&event.pubkey)
+        {
+            return Err(Error::restricted(
+                "Only the relay operator can set a group's storage quota",
+            ));
         }
+        GroupMetadata::slug_tag(event)?;
 
+        let name = self.metadata.name.clone();
         self.metadata.apply_tags(event);
+        if self.metadata.name != name && !name.is_empty() {
+            self.metadata.previous_names.push((name, event.created_at));
+        }
         self.update_state();
         Ok(())
     }
 
-    /// Changes the roles of one or more group members.
-    ///
-    /// This method enforces several important constraints to maintain group integrity:
-    /// 1. Only admins or the relay can change roles
-    /// 2. The last admin's role cannot be changed to non-admin
-    /// 3. The target users must already be members of the group
+    /// Accepts an admin-published emoji set for the group and links it from
+    /// the group metadata.
     ///
-    /// # Arguments
-    /// * `event` - The event containing role changes. Must have p-tags with pubkey and role.
-    /// * `relay_pubkey` - The relay's public key, which has special permissions.
-    ///
-    /// # Returns
-    /// * `Ok(())` if the roles were successfully updated
-    /// * `Err` if:
-    ///   - The user is not authorized to change roles
+    /// The set is an addressable event whose `d` tag is the group id, holding
+    /// `["emoji", <shortcode>, <https url>]` tags.
+    pub fn set_emoji_set(
+        &mut self,
+        event: &Event,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<(), Error> {
+        if event.kind != KIND_GROUP_EMOJI_SET_30030 {
+            return Err(Error::invalid("Invalid event kind for emoji set"));
+        }
+
+        if !self.can_edit_metadata(&event.pubkey, relay_pubkey) {
+            return Err(Error::restricted(
+                "Only admins can publish the group emoji set",
+            ));
+        }
+
+        let emoji_tags: Vec<&[String]> = event
+            .tags
+            .iter()
+            .map(|tag| tag.as_slice())
+            .filter(|tag| tag.first().is_some_and(|name| name == "emoji"))
+            .collect();
+
+        if emoji_tags.len() > MAX_GROUP_EMOJIS {
+            return Err(Error::invalid(format!(
+                "Emoji set has {} emoji, the limit is {MAX_GROUP_EMOJIS}",
+                emoji_tags.len()
+            )));
+        }
+
+        for tag in emoji_tags {
+            let (Some(shortcode), Some(url)) = (tag.get(1), tag.get(2)) else {
+                return Err(Error::invalid("Emoji tags need a shortcode and a URL"));
+            };
+
+            if shortcode.is_empty()
+                || !shortcode
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(Error::invalid(format!(
+                    "Invalid emoji shortcode: {shortcode}"
+                )));
+            }
+
+            if !Url::parse(url).is_ok_and(|url| url.scheme() == "https") {
+                return Err(Error::invalid(format!(
+                    "Emoji {shortcode} must use an https URL"
+                )));
+            }
+        }
+
+        self.metadata.emoji_set = Some(format!(
+            "{}:{}:{}",
+            KIND_GROUP_EMOJI_SET_30030.as_u16(),
+            event.pubkey,
+            self.id
+        ));
+        self.update_state();
+        Ok(())
+    }
+
+    /// Changes the roles of one or more group members.
+    ///
+    /// This method enforces several important constraints to maintain group integrity:
+    /// 1. Only admins or the relay can change roles
+    /// 2. The last admin's role cannot be changed to non-admin
+    /// 3. The target users must already be members of the group
+    ///
+    /// # Arguments
+    /// * `event` - The event containing role changes. Must have p-tags with pubkey and role.
+    /// * `relay_pubkey` - The relay's public key, which has special permissions.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the roles were successfully updated
+    /// * `Err` if:
+    ///   - The user is not authorized to change roles
     ///   - Attempting to remove the last admin
     ///   - Invalid tag format
     pub fn set_roles(
         &mut self,
         event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if event.kind != KIND_GROUP_SET_ROLES_9006 {
-            return Err(Error::notice("Invalid event kind for set roles"));
+            return Err(Error::invalid("Invalid event kind for set roles"));
         }
 
         if !self.can_edit_members(&event.pubkey, relay_pubkey) {
-            return Err(Error::notice("User is not authorized to set roles"));
+            return Err(Error::restricted("User is not authorized to set roles"));
         }
 
         let current_admins = self.admin_pubkeys();
+        let can_manage_admins = self.can_manage_admins(&event.pubkey, relay_pubkey);
+        let event_roles = role_tags(&event);
         for tag in event.tags.filter(TagKind::p()) {
-            let member = GroupMember::try_from(tag)?;
+            let member = GroupMember::from_p_tag(tag, &event_roles)?;
+            if !can_manage_admins
+                && (member.is(GroupRole::Admin) || current_admins.contains(&member.pubkey))
+            {
+                return Err(Error::restricted("Only admins can change admin roles"));
+            }
+
             if current_admins.len() == 1
                 && current_admins.contains(&member.pubkey)
                 && !member.roles.contains(&GroupRole::Admin)
             {
-                return Err(Error::notice("Notice: Cannot unset last admin role"));
+                return Err(Error::invalid("Cannot unset last admin role"));
             }
         }
 
         for tag in event.tags.filter(TagKind::p()) {
-            let member = GroupMember::try_from(tag)?;
+            let member = GroupMember::from_p_tag(tag, &event_roles)?;
             if let Some(existing_member) = self.members.get_mut(&member.pubkey) {
                 existing_member.roles = member.roles;
             }
@@ -874,6 +1616,57 @@ impl Group {
         ])
     }
 
+    /// Defines custom roles and the permissions they grant.
+    ///
+    /// Each `["role", <name>, <permission>...]` tag replaces the permissions of
+    /// that role; a tag without permissions leaves the role with none. The
+    /// built-in admin and member roles can't be redefined, and only admins can
+    /// define roles so permissions can't be used to escalate.
+    pub fn define_roles(
+        &mut self,
+        event: Box<Event>,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        if event.kind != KIND_GROUP_DEFINE_ROLES_9003 {
+            return Err(Error::invalid("Invalid event kind for define roles"));
+        }
+
+        if !self.can_manage_admins(&event.pubkey, relay_pubkey) {
+            return Err(Error::restricted("Only admins can define roles"));
+        }
+
+        let mut definitions = Vec::new();
+        for tag in event.tags.iter() {
+            let [kind, name, permissions @ ..] = tag.as_slice() else {
+                continue;
+            };
+            if kind != "role" {
+                continue;
+            }
+
+            let GroupRole::Custom(name) = GroupRole::from_str(name)? else {
+                return Err(Error::invalid(format!(
+                    "Cannot redefine built-in role {name}"
+                )));
+            };
+            let permissions = RolePermissions::from_names(permissions.iter().map(String::as_str))?;
+            definitions.push((name, permissions));
+        }
+
+        if definitions.is_empty() {
+            return Err(Error::invalid("No role definitions found"));
+        }
+
+        self.role_permissions.extend(definitions);
+        self.update_state();
+
+        let roles_event = self.generate_roles_event(relay_pubkey);
+        Ok(vec![
+            StoreCommand::SaveSignedEvent(event, self.scope.clone(), None),
+            StoreCommand::SaveUnsignedEvent(roles_event, self.scope.clone(), None),
+        ])
+    }
+
     /// Processes a join request for the group.
     ///
     /// This method handles join requests in different ways depending on the group type and request:
@@ -894,12 +1687,12 @@ impl Group {
     pub fn join_request(
         &mut self,
         event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         // println!("[join_request] Starting join request processing");
         if event.kind != KIND_GROUP_USER_JOIN_REQUEST_9021 {
             // println!("[join_request] Invalid event kind: {}", event.kind);
-            return Err(Error::notice(format!(
+            return Err(Error::invalid(format!(
                 "Invalid event kind for join request {}",
                 event.kind
             )));
@@ -911,6 +1704,15 @@ impl Group {
             return Err(Error::duplicate("User is already a member"));
         }
 
+        // Archived groups take no new members, with or without an invite
+        if self.metadata.archived {
+            return Err(Error::restricted(GROUP_ARCHIVED));
+        }
+
+        if self.moderation.is_banned(&event.pubkey) {
+            return Err(Error::restricted(GROUP_BANNED));
+        }
+
         // println!(
         //     "[join_request] Checking if group is closed: {}",
         //     self.metadata.closed
@@ -927,7 +1729,10 @@ impl Group {
             self.join_requests.remove(&event.pubkey);
             self.update_state();
             // println!("[join_request] Creating commands for open group join");
-            return self.create_join_request_commands(true, event, relay_pubkey);
+            let pubkey = event.pubkey;
+            let mut commands = self.create_join_request_commands(true, event, relay_pubkey)?;
+            commands.extend(self.welcome_commands([pubkey], relay_pubkey));
+            return Ok(commands);
         }
 
         // println!("[join_request] Checking for invite code");
@@ -970,18 +1775,14 @@ impl Group {
                 //     "[join_request] Invite code matched, adding member {}",
                 //     event.pubkey
                 // );
-                info!("Invite code matched, adding member {}", event.pubkey);
-
-                // Now modify the invite if needed (for single-use invites)
-                if !reusable {
-                    // For single-use invites, mark it as used
-                    // println!("[join_request] Single-use invite, marking as used");
-                    if let Some(invite) = self.invites.get_mut(invite_code) {
-                        invite.mark_used(event.pubkey, event.created_at);
-                        // Let the RefMut be dropped automatically at the end of this scope
-                    }
-                } else {
-                    // println!("[join_request] Reusable invite, no need to mark as used");
+                info!(
+                    "Invite code matched, adding member {} (reusable: {})",
+                    event.pubkey, reusable
+                );
+
+                // Record the redemption, which also uses up single-use invites
+                if let Some(invite) = self.invites.get_mut(invite_code) {
+                    invite.mark_used(event.pubkey, event.created_at);
                 }
 
                 // Add the member with the roles we collected earlier
@@ -992,7 +1793,15 @@ impl Group {
                 // println!("[join_request] Updating state...");
                 self.update_state();
                 // println!("[join_request] Creating commands for join with invite");
-                self.create_join_request_commands(true, event, relay_pubkey)
+                let pubkey = event.pubkey;
+                let mut commands = self.create_join_request_commands(true, event, relay_pubkey)?;
+                commands.push(StoreCommand::SaveUnsignedEvent(
+                    self.generate_redemptions_event(relay_pubkey),
+                    self.scope.clone(),
+                    None,
+                ));
+                commands.extend(self.welcome_commands([pubkey], relay_pubkey));
+                Ok(commands)
             }
             // Invite exists but cannot be used (already used and not reusable)
             Some((_, false, _, _)) => {
@@ -1031,17 +1840,29 @@ impl Group {
     pub fn handle_group_content(
         &mut self,
         event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
-        let is_admin = self.is_admin(&event.pubkey);
+        let can_post_in_broadcast =
+            self.has_permission(&event.pubkey, RolePermissions::POST_IN_BROADCAST);
         let is_member = self.is_member(&event.pubkey);
         let event_pubkey = event.pubkey;
         let event_kind = event.kind;
         let event_id = event.id;
 
+        if self.metadata.archived {
+            return Err(Error::restricted(GROUP_ARCHIVED));
+        }
+
+        if self.moderation.is_banned(&event_pubkey) {
+            return Err(Error::restricted(GROUP_BANNED));
+        }
+        if self.moderation.is_muted(&event_pubkey) {
+            return Err(Error::restricted(GROUP_MUTED));
+        }
+
         // Check broadcast restrictions first
         if self.metadata.is_broadcast
-            && !is_admin
+            && !can_post_in_broadcast
             && ![
                 KIND_GROUP_USER_JOIN_REQUEST_9021,
                 KIND_GROUP_USER_LEAVE_REQUEST_9022,
@@ -1056,6 +1877,16 @@ impl Group {
             return Err(Error::restricted("Only admins can post in broadcast mode"));
         }
 
+        // Protected events (NIP-70) never auto-join their author
+        if event.is_protected() && !is_member {
+            return Err(Error::restricted(
+                "Protected events can only be published by group members",
+            ));
+        }
+
+        self.check_kind_policy(&event)?;
+        self.check_content_limits(&event)?;
+
         let mut commands = vec![StoreCommand::SaveSignedEvent(
             event,
             self.scope.clone(),
@@ -1064,7 +1895,7 @@ impl Group {
 
         // For private and closed groups, only members can post
         if self.metadata.private && self.metadata.closed && !is_member {
-            return Err(Error::notice("User is not a member of this group"));
+            return Err(Error::restricted("User is not a member of this group"));
         }
 
         // Open groups auto-join the author when posting
@@ -1077,17 +1908,62 @@ impl Group {
             );
         } else if !is_member {
             // For closed groups, non-members can't post
-            return Err(Error::notice("User is not a member of this group"));
+            return Err(Error::restricted("User is not a member of this group"));
         }
 
         Ok(commands)
     }
 
+    /// Stores a content event of a bot whose token grants
+    /// [`BotCapability::PostContent`]. The bot doesn't join the group.
+    pub fn handle_bot_content(&self, event: Box<Event>) -> Result<Vec<StoreCommand>, Error> {
+        if self.metadata.archived {
+            return Err(Error::restricted(GROUP_ARCHIVED));
+        }
+        self.check_kind_policy(&event)?;
+        self.check_content_limits(&event)?;
+        Ok(vec![StoreCommand::SaveSignedEvent(
+            event,
+            self.scope.clone(),
+            None,
+        )])
+    }
+
+    /// Enforces the group's `allowed_kinds` and `denied_kinds`
+    fn check_kind_policy(&self, event: &Event) -> Result<(), Error> {
+        if self.metadata.permits_kind(event.kind) {
+            Ok(())
+        } else {
+            Err(Error::invalid(KIND_NOT_PERMITTED))
+        }
+    }
+
+    /// Enforces the group's `max_content_length` and `max_media_urls`
+    fn check_content_limits(&self, event: &Event) -> Result<(), Error> {
+        if let Some(limit) = self.metadata.max_content_length {
+            if event.content.chars().count() > limit {
+                return Err(Error::invalid(format!(
+                    "content exceeds this group's limit of {limit} characters"
+                )));
+            }
+        }
+
+        if let Some(limit) = self.metadata.max_media_urls {
+            if count_urls(&event.content) > limit {
+                return Err(Error::invalid(format!(
+                    "content exceeds this group's limit of {limit} media URLs"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     fn create_join_request_commands(
         &self,
         auto_joined: bool,
         event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         // println!(
         //     "[create_join_request_commands] Starting, auto_joined={}",
@@ -1098,7 +1974,7 @@ impl Group {
             //     "[create_join_request_commands] Invalid event kind: {}",
             //     event.kind
             // );
-            return Err(Error::notice(format!(
+            return Err(Error::invalid(format!(
                 "Invalid event kind for join request {}",
                 event.kind
             )));
@@ -1111,6 +1987,14 @@ impl Group {
         )];
         // println!("[create_join_request_commands] Added SaveSignedEvent to commands");
 
+        if !auto_joined {
+            commands.push(StoreCommand::SaveUnsignedEvent(
+                self.generate_join_requests_event(relay_pubkey),
+                self.scope.clone(),
+                None,
+            ));
+        }
+
         if auto_joined {
             // println!(
             //     "[create_join_request_commands] User auto-joined, generating membership events"
@@ -1139,20 +2023,67 @@ impl Group {
         Ok(commands)
     }
 
+    /// Relay-authored welcome notes for members joining the group, sent at
+    /// most once per member. Empty when the group has no welcome note.
+    pub fn welcome_commands(
+        &mut self,
+        new_members: impl IntoIterator<Item = PublicKey>,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Vec<StoreCommand> {
+        let Some(welcome) = self.metadata.welcome.clone() else {
+            return Vec::new();
+        };
+
+        let mut commands = Vec::new();
+        for pubkey in new_members {
+            if relay_pubkey.is_relay(&pubkey) || !self.welcomed.insert(pubkey) {
+                continue;
+            }
+
+            let note = UnsignedEvent::new(
+                relay_pubkey.signing_key(),
+                Timestamp::now_with_supplier(&Instant::now()),
+                KIND_GROUP_MESSAGE_9,
+                vec![
+                    Tag::custom(TagKind::h(), [self.id.clone()]),
+                    Tag::public_key(pubkey),
+                ],
+                welcome.clone(),
+            );
+            commands.push(StoreCommand::SaveUnsignedEvent(
+                note,
+                self.scope.clone(),
+                None,
+            ));
+        }
+        commands
+    }
+
+    /// Marks the members a stored welcome note was addressed to as welcomed
+    pub fn load_welcome_from_event(&mut self, event: &Event) {
+        self.welcomed.extend(event.tags.public_keys().copied());
+    }
+
     pub fn create_invite(
         &mut self,
         invite_event: &Event,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<bool, Error> {
         if invite_event.kind != KIND_GROUP_CREATE_INVITE_9009 {
-            return Err(Error::notice(format!(
+            return Err(Error::invalid(format!(
                 "Invalid event kind for create invite {}",
                 invite_event.kind
             )));
         }
 
         if !self.can_create_invites(&invite_event.pubkey, relay_pubkey) {
-            return Err(Error::notice("User is not authorized to create invites"));
+            return Err(Error::restricted(
+                "User is not authorized to create invites",
+            ));
+        }
+
+        if self.metadata.archived {
+            return Err(Error::restricted(GROUP_ARCHIVED));
         }
 
         info!("Creating invite with code: {:?}", invite_event.tags);
@@ -1160,14 +2091,11 @@ impl Group {
             .tags
             .find(TagKind::custom("code"))
             .and_then(|t| t.content())
-            .ok_or_else(|| Error::notice("Invite code not found in tag"))?;
+            .ok_or_else(|| Error::invalid("Invite code not found in tag"))?;
 
         // Check for duplicate invite code
         if self.invites.contains_key(invite_code) {
-            return Err(Error::event_error(
-                "Invite code already exists",
-                invite_event.id,
-            ));
+            return Err(Error::duplicate("Invite code already exists"));
         }
 
         // Check if the invite is reusable
@@ -1187,10 +2115,10 @@ impl Group {
     pub fn leave_request(
         &mut self,
         event: Box<Event>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<StoreCommand>, Error> {
         if event.kind != KIND_GROUP_USER_LEAVE_REQUEST_9022 {
-            return Err(Error::notice(format!(
+            return Err(Error::invalid(format!(
                 "Invalid event kind for leave request {}",
                 event.kind
             )));
@@ -1201,7 +2129,7 @@ impl Group {
         // Check if the user is an admin and if they're the last admin
         let is_admin = self.is_admin(&event.pubkey);
         if is_admin && self.admin_pubkeys().len() == 1 {
-            return Err(Error::notice("Cannot remove last admin"));
+            return Err(Error::invalid("Cannot remove last admin"));
         }
 
         let removed = self.members.remove(&event.pubkey).is_some();
@@ -1251,33 +2179,55 @@ impl Group {
         self.members.contains_key(pubkey)
     }
 
+    /// Permissions granted by all of a member's roles
+    pub fn permissions(&self, pubkey: &PublicKey) -> RolePermissions {
+        let Some(member) = self.members.get(pubkey) else {
+            return RolePermissions::NONE;
+        };
+
+        let mut permissions = RolePermissions::NONE;
+        for role in &member.roles {
+            match role {
+                GroupRole::Admin => permissions.insert(RolePermissions::ALL),
+                GroupRole::Member => {}
+                GroupRole::Custom(name) => {
+                    if let Some(custom) = self.role_permissions.get(name) {
+                        permissions.insert(*custom);
+                    }
+                }
+            }
+        }
+        permissions
+    }
+
+    pub fn has_permission(&self, pubkey: &PublicKey, permission: RolePermissions) -> bool {
+        self.permissions(pubkey).contains(permission)
+    }
+
     // State loading methods - used during startup to rebuild state from stored events
     pub fn load_metadata_from_event(&mut self, event: &Event) -> Result<(), Error> {
         self.metadata.apply_tags(event);
+        // The relay's metadata event lists every rename up to it
+        if event.kind == KIND_GROUP_METADATA_39000 {
+            self.metadata.previous_names = previous_names(event);
+        }
         self.update_timestamps(event);
         Ok(())
     }
 
     pub fn load_members_from_event(&mut self, event: &Event) -> Result<(), Error> {
-        let pubkey_and_roles = event
+        let event_roles = role_tags(event);
+        let tagged_members = event
             .tags
-            .iter()
-            .filter(|t| t.kind() == TagKind::p())
-            .filter_map(|t| {
-                let [_, pubkey, roles @ ..] = t.as_slice() else {
-                    return None;
-                };
-
-                let pubkey = PublicKey::parse(pubkey).ok()?;
-                Some((pubkey, roles))
-            })
+            .filter(TagKind::p())
+            .filter_map(|t| GroupMember::from_p_tag(t, &event_roles).ok())
             .collect::<Vec<_>>();
 
         // Handle based on event kind
         if event.kind == KIND_GROUP_MEMBERS_39002 {
             // Kind 39002: Members list without roles
             // For each pubkey listed, ensure they have at least the Member role
-            for (pubkey, _) in pubkey_and_roles {
+            for GroupMember { pubkey, .. } in tagged_members {
                 if let Some(existing_member) = self.members.get_mut(&pubkey) {
                     // Member exists - ensure they have Member role (in addition to any existing roles)
                     existing_member.roles.insert(GroupRole::Member);
@@ -1285,31 +2235,28 @@ impl Group {
                     // New member without roles - default to Member role
                     self.members.insert(
                         pubkey,
-                        GroupMember::new(pubkey, HashSet::from([GroupRole::Member])),
+                        GroupMember::new_member(pubkey).with_joined_at(event.created_at),
                     );
                 }
             }
         } else {
             // Kind 39001 or other events: Include role information
-            for (pubkey, roles) in pubkey_and_roles {
-                let mut roles = roles.to_vec();
-                if roles.is_empty() {
-                    roles.push(GroupRole::Member.to_string());
-                }
-
-                let new_roles = roles
-                    .iter()
-                    .map(|r| GroupRole::from_str(r).unwrap_or(GroupRole::Member))
-                    .collect::<HashSet<_>>();
-
+            for GroupMember {
+                pubkey,
+                roles: new_roles,
+                ..
+            } in tagged_members
+            {
                 // Merge roles instead of replacing - if member already exists, combine their roles
                 if let Some(existing_member) = self.members.get_mut(&pubkey) {
                     // Union the existing roles with the new roles
                     existing_member.roles.extend(new_roles);
                 } else {
                     // New member, insert with the roles from this event
-                    self.members
-                        .insert(pubkey, GroupMember::new(pubkey, new_roles));
+                    self.members.insert(
+                        pubkey,
+                        GroupMember::new(pubkey, new_roles).with_joined_at(event.created_at),
+                    );
                 }
             }
         }
@@ -1319,14 +2266,94 @@ impl Group {
         Ok(())
     }
 
+    pub fn load_roles_from_event(&mut self, event: &Event) -> Result<(), Error> {
+        for tag in event.tags.iter() {
+            // ["role", name, description, permission...]
+            let [kind, name, _description, permissions @ ..] = tag.as_slice() else {
+                continue;
+            };
+            if kind != "role" {
+                continue;
+            }
+
+            if let Ok(GroupRole::Custom(name)) = GroupRole::from_str(name) {
+                let permissions =
+                    RolePermissions::from_names(permissions.iter().map(String::as_str))
+                        .unwrap_or_default();
+                self.role_permissions.insert(name, permissions);
+            }
+        }
+
+        self.update_timestamps(event);
+        Ok(())
+    }
+
+    pub fn load_bot_tokens_from_event(&mut self, event: &Event) -> Result<(), Error> {
+        self.bot_tokens = serde_json::from_str(&event.content)
+            .map_err(|e| Error::invalid(format!("Invalid bot tokens for {}: {e}", self.id)))?;
+        Ok(())
+    }
+
+    pub fn load_webhooks_from_event(&mut self, event: &Event) -> Result<(), Error> {
+        self.webhooks = serde_json::from_str(&event.content).map_err(|e| {
+            Error::invalid(format!(
+                "Invalid webhook registrations for {}: {e}",
+                self.id
+            ))
+        })?;
+        Ok(())
+    }
+
+    /// Replays a stored join request. Requests must be replayed oldest first,
+    /// after the invites, for invite redemptions to be reconstructed.
     pub fn load_join_request_from_event(&mut self, event: &Event) -> Result<(), Error> {
         if !self.members.contains_key(&event.pubkey) {
             self.join_requests.insert(event.pubkey);
             self.update_timestamps(event);
+            return Ok(());
+        }
+
+        // A member whose request carried a usable code joined with it
+        let code = event
+            .tags
+            .find(TagKind::custom("code"))
+            .and_then(|t| t.content());
+        if let Some(invite) = code.and_then(|code| self.invites.get_mut(code)) {
+            if invite.can_use() && !invite.redemptions.iter().any(|(pk, _)| *pk == event.pubkey) {
+                invite.mark_used(event.pubkey, event.created_at);
+            }
         }
         Ok(())
     }
 
+    /// Replays a stored removal: pending requests of the removed pubkeys
+    /// were rejected, and a `ban` tag bans them
+    pub fn load_removal_from_event(&mut self, event: &Event) {
+        let ban = event.tags.find(TagKind::custom("ban")).is_some();
+        let until = moderation_expiration(event);
+        for pubkey in event.tags.public_keys() {
+            self.join_requests.remove(pubkey);
+            if ban {
+                self.moderation.bans.insert(*pubkey, until);
+            }
+        }
+    }
+
+    /// Replays a stored addition: a `mute` tag mutes the tagged pubkeys,
+    /// otherwise their requests were approved and their mutes and bans lifted
+    pub fn load_addition_from_event(&mut self, event: &Event) {
+        let mute = event.tags.find(TagKind::custom("mute")).is_some();
+        let until = moderation_expiration(event);
+        for pubkey in event.tags.public_keys() {
+            if mute {
+                self.moderation.mutes.insert(*pubkey, until);
+            } else {
+                self.join_requests.remove(pubkey);
+                self.moderation.lift(pubkey);
+            }
+        }
+    }
+
     pub fn load_invite_from_event(&mut self, event: &Event) -> Result<(), Error> {
         if let Some(code) = event
             .tags
@@ -1339,15 +2366,7 @@ impl Group {
                 .iter()
                 .any(|t| t.kind() == TagKind::custom("reusable"));
 
-            let roles = event
-                .tags
-                .iter()
-                .filter(|t| t.kind() == TagKind::custom("role"))
-                .filter_map(|t| t.content())
-                .map(|r| GroupRole::from_str(r).unwrap_or(GroupRole::Member))
-                .collect();
-
-            let mut invite = Invite::new(event.id, roles);
+            let mut invite = Invite::new(event.id, role_tags(event));
             invite.reusable = is_reusable;
 
             self.invites.insert(code.to_string(), invite);
@@ -1356,6 +2375,130 @@ impl Group {
         Ok(())
     }
 
+    /// Applies a stored state event (39000-39003, 39010 or 39011)
+    pub fn load_state_event(&mut self, event: &Event) -> Result<(), Error> {
+        if event.kind == KIND_GROUP_METADATA_39000 {
+            self.load_metadata_from_event(event)
+        } else if event.kind == KIND_GROUP_ADMINS_39001 || event.kind == KIND_GROUP_MEMBERS_39002 {
+            self.load_members_from_event(event)
+        } else if event.kind == KIND_GROUP_ROLES_39003 {
+            self.load_roles_from_event(event)
+        } else if event.kind == KIND_GROUP_WEBHOOKS_39010 {
+            self.load_webhooks_from_event(event)
+        } else if event.kind == KIND_GROUP_BOT_TOKENS_39011 {
+            self.load_bot_tokens_from_event(event)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Replays a stored creation, join request, addition, removal, invite or
+    /// welcome note of the relay. Events must be replayed oldest first, after the state events.
+    pub fn load_history_event(
+        &mut self,
+        event: &Event,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<(), Error> {
+        if event.kind == KIND_GROUP_CREATE_9007 {
+            self.created_at = event.created_at;
+        } else if event.kind == KIND_GROUP_USER_JOIN_REQUEST_9021 {
+            self.load_join_request_from_event(event)?;
+        } else if event.kind == KIND_GROUP_CREATE_INVITE_9009 {
+            self.load_invite_from_event(event)?;
+        } else if event.kind == KIND_GROUP_ADD_USER_9000 {
+            self.load_addition_from_event(event);
+        } else if event.kind == KIND_GROUP_REMOVE_USER_9001 {
+            self.load_removal_from_event(event);
+        } else if event.kind == KIND_GROUP_MESSAGE_9
+            && relay_pubkey.is_relay(&event.pubkey)
+            && !is_rejection_note(event, relay_pubkey)
+        {
+            self.load_welcome_from_event(event);
+        }
+        Ok(())
+    }
+
+    /// Reconstructs a group from its stored events alone, the way groups are
+    /// loaded at startup: the state events first, then the history events
+    /// oldest first. Events of other kinds are ignored.
+    ///
+    /// Returns None when no state event names a group.
+    pub fn rebuild_from_events(
+        events: &[Event],
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<Option<Group>, Error> {
+        let mut events: Vec<&Event> = events.iter().collect();
+        events.sort_by_key(|event| (event.created_at, event.id));
+
+        // Metadata first, it's the event the group is created from
+        let mut state_events: Vec<&Event> = events
+            .iter()
+            .copied()
+            .filter(|event| GROUP_STATE_KINDS.contains(&event.kind))
+            .filter(|event| Self::extract_group_id(event).is_some())
+            .collect();
+        state_events.sort_by_key(|event| event.kind != KIND_GROUP_METADATA_39000);
+
+        let Some(first) = state_events.first() else {
+            return Ok(None);
+        };
+        let mut group = Group::from(*first);
+        for event in &state_events {
+            group.load_state_event(event)?;
+        }
+
+        for event in events {
+            if let Err(e) = group.load_history_event(event, relay_pubkey) {
+                warn!(
+                    "Error replaying event {} of group {}: {}",
+                    event.id, group.id, e
+                );
+            }
+        }
+
+        if let Some(updated_at) = state_events.iter().map(|event| event.created_at).max() {
+            group.updated_at = updated_at;
+        }
+        if group.normalize_roles() {
+            info!("Normalized the roles of group {}", group.id);
+        }
+        Ok(Some(group))
+    }
+
+    /// Rewrites the roles of members, invites and role definitions in their
+    /// canonical form, for state from before role names were normalized.
+    /// Definitions named like a built-in role are dropped, as built-in roles
+    /// can't be redefined. Returns whether anything changed.
+    pub fn normalize_roles(&mut self) -> bool {
+        let mut changed = false;
+        let roles_of_members = self.members.values_mut().map(|member| &mut member.roles);
+        let roles_of_invites = self.invites.values_mut().map(|invite| &mut invite.roles);
+        for roles in roles_of_members.chain(roles_of_invites) {
+            let normalized: HashSet<GroupRole> = roles.iter().map(GroupRole::normalized).collect();
+            if normalized != *roles {
+                *roles = normalized;
+                changed = true;
+            }
+        }
+
+        for (name, permissions) in std::mem::take(&mut self.role_permissions) {
+            let GroupRole::Custom(canonical) = GroupRole::Custom(name.clone()).normalized() else {
+                changed = true;
+                continue;
+            };
+            changed |= canonical != name;
+            self.role_permissions
+                .entry(canonical)
+                .or_default()
+                .insert(permissions);
+        }
+
+        if changed {
+            self.update_roles();
+        }
+        changed
+    }
+
     // Helper methods
     pub fn update_roles(&mut self) {
         let unique_roles = self
@@ -1380,20 +2523,227 @@ impl Group {
         event.tags.find(TagKind::h()).and_then(|t| t.content())
     }
 
-    pub fn verify_member_access(&self, pubkey: &PublicKey, event_kind: Kind) -> Result<(), Error> {
-        if event_kind != KIND_GROUP_USER_JOIN_REQUEST_9021
-            && self.metadata.closed
-            && !self.is_member(pubkey)
-        {
-            return Err(Error::restricted(format!(
-                "User {pubkey} is not a member of this group"
-            )));
-        }
-        Ok(())
+    /// Annotations are moderation notes, only admins and the relay read or write them
+    pub fn can_see_annotations(
+        &self,
+        pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> bool {
+        relay_pubkey.is_relay(pubkey) || self.is_admin(pubkey)
     }
 
-    pub fn update_timestamps(&mut self, event: &Event) {
-        // Only update created_at if this is a group creation event
+    /// Stores an admin's internal note about members (`p` tags) or events
+    /// (`e` tags). Annotations are never shown to non-admins, even in public
+    /// groups.
+    pub fn annotate(
+        &self,
+        event: Box<Event>,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        if event.kind != KIND_GROUP_ANNOTATION_9030 {
+            return Err(Error::invalid("Invalid event kind for annotation"));
+        }
+
+        if !self.can_see_annotations(&event.pubkey, relay_pubkey) {
+            return Err(Error::restricted("Only admins can annotate"));
+        }
+
+        if event.tags.find(TagKind::p()).is_none() && event.tags.find(TagKind::e()).is_none() {
+            return Err(Error::invalid(
+                "Annotations must target a member (p tag) or an event (e tag)",
+            ));
+        }
+
+        Ok(vec![StoreCommand::SaveSignedEvent(
+            event,
+            self.scope.clone(),
+            None,
+        )])
+    }
+
+    /// Webhooks are managed by admins and the relay
+    pub fn can_manage_webhooks(
+        &self,
+        pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> bool {
+        relay_pubkey.is_relay(pubkey) || self.is_admin(pubkey)
+    }
+
+    /// Enabled webhooks that should receive an event of this kind
+    pub fn active_webhooks(&self, kind: Kind) -> impl Iterator<Item = &GroupWebhook> {
+        let private = self.metadata.private;
+        self.webhooks.iter().filter(move |webhook| {
+            !webhook.disabled && (!private || webhook.allow_private) && webhook.matches(kind)
+        })
+    }
+
+    /// Registers a webhook, returning the regenerated registrations event
+    pub fn add_webhook(
+        &mut self,
+        caller: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+        webhook: GroupWebhook,
+        max_webhooks: usize,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        if !self.can_manage_webhooks(caller, relay_pubkey) {
+            return Err(GroupError::PermissionDenied(
+                "Only admins can manage webhooks".to_string(),
+            ));
+        }
+        if self.metadata.private && !webhook.allow_private {
+            return Err(GroupError::ValidationFailed(
+                "Webhooks of private groups must acknowledge that content leaves the relay"
+                    .to_string(),
+            ));
+        }
+        if self.webhooks.len() >= max_webhooks {
+            return Err(GroupError::ValidationFailed(format!(
+                "A group can have at most {max_webhooks} webhooks"
+            )));
+        }
+
+        self.webhooks.push(webhook);
+        Ok(self.webhooks_commands(relay_pubkey))
+    }
+
+    pub fn remove_webhook(
+        &mut self,
+        caller: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+        webhook_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        if !self.can_manage_webhooks(caller, relay_pubkey) {
+            return Err(GroupError::PermissionDenied(
+                "Only admins can manage webhooks".to_string(),
+            ));
+        }
+
+        let count = self.webhooks.len();
+        self.webhooks.retain(|webhook| webhook.id != webhook_id);
+        if self.webhooks.len() == count {
+            return Err(GroupError::NotFound(format!(
+                "Webhook {webhook_id} not found"
+            )));
+        }
+        Ok(self.webhooks_commands(relay_pubkey))
+    }
+
+    /// Turns a failing webhook off, admins can register it again once fixed
+    pub fn disable_webhook(
+        &mut self,
+        relay_pubkey: &impl RelayIdentity,
+        webhook_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        let webhook = self
+            .webhooks
+            .iter_mut()
+            .find(|webhook| webhook.id == webhook_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Webhook {webhook_id} not found")))?;
+        webhook.disabled = true;
+        Ok(self.webhooks_commands(relay_pubkey))
+    }
+
+    fn webhooks_commands(&self, relay_pubkey: &impl RelayIdentity) -> Vec<StoreCommand> {
+        vec![StoreCommand::SaveUnsignedEvent(
+            self.generate_webhooks_event(relay_pubkey),
+            self.scope.clone(),
+            None,
+        )]
+    }
+
+    /// Bot tokens are managed by admins and the relay
+    pub fn can_manage_bot_tokens(
+        &self,
+        pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> bool {
+        relay_pubkey.is_relay(pubkey) || self.is_admin(pubkey)
+    }
+
+    /// Capabilities of the unexpired token with this hash
+    pub fn bot_capabilities(
+        &self,
+        token_hash: &str,
+        now: Timestamp,
+    ) -> Option<&BTreeSet<BotCapability>> {
+        self.bot_tokens
+            .iter()
+            .find(|token| token.hash == token_hash && !token.is_expired(now))
+            .map(|token| &token.capabilities)
+    }
+
+    /// Adds a bot token, dropping expired ones, and returns the regenerated
+    /// tokens event
+    pub fn add_bot_token(
+        &mut self,
+        caller: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+        token: BotToken,
+        max_tokens: usize,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        if !self.can_manage_bot_tokens(caller, relay_pubkey) {
+            return Err(GroupError::PermissionDenied(
+                "Only admins can manage bot tokens".to_string(),
+            ));
+        }
+
+        let now = Timestamp::now();
+        self.bot_tokens.retain(|token| !token.is_expired(now));
+        if self.bot_tokens.len() >= max_tokens {
+            return Err(GroupError::ValidationFailed(format!(
+                "A group can have at most {max_tokens} bot tokens"
+            )));
+        }
+
+        self.bot_tokens.push(token);
+        Ok(self.bot_tokens_commands(relay_pubkey))
+    }
+
+    pub fn revoke_bot_token(
+        &mut self,
+        caller: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+        token_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        if !self.can_manage_bot_tokens(caller, relay_pubkey) {
+            return Err(GroupError::PermissionDenied(
+                "Only admins can manage bot tokens".to_string(),
+            ));
+        }
+
+        let count = self.bot_tokens.len();
+        self.bot_tokens.retain(|token| token.id != token_id);
+        if self.bot_tokens.len() == count {
+            return Err(GroupError::NotFound(format!(
+                "Bot token {token_id} not found"
+            )));
+        }
+        Ok(self.bot_tokens_commands(relay_pubkey))
+    }
+
+    fn bot_tokens_commands(&self, relay_pubkey: &impl RelayIdentity) -> Vec<StoreCommand> {
+        vec![StoreCommand::SaveUnsignedEvent(
+            self.generate_bot_tokens_event(relay_pubkey),
+            self.scope.clone(),
+            None,
+        )]
+    }
+
+    pub fn verify_member_access(&self, pubkey: &PublicKey, event_kind: Kind) -> Result<(), Error> {
+        if event_kind != KIND_GROUP_USER_JOIN_REQUEST_9021
+            && self.metadata.closed
+            && !self.is_member(pubkey)
+        {
+            return Err(Error::restricted(format!(
+                "User {pubkey} is not a member of this group"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn update_timestamps(&mut self, event: &Event) {
+        // Only update created_at if this is a group creation event
         if event.kind == KIND_GROUP_CREATE_9007 {
             self.created_at = event.created_at;
         }
@@ -1409,7 +2759,7 @@ impl Group {
     /// The 39001/39002 events reflect the current membership state.
     pub fn generate_membership_events(
         &self,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
     ) -> Result<Vec<UnsignedEvent>, Error> {
         let mut events = Vec::new();
 
@@ -1432,7 +2782,7 @@ impl Group {
     /// This is signed by the relay on behalf of the admin/system
     fn generate_put_user_event_for_member(
         &self,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         member: &GroupMember,
     ) -> UnsignedEvent {
         // Determine the primary role for this member
@@ -1443,7 +2793,7 @@ impl Group {
         };
 
         UnsignedEvent::new(
-            *relay_pubkey,
+            relay_pubkey.signing_key(),
             Timestamp::now_with_supplier(&Instant::now()),
             KIND_GROUP_ADD_USER_9000,
             vec![
@@ -1457,7 +2807,10 @@ impl Group {
         )
     }
 
-    pub fn generate_admins_event(&self, relay_pubkey: &PublicKey) -> Result<UnsignedEvent, Error> {
+    pub fn generate_admins_event(
+        &self,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> Result<UnsignedEvent, Error> {
         // Collect all admins (including relay if it's legitimately a member/admin)
         let admins: Vec<_> = self
             .members
@@ -1482,7 +2835,7 @@ impl Group {
                     .roles
                     .iter()
                     .filter(|role| matches!(role, GroupRole::Admin))
-                    .map(|role| format!("{role:?}")),
+                    .map(|role| role.name().to_string()),
             );
 
             let tag = Tag::custom(TagKind::p(), tag_vals);
@@ -1491,13 +2844,13 @@ impl Group {
 
         // Validate we have at least one admin
         if tags.len() <= 1 {
-            return Err(Error::notice(
+            return Err(Error::internal(
                 "Cannot generate 39001 event: group has no admins",
             ));
         }
 
         Ok(UnsignedEvent::new(
-            *relay_pubkey,
+            relay_pubkey.signing_key(),
             Timestamp::now_with_supplier(&Instant::now()),
             KIND_GROUP_ADMINS_39001,
             tags,
@@ -1505,7 +2858,7 @@ impl Group {
         ))
     }
 
-    pub fn generate_members_event(&self, relay_pubkey: &PublicKey) -> UnsignedEvent {
+    pub fn generate_members_event(&self, relay_pubkey: &impl RelayIdentity) -> UnsignedEvent {
         // Include all members (including relay if it's legitimately a member)
         let members: Vec<&PublicKey> = self.members.keys().collect();
 
@@ -1517,7 +2870,7 @@ impl Group {
         }
 
         UnsignedEvent::new(
-            *relay_pubkey,
+            relay_pubkey.signing_key(),
             Timestamp::now_with_supplier(&Instant::now()),
             KIND_GROUP_MEMBERS_39002,
             tags,
@@ -1528,7 +2881,7 @@ impl Group {
     /// Generates all metadata-related events for the group
     pub fn generate_metadata_events(
         &self,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         relay_url: &str,
     ) -> Vec<UnsignedEvent> {
         vec![
@@ -1540,7 +2893,7 @@ impl Group {
     /// Generates all group state events
     pub fn generate_all_state_events(
         &self,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         relay_url: &str,
     ) -> Result<Vec<UnsignedEvent>, Error> {
         let mut events = self.generate_metadata_events(relay_pubkey, relay_url);
@@ -1551,7 +2904,11 @@ impl Group {
 
 // Event generation based on current state
 impl Group {
-    pub fn generate_metadata_event(&self, pubkey: &PublicKey, relay_url: &str) -> UnsignedEvent {
+    pub fn generate_metadata_event(
+        &self,
+        pubkey: &impl RelayIdentity,
+        relay_url: &str,
+    ) -> UnsignedEvent {
         // Private = needs authentication to read
         let access = if self.metadata.private {
             "private"
@@ -1581,6 +2938,65 @@ impl Group {
             tags.push(Tag::custom(TagKind::custom("picture"), [picture.clone()]));
         }
 
+        if let Some(emoji_set) = &self.metadata.emoji_set {
+            tags.push(Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::A)),
+                [emoji_set.clone()],
+            ));
+        }
+
+        if let Some(limit) = self.metadata.max_content_length {
+            tags.push(Tag::custom(
+                TagKind::custom("max_content_length"),
+                [limit.to_string()],
+            ));
+        }
+
+        if let Some(limit) = self.metadata.max_media_urls {
+            tags.push(Tag::custom(
+                TagKind::custom("max_media_urls"),
+                [limit.to_string()],
+            ));
+        }
+
+        if let Some(welcome) = &self.metadata.welcome {
+            tags.push(Tag::custom(TagKind::custom("welcome"), [welcome.clone()]));
+        }
+
+        if let Some(limit) = self.metadata.max_events {
+            tags.push(Tag::custom(
+                TagKind::custom("max_events"),
+                [limit.to_string()],
+            ));
+        }
+
+        if let Some(slug) = &self.metadata.slug {
+            tags.push(Tag::custom(TagKind::custom("slug"), [slug.clone()]));
+        }
+
+        for (name, changed_at) in &self.metadata.previous_names {
+            tags.push(Tag::custom(
+                TagKind::custom("previous_name"),
+                [name.clone(), changed_at.as_u64().to_string()],
+            ));
+        }
+
+        for (name, kinds) in [
+            ("allowed_kinds", &self.metadata.allowed_kinds),
+            ("denied_kinds", &self.metadata.denied_kinds),
+        ] {
+            if !kinds.is_empty() {
+                tags.push(Tag::custom(
+                    TagKind::custom(name),
+                    kinds.iter().map(|kind| kind.as_u16().to_string()),
+                ));
+            }
+        }
+
+        if self.metadata.archived {
+            tags.push(Tag::custom(TagKind::custom("archived"), &[] as &[String]));
+        }
+
         // Add any unknown tags
         tags.extend(self.metadata.unknown_tags.iter().cloned());
 
@@ -1601,7 +3017,7 @@ impl Group {
         }
 
         UnsignedEvent::new(
-            *pubkey,
+            pubkey.signing_key(),
             Timestamp::now_with_supplier(&Instant::now()),
             KIND_GROUP_METADATA_39000,
             tags,
@@ -1609,70 +3025,226 @@ impl Group {
         )
     }
 
-    pub fn generate_roles_event(&self, pubkey: &PublicKey) -> UnsignedEvent {
-        let supported_roles: Vec<(String, String)> = GroupRole::iter()
+    pub fn generate_roles_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let mut supported_roles: Vec<(String, String, RolePermissions)> = GroupRole::iter()
+            .filter(|role| !matches!(role, GroupRole::Custom(_)))
             .map(|role| {
                 let (name, description) = role.as_tuple();
-                (name.to_string(), description.to_string())
+                let permissions = if role == GroupRole::Admin {
+                    RolePermissions::ALL
+                } else {
+                    RolePermissions::NONE
+                };
+                (name.to_string(), description.to_string(), permissions)
             })
             .collect();
 
+        let mut custom_roles: Vec<_> = self.role_permissions.iter().collect();
+        custom_roles.sort_by_key(|(name, _)| name.as_str());
+        supported_roles.extend(
+            custom_roles
+                .into_iter()
+                .map(|(name, permissions)| (name.clone(), "Custom role".to_string(), *permissions)),
+        );
+
         let mut tags = Vec::new();
         tags.push(Tag::identifier(self.id.clone()));
 
-        for (role_name, role_description) in supported_roles {
-            tags.push(Tag::custom(
-                TagKind::custom("role"),
-                vec![role_name, role_description],
-            ));
+        // ["role", name, description, permission...]
+        for (role_name, role_description, permissions) in supported_roles {
+            let mut values = vec![role_name, role_description];
+            values.extend(permissions.names().into_iter().map(str::to_string));
+            tags.push(Tag::custom(TagKind::custom("role"), values));
         }
 
         UnsignedEvent::new(
-            *pubkey,
+            pubkey.signing_key(),
             Timestamp::now_with_supplier(&Instant::now()),
             KIND_GROUP_ROLES_39003,
             tags,
             "List of roles supported by this group".to_string(),
         )
     }
+
+    /// Invite redemption history, only visible to admins
+    pub fn generate_redemptions_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let mut tags = vec![Tag::identifier(self.id.clone())];
+        let mut codes: Vec<_> = self.invites.iter().collect();
+        codes.sort_by(|a, b| a.0.cmp(b.0));
+        for (code, invite) in codes {
+            for (redeemer, redeemed_at) in &invite.redemptions {
+                tags.push(Tag::custom(
+                    TagKind::custom("redemption"),
+                    [
+                        code.clone(),
+                        redeemer.to_hex(),
+                        redeemed_at.as_u64().to_string(),
+                    ],
+                ));
+            }
+        }
+
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_INVITE_REDEMPTIONS_39005,
+            tags,
+            "".to_string(),
+        )
+    }
+
+    /// Pending join requests, only visible to admins
+    pub fn generate_join_requests_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let mut requesters: Vec<&PublicKey> = self.join_requests.iter().collect();
+        requesters.sort();
+        let mut tags = vec![Tag::identifier(self.id.clone())];
+        tags.extend(requesters.into_iter().map(|pk| Tag::public_key(*pk)));
+
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_JOIN_REQUESTS_39004,
+            tags,
+            "".to_string(),
+        )
+    }
+
+    /// Active mutes and bans, only visible to admins. Each `mute` or `ban` tag
+    /// holds the pubkey and, for timed ones, when it ends.
+    pub fn generate_moderation_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let mut tags = vec![Tag::identifier(self.id.clone())];
+        for (name, entries) in [
+            ("mute", &self.moderation.mutes),
+            ("ban", &self.moderation.bans),
+        ] {
+            let mut active: Vec<_> = entries
+                .iter()
+                .filter(|(_, until)| until.is_none_or(|until| until > Timestamp::now()))
+                .collect();
+            active.sort();
+            for (target, until) in active {
+                let mut values = vec![target.to_hex()];
+                values.extend(until.map(|until| until.as_u64().to_string()));
+                tags.push(Tag::custom(TagKind::custom(name), values));
+            }
+        }
+
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_MODERATION_39006,
+            tags,
+            "".to_string(),
+        )
+    }
+
+    /// A note telling a requester their join request was declined, only
+    /// visible to them and the admins
+    pub fn generate_rejection_note(
+        &self,
+        requester: PublicKey,
+        reason: Option<&str>,
+        pubkey: &impl RelayIdentity,
+    ) -> UnsignedEvent {
+        let name = if self.metadata.name.is_empty() {
+            &self.id
+        } else {
+            &self.metadata.name
+        };
+        let content = match reason {
+            Some(reason) => format!("Your request to join {name} was declined: {reason}"),
+            None => format!("Your request to join {name} was declined"),
+        };
+
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_MESSAGE_9,
+            vec![
+                Tag::custom(TagKind::h(), [self.id.clone()]),
+                Tag::public_key(requester),
+                Tag::custom(
+                    TagKind::custom(JOIN_REJECTED_TAG),
+                    [reason.unwrap_or_default()],
+                ),
+            ],
+            content,
+        )
+    }
+
+    /// Whether any invite of the group was redeemed
+    pub fn has_redemptions(&self) -> bool {
+        self.invites
+            .values()
+            .any(|invite| !invite.redemptions.is_empty())
+    }
+
+    /// Hashed bot tokens, only ever read back by the relay
+    pub fn generate_bot_tokens_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let content = serde_json::to_string(&self.bot_tokens).unwrap_or_else(|_| "[]".to_string());
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_BOT_TOKENS_39011,
+            vec![Tag::identifier(self.id.clone())],
+            content,
+        )
+    }
+
+    /// Webhook registrations, only ever read back by the relay
+    pub fn generate_webhooks_event(&self, pubkey: &impl RelayIdentity) -> UnsignedEvent {
+        let content = serde_json::to_string(&self.webhooks).unwrap_or_else(|_| "[]".to_string());
+        UnsignedEvent::new(
+            pubkey.signing_key(),
+            Timestamp::now_with_supplier(&Instant::now()),
+            KIND_GROUP_WEBHOOKS_39010,
+            vec![Tag::identifier(self.id.clone())],
+            content,
+        )
+    }
 }
 
 // Authorization checks
 impl Group {
-    pub fn can_edit_members(&self, pubkey: &PublicKey, relay_pubkey: &PublicKey) -> bool {
-        if pubkey == relay_pubkey {
+    pub fn can_edit_members(&self, pubkey: &PublicKey, relay_pubkey: &impl RelayIdentity) -> bool {
+        if relay_pubkey.is_relay(pubkey) {
             return true;
         }
 
-        if !self.is_admin(pubkey) {
-            return false;
-        }
+        self.has_permission(pubkey, RolePermissions::MANAGE_MEMBERS)
+    }
 
-        true
+    /// Admin membership, roles and role definitions are never delegated
+    pub fn can_manage_admins(&self, pubkey: &PublicKey, relay_pubkey: &impl RelayIdentity) -> bool {
+        relay_pubkey.is_relay(pubkey) || self.is_admin(pubkey)
     }
 
-    pub fn can_edit_metadata(&self, pubkey: &PublicKey, relay_pubkey: &PublicKey) -> bool {
-        if self.is_admin(pubkey) {
+    pub fn can_edit_metadata(&self, pubkey: &PublicKey, relay_pubkey: &impl RelayIdentity) -> bool {
+        if self.has_permission(pubkey, RolePermissions::EDIT_METADATA) {
             return true;
         }
 
         // Relay pubkey can see all events
-        if relay_pubkey == pubkey {
-            debug!("Relay pubkey {} can edit metadata", relay_pubkey);
+        if relay_pubkey.is_relay(pubkey) {
+            debug!("Relay pubkey {} can edit metadata", pubkey);
             return true;
         }
 
         false
     }
 
-    pub fn can_create_invites(&self, pubkey: &PublicKey, relay_pubkey: &PublicKey) -> bool {
-        if self.is_admin(pubkey) {
+    pub fn can_create_invites(
+        &self,
+        pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
+    ) -> bool {
+        if self.has_permission(pubkey, RolePermissions::CREATE_INVITES) {
             return true;
         }
 
         // Relay pubkey can see all events
-        if relay_pubkey == pubkey {
-            debug!("Relay pubkey {} can create invites", relay_pubkey);
+        if relay_pubkey.is_relay(pubkey) {
+            debug!("Relay pubkey {} can create invites", pubkey);
             return true;
         }
 
@@ -1681,9 +3253,16 @@ impl Group {
 
     pub fn can_delete_group(
         &self,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         delete_group_event: &Event,
     ) -> Result<(), Error> {
+        // Deleting the whole group is never delegated to custom roles
+        if !self.can_manage_admins(&delete_group_event.pubkey, relay_pubkey) {
+            return Err(Error::restricted(
+                "User is not authorized to delete this group",
+            ));
+        }
+
         // For group deletion events, we use the event's pubkey since it's signed
         // No need for NIP-42 authentication - the signature proves identity
         let deletion_pubkey = Some(delete_group_event.pubkey);
@@ -1693,29 +3272,27 @@ impl Group {
     pub fn can_delete_event(
         &self,
         authed_pubkey: &Option<PublicKey>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         event: &Event,
         target: &str,
     ) -> Result<(), Error> {
         let Some(authed_pubkey) = authed_pubkey else {
-            return Err(Error::auth_required(
-                "Auth required: User is not authenticated",
-            ));
+            return Err(Error::auth_required("User is not authenticated"));
         };
 
         // Relay pubkey can delete all events
-        if relay_pubkey == authed_pubkey {
+        if relay_pubkey.is_relay(authed_pubkey) {
             debug!(
                 "Relay pubkey {} can delete {} {}, kind {}",
-                relay_pubkey, target, event.id, event.kind
+                authed_pubkey, target, event.id, event.kind
             );
             return Ok(());
         }
 
-        // Only admins can delete events
-        if self.is_admin(authed_pubkey) {
+        // Admins and roles with the delete permission can delete events
+        if self.has_permission(authed_pubkey, RolePermissions::DELETE_EVENTS) {
             debug!(
-                "Admin {} can delete {} {}, kind {}",
+                "User {} can delete {} {}, kind {}",
                 authed_pubkey, target, event.id, event.kind
             );
             return Ok(());
@@ -1729,9 +3306,32 @@ impl Group {
     pub fn can_see_event(
         &self,
         authed_pubkey: &Option<PublicKey>,
-        relay_pubkey: &PublicKey,
+        relay_pubkey: &impl RelayIdentity,
         event: &Event,
     ) -> Result<bool, Error> {
+        // Annotations, pending join requests, invite redemptions and
+        // moderation are admin-only whatever the group's privacy
+        if event.kind == KIND_GROUP_ANNOTATION_9030
+            || event.kind == KIND_GROUP_JOIN_REQUESTS_39004
+            || event.kind == KIND_GROUP_INVITE_REDEMPTIONS_39005
+            || event.kind == KIND_GROUP_MODERATION_39006
+        {
+            return Ok(authed_pubkey
+                .as_ref()
+                .is_some_and(|pubkey| self.can_see_annotations(pubkey, relay_pubkey)));
+        }
+
+        // Rejection notes are for the requester and the admins
+        if is_rejection_note(event, relay_pubkey) {
+            return Ok(authed_pubkey.as_ref().is_some_and(|pubkey| {
+                self.can_see_annotations(pubkey, relay_pubkey)
+                    || event
+                        .tags
+                        .public_keys()
+                        .any(|requester| requester == pubkey)
+            }));
+        }
+
         // Public groups are always visible
         if !self.metadata.private {
             debug!(
@@ -1747,16 +3347,14 @@ impl Group {
                 "User is not authenticated, cannot see event {}, kind {}",
                 event.id, event.kind
             );
-            return Err(Error::auth_required(
-                "Auth required: User is not authenticated",
-            ));
+            return Err(Error::auth_required("User is not authenticated"));
         };
 
         // Relay pubkey can see all events
-        if relay_pubkey == authed_pubkey {
+        if relay_pubkey.is_relay(authed_pubkey) {
             debug!(
                 "Relay pubkey {} can see event {}, kind {}",
-                relay_pubkey, event.id, event.kind
+                authed_pubkey, event.id, event.kind
             );
             return Ok(true);
         }
@@ -1804,8 +3402,8 @@ mod tests {
     use crate::test_utils::TestGroupMetadata;
     use crate::test_utils::{
         add_member_to_group, create_test_delete_event, create_test_event, create_test_group,
-        create_test_invite_event, create_test_keys, create_test_metadata_event,
-        create_test_role_event, remove_member_from_group,
+        create_test_group_with_members, create_test_invite_event, create_test_keys,
+        create_test_metadata_event, create_test_role_event, remove_member_from_group,
     };
     #[tokio::test]
     async fn test_group_creation() {
@@ -2260,59 +3858,179 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_delete_event_request_without_auth_admin_can_delete() {
-        let (admin_keys, member_keys, _) = create_test_keys().await;
+    async fn test_annotations_visible_only_to_admins() {
+        let (admin_keys, member_keys, non_member_keys) = create_test_keys().await;
         let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        group.metadata.private = false;
         let relay_pubkey = admin_keys.public_key();
 
-        let event = create_test_event(
-            &member_keys,
-            11,
-            vec![Tag::custom(TagKind::h(), [&group_id])],
+        let annotation = create_test_event(
+            &admin_keys,
+            9030,
+            vec![
+                Tag::custom(TagKind::h(), [&group_id]),
+                Tag::public_key(member_keys.public_key()),
+            ],
         )
         .await;
-        let delete_event = create_test_delete_event(&admin_keys, &group_id, &event).await;
-
-        // Admin should be able to delete without NIP-42 auth - signature is sufficient
-        let result = group.delete_event_request(Box::new(delete_event.clone()), &relay_pubkey);
-
-        assert!(result.is_ok());
-        let commands = result.unwrap();
-        assert_eq!(commands.len(), 2); // Delete command + save delete request event
-
-        // Check the delete command
-        match &commands[0] {
-            StoreCommand::DeleteEvents(filter, _, None) => {
-                // Check that the filter would match the deleted event
-                assert!(filter.ids.as_ref().unwrap().contains(&event.id));
-            }
-            _ => panic!("Expected DeleteEvents command"),
-        }
+        assert!(group
+            .annotate(Box::new(annotation.clone()), &relay_pubkey)
+            .is_ok());
 
-        // Check the save delete request event command
-        match &commands[1] {
-            StoreCommand::SaveSignedEvent(saved_event, _, None) => {
-                assert_eq!(saved_event.id, delete_event.id);
-                assert_eq!(saved_event.kind, KIND_GROUP_DELETE_EVENT_9005);
-            }
-            _ => panic!("Expected SaveSignedEvent command"),
-        }
+        let can_see = |pubkey: Option<PublicKey>| {
+            group
+                .can_see_event(&pubkey, &relay_pubkey, &annotation)
+                .unwrap()
+        };
+        assert!(can_see(Some(admin_keys.public_key())));
+        // Even in a public group, and even when the note is about them
+        assert!(!can_see(Some(member_keys.public_key())));
+        assert!(!can_see(Some(non_member_keys.public_key())));
+        assert!(!can_see(None));
     }
 
     #[tokio::test]
-    async fn test_delete_event_request_wrong_kind() {
+    async fn test_members_cannot_annotate() {
         let (admin_keys, member_keys, _) = create_test_keys().await;
-        let (mut group, _group_id) = create_test_group(&admin_keys).await;
-        let relay_pubkey = admin_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
 
-        // Create a regular event to delete
-        let event_to_delete = create_test_event(
+        let annotation = create_test_event(
             &member_keys,
-            11, // Regular event
-            vec![Tag::custom(TagKind::h(), [group.id.clone()])],
+            9030,
+            vec![
+                Tag::custom(TagKind::h(), [&group_id]),
+                Tag::public_key(admin_keys.public_key()),
+            ],
         )
         .await;
-
+        assert_eq!(
+            group
+                .annotate(Box::new(annotation), &admin_keys.public_key())
+                .unwrap_err()
+                .to_string(),
+            "Restricted: Only admins can annotate"
+        );
+
+        let untargeted = create_test_event(
+            &admin_keys,
+            9030,
+            vec![Tag::custom(TagKind::h(), [&group_id])],
+        )
+        .await;
+        assert!(group
+            .annotate(Box::new(untargeted), &admin_keys.public_key())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deletion_targets_only_own_events() {
+        let (admin_keys, member_keys, other_keys) = create_test_keys().await;
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        add_member_to_group(&mut group, &admin_keys, &other_keys, &group_id).await;
+        let h_tag = || vec![Tag::custom(TagKind::h(), [&group_id])];
+
+        let own = create_test_event(&member_keys, 9, h_tag()).await;
+        let others = create_test_event(&other_keys, 9, h_tag()).await;
+        let targets = vec![own.clone(), others.clone()];
+
+        let deletion = create_test_event(&member_keys, 5, vec![]).await;
+        assert_eq!(
+            group
+                .deletion_targets(&deletion, &targets, &admin_keys.public_key())
+                .unwrap(),
+            vec![own.id]
+        );
+
+        // Admins may delete everyone's events
+        let admin_deletion = create_test_event(&admin_keys, 5, vec![]).await;
+        assert_eq!(
+            group
+                .deletion_targets(&admin_deletion, &targets, &admin_keys.public_key())
+                .unwrap(),
+            vec![own.id, others.id]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deletion_targets_rejects_invisible_events() {
+        let (admin_keys, member_keys, outsider_keys) = create_test_keys().await;
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+
+        let message = create_test_event(
+            &member_keys,
+            9,
+            vec![Tag::custom(TagKind::h(), [&group_id])],
+        )
+        .await;
+        let deletion = create_test_event(&outsider_keys, 5, vec![]).await;
+
+        assert_eq!(
+            group
+                .deletion_targets(&deletion, &[message], &admin_keys.public_key())
+                .unwrap_err()
+                .to_string(),
+            "Restricted: Cannot delete events in a group you can't see"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_event_request_without_auth_admin_can_delete() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        let relay_pubkey = admin_keys.public_key();
+
+        let event = create_test_event(
+            &member_keys,
+            11,
+            vec![Tag::custom(TagKind::h(), [&group_id])],
+        )
+        .await;
+        let delete_event = create_test_delete_event(&admin_keys, &group_id, &event).await;
+
+        // Admin should be able to delete without NIP-42 auth - signature is sufficient
+        let result = group.delete_event_request(Box::new(delete_event.clone()), &relay_pubkey);
+
+        assert!(result.is_ok());
+        let commands = result.unwrap();
+        assert_eq!(commands.len(), 2); // Delete command + save delete request event
+
+        // Check the delete command
+        match &commands[0] {
+            StoreCommand::DeleteEvents(filter, _, None) => {
+                // Check that the filter would match the deleted event
+                assert!(filter.ids.as_ref().unwrap().contains(&event.id));
+            }
+            _ => panic!("Expected DeleteEvents command"),
+        }
+
+        // Check the save delete request event command
+        match &commands[1] {
+            StoreCommand::SaveSignedEvent(saved_event, _, None) => {
+                assert_eq!(saved_event.id, delete_event.id);
+                assert_eq!(saved_event.kind, KIND_GROUP_DELETE_EVENT_9005);
+            }
+            _ => panic!("Expected SaveSignedEvent command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_event_request_wrong_kind() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let (mut group, _group_id) = create_test_group(&admin_keys).await;
+        let relay_pubkey = admin_keys.public_key();
+
+        // Create a regular event to delete
+        let event_to_delete = create_test_event(
+            &member_keys,
+            11, // Regular event
+            vec![Tag::custom(TagKind::h(), [group.id.clone()])],
+        )
+        .await;
+
         // Create delete request with wrong kind (9 instead of 9005)
         let delete_request = create_test_event(
             &admin_keys,
@@ -2328,7 +4046,7 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Notice: Invalid event kind for delete event"
+            "Notice: invalid: Invalid event kind for delete event"
         );
     }
 
@@ -2416,7 +4134,7 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Notice: Notice: Cannot unset last admin role"
+            "Notice: invalid: Cannot unset last admin role"
         );
 
         // Verify the admin still has admin role
@@ -2518,7 +4236,7 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Notice: Notice: Cannot unset last admin role"
+            "Notice: invalid: Cannot unset last admin role"
         );
 
         // Verify admin still has admin role
@@ -2564,7 +4282,7 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Notice: Notice: Cannot unset last admin role"
+            "Notice: invalid: Cannot unset last admin role"
         );
 
         // Verify admin still has admin role
@@ -3000,7 +4718,7 @@ mod tests {
         assert!(last_admin_result.is_err());
         assert_eq!(
             last_admin_result.unwrap_err().to_string(),
-            "Notice: Cannot remove last admin"
+            "Notice: invalid: Cannot remove last admin"
         );
     }
 
@@ -3021,7 +4739,8 @@ mod tests {
         let invite = group.invites.get(invite_code).unwrap();
         assert!(!invite.reusable, "Invite should be single-use by default");
         assert_eq!(
-            invite.redeemed_by, None,
+            invite.redeemed_by(),
+            None,
             "New invite should not be used yet"
         );
     }
@@ -3079,8 +4798,8 @@ mod tests {
 
         // Verify the invite is marked as used
         let invite = group.invites.get(invite_code).unwrap();
-        assert!(invite.redeemed_by.is_some());
-        let (redeemed_by, _) = invite.redeemed_by.unwrap();
+        assert!(invite.redeemed_by().is_some());
+        let (redeemed_by, _) = *invite.redeemed_by().unwrap();
         assert_eq!(redeemed_by, user1_keys.public_key());
 
         // Second user tries to use the same invite
@@ -3135,8 +4854,9 @@ mod tests {
         let invite = group.invites.get(invite_code).unwrap();
         assert!(invite.reusable);
         assert_eq!(
-            invite.redeemed_by, None,
-            "Reusable invite should not track usage"
+            invite.redeemed_by(),
+            None,
+            "Reusable invites have no single redeemer"
         );
 
         // Second user tries to use the same invite
@@ -3179,11 +4899,1142 @@ mod tests {
         // Verify single-use invite properties
         let single_use_invite = group.invites.get(single_use_code).unwrap();
         assert!(!single_use_invite.reusable);
-        assert_eq!(single_use_invite.redeemed_by, None);
+        assert_eq!(single_use_invite.redeemed_by(), None);
 
         // Verify reusable invite properties
         let reusable_invite = group.invites.get(reusable_code).unwrap();
         assert!(reusable_invite.reusable);
-        assert_eq!(reusable_invite.redeemed_by, None);
+        assert_eq!(reusable_invite.redeemed_by(), None);
+    }
+
+    #[tokio::test]
+    async fn test_protected_event_in_private_group() {
+        let (admin_keys, member_keys, non_member_keys) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+
+        let private_metadata_event = create_test_metadata_event(
+            &admin_keys,
+            &group_id,
+            TestGroupMetadata {
+                name: None,
+                about: None,
+                picture: None,
+                is_private: true,
+                is_closed: false,
+                is_broadcast: false,
+            },
+        )
+        .await;
+        group
+            .set_metadata(&private_metadata_event, &admin_keys.public_key())
+            .unwrap();
+
+        let protected_chat = create_test_event(
+            &member_keys,
+            9,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::protected(),
+            ],
+        )
+        .await;
+        assert!(group
+            .handle_group_content(Box::new(protected_chat.clone()), &relay_keys.public_key())
+            .is_ok());
+
+        // Stored with normal group visibility
+        assert!(group
+            .can_see_event(
+                &Some(admin_keys.public_key()),
+                &relay_keys.public_key(),
+                &protected_chat
+            )
+            .unwrap());
+        assert!(!group
+            .can_see_event(
+                &Some(non_member_keys.public_key()),
+                &relay_keys.public_key(),
+                &protected_chat
+            )
+            .unwrap());
+
+        // Non-members are not auto-joined by a protected event, even in an open group
+        let outsider_chat = create_test_event(
+            &non_member_keys,
+            9,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::protected(),
+            ],
+        )
+        .await;
+        let result = group.handle_group_content(Box::new(outsider_chat), &relay_keys.public_key());
+        assert!(result.is_err());
+        assert!(!group.is_member(&non_member_keys.public_key()));
+    }
+
+    fn emoji_set_event_tags(group_id: &str, count: usize) -> Vec<Tag> {
+        let mut tags = vec![
+            Tag::identifier(group_id.to_string()),
+            Tag::custom(TagKind::h(), [group_id.to_string()]),
+        ];
+        for i in 0..count {
+            tags.push(Tag::custom(
+                TagKind::custom("emoji"),
+                [format!("emoji_{i}"), format!("https://example.com/{i}.png")],
+            ));
+        }
+        tags
+    }
+
+    #[tokio::test]
+    async fn test_emoji_set_requires_admin() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+
+        let event =
+            create_test_event(&member_keys, 30030, emoji_set_event_tags(&group_id, 3)).await;
+
+        let result = group.set_emoji_set(&event, &relay_keys.public_key());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Only admins can publish the group emoji set"));
+        assert!(group.metadata.emoji_set.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_emoji_set_rejects_oversized_and_invalid_sets() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+
+        let oversized = create_test_event(
+            &admin_keys,
+            30030,
+            emoji_set_event_tags(&group_id, MAX_GROUP_EMOJIS + 1),
+        )
+        .await;
+        assert!(group
+            .set_emoji_set(&oversized, &relay_keys.public_key())
+            .is_err());
+
+        let mut tags = emoji_set_event_tags(&group_id, 1);
+        tags.push(Tag::custom(
+            TagKind::custom("emoji"),
+            ["insecure", "http://example.com/insecure.png"],
+        ));
+        let insecure = create_test_event(&admin_keys, 30030, tags).await;
+        assert!(group
+            .set_emoji_set(&insecure, &relay_keys.public_key())
+            .unwrap_err()
+            .to_string()
+            .contains("https"));
+
+        assert!(group.metadata.emoji_set.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_emoji_set_links_from_metadata() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+
+        let event = create_test_event(&admin_keys, 30030, emoji_set_event_tags(&group_id, 5)).await;
+        assert!(group
+            .set_emoji_set(&event, &relay_keys.public_key())
+            .is_ok());
+
+        let coordinate = format!("30030:{}:{}", admin_keys.public_key(), group_id);
+        assert_eq!(group.metadata.emoji_set, Some(coordinate.clone()));
+
+        let metadata_event =
+            group.generate_metadata_event(&relay_keys.public_key(), "wss://test.relay");
+        let a_tag = metadata_event
+            .tags
+            .find(TagKind::SingleLetter(SingleLetterTag::lowercase(
+                Alphabet::A,
+            )))
+            .and_then(|tag| tag.content());
+        assert_eq!(a_tag, Some(coordinate.as_str()));
+
+        // The link survives a reload from the stored metadata event
+        let mut reloaded = GroupMetadata::new(group_id.clone());
+        reloaded.apply_tags(&metadata_event.sign_with_keys(&relay_keys).unwrap());
+        assert_eq!(reloaded.emoji_set, Some(coordinate));
+    }
+
+    async fn add_moderator(group: &mut Group, admin_keys: &Keys, moderator_keys: &Keys) {
+        let define_roles = create_test_event(
+            admin_keys,
+            9003,
+            vec![
+                Tag::custom(TagKind::h(), [group.id.clone()]),
+                Tag::custom(
+                    TagKind::custom("role"),
+                    ["moderator", "delete_events", "manage_members"],
+                ),
+            ],
+        )
+        .await;
+        group
+            .define_roles(Box::new(define_roles), &admin_keys.public_key())
+            .unwrap();
+
+        let add_moderator = create_test_event(
+            admin_keys,
+            9000,
+            vec![
+                Tag::custom(TagKind::h(), [group.id.clone()]),
+                Tag::custom(
+                    TagKind::p(),
+                    [
+                        moderator_keys.public_key().to_string(),
+                        "moderator".to_string(),
+                    ],
+                ),
+            ],
+        )
+        .await;
+        group
+            .add_members_from_event(Box::new(add_moderator), &admin_keys.public_key())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_roles_with_0xchat_tag_shapes() {
+        let (admin_keys, member_keys, moderator_keys) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        add_member_to_group(&mut group, &admin_keys, &moderator_keys, &group_id).await;
+        add_moderator(&mut group, &admin_keys, &Keys::generate()).await;
+
+        // A relay hint where the role goes, with the role in its own tag
+        let promote = create_test_event(
+            &admin_keys,
+            9006,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(
+                    TagKind::p(),
+                    [
+                        member_keys.public_key().to_string(),
+                        "wss://relay.0xchat.com".to_string(),
+                    ],
+                ),
+                Tag::custom(TagKind::custom("role"), ["Administrator"]),
+            ],
+        )
+        .await;
+        group.set_roles(Box::new(promote), &relay_pubkey).unwrap();
+        assert!(group.is_admin(&member_keys.public_key()));
+        assert!(group.has_permission(&member_keys.public_key(), RolePermissions::EDIT_METADATA));
+
+        // An empty relay hint, then a capitalized alias
+        let moderate = create_test_event(
+            &admin_keys,
+            9006,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(
+                    TagKind::p(),
+                    [
+                        moderator_keys.public_key().to_string(),
+                        String::new(),
+                        "Mod".to_string(),
+                    ],
+                ),
+            ],
+        )
+        .await;
+        group.set_roles(Box::new(moderate), &relay_pubkey).unwrap();
+        let moderator = moderator_keys.public_key();
+        assert!(group.members[&moderator].is(GroupRole::Custom("moderator".to_string())));
+        assert!(group.has_permission(&moderator, RolePermissions::DELETE_EVENTS));
+        assert!(!group.has_permission(&moderator, RolePermissions::EDIT_METADATA));
+
+        // Generated lists use the canonical lowercase names
+        let admins_event = group.generate_admins_event(&relay_pubkey).unwrap();
+        for tag in admins_event.tags.filter(TagKind::p()) {
+            assert_eq!(tag.as_slice()[2..], ["admin".to_string()]);
+        }
+        let roles_event = group.generate_roles_event(&relay_pubkey);
+        assert!(roles_event
+            .tags
+            .filter(TagKind::custom("role"))
+            .filter_map(|tag| tag.content())
+            .all(|name| name == name.to_lowercase()));
+    }
+
+    #[tokio::test]
+    async fn test_loaded_roles_are_normalized() {
+        let (admin_keys, member_keys, owner_keys) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+
+        // A 39001 as written by other relays and older versions of this one
+        let admins_event = create_test_event(
+            &relay_keys,
+            39001,
+            vec![
+                Tag::identifier(group_id.clone()),
+                Tag::custom(
+                    TagKind::p(),
+                    [member_keys.public_key().to_string(), "Admin".to_string()],
+                ),
+                Tag::custom(
+                    TagKind::p(),
+                    [owner_keys.public_key().to_string(), "owner".to_string()],
+                ),
+            ],
+        )
+        .await;
+        group.load_members_from_event(&admins_event).unwrap();
+        assert!(group.is_admin(&member_keys.public_key()));
+        assert!(group.is_admin(&owner_keys.public_key()));
+
+        // Roles kept from before normalization are migrated
+        group
+            .members
+            .get_mut(&member_keys.public_key())
+            .unwrap()
+            .roles = HashSet::from([GroupRole::Custom("Admin".to_string())]);
+        group
+            .role_permissions
+            .insert("Moderator".to_string(), RolePermissions::DELETE_EVENTS);
+        assert!(!group.is_admin(&member_keys.public_key()));
+
+        assert!(group.normalize_roles());
+        assert!(group.is_admin(&member_keys.public_key()));
+        assert!(group.can_edit_metadata(&member_keys.public_key(), &relay_keys.public_key()));
+        assert_eq!(
+            group.role_permissions.get("moderator"),
+            Some(&RolePermissions::DELETE_EVENTS)
+        );
+        assert!(!group.role_permissions.contains_key("Moderator"));
+        assert!(!group.normalize_roles());
+    }
+
+    #[tokio::test]
+    async fn test_moderator_can_delete_messages_but_not_edit_metadata() {
+        let (admin_keys, member_keys, moderator_keys) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        add_moderator(&mut group, &admin_keys, &moderator_keys).await;
+
+        let message = create_test_event(
+            &member_keys,
+            9,
+            vec![Tag::custom(TagKind::h(), [group_id.clone()])],
+        )
+        .await;
+        let delete_event = create_test_delete_event(&moderator_keys, &group_id, &message).await;
+        assert!(group
+            .delete_event_request(Box::new(delete_event), &relay_pubkey)
+            .is_ok());
+
+        let rename = create_test_event(
+            &moderator_keys,
+            9002,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(TagKind::Name, ["Renamed"]),
+            ],
+        )
+        .await;
+        assert!(group.set_metadata(&rename, &relay_pubkey).is_err());
+        assert_eq!(group.metadata.name, group_id);
+
+        // Plain members keep no moderation permissions
+        let member_delete = create_test_delete_event(&member_keys, &group_id, &message).await;
+        assert!(group
+            .delete_event_request(Box::new(member_delete), &relay_pubkey)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_moderator_cannot_touch_admins() {
+        let (admin_keys, member_keys, moderator_keys) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_moderator(&mut group, &admin_keys, &moderator_keys).await;
+
+        // Approving a regular member is allowed
+        let add_member = create_test_event(
+            &moderator_keys,
+            9000,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::public_key(member_keys.public_key()),
+            ],
+        )
+        .await;
+        assert!(group
+            .add_members_from_event(Box::new(add_member), &relay_pubkey)
+            .is_ok());
+        assert!(group.is_member(&member_keys.public_key()));
+
+        // A second admin, so removing the first isn't blocked as the last admin
+        let second_admin = Keys::generate();
+        let add_admin = create_test_event(
+            &admin_keys,
+            9000,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(
+                    TagKind::p(),
+                    [second_admin.public_key().to_string(), "admin".to_string()],
+                ),
+            ],
+        )
+        .await;
+        group
+            .add_members_from_event(Box::new(add_admin), &relay_pubkey)
+            .unwrap();
+
+        let remove_admin = create_test_event(
+            &moderator_keys,
+            9001,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::public_key(admin_keys.public_key()),
+            ],
+        )
+        .await;
+        assert!(group
+            .remove_members(Box::new(remove_admin), &relay_pubkey)
+            .is_err());
+        assert!(group.is_admin(&admin_keys.public_key()));
+
+        let promote_self = create_test_event(
+            &moderator_keys,
+            9000,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(
+                    TagKind::p(),
+                    [moderator_keys.public_key().to_string(), "admin".to_string()],
+                ),
+            ],
+        )
+        .await;
+        assert!(group
+            .add_members_from_event(Box::new(promote_self), &relay_pubkey)
+            .is_err());
+        assert!(!group.is_admin(&moderator_keys.public_key()));
+    }
+
+    #[tokio::test]
+    async fn test_roles_event_describes_permissions() {
+        let (admin_keys, _, moderator_keys) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, _) = create_test_group(&admin_keys).await;
+        add_moderator(&mut group, &admin_keys, &moderator_keys).await;
+
+        let roles_event = group.generate_roles_event(&relay_pubkey);
+        let moderator_tag = roles_event
+            .tags
+            .iter()
+            .map(|tag| tag.as_slice())
+            .find(|tag| tag.get(1).map(String::as_str) == Some("moderator"))
+            .expect("moderator role listed");
+        assert_eq!(
+            moderator_tag[2..],
+            ["Custom role", "manage_members", "delete_events"]
+        );
+
+        // Definitions survive a reload from the roles event
+        let mut reloaded = Group::new_with_id(group.id.clone());
+        reloaded
+            .load_roles_from_event(&roles_event.sign_with_keys(&admin_keys).unwrap())
+            .unwrap();
+        assert_eq!(
+            reloaded.role_permissions.get("moderator"),
+            Some(&RolePermissions::from_names(["delete_events", "manage_members"]).unwrap())
+        );
+        assert!(!reloaded.role_permissions.contains_key("admin"));
+    }
+
+    async fn set_content_limits(
+        group: &mut Group,
+        admin_keys: &Keys,
+        max_content_length: &str,
+        max_media_urls: &str,
+    ) {
+        let event = create_test_event(
+            admin_keys,
+            9002,
+            vec![
+                Tag::custom(TagKind::h(), [group.id.clone()]),
+                Tag::custom(TagKind::custom("max_content_length"), [max_content_length]),
+                Tag::custom(TagKind::custom("max_media_urls"), [max_media_urls]),
+            ],
+        )
+        .await;
+        group
+            .set_metadata(&event, &Keys::generate().public_key())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_content_limits_at_boundaries() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        set_content_limits(&mut group, &admin_keys, "500", "2").await;
+
+        let message = |content: String| {
+            let builder = EventBuilder::new(Kind::Custom(9), content)
+                .tag(Tag::custom(TagKind::h(), [group_id.clone()]));
+            Box::new(builder.sign_with_keys(&member_keys).unwrap())
+        };
+
+        // Characters, not bytes
+        assert!(group
+            .handle_group_content(message("é".repeat(500)), &relay_pubkey)
+            .is_ok());
+        let err = group
+            .handle_group_content(message("a".repeat(501)), &relay_pubkey)
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid:"));
+        assert!(err.to_string().contains("500 characters"));
+
+        let two_urls = "see https://a.example/x.png and http://b.example/y.jpg".to_string();
+        assert!(group
+            .handle_group_content(message(two_urls.clone()), &relay_pubkey)
+            .is_ok());
+        let err = group
+            .handle_group_content(
+                message(format!("{two_urls} https://c.example/z.gif")),
+                &relay_pubkey,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("2 media URLs"));
+    }
+
+    #[tokio::test]
+    async fn test_content_limits_clamped_and_published() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, _) = create_test_group(&admin_keys).await;
+        set_content_limits(&mut group, &admin_keys, "100000000", "1000").await;
+
+        assert_eq!(
+            group.metadata.max_content_length,
+            Some(MAX_GROUP_CONTENT_LENGTH)
+        );
+        assert_eq!(group.metadata.max_media_urls, Some(MAX_GROUP_MEDIA_URLS));
+
+        let metadata_event = group.generate_metadata_event(&relay_pubkey, "wss://test.relay");
+        let tag_value = |name: &str| {
+            metadata_event
+                .tags
+                .iter()
+                .map(|tag| tag.as_slice())
+                .find(|tag| tag[0] == name)
+                .map(|tag| tag[1].clone())
+        };
+        assert_eq!(
+            tag_value("max_content_length"),
+            Some(MAX_GROUP_CONTENT_LENGTH.to_string())
+        );
+        assert_eq!(
+            tag_value("max_media_urls"),
+            Some(MAX_GROUP_MEDIA_URLS.to_string())
+        );
+
+        // 0 removes the limits
+        set_content_limits(&mut group, &admin_keys, "0", "0").await;
+        assert_eq!(group.metadata.max_content_length, None);
+        assert_eq!(group.metadata.max_media_urls, None);
+    }
+
+    #[tokio::test]
+    async fn test_only_the_relay_sets_a_group_quota() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        let tags = vec![
+            Tag::custom(TagKind::h(), [group_id.clone()]),
+            Tag::custom(TagKind::custom("max_events"), ["1000"]),
+        ];
+
+        let by_admin = create_test_event(&admin_keys, 9002, tags.clone()).await;
+        assert!(group
+            .set_metadata(&by_admin, &relay_keys.public_key())
+            .unwrap_err()
+            .to_string()
+            .contains("relay operator"));
+        assert_eq!(group.metadata.max_events, None);
+
+        let by_relay = create_test_event(&relay_keys, 9002, tags).await;
+        group
+            .set_metadata(&by_relay, &relay_keys.public_key())
+            .unwrap();
+        assert_eq!(group.metadata.max_events, Some(1000));
+
+        // The quota survives a reload from the stored metadata event
+        let metadata_event =
+            group.generate_metadata_event(&relay_keys.public_key(), "wss://test.relay");
+        let mut reloaded = GroupMetadata::new(group_id);
+        reloaded.apply_tags(&metadata_event.sign_with_keys(&relay_keys).unwrap());
+        assert_eq!(reloaded.max_events, Some(1000));
+    }
+
+    #[tokio::test]
+    async fn test_renames_are_kept_as_previous_names() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        let original = group.metadata.name.clone();
+        let rename = |name: &str| {
+            create_test_event(
+                &admin_keys,
+                9002,
+                vec![
+                    Tag::custom(TagKind::h(), [group_id.clone()]),
+                    Tag::custom(TagKind::Name, [name]),
+                ],
+            )
+        };
+
+        for name in ["Renamed", "Renamed", "Final"] {
+            let event = rename(name).await;
+            group
+                .set_metadata(&event, &relay_keys.public_key())
+                .unwrap();
+        }
+        let names: Vec<&str> = group
+            .metadata
+            .previous_names
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, [original.as_str(), "Renamed"]);
+
+        // Only the relay's metadata event carries the history
+        let forged = create_test_event(
+            &admin_keys,
+            9002,
+            vec![
+                Tag::custom(TagKind::h(), [group_id.clone()]),
+                Tag::custom(TagKind::custom("previous_name"), ["Forged", "1"]),
+            ],
+        )
+        .await;
+        group
+            .set_metadata(&forged, &relay_keys.public_key())
+            .unwrap();
+        assert_eq!(group.metadata.previous_names.len(), 2);
+
+        let metadata_event = group
+            .generate_metadata_event(&relay_keys.public_key(), "wss://test.relay")
+            .sign_with_keys(&relay_keys)
+            .unwrap();
+        let mut reloaded = Group::from(&metadata_event);
+        reloaded.load_metadata_from_event(&metadata_event).unwrap();
+        assert_eq!(
+            reloaded.metadata.previous_names,
+            group.metadata.previous_names
+        );
+
+        group.metadata.truncate_previous_names(1);
+        assert_eq!(group.metadata.previous_names[0].0, "Renamed");
+    }
+
+    async fn set_kind_policy(group: &mut Group, admin_keys: &Keys, name: &str, kinds: &[&str]) {
+        let event = create_test_event(
+            admin_keys,
+            9002,
+            vec![
+                Tag::custom(TagKind::h(), [group.id.clone()]),
+                Tag::custom(TagKind::custom(name), kinds.iter().copied()),
+            ],
+        )
+        .await;
+        group
+            .set_metadata(&event, &Keys::generate().public_key())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kind_policy() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let relay_pubkey = relay_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+
+        let post = |kind: u16| {
+            let builder = EventBuilder::new(Kind::Custom(kind), "hello")
+                .tag(Tag::custom(TagKind::h(), [group_id.clone()]));
+            Box::new(builder.sign_with_keys(&member_keys).unwrap())
+        };
+
+        set_kind_policy(&mut group, &admin_keys, "allowed_kinds", &["9", "x"]).await;
+        assert_eq!(group.metadata.allowed_kinds, vec![Kind::Custom(9)]);
+        assert!(group.handle_group_content(post(9), &relay_pubkey).is_ok());
+        let err = group
+            .handle_group_content(post(1), &relay_pubkey)
+            .unwrap_err();
+        assert!(err.to_string().contains(KIND_NOT_PERMITTED));
+        // Management kinds are never restricted
+        assert!(group
+            .metadata
+            .permits_kind(KIND_GROUP_USER_LEAVE_REQUEST_9022));
+        assert!(group.metadata.permits_kind(KIND_GROUP_EDIT_METADATA_9002));
+
+        // The policy is published and survives a reload
+        let metadata_event = group.generate_metadata_event(&relay_pubkey, "wss://test.relay");
+        let mut reloaded = GroupMetadata::new(group_id.clone());
+        reloaded.apply_tags(&metadata_event.sign_with_keys(&relay_keys).unwrap());
+        assert_eq!(reloaded.allowed_kinds, vec![Kind::Custom(9)]);
+
+        // Denied kinds win over allowed ones
+        set_kind_policy(&mut group, &admin_keys, "denied_kinds", &["9"]).await;
+        assert!(group.handle_group_content(post(9), &relay_pubkey).is_err());
+
+        // A tag without kinds clears the list
+        set_kind_policy(&mut group, &admin_keys, "allowed_kinds", &[]).await;
+        set_kind_policy(&mut group, &admin_keys, "denied_kinds", &[]).await;
+        assert!(group.handle_group_content(post(1), &relay_pubkey).is_ok());
+        let metadata_event = group.generate_metadata_event(&relay_pubkey, "wss://test.relay");
+        assert!(metadata_event
+            .tags
+            .iter()
+            .all(|tag| !tag.as_slice()[0].ends_with("_kinds")));
+    }
+
+    fn webhook(id: &str, allow_private: bool) -> GroupWebhook {
+        GroupWebhook {
+            id: id.to_string(),
+            url: "https://bridge.example.com/hook".to_string(),
+            kinds: vec![],
+            allow_private,
+            disabled: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_registration_permissions() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, _) = create_test_group_with_members(&admin_keys, &member_keys).await;
+        assert!(group.metadata.private);
+
+        assert!(matches!(
+            group.add_webhook(
+                &member_keys.public_key(),
+                &relay_pubkey,
+                webhook("a", true),
+                2
+            ),
+            Err(GroupError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            group.add_webhook(
+                &admin_keys.public_key(),
+                &relay_pubkey,
+                webhook("a", false),
+                2
+            ),
+            Err(GroupError::ValidationFailed(_))
+        ));
+
+        let commands = group
+            .add_webhook(
+                &admin_keys.public_key(),
+                &relay_pubkey,
+                webhook("a", true),
+                2,
+            )
+            .unwrap();
+        let [StoreCommand::SaveUnsignedEvent(event, _, _)] = commands.as_slice() else {
+            panic!("expected the regenerated registrations event");
+        };
+        assert_eq!(event.kind, KIND_GROUP_WEBHOOKS_39010);
+        group
+            .add_webhook(&relay_pubkey, &relay_pubkey, webhook("b", true), 2)
+            .unwrap();
+        assert!(matches!(
+            group.add_webhook(
+                &admin_keys.public_key(),
+                &relay_pubkey,
+                webhook("c", true),
+                2
+            ),
+            Err(GroupError::ValidationFailed(_))
+        ));
+
+        assert!(matches!(
+            group.remove_webhook(&member_keys.public_key(), &relay_pubkey, "a"),
+            Err(GroupError::PermissionDenied(_))
+        ));
+        group
+            .remove_webhook(&admin_keys.public_key(), &relay_pubkey, "a")
+            .unwrap();
+        assert_eq!(group.webhooks, vec![webhook("b", true)]);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_registrations_round_trip() {
+        let (admin_keys, _, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        let mut bridge = webhook("bridge", true);
+        bridge.kinds = vec![9];
+        group
+            .add_webhook(
+                &admin_keys.public_key(),
+                &relay_keys.public_key(),
+                bridge,
+                5,
+            )
+            .unwrap();
+        group
+            .disable_webhook(&relay_keys.public_key(), "bridge")
+            .unwrap();
+
+        let event = group
+            .generate_webhooks_event(&relay_keys.public_key())
+            .sign_with_keys(&relay_keys)
+            .unwrap();
+        let mut loaded = Group::new_with_id(group_id);
+        loaded.load_webhooks_from_event(&event).unwrap();
+
+        assert_eq!(loaded.webhooks, group.webhooks);
+        assert!(loaded.webhooks[0].disabled);
+        assert_eq!(group.active_webhooks(Kind::Custom(9)).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_archived_group_is_read_only() {
+        let (admin_keys, member_keys, non_member_keys) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let relay_pubkey = relay_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        let h = Tag::custom(TagKind::h(), [group_id.clone()]);
+
+        let note = create_test_event(&member_keys, 9, vec![h.clone()]).await;
+        group
+            .handle_group_content(Box::new(note.clone()), &relay_pubkey)
+            .unwrap();
+
+        let archive = create_test_event(
+            &admin_keys,
+            KIND_GROUP_EDIT_METADATA_9002.as_u16(),
+            vec![h.clone(), Tag::custom(TagKind::custom("archived"), [""])],
+        )
+        .await;
+        group.set_metadata(&archive, &relay_pubkey).unwrap();
+        assert!(group.metadata.archived);
+
+        // No new content, members or invites
+        let blocked = create_test_event(&member_keys, 9, vec![h.clone()]).await;
+        let result = group.handle_group_content(Box::new(blocked), &relay_pubkey);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("Restricted: {GROUP_ARCHIVED}")
+        );
+        let join = create_test_event(
+            &non_member_keys,
+            KIND_GROUP_USER_JOIN_REQUEST_9021.as_u16(),
+            vec![h.clone()],
+        )
+        .await;
+        assert!(matches!(
+            group.join_request(Box::new(join), &relay_pubkey),
+            Err(Error::Restricted { .. })
+        ));
+        let invite = create_test_event(
+            &admin_keys,
+            KIND_GROUP_CREATE_INVITE_9009.as_u16(),
+            vec![
+                h.clone(),
+                Tag::custom(TagKind::custom("code"), ["archived"]),
+            ],
+        )
+        .await;
+        assert!(matches!(
+            group.create_invite(&invite, &relay_pubkey),
+            Err(Error::Restricted { .. })
+        ));
+
+        // History stays readable and membership intact
+        assert!(group.is_member(&member_keys.public_key()));
+        assert!(group
+            .can_see_event(&Some(member_keys.public_key()), &relay_pubkey, &note)
+            .unwrap());
+
+        // The flag survives a restart through the stored 39000
+        let metadata = group
+            .generate_metadata_event(&relay_pubkey, "wss://test.relay")
+            .sign_with_keys(&relay_keys)
+            .unwrap();
+        let mut loaded = Group::new_with_id(group_id.clone());
+        loaded.load_metadata_from_event(&metadata).unwrap();
+        assert!(loaded.metadata.archived);
+
+        let unarchive = create_test_event(
+            &admin_keys,
+            KIND_GROUP_EDIT_METADATA_9002.as_u16(),
+            vec![h.clone(), Tag::custom(TagKind::custom("unarchived"), [""])],
+        )
+        .await;
+        group.set_metadata(&unarchive, &relay_pubkey).unwrap();
+        assert!(!group.metadata.archived);
+        let note = create_test_event(&member_keys, 9, vec![h]).await;
+        assert!(group
+            .handle_group_content(Box::new(note), &relay_pubkey)
+            .is_ok());
+        let metadata = group.generate_metadata_event(&relay_pubkey, "wss://test.relay");
+        assert!(metadata.tags.find(TagKind::custom("archived")).is_none());
+    }
+
+    fn saved_unsigned(commands: &[StoreCommand], kind: Kind) -> Vec<UnsignedEvent> {
+        commands
+            .iter()
+            .filter_map(|command| match command {
+                StoreCommand::SaveUnsignedEvent(event, _, _) if event.kind == kind => {
+                    Some(event.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rejected_requester_is_notified_and_can_ask_again() {
+        let (admin_keys, requester_keys, member_keys) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let relay_pubkey = relay_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        let h = Tag::custom(TagKind::h(), [group_id.clone()]);
+        let requester = requester_keys.public_key();
+
+        let join = create_test_event(&requester_keys, 9021, vec![h.clone()]).await;
+        let commands = group.join_request(Box::new(join), &relay_pubkey).unwrap();
+        let pending = saved_unsigned(&commands, KIND_GROUP_JOIN_REQUESTS_39004);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].tags.public_keys().collect::<Vec<_>>(),
+            [&requester]
+        );
+
+        let reject = create_test_event(
+            &admin_keys,
+            9001,
+            vec![
+                h.clone(),
+                Tag::public_key(requester),
+                Tag::custom(TagKind::custom("reason"), ["not this time"]),
+            ],
+        )
+        .await;
+        let commands = group
+            .remove_members(Box::new(reject), &relay_pubkey)
+            .unwrap();
+        assert!(!group.join_requests.contains(&requester));
+        assert!(!group.moderation.is_banned(&requester));
+
+        let pending = saved_unsigned(&commands, KIND_GROUP_JOIN_REQUESTS_39004);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].tags.public_keys().count(), 0);
+
+        // Only the requester and the admins read the note
+        let notes = saved_unsigned(&commands, KIND_GROUP_MESSAGE_9);
+        assert_eq!(notes.len(), 1);
+        let note = notes[0].clone().sign_with_keys(&relay_keys).unwrap();
+        assert!(note.content.ends_with("not this time"));
+        assert!(is_rejection_note(&note, &relay_pubkey));
+        assert!(group
+            .can_see_event(&Some(requester), &relay_pubkey, &note)
+            .unwrap());
+        assert!(group
+            .can_see_event(&Some(admin_keys.public_key()), &relay_pubkey, &note)
+            .unwrap());
+        assert!(!group
+            .can_see_event(&Some(member_keys.public_key()), &relay_pubkey, &note)
+            .unwrap());
+        let pending = pending[0].clone().sign_with_keys(&relay_keys).unwrap();
+        assert!(!group
+            .can_see_event(&Some(member_keys.public_key()), &relay_pubkey, &pending)
+            .unwrap());
+
+        // Without a ban the requester can ask again
+        let rejoin = create_test_event(&requester_keys, 9021, vec![h]).await;
+        group.join_request(Box::new(rejoin), &relay_pubkey).unwrap();
+        assert!(group.join_requests.contains(&requester));
+    }
+
+    #[tokio::test]
+    async fn test_banned_requester_cannot_ask_again() {
+        let (admin_keys, requester_keys, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let relay_pubkey = relay_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        let h = Tag::custom(TagKind::h(), [group_id.clone()]);
+        let requester = requester_keys.public_key();
+
+        let join = create_test_event(&requester_keys, 9021, vec![h.clone()]).await;
+        group.join_request(Box::new(join), &relay_pubkey).unwrap();
+        let reject = create_test_event(
+            &admin_keys,
+            9001,
+            vec![
+                h.clone(),
+                Tag::public_key(requester),
+                Tag::custom(TagKind::custom("ban"), [""]),
+            ],
+        )
+        .await;
+        group
+            .remove_members(Box::new(reject.clone()), &relay_pubkey)
+            .unwrap();
+        assert!(group.moderation.is_banned(&requester));
+
+        let rejoin = create_test_event(&requester_keys, 9021, vec![h.clone()]).await;
+        let result = group.join_request(Box::new(rejoin), &relay_pubkey);
+        assert!(result.unwrap_err().to_string().starts_with("Restricted"));
+        assert!(!group.join_requests.contains(&requester));
+
+        // The ban survives a restart through the stored removal
+        let mut loaded = Group::new_with_id(group_id.clone());
+        loaded.load_history_event(&reject, &relay_pubkey).unwrap();
+        assert!(loaded.moderation.is_banned(&requester));
+
+        // Adding the requester lifts the ban
+        let add = create_test_event(&admin_keys, 9000, vec![h, Tag::public_key(requester)]).await;
+        group
+            .add_members_from_event(Box::new(add.clone()), &relay_pubkey)
+            .unwrap();
+        assert!(group.is_member(&requester));
+        assert!(!group.moderation.is_banned(&requester));
+        loaded.load_history_event(&add, &relay_pubkey).unwrap();
+        assert!(!loaded.moderation.is_banned(&requester));
+    }
+
+    #[tokio::test]
+    async fn test_mute_expires_and_member_can_post_again() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_keys = Keys::generate();
+        let relay_pubkey = relay_keys.public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        let h = Tag::custom(TagKind::h(), [group_id.clone()]);
+        let member = member_keys.public_key();
+
+        let until = Timestamp::now() + 2;
+        let mute = create_test_event(
+            &admin_keys,
+            9000,
+            vec![
+                h.clone(),
+                Tag::public_key(member),
+                Tag::custom(TagKind::custom("mute"), [""]),
+                Tag::expiration(until),
+            ],
+        )
+        .await;
+        let commands = group
+            .add_members_from_event(Box::new(mute.clone()), &relay_pubkey)
+            .unwrap();
+        // Muting leaves the membership alone
+        assert!(group.is_member(&member));
+        assert_eq!(group.moderation.mutes[&member], Some(until));
+
+        let state = saved_unsigned(&commands, KIND_GROUP_MODERATION_39006);
+        assert_eq!(state.len(), 1);
+        let mute_tag = state[0].tags.find(TagKind::custom("mute")).unwrap();
+        assert_eq!(
+            mute_tag.as_slice()[1..],
+            [member.to_hex(), until.as_u64().to_string()]
+        );
+        let state = state[0].clone().sign_with_keys(&relay_keys).unwrap();
+        assert!(!group
+            .can_see_event(&Some(member), &relay_pubkey, &state)
+            .unwrap());
+        assert!(group
+            .can_see_event(&Some(admin_keys.public_key()), &relay_pubkey, &state)
+            .unwrap());
+
+        let post = create_test_event(&member_keys, 9, vec![h.clone()]).await;
+        let result = group.handle_group_content(Box::new(post), &relay_pubkey);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("Restricted: {GROUP_MUTED}")
+        );
+
+        // The mute survives a restart through the stored 9000
+        let mut loaded = Group::new_with_id(group_id.clone());
+        loaded.load_history_event(&mute, &relay_pubkey).unwrap();
+        assert!(loaded.moderation.is_muted(&member));
+
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        assert!(!group.moderation.is_muted(&member));
+        assert!(!loaded.moderation.is_muted(&member));
+        let post = create_test_event(&member_keys, 9, vec![h]).await;
+        assert!(group
+            .handle_group_content(Box::new(post), &relay_pubkey)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ban_blocks_content_and_join_until_lifted() {
+        let (admin_keys, member_keys, _) = create_test_keys().await;
+        let relay_pubkey = Keys::generate().public_key();
+        let (mut group, group_id) = create_test_group(&admin_keys).await;
+        add_member_to_group(&mut group, &admin_keys, &member_keys, &group_id).await;
+        let h = Tag::custom(TagKind::h(), [group_id.clone()]);
+        let member = member_keys.public_key();
+
+        let ban = create_test_event(
+            &admin_keys,
+            9001,
+            vec![
+                h.clone(),
+                Tag::public_key(member),
+                Tag::custom(TagKind::custom("ban"), [""]),
+            ],
+        )
+        .await;
+        let commands = group.remove_members(Box::new(ban), &relay_pubkey).unwrap();
+        assert!(!group.is_member(&member));
+        assert_eq!(group.moderation.bans[&member], None);
+        let state = saved_unsigned(&commands, KIND_GROUP_MODERATION_39006);
+        assert_eq!(
+            state[0]
+                .tags
+                .find(TagKind::custom("ban"))
+                .unwrap()
+                .as_slice()[1..],
+            [member.to_hex()]
+        );
+
+        let post = create_test_event(&member_keys, 9, vec![h.clone()]).await;
+        let result = group.handle_group_content(Box::new(post), &relay_pubkey);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("Restricted: {GROUP_BANNED}")
+        );
+        let join = create_test_event(&member_keys, 9021, vec![h.clone()]).await;
+        assert!(group.join_request(Box::new(join), &relay_pubkey).is_err());
+
+        let add = create_test_event(&admin_keys, 9000, vec![h, Tag::public_key(member)]).await;
+        let commands = group
+            .add_members_from_event(Box::new(add), &relay_pubkey)
+            .unwrap();
+        assert!(group.moderation.is_empty());
+        let state = saved_unsigned(&commands, KIND_GROUP_MODERATION_39006);
+        assert!(state[0].tags.find(TagKind::custom("ban")).is_none());
     }
 }
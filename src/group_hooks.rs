@@ -0,0 +1,130 @@
+//! Integration point for reacting to group lifecycle changes.
+//!
+//! The groups event processor invokes every registered [`GroupEventHook`]
+//! after an event has been accepted and its store commands produced.
+//! Implementations must return quickly; anything slow (network calls) should
+//! be queued and handled in the background.
+
+use crate::group::{
+    GroupMetadata, KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_DELETE_9008,
+    KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_REMOVE_USER_9001, KIND_GROUP_USER_JOIN_REQUEST_9021,
+    KIND_GROUP_USER_LEAVE_REQUEST_9022,
+};
+use crate::Groups;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+#[async_trait]
+pub trait GroupEventHook: Send + Sync + Debug {
+    async fn on_group_created(&self, _scope: &Scope, _group_id: &str, _creator: &PublicKey) {}
+
+    async fn on_group_deleted(&self, _scope: &Scope, _group_id: &str) {}
+
+    async fn on_member_added(&self, _scope: &Scope, _group_id: &str, _member: &PublicKey) {}
+
+    async fn on_member_removed(&self, _scope: &Scope, _group_id: &str, _member: &PublicKey) {}
+
+    async fn on_metadata_changed(
+        &self,
+        _scope: &Scope,
+        _group_id: &str,
+        _metadata: &GroupMetadata,
+    ) {
+    }
+}
+
+/// A single change to a group, derived from an accepted event.
+#[derive(Debug, Clone)]
+pub enum GroupChange {
+    Created { creator: PublicKey },
+    Deleted,
+    MemberAdded(PublicKey),
+    MemberRemoved(PublicKey),
+    MetadataChanged(GroupMetadata),
+}
+
+/// Membership of the event author before the event was processed, needed to
+/// tell whether a join or leave request actually changed anything.
+pub fn was_member(groups: &Groups, event: &Event, scope: &Scope) -> bool {
+    group_id(event)
+        .and_then(|group_id| groups.get_group(scope, group_id))
+        .is_some_and(|group| group.is_member(&event.pubkey))
+}
+
+pub fn group_id(event: &Event) -> Option<&str> {
+    event.tags.find(TagKind::h()).and_then(|tag| tag.content())
+}
+
+/// Works out which group changes an accepted event caused.
+pub fn changes_for(
+    groups: &Groups,
+    event: &Event,
+    scope: &Scope,
+    was_member: bool,
+) -> Vec<GroupChange> {
+    let Some(group_id) = group_id(event) else {
+        return vec![];
+    };
+    let group = groups.get_group(scope, group_id);
+    let is_member = group
+        .as_ref()
+        .is_some_and(|group| group.is_member(&event.pubkey));
+
+    match event.kind {
+        k if k == KIND_GROUP_CREATE_9007 => vec![GroupChange::Created {
+            creator: event.pubkey,
+        }],
+        k if k == KIND_GROUP_DELETE_9008 => vec![GroupChange::Deleted],
+        k if k == KIND_GROUP_ADD_USER_9000 => event
+            .tags
+            .public_keys()
+            .map(|pk| GroupChange::MemberAdded(*pk))
+            .collect(),
+        k if k == KIND_GROUP_REMOVE_USER_9001 => event
+            .tags
+            .public_keys()
+            .map(|pk| GroupChange::MemberRemoved(*pk))
+            .collect(),
+        k if k == KIND_GROUP_USER_JOIN_REQUEST_9021 && !was_member && is_member => {
+            vec![GroupChange::MemberAdded(event.pubkey)]
+        }
+        k if k == KIND_GROUP_USER_LEAVE_REQUEST_9022 && was_member && !is_member => {
+            vec![GroupChange::MemberRemoved(event.pubkey)]
+        }
+        k if k == KIND_GROUP_EDIT_METADATA_9002 => group
+            .map(|group| vec![GroupChange::MetadataChanged(group.metadata.clone())])
+            .unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+/// Invokes the hooks for each change, in order.
+pub async fn dispatch(
+    hooks: &[Arc<dyn GroupEventHook>],
+    scope: &Scope,
+    group_id: &str,
+    changes: &[GroupChange],
+) {
+    for hook in hooks {
+        for change in changes {
+            match change {
+                GroupChange::Created { creator } => {
+                    hook.on_group_created(scope, group_id, creator).await
+                }
+                GroupChange::Deleted => hook.on_group_deleted(scope, group_id).await,
+                GroupChange::MemberAdded(member) => {
+                    hook.on_member_added(scope, group_id, member).await
+                }
+                GroupChange::MemberRemoved(member) => {
+                    hook.on_member_removed(scope, group_id, member).await
+                }
+                GroupChange::MetadataChanged(metadata) => {
+                    hook.on_metadata_changed(scope, group_id, metadata).await
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Suggested group lists after joins and leaves.
+//!
+//! Clients keep the groups a user is in as a NIP-51 simple groups list
+//! (kind 10009), and it drifts from the actual membership when a user is
+//! added or removed by an admin, or joins from another client. The relay
+//! can't sign the user's list, so on every join or leave [`GroupListSync`]
+//! takes the user's latest list stored here, adds or removes the group's
+//! `group` entry, and stores the result as a relay-signed
+//! [`KIND_GROUP_LIST_SUGGESTION_39012`] addressed to the user. Only the user
+//! and the relay can read it; clients may re-sign its tags as the new list.
+//!
+//! The content of the user's list, which holds its encrypted private items,
+//! is carried over unchanged.
+
+use crate::group::{KIND_GROUP_LIST_SUGGESTION_39012, KIND_SIMPLE_LIST_10009};
+use crate::group_hooks::GroupEventHook;
+use crate::groups::Groups;
+use crate::relay_keys::RelayIdentity;
+use crate::utils::apply_store_commands;
+use crate::RelayDatabase;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+#[derive(Debug)]
+pub struct GroupListSync {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+}
+
+/// The URL clients reach a scope at: subdomain scopes are served from a
+/// subdomain of the relay URL.
+pub fn scope_relay_url(relay_url: &str, scope: &Scope) -> String {
+    match (scope, relay_url.split_once("://")) {
+        (Scope::Named { name, .. }, Some((scheme, host))) => format!("{scheme}://{name}.{host}"),
+        _ => relay_url.to_string(),
+    }
+}
+
+fn is_group_entry(tag: &Tag, group_id: &str, relay_url: &str) -> bool {
+    let values = tag.as_slice();
+    values.first().map(String::as_str) == Some("group")
+        && values.get(1).map(String::as_str) == Some(group_id)
+        && values
+            .get(2)
+            .is_none_or(|url| url.trim_end_matches('/') == relay_url.trim_end_matches('/'))
+}
+
+/// The tags of the user's list with the group added or removed, or None when
+/// the list needs no change.
+pub fn updated_list_tags(
+    current: &[Tag],
+    group_id: &str,
+    relay_url: &str,
+    name: Option<&str>,
+    joined: bool,
+) -> Option<Vec<Tag>> {
+    let listed = current
+        .iter()
+        .any(|tag| is_group_entry(tag, group_id, relay_url));
+    if listed == joined {
+        return None;
+    }
+
+    if !joined {
+        return Some(
+            current
+                .iter()
+                .filter(|tag| !is_group_entry(tag, group_id, relay_url))
+                .cloned()
+                .collect(),
+        );
+    }
+
+    let mut entry = vec![
+        "group".to_string(),
+        group_id.to_string(),
+        relay_url.to_string(),
+    ];
+    if let Some(name) = name.filter(|name| !name.is_empty()) {
+        entry.push(name.to_string());
+    }
+    let mut tags = current.to_vec();
+    tags.push(Tag::parse(entry).expect("group entry has a name"));
+    Some(tags)
+}
+
+impl GroupListSync {
+    pub fn new(groups: Arc<Groups>, database: Arc<RelayDatabase>, relay_keys: Keys) -> Arc<Self> {
+        Arc::new(Self {
+            groups,
+            database,
+            relay_keys,
+        })
+    }
+
+    /// Stores a suggested list for a member who joined or left the group.
+    pub async fn suggest(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        member: &PublicKey,
+        joined: bool,
+    ) -> Result<()> {
+        if self.groups.relay_keys().is_relay(member) {
+            return Ok(());
+        }
+
+        let filter = Filter::new()
+            .kind(KIND_SIMPLE_LIST_10009)
+            .author(*member)
+            .limit(1);
+        let current = self.database.query(vec![filter], scope).await?;
+        let current = current.first();
+        let (current_tags, content) = match current {
+            Some(list) => (list.tags.to_vec(), list.content.clone()),
+            None => (Vec::new(), String::new()),
+        };
+
+        let relay_url = scope_relay_url(&self.groups.relay_url, scope);
+        let name = self
+            .groups
+            .get_group(scope, group_id)
+            .map(|group| group.metadata.name.clone());
+        let Some(list_tags) =
+            updated_list_tags(&current_tags, group_id, &relay_url, name.as_deref(), joined)
+        else {
+            return Ok(());
+        };
+
+        debug!(
+            "Suggesting an updated group list to {} after {} {}",
+            member,
+            if joined { "joining" } else { "leaving" },
+            group_id
+        );
+        let mut tags = vec![Tag::identifier(member.to_hex()), Tag::public_key(*member)];
+        tags.extend(list_tags);
+        let suggestion = UnsignedEvent::new(
+            self.relay_keys.public_key(),
+            Timestamp::now(),
+            KIND_GROUP_LIST_SUGGESTION_39012,
+            tags,
+            content,
+        );
+        apply_store_commands(
+            &self.database,
+            &self.relay_keys,
+            vec![StoreCommand::SaveUnsignedEvent(
+                suggestion,
+                scope.clone(),
+                None,
+            )],
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl GroupEventHook for GroupListSync {
+    async fn on_member_added(&self, scope: &Scope, group_id: &str, member: &PublicKey) {
+        if let Err(e) = self.suggest(scope, group_id, member, true).await {
+            warn!("Failed to suggest a group list to {}: {}", member, e);
+        }
+    }
+
+    async fn on_member_removed(&self, scope: &Scope, group_id: &str, member: &PublicKey) {
+        if let Err(e) = self.suggest(scope, group_id, member, false).await {
+            warn!("Failed to suggest a group list to {}: {}", member, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        KIND_GROUP_CREATE_9007, KIND_GROUP_USER_JOIN_REQUEST_9021,
+        KIND_GROUP_USER_LEAVE_REQUEST_9022,
+    };
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn test_scope_relay_url() {
+        assert_eq!(
+            scope_relay_url("wss://groups.example", &Scope::Default),
+            "wss://groups.example"
+        );
+        assert_eq!(
+            scope_relay_url("wss://groups.example", &Scope::named("team").unwrap()),
+            "wss://team.groups.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_suggestions_on_join_and_leave() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let relay_url = "wss://test.relay";
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                relay_url.to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let sync = GroupListSync::new(groups.clone(), database.clone(), relay_keys.clone());
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_hook(sync.clone());
+        let admin = Keys::generate();
+        let user = Keys::generate();
+        let scope = Scope::Default;
+        let h = Tag::custom(TagKind::h(), ["synced"]);
+
+        let handle = |event: Event, keys: &Keys| {
+            let context = EventContext {
+                authed_pubkey: Some(keys.public_key()),
+                subdomain: Arc::new(scope.clone()),
+                relay_pubkey: relay_keys.public_key(),
+            };
+            let processor = &processor;
+            let database = &database;
+            let relay_keys = &relay_keys;
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await
+                    .unwrap();
+                apply_store_commands(database, relay_keys, commands)
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+        };
+        let suggestion = || async {
+            let suggestions = database
+                .query(
+                    vec![Filter::new()
+                        .kind(KIND_GROUP_LIST_SUGGESTION_39012)
+                        .pubkey(user.public_key())],
+                    &scope,
+                )
+                .await
+                .unwrap();
+            assert_eq!(suggestions.len(), 1);
+            suggestions.first().unwrap().clone()
+        };
+        let entries = |event: &Event| -> Vec<Vec<String>> {
+            event
+                .tags
+                .iter()
+                .filter(|tag| tag.kind() == TagKind::custom("group"))
+                .map(|tag| tag.as_slice().to_vec())
+                .collect()
+        };
+
+        // Open groups accept join requests right away
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tags([h.clone(), Tag::custom(TagKind::custom("public"), [""])])
+            .tags([Tag::custom(TagKind::custom("open"), [""])])
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(create, &admin).await;
+
+        // The user already lists a group on another relay
+        let other = Tag::parse(["group", "elsewhere", "wss://other.relay"]).unwrap();
+        let list = EventBuilder::new(KIND_SIMPLE_LIST_10009, "")
+            .tag(other.clone())
+            .sign_with_keys(&user)
+            .unwrap();
+        handle(list, &user).await;
+
+        let join = EventBuilder::new(KIND_GROUP_USER_JOIN_REQUEST_9021, "")
+            .tag(h.clone())
+            .sign_with_keys(&user)
+            .unwrap();
+        handle(join, &user).await;
+
+        let joined = suggestion().await;
+        assert_eq!(joined.pubkey, relay_keys.public_key());
+        assert_eq!(
+            joined.tags.identifier(),
+            Some(user.public_key().to_hex().as_str())
+        );
+        let listed = entries(&joined);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0], other.as_slice().to_vec());
+        assert_eq!(listed[1][1], "synced");
+        assert_eq!(listed[1][2], relay_url);
+
+        // The user re-signs the suggestion as their list, then leaves
+        let list = EventBuilder::new(KIND_SIMPLE_LIST_10009, "")
+            .tags(
+                joined
+                    .tags
+                    .iter()
+                    .filter(|tag| tag.kind() == TagKind::custom("group"))
+                    .cloned(),
+            )
+            .custom_created_at(joined.created_at + 1)
+            .sign_with_keys(&user)
+            .unwrap();
+        handle(list, &user).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let leave = EventBuilder::new(KIND_GROUP_USER_LEAVE_REQUEST_9022, "")
+            .tag(h.clone())
+            .sign_with_keys(&user)
+            .unwrap();
+        handle(leave, &user).await;
+
+        let left = suggestion().await;
+        assert!(left.created_at > joined.created_at);
+        assert_eq!(entries(&left), vec![other.as_slice().to_vec()]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Loads the groups a REQ asks for while the startup load is still running.
+//!
+//! Filter verification and event visibility are synchronous, so they can only
+//! see groups already in memory. Until [`Groups::load_all`] finishes, this
+//! middleware loads the groups named by a REQ's `#h` (and `#d`, for group state
+//! kinds) filters before passing it on, so clients can read them right away.
+
+use crate::groups::{Groups, ADDRESSABLE_EVENT_KINDS};
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct GroupLoadingMiddleware {
+    groups: Arc<Groups>,
+}
+
+impl GroupLoadingMiddleware {
+    pub fn new(groups: Arc<Groups>) -> Self {
+        Self { groups }
+    }
+}
+
+/// Group ids referenced by a set of filters
+pub fn requested_group_ids(filters: &[Filter]) -> HashSet<String> {
+    let h = SingleLetterTag::lowercase(Alphabet::H);
+    let d = SingleLetterTag::lowercase(Alphabet::D);
+
+    filters
+        .iter()
+        .flat_map(|filter| {
+            let queries_state = filter
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| kinds.iter().any(|k| ADDRESSABLE_EVENT_KINDS.contains(k)));
+            let d_values = filter
+                .generic_tags
+                .get(&d)
+                .filter(|_| queries_state)
+                .into_iter()
+                .flatten();
+            filter
+                .generic_tags
+                .get(&h)
+                .into_iter()
+                .flatten()
+                .chain(d_values)
+                .cloned()
+        })
+        .collect()
+}
+
+impl NostrMiddleware<()> for GroupLoadingMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if self.groups.is_loaded() {
+            return ctx.next().await;
+        }
+
+        let group_ids = match &ctx.message {
+            Some(ClientMessage::Req { filter, .. }) => {
+                requested_group_ids(std::slice::from_ref(filter.as_ref()))
+            }
+            Some(ClientMessage::ReqMultiFilter { filters, .. }) => requested_group_ids(filters),
+            _ => return ctx.next().await,
+        };
+
+        let scope = ctx.state.read().await.subdomain().clone();
+        for group_id in group_ids {
+            if let Err(e) = self.groups.get_or_load(&scope, &group_id).await {
+                warn!(
+                    "[{}] Failed to load group {} on demand: {}",
+                    ctx.connection_id, group_id, e
+                );
+            }
+        }
+
+        ctx.next().await
+    }
+}
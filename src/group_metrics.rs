@@ -0,0 +1,444 @@
+//! Per-group activity metrics.
+//!
+//! Labelling the global metrics by group would create one series per group
+//! ever seen, so per-group values are kept here and rendered at scrape time
+//! for the busiest `top_n` groups only (by stored events, then active
+//! subscriptions). Groups leaving the top N simply stop being reported.
+//!
+//! Reported per group, labelled by scope and group id:
+//! - `group_events_total`: signed events stored for the group since startup
+//! - `group_active_subscriptions`: open subscriptions with the group in `#h`
+//! - `group_members`: current member count
+//! - `group_join_requests`: pending join requests
+
+use crate::config::GroupMetricsSettings;
+use crate::gc::GroupState;
+use crate::groups::Groups;
+use crate::utils::scope_name;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use relay_builder::StoreCommand;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+type GroupKey = (Scope, String);
+
+#[derive(Debug, Default)]
+pub struct GroupMetrics {
+    enabled: bool,
+    top_n: usize,
+    events: DashMap<GroupKey, u64>,
+    subscriptions: DashMap<GroupKey, usize>,
+    /// Groups each open subscription counts towards, per connection
+    connections: DashMap<String, HashMap<String, Vec<GroupKey>>>,
+}
+
+impl GroupMetrics {
+    pub fn new(settings: &GroupMetricsSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            top_n: settings.top_n,
+            ..Default::default()
+        }
+    }
+
+    /// Counts the signed group events among the given store commands.
+    /// Ephemeral events are relayed but never stored, so they don't count.
+    pub fn record_stored(&self, commands: &[StoreCommand]) {
+        if !self.enabled {
+            return;
+        }
+
+        for command in commands {
+            if let StoreCommand::SaveSignedEvent(event, scope, _) = command {
+                if event.kind.is_ephemeral() {
+                    continue;
+                }
+                if let Some(group_id) = event.tags.find(TagKind::h()).and_then(|t| t.content()) {
+                    *self
+                        .events
+                        .entry((scope.clone(), group_id.to_string()))
+                        .or_default() += 1;
+                }
+            }
+        }
+    }
+
+    fn subscribe(
+        &self,
+        connection_id: &str,
+        scope: &Scope,
+        subscription_id: &SubscriptionId,
+        filters: &[Filter],
+    ) {
+        // A REQ reusing an id replaces the previous subscription
+        self.unsubscribe(connection_id, subscription_id);
+
+        let h = SingleLetterTag::lowercase(Alphabet::H);
+        let mut keys: Vec<GroupKey> = Vec::new();
+        for group_id in filters
+            .iter()
+            .filter_map(|filter| filter.generic_tags.get(&h))
+            .flatten()
+        {
+            let key = (scope.clone(), group_id.clone());
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            return;
+        }
+
+        for key in &keys {
+            *self.subscriptions.entry(key.clone()).or_default() += 1;
+        }
+        self.connections
+            .entry(connection_id.to_string())
+            .or_default()
+            .insert(subscription_id.to_string(), keys);
+    }
+
+    fn unsubscribe(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        let keys = self
+            .connections
+            .get_mut(connection_id)
+            .and_then(|mut subscriptions| subscriptions.remove(subscription_id.as_str()));
+        for key in keys.unwrap_or_default() {
+            self.release(&key);
+        }
+    }
+
+    fn release(&self, key: &GroupKey) {
+        let emptied = match self.subscriptions.get_mut(key) {
+            Some(mut count) => {
+                *count = count.saturating_sub(1);
+                *count == 0
+            }
+            None => false,
+        };
+        if emptied {
+            self.subscriptions.remove_if(key, |_, count| *count == 0);
+        }
+    }
+
+    /// Renders the metrics of the busiest groups in Prometheus text format.
+    pub fn render(&self, groups: &Groups) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+
+        struct Row {
+            scope: String,
+            group_id: String,
+            events: u64,
+            subscriptions: usize,
+            members: usize,
+            join_requests: usize,
+        }
+
+        let mut rows: Vec<Row> = groups
+            .iter()
+            .map(|entry| {
+                let key = entry.key();
+                let group = entry.value();
+                Row {
+                    scope: scope_name(&key.0).to_string(),
+                    group_id: key.1.clone(),
+                    events: self.events.get(key).map_or(0, |count| *count),
+                    subscriptions: self.subscriptions.get(key).map_or(0, |count| *count),
+                    members: group.members.len(),
+                    join_requests: group.join_requests.len(),
+                }
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            (b.events, b.subscriptions)
+                .cmp(&(a.events, a.subscriptions))
+                .then_with(|| (&a.scope, &a.group_id).cmp(&(&b.scope, &b.group_id)))
+        });
+        rows.truncate(self.top_n);
+
+        let mut output = String::new();
+        let families: [(&str, &str, &str, fn(&Row) -> String); 4] = [
+            (
+                "group_events_total",
+                "counter",
+                "Signed events stored per group since startup",
+                |row| row.events.to_string(),
+            ),
+            (
+                "group_active_subscriptions",
+                "gauge",
+                "Open subscriptions filtering on the group",
+                |row| row.subscriptions.to_string(),
+            ),
+            ("group_members", "gauge", "Members per group", |row| {
+                row.members.to_string()
+            }),
+            (
+                "group_join_requests",
+                "gauge",
+                "Pending join requests per group",
+                |row| row.join_requests.to_string(),
+            ),
+        ];
+        for (name, kind, help, value) in families {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} {kind}");
+            for row in &rows {
+                let _ = writeln!(
+                    output,
+                    "{name}{{scope=\"{}\",group=\"{}\"}} {}",
+                    escape_label(&row.scope),
+                    escape_label(&row.group_id),
+                    value(row)
+                );
+            }
+        }
+        output
+    }
+}
+
+/// Event counters of deleted groups are never rendered again. Subscription
+/// counts follow open subscriptions and go away with them.
+impl GroupState for GroupMetrics {
+    fn name(&self) -> &'static str {
+        "group_metrics"
+    }
+
+    fn group_keys(&self) -> Vec<(Scope, String)> {
+        self.events
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    fn forget_group(&self, scope: &Scope, group_id: &str) -> usize {
+        self.events
+            .remove(&(scope.clone(), group_id.to_string()))
+            .map_or(0, |_| 1)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Tracks which groups open subscriptions filter on.
+#[derive(Debug, Clone)]
+pub struct GroupMetricsMiddleware {
+    metrics: Arc<GroupMetrics>,
+}
+
+impl GroupMetricsMiddleware {
+    pub fn new(metrics: Arc<GroupMetrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl NostrMiddleware<()> for GroupMetricsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if !self.metrics.enabled {
+            return ctx.next().await;
+        }
+
+        let connection_id = ctx.connection_id.to_string();
+        match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                let scope = ctx.state.read().await.subdomain().clone();
+                self.metrics.subscribe(
+                    &connection_id,
+                    &scope,
+                    subscription_id,
+                    std::slice::from_ref(filter.as_ref()),
+                );
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                let scope = ctx.state.read().await.subdomain().clone();
+                self.metrics
+                    .subscribe(&connection_id, &scope, subscription_id, filters);
+            }
+            Some(ClientMessage::Close(subscription_id)) => {
+                self.metrics.unsubscribe(&connection_id, subscription_id);
+            }
+            _ => {}
+        }
+
+        ctx.next().await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        if let Some((_, subscriptions)) = self
+            .metrics
+            .connections
+            .remove(&ctx.connection_id.to_string())
+        {
+            for key in subscriptions.into_values().flatten() {
+                self.metrics.release(&key);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::{create_test_event, create_test_keys, setup_test};
+    use relay_builder::{EventContext, EventProcessor};
+    use tempfile::TempDir;
+    use tokio::sync::RwLock;
+
+    fn settings(top_n: usize) -> GroupMetricsSettings {
+        GroupMetricsSettings {
+            enabled: true,
+            top_n,
+        }
+    }
+
+    async fn groups_with(relay_keys: &Keys, group_ids: &[&str]) -> (TempDir, Arc<Groups>) {
+        let (tmp_dir, database, _) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database,
+                relay_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        for group_id in group_ids {
+            let create = create_test_event(
+                relay_keys,
+                9007,
+                vec![Tag::custom(TagKind::h(), [*group_id])],
+            )
+            .await;
+            groups
+                .handle_group_create(Box::new(create), &Scope::Default)
+                .await
+                .unwrap();
+        }
+        (tmp_dir, groups)
+    }
+
+    #[tokio::test]
+    async fn test_counts_group_messages_through_processor() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (_tmp_dir, groups) = groups_with(&relay_keys, &["test_group"]).await;
+        groups
+            .get_group_mut(&Scope::Default, "test_group")
+            .unwrap()
+            .metadata
+            .closed = false;
+        let metrics = Arc::new(GroupMetrics::new(&settings(10)));
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_group_metrics(metrics.clone());
+        let context = EventContext {
+            authed_pubkey: Some(member_keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+
+        for _ in 0..3 {
+            let message = create_test_event(
+                &member_keys,
+                9,
+                vec![Tag::custom(TagKind::h(), ["test_group"])],
+            )
+            .await;
+            processor
+                .handle_event(message, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+        }
+
+        let output = metrics.render(&groups);
+        assert!(output.contains("group_events_total{scope=\"default\",group=\"test_group\"} 3"));
+        assert!(output.contains("group_members{scope=\"default\",group=\"test_group\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_follow_req_and_close() {
+        let (relay_keys, _, _) = create_test_keys().await;
+        let (_tmp_dir, groups) = groups_with(&relay_keys, &["chat"]).await;
+        let metrics = GroupMetrics::new(&settings(10));
+        let filter = Filter::new().custom_tag(SingleLetterTag::lowercase(Alphabet::H), "chat");
+        let line = "group_active_subscriptions{scope=\"default\",group=\"chat\"}";
+
+        metrics.subscribe(
+            "conn-1",
+            &Scope::Default,
+            &SubscriptionId::new("a"),
+            &[filter.clone()],
+        );
+        metrics.subscribe(
+            "conn-2",
+            &Scope::Default,
+            &SubscriptionId::new("a"),
+            &[filter.clone()],
+        );
+        // Re-sending the same id replaces the subscription
+        metrics.subscribe(
+            "conn-2",
+            &Scope::Default,
+            &SubscriptionId::new("a"),
+            &[filter],
+        );
+        assert!(metrics.render(&groups).contains(&format!("{line} 2")));
+
+        metrics.unsubscribe("conn-1", &SubscriptionId::new("a"));
+        assert!(metrics.render(&groups).contains(&format!("{line} 1")));
+    }
+
+    #[tokio::test]
+    async fn test_only_top_groups_are_reported() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (_tmp_dir, groups) = groups_with(&relay_keys, &["busy", "quiet", "idle"]).await;
+        let metrics = GroupMetrics::new(&settings(2));
+
+        for (group_id, count) in [("busy", 5), ("quiet", 1)] {
+            for _ in 0..count {
+                let event =
+                    create_test_event(&member_keys, 9, vec![Tag::custom(TagKind::h(), [group_id])])
+                        .await;
+                metrics.record_stored(&[StoreCommand::SaveSignedEvent(
+                    Box::new(event),
+                    Scope::Default,
+                    None,
+                )]);
+            }
+        }
+
+        let output = metrics.render(&groups);
+        assert!(output.contains("group=\"busy\"} 5"));
+        assert!(output.contains("group=\"quiet\"} 1"));
+        assert!(!output.contains("group=\"idle\""));
+
+        let disabled = GroupMetrics::new(&GroupMetricsSettings {
+            enabled: false,
+            top_n: 2,
+        });
+        assert!(disabled.render(&groups).is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+//! Checks that loaded group state matches the stored events.
+//!
+//! Every group is rebuilt from the database with
+//! [`Group::rebuild_from_events`], exactly as it would be after a restart,
+//! and its members, custom roles, metadata, invites, join requests and
+//! moderation are compared with the loaded copy. Each difference is reported
+//! as a [`Discrepancy`]. With `heal`, the loaded copy is replaced with the
+//! rebuilt one and the group's state events are regenerated from it.
+//!
+//! Membership lists held back by the coalescer are written within one window,
+//! so a group changed moments before a check can show a difference that
+//! isn't one. Heal when groups are quiet.
+
+use crate::group::Group;
+use crate::groups::Groups;
+use crate::utils::{apply_store_commands, scope_name};
+use crate::RelayDatabase;
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tracing::{info, warn};
+
+/// A part of a group whose loaded state differs from the rebuilt one
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Discrepancy {
+    pub scope: String,
+    pub group_id: String,
+    pub field: &'static str,
+    pub live: Value,
+    pub rebuilt: Value,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerificationReport {
+    /// Groups checked
+    pub groups: usize,
+    pub discrepancies: Vec<Discrepancy>,
+    /// Groups replaced with their rebuilt state
+    pub healed: usize,
+}
+
+/// The compared parts of a group, in a form that doesn't depend on hash order
+fn fields(group: &Group) -> [(&'static str, Value); 6] {
+    let members: BTreeMap<String, BTreeSet<String>> = group
+        .members
+        .values()
+        .map(|member| {
+            (
+                member.pubkey.to_hex(),
+                member.roles.iter().map(ToString::to_string).collect(),
+            )
+        })
+        .collect();
+    let roles: BTreeMap<&String, _> = group.role_permissions.iter().collect();
+    let invites: BTreeMap<&String, Value> = group
+        .invites
+        .iter()
+        .map(|(code, invite)| {
+            let roles: BTreeSet<String> = invite.roles.iter().map(ToString::to_string).collect();
+            (
+                code,
+                json!({
+                    "roles": roles,
+                    "reusable": invite.reusable,
+                    "redemptions": invite.redemptions,
+                }),
+            )
+        })
+        .collect();
+    let join_requests: BTreeSet<String> =
+        group.join_requests.iter().map(PublicKey::to_hex).collect();
+    let moderation = |entries: &HashMap<PublicKey, Option<Timestamp>>| {
+        entries
+            .iter()
+            .map(|(pubkey, until)| (pubkey.to_hex(), until.map(|until| until.as_u64())))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let moderation = json!({
+        "mutes": moderation(&group.moderation.mutes),
+        "bans": moderation(&group.moderation.bans),
+    });
+
+    [
+        ("members", json!(members)),
+        ("roles", json!(roles)),
+        ("metadata", json!(group.metadata)),
+        ("invites", json!(invites)),
+        ("join_requests", json!(join_requests)),
+        ("moderation", moderation),
+    ]
+}
+
+/// The differences between a loaded group and its rebuilt state
+pub fn diff_group(live: &Group, rebuilt: &Group) -> Vec<Discrepancy> {
+    fields(live)
+        .into_iter()
+        .zip(fields(rebuilt))
+        .filter(|((_, live), (_, rebuilt))| live != rebuilt)
+        .map(|((field, live_value), (_, rebuilt_value))| Discrepancy {
+            scope: scope_name(&rebuilt.scope).to_string(),
+            group_id: live.id.clone(),
+            field,
+            live: live_value,
+            rebuilt: rebuilt_value,
+        })
+        .collect()
+}
+
+/// Rebuilds every loaded group from the database and reports where the
+/// loaded state differs, replacing it with the rebuilt state when `heal`.
+pub async fn verify_group_state(
+    groups: &Groups,
+    database: &RelayDatabase,
+    relay_keys: &Keys,
+    heal: bool,
+) -> Result<VerificationReport> {
+    let mut report = VerificationReport::default();
+
+    for (scope, group_id, live) in groups.list_all_groups() {
+        report.groups += 1;
+        let Some(rebuilt) = groups.rebuild_group(&scope, &group_id).await? else {
+            // Nothing stored to rebuild it from, so nothing to heal it with
+            warn!(
+                "Group {} in scope {} is loaded but has no stored state",
+                group_id,
+                scope_name(&scope)
+            );
+            report.discrepancies.push(Discrepancy {
+                scope: scope_name(&scope).to_string(),
+                group_id,
+                field: "group",
+                live: json!(true),
+                rebuilt: json!(false),
+            });
+            continue;
+        };
+
+        let discrepancies = diff_group(&live, &rebuilt);
+        if discrepancies.is_empty() {
+            continue;
+        }
+        warn!(
+            "Group {} in scope {} differs from its stored events in: {}",
+            group_id,
+            scope_name(&scope),
+            discrepancies
+                .iter()
+                .map(|discrepancy| discrepancy.field)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        report.discrepancies.extend(discrepancies);
+
+        if heal {
+            let commands = groups.replace_group(rebuilt);
+            apply_store_commands(database, relay_keys, commands).await?;
+            report.healed += 1;
+        }
+    }
+
+    info!(
+        "Verified {} groups: {} discrepancies, {} healed",
+        report.groups,
+        report.discrepancies.len(),
+        report.healed
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{GroupRole, KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use nostr_lmdb::Scope;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_corrupted_state_is_detected_and_healed() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let admin = Keys::generate();
+        let member = Keys::generate().public_key();
+        let scope = Scope::Default;
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h = Tag::custom(TagKind::h(), ["verified"]);
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        let add = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+            .tags([h.clone(), Tag::public_key(member)])
+            .sign_with_keys(&admin)
+            .unwrap();
+        for event in [create, add] {
+            let commands = processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+
+        // Consistent state has nothing to report
+        let report = verify_group_state(&groups, &database, &relay_keys, false)
+            .await
+            .unwrap();
+        assert_eq!(report.groups, 1);
+        assert_eq!(report.discrepancies, vec![]);
+
+        // Corrupt the loaded copy behind the processor's back
+        let name = groups
+            .get_group(&scope, "verified")
+            .unwrap()
+            .metadata
+            .name
+            .clone();
+        {
+            let mut group = groups.get_group_mut(&scope, "verified").unwrap();
+            group.members.remove(&member);
+            group.metadata.name = "tampered".to_string();
+            group
+                .role_permissions
+                .insert("ghost".to_string(), Default::default());
+        }
+
+        let report = verify_group_state(&groups, &database, &relay_keys, false)
+            .await
+            .unwrap();
+        let fields: Vec<&str> = report
+            .discrepancies
+            .iter()
+            .map(|discrepancy| discrepancy.field)
+            .collect();
+        assert_eq!(fields, vec!["members", "roles", "metadata"]);
+        assert_eq!(report.discrepancies[0].group_id, "verified");
+        assert_eq!(report.discrepancies[0].scope, "default");
+        assert_eq!(report.healed, 0);
+        assert!(!groups
+            .get_group(&scope, "verified")
+            .unwrap()
+            .members
+            .contains_key(&member));
+
+        let report = verify_group_state(&groups, &database, &relay_keys, true)
+            .await
+            .unwrap();
+        assert_eq!(report.healed, 1);
+        {
+            let group = groups.get_group(&scope, "verified").unwrap();
+            assert!(group.members[&member].is(GroupRole::Member));
+            assert_eq!(group.metadata.name, name);
+            assert!(group.role_permissions.is_empty());
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let report = verify_group_state(&groups, &database, &relay_keys, false)
+            .await
+            .unwrap();
+        assert_eq!(report.discrepancies, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_from_events_matches_load() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let admin = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(Tag::custom(TagKind::h(), ["pure"]))
+            .sign_with_keys(&admin)
+            .unwrap();
+        let commands = processor
+            .handle_event(create.clone(), Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap();
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let stored = database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap();
+        let stored: Vec<Event> = stored.into_iter().collect();
+        let rebuilt = Group::rebuild_from_events(&stored, &relay_keys.public_key())
+            .unwrap()
+            .unwrap();
+        let live = groups.get_group(&Scope::Default, "pure").unwrap();
+        assert_eq!(diff_group(&live, &rebuilt), vec![]);
+        assert_eq!(rebuilt.created_at, create.created_at);
+        assert!(rebuilt.is_admin(&admin.public_key()));
+
+        // Without state events there is no group
+        assert!(
+            Group::rebuild_from_events(&[create], &relay_keys.public_key())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,796 @@
+//! Webhooks registered by group admins for their own integrations.
+//!
+//! Registrations live on the [`Group`](crate::group::Group) and are persisted
+//! in a relay-signed 39010 event that is never served to clients. Each
+//! accepted event of a group with webhooks, except NIP-70 protected ones, is
+//! posted as JSON to the matching endpoints, signed like the relay-wide
+//! webhook with a per-webhook secret. The secret is derived from the relay
+//! key, so it is only shown once, at registration, and never stored.
+//!
+//! Deliveries are rate capped per group and run concurrently, so a slow
+//! endpoint only delays its own deliveries. A webhook that keeps failing is
+//! disabled so a dead endpoint doesn't keep costing the relay retries.
+//!
+//! Anyone can create a group, so webhook hosts are resolved at registration,
+//! and loopback, private and link-local addresses are refused unless the
+//! operator allows the host. Deliveries connect through
+//! [`HttpClient::public_only`], which applies the same rule to the addresses
+//! it connects to, so a host repointed after the check can't reach inside.
+
+use crate::config::GroupWebhookSettings;
+use crate::gc::GroupState;
+use crate::groups::{GroupError, GroupWebhook, Groups};
+use crate::http_client::{is_internal, HttpClient};
+use crate::metrics;
+use crate::utils::{apply_store_commands, scope_name};
+use crate::webhook::{sign_payload, SIGNATURE_HEADER};
+use crate::RelayDatabase;
+use anyhow::Result;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+pub struct GroupWebhookPayload<'a> {
+    pub scope: &'a str,
+    pub group_id: &'a str,
+    pub webhook_id: &'a str,
+    pub event: &'a Event,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    scope: Scope,
+    group_id: String,
+    webhook: GroupWebhook,
+    event: Event,
+}
+
+type WebhookKey = (Scope, String, String);
+
+#[derive(Debug)]
+pub struct GroupWebhooks {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    settings: GroupWebhookSettings,
+    queue: mpsc::Sender<Delivery>,
+    /// Deliveries per group in the current rate window
+    rates: DashMap<(Scope, String), (Instant, u32)>,
+    /// Consecutive failed deliveries per webhook
+    failures: DashMap<WebhookKey, u32>,
+}
+
+impl GroupWebhooks {
+    fn new(
+        settings: &GroupWebhookSettings,
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+    ) -> (Arc<Self>, mpsc::Receiver<Delivery>) {
+        let (queue, receiver) = mpsc::channel(settings.queue_capacity.max(1));
+        let webhooks = Arc::new(Self {
+            groups,
+            database,
+            relay_keys,
+            settings: settings.clone(),
+            queue,
+            rates: DashMap::new(),
+            failures: DashMap::new(),
+        });
+        (webhooks, receiver)
+    }
+
+    /// Starts the delivery task and returns the dispatcher to register.
+    pub fn start(
+        settings: &GroupWebhookSettings,
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+        cancellation_token: CancellationToken,
+    ) -> Result<Arc<Self>> {
+        let client = HttpClient::public_only(settings.allowed_hosts.clone())?;
+        let (webhooks, receiver) = Self::new(settings, groups, database, relay_keys);
+        tokio::spawn(deliver(
+            webhooks.clone(),
+            client,
+            receiver,
+            cancellation_token,
+        ));
+        Ok(webhooks)
+    }
+
+    /// The HMAC secret deliveries of a webhook are signed with
+    pub fn secret(&self, scope: &Scope, group_id: &str, webhook_id: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.relay_keys.secret_key().to_secret_hex().as_bytes())
+                .expect("HMAC accepts any key length");
+        mac.update(format!("{}:{group_id}:{webhook_id}", scope_name(scope)).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Registers a webhook for the caller's group, returning it and its secret
+    pub async fn register(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        url: &str,
+        kinds: Vec<u16>,
+        allow_private: bool,
+    ) -> Result<(GroupWebhook, String), GroupError> {
+        check_destination(url, &self.settings.allowed_hosts)
+            .await
+            .map_err(GroupError::ValidationFailed)?;
+
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let digest = Sha256::digest(format!("{}:{group_id}:{url}:{nanos}", scope_name(scope)));
+        let webhook = GroupWebhook {
+            id: hex::encode(&digest[..8]),
+            url: url.to_string(),
+            kinds,
+            allow_private,
+            disabled: false,
+        };
+
+        let commands = self.groups.add_webhook(
+            scope,
+            group_id,
+            caller,
+            webhook.clone(),
+            self.settings.max_per_group,
+        )?;
+        self.persist(commands).await?;
+
+        info!(
+            "Registered webhook {} for group {} in scope {}",
+            webhook.id,
+            group_id,
+            scope_name(scope)
+        );
+        let secret = self.secret(scope, group_id, &webhook.id);
+        Ok((webhook, secret))
+    }
+
+    pub async fn unregister(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        webhook_id: &str,
+    ) -> Result<(), GroupError> {
+        let commands = self
+            .groups
+            .remove_webhook(scope, group_id, caller, webhook_id)?;
+        self.persist(commands).await
+    }
+
+    async fn persist(&self, commands: Vec<StoreCommand>) -> Result<(), GroupError> {
+        apply_store_commands(&self.database, &self.relay_keys, commands)
+            .await
+            .map_err(GroupError::Internal)
+    }
+
+    /// Queues deliveries for the group events among the stored commands
+    pub fn dispatch(&self, commands: &[StoreCommand]) {
+        for command in commands {
+            let StoreCommand::SaveSignedEvent(event, scope, _) = command else {
+                continue;
+            };
+            // Only the author may publish these anywhere else
+            if event.is_protected() {
+                continue;
+            }
+            let Some(group_id) = event.tags.find(TagKind::h()).and_then(|tag| tag.content()) else {
+                continue;
+            };
+            let Some(group) = self.groups.get_group(scope, group_id) else {
+                continue;
+            };
+
+            let webhooks: Vec<GroupWebhook> = group.active_webhooks(event.kind).cloned().collect();
+            drop(group);
+            if webhooks.is_empty() {
+                continue;
+            }
+            if !self.within_rate(scope, group_id) {
+                debug!("Group {} is over its webhook rate, dropping", group_id);
+                metrics::group_webhook_deliveries("rate_limited").increment(webhooks.len() as u64);
+                continue;
+            }
+
+            for webhook in webhooks {
+                let delivery = Delivery {
+                    scope: scope.clone(),
+                    group_id: group_id.to_string(),
+                    webhook,
+                    event: (**event).clone(),
+                };
+                if let Err(e) = self.queue.try_send(delivery) {
+                    warn!("Group webhook queue full, dropping delivery: {}", e);
+                    metrics::group_webhook_deliveries("dropped").increment(1);
+                }
+            }
+        }
+    }
+
+    /// Counts an event against the group's per-minute delivery cap
+    pub(crate) fn within_rate(&self, scope: &Scope, group_id: &str) -> bool {
+        let now = Instant::now();
+        let mut entry = self
+            .rates
+            .entry((scope.clone(), group_id.to_string()))
+            .or_insert((now, 0));
+        let (window_start, count) = entry.value_mut();
+        if now.duration_since(*window_start) >= RATE_WINDOW {
+            *window_start = now;
+            *count = 0;
+        }
+        if *count >= self.settings.max_events_per_minute {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    async fn record_result(&self, delivery: &Delivery, delivered: bool) {
+        let key = (
+            delivery.scope.clone(),
+            delivery.group_id.clone(),
+            delivery.webhook.id.clone(),
+        );
+        if delivered {
+            self.failures.remove(&key);
+            return;
+        }
+
+        let failures = {
+            let mut entry = self.failures.entry(key.clone()).or_insert(0);
+            *entry += 1;
+            *entry
+        };
+        if failures < self.settings.disable_after_failures {
+            return;
+        }
+
+        self.failures.remove(&key);
+        warn!(
+            "Disabling webhook {} of group {} after {} failed deliveries",
+            delivery.webhook.id, delivery.group_id, failures
+        );
+        let result =
+            self.groups
+                .disable_webhook(&delivery.scope, &delivery.group_id, &delivery.webhook.id);
+        match result {
+            Ok(commands) => {
+                if let Err(e) = self.persist(commands).await {
+                    warn!("Failed to persist disabled webhook: {}", e);
+                }
+                metrics::group_webhook_deliveries("disabled").increment(1);
+            }
+            // Removed or the group was deleted in the meantime
+            Err(e) => debug!("Not disabling webhook: {}", e),
+        }
+    }
+}
+
+impl GroupState for GroupWebhooks {
+    fn name(&self) -> &'static str {
+        "group_webhooks"
+    }
+
+    fn group_keys(&self) -> Vec<(Scope, String)> {
+        let mut keys: Vec<(Scope, String)> =
+            self.rates.iter().map(|entry| entry.key().clone()).collect();
+        for entry in self.failures.iter() {
+            let (scope, group_id, _) = entry.key();
+            let key = (scope.clone(), group_id.clone());
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    fn forget_group(&self, scope: &Scope, group_id: &str) -> usize {
+        let rates = self
+            .rates
+            .remove(&(scope.clone(), group_id.to_string()))
+            .map_or(0, |_| 1);
+        let failures = self.failures.len();
+        self.failures
+            .retain(|(s, g, _), _| !(s == scope && g == group_id));
+        rates + failures - self.failures.len()
+    }
+}
+
+async fn deliver(
+    webhooks: Arc<GroupWebhooks>,
+    client: HttpClient,
+    mut receiver: mpsc::Receiver<Delivery>,
+    cancellation_token: CancellationToken,
+) {
+    let permits = Arc::new(Semaphore::new(
+        webhooks.settings.max_concurrent_deliveries.max(1),
+    ));
+    let mut tasks = JoinSet::new();
+    loop {
+        let delivery = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            delivery = receiver.recv() => match delivery {
+                Some(delivery) => delivery,
+                None => break,
+            },
+        };
+        let permit = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            permit = permits.clone().acquire_owned() => {
+                permit.expect("delivery semaphore is never closed")
+            }
+        };
+
+        while tasks.try_join_next().is_some() {}
+        let webhooks = webhooks.clone();
+        let client = client.clone();
+        tasks.spawn(async move {
+            deliver_one(&webhooks, &client, delivery).await;
+            drop(permit);
+        });
+    }
+    tasks.shutdown().await;
+}
+
+async fn deliver_one(webhooks: &GroupWebhooks, client: &HttpClient, delivery: Delivery) {
+    // Skip deliveries queued before the webhook was removed or disabled
+    let still_active = webhooks
+        .groups
+        .get_group(&delivery.scope, &delivery.group_id)
+        .is_some_and(|group| {
+            group
+                .active_webhooks(delivery.event.kind)
+                .any(|webhook| webhook.id == delivery.webhook.id)
+        });
+    if !still_active {
+        return;
+    }
+
+    // The host may have been repointed since registration. The client
+    // refuses internal addresses again when it connects.
+    if let Err(e) = check_destination(&delivery.webhook.url, &webhooks.settings.allowed_hosts).await
+    {
+        warn!("Not delivering to webhook {}: {}", delivery.webhook.id, e);
+        metrics::group_webhook_deliveries("failed").increment(1);
+        webhooks.record_result(&delivery, false).await;
+        return;
+    }
+
+    let payload = GroupWebhookPayload {
+        scope: scope_name(&delivery.scope),
+        group_id: &delivery.group_id,
+        webhook_id: &delivery.webhook.id,
+        event: &delivery.event,
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize group webhook payload: {}", e);
+            return;
+        }
+    };
+    let secret = webhooks.secret(&delivery.scope, &delivery.group_id, &delivery.webhook.id);
+    let headers = [(SIGNATURE_HEADER, sign_payload(&secret, &body))];
+
+    let settings = &webhooks.settings;
+    let mut backoff = settings.retry_backoff;
+    let mut delivered = false;
+    for attempt in 0..=settings.max_retries {
+        match client
+            .post_json(&delivery.webhook.url, body.clone(), &headers)
+            .await
+        {
+            Ok(status) if status.is_success() => {
+                delivered = true;
+                break;
+            }
+            Ok(status) => debug!("Group webhook attempt {} got status {}", attempt, status),
+            Err(e) => debug!("Group webhook attempt {} failed: {}", attempt, e),
+        }
+
+        if attempt < settings.max_retries {
+            tokio::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(Duration::from_secs(60));
+        }
+    }
+
+    metrics::group_webhook_deliveries(if delivered { "delivered" } else { "failed" }).increment(1);
+    webhooks.record_result(&delivery, delivered).await;
+}
+
+/// Checks that a webhook URL is http(s) and that its host doesn't resolve to
+/// an address inside the relay's network, unless the host is allowed.
+pub(crate) async fn check_destination(url: &str, allowed_hosts: &[String]) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid webhook URL: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err("Webhook URLs must be http or https".to_string());
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "Webhook URLs need a host".to_string())?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+    {
+        return Ok(());
+    }
+
+    let addresses: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let port = parsed.port_or_known_default().unwrap_or(443);
+            tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|e| format!("Can't resolve webhook host {host}: {e}"))?
+                .map(|address| address.ip())
+                .collect()
+        }
+    };
+    if addresses.is_empty() {
+        return Err(format!("Webhook host {host} has no addresses"));
+    }
+    match addresses.into_iter().find(|ip| is_internal(*ip)) {
+        Some(ip) => Err(format!(
+            "Webhook host {host} resolves to the internal address {ip}"
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{KIND_GROUP_CREATE_9007, KIND_GROUP_EDIT_METADATA_9002};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, routing::post, Router};
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::{Mutex, RwLock};
+
+    type Captured = Arc<Mutex<Vec<(Option<String>, Bytes)>>>;
+
+    async fn capture(State(captured): State<Captured>, headers: HeaderMap, body: Bytes) {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        captured.lock().await.push((signature, body));
+    }
+
+    async fn start_test_server() -> (String, Captured) {
+        let captured: Captured = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/hook", post(capture))
+            .with_state(captured.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (format!("http://{addr}/hook"), captured)
+    }
+
+    fn settings() -> GroupWebhookSettings {
+        GroupWebhookSettings {
+            max_per_group: 3,
+            max_events_per_minute: 100,
+            disable_after_failures: 2,
+            max_retries: 0,
+            queue_capacity: 100,
+            retry_backoff: Duration::from_millis(1),
+            max_concurrent_deliveries: 4,
+            // The test endpoints listen on loopback
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+        }
+    }
+
+    struct Relay {
+        _tmp_dir: tempfile::TempDir,
+        processor: GroupsRelayProcessor,
+        webhooks: Arc<GroupWebhooks>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+    }
+
+    impl Relay {
+        async fn new() -> Self {
+            let (_tmp_dir, database, relay_keys) = setup_test().await;
+            let groups = Arc::new(
+                Groups::load_groups(
+                    database.clone(),
+                    relay_keys.public_key(),
+                    "wss://test.relay".to_string(),
+                )
+                .await
+                .unwrap(),
+            );
+            let webhooks = GroupWebhooks::start(
+                &settings(),
+                groups.clone(),
+                database.clone(),
+                relay_keys.clone(),
+                CancellationToken::new(),
+            )
+            .unwrap();
+            let processor = GroupsRelayProcessor::new(groups, relay_keys.public_key())
+                .with_group_webhooks(webhooks.clone());
+
+            Self {
+                _tmp_dir,
+                processor,
+                webhooks,
+                database,
+                relay_keys,
+            }
+        }
+
+        async fn send(&self, keys: &Keys, builder: EventBuilder) -> Event {
+            let event = builder.sign_with_keys(keys).unwrap();
+            let context = EventContext {
+                authed_pubkey: Some(keys.public_key()),
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey: self.relay_keys.public_key(),
+            };
+            let commands = self
+                .processor
+                .handle_event(event.clone(), Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+            apply_store_commands(&self.database, &self.relay_keys, commands)
+                .await
+                .unwrap();
+            event
+        }
+
+        /// Creates a public group owned by `admin`
+        async fn create_group(&self, admin: &Keys, group_id: &str) {
+            let h = Tag::custom(TagKind::h(), [group_id]);
+            self.send(
+                admin,
+                EventBuilder::new(KIND_GROUP_CREATE_9007, "").tag(h.clone()),
+            )
+            .await;
+            let public = Tag::custom(TagKind::custom("public"), &[] as &[String]);
+            self.send(
+                admin,
+                EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "").tags([h, public]),
+            )
+            .await;
+        }
+    }
+
+    async fn wait_for<T, F, Fut>(mut check: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Option<T>>,
+    {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(value) = check().await {
+                    return value;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition was not met in time")
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_group_events() {
+        let relay = Relay::new().await;
+        let admin = Keys::generate();
+        relay.create_group(&admin, "bridged").await;
+        let (url, captured) = start_test_server().await;
+
+        let (webhook, secret) = relay
+            .webhooks
+            .register(
+                &Scope::Default,
+                "bridged",
+                &admin.public_key(),
+                &url,
+                vec![9],
+                false,
+            )
+            .await
+            .unwrap();
+
+        let message = relay
+            .send(
+                &admin,
+                EventBuilder::new(Kind::Custom(9), "hello bridge")
+                    .tag(Tag::custom(TagKind::h(), ["bridged"])),
+            )
+            .await;
+        // Not a selected kind
+        relay
+            .send(
+                &admin,
+                EventBuilder::new(Kind::Custom(11), "thread")
+                    .tag(Tag::custom(TagKind::h(), ["bridged"])),
+            )
+            .await;
+
+        let (signature, body) = wait_for(|| {
+            let captured = captured.clone();
+            async move { captured.lock().await.first().cloned() }
+        })
+        .await;
+        assert_eq!(signature, Some(sign_payload(&secret, &body)));
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["group_id"], "bridged");
+        assert_eq!(payload["webhook_id"], webhook.id.as_str());
+        assert_eq!(payload["event"]["id"], message.id.to_hex());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(captured.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_registration_requires_admin_and_valid_url() {
+        let relay = Relay::new().await;
+        let admin = Keys::generate();
+        let outsider = Keys::generate();
+        relay.create_group(&admin, "bridged").await;
+
+        let register = |caller: PublicKey, url: &'static str| {
+            let webhooks = relay.webhooks.clone();
+            async move {
+                webhooks
+                    .register(&Scope::Default, "bridged", &caller, url, vec![], false)
+                    .await
+            }
+        };
+        assert!(matches!(
+            register(outsider.public_key(), "https://203.0.113.10/hook").await,
+            Err(GroupError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            register(admin.public_key(), "ftp://203.0.113.10/hook").await,
+            Err(GroupError::ValidationFailed(_))
+        ));
+        assert!(matches!(
+            register(admin.public_key(), "http://169.254.169.254/latest").await,
+            Err(GroupError::ValidationFailed(_))
+        ));
+        assert!(register(admin.public_key(), "https://203.0.113.10/hook")
+            .await
+            .is_ok());
+
+        // Registrations survive a reload
+        let reloaded = Groups::load_groups(
+            relay.database.clone(),
+            relay.relay_keys.public_key(),
+            "wss://test.relay".to_string(),
+        )
+        .await
+        .unwrap();
+        let group = reloaded.get_group(&Scope::Default, "bridged").unwrap();
+        assert_eq!(group.webhooks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failing_webhook_is_disabled() {
+        let relay = Relay::new().await;
+        let admin = Keys::generate();
+        relay.create_group(&admin, "bridged").await;
+
+        // Nothing listens on this port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+
+        let (webhook, _) = relay
+            .webhooks
+            .register(
+                &Scope::Default,
+                "bridged",
+                &admin.public_key(),
+                &url,
+                vec![],
+                false,
+            )
+            .await
+            .unwrap();
+        for i in 0..2 {
+            relay
+                .send(
+                    &admin,
+                    EventBuilder::new(Kind::Custom(9), format!("message {i}"))
+                        .tag(Tag::custom(TagKind::h(), ["bridged"])),
+                )
+                .await;
+        }
+
+        let groups = relay.processor.groups().clone();
+        wait_for(|| {
+            let disabled = groups
+                .get_group(&Scope::Default, "bridged")
+                .is_some_and(|group| {
+                    group
+                        .webhooks
+                        .iter()
+                        .any(|w| w.id == webhook.id && w.disabled)
+                });
+            async move { disabled.then_some(()) }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_rejects_internal_destinations() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.1.2.3/hook",
+            "http://192.168.0.10/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(
+                check_destination(url, &[]).await.is_err(),
+                "{url} should be rejected"
+            );
+        }
+
+        assert!(check_destination("https://203.0.113.10/hook", &[])
+            .await
+            .is_ok());
+        // Operators can opt internal hosts in
+        let allowed = vec!["10.1.2.3".to_string()];
+        assert!(check_destination("http://10.1.2.3/hook", &allowed)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_never_connect_to_internal_addresses() {
+        let (url, captured) = start_test_server().await;
+        let by_name = url.replace("127.0.0.1", "localhost");
+
+        // A name passing a separate check would still resolve to loopback here
+        let client = HttpClient::public_only(Vec::new()).unwrap();
+        assert!(client
+            .post_json(&by_name, b"{}".to_vec(), &[])
+            .await
+            .is_err());
+        assert!(captured.lock().await.is_empty());
+
+        let client = HttpClient::public_only(vec!["localhost".to_string()]).unwrap();
+        let status = client
+            .post_json(&by_name, b"{}".to_vec(), &[])
+            .await
+            .unwrap();
+        assert!(status.is_success());
+        assert_eq!(captured.lock().await.len(), 1);
+    }
+}
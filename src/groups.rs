@@ -1,58 +1,376 @@
+use crate::error::Rejection;
 pub use crate::group::{
-    Group, GroupError, GroupMember, GroupMetadata, GroupRole, Invite, ADDRESSABLE_EVENT_KINDS,
-    KIND_GROUP_ADD_USER_9000, KIND_GROUP_ADMINS_39001, KIND_GROUP_CREATE_9007,
-    KIND_GROUP_CREATE_INVITE_9009, KIND_GROUP_DELETE_9008, KIND_GROUP_DELETE_EVENT_9005,
-    KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_MEMBERS_39002, KIND_GROUP_METADATA_39000,
-    KIND_GROUP_REMOVE_USER_9001, KIND_GROUP_SET_ROLES_9006, KIND_GROUP_USER_JOIN_REQUEST_9021,
-    KIND_GROUP_USER_LEAVE_REQUEST_9022, KIND_SIMPLE_LIST_10009, NON_GROUP_ALLOWED_KINDS,
+    BotCapability, BotToken, Group, GroupError, GroupMember, GroupMetadata, GroupRole,
+    GroupWebhook, Invite, Moderation, RolePermissions, ADDRESSABLE_EVENT_KINDS, GROUP_STATE_KINDS,
+    KIND_GROUP_ADD_USER_9000, KIND_GROUP_ADMINS_39001, KIND_GROUP_ANNOTATION_9030,
+    KIND_GROUP_BOT_TOKENS_39011, KIND_GROUP_CREATE_9007, KIND_GROUP_CREATE_INVITE_9009,
+    KIND_GROUP_DEFINE_ROLES_9003, KIND_GROUP_DELETE_9008, KIND_GROUP_DELETE_EVENT_9005,
+    KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_EMOJI_SET_30030, KIND_GROUP_INVITE_REDEMPTIONS_39005,
+    KIND_GROUP_JOIN_REQUESTS_39004, KIND_GROUP_MEMBERS_39002, KIND_GROUP_MESSAGE_9,
+    KIND_GROUP_METADATA_39000, KIND_GROUP_MODERATION_39006, KIND_GROUP_REMOVE_USER_9001,
+    KIND_GROUP_ROLES_39003, KIND_GROUP_SET_ROLES_9006, KIND_GROUP_USER_JOIN_REQUEST_9021,
+    KIND_GROUP_USER_LEAVE_REQUEST_9022, KIND_GROUP_WEBHOOKS_39010, KIND_SIMPLE_LIST_10009,
+    MAX_PREVIOUS_NAMES, NON_GROUP_ALLOWED_KINDS, RELAY_GENERATED_KINDS, SLUG_IN_USE,
 };
 use crate::metrics;
+use crate::relay_keys::{RelayIdentity, RelayPubkeys};
 use crate::StoreCommand;
 use anyhow::Result;
-use dashmap::{
-    mapref::one::{Ref, RefMut},
-    DashMap,
-};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+use parking_lot::{RawRwLock, RwLock};
 use relay_builder::{Error, RelayDatabase};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::OnceCell;
 use tracing::{debug, error, info, warn};
 
 // Type aliases to make complex types more manageable
 type ScopedGroupKey = (Scope, String);
-type ScopedGroupRef<'a> = Ref<'a, ScopedGroupKey, Group>;
-type ScopedGroupRefMut<'a> = RefMut<'a, ScopedGroupKey, Group>;
+/// Each group has its own lock, so events for different groups don't
+/// contend and visibility checks never wait for each other
+type SharedGroup = Arc<RwLock<Group>>;
+
+/// A group locked for reading. Only the group's own lock is held, not the
+/// map, so other groups can be read, changed, added and removed meanwhile.
+pub struct GroupRef {
+    key: ScopedGroupKey,
+    guard: ArcRwLockReadGuard<RawRwLock, Group>,
+}
+
+impl GroupRef {
+    pub fn key(&self) -> &ScopedGroupKey {
+        &self.key
+    }
+
+    pub fn value(&self) -> &Group {
+        &self.guard
+    }
+}
+
+impl Deref for GroupRef {
+    type Target = Group;
+
+    fn deref(&self) -> &Group {
+        &self.guard
+    }
+}
+
+impl fmt::Debug for GroupRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GroupRef").field(self.value()).finish()
+    }
+}
+
+/// A group locked for writing, blocking only readers and writers of that group
+pub struct GroupRefMut {
+    key: ScopedGroupKey,
+    guard: ArcRwLockWriteGuard<RawRwLock, Group>,
+}
+
+impl GroupRefMut {
+    pub fn key(&self) -> &ScopedGroupKey {
+        &self.key
+    }
+
+    pub fn value(&self) -> &Group {
+        &self.guard
+    }
+
+    pub fn value_mut(&mut self) -> &mut Group {
+        &mut self.guard
+    }
+}
+
+impl Deref for GroupRefMut {
+    type Target = Group;
+
+    fn deref(&self) -> &Group {
+        &self.guard
+    }
+}
+
+impl DerefMut for GroupRefMut {
+    fn deref_mut(&mut self) -> &mut Group {
+        &mut self.guard
+    }
+}
+
+impl fmt::Debug for GroupRefMut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GroupRefMut").field(self.value()).finish()
+    }
+}
+
+/// Groups of a scope each pubkey can read, used to narrow subscriptions
+#[derive(Debug, Default)]
+struct ReadIndex {
+    public: BTreeSet<String>,
+    memberships: HashMap<PublicKey, BTreeSet<String>>,
+}
 
 #[derive(Debug)]
 pub struct Groups {
     db: Arc<RelayDatabase>,
-    groups: DashMap<ScopedGroupKey, Group>, // (scope, group_id) -> Group
+    groups: DashMap<ScopedGroupKey, SharedGroup>, // (scope, group_id) -> Group
+    /// Set once every scope has been loaded
+    loaded: AtomicBool,
+    /// Groups loaded on demand while the full load is running
+    lazy_loads: DashMap<ScopedGroupKey, Arc<OnceCell<()>>>,
+    /// Built on first use per scope, dropped when membership or visibility
+    /// changes
+    read_indexes: DashMap<Scope, Arc<ReadIndex>>,
+    /// Bumped on every drop, so an index built from older state isn't kept
+    read_index_generation: AtomicU64,
+    /// The active key, which signs generated events
     pub relay_pubkey: PublicKey,
+    /// The active key and previous keys still recognized as the relay
+    relay_keys: RelayPubkeys,
     pub relay_url: String,
+    /// Group id holding each slug of a scope, rebuilt on load
+    slugs: Arc<DashMap<ScopedGroupKey, String>>,
+    /// Set on sandboxes, which check slugs against the state they copy
+    /// without claiming or releasing any
+    slugs_read_only: bool,
+    max_previous_names: usize,
 }
 
 impl Groups {
+    /// Creates an empty group state; groups are loaded on demand through
+    /// [`Groups::get_or_load`] until [`Groups::load_all`] completes.
+    pub fn new(database: Arc<RelayDatabase>, relay_pubkey: PublicKey, relay_url: String) -> Self {
+        Self {
+            db: database,
+            groups: DashMap::new(),
+            loaded: AtomicBool::new(false),
+            lazy_loads: DashMap::new(),
+            read_indexes: DashMap::new(),
+            read_index_generation: AtomicU64::new(0),
+            relay_pubkey,
+            relay_keys: RelayPubkeys::new(relay_pubkey),
+            relay_url,
+            slugs: Arc::default(),
+            slugs_read_only: false,
+            max_previous_names: MAX_PREVIOUS_NAMES,
+        }
+    }
+
+    /// Keeps recognizing keys the relay was rotated away from as the relay
+    pub fn with_previous_relay_keys(
+        mut self,
+        previous: impl IntoIterator<Item = PublicKey>,
+    ) -> Self {
+        self.relay_keys = self.relay_keys.with_previous(previous);
+        self
+    }
+
+    /// How many past names a group keeps in its metadata
+    pub fn with_max_previous_names(mut self, max: usize) -> Self {
+        self.max_previous_names = max;
+        self
+    }
+
+    /// A loaded state holding nothing but a copy of the given group, when
+    /// it's loaded here. It shares this state's slugs without changing them,
+    /// so events applied to it leave this state untouched.
+    pub fn sandbox(&self, scope: &Scope, group_id: Option<&str>) -> Groups {
+        let groups = DashMap::new();
+        if let Some(group) = group_id.and_then(|group_id| self.get_group(scope, group_id)) {
+            groups.insert(
+                group.key().clone(),
+                Arc::new(RwLock::new(group.value().clone())),
+            );
+        }
+
+        Self {
+            db: self.db.clone(),
+            groups,
+            loaded: AtomicBool::new(true),
+            lazy_loads: DashMap::new(),
+            read_indexes: DashMap::new(),
+            read_index_generation: AtomicU64::new(0),
+            relay_pubkey: self.relay_pubkey,
+            relay_keys: self.relay_keys.clone(),
+            relay_url: self.relay_url.clone(),
+            slugs: self.slugs.clone(),
+            slugs_read_only: true,
+            max_previous_names: self.max_previous_names,
+        }
+    }
+
+    /// The database the groups are loaded from
+    pub fn database(&self) -> &Arc<RelayDatabase> {
+        &self.db
+    }
+
+    /// The keys recognized as the relay, the active one first
+    pub fn relay_keys(&self) -> &RelayPubkeys {
+        &self.relay_keys
+    }
+
+    /// Creates the group state and loads every group before returning
     pub async fn load_groups(
         database: Arc<RelayDatabase>,
         relay_pubkey: PublicKey,
         relay_url: String,
     ) -> Result<Self, Error> {
+        let groups = Self::new(database, relay_pubkey, relay_url);
+        groups.load_all().await?;
+        Ok(groups)
+    }
+
+    /// Loads every group in the background, so the relay can serve while
+    /// a large database is scanned.
+    pub fn start_background_load(self: &Arc<Self>) {
+        let groups = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(e) = groups.load_all().await {
+                error!("Failed to load groups: {}", e);
+            }
+        });
+    }
+
+    /// Whether every group has been loaded
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Acquire)
+    }
+
+    /// Whether the state of a group is known: it was loaded, or the full load
+    /// finished without finding it
+    pub fn is_known(&self, scope: &Scope, group_id: &str) -> bool {
+        let key = (scope.clone(), group_id.to_string());
+        self.is_loaded()
+            || self.groups.contains_key(&key)
+            || self
+                .lazy_loads
+                .get(&key)
+                .is_some_and(|cell| cell.initialized())
+    }
+
+    /// Returns a group, loading only that group from the database if the full
+    /// load hasn't reached it yet. Concurrent callers share a single load.
+    pub async fn get_or_load(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+    ) -> Result<Option<GroupRef>, Error> {
+        if self.is_loaded() {
+            return Ok(self.get_group(scope, group_id));
+        }
+        if let Some(group) = self.get_group(scope, group_id) {
+            return Ok(Some(group));
+        }
+
+        let key = (scope.clone(), group_id.to_string());
+        let cell = Arc::clone(self.lazy_loads.entry(key.clone()).or_default().value());
+        cell.get_or_try_init(|| async {
+            let start = Instant::now();
+            let mut loaded = Self::load_groups_for_scope(
+                self.db.clone(),
+                &self.relay_keys,
+                scope,
+                Some(group_id),
+            )
+            .await?;
+            if let Some(group) = loaded.remove(group_id) {
+                // The full load may have inserted it meanwhile, keep that one
+                self.index_slug(scope, &group);
+                self.groups
+                    .entry(key)
+                    .or_insert_with(|| Arc::new(RwLock::new(group)));
+            }
+            metrics::group_load_duration("group").record(start.elapsed().as_secs_f64());
+            Ok::<(), Error>(())
+        })
+        .await?;
+
+        Ok(self.get_group(scope, group_id))
+    }
+
+    /// Ids of the managed groups of a scope the pubkey can read: public groups
+    /// and the groups they belong to.
+    ///
+    /// `None` when reads can't be narrowed: the relay reads everything, and
+    /// before the full load the set would be incomplete.
+    pub fn readable_group_ids(
+        &self,
+        scope: &Scope,
+        pubkey: Option<&PublicKey>,
+    ) -> Option<BTreeSet<String>> {
+        if !self.is_loaded() || pubkey.is_some_and(|pubkey| self.relay_keys.is_relay(pubkey)) {
+            return None;
+        }
+
+        let index = self.read_index(scope);
+        let mut readable = index.public.clone();
+        if let Some(groups) = pubkey.and_then(|pubkey| index.memberships.get(pubkey)) {
+            readable.extend(groups.iter().cloned());
+        }
+        Some(readable)
+    }
+
+    fn read_index(&self, scope: &Scope) -> Arc<ReadIndex> {
+        if let Some(index) = self.read_indexes.get(scope) {
+            return Arc::clone(index.value());
+        }
+
+        let generation = self.read_index_generation.load(Ordering::Acquire);
+        let mut index = ReadIndex::default();
+        for entry in self.groups.iter().filter(|entry| &entry.key().0 == scope) {
+            let group = entry.value().read();
+            if !group.metadata.private {
+                index.public.insert(group.id.clone());
+            }
+            for pubkey in group.members.keys() {
+                index
+                    .memberships
+                    .entry(*pubkey)
+                    .or_default()
+                    .insert(group.id.clone());
+            }
+        }
+
+        let index = Arc::new(index);
+        // Checked under the shard lock a concurrent drop also takes. When
+        // membership changed while it was built, the next read builds it again.
+        let entry = self.read_indexes.entry(scope.clone());
+        if self.read_index_generation.load(Ordering::Acquire) == generation {
+            entry.insert(Arc::clone(&index));
+        }
+        index
+    }
+
+    /// Drops the read index of a scope where someone's membership or a
+    /// group's visibility changed
+    fn membership_changed(&self, scope: &Scope) {
+        self.read_index_generation.fetch_add(1, Ordering::AcqRel);
+        self.read_indexes.remove(scope);
+    }
+
+    /// Loads every group of every scope, keeping groups already loaded on demand
+    pub async fn load_all(&self) -> Result<(), Error> {
+        let start = Instant::now();
+
         // Get all scopes available in the database
-        let scopes = match database.list_scopes().await {
+        let scopes = match self.db.list_scopes().await {
             Ok(s) => s,
             Err(e) => return Err(Error::internal(format!("Failed to list scopes: {e}"))),
         };
 
         info!("Found {} scopes to load groups from", scopes.len());
-        let all_groups = DashMap::new();
         let mut load_failures = Vec::new();
 
         // Load groups from each scope
         for scope in &scopes {
-            match Self::load_groups_for_scope(database.clone(), scope, relay_pubkey).await {
+            match Self::load_groups_for_scope(self.db.clone(), &self.relay_keys, scope, None).await
+            {
                 Ok(scope_groups) => {
                     info!(
                         "Loaded {} groups from scope {:?}",
@@ -60,7 +378,9 @@ impl Groups {
                         scope
                     );
                     for (group_id, group) in scope_groups {
-                        all_groups.insert((scope.clone(), group_id), group);
+                        self.groups
+                            .entry((scope.clone(), group_id))
+                            .or_insert_with(|| Arc::new(RwLock::new(group)));
                     }
                 }
                 Err(e) => {
@@ -79,105 +399,152 @@ impl Groups {
             );
         }
 
-        Ok(Self {
-            db: database,
-            groups: all_groups,
-            relay_pubkey,
-            relay_url,
-        })
+        self.slugs.clear();
+        for entry in self.groups.iter() {
+            self.index_slug(&entry.key().0, &entry.value().read());
+        }
+        self.loaded.store(true, Ordering::Release);
+        self.lazy_loads.clear();
+        self.read_index_generation.fetch_add(1, Ordering::AcqRel);
+        self.read_indexes.clear();
+        metrics::group_load_duration("full").record(start.elapsed().as_secs_f64());
+        info!(
+            "Loaded {} groups in {:?}",
+            self.groups.len(),
+            start.elapsed()
+        );
+
+        Ok(())
     }
 
-    /// Helper function to load groups for a single scope
+    /// The creations, join requests, additions, removals, invites and welcome
+    /// notes a group's history is replayed from
+    fn history_filters(group_id: &str, relay_keys: &RelayPubkeys) -> Vec<Filter> {
+        vec![
+            Filter::new()
+                .kinds(vec![
+                    KIND_GROUP_CREATE_9007,            // 9007
+                    KIND_GROUP_USER_JOIN_REQUEST_9021, // 9021
+                    KIND_GROUP_ADD_USER_9000,          // 9000
+                    KIND_GROUP_REMOVE_USER_9001,       // 9001
+                    KIND_GROUP_CREATE_INVITE_9009,     // 9009
+                ])
+                .custom_tag(SingleLetterTag::lowercase(Alphabet::H), group_id)
+                .since(Timestamp::from(0)),
+            // Welcome notes, so members are only welcomed once
+            Filter::new()
+                .kind(KIND_GROUP_MESSAGE_9)
+                .authors(
+                    std::iter::once(relay_keys.active())
+                        .chain(relay_keys.previous().iter().copied()),
+                )
+                .custom_tag(SingleLetterTag::lowercase(Alphabet::H), group_id),
+        ]
+    }
+
+    /// Rebuilds a group from the database alone, without touching the loaded
+    /// copy. None when no state event of the group is stored.
+    pub async fn rebuild_group(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+    ) -> Result<Option<Group>, Error> {
+        let mut rebuilt =
+            Self::load_groups_for_scope(self.db.clone(), &self.relay_keys, scope, Some(group_id))
+                .await?;
+        Ok(rebuilt.remove(group_id))
+    }
+
+    /// Replaces the loaded copy of a group, returning the commands that store
+    /// its state events
+    pub fn replace_group(&self, group: Group) -> Vec<StoreCommand> {
+        let scope = group.scope.clone();
+        let commands: Vec<StoreCommand> = self
+            .state_events(&group)
+            .into_iter()
+            .map(|event| StoreCommand::SaveUnsignedEvent(event, scope.clone(), None))
+            .collect();
+        self.slugs
+            .retain(|(slug_scope, _), group_id| slug_scope != &scope || group_id != &group.id);
+        self.index_slug(&scope, &group);
+        // Written in place, so writers waiting on the lock apply their change
+        // to the replacement instead of a copy that's no longer in the map
+        let key = (scope, group.id.clone());
+        match self.shared_group(&key) {
+            Some(shared) => *shared.write() = group,
+            None => {
+                self.groups.insert(key, Arc::new(RwLock::new(group)));
+            }
+        }
+        self.membership_changed(&key.0);
+        commands
+    }
+
+    /// Helper function to load the groups of a single scope, or only the
+    /// given group
     async fn load_groups_for_scope(
         database: Arc<RelayDatabase>,
+        relay_keys: &RelayPubkeys,
         scope: &Scope,
-        _relay_pubkey: PublicKey,
+        group_id: Option<&str>,
     ) -> Result<HashMap<String, Group>, Error> {
-        info!("Loading groups from scope: {:?}", scope);
+        debug!("Loading groups from scope: {:?}", scope);
         let mut groups = HashMap::new();
 
         // Step 1: Load current state from replaceable events
-        let metadata_filter = vec![Filter::new()
-            .kinds(vec![
-                KIND_GROUP_METADATA_39000, // 39000
-                KIND_GROUP_ADMINS_39001,   // 39001
-                KIND_GROUP_MEMBERS_39002,  // 39002
-            ])
-            .since(Timestamp::from(0))];
+        let mut metadata_filter = Filter::new()
+            .kinds(GROUP_STATE_KINDS)
+            .since(Timestamp::from(0));
+        if let Some(group_id) = group_id {
+            metadata_filter = metadata_filter.identifier(group_id);
+        }
+        let metadata_filter = vec![metadata_filter];
 
         let metadata_events = match database.query(metadata_filter, scope).await {
             Ok(events) => events,
             Err(e) => {
-                return Err(Error::notice(format!(
+                return Err(Error::internal(format!(
                     "Error querying metadata events for scope {scope:?}: {e}"
                 )))
             }
         };
 
-        info!(
+        debug!(
             "Found {} metadata events in scope {:?}",
             metadata_events.len(),
             scope
         );
 
-        // Process events in order to build current state
-        for event in metadata_events.clone() {
+        let mut group_events: HashMap<String, Vec<Event>> = HashMap::new();
+        for event in metadata_events {
             let group_id = match Group::extract_group_id(&event) {
-                Some(id) => id,
+                Some(id) => id.to_string(),
                 None => {
                     warn!("Group ID not found in event: {:?}", event);
                     continue; // Skip this event instead of failing the entire load
                 }
             };
-
-            if event.kind == KIND_GROUP_METADATA_39000 {
-                debug!("[{}] Processing metadata in scope {:?}", group_id, scope);
-                groups
-                    .entry(group_id.to_string())
-                    .or_insert_with(|| {
-                        let mut g = Group::from(&event);
-                        g.scope = scope.clone();
-                        g
-                    })
-                    .load_metadata_from_event(&event)?;
-            } else if event.kind == KIND_GROUP_ADMINS_39001
-                || event.kind == KIND_GROUP_MEMBERS_39002
-            {
-                debug!("[{}] Processing members in scope {:?}", group_id, scope);
-                groups
-                    .entry(group_id.to_string())
-                    .or_insert_with(|| {
-                        let mut g = Group::from(&event);
-                        g.scope = scope.clone();
-                        g
-                    })
-                    .load_members_from_event(&event)?;
-            }
+            group_events.entry(group_id).or_default().push(event);
         }
 
         // Step 2: Load historical data for each group
-        info!("Processing {} groups in scope {:?}", groups.len(), scope);
+        debug!(
+            "Processing {} groups in scope {:?}",
+            group_events.len(),
+            scope
+        );
         let mut historical_load_errors = Vec::new();
 
-        for (group_id, group) in groups.iter_mut() {
+        for (group_id, mut events) in group_events {
             debug!(
                 "[{}] Loading historical data in scope {:?}",
                 group_id, scope
             );
 
-            let historical_filter = vec![Filter::new()
-                .kinds(vec![
-                    KIND_GROUP_CREATE_9007,            // 9007
-                    KIND_GROUP_USER_JOIN_REQUEST_9021, // 9021
-                    KIND_GROUP_CREATE_INVITE_9009,     // 9009
-                ])
-                .custom_tag(
-                    SingleLetterTag::lowercase(Alphabet::H),
-                    group_id.to_string(),
-                )
-                .since(Timestamp::from(0))];
-
-            match database.query(historical_filter, scope).await {
+            match database
+                .query(Self::history_filters(&group_id, relay_keys), scope)
+                .await
+            {
                 Ok(historical_events) => {
                     debug!(
                         "[{}] Found {} historical events in scope {:?}",
@@ -185,34 +552,7 @@ impl Groups {
                         historical_events.len(),
                         scope
                     );
-
-                    for event in historical_events {
-                        if event.kind == KIND_GROUP_CREATE_9007 {
-                            debug!("[{}] Found creation event in scope {:?}", group_id, scope);
-                            group.created_at = event.created_at;
-                        } else if event.kind == KIND_GROUP_USER_JOIN_REQUEST_9021 {
-                            if let Err(e) = group.load_join_request_from_event(&event) {
-                                warn!(
-                                    "Error loading join request for group {} in scope {:?}: {}",
-                                    group_id, scope, e
-                                );
-                            }
-                        } else if event.kind == KIND_GROUP_CREATE_INVITE_9009 {
-                            if let Err(e) = group.load_invite_from_event(&event) {
-                                warn!(
-                                    "Error loading invite for group {} in scope {:?}: {}",
-                                    group_id, scope, e
-                                );
-                            }
-                        }
-                    }
-
-                    // Update timestamps
-                    group.updated_at = metadata_events
-                        .iter()
-                        .map(|e| e.created_at)
-                        .max()
-                        .unwrap_or(group.updated_at);
+                    events.extend(historical_events);
                 }
                 Err(e) => {
                     warn!(
@@ -220,9 +560,14 @@ impl Groups {
                         group_id, scope, e
                     );
                     historical_load_errors.push((group_id.clone(), e.to_string()));
-                    // Continue with the next group
+                    // Continue with the state events alone
                 }
             }
+
+            if let Some(mut group) = Group::rebuild_from_events(&events, relay_keys)? {
+                group.scope = scope.clone();
+                groups.insert(group_id, group);
+            }
         }
 
         // Log summary of historical data loading errors if any
@@ -238,18 +583,45 @@ impl Groups {
         Ok(groups)
     }
 
+    /// The group's lock, cloned out so the map shard isn't held while it's
+    /// locked
+    fn shared_group(&self, key: &ScopedGroupKey) -> Option<SharedGroup> {
+        self.groups.get(key).map(|entry| Arc::clone(entry.value()))
+    }
+
+    fn read_group(&self, key: ScopedGroupKey) -> Option<GroupRef> {
+        let guard = self.shared_group(&key)?.read_arc();
+        Some(GroupRef { key, guard })
+    }
+
+    /// Write access to the group currently in the map. A group removed or
+    /// moved while we waited for its lock is looked up again, so changes
+    /// never land on a copy nobody reads anymore.
+    fn write_group(&self, key: ScopedGroupKey) -> Option<GroupRefMut> {
+        loop {
+            let shared = self.shared_group(&key)?;
+            let guard = shared.write_arc();
+            // Only a shard read lock is taken while the group's is held, and
+            // shard readers don't queue behind waiting writers
+            let current = self
+                .groups
+                .get(&key)
+                .is_some_and(|entry| Arc::ptr_eq(entry.value(), &shared));
+            if current {
+                return Some(GroupRefMut { key, guard });
+            }
+        }
+    }
+
     // Basic accessor methods
-    pub fn get_group(&self, scope: &Scope, group_id: &str) -> Option<ScopedGroupRef<'_>> {
-        // Create the key with minimal cloning
-        let key = (scope.clone(), group_id.to_string());
-        self.groups.get(&key)
+    pub fn get_group(&self, scope: &Scope, group_id: &str) -> Option<GroupRef> {
+        self.read_group((scope.clone(), group_id.to_string()))
     }
 
     // Nothing - removing backward compatibility method
 
-    pub fn get_group_mut(&self, scope: &Scope, group_id: &str) -> Option<ScopedGroupRefMut<'_>> {
-        let key = (scope.clone(), group_id.to_string());
-        self.groups.get_mut(&key)
+    pub fn get_group_mut(&self, scope: &Scope, group_id: &str) -> Option<GroupRefMut> {
+        self.write_group((scope.clone(), group_id.to_string()))
     }
 
     // Nothing - removing backward compatibility method
@@ -275,7 +647,7 @@ impl Groups {
             .iter()
             .map(|entry| {
                 let (scope, group_id) = entry.key();
-                let group = entry.value().clone();
+                let group = entry.value().read().clone();
                 (scope.clone(), group_id.clone(), group)
             })
             .collect()
@@ -291,7 +663,7 @@ impl Groups {
     }
 
     // More efficient implementation of find_group_in_any_scope
-    pub fn find_group_in_any_scope(&self, group_id: &str) -> Option<(Scope, ScopedGroupRef<'_>)> {
+    pub fn find_group_in_any_scope(&self, group_id: &str) -> Option<(Scope, GroupRef)> {
         // First find the matching scope (holding minimal locks)
         let mut found_scope = None;
 
@@ -306,7 +678,7 @@ impl Groups {
         // Second pass to get the actual group reference
         if let Some(scope) = found_scope {
             let key = (scope.clone(), group_id.to_string());
-            if let Some(group) = self.groups.get(&key) {
+            if let Some(group) = self.read_group(key) {
                 return Some((scope, group));
             }
         }
@@ -314,39 +686,29 @@ impl Groups {
         None
     }
 
-    pub fn find_group_from_event<'a>(
-        &'a self,
-        event: &Event,
-        scope: &Scope,
-    ) -> Option<Ref<'a, (Scope, String), Group>> {
+    pub fn find_group_from_event(&self, event: &Event, scope: &Scope) -> Option<GroupRef> {
         let group_id = Group::extract_group_id(event)?;
         self.get_group(scope, group_id)
     }
 
     // Nothing - removing backward compatibility method
 
-    pub fn find_group_from_event_mut<'a>(
-        &'a self,
+    pub fn find_group_from_event_mut(
+        &self,
         event: &Event,
         scope: &Scope,
-    ) -> Result<Option<ScopedGroupRefMut<'a>>, Error> {
+    ) -> Result<Option<GroupRefMut>, Error> {
         let Some(group_id) = Group::extract_group_id(event) else {
             return Ok(None);
         };
 
+        // Only this group's write lock is taken, the map isn't locked
         let key = (scope.clone(), group_id.to_string());
-
-        // Check existence first - this acquires a read lock briefly
-        // but avoids creating a RefMut that won't be needed
-        if !self.groups.contains_key(&key) {
-            return Ok(None);
-        }
-
-        // Now acquire the write lock for the group
-        let mut group_ref_opt = self.groups.get_mut(&key);
+        let mut group_ref_opt = self.write_group(key);
 
         if let Some(ref mut group_ref) = group_ref_opt {
-            if event.pubkey != self.relay_pubkey && event.kind != KIND_GROUP_USER_LEAVE_REQUEST_9022
+            if !self.relay_keys.is_relay(&event.pubkey)
+                && event.kind != KIND_GROUP_USER_LEAVE_REQUEST_9022
             {
                 let verification_result = group_ref.verify_member_access(&event.pubkey, event.kind);
                 verification_result?
@@ -358,11 +720,7 @@ impl Groups {
 
     // Nothing - removing backward compatibility method
 
-    pub fn find_group_from_event_h_tag<'a>(
-        &'a self,
-        event: &Event,
-        scope: &Scope,
-    ) -> Option<Ref<'a, (Scope, String), Group>> {
+    pub fn find_group_from_event_h_tag(&self, event: &Event, scope: &Scope) -> Option<GroupRef> {
         let group_id = Group::extract_group_h_tag(event)?;
         self.get_group(scope, group_id)
     }
@@ -442,7 +800,7 @@ impl Groups {
         let mut group = Group::new(&event, scope.clone())?;
 
         // Only allow migrating unmanaged groups to managed ones if creator is relay admin
-        if !previous_events.is_empty() && event.pubkey != self.relay_pubkey {
+        if !previous_events.is_empty() && !self.relay_keys.is_relay(&event.pubkey) {
             return Err(Error::event_error(
                 "Only relay admin can create a managed group from an unmanaged one",
                 event_id,
@@ -466,16 +824,22 @@ impl Groups {
             }
         }
 
+        if let Some(slug) = GroupMetadata::slug_tag(&event)?.filter(|slug| !slug.is_empty()) {
+            self.claim_slug(scope, &group.id, slug)?;
+        }
+
         // Now insert the new group with scope
-        self.groups.insert(key, group.clone());
+        self.groups
+            .insert(key, Arc::new(RwLock::new(group.clone())));
 
+        self.membership_changed(scope);
         metrics::groups_created().increment(1);
 
         // Make sure we're using the correct scope for all StoreCommands
         let mut commands = vec![StoreCommand::SaveSignedEvent(event, scope.clone(), None)];
         commands.extend(
             group
-                .generate_all_state_events(&self.relay_pubkey, &self.relay_url)?
+                .generate_all_state_events(&self.relay_keys, &self.relay_url)?
                 .into_iter()
                 .map(|e| StoreCommand::SaveUnsignedEvent(e, scope.clone(), None)),
         );
@@ -494,11 +858,173 @@ impl Groups {
             .ok_or_else(|| Error::event_error("[SetRoles] Group not found", event_id))?;
 
         // Group now uses the correct scope internally
-        group.set_roles(event, &self.relay_pubkey)
+        group.set_roles(event, &self.relay_keys)
     }
 
     // Nothing - removing backward compatibility method
 
+    pub fn handle_define_roles(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let mut group = self
+            .find_group_from_event_mut(&event, scope)?
+            .ok_or_else(|| Error::event_error("[DefineRoles] Group not found", event_id))?;
+
+        group.define_roles(event, &self.relay_keys)
+    }
+
+    pub fn handle_annotation(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let group = self
+            .find_group_from_event(&event, scope)
+            .ok_or_else(|| Error::event_error("[Annotation] Group not found", event_id))?;
+
+        group.annotate(event, &self.relay_keys)
+    }
+
+    pub fn add_webhook(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        webhook: GroupWebhook,
+        max_webhooks: usize,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        self.get_group_mut(scope, group_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Group {group_id} not found")))?
+            .add_webhook(caller, &self.relay_keys, webhook, max_webhooks)
+    }
+
+    pub fn remove_webhook(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        webhook_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        self.get_group_mut(scope, group_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Group {group_id} not found")))?
+            .remove_webhook(caller, &self.relay_keys, webhook_id)
+    }
+
+    pub fn add_bot_token(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        token: BotToken,
+        max_tokens: usize,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        self.get_group_mut(scope, group_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Group {group_id} not found")))?
+            .add_bot_token(caller, &self.relay_keys, token, max_tokens)
+    }
+
+    pub fn revoke_bot_token(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        caller: &PublicKey,
+        token_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        self.get_group_mut(scope, group_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Group {group_id} not found")))?
+            .revoke_bot_token(caller, &self.relay_keys, token_id)
+    }
+
+    pub fn handle_bot_content(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let group = self
+            .find_group_from_event(&event, scope)
+            .ok_or_else(|| Error::event_error("[BotContent] Group not found", event_id))?;
+
+        group.handle_bot_content(event)
+    }
+
+    pub fn handle_bot_delete_event(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let mut group = self
+            .find_group_from_event_mut(&event, scope)?
+            .ok_or_else(|| Error::event_error("[BotDeleteEvent] Group not found", event_id))?;
+
+        group.bot_delete_event_request(event)
+    }
+
+    pub fn disable_webhook(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        webhook_id: &str,
+    ) -> Result<Vec<StoreCommand>, GroupError> {
+        self.get_group_mut(scope, group_id)
+            .ok_or_else(|| GroupError::NotFound(format!("Group {group_id} not found")))?
+            .disable_webhook(&self.relay_keys, webhook_id)
+    }
+
+    /// Handles NIP-09 deletions (kind 5). Referenced events of managed groups
+    /// are deleted when the author may delete them; references to other
+    /// events are left to the generic NIP-09 handling.
+    pub async fn handle_event_deletion(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_ids: Vec<EventId> = event.tags.event_ids().copied().collect();
+
+        let mut to_delete = Vec::new();
+        if !event_ids.is_empty() {
+            let referenced = self
+                .db
+                .query(vec![Filter::new().ids(event_ids)], scope)
+                .await
+                .map_err(|e| Error::failed(format!("Failed to look up deleted events: {e}")))?;
+
+            let mut by_group: HashMap<String, Vec<Event>> = HashMap::new();
+            for target in referenced {
+                if let Some(group_id) = Group::extract_group_h_tag(&target) {
+                    by_group
+                        .entry(group_id.to_string())
+                        .or_default()
+                        .push(target);
+                }
+            }
+
+            for (group_id, targets) in &by_group {
+                // Unmanaged groups follow the generic NIP-09 rules
+                let Some(group) = self.get_group(scope, group_id) else {
+                    continue;
+                };
+                to_delete.extend(group.deletion_targets(&event, targets, &self.relay_keys)?);
+            }
+        }
+
+        let mut commands = Vec::new();
+        if !to_delete.is_empty() {
+            commands.push(StoreCommand::DeleteEvents(
+                Filter::new().ids(to_delete),
+                scope.clone(),
+                None,
+            ));
+        }
+        commands.push(StoreCommand::SaveSignedEvent(event, scope.clone(), None));
+        Ok(commands)
+    }
+
     pub fn handle_put_user(
         &self,
         event: Box<Event>,
@@ -510,7 +1036,9 @@ impl Groups {
             .ok_or_else(|| Error::event_error("[PutUser] Group not found", event_id))?;
 
         // Group now uses the correct scope internally
-        group.add_members_from_event(event, &self.relay_pubkey)
+        let commands = group.add_members_from_event(event, &self.relay_keys)?;
+        self.membership_changed(scope);
+        Ok(commands)
     }
 
     // Nothing - removing backward compatibility method
@@ -525,7 +1053,9 @@ impl Groups {
             .find_group_from_event_mut(&event, scope)?
             .ok_or_else(|| Error::event_error("[RemoveUser] Group not found", event_id))?;
 
-        group.remove_members(event, &self.relay_pubkey)
+        let commands = group.remove_members(event, &self.relay_keys)?;
+        self.membership_changed(scope);
+        Ok(commands)
     }
 
     // Nothing - removing backward compatibility method
@@ -540,7 +1070,7 @@ impl Groups {
             .find_group_from_event_mut(&event, scope)?
             .ok_or_else(|| Error::event_error("[GroupManagement] Group not found", event_id))?;
 
-        group.handle_group_content(event, &self.relay_pubkey)
+        group.handle_group_content(event, &self.relay_keys)
     }
 
     // Nothing - removing backward compatibility method
@@ -555,7 +1085,26 @@ impl Groups {
             .find_group_from_event_mut(&event, scope)?
             .ok_or_else(|| Error::event_error("[EditMetadata] Group not found", event_id))?;
 
-        group.set_metadata(&event, &self.relay_pubkey)?;
+        let previous_slug = group.metadata.slug.clone();
+        let slug = GroupMetadata::slug_tag(&event)?.filter(|slug| !slug.is_empty());
+        let claimed = match slug {
+            Some(slug) => self.claim_slug(scope, &group.id, slug)?,
+            None => false,
+        };
+        if let Err(e) = group.set_metadata(&event, &self.relay_keys) {
+            if let Some(slug) = slug.filter(|_| claimed) {
+                self.release_slug(scope, &group.id, slug);
+            }
+            return Err(e);
+        }
+        if let Some(previous) =
+            previous_slug.filter(|previous| group.metadata.slug.as_ref() != Some(previous))
+        {
+            self.release_slug(scope, &group.id, &previous);
+        }
+        group
+            .metadata
+            .truncate_previous_names(self.max_previous_names);
 
         let scope_clone = scope.clone();
         let mut commands = vec![StoreCommand::SaveSignedEvent(
@@ -565,10 +1114,35 @@ impl Groups {
         )];
         commands.extend(
             group
-                .generate_metadata_events(&self.relay_pubkey, &self.relay_url)
+                .generate_metadata_events(&self.relay_keys, &self.relay_url)
                 .into_iter()
                 .map(|e| StoreCommand::SaveUnsignedEvent(e, scope_clone.clone(), None)),
         );
+        // Privacy may have changed
+        self.membership_changed(scope);
+
+        Ok(commands)
+    }
+
+    pub fn handle_emoji_set(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let mut group = self
+            .find_group_from_event_mut(&event, scope)?
+            .ok_or_else(|| Error::event_error("[EmojiSet] Group not found", event_id))?;
+
+        group.set_emoji_set(&event, &self.relay_keys)?;
+
+        let mut commands = vec![StoreCommand::SaveSignedEvent(event, scope.clone(), None)];
+        commands.extend(
+            group
+                .generate_metadata_events(&self.relay_keys, &self.relay_url)
+                .into_iter()
+                .map(|e| StoreCommand::SaveUnsignedEvent(e, scope.clone(), None)),
+        );
 
         Ok(commands)
     }
@@ -585,7 +1159,7 @@ impl Groups {
             let mut group = self
                 .find_group_from_event_mut(&event, scope)?
                 .ok_or_else(|| Error::event_error("[CreateInvite] Group not found", event_id))?;
-            group.create_invite(&event, &self.relay_pubkey)?;
+            group.create_invite(&event, &self.relay_keys)?;
         }
 
         // Regardless of whether the invite was newly created or already existed (created=false),
@@ -611,10 +1185,11 @@ impl Groups {
                 .find_group_from_event_mut(&event, scope)?
                 .ok_or_else(|| Error::event_error("[JoinRequest] Group not found", event_id))?;
 
-            result = group.join_request(event, &self.relay_pubkey);
+            result = group.join_request(event, &self.relay_keys)?;
         }
 
-        result
+        self.membership_changed(scope);
+        Ok(result)
     }
 
     // Nothing - removing backward compatibility method
@@ -629,48 +1204,278 @@ impl Groups {
             .find_group_from_event_mut(&event, scope)?
             .ok_or_else(|| Error::event_error("[LeaveRequest] Group not found", event_id))?;
 
-        group.leave_request(event, &self.relay_pubkey)
+        let commands = group.leave_request(event, &self.relay_keys)?;
+        self.membership_changed(scope);
+        Ok(commands)
     }
 
     // Nothing - removing backward compatibility method
 
-    pub fn handle_delete_event(
+    pub fn handle_delete_event(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let mut group = self
+            .find_group_from_event_mut(&event, scope)?
+            .ok_or_else(|| {
+                Error::event_error("Group not found for this group content", event_id)
+            })?;
+
+        group.delete_event_request(event, &self.relay_keys)
+    }
+
+    // Nothing - removing backward compatibility method
+
+    pub fn handle_delete_group(
+        &self,
+        event: Box<Event>,
+        scope: &Scope,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let event_id = event.id;
+        let group = self
+            .find_group_from_event(&event, scope)
+            .ok_or_else(|| Error::event_error("[DeleteGroup] Group not found", event_id))?;
+
+        // Extract the group ID
+        let group_id = group.key().1.clone();
+        let slug = group.metadata.slug.clone();
+        let commands = group.delete_group_request(event, &self.relay_keys)?;
+        drop(group);
+        if let Some(slug) = slug {
+            self.release_slug(scope, &group_id, &slug);
+        }
+
+        // Remove using the composite key: (scope, group_id)
+        let key = (scope.clone(), group_id);
+        self.groups.remove(&key);
+        self.membership_changed(scope);
+
+        Ok(commands)
+    }
+
+    /// The id of the group holding a slug in the scope
+    pub fn group_id_by_slug(&self, scope: &Scope, slug: &str) -> Option<String> {
+        self.slugs
+            .get(&(scope.clone(), slug.to_string()))
+            .map(|group_id| group_id.clone())
+    }
+
+    fn index_slug(&self, scope: &Scope, group: &Group) {
+        if self.slugs_read_only {
+            return;
+        }
+        if let Some(slug) = &group.metadata.slug {
+            self.slugs
+                .insert((scope.clone(), slug.clone()), group.id.clone());
+        }
+    }
+
+    /// Reserves a slug for the group, returning whether it was free. Slugs
+    /// of groups the full load hasn't reached yet are unknown, so none are
+    /// claimed before it finishes.
+    fn claim_slug(&self, scope: &Scope, group_id: &str, slug: &str) -> Result<bool, Error> {
+        if !self.is_loaded() {
+            return Err(Error::internal(
+                "Slugs can't be checked until every group is loaded, try again shortly",
+            ));
+        }
+
+        let key = (scope.clone(), slug.to_string());
+        if self.slugs_read_only {
+            return match self.slugs.get(&key) {
+                Some(holder) if *holder != group_id => Err(Error::invalid(SLUG_IN_USE)),
+                holder => Ok(holder.is_none()),
+            };
+        }
+
+        match self.slugs.entry(key) {
+            Entry::Occupied(holder) if holder.get() != group_id => Err(Error::invalid(SLUG_IN_USE)),
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(group_id.to_string());
+                Ok(true)
+            }
+        }
+    }
+
+    fn release_slug(&self, scope: &Scope, group_id: &str, slug: &str) {
+        if self.slugs_read_only {
+            return;
+        }
+        self.slugs
+            .remove_if(&(scope.clone(), slug.to_string()), |_, holder| {
+                holder == group_id
+            });
+    }
+
+    /// Re-issues the 39000-39003 state of every group signed by the active
+    /// relay key, deleting the copies signed by previous keys. Addressable
+    /// events are replaced per author, so without the deletion both copies
+    /// would be served and loaded.
+    pub fn reissue_state_events(&self) -> Vec<StoreCommand> {
+        let mut commands = Vec::new();
+        let mut scopes = HashSet::new();
+
+        for entry in self.groups.iter() {
+            let scope = &entry.key().0;
+            commands.extend(
+                self.state_events(&entry.value().read())
+                    .into_iter()
+                    .map(|event| StoreCommand::SaveUnsignedEvent(event, scope.clone(), None)),
+            );
+            scopes.insert(scope.clone());
+        }
+
+        if !self.relay_keys.previous().is_empty() {
+            let previous_state = Filter::new()
+                .kinds([
+                    KIND_GROUP_METADATA_39000,
+                    KIND_GROUP_ADMINS_39001,
+                    KIND_GROUP_MEMBERS_39002,
+                    KIND_GROUP_ROLES_39003,
+                    KIND_GROUP_JOIN_REQUESTS_39004,
+                    KIND_GROUP_INVITE_REDEMPTIONS_39005,
+                    KIND_GROUP_MODERATION_39006,
+                    KIND_GROUP_WEBHOOKS_39010,
+                    KIND_GROUP_BOT_TOKENS_39011,
+                ])
+                .authors(self.relay_keys.previous().iter().copied());
+            commands.extend(
+                scopes
+                    .into_iter()
+                    .map(|scope| StoreCommand::DeleteEvents(previous_state.clone(), scope, None)),
+            );
+        }
+
+        commands
+    }
+
+    /// The relay-generated addressable events describing a group's state
+    fn state_events(&self, group: &Group) -> Vec<UnsignedEvent> {
+        let mut events = vec![
+            group.generate_metadata_event(&self.relay_keys, &self.relay_url),
+            group.generate_roles_event(&self.relay_keys),
+            group.generate_members_event(&self.relay_keys),
+        ];
+        match group.generate_admins_event(&self.relay_keys) {
+            Ok(admins_event) => events.push(admins_event),
+            Err(e) => warn!("Not issuing admins of group {}: {}", group.id, e),
+        }
+        if !group.join_requests.is_empty() {
+            events.push(group.generate_join_requests_event(&self.relay_keys));
+        }
+        if group.has_redemptions() {
+            events.push(group.generate_redemptions_event(&self.relay_keys));
+        }
+        if !group.moderation.is_empty() {
+            events.push(group.generate_moderation_event(&self.relay_keys));
+        }
+        if !group.webhooks.is_empty() {
+            events.push(group.generate_webhooks_event(&self.relay_keys));
+        }
+        if !group.bot_tokens.is_empty() {
+            events.push(group.generate_bot_tokens_event(&self.relay_keys));
+        }
+        events
+    }
+
+    /// Moves a group to another scope, returning the number of its events
+    /// and the commands that finish the move.
+    ///
+    /// The group's events are copied to the destination with their
+    /// signatures, then the group is registered there before it is dropped
+    /// from the source, so it is always found in one of them. The returned
+    /// commands save its regenerated state events in the destination and
+    /// delete the originals. With `dry_run` only the events are counted.
+    pub async fn move_group(
         &self,
-        event: Box<Event>,
-        scope: &Scope,
-    ) -> Result<Vec<StoreCommand>, Error> {
-        let event_id = event.id;
-        let mut group = self
-            .find_group_from_event_mut(&event, scope)?
-            .ok_or_else(|| {
-                Error::event_error("Group not found for this group content", event_id)
-            })?;
+        group_id: &str,
+        from: &Scope,
+        to: &Scope,
+        dry_run: bool,
+    ) -> Result<(usize, Vec<StoreCommand>), GroupError> {
+        let internal = |e: Error| GroupError::Internal(anyhow::anyhow!("{e}"));
+        if from == to {
+            return Err(GroupError::ValidationFailed(
+                "Source and destination scopes are the same".to_string(),
+            ));
+        }
 
-        group.delete_event_request(event, &self.relay_pubkey)
-    }
+        if self
+            .get_or_load(from, group_id)
+            .await
+            .map_err(internal)?
+            .is_none()
+        {
+            return Err(GroupError::NotFound(group_id.to_string()));
+        }
+        if self
+            .get_or_load(to, group_id)
+            .await
+            .map_err(internal)?
+            .is_some()
+        {
+            return Err(GroupError::ValidationFailed(format!(
+                "A group {group_id} already exists in the destination scope"
+            )));
+        }
 
-    // Nothing - removing backward compatibility method
+        let content = Filter::new().custom_tag(SingleLetterTag::lowercase(Alphabet::H), group_id);
+        let state = Filter::new().identifier(group_id);
+        let events = self
+            .db
+            .query(vec![content.clone()], from)
+            .await
+            .map_err(|e| GroupError::Internal(e.into()))?;
+        if dry_run {
+            return Ok((events.len(), Vec::new()));
+        }
 
-    pub fn handle_delete_group(
-        &self,
-        event: Box<Event>,
-        scope: &Scope,
-    ) -> Result<Vec<StoreCommand>, Error> {
-        let event_id = event.id;
-        let group = self
-            .find_group_from_event(&event, scope)
-            .ok_or_else(|| Error::event_error("[DeleteGroup] Group not found", event_id))?;
+        for event in events.iter() {
+            self.db
+                .save_event(event, to)
+                .await
+                .map_err(|e| GroupError::Internal(e.into()))?;
+        }
 
-        // Extract the group ID
-        let group_id = group.key().1.clone();
-        let commands = group.delete_group_request(event, &self.relay_pubkey)?;
-        drop(group);
+        let mut group = self
+            .get_group(from, group_id)
+            .map(|group| group.value().clone())
+            .ok_or_else(|| GroupError::NotFound(group_id.to_string()))?;
+        group.scope = to.clone();
+        let moved = Arc::new(RwLock::new(group));
+        self.groups
+            .insert((to.clone(), group_id.to_string()), Arc::clone(&moved));
+        // Removed before its lock is taken: writers that already hold it
+        // finish first and are copied over, later ones look the group up
+        // again and find it gone from the source
+        if let Some((_, source)) = self.groups.remove(&(from.clone(), group_id.to_string())) {
+            let mut latest = source.write().clone();
+            latest.scope = to.clone();
+            *moved.write() = latest;
+        }
 
-        // Remove using the composite key: (scope, group_id)
-        let key = (scope.clone(), group_id);
-        self.groups.remove(&key);
+        let mut commands: Vec<StoreCommand> = self
+            .state_events(&moved.read())
+            .into_iter()
+            .map(|event| StoreCommand::SaveUnsignedEvent(event, to.clone(), None))
+            .collect();
+        commands.push(StoreCommand::DeleteEvents(content, from.clone(), None));
+        commands.push(StoreCommand::DeleteEvents(state, from.clone(), None));
 
-        Ok(commands)
+        self.membership_changed(from);
+        self.membership_changed(to);
+        info!(
+            "Moved group {} with {} events from {:?} to {:?}",
+            group_id,
+            events.len(),
+            from,
+            to
+        );
+
+        Ok((events.len(), commands))
     }
 
     // Nothing - removing backward compatibility method
@@ -685,7 +1490,7 @@ impl Groups {
         ];
 
         for group in self.iter() {
-            let group = group.value();
+            let group = group.value().read();
             let idx = match (group.metadata.private, group.metadata.closed) {
                 (false, false) => 0,
                 (false, true) => 1,
@@ -709,7 +1514,7 @@ impl Groups {
 
         for entry in self.iter() {
             let (key_scope, _) = entry.key();
-            let group = entry.value();
+            let group = entry.value().read();
 
             // Only count groups in the specified scope
             if key_scope != scope {
@@ -743,7 +1548,7 @@ impl Groups {
             GroupError::PermissionDenied("Authentication required for private group".to_string())
         })?;
 
-        if pubkey == self.relay_pubkey || group.is_member(&pubkey) {
+        if self.relay_keys.is_relay(&pubkey) || group.is_member(&pubkey) {
             Ok(())
         } else {
             Err(GroupError::PermissionDenied(
@@ -772,7 +1577,7 @@ impl Groups {
 }
 
 impl Deref for Groups {
-    type Target = DashMap<(Scope, String), Group>;
+    type Target = DashMap<ScopedGroupKey, SharedGroup>;
 
     fn deref(&self) -> &Self::Target {
         &self.groups
@@ -823,8 +1628,16 @@ mod tests {
         Groups {
             db: Arc::new(db),
             groups: DashMap::new(),
+            loaded: AtomicBool::new(true),
+            lazy_loads: DashMap::new(),
+            read_indexes: DashMap::new(),
+            read_index_generation: AtomicU64::new(0),
             relay_pubkey: admin_keys.public_key(),
+            relay_keys: RelayPubkeys::new(admin_keys.public_key()),
             relay_url: "wss://test.relay.url".to_string(),
+            slugs: Arc::default(),
+            slugs_read_only: false,
+            max_previous_names: MAX_PREVIOUS_NAMES,
         }
     }
 
@@ -859,6 +1672,21 @@ mod tests {
         assert!(group.value().is_admin(&admin_keys.public_key()));
     }
 
+    #[tokio::test]
+    async fn test_replace_group_writes_in_place() {
+        let (groups, _, _, _, group_id, scope) = setup_test_groups().await;
+        let key = (scope.clone(), group_id.clone());
+        // Held by a writer that looked the group up before the replacement
+        let shared = groups.shared_group(&key).unwrap();
+
+        let mut replacement = groups.get_group(&scope, &group_id).unwrap().value().clone();
+        replacement.metadata.name = "healed".to_string();
+        groups.replace_group(replacement);
+
+        assert!(Arc::ptr_eq(&shared, &groups.shared_group(&key).unwrap()));
+        assert_eq!(shared.read().metadata.name, "healed");
+    }
+
     #[tokio::test]
     async fn test_handle_group_create_relay_never_member() {
         let (admin_keys, user_keys, _) = create_test_keys().await;
@@ -1525,7 +2353,11 @@ mod tests {
 
         // According to NIP-29, the join request should be saved
         let result = groups.handle_join_request(join_event, &scope).unwrap();
-        assert_eq!(result.len(), 1, "Join request should be saved");
+        assert_eq!(
+            result.len(),
+            2,
+            "Join request should be saved with the pending requests"
+        );
 
         match &result[0] {
             StoreCommand::SaveSignedEvent(event, _, _) => {
@@ -1533,6 +2365,12 @@ mod tests {
             }
             _ => panic!("Expected SaveSignedEvent command"),
         }
+        match &result[1] {
+            StoreCommand::SaveUnsignedEvent(event, _, _) => {
+                assert_eq!(event.kind, KIND_GROUP_JOIN_REQUESTS_39004);
+            }
+            _ => panic!("Expected SaveUnsignedEvent command"),
+        }
 
         let group = groups.get_group(&scope, &group_id).unwrap();
         assert!(!group.value().is_member(&member_keys.public_key()));
@@ -1549,7 +2387,11 @@ mod tests {
 
         // According to NIP-29, the join request should be saved
         let result = groups.handle_join_request(join_event, &scope).unwrap();
-        assert_eq!(result.len(), 1, "Join request should be saved");
+        assert_eq!(
+            result.len(),
+            2,
+            "Join request should be saved with the pending requests"
+        );
 
         match &result[0] {
             StoreCommand::SaveSignedEvent(event, _, _) => {
@@ -1557,6 +2399,12 @@ mod tests {
             }
             _ => panic!("Expected SaveSignedEvent command"),
         }
+        match &result[1] {
+            StoreCommand::SaveUnsignedEvent(event, _, _) => {
+                assert_eq!(event.kind, KIND_GROUP_JOIN_REQUESTS_39004);
+            }
+            _ => panic!("Expected SaveUnsignedEvent command"),
+        }
 
         let group = groups.get_group(&scope, &group_id).unwrap();
         // The join request should be added to the join_requests set
@@ -1688,7 +2536,7 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
-            "Notice: Cannot remove last admin"
+            "Notice: invalid: Cannot remove last admin"
         );
 
         // Verify admin is still in the group
@@ -2075,4 +2923,628 @@ mod tests {
             assert!(group.value().is_member(&non_member_keys.public_key()));
         }
     }
+
+    #[tokio::test]
+    async fn test_member_deletes_only_own_group_messages() {
+        let (groups, admin_keys, member_keys, _, group_id, scope) = setup_test_groups().await;
+        let add_member = create_test_event(
+            &admin_keys,
+            KIND_GROUP_ADD_USER_9000,
+            vec![
+                Tag::custom(TagKind::h(), [&group_id]),
+                Tag::public_key(member_keys.public_key()),
+            ],
+        )
+        .await;
+        groups.handle_put_user(add_member, &scope).unwrap();
+
+        let h_tag = || vec![Tag::custom(TagKind::h(), [&group_id])];
+        let own = create_test_event(&member_keys, Kind::Custom(9), h_tag()).await;
+        let admins = create_test_event(&admin_keys, Kind::Custom(9), h_tag()).await;
+        let outside = create_test_event(&member_keys, Kind::TextNote, vec![]).await;
+        for event in [&own, &admins, &outside] {
+            groups.db.save_event(event, &scope).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let deletion = create_test_event(
+            &member_keys,
+            Kind::EventDeletion,
+            vec![
+                Tag::event(own.id),
+                Tag::event(admins.id),
+                Tag::event(outside.id),
+            ],
+        )
+        .await;
+        let commands = groups
+            .handle_event_deletion(deletion.clone(), &scope)
+            .await
+            .unwrap();
+
+        assert_eq!(commands.len(), 2);
+        let StoreCommand::DeleteEvents(filter, _, _) = &commands[0] else {
+            panic!("Expected DeleteEvents command");
+        };
+        // The admin's message stays, the non-group note is left to NIP-09
+        assert_eq!(filter.ids, Some([own.id].into_iter().collect()));
+        assert!(matches!(
+            &commands[1],
+            StoreCommand::SaveSignedEvent(event, _, _) if event.id == deletion.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_previous_relay_key_keeps_relay_permissions() {
+        let (old_relay_keys, user_keys, member_keys) = create_test_keys().await;
+        let new_relay_keys = Keys::generate();
+        let stranger_keys = Keys::generate();
+        let groups = create_test_groups_with_db(&new_relay_keys)
+            .await
+            .with_previous_relay_keys([old_relay_keys.public_key()]);
+        let scope = Scope::Default;
+        let h_tag = Tag::custom(TagKind::h(), [TEST_GROUP_ID]);
+
+        let create =
+            create_test_event(&user_keys, KIND_GROUP_CREATE_9007, vec![h_tag.clone()]).await;
+        groups.handle_group_create(create, &scope).await.unwrap();
+
+        let stranger_add = create_test_event(
+            &stranger_keys,
+            KIND_GROUP_ADD_USER_9000,
+            vec![h_tag.clone(), Tag::public_key(member_keys.public_key())],
+        )
+        .await;
+        assert!(groups.handle_put_user(stranger_add, &scope).is_err());
+
+        let old_key_add = create_test_event(
+            &old_relay_keys,
+            KIND_GROUP_ADD_USER_9000,
+            vec![h_tag.clone(), Tag::public_key(member_keys.public_key())],
+        )
+        .await;
+        groups.handle_put_user(old_key_add, &scope).unwrap();
+
+        let message = create_test_event(&member_keys, Kind::Custom(9), vec![h_tag]).await;
+        let group = groups.get_group(&scope, TEST_GROUP_ID).unwrap();
+        let group = group.value();
+        assert!(group.is_member(&member_keys.public_key()));
+        assert!(group
+            .can_delete_event(
+                &Some(old_relay_keys.public_key()),
+                groups.relay_keys(),
+                &message,
+                "event"
+            )
+            .is_ok());
+        assert!(group
+            .can_delete_event(
+                &Some(stranger_keys.public_key()),
+                groups.relay_keys(),
+                &message,
+                "event"
+            )
+            .is_err());
+        assert!(group
+            .can_see_event(
+                &Some(old_relay_keys.public_key()),
+                groups.relay_keys(),
+                &message
+            )
+            .unwrap());
+
+        // Generated state is signed by the active key only
+        let admins_event = group.generate_admins_event(groups.relay_keys()).unwrap();
+        assert_eq!(admins_event.pubkey, new_relay_keys.public_key());
+    }
+
+    #[tokio::test]
+    async fn test_invite_redemptions_survive_reload() {
+        use crate::groups_event_processor::GroupsRelayProcessor;
+        use crate::utils::apply_store_commands;
+        use relay_builder::{EventContext, EventProcessor};
+        use tokio::sync::RwLock;
+
+        let (_tmp_dir, database, relay_keys) = crate::test_utils::setup_test().await;
+        let load = || {
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+        };
+        let groups = Arc::new(load().await.unwrap());
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let admin = Keys::generate();
+        let (first, second, third) = create_test_keys().await;
+        let h_tag = Tag::custom(TagKind::h(), [TEST_GROUP_ID]);
+        let code = |code: &str| Tag::custom(TagKind::custom("code"), [code]);
+
+        let steps = [
+            (&admin, KIND_GROUP_CREATE_9007, vec![h_tag.clone()]),
+            (
+                &admin,
+                KIND_GROUP_EDIT_METADATA_9002,
+                vec![
+                    h_tag.clone(),
+                    Tag::custom(TagKind::custom("closed"), Vec::<String>::new()),
+                ],
+            ),
+            (
+                &admin,
+                KIND_GROUP_CREATE_INVITE_9009,
+                vec![
+                    h_tag.clone(),
+                    code("shared"),
+                    Tag::custom(TagKind::custom("reusable"), Vec::<String>::new()),
+                ],
+            ),
+            (
+                &admin,
+                KIND_GROUP_CREATE_INVITE_9009,
+                vec![h_tag.clone(), code("single")],
+            ),
+            (
+                &first,
+                KIND_GROUP_USER_JOIN_REQUEST_9021,
+                vec![h_tag.clone(), code("shared")],
+            ),
+            (
+                &second,
+                KIND_GROUP_USER_JOIN_REQUEST_9021,
+                vec![h_tag.clone(), code("shared")],
+            ),
+            (
+                &third,
+                KIND_GROUP_USER_JOIN_REQUEST_9021,
+                vec![h_tag.clone(), code("single")],
+            ),
+        ];
+        // Distinct timestamps keep the replay order unambiguous
+        let start = Timestamp::now().as_u64() - 60;
+        for (offset, (keys, kind, tags)) in (0..).zip(steps) {
+            let context = EventContext {
+                authed_pubkey: Some(keys.public_key()),
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey: relay_keys.public_key(),
+            };
+            let event = EventBuilder::new(kind, "")
+                .tags(tags)
+                .custom_created_at(Timestamp::from(start + offset))
+                .sign_with_keys(keys)
+                .unwrap();
+            let commands = processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let redeemers = |groups: &Groups, code: &str| -> Vec<PublicKey> {
+            let group = groups.get_group(&Scope::Default, TEST_GROUP_ID).unwrap();
+            group.value().invites[code]
+                .redemptions
+                .iter()
+                .map(|(pubkey, _)| *pubkey)
+                .collect()
+        };
+        let shared = vec![first.public_key(), second.public_key()];
+        let single = vec![third.public_key()];
+        assert_eq!(redeemers(&groups, "shared"), shared);
+        assert_eq!(redeemers(&groups, "single"), single);
+
+        let history = database
+            .query(
+                vec![Filter::new()
+                    .kind(KIND_GROUP_INVITE_REDEMPTIONS_39005)
+                    .identifier(TEST_GROUP_ID)],
+                &Scope::Default,
+            )
+            .await
+            .unwrap();
+        let history = history.first().unwrap();
+        assert_eq!(
+            history.tags.filter(TagKind::custom("redemption")).count(),
+            3
+        );
+        let group = groups.get_group(&Scope::Default, TEST_GROUP_ID).unwrap();
+        let group = group.value();
+        assert!(group
+            .can_see_event(&Some(admin.public_key()), &relay_keys.public_key(), history)
+            .unwrap());
+        assert!(!group
+            .can_see_event(&Some(first.public_key()), &relay_keys.public_key(), history)
+            .unwrap());
+
+        // A restart rebuilds the history from the stored join requests
+        let reloaded = load().await.unwrap();
+        assert_eq!(redeemers(&reloaded, "shared"), shared);
+        assert_eq!(redeemers(&reloaded, "single"), single);
+        let group = reloaded.get_group(&Scope::Default, TEST_GROUP_ID).unwrap();
+        assert!(!group.value().invites["single"].can_use());
+    }
+
+    #[tokio::test]
+    async fn test_welcome_note_sent_once_per_member() {
+        use crate::groups_event_processor::GroupsRelayProcessor;
+        use crate::utils::apply_store_commands;
+        use relay_builder::{EventContext, EventProcessor};
+        use tokio::sync::RwLock;
+
+        let (_tmp_dir, database, relay_keys) = crate::test_utils::setup_test().await;
+        let load = || {
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+        };
+        let admin = Keys::generate();
+        let (member, _, _) = create_test_keys().await;
+        let h_tag = Tag::custom(TagKind::h(), [TEST_GROUP_ID]);
+        let flag = |name: &str| Tag::custom(TagKind::custom(name), Vec::<String>::new());
+        let start = Timestamp::now().as_u64() - 60;
+
+        let run = |processor: GroupsRelayProcessor,
+                   steps: Vec<(&'static str, Kind, Vec<Tag>)>,
+                   offset: u64| {
+            let database = &database;
+            let relay_keys = &relay_keys;
+            let admin = &admin;
+            let member = &member;
+            async move {
+                for (index, (who, kind, tags)) in (offset..).zip(steps) {
+                    let keys = if who == "admin" { admin } else { member };
+                    let context = EventContext {
+                        authed_pubkey: Some(keys.public_key()),
+                        subdomain: Arc::new(Scope::Default),
+                        relay_pubkey: relay_keys.public_key(),
+                    };
+                    let event = EventBuilder::new(kind, "")
+                        .tags(tags)
+                        .custom_created_at(Timestamp::from(start + index))
+                        .sign_with_keys(keys)
+                        .unwrap();
+                    let commands = processor
+                        .handle_event(event, Arc::new(RwLock::new(())), &context)
+                        .await
+                        .unwrap();
+                    apply_store_commands(database, relay_keys, commands)
+                        .await
+                        .unwrap();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+            }
+        };
+        let rejoin = || {
+            vec![
+                (
+                    "member",
+                    KIND_GROUP_USER_LEAVE_REQUEST_9022,
+                    vec![h_tag.clone()],
+                ),
+                (
+                    "member",
+                    KIND_GROUP_USER_JOIN_REQUEST_9021,
+                    vec![h_tag.clone()],
+                ),
+            ]
+        };
+
+        let groups = Arc::new(load().await.unwrap());
+        let mut steps = vec![
+            ("admin", KIND_GROUP_CREATE_9007, vec![h_tag.clone()]),
+            (
+                "admin",
+                KIND_GROUP_EDIT_METADATA_9002,
+                vec![
+                    h_tag.clone(),
+                    flag("open"),
+                    Tag::custom(TagKind::custom("welcome"), ["Welcome aboard!"]),
+                ],
+            ),
+            (
+                "member",
+                KIND_GROUP_USER_JOIN_REQUEST_9021,
+                vec![h_tag.clone()],
+            ),
+        ];
+        steps.extend(rejoin());
+        run(
+            GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key()),
+            steps,
+            0,
+        )
+        .await;
+        let group = groups.get_group(&Scope::Default, TEST_GROUP_ID).unwrap();
+        assert!(group.value().is_member(&member.public_key()));
+        let metadata = group
+            .value()
+            .generate_metadata_event(groups.relay_keys(), "wss://test.relay");
+        assert_eq!(
+            metadata
+                .tags
+                .find(TagKind::custom("welcome"))
+                .and_then(|tag| tag.content()),
+            Some("Welcome aboard!")
+        );
+        drop(group);
+
+        // A restart remembers who was welcomed
+        let reloaded = Arc::new(load().await.unwrap());
+        run(
+            GroupsRelayProcessor::new(reloaded, relay_keys.public_key()),
+            rejoin(),
+            10,
+        )
+        .await;
+
+        let notes = database
+            .query(
+                vec![Filter::new()
+                    .kind(KIND_GROUP_MESSAGE_9)
+                    .author(relay_keys.public_key())
+                    .pubkey(member.public_key())],
+                &Scope::Default,
+            )
+            .await
+            .unwrap();
+        assert_eq!(notes.len(), 1);
+        let note = notes.first().unwrap();
+        assert_eq!(note.content, "Welcome aboard!");
+        assert_eq!(Group::extract_group_h_tag(note), Some(TEST_GROUP_ID));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_posts_to_many_groups() {
+        use std::time::Duration;
+
+        const GROUPS: usize = 8;
+        const TASKS: usize = 32;
+        const POSTS: usize = 50;
+
+        async fn run(groups: Arc<Groups>, batches: Vec<Vec<Event>>) {
+            let tasks: Vec<_> = batches
+                .into_iter()
+                .map(|batch| {
+                    let groups = Arc::clone(&groups);
+                    tokio::spawn(async move {
+                        for event in batch {
+                            groups
+                                .handle_group_content(Box::new(event.clone()), &Scope::Default)
+                                .unwrap();
+                            let group = groups
+                                .find_group_from_event(&event, &Scope::Default)
+                                .unwrap();
+                            assert!(group
+                                .can_see_event(&Some(event.pubkey), groups.relay_keys(), &event)
+                                .unwrap());
+                            tokio::task::yield_now().await;
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        }
+
+        let relay_keys = Keys::generate();
+        let groups = Arc::new(create_test_groups_with_db(&relay_keys).await);
+        let mut admins = Vec::new();
+        for index in 0..GROUPS {
+            let admin = Keys::generate();
+            let tags = vec![Tag::custom(TagKind::h(), [format!("group_{index}")])];
+            let create = create_test_event(&admin, KIND_GROUP_CREATE_9007, tags).await;
+            groups
+                .handle_group_create(create, &Scope::Default)
+                .await
+                .unwrap();
+            admins.push(admin);
+        }
+
+        // Signed upfront so the tasks only contend on the groups
+        let batches: Vec<Vec<Event>> = (0..TASKS)
+            .map(|task| {
+                let index = task % GROUPS;
+                (0..POSTS)
+                    .map(|post| {
+                        EventBuilder::new(KIND_GROUP_MESSAGE_9, format!("{task}-{post}"))
+                            .tag(Tag::custom(TagKind::h(), [format!("group_{index}")]))
+                            .sign_with_keys(&admins[index])
+                            .unwrap()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        tokio::time::timeout(Duration::from_secs(60), run(groups.clone(), batches))
+            .await
+            .expect("concurrent posting deadlocked");
+        assert_eq!(groups.len(), GROUPS);
+    }
+
+    fn slug_tags(group_id: &str, slug: &str) -> Vec<Tag> {
+        vec![
+            Tag::custom(TagKind::h(), [group_id]),
+            Tag::custom(TagKind::custom("slug"), [slug]),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_slugs_are_unique_within_a_scope() {
+        let (groups, admin_keys, _, _, group_id, scope) = setup_test_groups().await;
+        let create = create_test_event(
+            &admin_keys,
+            KIND_GROUP_CREATE_9007,
+            vec![Tag::custom(TagKind::h(), ["other_group"])],
+        )
+        .await;
+        groups.handle_group_create(create, &scope).await.unwrap();
+        let set_slug = |group_id: &str, slug: &str| {
+            create_test_event(
+                &admin_keys,
+                KIND_GROUP_EDIT_METADATA_9002,
+                slug_tags(group_id, slug),
+            )
+        };
+
+        groups
+            .handle_edit_metadata(set_slug(&group_id, "team").await, &scope)
+            .unwrap();
+        assert_eq!(
+            groups.group_id_by_slug(&scope, "team"),
+            Some(group_id.clone())
+        );
+
+        let error = groups
+            .handle_edit_metadata(set_slug("other_group", "team").await, &scope)
+            .unwrap_err();
+        assert_eq!(error.to_string(), format!("invalid: {SLUG_IN_USE}"));
+        let other = groups.get_group(&scope, "other_group").unwrap();
+        assert_eq!(other.metadata.slug, None);
+        drop(other);
+
+        assert!(groups
+            .handle_edit_metadata(set_slug("other_group", "Team!").await, &scope)
+            .is_err());
+
+        // A group taking another slug frees its old one
+        groups
+            .handle_edit_metadata(set_slug(&group_id, "crew").await, &scope)
+            .unwrap();
+        groups
+            .handle_edit_metadata(set_slug("other_group", "team").await, &scope)
+            .unwrap();
+        assert_eq!(
+            groups.group_id_by_slug(&scope, "team").as_deref(),
+            Some("other_group")
+        );
+
+        // Groups created with a slug are checked too, within their own scope
+        let create_with_slug = |group_id: &'static str| {
+            let mut tags = slug_tags(group_id, "crew");
+            tags.push(Tag::custom(TagKind::Name, [group_id]));
+            create_test_event(&admin_keys, KIND_GROUP_CREATE_9007, tags)
+        };
+        let error = groups
+            .handle_group_create(create_with_slug("third_group").await, &scope)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), format!("invalid: {SLUG_IN_USE}"));
+        assert!(groups.get_group(&scope, "third_group").is_none());
+
+        let elsewhere = Scope::named("elsewhere").unwrap();
+        groups
+            .handle_group_create(create_with_slug("third_group").await, &elsewhere)
+            .await
+            .unwrap();
+        assert_eq!(
+            groups.group_id_by_slug(&elsewhere, "crew").as_deref(),
+            Some("third_group")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sandboxes_check_slugs_without_claiming_them() {
+        let (groups, admin_keys, _, _, group_id, scope) = setup_test_groups().await;
+        let set_slug = |group_id: &str, slug: &str| {
+            create_test_event(
+                &admin_keys,
+                KIND_GROUP_EDIT_METADATA_9002,
+                slug_tags(group_id, slug),
+            )
+        };
+        let create = create_test_event(
+            &admin_keys,
+            KIND_GROUP_CREATE_9007,
+            vec![Tag::custom(TagKind::h(), ["other_group"])],
+        )
+        .await;
+        groups.handle_group_create(create, &scope).await.unwrap();
+        groups
+            .handle_edit_metadata(set_slug(&group_id, "team").await, &scope)
+            .unwrap();
+
+        let sandbox = groups.sandbox(&scope, Some("other_group"));
+        let error = sandbox
+            .handle_edit_metadata(set_slug("other_group", "team").await, &scope)
+            .unwrap_err();
+        assert_eq!(error.to_string(), format!("invalid: {SLUG_IN_USE}"));
+
+        sandbox
+            .handle_edit_metadata(set_slug("other_group", "crew").await, &scope)
+            .unwrap();
+        assert_eq!(groups.group_id_by_slug(&scope, "crew"), None);
+        let sandbox = groups.sandbox(&scope, Some(&group_id));
+        sandbox
+            .handle_edit_metadata(set_slug(&group_id, "squad").await, &scope)
+            .unwrap();
+        assert_eq!(groups.group_id_by_slug(&scope, "team"), Some(group_id));
+    }
+
+    #[tokio::test]
+    async fn test_renames_and_slugs_survive_a_restart() {
+        use crate::utils::apply_store_commands;
+
+        let (_tmp_dir, database, relay_keys) = crate::test_utils::setup_test().await;
+        let load = || {
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+        };
+        let groups = load().await.unwrap().with_max_previous_names(2);
+        let admin = Keys::generate();
+        let scope = Scope::Default;
+        let h_tag = Tag::custom(TagKind::h(), [TEST_GROUP_ID]);
+
+        let create = create_test_event(
+            &admin,
+            KIND_GROUP_CREATE_9007,
+            vec![h_tag.clone(), Tag::custom(TagKind::Name, ["First"])],
+        )
+        .await;
+        groups.handle_group_create(create, &scope).await.unwrap();
+
+        let mut commands = Vec::new();
+        for name in ["Second", "Third", "Fourth"] {
+            let mut tags = slug_tags(TEST_GROUP_ID, "renamed");
+            tags.push(Tag::custom(TagKind::Name, [name]));
+            let event = create_test_event(&admin, KIND_GROUP_EDIT_METADATA_9002, tags).await;
+            commands = groups.handle_edit_metadata(event, &scope).unwrap();
+        }
+        // The last metadata event holds the whole state
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let metadata = groups
+            .get_group(&scope, TEST_GROUP_ID)
+            .unwrap()
+            .metadata
+            .clone();
+        let names: Vec<&str> = metadata
+            .previous_names
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["Second", "Third"]);
+
+        let reloaded = load().await.unwrap();
+        let group = reloaded.get_group(&scope, TEST_GROUP_ID).unwrap();
+        assert_eq!(group.metadata.name, "Fourth");
+        assert_eq!(group.metadata.previous_names, metadata.previous_names);
+        assert_eq!(group.metadata.slug.as_deref(), Some("renamed"));
+        drop(group);
+        assert_eq!(
+            reloaded.group_id_by_slug(&scope, "renamed").as_deref(),
+            Some(TEST_GROUP_ID)
+        );
+    }
 }
@@ -1,15 +1,60 @@
+use crate::archive::Archive;
+use crate::bot_tokens::BotTokens;
+use crate::committed::wait_for_commit;
+use crate::directory::GroupDirectory;
+use crate::dry_run;
+use crate::error::Rejection;
+use crate::group::{
+    KIND_DM_RELAYS_10050, KIND_GENERAL_EVENT_DELETION, KIND_GIFT_WRAP, KIND_GROUP_DIRECTORY_39100,
+    KIND_GROUP_LIST_SUGGESTION_39012, KIND_GROUP_SPAM_MUTE_39013,
+};
+use crate::group_hooks::{self, GroupEventHook};
+use crate::group_metrics::GroupMetrics;
+use crate::group_webhooks::GroupWebhooks;
 use crate::groups::{
-    Group, ADDRESSABLE_EVENT_KINDS, KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007,
-    KIND_GROUP_CREATE_INVITE_9009, KIND_GROUP_DELETE_9008, KIND_GROUP_DELETE_EVENT_9005,
-    KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_REMOVE_USER_9001, KIND_GROUP_SET_ROLES_9006,
-    KIND_GROUP_USER_JOIN_REQUEST_9021, KIND_GROUP_USER_LEAVE_REQUEST_9022, NON_GROUP_ALLOWED_KINDS,
+    BotCapability, Group, ADDRESSABLE_EVENT_KINDS, KIND_GROUP_ADD_USER_9000,
+    KIND_GROUP_ANNOTATION_9030, KIND_GROUP_BOT_TOKENS_39011, KIND_GROUP_CREATE_9007,
+    KIND_GROUP_CREATE_INVITE_9009, KIND_GROUP_DEFINE_ROLES_9003, KIND_GROUP_DELETE_9008,
+    KIND_GROUP_DELETE_EVENT_9005, KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_EMOJI_SET_30030,
+    KIND_GROUP_REMOVE_USER_9001, KIND_GROUP_SET_ROLES_9006, KIND_GROUP_USER_JOIN_REQUEST_9021,
+    KIND_GROUP_USER_LEAVE_REQUEST_9022, KIND_GROUP_WEBHOOKS_39010, NON_GROUP_ALLOWED_KINDS,
 };
+use crate::health;
+use crate::membership_state::MembershipCoalescer;
+use crate::payments::{PaidAction, PaymentStatus, PaymentVerifier};
+use crate::persistent_window::PersistentWindow;
+use crate::posting_policy::PostingPolicy;
+use crate::quotas::StorageQuotas;
+use crate::rejections::Rejections;
+use crate::relay_keys::RelayIdentity;
+use crate::replication::Replicator;
+use crate::spam_filter::{self, SpamFilter, Verdict};
 use crate::Groups;
+use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
 use relay_builder::{EventContext, EventProcessor, Result, StoreCommand};
+use std::fmt;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::debug;
+use tokio::sync::{RwLock, Semaphore};
+use tracing::{debug, warn};
+
+/// Batches of accepted events waiting to be committed before they're
+/// forwarded, beyond which new ones aren't forwarded
+const MAX_PENDING_OUTBOUND: usize = 1024;
+
+/// An extra visibility predicate, given the event, the scope it is read in
+/// and the authenticated pubkey
+type VisibilityFn = dyn Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync;
+
+/// A deployment-specific predicate ANDed with the group visibility rules
+#[derive(Clone)]
+pub struct ExtraVisibility(Arc<VisibilityFn>);
+
+impl fmt::Debug for ExtraVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExtraVisibility")
+    }
+}
 
 /// Groups event processor implementing NIP-29 (Relay-based Groups) functionality.
 ///
@@ -26,6 +71,26 @@ use tracing::debug;
 pub struct GroupsRelayProcessor {
     groups: Arc<Groups>,
     relay_pubkey: PublicKey,
+    posting_policy: Option<Arc<PostingPolicy>>,
+    replicator: Option<Arc<Replicator>>,
+    archive: Option<Arc<Archive>>,
+    group_metrics: Option<Arc<GroupMetrics>>,
+    hooks: Vec<Arc<dyn GroupEventHook>>,
+    group_creation_limit: Option<Arc<PersistentWindow>>,
+    group_webhooks: Option<Arc<GroupWebhooks>>,
+    bot_tokens: Option<Arc<BotTokens>>,
+    personal_kinds: Vec<Kind>,
+    extra_visibility: Option<ExtraVisibility>,
+    reject_ephemeral: bool,
+    membership_coalescer: Option<Arc<MembershipCoalescer>>,
+    rejections: Option<Arc<Rejections>>,
+    spam_filter: Option<Arc<SpamFilter>>,
+    payment_verifier: Option<Arc<dyn PaymentVerifier>>,
+    paid_actions: Vec<PaidAction>,
+    directory: Option<Arc<GroupDirectory>>,
+    private_messages: bool,
+    storage_quotas: Option<Arc<StorageQuotas>>,
+    outbound_permits: Arc<Semaphore>,
 }
 
 impl GroupsRelayProcessor {
@@ -38,9 +103,56 @@ impl GroupsRelayProcessor {
         Self {
             groups,
             relay_pubkey,
+            posting_policy: None,
+            replicator: None,
+            archive: None,
+            group_metrics: None,
+            hooks: Vec::new(),
+            group_creation_limit: None,
+            group_webhooks: None,
+            bot_tokens: None,
+            personal_kinds: Vec::new(),
+            extra_visibility: None,
+            reject_ephemeral: false,
+            membership_coalescer: None,
+            rejections: None,
+            spam_filter: None,
+            payment_verifier: None,
+            paid_actions: Vec::new(),
+            directory: None,
+            private_messages: false,
+            storage_quotas: None,
+            outbound_permits: Arc::new(Semaphore::new(MAX_PENDING_OUTBOUND)),
         }
     }
 
+    /// Enforce an operator-defined posting policy on group content events
+    pub fn with_posting_policy(mut self, posting_policy: Arc<PostingPolicy>) -> Self {
+        self.posting_policy = Some(posting_policy);
+        self
+    }
+
+    /// Accept these kinds, like profiles and relay lists, without group
+    /// checks and show them to everyone
+    pub fn with_personal_kinds(mut self, kinds: impl IntoIterator<Item = Kind>) -> Self {
+        self.personal_kinds = kinds.into_iter().collect();
+        self
+    }
+
+    /// Only show events the built-in group visibility allows and `visible`
+    /// also accepts, e.g. to hide authors on a blocklist.
+    ///
+    /// It applies to stored events returned for a REQ and to live events
+    /// delivered to open subscriptions. Returning false hides the event
+    /// silently; the client gets no error or notice.
+    pub fn with_extra_visibility<F>(mut self, visible: F) -> Self
+    where
+        F: Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + 'static,
+    {
+        self.extra_visibility = Some(ExtraVisibility(Arc::new(visible)));
+        self
+    }
+
     /// Get a reference to the groups state manager
     pub fn groups(&self) -> &Arc<Groups> {
         &self.groups
@@ -51,6 +163,482 @@ impl GroupsRelayProcessor {
         &self.relay_pubkey
     }
 
+    /// Push stored events to peer relays
+    pub fn with_replicator(mut self, replicator: Arc<Replicator>) -> Self {
+        self.replicator = Some(replicator);
+        self
+    }
+
+    /// Republish stored events to archive relays
+    pub fn with_archive(mut self, archive: Arc<Archive>) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Count stored events per group
+    pub fn with_group_metrics(mut self, group_metrics: Arc<GroupMetrics>) -> Self {
+        self.group_metrics = Some(group_metrics);
+        self
+    }
+
+    /// Register a hook notified of group lifecycle changes
+    pub fn with_hook(mut self, hook: Arc<dyn GroupEventHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Cap how many groups a pubkey can create per scope
+    pub fn with_group_creation_limit(mut self, limit: Arc<PersistentWindow>) -> Self {
+        self.group_creation_limit = Some(limit);
+        self
+    }
+
+    /// Deliver group events to the webhooks their admins registered
+    pub fn with_group_webhooks(mut self, group_webhooks: Arc<GroupWebhooks>) -> Self {
+        self.group_webhooks = Some(group_webhooks);
+        self
+    }
+
+    /// Support NIP-17 direct messages: kind 10050 DM relay lists are
+    /// accepted as personal events, and gift wraps are only shown to the
+    /// pubkeys in their `p` tags. REQs for gift wraps addressed to someone
+    /// else are rejected.
+    pub fn with_private_messages(mut self) -> Self {
+        self.private_messages = true;
+        if !self.personal_kinds.contains(&KIND_DM_RELAYS_10050) {
+            self.personal_kinds.push(KIND_DM_RELAYS_10050);
+        }
+        self
+    }
+
+    /// Reject ephemeral events (kinds 20000-29999) instead of relaying them
+    pub fn without_ephemeral_events(mut self) -> Self {
+        self.reject_ephemeral = true;
+        self
+    }
+
+    /// Regenerate the membership lists of a group once per burst of
+    /// member additions and removals
+    pub fn with_membership_coalescer(mut self, coalescer: Arc<MembershipCoalescer>) -> Self {
+        self.membership_coalescer = Some(coalescer);
+        self
+    }
+
+    /// Count and keep the reasons events were rejected
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Score group messages for spam, rejecting or shadow-muting them
+    pub fn with_spam_filter(mut self, spam_filter: Arc<SpamFilter>) -> Self {
+        self.spam_filter = Some(spam_filter);
+        self
+    }
+
+    /// Only accept the given actions from authors the verifier says paid
+    pub fn with_payment_verifier(
+        mut self,
+        verifier: Arc<dyn PaymentVerifier>,
+        actions: impl IntoIterator<Item = PaidAction>,
+    ) -> Self {
+        self.payment_verifier = Some(verifier);
+        self.paid_actions = actions.into_iter().collect();
+        self
+    }
+
+    /// Keep a relay-signed directory of each scope's public groups
+    pub fn with_directory(mut self, directory: Arc<GroupDirectory>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Reject events over the storage quota of their scope or group
+    pub fn with_storage_quotas(mut self, quotas: Arc<StorageQuotas>) -> Self {
+        self.storage_quotas = Some(quotas);
+        self
+    }
+
+    /// Runs an event through the processor without storing it or changing
+    /// anything: it's applied to a copy of its group, and the components
+    /// that act on accepted events, keep state of their own or call out,
+    /// like replication, webhooks, hooks, the spam filter, the creation
+    /// limit, bot tokens and the payment bridge, are left out.
+    pub async fn simulate(
+        &self,
+        event: Event,
+        context: &EventContext,
+    ) -> Result<Vec<StoreCommand>> {
+        self.check_quota(&event, &context.subdomain).await?;
+        let group_id = self.group_id_of(&event);
+        if let Some(group_id) = group_id {
+            self.groups
+                .get_or_load(&context.subdomain, group_id)
+                .await?;
+        }
+
+        let sandbox = Self {
+            groups: Arc::new(self.groups.sandbox(&context.subdomain, group_id)),
+            replicator: None,
+            archive: None,
+            group_metrics: None,
+            hooks: Vec::new(),
+            group_creation_limit: None,
+            group_webhooks: None,
+            membership_coalescer: None,
+            rejections: None,
+            spam_filter: None,
+            directory: None,
+            bot_tokens: None,
+            payment_verifier: None,
+            ..self.clone()
+        };
+        sandbox.process_event(event, context).await
+    }
+
+    /// Turns down an event that would go over the storage quota of its scope,
+    /// or of its group when the relay set one
+    async fn check_quota(&self, event: &Event, scope: &Scope) -> Result<()> {
+        let Some(quotas) = &self.storage_quotas else {
+            return Ok(());
+        };
+        if self.is_relay(&event.pubkey) {
+            return Ok(());
+        }
+
+        let mut group_max_events = None;
+        if let Some(group_id) = self.group_id_of(event) {
+            self.groups.get_or_load(scope, group_id).await?;
+            group_max_events = self
+                .groups
+                .find_group_from_event(event, scope)
+                .and_then(|group| group.metadata.max_events);
+        }
+        quotas.check(scope, event, group_max_events)
+    }
+
+    /// Turns down a paid action the author hasn't paid for, pointing to the
+    /// invoice when the verifier has one. The group id is checked first, so
+    /// the verifier is only asked about events that could be accepted.
+    async fn check_payment(&self, event: &Event, action: PaidAction, scope: &Scope) -> Result<()> {
+        let Some(verifier) = &self.payment_verifier else {
+            return Ok(());
+        };
+        if !self.paid_actions.contains(&action) || self.is_relay(&event.pubkey) {
+            return Ok(());
+        }
+
+        let Some(group_id) = Group::extract_group_id(event).filter(|id| !id.is_empty()) else {
+            return Err(relay_builder::Error::event_error(
+                "Group ID not found in event",
+                event.id,
+            ));
+        };
+        let exists = self.groups.get_group(scope, group_id).is_some();
+        match action {
+            PaidAction::CreateGroup if exists => {
+                return Err(relay_builder::Error::event_error(
+                    "Group already exists",
+                    event.id,
+                ));
+            }
+            PaidAction::CreateInvite if !exists => {
+                return Err(relay_builder::Error::event_error(
+                    "[CreateInvite] Group not found",
+                    event.id,
+                ));
+            }
+            _ => {}
+        }
+
+        match verifier.check_paid(&event.pubkey, &event.id, action).await {
+            PaymentStatus::Paid => Ok(()),
+            PaymentStatus::Unpaid {
+                invoice_url: Some(url),
+            } => Err(relay_builder::Error::payment_required(format!(
+                "{action} requires a fee, pay at {url}"
+            ))),
+            PaymentStatus::Unpaid { invoice_url: None } => Err(
+                relay_builder::Error::payment_required(format!("{action} requires a fee")),
+            ),
+        }
+    }
+
+    /// Judge the events of keys that presented a bot token by the token
+    pub fn with_bot_tokens(mut self, bot_tokens: Arc<BotTokens>) -> Self {
+        self.bot_tokens = Some(bot_tokens);
+        self
+    }
+
+    /// Applies an event of a key holding a bot token for its group, using
+    /// the token's capabilities instead of the key's membership. None when
+    /// the key holds no token for the group.
+    fn handle_bot_event(&self, event: &Event, scope: &Scope) -> Result<Option<Vec<StoreCommand>>> {
+        let Some(bot_tokens) = &self.bot_tokens else {
+            return Ok(None);
+        };
+        let Some(group_id) = self.group_id_of(event) else {
+            return Ok(None);
+        };
+        let Some(capabilities) = bot_tokens.capabilities(scope, group_id, &event.pubkey) else {
+            return Ok(None);
+        };
+
+        let is_content = !Group::is_group_management_kind(event.kind)
+            && !NON_GROUP_ALLOWED_KINDS.contains(&event.kind)
+            && ![
+                KIND_GENERAL_EVENT_DELETION,
+                KIND_GROUP_EMOJI_SET_30030,
+                KIND_GROUP_WEBHOOKS_39010,
+                KIND_GROUP_BOT_TOKENS_39011,
+                KIND_GROUP_LIST_SUGGESTION_39012,
+                KIND_GROUP_SPAM_MUTE_39013,
+                KIND_GROUP_DIRECTORY_39100,
+            ]
+            .contains(&event.kind);
+
+        let event = Box::new(event.clone());
+        let commands = match event.kind {
+            k if k == KIND_GROUP_DELETE_EVENT_9005
+                && capabilities.contains(&BotCapability::DeleteEvents) =>
+            {
+                self.groups.handle_bot_delete_event(event, scope)?
+            }
+            _ if is_content && capabilities.contains(&BotCapability::PostContent) => {
+                self.check_posting_policy(&event, scope)?;
+                self.groups.handle_bot_content(event, scope)?
+            }
+            kind => {
+                return Err(relay_builder::Error::restricted(format!(
+                    "bot token doesn't allow kind {kind} in this group"
+                )))
+            }
+        };
+        Ok(Some(commands))
+    }
+
+    /// Notifies the hooks of the changes an accepted event made to its group
+    async fn notify_hooks(&self, event: &Event, scope: &Scope, was_member: bool) {
+        let Some(group_id) = group_hooks::group_id(event) else {
+            return;
+        };
+        let changes = group_hooks::changes_for(&self.groups, event, scope, was_member);
+        if !changes.is_empty() {
+            group_hooks::dispatch(&self.hooks, scope, group_id, &changes).await;
+        }
+    }
+
+    /// Records accepted events, and hands them to replication, the archive
+    /// and group webhooks once relay_builder has committed them
+    fn replicate(&self, commands: &[StoreCommand], context: &EventContext) {
+        if let Some(group_metrics) = &self.group_metrics {
+            group_metrics.record_stored(commands);
+        }
+
+        if self.replicator.is_none() && self.archive.is_none() && self.group_webhooks.is_none() {
+            return;
+        }
+        // Shadow-muted messages and their markers stay on this relay
+        let is_muted = |event_id: &EventId| {
+            self.spam_filter
+                .as_ref()
+                .is_some_and(|spam_filter| spam_filter.muted_author(event_id).is_some())
+        };
+        let saved: Vec<StoreCommand> = commands
+            .iter()
+            .filter(|command| match command {
+                StoreCommand::SaveSignedEvent(event, _, _) => !is_muted(&event.id),
+                StoreCommand::SaveUnsignedEvent(event, _, _) => {
+                    event.kind != KIND_GROUP_SPAM_MUTE_39013
+                }
+                StoreCommand::DeleteEvents(..) => false,
+            })
+            .filter_map(|command| match command {
+                StoreCommand::SaveSignedEvent(event, scope, _) => Some(
+                    StoreCommand::SaveSignedEvent(event.clone(), scope.clone(), None),
+                ),
+                StoreCommand::SaveUnsignedEvent(event, scope, _) => Some(
+                    StoreCommand::SaveUnsignedEvent(event.clone(), scope.clone(), None),
+                ),
+                StoreCommand::DeleteEvents(..) => None,
+            })
+            .collect();
+        if saved.is_empty() {
+            return;
+        }
+        let Ok(permit) = self.outbound_permits.clone().try_acquire_owned() else {
+            warn!("Too many accepted events waiting to be stored, not forwarding these");
+            return;
+        };
+
+        let database = self.groups.database().clone();
+        let replicator = self.replicator.clone();
+        let archive = self.archive.clone();
+        let group_webhooks = self.group_webhooks.clone();
+        let authed_pubkey = context.authed_pubkey;
+        tokio::spawn(async move {
+            let committed = wait_for_commit(&database, &saved).await;
+            drop(permit);
+            if committed.is_empty() {
+                return;
+            }
+            if let Some(replicator) = &replicator {
+                replicator.replicate(&committed, authed_pubkey.as_ref());
+            }
+            if let Some(archive) = &archive {
+                archive.archive(&committed);
+            }
+            if let Some(group_webhooks) = &group_webhooks {
+                group_webhooks.dispatch(&committed);
+            }
+        });
+    }
+
+    fn coalesce_membership(
+        &self,
+        scope: &Scope,
+        group_id: Option<&str>,
+        commands: &mut Vec<StoreCommand>,
+    ) {
+        if let (Some(coalescer), Some(group_id)) = (&self.membership_coalescer, group_id) {
+            coalescer.coalesce(scope, group_id, commands);
+        }
+    }
+
+    /// Whether the pubkey is the relay, including keys it was rotated away from
+    fn is_relay(&self, pubkey: &PublicKey) -> bool {
+        *pubkey == self.relay_pubkey || self.groups.relay_keys().is_relay(pubkey)
+    }
+
+    /// The group visibility rules, before any extra predicate
+    fn builtin_visibility(&self, event: &Event, context: &EventContext) -> Result<bool> {
+        if event.kind == KIND_GIFT_WRAP {
+            return Ok(self.can_see_gift_wrap(event, &context.authed_pubkey));
+        }
+
+        if self.personal_kinds.contains(&event.kind) {
+            return Ok(true);
+        }
+
+        if health::is_readiness_record(event) && self.is_relay(&event.pubkey) {
+            return Ok(context
+                .authed_pubkey
+                .is_some_and(|pubkey| self.is_relay(&pubkey)));
+        }
+
+        // Webhook registrations, bot tokens and spam mutes are relay-internal
+        if event.kind == KIND_GROUP_WEBHOOKS_39010
+            || event.kind == KIND_GROUP_BOT_TOKENS_39011
+            || event.kind == KIND_GROUP_SPAM_MUTE_39013
+        {
+            return Ok(context
+                .authed_pubkey
+                .is_some_and(|pubkey| self.is_relay(&pubkey)));
+        }
+
+        // The directory only lists public groups
+        if event.kind == KIND_GROUP_DIRECTORY_39100 {
+            return Ok(true);
+        }
+
+        // Group list suggestions reveal the groups a user is in
+        if event.kind == KIND_GROUP_LIST_SUGGESTION_39012 {
+            return Ok(context.authed_pubkey.is_some_and(|pubkey| {
+                self.is_relay(&pubkey) || event.tags.public_keys().any(|member| *member == pubkey)
+            }));
+        }
+
+        // Check if this is a group event
+        if let Some(group_ref) = self.groups.find_group_from_event(event, &context.subdomain) {
+            // Group event - check access control using the group's can_see_event method
+            group_ref
+                .value()
+                .can_see_event(&context.authed_pubkey, self.groups.relay_keys(), event)
+        } else if self
+            .group_id_of(event)
+            .is_some_and(|group_id| !self.groups.is_known(&context.subdomain, group_id))
+        {
+            // Still loading, it may turn out to be a private group
+            Ok(false)
+        } else {
+            // Not a group event or unmanaged group - allow it through
+            Ok(true)
+        }
+    }
+
+    /// Runs the spam checks on a message to a managed group, returning
+    /// whether to shadow-mute it. Admins and the relay are exempt.
+    fn check_spam(&self, event: &Event, scope: &Scope) -> Result<bool> {
+        let Some(spam_filter) = &self.spam_filter else {
+            return Ok(false);
+        };
+        let Some(group) = self.groups.find_group_from_event(event, scope) else {
+            return Ok(false);
+        };
+        if self.is_relay(&event.pubkey) || group.is_admin(&event.pubkey) {
+            return Ok(false);
+        }
+
+        match spam_filter.check(scope, &group.id, &group.metadata, event) {
+            Verdict::Clean => Ok(false),
+            Verdict::Reject(reason) => Err(reason.rejection()),
+            Verdict::Mute(reason) => {
+                debug!(
+                    "Shadow-muting {} from {} in group {}: {:?}",
+                    event.id, event.pubkey, group.id, reason
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    /// Clears the spam scores of pubkeys an admin acted on, deleting the
+    /// markers of the messages that are no longer muted
+    fn pardon_spam(
+        &self,
+        scope: &Scope,
+        group_id: Option<&str>,
+        pubkeys: &[PublicKey],
+        deleted: &[EventId],
+        commands: &mut Vec<StoreCommand>,
+    ) {
+        if let (Some(spam_filter), Some(group_id)) = (&self.spam_filter, group_id) {
+            let pardoned = spam_filter.pardon(scope, group_id, pubkeys, deleted);
+            if !pardoned.is_empty() {
+                commands.push(spam_filter::unmute_markers(scope, group_id, &pardoned));
+            }
+        }
+    }
+
+    /// Shadow-muted messages are only shown to their author, the group
+    /// admins and the relay
+    fn can_see_muted(&self, event: &Event, context: &EventContext) -> bool {
+        let Some(author) = self
+            .spam_filter
+            .as_ref()
+            .and_then(|spam_filter| spam_filter.muted_author(&event.id))
+        else {
+            return true;
+        };
+        let Some(pubkey) = &context.authed_pubkey else {
+            return false;
+        };
+        *pubkey == author
+            || self.is_relay(pubkey)
+            || self
+                .groups
+                .find_group_from_event(event, &context.subdomain)
+                .is_some_and(|group| group.is_admin(pubkey))
+    }
+
+    /// Checks a content event against the posting policy, if one is configured
+    fn check_posting_policy(&self, event: &Event, scope: &Scope) -> Result<()> {
+        match &self.posting_policy {
+            Some(policy) if !self.is_relay(&event.pubkey) => policy.check(event, scope),
+            _ => Ok(()),
+        }
+    }
+
     /// Checks if a filter is querying group-related data
     fn is_group_query(&self, filter: &Filter) -> bool {
         filter
@@ -69,6 +657,56 @@ impl GroupsRelayProcessor {
             .is_some_and(|kinds| kinds.iter().any(|k| ADDRESSABLE_EVENT_KINDS.contains(k)))
     }
 
+    /// Checks if a filter can match gift wrap events: it asks for kind 1059,
+    /// or for any kind by recipient (`#p`), which is how wraps are fetched
+    fn is_gift_wrap_query(&self, filter: &Filter) -> bool {
+        match filter.kinds.as_ref().filter(|kinds| !kinds.is_empty()) {
+            Some(kinds) => kinds.contains(&KIND_GIFT_WRAP),
+            None => filter
+                .generic_tags
+                .contains_key(&SingleLetterTag::lowercase(Alphabet::P)),
+        }
+    }
+
+    /// Gift wraps are only visible to their recipients (`p` tags), their
+    /// author and the relay itself. With private messages, NIP-59 wraps are
+    /// signed by a throwaway key, so only recipients and the relay see them.
+    fn can_see_gift_wrap(&self, event: &Event, authed_pubkey: &Option<PublicKey>) -> bool {
+        let Some(pubkey) = authed_pubkey else {
+            return false;
+        };
+
+        self.is_relay(pubkey)
+            || (!self.private_messages && *pubkey == event.pubkey)
+            || event.tags.public_keys().any(|p| p == pubkey)
+    }
+
+    /// Whether a gift wrap filter asks for wraps addressed to someone other
+    /// than the authenticated pubkey
+    fn asks_for_others_gift_wraps(&self, filter: &Filter, authed_pubkey: &PublicKey) -> bool {
+        if self.is_relay(authed_pubkey) {
+            return false;
+        }
+        filter
+            .generic_tags
+            .get(&SingleLetterTag::lowercase(Alphabet::P))
+            .is_some_and(|recipients| {
+                recipients
+                    .iter()
+                    .any(|recipient| *recipient != authed_pubkey.to_hex())
+            })
+    }
+
+    /// Group a managed group event belongs to: its `h` tag, or the `d` tag of
+    /// relay-generated state events
+    fn group_id_of<'a>(&self, event: &'a Event) -> Option<&'a str> {
+        if ADDRESSABLE_EVENT_KINDS.contains(&event.kind) {
+            event.tags.identifier()
+        } else {
+            Group::extract_group_h_tag(event)
+        }
+    }
+
     /// Gets all group tags from a filter
     fn get_group_tags<'a>(&self, filter: &'a Filter) -> impl Iterator<Item = String> + 'a {
         filter
@@ -78,84 +716,47 @@ impl GroupsRelayProcessor {
             .flat_map(|(_, tag_set)| tag_set.iter())
             .cloned()
     }
-}
 
-impl EventProcessor for GroupsRelayProcessor {
-    fn verify_filters(
+    /// Applies an event, returning the commands to store. `handle_event`
+    /// wraps it to record rejections.
+    async fn process_event(
         &self,
-        filters: &[Filter],
-        _custom_state: Arc<RwLock<()>>,
+        event: Event,
         context: &EventContext,
-    ) -> Result<()> {
-        // For groups relay, we need to verify access to group queries
-        for filter in filters {
-            // Check if this filter queries group-related data
-            if self.is_group_query(filter) {
-                // Get all group tags from the filter
-                let group_tags: Vec<String> = self.get_group_tags(filter).collect();
-
-                // Verify access to each group mentioned in the filter
-                for group_tag in group_tags {
-                    if let Some(group_ref) = self.groups.get_group(&context.subdomain, &group_tag) {
-                        // Managed group - check if the user can read from this group
-                        let group = group_ref.value();
-                        if group.metadata.private {
-                            // Private group - user must be a member or relay admin
-                            if let Some(pubkey) = &context.authed_pubkey {
-                                // Relay admin has access to all groups
-                                if pubkey != &self.relay_pubkey && !group.is_member(pubkey) {
-                                    return Err(relay_builder::Error::restricted(
-                                        "Access denied to private group".to_string(),
-                                    ));
-                                }
-                            } else {
-                                return Err(relay_builder::Error::auth_required(
-                                    "Authentication required to access private groups".to_string(),
-                                ));
-                            }
-                        }
-                        // Public groups allow everyone to read
-                    }
-                    // Unmanaged groups are allowed (everyone can read from them)
-                }
-            }
+    ) -> Result<Vec<StoreCommand>> {
+        let subdomain = context.subdomain.clone();
 
-            // For addressable events, verify the user can access the groups they reference
-            if self.is_addressable_query(filter) {
-                // Addressable events might reference groups in their identifiers
-                // For now, we'll allow these queries and rely on visibility filtering
-                // during event delivery to handle access control
-            }
+        // Already stored and forwarded, it just went around a mirror loop
+        if self.replicator.as_ref().is_some_and(|replicator| {
+            replicator.is_mirrored_back(&event, context.authed_pubkey.as_ref())
+        }) {
+            return Ok(vec![]);
         }
 
-        Ok(())
-    }
+        // Ephemeral events take the same membership checks as stored ones.
+        // The store drops them, so they only reach live subscriptions.
+        if event.kind.is_ephemeral() && self.reject_ephemeral {
+            return Err(relay_builder::Error::restricted(
+                "ephemeral events are not accepted",
+            ));
+        }
 
-    fn can_see_event(
-        &self,
-        event: &Event,
-        _custom_state: Arc<RwLock<()>>,
-        context: &EventContext,
-    ) -> Result<bool> {
-        // Check if this is a group event
-        if let Some(group_ref) = self.groups.find_group_from_event(event, &context.subdomain) {
-            // Group event - check access control using the group's can_see_event method
-            group_ref
-                .value()
-                .can_see_event(&context.authed_pubkey, &context.relay_pubkey, event)
-        } else {
-            // Not a group event or unmanaged group - allow it through
-            Ok(true)
+        // Personal events belong to their author, whatever group they mention
+        if self.personal_kinds.contains(&event.kind) {
+            debug!(target: "groups_relay_logic", "Processing personal event: kind={}, id={}", event.kind, event.id);
+            let commands = vec![StoreCommand::SaveSignedEvent(
+                Box::new(event),
+                (*subdomain).clone(),
+                None,
+            )];
+            self.replicate(&commands, context);
+            return Ok(commands);
         }
-    }
 
-    async fn handle_event(
-        &self,
-        event: Event,
-        _custom_state: Arc<RwLock<()>>,
-        context: &EventContext,
-    ) -> Result<Vec<StoreCommand>> {
-        let subdomain = context.subdomain.clone();
+        // Make sure the group is in memory before deciding whether it's managed
+        if let Some(group_id) = self.group_id_of(&event) {
+            self.groups.get_or_load(&subdomain, group_id).await?;
+        }
 
         // Allow events through for unmanaged groups (groups not in relay state)
         // Per NIP-29: In unmanaged groups, everyone is considered a member
@@ -168,20 +769,60 @@ impl EventProcessor for GroupsRelayProcessor {
                 .is_none()
         {
             debug!(target: "groups_relay_logic", "Processing unmanaged group event: kind={}, id={}", event.kind, event.id);
-            return Ok(vec![StoreCommand::SaveSignedEvent(
+            self.check_posting_policy(&event, &subdomain)?;
+            let commands = vec![StoreCommand::SaveSignedEvent(
                 Box::new(event),
                 (*subdomain).clone(),
                 None,
-            )]);
+            )];
+            self.replicate(&commands, context);
+            return Ok(commands);
         }
 
-        let events_to_save = match event.kind {
+        // Dry runs are answered with the would-be changes and never stored
+        if dry_run::is_dry_run(&event) {
+            return Err(relay_builder::Error::failed(dry_run::describe(
+                &self.groups,
+                &event,
+                &subdomain,
+            )));
+        }
+
+        if let Some(commands) = self.handle_bot_event(&event, &subdomain)? {
+            self.replicate(&commands, context);
+            return Ok(commands);
+        }
+
+        // Keep what the hooks need to work out the group changes afterwards
+        let hook_event = (!self.hooks.is_empty()).then(|| {
+            let was_member = group_hooks::was_member(&self.groups, &event, &subdomain);
+            (event.clone(), was_member)
+        });
+
+        let kind = event.kind;
+        let mut events_to_save = match event.kind {
             k if k == KIND_GROUP_CREATE_9007 => {
                 debug!(target: "groups_relay_logic", "Processing group create event: id={}", event.id);
+                let creator = event.pubkey.to_hex();
+                let limit = self
+                    .group_creation_limit
+                    .as_ref()
+                    .filter(|_| !self.is_relay(&event.pubkey));
+                if let Some(retry_after) = limit.and_then(|l| l.check(&subdomain, &creator)) {
+                    return Err(relay_builder::Error::rate_limited(format!(
+                        "group creation limit reached, try again in {}s",
+                        retry_after.as_secs()
+                    )));
+                }
+                self.check_payment(&event, PaidAction::CreateGroup, &subdomain)
+                    .await?;
                 let commands = self
                     .groups
                     .handle_group_create(Box::new(event), &subdomain)
                     .await?;
+                if let Some(limit) = limit {
+                    limit.record(&subdomain, &creator);
+                }
                 debug!(target: "groups_relay_logic", "Group create generated {} commands", commands.len());
                 for cmd in &commands {
                     match cmd {
@@ -222,15 +863,40 @@ impl EventProcessor for GroupsRelayProcessor {
                 self.groups.handle_set_roles(Box::new(event), &subdomain)?
             }
 
+            k if k == KIND_GROUP_DEFINE_ROLES_9003 => {
+                debug!(target: "groups_relay_logic", "Processing group define roles event: id={}", event.id);
+                self.groups
+                    .handle_define_roles(Box::new(event), &subdomain)?
+            }
+
+            k if k == KIND_GROUP_ANNOTATION_9030 => {
+                debug!(target: "groups_relay_logic", "Processing group annotation event: id={}", event.id);
+                self.groups.handle_annotation(Box::new(event), &subdomain)?
+            }
+
             k if k == KIND_GROUP_ADD_USER_9000 => {
                 debug!(target: "groups_relay_logic", "Processing group add user event: id={}", event.id);
-                self.groups.handle_put_user(Box::new(event), &subdomain)?
+                let group_id = Group::extract_group_h_tag(&event).map(str::to_string);
+                // Muting doesn't pardon the muted pubkeys' spam
+                let added: Vec<PublicKey> = if event.tags.find(TagKind::custom("mute")).is_some() {
+                    Vec::new()
+                } else {
+                    event.tags.public_keys().copied().collect()
+                };
+                let mut commands = self.groups.handle_put_user(Box::new(event), &subdomain)?;
+                self.coalesce_membership(&subdomain, group_id.as_deref(), &mut commands);
+                self.pardon_spam(&subdomain, group_id.as_deref(), &added, &[], &mut commands);
+                commands
             }
 
             k if k == KIND_GROUP_REMOVE_USER_9001 => {
                 debug!(target: "groups_relay_logic", "Processing group remove user event: id={}", event.id);
-                self.groups
-                    .handle_remove_user(Box::new(event), &subdomain)?
+                let group_id = Group::extract_group_h_tag(&event).map(str::to_string);
+                let mut commands = self
+                    .groups
+                    .handle_remove_user(Box::new(event), &subdomain)?;
+                self.coalesce_membership(&subdomain, group_id.as_deref(), &mut commands);
+                commands
             }
 
             k if k == KIND_GROUP_DELETE_9008 => {
@@ -241,22 +907,95 @@ impl EventProcessor for GroupsRelayProcessor {
 
             k if k == KIND_GROUP_DELETE_EVENT_9005 => {
                 debug!(target: "groups_relay_logic", "Processing group content event deletion: id={}", event.id);
-                self.groups
-                    .handle_delete_event(Box::new(event), &subdomain)?
+                let group_id = Group::extract_group_h_tag(&event).map(str::to_string);
+                let deleted: Vec<EventId> = event.tags.event_ids().copied().collect();
+                let mut commands = self
+                    .groups
+                    .handle_delete_event(Box::new(event), &subdomain)?;
+                self.pardon_spam(
+                    &subdomain,
+                    group_id.as_deref(),
+                    &[],
+                    &deleted,
+                    &mut commands,
+                );
+                commands
             }
 
             k if k == KIND_GROUP_CREATE_INVITE_9009 => {
                 debug!(target: "groups_relay_logic", "Processing group create invite event: id={}", event.id);
+                self.check_payment(&event, PaidAction::CreateInvite, &subdomain)
+                    .await?;
                 self.groups
                     .handle_create_invite(Box::new(event), &subdomain)?
             }
 
+            k if k == KIND_GROUP_EMOJI_SET_30030 && event.tags.find(TagKind::h()).is_some() => {
+                debug!(target: "groups_relay_logic", "Processing group emoji set event: id={}", event.id);
+                self.groups.handle_emoji_set(Box::new(event), &subdomain)?
+            }
+
+            k if k == KIND_GROUP_WEBHOOKS_39010 => {
+                return Err(relay_builder::Error::restricted(
+                    "Webhook registrations are managed through the HTTP API",
+                ));
+            }
+
+            k if k == KIND_GROUP_BOT_TOKENS_39011 => {
+                return Err(relay_builder::Error::restricted(
+                    "Bot tokens are managed through the HTTP API",
+                ));
+            }
+
+            k if k == KIND_GROUP_LIST_SUGGESTION_39012 => {
+                return Err(relay_builder::Error::restricted(
+                    "Group list suggestions are generated by the relay",
+                ));
+            }
+
+            k if k == KIND_GROUP_SPAM_MUTE_39013 => {
+                return Err(relay_builder::Error::restricted(
+                    "Spam mutes are managed by the relay",
+                ));
+            }
+
+            k if k == KIND_GROUP_DIRECTORY_39100 => {
+                return Err(relay_builder::Error::restricted(
+                    "The group directory is generated by the relay",
+                ));
+            }
+
+            k if k == KIND_GENERAL_EVENT_DELETION => {
+                debug!(target: "groups_relay_logic", "Processing event deletion: id={}", event.id);
+                self.groups
+                    .handle_event_deletion(Box::new(event), &subdomain)
+                    .await?
+            }
+
             k if !NON_GROUP_ALLOWED_KINDS.contains(&k)
                 && event.tags.find(TagKind::h()).is_some() =>
             {
                 debug!(target: "groups_relay_logic", "Processing group content event: kind={}, id={}", event.kind, event.id);
-                self.groups
-                    .handle_group_content(Box::new(event), &subdomain)?
+                self.check_posting_policy(&event, &subdomain)?;
+                let mute = self.check_spam(&event, &subdomain)?;
+                let (event_id, author) = (event.id, event.pubkey);
+                let group_id = Group::extract_group_h_tag(&event).map(str::to_string);
+                let mut commands = self
+                    .groups
+                    .handle_group_content(Box::new(event), &subdomain)?;
+                if let (true, Some(spam_filter), Some(group_id)) =
+                    (mute, &self.spam_filter, group_id)
+                {
+                    spam_filter.mute(&subdomain, &group_id, event_id, author);
+                    commands.push(spam_filter::mute_marker(
+                        &subdomain,
+                        &group_id,
+                        event_id,
+                        author,
+                        self.relay_pubkey,
+                    ));
+                }
+                commands
             }
 
             _ => {
@@ -269,16 +1008,148 @@ impl EventProcessor for GroupsRelayProcessor {
             }
         };
 
+        if let Some(directory) = &self.directory {
+            directory.update(kind, &subdomain, &mut events_to_save);
+        }
+        if let Some(coalescer) = &self.membership_coalescer {
+            events_to_save.extend(coalescer.take_due(&subdomain));
+        }
+
         debug!(target: "groups_relay_logic", "Returning {} store commands from handle_event", events_to_save.len());
+        self.replicate(&events_to_save, context);
+        if let Some((event, was_member)) = hook_event {
+            self.notify_hooks(&event, &subdomain, was_member).await;
+        }
         Ok(events_to_save)
     }
 }
 
+impl EventProcessor for GroupsRelayProcessor {
+    fn verify_filters(
+        &self,
+        filters: &[Filter],
+        _custom_state: Arc<RwLock<()>>,
+        context: &EventContext,
+    ) -> Result<()> {
+        // For groups relay, we need to verify access to group queries
+        for filter in filters {
+            // Gift wraps are addressed to specific users, so they need auth
+            if self.is_gift_wrap_query(filter) {
+                let Some(pubkey) = &context.authed_pubkey else {
+                    return Err(relay_builder::Error::auth_required(
+                        "Authentication required to access gift wraps".to_string(),
+                    ));
+                };
+                // Kindless recipient filters also match mentions, which
+                // can_see_event already strips of others' wraps
+                let asks_for_wraps = filter
+                    .kinds
+                    .as_ref()
+                    .is_some_and(|kinds| kinds.contains(&KIND_GIFT_WRAP));
+                if self.private_messages
+                    && asks_for_wraps
+                    && self.asks_for_others_gift_wraps(filter, pubkey)
+                {
+                    return Err(relay_builder::Error::restricted(
+                        "Gift wraps can only be fetched by their recipient".to_string(),
+                    ));
+                }
+            }
+
+            // Check if this filter queries group-related data
+            if self.is_group_query(filter) {
+                // Get all group tags from the filter
+                let group_tags: Vec<String> = self.get_group_tags(filter).collect();
+
+                // Verify access to each group mentioned in the filter
+                for group_tag in group_tags {
+                    if let Some(group_ref) = self.groups.get_group(&context.subdomain, &group_tag) {
+                        // Managed group - check if the user can read from this group
+                        let group = group_ref.value();
+                        if group.metadata.private {
+                            // Private group - user must be a member or relay admin
+                            if let Some(pubkey) = &context.authed_pubkey {
+                                // Relay admin has access to all groups
+                                if !self.is_relay(pubkey) && !group.is_member(pubkey) {
+                                    return Err(relay_builder::Error::restricted(
+                                        "Access denied to private group".to_string(),
+                                    ));
+                                }
+                            } else {
+                                return Err(relay_builder::Error::auth_required(
+                                    "Authentication required to access private groups".to_string(),
+                                ));
+                            }
+                        }
+                        // Public groups allow everyone to read
+                    }
+                    // Unmanaged groups are allowed (everyone can read from them)
+                }
+            }
+
+            // For addressable events, verify the user can access the groups they reference
+            if self.is_addressable_query(filter) {
+                // Addressable events might reference groups in their identifiers
+                // For now, we'll allow these queries and rely on visibility filtering
+                // during event delivery to handle access control
+            }
+        }
+
+        Ok(())
+    }
+
+    fn can_see_event(
+        &self,
+        event: &Event,
+        _custom_state: Arc<RwLock<()>>,
+        context: &EventContext,
+    ) -> Result<bool> {
+        // relay_builder runs this for both stored and live events
+        if !self.builtin_visibility(event, context)? || !self.can_see_muted(event, context) {
+            return Ok(false);
+        }
+
+        Ok(match &self.extra_visibility {
+            Some(ExtraVisibility(visible)) => {
+                visible(event, &context.subdomain, context.authed_pubkey.as_ref())
+            }
+            None => true,
+        })
+    }
+
+    async fn handle_event(
+        &self,
+        event: Event,
+        _custom_state: Arc<RwLock<()>>,
+        context: &EventContext,
+    ) -> Result<Vec<StoreCommand>> {
+        let (event_id, kind, pubkey) = (event.id, event.kind, event.pubkey);
+        let result = match self.check_quota(&event, &context.subdomain).await {
+            Ok(()) => self.process_event(event, context).await,
+            Err(error) => Err(error),
+        };
+
+        match &result {
+            Ok(commands) => {
+                if let Some(quotas) = &self.storage_quotas {
+                    quotas.record(commands).await;
+                }
+            }
+            Err(error) => {
+                if let Some(rejections) = &self.rejections {
+                    rejections.record(event_id, kind, pubkey, error);
+                }
+            }
+        }
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::test_utils::{create_test_event, create_test_keys, setup_test};
-    use nostr_lmdb::Scope;
+    use crate::utils::apply_store_commands;
 
     fn empty_state() -> Arc<RwLock<()>> {
         Arc::new(RwLock::new(()))
@@ -386,4 +1257,669 @@ mod tests {
             _ => panic!("Expected SaveSignedEvent command"),
         }
     }
+
+    #[tokio::test]
+    async fn test_extra_visibility_hides_blocked_author() {
+        let (_tmp_dir, database, admin_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                admin_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+
+        let (_admin_keys, member_keys, blocked_keys) = create_test_keys().await;
+        let blocked = blocked_keys.public_key();
+        let processor = GroupsRelayProcessor::new(groups, admin_keys.public_key())
+            .with_extra_visibility(move |event, _scope, _authed| event.pubkey != blocked);
+        let context = EventContext {
+            authed_pubkey: Some(member_keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: admin_keys.public_key(),
+        };
+
+        // The same check answers REQs for stored events and live delivery
+        let visible = create_test_event(&member_keys, 1, vec![]).await;
+        let hidden = create_test_event(&blocked_keys, 1, vec![]).await;
+        assert!(processor
+            .can_see_event(&visible, empty_state(), &context)
+            .unwrap());
+        assert!(!processor
+            .can_see_event(&hidden, empty_state(), &context)
+            .unwrap());
+
+        // Group events the group rules allow are filtered too, without an error
+        let group_event = create_test_event(
+            &blocked_keys,
+            11,
+            vec![Tag::custom(TagKind::h(), ["unmanaged_group"])],
+        )
+        .await;
+        assert!(!processor
+            .can_see_event(&group_event, empty_state(), &context)
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_readiness_record_is_only_visible_to_the_relay() {
+        let (_tmp_dir, database, admin_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                admin_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+
+        let processor = GroupsRelayProcessor::new(groups, admin_keys.public_key());
+        let (_admin_keys, member_keys, _non_member_keys) = create_test_keys().await;
+        let record = EventBuilder::new(Kind::Custom(30078), "")
+            .tag(Tag::identifier(health::READINESS_RECORD))
+            .sign_with_keys(&admin_keys)
+            .unwrap();
+
+        let relay_pubkey = admin_keys.public_key();
+        for (authed_pubkey, visible) in [
+            (Some(relay_pubkey), true),
+            (Some(member_keys.public_key()), false),
+            (None, false),
+        ] {
+            let context = EventContext {
+                authed_pubkey,
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey,
+            };
+            assert_eq!(
+                processor
+                    .can_see_event(&record, empty_state(), &context)
+                    .unwrap(),
+                visible
+            );
+        }
+    }
+
+    fn gift_wrap_context(
+        authed_pubkey: Option<PublicKey>,
+        relay_pubkey: PublicKey,
+    ) -> EventContext {
+        EventContext {
+            authed_pubkey,
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gift_wrap_visible_only_to_recipients_and_author() {
+        let (_tmp_dir, database, admin_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                admin_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+
+        let processor = GroupsRelayProcessor::new(groups, admin_keys.public_key());
+        let (_admin_keys, author_keys, stranger_keys) = create_test_keys().await;
+        let first_recipient = Keys::generate();
+        let second_recipient = Keys::generate();
+
+        let event = create_test_event(
+            &author_keys,
+            1059,
+            vec![
+                Tag::public_key(first_recipient.public_key()),
+                Tag::public_key(second_recipient.public_key()),
+            ],
+        )
+        .await;
+
+        let relay_pubkey = admin_keys.public_key();
+        for keys in [
+            &first_recipient,
+            &second_recipient,
+            &author_keys,
+            &admin_keys,
+        ] {
+            let context = gift_wrap_context(Some(keys.public_key()), relay_pubkey);
+            assert!(processor
+                .can_see_event(&event, empty_state(), &context)
+                .unwrap());
+        }
+
+        let stranger_context = gift_wrap_context(Some(stranger_keys.public_key()), relay_pubkey);
+        assert!(!processor
+            .can_see_event(&event, empty_state(), &stranger_context)
+            .unwrap());
+
+        let anonymous_context = gift_wrap_context(None, relay_pubkey);
+        assert!(!processor
+            .can_see_event(&event, empty_state(), &anonymous_context)
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_gift_wrap_query_requires_auth() {
+        let (_tmp_dir, database, admin_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                admin_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+
+        let processor = GroupsRelayProcessor::new(groups, admin_keys.public_key());
+        let recipient = Keys::generate();
+        let filter = Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkey(recipient.public_key());
+
+        let anonymous_context = gift_wrap_context(None, admin_keys.public_key());
+        let result = processor.verify_filters(&[filter.clone()], empty_state(), &anonymous_context);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Authentication required to access gift wraps"));
+
+        let recipient_context =
+            gift_wrap_context(Some(recipient.public_key()), admin_keys.public_key());
+        assert!(processor
+            .verify_filters(&[filter.clone()], empty_state(), &recipient_context)
+            .is_ok());
+
+        // The relay key can query any gift wrap for moderation
+        let relay_context =
+            gift_wrap_context(Some(admin_keys.public_key()), admin_keys.public_key());
+        assert!(processor
+            .verify_filters(&[filter], empty_state(), &relay_context)
+            .is_ok());
+
+        // Without kinds a recipient filter matches gift wraps too
+        let any_kind = Filter::new().pubkey(recipient.public_key());
+        assert!(processor
+            .verify_filters(&[any_kind], empty_state(), &anonymous_context)
+            .unwrap_err()
+            .to_string()
+            .contains("Authentication required to access gift wraps"));
+
+        // Other kinds by recipient, and kindless filters without one, stay open
+        let notes = Filter::new()
+            .kind(Kind::TextNote)
+            .pubkey(recipient.public_key());
+        let by_author = Filter::new().author(recipient.public_key());
+        assert!(processor
+            .verify_filters(&[notes, by_author], empty_state(), &anonymous_context)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_private_messages_only_reach_their_recipient() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor =
+            GroupsRelayProcessor::new(groups, relay_keys.public_key()).with_private_messages();
+        let relay_pubkey = relay_keys.public_key();
+        let (alice, bob, carol) = create_test_keys().await;
+
+        // Alice's DM relays are published without a group and readable by anyone
+        let dm_relays = create_test_event(
+            &alice,
+            10050,
+            vec![Tag::custom(TagKind::Relay, ["wss://test.relay.com"])],
+        )
+        .await;
+        let commands = processor
+            .handle_event(
+                dm_relays.clone(),
+                empty_state(),
+                &gift_wrap_context(Some(alice.public_key()), relay_pubkey),
+            )
+            .await
+            .unwrap();
+        assert_eq!(commands.len(), 1);
+        assert!(processor
+            .can_see_event(
+                &dm_relays,
+                empty_state(),
+                &gift_wrap_context(None, relay_pubkey)
+            )
+            .unwrap());
+
+        // A wrap Alice sends Bob is signed by a throwaway key
+        let throwaway = Keys::generate();
+        let wrap =
+            create_test_event(&throwaway, 1059, vec![Tag::public_key(bob.public_key())]).await;
+        assert!(processor
+            .handle_event(
+                wrap.clone(),
+                empty_state(),
+                &gift_wrap_context(Some(alice.public_key()), relay_pubkey),
+            )
+            .await
+            .is_ok());
+
+        let sees = |keys: &Keys| {
+            processor
+                .can_see_event(
+                    &wrap,
+                    empty_state(),
+                    &gift_wrap_context(Some(keys.public_key()), relay_pubkey),
+                )
+                .unwrap()
+        };
+        assert!(sees(&bob));
+        assert!(!sees(&carol));
+        assert!(!sees(&throwaway));
+
+        let inbox = Filter::new().kind(Kind::GiftWrap).pubkey(bob.public_key());
+        assert!(processor
+            .verify_filters(
+                &[inbox.clone()],
+                empty_state(),
+                &gift_wrap_context(Some(bob.public_key()), relay_pubkey),
+            )
+            .is_ok());
+        assert!(processor
+            .verify_filters(
+                &[inbox.clone()],
+                empty_state(),
+                &gift_wrap_context(Some(carol.public_key()), relay_pubkey),
+            )
+            .unwrap_err()
+            .to_string()
+            .contains("only be fetched by their recipient"));
+        assert!(processor
+            .verify_filters(
+                &[inbox],
+                empty_state(),
+                &gift_wrap_context(None, relay_pubkey)
+            )
+            .unwrap_err()
+            .to_string()
+            .contains("Authentication required"));
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingHook {
+        calls: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl GroupEventHook for RecordingHook {
+        async fn on_group_created(&self, _scope: &Scope, group_id: &str, _creator: &PublicKey) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("created {group_id}"));
+        }
+
+        async fn on_member_added(&self, _scope: &Scope, group_id: &str, member: &PublicKey) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("added {group_id} {member}"));
+        }
+
+        async fn on_member_removed(&self, _scope: &Scope, group_id: &str, member: &PublicKey) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("removed {group_id} {member}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_notified_of_membership_changes() {
+        let (_tmp_dir, database, admin_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                admin_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+
+        let hook = Arc::new(RecordingHook::default());
+        let processor =
+            GroupsRelayProcessor::new(groups, admin_keys.public_key()).with_hook(hook.clone());
+        let (_, creator_keys, member_keys) = create_test_keys().await;
+        let context = EventContext {
+            authed_pubkey: Some(creator_keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: admin_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["hooked"]);
+
+        let create = create_test_event(&creator_keys, 9007, vec![h_tag.clone()]).await;
+        processor
+            .handle_event(create, empty_state(), &context)
+            .await
+            .unwrap();
+
+        let add = create_test_event(
+            &creator_keys,
+            9000,
+            vec![h_tag.clone(), Tag::public_key(member_keys.public_key())],
+        )
+        .await;
+        processor
+            .handle_event(add, empty_state(), &context)
+            .await
+            .unwrap();
+
+        let remove = create_test_event(
+            &creator_keys,
+            9001,
+            vec![h_tag, Tag::public_key(member_keys.public_key())],
+        )
+        .await;
+        processor
+            .handle_event(remove, empty_state(), &context)
+            .await
+            .unwrap();
+
+        let member = member_keys.public_key();
+        assert_eq!(
+            *hook.calls.lock().unwrap(),
+            vec![
+                "created hooked".to_string(),
+                format!("added hooked {member}"),
+                format!("removed hooked {member}"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_served_before_full_load_completes() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let (_, creator_keys, outsider_keys) = create_test_keys().await;
+        let scope = Scope::Default;
+
+        // Seed several hundred groups through a fully loaded relay
+        let seeding = GroupsRelayProcessor::new(
+            Arc::new(Groups::new(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )),
+            relay_keys.public_key(),
+        );
+        seeding.groups().load_all().await.unwrap();
+        let context = EventContext {
+            authed_pubkey: Some(creator_keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        for i in 0..300 {
+            let create = create_test_event(
+                &creator_keys,
+                9007,
+                vec![Tag::custom(TagKind::h(), [format!("group_{i}")])],
+            )
+            .await;
+            for command in seeding
+                .handle_event(create, empty_state(), &context)
+                .await
+                .unwrap()
+            {
+                match command {
+                    StoreCommand::SaveSignedEvent(event, scope, _) => {
+                        database.save_event(&event, &scope).await.unwrap()
+                    }
+                    StoreCommand::SaveUnsignedEvent(event, scope, _) => database
+                        .save_event(&event.sign_with_keys(&relay_keys).unwrap(), &scope)
+                        .await
+                        .unwrap(),
+                    _ => {}
+                }
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // A restarted relay that hasn't run its full load yet
+        let groups = Arc::new(Groups::new(
+            database.clone(),
+            relay_keys.public_key(),
+            "wss://test.relay.com".to_string(),
+        ));
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let message = create_test_event(
+            &creator_keys,
+            9,
+            vec![Tag::custom(TagKind::h(), ["group_150"])],
+        )
+        .await;
+        let filters =
+            [Filter::new().custom_tag(SingleLetterTag::lowercase(Alphabet::H), "group_150")];
+        let outsider_context = EventContext {
+            authed_pubkey: Some(outsider_keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+
+        // Not loaded yet, so its private events stay hidden
+        assert!(!processor
+            .can_see_event(&message, empty_state(), &outsider_context)
+            .unwrap());
+
+        // What the loading middleware does for a REQ naming the group
+        assert!(groups
+            .get_or_load(&scope, "group_150")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(!groups.is_loaded());
+        assert_eq!(groups.list_all_groups().len(), 1);
+
+        assert!(processor
+            .verify_filters(&filters, empty_state(), &context)
+            .is_ok());
+        assert!(processor
+            .can_see_event(&message, empty_state(), &context)
+            .unwrap());
+        assert!(processor
+            .verify_filters(&filters, empty_state(), &outsider_context)
+            .is_err());
+        assert!(!processor
+            .can_see_event(&message, empty_state(), &outsider_context)
+            .unwrap());
+
+        groups.load_all().await.unwrap();
+        assert!(groups.is_loaded());
+        assert_eq!(groups.list_all_groups().len(), 300);
+    }
+
+    #[tokio::test]
+    async fn test_personal_kinds_are_replaceable_and_public() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let (admin_keys, user_keys, _) = create_test_keys().await;
+        let scope = Scope::Default;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups, relay_keys.public_key())
+            .with_personal_kinds([Kind::Metadata, Kind::ContactList, Kind::RelayList]);
+        let admin_context = EventContext {
+            authed_pubkey: Some(admin_keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let user_context = EventContext {
+            authed_pubkey: Some(user_keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let anonymous_context = EventContext {
+            authed_pubkey: None,
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+
+        // Groups are private by default
+        let create = create_test_event(
+            &admin_keys,
+            9007,
+            vec![Tag::custom(TagKind::h(), ["private_group"])],
+        )
+        .await;
+        let commands = processor
+            .handle_event(create, empty_state(), &admin_context)
+            .await
+            .unwrap();
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+
+        let now = Timestamp::now().as_u64();
+        for (name, created_at) in [("old", now - 10), ("new", now)] {
+            let profile = EventBuilder::new(Kind::Metadata, format!(r#"{{"name":"{name}"}}"#))
+                .tag(Tag::custom(TagKind::h(), ["private_group"]))
+                .custom_created_at(Timestamp::from(created_at))
+                .sign_with_keys(&user_keys)
+                .unwrap();
+            let commands = processor
+                .handle_event(profile, empty_state(), &user_context)
+                .await
+                .unwrap();
+            assert!(matches!(
+                commands.as_slice(),
+                [StoreCommand::SaveSignedEvent(..)]
+            ));
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let profiles = database
+            .query(
+                vec![Filter::new()
+                    .kind(Kind::Metadata)
+                    .author(user_keys.public_key())],
+                &scope,
+            )
+            .await
+            .unwrap();
+        assert_eq!(profiles.len(), 1);
+        let profile = profiles.first().unwrap();
+        assert!(profile.content.contains("new"));
+
+        // Readable without AUTH even though it mentions a private group
+        assert!(processor
+            .can_see_event(profile, empty_state(), &anonymous_context)
+            .unwrap());
+        let message = create_test_event(
+            &admin_keys,
+            9,
+            vec![Tag::custom(TagKind::h(), ["private_group"])],
+        )
+        .await;
+        assert!(processor
+            .can_see_event(&message, empty_state(), &anonymous_context)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_group_events_are_relayed_not_stored() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let (admin_keys, member_keys, outsider_keys) = create_test_keys().await;
+        let scope = Scope::Default;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay.com".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let context = |keys: &Keys| EventContext {
+            authed_pubkey: Some(keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["ephemeral_group"]);
+
+        let create = create_test_event(&admin_keys, 9007, vec![h_tag.clone()]).await;
+        let add = create_test_event(
+            &admin_keys,
+            9000,
+            vec![h_tag.clone(), Tag::public_key(member_keys.public_key())],
+        )
+        .await;
+        for event in [create, add] {
+            let commands = processor
+                .handle_event(event, empty_state(), &context(&admin_keys))
+                .await
+                .unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+        }
+
+        // A member's typing indicator is handed on for live delivery...
+        let typing = create_test_event(&member_keys, 20001, vec![h_tag.clone()]).await;
+        let commands = processor
+            .handle_event(typing.clone(), empty_state(), &context(&member_keys))
+            .await
+            .unwrap();
+        assert!(matches!(
+            commands.as_slice(),
+            [StoreCommand::SaveSignedEvent(event, _, _)] if event.id == typing.id
+        ));
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        // ...but never stored, so historical REQs can't return it
+        let stored = database
+            .query(vec![Filter::new().id(typing.id)], &scope)
+            .await
+            .unwrap();
+        assert!(stored.is_empty());
+
+        // Non-members are rejected like for any other group content
+        let outsider_typing = create_test_event(&outsider_keys, 20001, vec![h_tag.clone()]).await;
+        assert!(processor
+            .handle_event(outsider_typing, empty_state(), &context(&outsider_keys))
+            .await
+            .is_err());
+
+        // And the relay can refuse ephemeral events altogether
+        let strict =
+            GroupsRelayProcessor::new(groups, relay_keys.public_key()).without_ephemeral_events();
+        assert!(strict
+            .handle_event(typing, empty_state(), &context(&member_keys))
+            .await
+            .is_err());
+    }
 }
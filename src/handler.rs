@@ -1,18 +1,34 @@
-use crate::groups::Invite;
+use crate::bot_tokens::BotTokenInfo;
+use crate::directory::directory_entries;
+use crate::group::Group;
+use crate::group_verification::verify_group_state;
+use crate::groups::{
+    BotCapability, GroupError, GroupRole, GroupWebhook, Invite, KIND_GROUP_ANNOTATION_9030,
+};
+use crate::nip98;
+use crate::read_only::READ_ONLY_MESSAGE;
+use crate::relay_keys::RelayIdentity;
+use crate::scope_policy::list_scopes;
 use crate::server::ServerState;
+use crate::utils::apply_store_commands;
 use axum::{
-    body::Body,
-    extract::State,
-    http::{Method, Request, StatusCode},
-    response::{IntoResponse, Json},
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{HeaderMap, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
 };
 use nostr_lmdb::Scope;
-use serde::Serialize;
-use std::collections::HashMap;
+use nostr_sdk::prelude::{
+    Alphabet, Event, Filter, PublicKey, SingleLetterTag, Timestamp, ToBech32,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
-use tracing::debug;
+use tracing::{debug, error};
 
 #[derive(Serialize)]
 pub struct GroupResponse {
@@ -35,6 +51,41 @@ struct MemberResponse {
     roles: Vec<String>,
 }
 
+/// Default and maximum page sizes for the members endpoint
+pub const DEFAULT_MEMBERS_PAGE_SIZE: usize = 50;
+pub const MAX_MEMBERS_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MembersQuery {
+    /// Only list members with this role
+    pub role: Option<String>,
+    /// Prefix of the member's npub or hex pubkey
+    pub q: Option<String>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    /// Subdomain scope of the group, the default scope when omitted
+    pub subdomain: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberEntry {
+    pub pubkey: String,
+    pub npub: String,
+    pub roles: Vec<String>,
+    pub joined_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MembersPage {
+    pub members: Vec<MemberEntry>,
+    /// Cursor for the next page, absent on the last one
+    pub next_cursor: Option<String>,
+    /// Pending join requests, only shown to admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_requests: Option<Vec<String>>,
+}
+
 #[derive(Serialize)]
 pub struct SubdomainResponse {
     subdomains: Vec<String>,
@@ -70,6 +121,19 @@ pub async fn handle_health() -> impl IntoResponse {
     "OK"
 }
 
+/// `GET /readyz`: 200 once every dependency answers, 503 naming the failing
+/// ones otherwise
+pub async fn handle_readyz(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    match state.readiness.check().await {
+        Ok(()) => Json(serde_json::json!({ "status": "ready" })).into_response(),
+        Err(failing) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "not_ready", "failing": failing })),
+        )
+            .into_response(),
+    }
+}
+
 pub async fn handle_metrics(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
     state.metrics_handle.render()
 }
@@ -121,6 +185,885 @@ pub async fn handle_config(State(state): State<Arc<ServerState>>) -> impl IntoRe
     Json(ConfigResponse { base_domain_parts })
 }
 
+/// Verifies the request's NIP-98 authorization, answering 401 on failure.
+/// `body` is the raw request body the authorization has to cover.
+fn authenticate(
+    state: &ServerState,
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    body: &[u8],
+) -> Result<PublicKey, Response> {
+    nip98::verify_request(headers, method, uri, body, &state.seen_authorizations).map_err(|e| {
+        debug!("Rejecting {} {}: {}", method, uri.path(), e);
+        (StatusCode::UNAUTHORIZED, e.to_string()).into_response()
+    })
+}
+
+/// Verifies the request is NIP-98 signed by the relay's keys, answering 403
+/// with `forbidden` for other callers
+fn authenticate_relay(
+    state: &ServerState,
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    body: &[u8],
+    forbidden: &'static str,
+) -> Result<PublicKey, Response> {
+    let caller = authenticate(state, headers, method, uri, body)?;
+    if !state.http_state.groups.relay_keys().is_relay(&caller) {
+        return Err((StatusCode::FORBIDDEN, forbidden).into_response());
+    }
+    Ok(caller)
+}
+
+/// Parses a JSON body read as bytes, so its NIP-98 payload hash can be
+/// checked first
+fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, Response> {
+    serde_json::from_slice(body)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response())
+}
+
+/// Answers 503 on endpoints that store events while the relay is read-only
+fn ensure_writable(state: &ServerState) -> Result<(), Response> {
+    if state.read_only.is_enabled() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, READ_ONLY_MESSAGE).into_response());
+    }
+    Ok(())
+}
+
+/// Scope named by the `subdomain` query parameter, the default scope when absent
+fn scope_from_subdomain(subdomain: Option<&str>) -> Result<Scope, Response> {
+    match subdomain.filter(|s| !s.is_empty()) {
+        Some(name) => Scope::named(name)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid subdomain").into_response()),
+        None => Ok(Scope::Default),
+    }
+}
+
+/// Lists a group's members ordered by `(joined_at, pubkey)`.
+///
+/// The cursor is the last returned `(joined_at, pubkey)` pair, so pages stay
+/// stable when members join or leave between requests: nobody is skipped or
+/// repeated, and new members show up at the end.
+pub fn members_page(
+    group: &Group,
+    query: &MembersQuery,
+    include_join_requests: bool,
+) -> MembersPage {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MEMBERS_PAGE_SIZE)
+        .clamp(1, MAX_MEMBERS_PAGE_SIZE);
+    let after = query.cursor.as_deref().and_then(parse_members_cursor);
+    let search = query.q.as_deref().map(str::to_lowercase);
+
+    let mut members: Vec<_> = group
+        .members
+        .values()
+        .filter(|member| {
+            query
+                .role
+                .as_deref()
+                .is_none_or(|role| member.roles.iter().any(|r| r.name() == role))
+        })
+        .map(|member| {
+            let npub = member.pubkey.to_bech32().unwrap_or_default();
+            (member, npub)
+        })
+        .filter(|(member, npub)| {
+            search.as_deref().is_none_or(|search| {
+                npub.starts_with(search) || member.pubkey.to_hex().starts_with(search)
+            })
+        })
+        .filter(|(member, _)| {
+            after.is_none_or(|(joined_at, pubkey)| {
+                (member.joined_at, member.pubkey) > (joined_at, pubkey)
+            })
+        })
+        .collect();
+    members.sort_by_key(|(member, _)| (member.joined_at, member.pubkey));
+
+    let has_more = members.len() > limit;
+    members.truncate(limit);
+
+    let next_cursor = has_more
+        .then(|| members.last())
+        .flatten()
+        .map(|(member, _)| format!("{}:{}", member.joined_at.as_u64(), member.pubkey.to_hex()));
+
+    let members = members
+        .into_iter()
+        .map(|(member, npub)| {
+            let mut roles: Vec<String> =
+                member.roles.iter().map(|r| r.name().to_string()).collect();
+            roles.sort();
+            MemberEntry {
+                pubkey: member.pubkey.to_hex(),
+                npub,
+                roles,
+                joined_at: member.joined_at.as_u64(),
+            }
+        })
+        .collect();
+
+    let join_requests = include_join_requests.then(|| {
+        let mut requests: Vec<String> = group.join_requests.iter().map(|pk| pk.to_hex()).collect();
+        requests.sort();
+        requests
+    });
+
+    MembersPage {
+        members,
+        next_cursor,
+        join_requests,
+    }
+}
+
+fn parse_members_cursor(cursor: &str) -> Option<(Timestamp, PublicKey)> {
+    let (joined_at, pubkey) = cursor.split_once(':')?;
+    Some((
+        Timestamp::from(joined_at.parse::<u64>().ok()?),
+        PublicKey::from_hex(pubkey).ok()?,
+    ))
+}
+
+/// `GET /api/groups/{id}/members`, authenticated with NIP-98.
+///
+/// Open to the group's members and admins; only admins (and the relay) see
+/// pending join requests.
+pub async fn handle_group_members(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<MembersQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    let groups = &state.http_state.groups;
+    let Some(group) = groups.get_group(&scope, &group_id) else {
+        return (StatusCode::NOT_FOUND, "Group not found").into_response();
+    };
+
+    let is_admin = groups.relay_keys().is_relay(&caller) || group.is_admin(&caller);
+    if !is_admin && !group.is_member(&caller) {
+        return (StatusCode::FORBIDDEN, "Only group members can list members").into_response();
+    }
+
+    Json(members_page(&group, &query, is_admin)).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ScopeQuery {
+    pub subdomain: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DirectoryQuery {
+    pub subdomain: Option<String>,
+    /// Only list groups whose name or about contains this, ignoring case
+    pub q: Option<String>,
+}
+
+/// `GET /api/directory`: the public groups of a scope, as listed in its
+/// relay-signed directory event. No authentication needed.
+pub async fn handle_directory(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<DirectoryQuery>,
+) -> impl IntoResponse {
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    Json(directory_entries(
+        &state.http_state.groups,
+        &scope,
+        query.q.as_deref(),
+    ))
+    .into_response()
+}
+
+/// `POST /api/simulate`: whether a signed event would be accepted, which
+/// check would reject it and the store commands it would produce, without
+/// publishing it. The event's author authenticates with NIP-98, and only a
+/// few simulations run at once.
+pub async fn handle_simulate(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let caller = match authenticate(&state, &headers, &method, &uri, &body) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+    let event: Event = match parse_json(&body) {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    if event.pubkey != caller {
+        return (
+            StatusCode::FORBIDDEN,
+            "Only the event's author can simulate it",
+        )
+            .into_response();
+    }
+
+    let Some(_running) = state.simulator.try_reserve() else {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many simulations running, try again shortly",
+        )
+            .into_response();
+    };
+    Json(state.simulator.simulate(event, &scope).await).into_response()
+}
+
+/// `GET /api/groups/{id}/annotations`: the group's moderation notes, newest
+/// first. Admins only, authenticated with NIP-98.
+pub async fn handle_list_annotations(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    let groups = &state.http_state.groups;
+    match groups.get_group(&scope, &group_id) {
+        None => return (StatusCode::NOT_FOUND, "Group not found").into_response(),
+        Some(group) if !group.can_see_annotations(&caller, groups.relay_keys()) => {
+            return (StatusCode::FORBIDDEN, "Only admins can read annotations").into_response();
+        }
+        Some(_) => {}
+    }
+
+    let filter = Filter::new()
+        .kind(KIND_GROUP_ANNOTATION_9030)
+        .custom_tag(SingleLetterTag::lowercase(Alphabet::H), &group_id);
+    match state.database.query(vec![filter], &scope).await {
+        Ok(events) => Json(events.into_iter().collect::<Vec<Event>>()).into_response(),
+        Err(e) => {
+            error!("Failed to query annotations for {}: {}", group_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+/// `POST /api/groups/{id}/annotations`: stores a signed annotation event.
+///
+/// The body is a kind 9030 event signed by the NIP-98 caller; it goes through
+/// the same checks as one published over the websocket, and every command
+/// those produce is applied and counted against the storage quota. Like
+/// other relay-side writes, it reaches subscribers on their next REQ.
+pub async fn handle_create_annotation(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let caller = match authenticate(&state, &headers, &method, &uri, &body) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let event: Event = match parse_json(&body) {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    if event.pubkey != caller || event.verify().is_err() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Annotation must be signed by the authenticated pubkey",
+        )
+            .into_response();
+    }
+    if Group::extract_group_h_tag(&event) != Some(group_id.as_str()) {
+        return (StatusCode::BAD_REQUEST, "Annotation is for another group").into_response();
+    }
+
+    let event_id = event.id;
+    let commands = match state
+        .http_state
+        .groups
+        .handle_annotation(Box::new(event), &scope)
+    {
+        Ok(commands) => commands,
+        Err(e) => return (StatusCode::FORBIDDEN, e.to_string()).into_response(),
+    };
+
+    if let Some(quotas) = &state.storage_quotas {
+        quotas.record(&commands).await;
+    }
+    if let Err(e) = apply_store_commands(&state.database, &state.relay_keys, commands).await {
+        error!("Failed to save annotation {}: {}", event_id, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+    }
+
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "id": event_id.to_hex() })),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterWebhookRequest {
+    pub url: String,
+    /// Kinds to deliver, every kind but annotations when empty
+    #[serde(default)]
+    pub kinds: Vec<u16>,
+    /// Required for private groups: content is sent to a third party
+    #[serde(default)]
+    pub i_understand_content_leaves_the_relay: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisteredWebhook {
+    #[serde(flatten)]
+    pub webhook: GroupWebhook,
+    /// Shown only once, deliveries are signed with it
+    pub secret: String,
+}
+
+fn group_error_response(error: GroupError) -> Response {
+    let status = match &error {
+        GroupError::NotFound(_) => StatusCode::NOT_FOUND,
+        GroupError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        GroupError::ValidationFailed(_) | GroupError::InvalidState(_) => StatusCode::BAD_REQUEST,
+        GroupError::Internal(e) => {
+            error!("Group request failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    (status, error.to_string()).into_response()
+}
+
+fn webhooks_disabled() -> Response {
+    (StatusCode::NOT_FOUND, "Group webhooks are not enabled").into_response()
+}
+
+/// `GET /api/groups/{id}/webhooks`: the group's webhooks, without secrets.
+/// Admins only, authenticated with NIP-98.
+pub async fn handle_list_group_webhooks(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if state.group_webhooks.is_none() {
+        return webhooks_disabled();
+    }
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    let groups = &state.http_state.groups;
+    match groups.get_group(&scope, &group_id) {
+        None => (StatusCode::NOT_FOUND, "Group not found").into_response(),
+        Some(group) if !group.can_manage_webhooks(&caller, groups.relay_keys()) => {
+            (StatusCode::FORBIDDEN, "Only admins can manage webhooks").into_response()
+        }
+        Some(group) => Json(group.webhooks.clone()).into_response(),
+    }
+}
+
+/// `POST /api/groups/{id}/webhooks`: registers a webhook and returns its
+/// signing secret. Admins only, authenticated with NIP-98.
+pub async fn handle_register_group_webhook(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let Some(group_webhooks) = &state.group_webhooks else {
+        return webhooks_disabled();
+    };
+    let caller = match authenticate(&state, &headers, &method, &uri, &body) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let request: RegisterWebhookRequest = match parse_json(&body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    match group_webhooks
+        .register(
+            &scope,
+            &group_id,
+            &caller,
+            &request.url,
+            request.kinds,
+            request.i_understand_content_leaves_the_relay,
+        )
+        .await
+    {
+        Ok((webhook, secret)) => (
+            StatusCode::CREATED,
+            Json(RegisteredWebhook { webhook, secret }),
+        )
+            .into_response(),
+        Err(e) => group_error_response(e),
+    }
+}
+
+/// `DELETE /api/groups/{id}/webhooks/{webhook_id}`, admins only
+pub async fn handle_delete_group_webhook(
+    State(state): State<Arc<ServerState>>,
+    Path((group_id, webhook_id)): Path<(String, String)>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let Some(group_webhooks) = &state.group_webhooks else {
+        return webhooks_disabled();
+    };
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    match group_webhooks
+        .unregister(&scope, &group_id, &caller, &webhook_id)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => group_error_response(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueBotTokenRequest {
+    pub capabilities: BTreeSet<BotCapability>,
+    /// Seconds until the token expires
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedBotToken {
+    #[serde(flatten)]
+    pub info: BotTokenInfo,
+    /// Shown only once, the bot presents it in a `bot-token` AUTH tag
+    pub token: String,
+}
+
+/// `GET /api/groups/{id}/bot-tokens`: the group's unexpired bot tokens,
+/// without the tokens themselves. Admins only, authenticated with NIP-98.
+pub async fn handle_list_bot_tokens(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    let groups = &state.http_state.groups;
+    match groups.get_group(&scope, &group_id) {
+        None => (StatusCode::NOT_FOUND, "Group not found").into_response(),
+        Some(group) if !group.can_manage_bot_tokens(&caller, groups.relay_keys()) => {
+            (StatusCode::FORBIDDEN, "Only admins can manage bot tokens").into_response()
+        }
+        Some(group) => {
+            let now = Timestamp::now();
+            let tokens: Vec<BotTokenInfo> = group
+                .bot_tokens
+                .iter()
+                .filter(|token| !token.is_expired(now))
+                .map(BotTokenInfo::from)
+                .collect();
+            Json(tokens).into_response()
+        }
+    }
+}
+
+/// `POST /api/groups/{id}/bot-tokens`: issues a bot token and returns it.
+/// Admins only, authenticated with NIP-98.
+pub async fn handle_issue_bot_token(
+    State(state): State<Arc<ServerState>>,
+    Path(group_id): Path<String>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let caller = match authenticate(&state, &headers, &method, &uri, &body) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let request: IssueBotTokenRequest = match parse_json(&body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    match state
+        .bot_tokens
+        .issue(
+            &scope,
+            &group_id,
+            &caller,
+            request.capabilities,
+            Duration::from_secs(request.expires_in),
+        )
+        .await
+    {
+        Ok((token, secret)) => (
+            StatusCode::CREATED,
+            Json(IssuedBotToken {
+                info: BotTokenInfo::from(&token),
+                token: secret,
+            }),
+        )
+            .into_response(),
+        Err(e) => group_error_response(e),
+    }
+}
+
+/// `DELETE /api/groups/{id}/bot-tokens/{token_id}`, admins only. Open
+/// connections using the token lose its capabilities right away.
+pub async fn handle_revoke_bot_token(
+    State(state): State<Arc<ServerState>>,
+    Path((group_id, token_id)): Path<(String, String)>,
+    Query(query): Query<ScopeQuery>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+    let scope = match scope_from_subdomain(query.subdomain.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    match state
+        .bot_tokens
+        .revoke(&scope, &group_id, &caller, &token_id)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => group_error_response(e),
+    }
+}
+
+/// `POST /api/account/deletion`: deletes everything the NIP-98 caller
+/// authored. Runs in the background, poll the returned job for progress.
+pub async fn handle_request_account_deletion(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = ensure_writable(&state) {
+        return response;
+    }
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+
+    let status = state.account_deletions.start(caller);
+    (StatusCode::ACCEPTED, Json(status)).into_response()
+}
+
+/// `GET /api/account/deletion/{id}`: progress of the caller's deletion job
+pub async fn handle_account_deletion_status(
+    State(state): State<Arc<ServerState>>,
+    Path(job_id): Path<String>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let caller = match authenticate(&state, &headers, &method, &uri, &[]) {
+        Ok(pubkey) => pubkey,
+        Err(response) => return response,
+    };
+
+    // Other users' jobs look the same as missing ones
+    match state.account_deletions.status(&job_id) {
+        Some(status) if status.pubkey == caller.to_hex() => Json(status).into_response(),
+        _ => (StatusCode::NOT_FOUND, "Deletion job not found").into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadOnlyRequest {
+    pub enabled: bool,
+}
+
+/// `PUT /admin/read_only`: turns read-only mode on or off without a restart.
+/// Only the relay's keys may call it, authenticated with NIP-98.
+pub async fn handle_set_read_only(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &body,
+        "Only the relay can change read-only mode",
+    ) {
+        return response;
+    }
+    let request: ReadOnlyRequest = match parse_json(&body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    state.read_only.set(request.enabled);
+    Json(serde_json::json!({ "read_only": request.enabled })).into_response()
+}
+
+/// `GET /admin/connections`: every live connection with its scope, auth
+/// state, open subscriptions and message counters. Only the relay's keys may
+/// call it.
+pub async fn handle_list_connections(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &[],
+        "Only the relay can list connections",
+    ) {
+        return response;
+    }
+
+    let connections = state
+        .connection_stats
+        .connections(&state.subscription_registry);
+    Json(serde_json::json!({ "connections": connections })).into_response()
+}
+
+/// `GET /admin/rejections`: the most recent events the processor rejected,
+/// newest first, with their reason codes. Only the relay's keys may call it.
+pub async fn handle_get_rejections(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &[],
+        "Only the relay can read rejections",
+    ) {
+        return response;
+    }
+
+    Json(serde_json::json!({ "rejections": state.rejections.recent() })).into_response()
+}
+
+/// `GET /admin/quotas`: events and bytes counted against each scope's storage
+/// quota, with the limits. Only the relay's keys may call it.
+pub async fn handle_get_quotas(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &[],
+        "Only the relay can read storage quotas",
+    ) {
+        return response;
+    }
+
+    let Some(quotas) = &state.storage_quotas else {
+        return (StatusCode::NOT_FOUND, "Storage quotas are not enabled").into_response();
+    };
+    Json(serde_json::json!({ "scopes": quotas.scope_usage() })).into_response()
+}
+
+/// `GET /admin/scopes`: every stored scope with its group and event counts,
+/// and whether the scope policy serves it. Only the relay's keys may call it.
+pub async fn handle_list_scopes(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &[],
+        "Only the relay can list scopes",
+    ) {
+        return response;
+    }
+
+    match list_scopes(
+        &state.database,
+        &state.http_state.groups,
+        &state.scope_policy,
+    )
+    .await
+    {
+        Ok(scopes) => Json(serde_json::json!({ "scopes": scopes })).into_response(),
+        Err(e) => {
+            error!("Failed to list scopes: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to list scopes").into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyGroupsRequest {
+    #[serde(default)]
+    pub heal: bool,
+}
+
+/// `POST /admin/verify_groups`: rebuilds every group from its stored events
+/// and reports where the loaded state differs. With `heal`, differing groups
+/// are replaced with their rebuilt state. Only the relay's keys may call it.
+pub async fn handle_verify_groups(
+    State(state): State<Arc<ServerState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err(response) = authenticate_relay(
+        &state,
+        &headers,
+        &method,
+        &uri,
+        &body,
+        "Only the relay can verify group state",
+    ) {
+        return response;
+    }
+    let request: VerifyGroupsRequest = match parse_json(&body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    if request.heal {
+        if let Err(response) = ensure_writable(&state) {
+            return response;
+        }
+    }
+
+    match verify_group_state(
+        &state.http_state.groups,
+        &state.database,
+        &state.relay_keys,
+        request.heal,
+    )
+    .await
+    {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            error!("Failed to verify group state: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to verify group state",
+            )
+                .into_response()
+        }
+    }
+}
+
 /// Serve the frontend without needing state
 pub async fn serve_frontend() -> impl IntoResponse {
     debug!("Serving frontend HTML for root path");
@@ -141,3 +1084,125 @@ pub async fn serve_frontend() -> impl IntoResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group::GroupMember;
+    use crate::test_utils::{create_test_group, create_test_keys};
+    use nostr_sdk::prelude::Keys;
+
+    fn group_with_members(count: u64) -> (Group, Vec<PublicKey>) {
+        let mut group = Group::new_with_id("test_group".to_string());
+
+        let mut pubkeys = Vec::new();
+        for i in 0..count {
+            let pubkey = Keys::generate().public_key();
+            group.members.insert(
+                pubkey,
+                GroupMember::new_member(pubkey).with_joined_at(Timestamp::from(1_000 + i)),
+            );
+            pubkeys.push(pubkey);
+        }
+        (group, pubkeys)
+    }
+
+    fn page(group: &Group, cursor: Option<String>) -> MembersPage {
+        let query = MembersQuery {
+            cursor,
+            limit: Some(2),
+            ..Default::default()
+        };
+        members_page(group, &query, false)
+    }
+
+    #[test]
+    fn test_pagination_is_stable_across_membership_changes() {
+        let (mut group, pubkeys) = group_with_members(5);
+
+        let first = page(&group, None);
+        let first_keys: Vec<_> = first.members.iter().map(|m| m.pubkey.clone()).collect();
+        assert_eq!(first_keys, vec![pubkeys[0].to_hex(), pubkeys[1].to_hex()]);
+
+        // A member already listed leaves and a new one joins before the next page
+        group.members.remove(&pubkeys[0]);
+        let newcomer = Keys::generate().public_key();
+        group.members.insert(
+            newcomer,
+            GroupMember::new_member(newcomer).with_joined_at(Timestamp::from(2_000)),
+        );
+
+        let second = page(&group, first.next_cursor.clone());
+        let second_keys: Vec<_> = second.members.iter().map(|m| m.pubkey.clone()).collect();
+        assert_eq!(second_keys, vec![pubkeys[2].to_hex(), pubkeys[3].to_hex()]);
+
+        let third = page(&group, second.next_cursor.clone());
+        let third_keys: Vec<_> = third.members.iter().map(|m| m.pubkey.clone()).collect();
+        assert_eq!(third_keys, vec![pubkeys[4].to_hex(), newcomer.to_hex()]);
+        assert!(third.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_members_search_and_role_filter() {
+        let (mut group, pubkeys) = group_with_members(3);
+        group
+            .members
+            .get_mut(&pubkeys[1])
+            .unwrap()
+            .roles
+            .insert(GroupRole::Admin);
+
+        let by_role = members_page(
+            &group,
+            &MembersQuery {
+                role: Some("admin".to_string()),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(by_role.members.len(), 1);
+        assert_eq!(by_role.members[0].roles, vec!["admin", "member"]);
+
+        let npub = pubkeys[2].to_bech32().unwrap();
+        for prefix in [&npub[..16], &pubkeys[2].to_hex()[..12]] {
+            let found = members_page(
+                &group,
+                &MembersQuery {
+                    q: Some(prefix.to_string()),
+                    ..Default::default()
+                },
+                false,
+            );
+            assert_eq!(found.members.len(), 1);
+            assert_eq!(found.members[0].npub, npub);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_requests_hidden_from_non_admins() {
+        let (admin_keys, member_keys, requester_keys) = create_test_keys().await;
+        let (mut group, _) = create_test_group(&admin_keys).await;
+        group.members.insert(
+            member_keys.public_key(),
+            GroupMember::new_member(member_keys.public_key()),
+        );
+        group.join_requests.insert(requester_keys.public_key());
+
+        let member_view = members_page(&group, &MembersQuery::default(), false);
+        assert!(member_view.join_requests.is_none());
+        assert!(!serde_json::to_string(&member_view)
+            .unwrap()
+            .contains("join_requests"));
+        // Roles stay visible to members
+        assert!(member_view
+            .members
+            .iter()
+            .any(|m| m.roles.contains(&"admin".to_string())));
+
+        let admin_view = members_page(&group, &MembersQuery::default(), true);
+        assert_eq!(
+            admin_view.join_requests,
+            Some(vec![requester_keys.public_key().to_hex()])
+        );
+    }
+}
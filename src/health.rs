@@ -0,0 +1,344 @@
+//! Liveness and readiness probes for orchestrators like Kubernetes.
+//!
+//! `GET /healthz` answers 200 whenever the runtime gets to serve it, so it
+//! stays up while a dependency is failing.
+//! `GET /readyz` runs every [`Probe`] of a [`Readiness`] within a deadline
+//! and answers 503 naming the failing dependencies until all of them pass:
+//!
+//! - `groups`: the background load of every group has finished
+//! - `database`: LMDB takes writes, checked by saving a relay-signed meta
+//!   record and reading it back. Only the relay can read the record, and in
+//!   read-only mode the probe only checks that LMDB answers a query.
+//! - `signer`: the relay's key signs an event
+//!
+//! relay_builder's crypto worker and replaceable event buffer are internal
+//! to it, so they aren't probed directly.
+
+use crate::groups::Groups;
+use crate::read_only::ReadOnlyMode;
+use crate::RelayDatabase;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long each probe gets before its dependency counts as failing
+pub const PROBE_DEADLINE: Duration = Duration::from_secs(2);
+
+/// `d` tag of the record the database probe writes
+pub const READINESS_RECORD: &str = "groups_relay:readyz";
+
+/// NIP-78 application data, for the database probe's record
+const KIND_APP_DATA: Kind = Kind::Custom(30078);
+
+/// Whether the event is a readiness record, which only the relay may read.
+/// The author still has to be checked against the relay's keys.
+pub fn is_readiness_record(event: &Event) -> bool {
+    event.kind == KIND_APP_DATA && event.tags.identifier() == Some(READINESS_RECORD)
+}
+
+#[async_trait]
+pub trait Probe: Send + Sync {
+    /// The dependency named when the probe fails
+    fn name(&self) -> &'static str;
+
+    async fn check(&self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FailedProbe {
+    pub dependency: &'static str,
+    pub error: String,
+}
+
+pub struct Readiness {
+    probes: Vec<Arc<dyn Probe>>,
+    deadline: Duration,
+}
+
+impl std::fmt::Debug for Readiness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.probes.iter().map(|probe| probe.name()).collect();
+        f.debug_struct("Readiness")
+            .field("probes", &names)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl Readiness {
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            probes: Vec::new(),
+            deadline,
+        }
+    }
+
+    pub fn with_probe(mut self, probe: impl Probe + 'static) -> Self {
+        self.probes.push(Arc::new(probe));
+        self
+    }
+
+    /// Runs the probes concurrently, returning those that failed or missed
+    /// the deadline
+    pub async fn check(&self) -> Result<(), Vec<FailedProbe>> {
+        let results = join_all(self.probes.iter().map(|probe| async move {
+            let error = match tokio::time::timeout(self.deadline, probe.check()).await {
+                Ok(Ok(())) => return None,
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("no answer within {:?}", self.deadline),
+            };
+            Some(FailedProbe {
+                dependency: probe.name(),
+                error,
+            })
+        }))
+        .await;
+
+        let failed: Vec<FailedProbe> = results.into_iter().flatten().collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+}
+
+/// Ready once every group is loaded
+pub struct GroupsLoaded(pub Arc<Groups>);
+
+#[async_trait]
+impl Probe for GroupsLoaded {
+    fn name(&self) -> &'static str {
+        "groups"
+    }
+
+    async fn check(&self) -> Result<()> {
+        if !self.0.is_loaded() {
+            bail!("groups are still loading");
+        }
+        Ok(())
+    }
+}
+
+/// Ready while a relay-signed record can be saved and read back
+pub struct DatabaseWritable {
+    database: Arc<RelayDatabase>,
+    keys: Keys,
+    read_only: Option<Arc<ReadOnlyMode>>,
+    /// Timestamp of the last record, so each one replaces the previous
+    last_written: Mutex<Timestamp>,
+}
+
+impl DatabaseWritable {
+    pub fn new(database: Arc<RelayDatabase>, keys: Keys) -> Self {
+        Self {
+            database,
+            keys,
+            read_only: None,
+            last_written: Mutex::new(Timestamp::from(0)),
+        }
+    }
+
+    /// Skips the write while the relay is read-only
+    pub fn with_read_only(mut self, read_only: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// A timestamp after the previous record's, since a replaceable event
+    /// from the same second may not replace it
+    fn next_created_at(&self) -> Timestamp {
+        let mut last = self.last_written.lock();
+        let now = Timestamp::now();
+        *last = if now > *last {
+            now
+        } else {
+            Timestamp::from(last.as_u64() + 1)
+        };
+        *last
+    }
+}
+
+#[async_trait]
+impl Probe for DatabaseWritable {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    async fn check(&self) -> Result<()> {
+        let filter = Filter::new()
+            .kind(KIND_APP_DATA)
+            .author(self.keys.public_key())
+            .identifier(READINESS_RECORD);
+        if self
+            .read_only
+            .as_ref()
+            .is_some_and(|mode| mode.is_enabled())
+        {
+            self.database
+                .query(vec![filter.limit(1)], &Scope::Default)
+                .await?;
+            return Ok(());
+        }
+
+        let record = EventBuilder::new(KIND_APP_DATA, "")
+            .tag(Tag::identifier(READINESS_RECORD))
+            .custom_created_at(self.next_created_at())
+            .sign_with_keys(&self.keys)?;
+        self.database.save_event(&record, &Scope::Default).await?;
+
+        // Saves can be queued, the deadline bounds the wait
+        loop {
+            let stored = self
+                .database
+                .query(vec![filter.clone()], &Scope::Default)
+                .await?;
+            if stored.into_iter().any(|event| event.id == record.id) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Ready while the relay's signer answers a signing request
+pub struct SignerResponds {
+    signer: Arc<dyn NostrSigner>,
+    pubkey: PublicKey,
+}
+
+impl SignerResponds {
+    pub fn new(signer: Arc<dyn NostrSigner>, pubkey: PublicKey) -> Self {
+        Self { signer, pubkey }
+    }
+}
+
+#[async_trait]
+impl Probe for SignerResponds {
+    fn name(&self) -> &'static str {
+        "signer"
+    }
+
+    async fn check(&self) -> Result<()> {
+        let ping = EventBuilder::new(KIND_APP_DATA, "ping")
+            .tag(Tag::identifier(READINESS_RECORD))
+            .build(self.pubkey);
+        let signed = self.signer.sign_event(ping).await?;
+        signed.verify()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::handle_health;
+    use crate::test_utils::setup_test;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    /// A crypto worker that took the request and never answers
+    struct StalledSigner;
+
+    #[async_trait]
+    impl Probe for StalledSigner {
+        fn name(&self) -> &'static str {
+            "signer"
+        }
+
+        async fn check(&self) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_signer_fails_readiness_but_not_liveness() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let probes = || {
+            Readiness::new(Duration::from_millis(200))
+                .with_probe(GroupsLoaded(groups.clone()))
+                .with_probe(DatabaseWritable::new(database.clone(), relay_keys.clone()))
+        };
+
+        let ready = probes().with_probe(SignerResponds::new(
+            Arc::new(relay_keys.clone()),
+            relay_keys.public_key(),
+        ));
+        assert_eq!(ready.check().await, Ok(()));
+
+        let stalled = probes().with_probe(StalledSigner);
+        let failed = stalled.check().await.unwrap_err();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].dependency, "signer");
+        assert!(failed[0].error.contains("no answer"));
+
+        assert_eq!(
+            handle_health().await.into_response().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_database_probe_replaces_its_record_and_skips_writes_when_read_only() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let read_only = Arc::new(ReadOnlyMode::new(false));
+        let probe = DatabaseWritable::new(database.clone(), relay_keys.clone())
+            .with_read_only(read_only.clone());
+        let records = || async {
+            database
+                .query(
+                    vec![Filter::new()
+                        .kind(KIND_APP_DATA)
+                        .identifier(READINESS_RECORD)],
+                    &Scope::Default,
+                )
+                .await
+                .unwrap()
+        };
+
+        // Twice within the same second, each record replaces the previous one
+        probe.check().await.unwrap();
+        probe.check().await.unwrap();
+        let stored = records().await;
+        assert_eq!(stored.len(), 1);
+        assert!(is_readiness_record(stored.first().unwrap()));
+
+        read_only.set(true);
+        let before = stored.first().unwrap().id;
+        probe.check().await.unwrap();
+        assert_eq!(records().await.first().unwrap().id, before);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_until_groups_load() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(Groups::new(
+            database,
+            relay_keys.public_key(),
+            "wss://test.relay".to_string(),
+        ));
+        let readiness =
+            Readiness::new(Duration::from_millis(200)).with_probe(GroupsLoaded(groups.clone()));
+
+        let failed = readiness.check().await.unwrap_err();
+        assert_eq!(failed[0].dependency, "groups");
+
+        groups.load_all().await.unwrap();
+        assert_eq!(readiness.check().await, Ok(()));
+    }
+}
@@ -0,0 +1,177 @@
+//! Minimal outbound HTTP client used for webhook deliveries and payment
+//! callbacks.
+//!
+//! Clients built with [`HttpClient::public_only`] resolve hosts through a
+//! [`CheckedResolver`] that drops internal addresses, so the connection goes
+//! to an address that passed the check instead of the answer of a second
+//! lookup a DNS-rebinding host could change in between. IP literals skip
+//! the resolver and have to be checked by the caller.
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::dns::Name;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::Service;
+
+/// Requests that take longer than this are treated as failed deliveries
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loopback, private, link-local, unique-local and other non-public addresses
+pub(crate) fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Resolves hosts with the system resolver, keeping only public addresses
+/// unless every address is accepted or the host is allowed
+#[derive(Debug, Clone, Default)]
+pub struct CheckedResolver {
+    /// Hosts that may resolve to internal addresses, None to accept any
+    allowed_hosts: Option<Arc<Vec<String>>>,
+}
+
+impl CheckedResolver {
+    fn accepts_internal(&self, host: &str) -> bool {
+        self.allowed_hosts.as_ref().is_none_or(|allowed| {
+            allowed
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        })
+    }
+}
+
+impl Service<Name> for CheckedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_ascii_lowercase();
+        let accepts_internal = self.accepts_internal(&host);
+        Box::pin(async move {
+            // The connector sets the port of the URL on each address
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|address| accepts_internal || !is_internal(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{host} has no public address"),
+                ));
+            }
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client<HttpsConnector<HttpConnector<CheckedResolver>>, Full<Bytes>>,
+}
+
+impl HttpClient {
+    pub fn new() -> Result<Self> {
+        Self::with_resolver(CheckedResolver::default())
+    }
+
+    /// A client that never connects to an internal address, unless its host
+    /// is one of `allowed_hosts`
+    pub fn public_only(allowed_hosts: Vec<String>) -> Result<Self> {
+        Self::with_resolver(CheckedResolver {
+            allowed_hosts: Some(Arc::new(allowed_hosts)),
+        })
+    }
+
+    fn with_resolver(resolver: CheckedResolver) -> Result<Self> {
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_provider_and_native_roots(rustls::crypto::ring::default_provider())?
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http);
+
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        })
+    }
+
+    /// POSTs a JSON body with extra headers and returns the response status.
+    pub async fn post_json(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        headers: &[(&str, String)],
+    ) -> Result<StatusCode> {
+        let mut request = Request::post(url).header("content-type", "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let request = request.body(Full::new(Bytes::from(body)))?;
+
+        let response =
+            tokio::time::timeout(REQUEST_TIMEOUT, self.client.request(request)).await??;
+        Ok(response.status())
+    }
+
+    /// POSTs a JSON body and returns the response status and body.
+    pub async fn post_json_for_body(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, Bytes)> {
+        let request = Request::post(url)
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body)))?;
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let response = self.client.request(request).await?;
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            anyhow::Ok((status, body))
+        })
+        .await??;
+        Ok(response)
+    }
+}
@@ -0,0 +1,342 @@
+//! Subscription introspection for debugging clients.
+//!
+//! A REQ whose subscription id is [`INTROSPECT_SUBSCRIPTION_ID`] is not
+//! executed. Instead the relay answers with a single NOTICE containing a JSON
+//! description of the requesting connection's active subscriptions, with
+//! filters as the relay sees them after limit capping, plus the connection's
+//! auth state and scope. Only the requesting connection's own data is ever
+//! returned.
+//!
+//! Subscriptions are registered when their REQ passes through, and dropped
+//! again when the relay answers them with a CLOSED, so REQs rejected further
+//! down the chain don't count toward `max_subscriptions`.
+
+use crate::capabilities::{truncation_notice, CapabilityRegistry};
+use crate::connection_stats::{ConnectionStats, ConnectionSummary};
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{
+    DisconnectContext, InboundContext, NostrMiddleware, OutboundContext,
+};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const INTROSPECT_SUBSCRIPTION_ID: &str = "_introspect";
+
+/// Active subscriptions per connection, keyed by connection id.
+#[derive(Debug, Default)]
+pub struct SubscriptionRegistry {
+    connections: DashMap<String, BTreeMap<String, Vec<Filter>>>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, connection_id: &str, subscription_id: &SubscriptionId, filters: Vec<Filter>) {
+        self.connections
+            .entry(connection_id.to_string())
+            .or_default()
+            .insert(subscription_id.to_string(), filters);
+    }
+
+    pub fn remove(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        if let Some(mut subscriptions) = self.connections.get_mut(connection_id) {
+            subscriptions.remove(&subscription_id.to_string());
+        }
+    }
+
+    pub fn remove_connection(&self, connection_id: &str) {
+        self.connections.remove(connection_id);
+    }
+
+    pub fn contains(&self, connection_id: &str, subscription_id: &SubscriptionId) -> bool {
+        self.connections
+            .get(connection_id)
+            .is_some_and(|subscriptions| subscriptions.contains_key(&subscription_id.to_string()))
+    }
+
+    pub fn subscription_count(&self, connection_id: &str) -> usize {
+        self.connections
+            .get(connection_id)
+            .map_or(0, |subscriptions| subscriptions.len())
+    }
+
+    pub fn subscriptions(&self, connection_id: &str) -> BTreeMap<String, Vec<Filter>> {
+        self.connections
+            .get(connection_id)
+            .map(|subscriptions| subscriptions.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Applies the same limit capping the relay uses when serving a REQ.
+pub fn normalize_filter(mut filter: Filter, max_limit: usize) -> Filter {
+    filter.limit = Some(filter.limit.map_or(max_limit, |limit| limit.min(max_limit)));
+    filter
+}
+
+/// Builds the JSON body of the introspection NOTICE.
+pub fn build_report(
+    authed_pubkey: Option<&PublicKey>,
+    scope: &Scope,
+    subscriptions: &BTreeMap<String, Vec<Filter>>,
+    stats: Option<&ConnectionSummary>,
+) -> String {
+    let scope = match scope {
+        Scope::Default => "default".to_string(),
+        Scope::Named { name, .. } => name.clone(),
+    };
+
+    json!({
+        "introspect": {
+            "authed_pubkey": authed_pubkey.map(|pk| pk.to_hex()),
+            "scope": scope,
+            "subscriptions": subscriptions,
+            "stats": stats,
+        }
+    })
+    .to_string()
+}
+
+#[derive(Debug, Clone)]
+pub struct IntrospectionMiddleware {
+    registry: Arc<SubscriptionRegistry>,
+    max_limit: usize,
+    capabilities: Option<Arc<CapabilityRegistry>>,
+    connection_stats: Option<Arc<ConnectionStats>>,
+}
+
+impl IntrospectionMiddleware {
+    pub fn new(registry: Arc<SubscriptionRegistry>, max_limit: usize) -> Self {
+        Self {
+            registry,
+            max_limit,
+            capabilities: None,
+            connection_stats: None,
+        }
+    }
+
+    /// Include the connection's live protocol statistics in the report
+    pub fn with_connection_stats(mut self, connection_stats: Arc<ConnectionStats>) -> Self {
+        self.connection_stats = Some(connection_stats);
+        self
+    }
+
+    /// Send a truncation NOTICE to clients that declared support for it
+    pub fn with_capabilities(mut self, capabilities: Arc<CapabilityRegistry>) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Records the subscription and returns the truncation notice to send, if any.
+    fn track(
+        &self,
+        connection_id: &str,
+        subscription_id: &SubscriptionId,
+        filters: Vec<Filter>,
+    ) -> Option<String> {
+        let notice = self.capabilities.as_ref().and_then(|capabilities| {
+            truncation_notice(
+                capabilities.get(connection_id),
+                subscription_id,
+                &filters,
+                self.max_limit,
+            )
+        });
+
+        let filters = filters
+            .into_iter()
+            .map(|filter| normalize_filter(filter, self.max_limit))
+            .collect();
+        self.registry.add(connection_id, subscription_id, filters);
+        notice
+    }
+}
+
+impl NostrMiddleware<()> for IntrospectionMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let connection_id = ctx.connection_id.to_string();
+
+        let subscription_id = match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                if subscription_id.as_str() != INTROSPECT_SUBSCRIPTION_ID {
+                    if let Some(notice) = self.track(
+                        &connection_id,
+                        subscription_id,
+                        vec![filter.clone().into_owned()],
+                    ) {
+                        ctx.send_message(RelayMessage::notice(notice))?;
+                    }
+                    return ctx.next().await;
+                }
+                subscription_id.clone().into_owned()
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                if subscription_id.as_str() != INTROSPECT_SUBSCRIPTION_ID {
+                    if let Some(notice) =
+                        self.track(&connection_id, subscription_id, filters.clone())
+                    {
+                        ctx.send_message(RelayMessage::notice(notice))?;
+                    }
+                    return ctx.next().await;
+                }
+                subscription_id.clone().into_owned()
+            }
+            Some(ClientMessage::Close(subscription_id)) => {
+                self.registry.remove(&connection_id, subscription_id);
+                return ctx.next().await;
+            }
+            _ => return ctx.next().await,
+        };
+
+        debug!(
+            "[{}] Answering introspection request {}",
+            connection_id, subscription_id
+        );
+
+        let report = {
+            let state = ctx.state.read().await;
+            build_report(
+                state.authed_pubkey.as_ref(),
+                state.subdomain(),
+                &self.registry.subscriptions(&connection_id),
+                self.connection_stats
+                    .as_ref()
+                    .and_then(|stats| stats.summary(&connection_id))
+                    .as_ref(),
+            )
+        };
+
+        ctx.send_message(RelayMessage::notice(report))?;
+        Ok(())
+    }
+
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ()>) -> Result<(), anyhow::Error> {
+        // Rejected or ended by the relay, the client no longer holds it
+        if let Some(RelayMessage::Closed {
+            subscription_id, ..
+        }) = ctx.message.as_ref()
+        {
+            self.registry
+                .remove(&ctx.connection_id.to_string(), subscription_id);
+        }
+        Ok(())
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        self.registry
+            .remove_connection(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Capabilities;
+    use serde_json::Value;
+
+    #[test]
+    fn test_normalize_filter_caps_limit() {
+        let capped = normalize_filter(Filter::new().kind(Kind::TextNote).limit(5000), 500);
+        assert_eq!(capped.limit, Some(500));
+
+        let defaulted = normalize_filter(Filter::new().kind(Kind::TextNote), 500);
+        assert_eq!(defaulted.limit, Some(500));
+
+        let untouched = normalize_filter(Filter::new().kind(Kind::TextNote).limit(10), 500);
+        assert_eq!(untouched.limit, Some(10));
+    }
+
+    #[test]
+    fn test_report_contains_capped_limit() {
+        let registry = Arc::new(SubscriptionRegistry::new());
+        let middleware = IntrospectionMiddleware::new(registry.clone(), 500);
+        middleware.track(
+            "conn-1",
+            &SubscriptionId::new("feed"),
+            vec![Filter::new().kind(Kind::TextNote).limit(10_000)],
+        );
+
+        let keys = Keys::generate();
+        let report = build_report(
+            Some(&keys.public_key()),
+            &Scope::Default,
+            &registry.subscriptions("conn-1"),
+            None,
+        );
+        let report: Value = serde_json::from_str(&report).unwrap();
+
+        assert_eq!(
+            report["introspect"]["subscriptions"]["feed"][0]["limit"],
+            500
+        );
+        assert_eq!(
+            report["introspect"]["authed_pubkey"],
+            keys.public_key().to_hex()
+        );
+        assert_eq!(report["introspect"]["scope"], "default");
+    }
+
+    #[test]
+    fn test_truncation_notice_requires_declared_capability() {
+        let capabilities = Arc::new(CapabilityRegistry::new());
+        capabilities.set("declaring", Capabilities::TRUNCATION_NOTICE);
+        let middleware = IntrospectionMiddleware::new(Arc::new(SubscriptionRegistry::new()), 500)
+            .with_capabilities(capabilities);
+        let filters = vec![Filter::new().kind(Kind::TextNote).limit(10_000)];
+        let sub_id = SubscriptionId::new("feed");
+
+        assert!(middleware
+            .track("declaring", &sub_id, filters.clone())
+            .is_some());
+        assert!(middleware.track("vanilla", &sub_id, filters).is_none());
+    }
+
+    #[test]
+    fn test_registry_is_isolated_per_connection() {
+        let registry = SubscriptionRegistry::new();
+        registry.add(
+            "conn-1",
+            &SubscriptionId::new("mine"),
+            vec![Filter::new().kind(Kind::TextNote)],
+        );
+        registry.add(
+            "conn-2",
+            &SubscriptionId::new("theirs"),
+            vec![Filter::new().kind(Kind::Metadata)],
+        );
+
+        let report = build_report(
+            None,
+            &Scope::Default,
+            &registry.subscriptions("conn-1"),
+            None,
+        );
+        assert!(report.contains("mine"));
+        assert!(!report.contains("theirs"));
+
+        registry.remove("conn-1", &SubscriptionId::new("mine"));
+        assert!(registry.subscriptions("conn-1").is_empty());
+
+        registry.remove_connection("conn-2");
+        assert!(registry.subscriptions("conn-2").is_empty());
+    }
+}
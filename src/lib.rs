@@ -1,23 +1,69 @@
+pub mod account_deletion;
+pub mod admission;
 pub mod app_state;
+pub mod archive;
+pub mod auth_resubscribe;
+pub mod bot_tokens;
+pub mod capabilities;
+pub mod committed;
 pub mod config;
+pub mod connection_stats;
 pub mod create_client;
+pub mod directory;
+pub mod dry_run;
+pub mod duplicate_events;
 pub mod error;
+pub mod event_limits;
+pub mod gc;
 pub mod group;
+pub mod group_hooks;
+pub mod group_list_sync;
+pub mod group_loading_middleware;
+pub mod group_metrics;
+pub mod group_verification;
+pub mod group_webhooks;
 pub mod groups;
 pub mod groups_event_processor;
 pub mod handler;
+pub mod health;
+pub mod http_client;
+pub mod introspection;
+pub mod load_shedding;
+pub mod member_profiles;
+pub mod membership_state;
 pub mod metrics;
 pub mod metrics_handler;
+pub mod nip70_middleware;
+pub mod nip98;
+pub mod payments;
+pub mod persistent_window;
+pub mod posting_policy;
+pub mod query_pushdown;
+pub mod quotas;
+pub mod read_only;
+pub mod rejections;
+pub mod relay_admin;
+pub mod relay_keys;
 #[cfg(test)]
 pub mod relay_middleware_integration_tests;
 #[cfg(test)]
 pub mod relay_middleware_tests;
+pub mod relay_profile;
+pub mod replay_hints;
+pub mod replication;
 pub mod sampled_metrics_handler;
+pub mod scope_policy;
+pub mod seen_events;
 pub mod server;
+pub mod simulation;
+pub mod spam_filter;
+pub mod state_integrity;
+pub mod subscription_limits;
 pub mod utils;
 pub mod validation_middleware;
+pub mod webhook;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 pub mod test_utils;
 
 // Re-export commonly used items
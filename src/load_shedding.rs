@@ -0,0 +1,550 @@
+//! Health-aware load shedding.
+//!
+//! A single [`LoadState`] tracks how busy the relay is, based on the
+//! processing latency of inbound EVENTs and the number of them currently in
+//! flight. REQs aren't measured: shedding them makes them fast, which would
+//! lower the latency that caused the shedding. The [`LoadSheddingMiddleware`]
+//! consults the state on the REQ and EVENT paths and degrades in stages,
+//! cheapest traffic first:
+//!
+//! 1. `Elevated`: anonymous historical REQs are closed.
+//! 2. `High`: anonymous EVENTs are rejected as well.
+//! 3. `Critical`: authenticated publishes are delayed before processing.
+//!
+//! Events signed by the relay itself are never shed. Levels only drop once
+//! the pressure falls well below the threshold that raised them, so the relay
+//! does not flap between levels around a threshold. While no EVENT comes in,
+//! [`LoadState::spawn_decay`] lets the latency average fade every
+//! [`DECAY_INTERVAL`] so an idle relay gets back to `Normal`.
+//!
+//! Shedding is off unless `load_shedding.enabled` is set.
+
+use crate::config::LoadSheddingSettings;
+use crate::metrics;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+pub const OVERLOADED_MESSAGE: &str = "error: overloaded, retry later";
+
+/// Pressure (relative to the configured thresholds) at which each level is entered.
+const ELEVATED_PRESSURE: f64 = 1.0;
+const HIGH_PRESSURE: f64 = 1.5;
+const CRITICAL_PRESSURE: f64 = 2.0;
+
+/// Weight of the newest sample in the latency moving average.
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+
+/// How often an idle relay's latency average decays
+pub const DECAY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LoadLevel {
+    Normal = 0,
+    Elevated = 1,
+    High = 2,
+    Critical = 3,
+}
+
+impl LoadLevel {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LoadLevel::Normal,
+            1 => LoadLevel::Elevated,
+            2 => LoadLevel::High,
+            _ => LoadLevel::Critical,
+        }
+    }
+
+    fn from_pressure(pressure: f64) -> Self {
+        if pressure >= CRITICAL_PRESSURE {
+            LoadLevel::Critical
+        } else if pressure >= HIGH_PRESSURE {
+            LoadLevel::High
+        } else if pressure >= ELEVATED_PRESSURE {
+            LoadLevel::Elevated
+        } else {
+            LoadLevel::Normal
+        }
+    }
+
+    /// Pressure needed to enter this level.
+    fn entry_pressure(self) -> f64 {
+        match self {
+            LoadLevel::Normal => 0.0,
+            LoadLevel::Elevated => ELEVATED_PRESSURE,
+            LoadLevel::High => HIGH_PRESSURE,
+            LoadLevel::Critical => CRITICAL_PRESSURE,
+        }
+    }
+
+    fn step_down(self) -> Self {
+        match self {
+            LoadLevel::Normal | LoadLevel::Elevated => LoadLevel::Normal,
+            LoadLevel::High => LoadLevel::Elevated,
+            LoadLevel::Critical => LoadLevel::High,
+        }
+    }
+}
+
+/// What to do with an inbound EVENT under the current load level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventAction {
+    Accept,
+    Delay(Duration),
+    Reject,
+}
+
+/// Shared view of relay load, consulted by the REQ and EVENT paths.
+#[derive(Debug)]
+pub struct LoadState {
+    settings: LoadSheddingSettings,
+    level: AtomicU8,
+    latency_ewma_us: AtomicU64,
+    in_flight: AtomicUsize,
+    /// Latency samples recorded, and the count at the last decay tick
+    samples: AtomicU64,
+    samples_at_tick: AtomicU64,
+}
+
+impl LoadState {
+    pub fn new(settings: LoadSheddingSettings) -> Self {
+        Self {
+            settings,
+            level: AtomicU8::new(LoadLevel::Normal as u8),
+            latency_ewma_us: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            samples: AtomicU64::new(0),
+            samples_at_tick: AtomicU64::new(0),
+        }
+    }
+
+    pub fn level(&self) -> LoadLevel {
+        LoadLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Records the processing latency of one inbound EVENT and re-evaluates
+    /// the load level.
+    pub fn record_latency(&self, latency: Duration) -> LoadLevel {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.update_latency(latency)
+    }
+
+    /// Decays the latency average as if an instant EVENT was processed,
+    /// unless a real one was since the last call, and re-evaluates the level.
+    pub fn decay(&self) -> LoadLevel {
+        let samples = self.samples.load(Ordering::Relaxed);
+        if self.samples_at_tick.swap(samples, Ordering::Relaxed) != samples {
+            return self.level();
+        }
+        self.update_latency(Duration::ZERO)
+    }
+
+    /// Decays the load every [`DECAY_INTERVAL`] until shutdown
+    pub fn spawn_decay(self: Arc<Self>, cancellation_token: CancellationToken) {
+        if !self.settings.enabled {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(DECAY_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        self.decay();
+                    }
+                }
+            }
+        });
+    }
+
+    fn update_latency(&self, latency: Duration) -> LoadLevel {
+        let sample = latency.as_micros().min(u64::MAX as u128) as f64;
+        let previous = self.latency_ewma_us.load(Ordering::Relaxed) as f64;
+        let updated = if previous == 0.0 {
+            sample
+        } else {
+            previous + LATENCY_EWMA_ALPHA * (sample - previous)
+        };
+        self.latency_ewma_us
+            .store(updated as u64, Ordering::Relaxed);
+
+        self.observe(Duration::from_micros(updated as u64), self.in_flight())
+    }
+
+    /// Feeds a load signal into the state machine and returns the new level.
+    ///
+    /// Levels rise immediately to whatever the pressure calls for, but only
+    /// fall one step at a time, and only once the pressure is below the
+    /// current level's entry point scaled by `exit_ratio`.
+    pub fn observe(&self, latency: Duration, in_flight: usize) -> LoadLevel {
+        if !self.settings.enabled {
+            return LoadLevel::Normal;
+        }
+
+        let pressure = self.pressure(latency, in_flight);
+        let current = self.level();
+        let target = LoadLevel::from_pressure(pressure);
+
+        let next = if target > current {
+            target
+        } else if target < current && pressure < current.entry_pressure() * self.settings.exit_ratio
+        {
+            current.step_down()
+        } else {
+            current
+        };
+
+        if next != current {
+            self.level.store(next as u8, Ordering::Relaxed);
+            info!(
+                "Load level changed from {:?} to {:?} (pressure {:.2})",
+                current, next, pressure
+            );
+            metrics::load_level().set(next as u8 as f64);
+        }
+
+        next
+    }
+
+    fn pressure(&self, latency: Duration, in_flight: usize) -> f64 {
+        let latency_pressure = if self.settings.latency_threshold.is_zero() {
+            0.0
+        } else {
+            latency.as_secs_f64() / self.settings.latency_threshold.as_secs_f64()
+        };
+        let in_flight_pressure = if self.settings.max_in_flight == 0 {
+            0.0
+        } else {
+            in_flight as f64 / self.settings.max_in_flight as f64
+        };
+        latency_pressure.max(in_flight_pressure)
+    }
+
+    /// Whether a REQ should be closed instead of served.
+    pub fn should_shed_req(&self, authenticated: bool, historical: bool) -> bool {
+        !authenticated && historical && self.level() >= LoadLevel::Elevated
+    }
+
+    /// Decides how an inbound EVENT is handled under the current level.
+    pub fn event_action(&self, authenticated: bool, from_relay: bool) -> EventAction {
+        if from_relay {
+            return EventAction::Accept;
+        }
+
+        match (self.level(), authenticated) {
+            (LoadLevel::High | LoadLevel::Critical, false) => EventAction::Reject,
+            (LoadLevel::Critical, true) => EventAction::Delay(self.settings.authed_publish_delay),
+            _ => EventAction::Accept,
+        }
+    }
+
+    fn enter(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { state: self }
+    }
+}
+
+struct InFlightGuard<'a> {
+    state: &'a LoadState,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A REQ is historical unless every filter asks for live events only (`limit: 0`).
+fn is_historical_req(filters: &[&Filter]) -> bool {
+    filters.iter().any(|filter| filter.limit != Some(0))
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadSheddingMiddleware {
+    load_state: Arc<LoadState>,
+    relay_pubkey: PublicKey,
+}
+
+impl LoadSheddingMiddleware {
+    pub fn new(load_state: Arc<LoadState>, relay_pubkey: PublicKey) -> Self {
+        Self {
+            load_state,
+            relay_pubkey,
+        }
+    }
+}
+
+impl NostrMiddleware<()> for LoadSheddingMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let authenticated = ctx.state.read().await.authed_pubkey.is_some();
+
+        match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                if self
+                    .load_state
+                    .should_shed_req(authenticated, is_historical_req(&[&**filter]))
+                {
+                    debug!(
+                        "[{}] Shedding anonymous REQ {} under load",
+                        ctx.connection_id, subscription_id
+                    );
+                    metrics::load_shed("req").increment(1);
+                    ctx.send_message(RelayMessage::closed(
+                        subscription_id.clone().into_owned(),
+                        OVERLOADED_MESSAGE,
+                    ))?;
+                    return Ok(());
+                }
+                ctx.next().await
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                let filters: Vec<&Filter> = filters.iter().collect();
+                if self
+                    .load_state
+                    .should_shed_req(authenticated, is_historical_req(&filters))
+                {
+                    debug!(
+                        "[{}] Shedding anonymous REQ {} under load",
+                        ctx.connection_id, subscription_id
+                    );
+                    metrics::load_shed("req").increment(1);
+                    ctx.send_message(RelayMessage::closed(
+                        subscription_id.clone().into_owned(),
+                        OVERLOADED_MESSAGE,
+                    ))?;
+                    return Ok(());
+                }
+                ctx.next().await
+            }
+            Some(ClientMessage::Event(event)) => {
+                let from_relay = event.pubkey == self.relay_pubkey;
+                match self.load_state.event_action(authenticated, from_relay) {
+                    EventAction::Accept => {}
+                    EventAction::Reject => {
+                        debug!(
+                            "[{}] Shedding anonymous event {} under load",
+                            ctx.connection_id, event.id
+                        );
+                        metrics::load_shed("event").increment(1);
+                        ctx.send_message(RelayMessage::ok(event.id, false, OVERLOADED_MESSAGE))?;
+                        return Ok(());
+                    }
+                    EventAction::Delay(delay) => {
+                        metrics::load_shed("delayed_event").increment(1);
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+
+                let _guard = self.load_state.enter();
+                let started = Instant::now();
+                let result = ctx.next().await;
+                self.load_state.record_latency(started.elapsed());
+                result
+            }
+            _ => ctx.next().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_settings() -> LoadSheddingSettings {
+        LoadSheddingSettings {
+            enabled: true,
+            latency_threshold: Duration::from_millis(100),
+            max_in_flight: 100,
+            authed_publish_delay: Duration::from_millis(50),
+            exit_ratio: 0.8,
+        }
+    }
+
+    #[test]
+    fn test_staged_degradation_order() {
+        let state = LoadState::new(test_settings());
+
+        // Normal: everything goes through
+        assert_eq!(
+            state.observe(Duration::from_millis(10), 0),
+            LoadLevel::Normal
+        );
+        assert!(!state.should_shed_req(false, true));
+        assert_eq!(state.event_action(false, false), EventAction::Accept);
+        assert_eq!(state.event_action(true, false), EventAction::Accept);
+
+        // Elevated: only anonymous historical REQs are shed
+        assert_eq!(
+            state.observe(Duration::from_millis(110), 0),
+            LoadLevel::Elevated
+        );
+        assert!(state.should_shed_req(false, true));
+        assert!(!state.should_shed_req(false, false));
+        assert!(!state.should_shed_req(true, true));
+        assert_eq!(state.event_action(false, false), EventAction::Accept);
+        assert_eq!(state.event_action(true, false), EventAction::Accept);
+
+        // High: anonymous events are rejected too
+        assert_eq!(
+            state.observe(Duration::from_millis(160), 0),
+            LoadLevel::High
+        );
+        assert!(state.should_shed_req(false, true));
+        assert_eq!(state.event_action(false, false), EventAction::Reject);
+        assert_eq!(state.event_action(true, false), EventAction::Accept);
+
+        // Critical: authenticated publishes are slowed down, never rejected
+        assert_eq!(
+            state.observe(Duration::from_millis(10), 250),
+            LoadLevel::Critical
+        );
+        assert_eq!(state.event_action(false, false), EventAction::Reject);
+        assert_eq!(
+            state.event_action(true, false),
+            EventAction::Delay(Duration::from_millis(50))
+        );
+    }
+
+    #[test]
+    fn test_disabled_never_sheds() {
+        let state = LoadState::new(LoadSheddingSettings {
+            enabled: false,
+            ..test_settings()
+        });
+
+        assert_eq!(
+            state.observe(Duration::from_secs(10), 1000),
+            LoadLevel::Normal
+        );
+        assert!(!state.should_shed_req(false, true));
+        assert_eq!(state.event_action(false, false), EventAction::Accept);
+    }
+
+    #[test]
+    fn test_relay_events_never_shed() {
+        let state = LoadState::new(test_settings());
+        assert_eq!(
+            state.observe(Duration::from_secs(10), 1000),
+            LoadLevel::Critical
+        );
+
+        assert_eq!(state.event_action(false, true), EventAction::Accept);
+        assert_eq!(state.event_action(true, true), EventAction::Accept);
+    }
+
+    #[test]
+    fn test_hysteresis_prevents_flapping() {
+        let state = LoadState::new(test_settings());
+        assert_eq!(
+            state.observe(Duration::from_millis(105), 0),
+            LoadLevel::Elevated
+        );
+
+        // Dipping just below the threshold keeps the level
+        assert_eq!(
+            state.observe(Duration::from_millis(95), 0),
+            LoadLevel::Elevated
+        );
+        assert_eq!(
+            state.observe(Duration::from_millis(85), 0),
+            LoadLevel::Elevated
+        );
+
+        // Only a clear drop below the exit ratio releases it
+        assert_eq!(
+            state.observe(Duration::from_millis(70), 0),
+            LoadLevel::Normal
+        );
+    }
+
+    #[test]
+    fn test_recovery_steps_down_one_level_at_a_time() {
+        let state = LoadState::new(test_settings());
+        assert_eq!(
+            state.observe(Duration::from_millis(300), 0),
+            LoadLevel::Critical
+        );
+
+        assert_eq!(state.observe(Duration::ZERO, 0), LoadLevel::High);
+        assert_eq!(state.observe(Duration::ZERO, 0), LoadLevel::Elevated);
+        assert_eq!(state.observe(Duration::ZERO, 0), LoadLevel::Normal);
+    }
+
+    #[test]
+    fn test_record_latency_smooths_spikes() {
+        let state = LoadState::new(test_settings());
+        state.record_latency(Duration::from_millis(10));
+
+        // A single slow message does not push the relay into shedding
+        assert_eq!(
+            state.record_latency(Duration::from_millis(200)),
+            LoadLevel::Normal
+        );
+
+        // Sustained slowness does
+        let mut level = LoadLevel::Normal;
+        for _ in 0..20 {
+            level = state.record_latency(Duration::from_millis(200));
+        }
+        assert_eq!(level, LoadLevel::High);
+    }
+
+    #[test]
+    fn test_idle_relay_decays_back_to_normal() {
+        let state = LoadState::new(test_settings());
+        for _ in 0..20 {
+            state.record_latency(Duration::from_millis(200));
+        }
+        assert_eq!(state.level(), LoadLevel::High);
+
+        // A tick right after traffic leaves the average alone
+        let before = state.latency_ewma_us.load(Ordering::Relaxed);
+        assert_eq!(state.decay(), LoadLevel::High);
+        assert_eq!(state.latency_ewma_us.load(Ordering::Relaxed), before);
+
+        let mut ticks = 0;
+        while state.decay() != LoadLevel::Normal {
+            ticks += 1;
+            assert!(ticks < 100, "never decayed");
+        }
+
+        // Traffic since the last tick stops the decay again
+        state.record_latency(Duration::from_millis(200));
+        let before = state.latency_ewma_us.load(Ordering::Relaxed);
+        state.decay();
+        assert_eq!(state.latency_ewma_us.load(Ordering::Relaxed), before);
+    }
+
+    #[test]
+    fn test_live_only_reqs_are_not_historical() {
+        let live = Filter::new().kind(Kind::TextNote).limit(0);
+        let historical = Filter::new().kind(Kind::TextNote).limit(10);
+        let unbounded = Filter::new().kind(Kind::TextNote);
+
+        assert!(!is_historical_req(&[&live]));
+        assert!(is_historical_req(&[&live, &historical]));
+        assert!(is_historical_req(&[&unbounded]));
+    }
+}
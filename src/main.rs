@@ -15,7 +15,7 @@
 use anyhow::{Context, Result};
 use clap::Parser;
 use groups_relay::{config, groups::Groups, server, RelayDatabase};
-use nostr_sdk::RelayUrl;
+use nostr_sdk::{Keys, RelayUrl};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio_util::sync::CancellationToken;
@@ -41,9 +41,10 @@ struct Args {
     local_addr: Option<String>,
 }
 
-fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
+fn setup_tracing(log_format: config::LogFormat) -> tracing_appender::non_blocking::WorkerGuard {
     #[cfg(feature = "console")]
     {
+        let _ = log_format;
         use std::time::Duration;
         console_subscriber::ConsoleLayer::builder()
             .server_addr(([0, 0, 0, 0], 6669))
@@ -63,7 +64,7 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
         // Create non-blocking stdout writer
         let (non_blocking, guard) = tracing_appender::non_blocking(std::io::stdout());
 
-        fmt()
+        let subscriber = fmt()
             .with_writer(non_blocking)
             .with_env_filter(env_filter)
             .with_timer(fmt::time::SystemTime)
@@ -72,16 +73,22 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
             .with_thread_names(false)
             .with_file(false)
             .with_line_number(false)
-            .with_level(true)
-            .init();
+            .with_level(true);
+        match log_format {
+            config::LogFormat::Json => subscriber.json().flatten_event(true).init(),
+            config::LogFormat::Text => subscriber.init(),
+        }
 
         guard // Return the guard to keep it alive
     }
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = config::Config::new(&args.config_dir).context("Failed to load configuration")?;
+
     // Keep the guard alive for the entire program duration
-    let _guard = setup_tracing();
+    let _guard = setup_tracing(config.log_format());
 
     // Build runtime with explicit worker thread count to prevent deadlock
     // on low-CPU machines. Default is num_cpus, but with only 2 workers,
@@ -95,10 +102,10 @@ fn main() -> Result<()> {
         .build()
         .expect("Failed to create Tokio runtime");
 
-    runtime.block_on(async_main())
+    runtime.block_on(async_main(args, config))
 }
 
-async fn async_main() -> Result<()> {
+async fn async_main(args: Args, config: config::Config) -> Result<()> {
     // Initialize watchdog to detect runtime stalls
     // With panic(false), it logs diagnostics but doesn't crash
     let _watchdog = Watchdog::builder()
@@ -113,8 +120,6 @@ async fn async_main() -> Result<()> {
 
     tracing::info!("Watchdog initialized with 10s timeout");
 
-    let args = Args::parse();
-    let config = config::Config::new(&args.config_dir).context("Failed to load configuration")?;
     let relay_settings = config
         .get_settings()
         .context("Failed to get relay settings")?;
@@ -127,6 +132,40 @@ async fn async_main() -> Result<()> {
         db_path: relay_settings.db_path.clone(),
         max_limit: relay_settings.max_limit,
         max_subscriptions: relay_settings.max_subscriptions,
+        max_filters_per_req: relay_settings.max_filters_per_req,
+        max_filter_ids: relay_settings.max_filter_ids,
+        max_event_size: relay_settings.max_event_size,
+        max_event_tags: relay_settings.max_event_tags,
+        max_content_length: relay_settings.max_content_length,
+        max_member_tags: relay_settings.max_member_tags,
+        membership_state_window: relay_settings.membership_state_window,
+        directory_window: relay_settings.directory_window,
+        state_check_interval: relay_settings.state_check_interval,
+        max_previous_names: relay_settings.max_previous_names,
+        load_shedding: relay_settings.load_shedding.clone(),
+        admission: relay_settings.admission.clone(),
+        group_metrics: relay_settings.group_metrics.clone(),
+        bot_tokens: relay_settings.bot_tokens.clone(),
+        resubscribe_notice_after_auth: relay_settings.resubscribe_notice_after_auth,
+        query_pushdown: relay_settings.query_pushdown,
+        group_list_sync: relay_settings.group_list_sync,
+        replay_hints: relay_settings.replay_hints,
+        allowed_personal_kinds: relay_settings.allowed_personal_kinds.clone(),
+        private_messages: relay_settings.private_messages,
+        read_only: relay_settings.read_only,
+        allow_ephemeral_events: relay_settings.allow_ephemeral_events,
+        posting_policy: relay_settings.posting_policy.clone(),
+        replication: relay_settings.replication.clone(),
+        archive: relay_settings.archive.clone(),
+        webhook: relay_settings.webhook.clone(),
+        group_creation_limit: relay_settings.group_creation_limit.clone(),
+        group_webhooks: relay_settings.group_webhooks.clone(),
+        profile: relay_settings.profile.clone(),
+        spam_filter: relay_settings.spam_filter.clone(),
+        scope_policy: relay_settings.scope_policy.clone(),
+        paid_group_creation: relay_settings.paid_group_creation.clone(),
+        storage_quotas: relay_settings.storage_quotas.clone(),
+        group_validation: relay_settings.group_validation.clone(),
     };
 
     if let Some(target_url) = args.relay_url {
@@ -141,20 +180,24 @@ async fn async_main() -> Result<()> {
     let _relay_url = RelayUrl::parse(&settings.relay_url)
         .unwrap_or_else(|_| panic!("Invalid relay_url scheme: {}", settings.relay_url));
 
-    let relay_keys = relay_settings.relay_keys()?;
+    let admin_keys = relay_settings.admin_keys()?;
+    let relay_keys = admin_keys[0].clone();
     let _cancellation_token = CancellationToken::new();
 
     // Create database (CryptoHelper is created internally)
     let database = RelayDatabase::new(settings.db_path.clone()).await?;
     let database = Arc::new(database);
+    // Groups load in the background so large databases don't delay startup
     let groups = Arc::new(
-        Groups::load_groups(
+        Groups::new(
             Arc::clone(&database),
             relay_keys.public_key(),
             settings.relay_url.clone(),
         )
-        .await?,
+        .with_previous_relay_keys(admin_keys[1..].iter().map(Keys::public_key))
+        .with_max_previous_names(settings.max_previous_names),
     );
+    groups.start_background_load();
 
     server::run_server(settings, relay_keys, database, groups).await?;
 
@@ -0,0 +1,305 @@
+//! Profiles of a group's members.
+//!
+//! Profiles (kind 0) and contact lists (kind 3) belong to their author and
+//! don't carry a group, so reading the profiles of a group's members normally
+//! means knowing every member's pubkey. As a convenience, a REQ for only
+//! these kinds with an `#h` filter is read as "these lists for the members of
+//! the named groups": the `#h` filter is replaced with the members as
+//! `authors` before the REQ is served.
+//!
+//! The member list of a private group is only expanded for its members and
+//! the relay, other clients get a CLOSED. Groups the relay doesn't manage
+//! have no member list, so filters naming only those are left alone.
+
+use crate::groups::Groups;
+use crate::relay_keys::RelayIdentity;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Kinds a group `#h` filter is expanded to member authors for
+pub const MEMBER_LIST_KINDS: [Kind; 2] = [Kind::Metadata, Kind::ContactList];
+
+pub const AUTH_REQUIRED: &str = "auth-required: authentication required to access private groups";
+pub const ACCESS_DENIED: &str = "restricted: access denied to private group";
+
+/// What expansion did to a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expansion {
+    /// Not a member profile filter
+    Unchanged,
+    /// `#h` replaced with the members as authors
+    Expanded,
+    /// The groups have no members the filter can match
+    Empty,
+}
+
+/// Replaces the `#h` filter of a profile or contact list filter with the
+/// members of the named groups, returning the CLOSED reason on failure.
+pub fn expand(
+    filter: &mut Filter,
+    groups: &Groups,
+    scope: &Scope,
+    authed_pubkey: Option<&PublicKey>,
+) -> Result<Expansion, &'static str> {
+    let h = SingleLetterTag::lowercase(Alphabet::H);
+    let Some(group_ids) = filter.generic_tags.get(&h) else {
+        return Ok(Expansion::Unchanged);
+    };
+    let only_member_lists = filter.kinds.as_ref().is_some_and(|kinds| {
+        !kinds.is_empty() && kinds.iter().all(|kind| MEMBER_LIST_KINDS.contains(kind))
+    });
+    if !only_member_lists {
+        return Ok(Expansion::Unchanged);
+    }
+
+    let is_relay = authed_pubkey.is_some_and(|pubkey| groups.relay_keys().is_relay(pubkey));
+    let mut members = BTreeSet::new();
+    let mut managed = false;
+    for group_id in group_ids {
+        let Some(group_ref) = groups.get_group(scope, group_id) else {
+            continue;
+        };
+        let group = group_ref.value();
+        managed = true;
+
+        if group.metadata.private && !is_relay {
+            match authed_pubkey {
+                None => return Err(AUTH_REQUIRED),
+                Some(pubkey) if !group.is_member(pubkey) => return Err(ACCESS_DENIED),
+                Some(_) => {}
+            }
+        }
+        members.extend(group.members.keys().copied());
+    }
+    if !managed {
+        return Ok(Expansion::Unchanged);
+    }
+
+    if let Some(authors) = &filter.authors {
+        members.retain(|pubkey| authors.contains(pubkey));
+    }
+    if members.is_empty() {
+        return Ok(Expansion::Empty);
+    }
+
+    filter.generic_tags.remove(&h);
+    filter.authors = Some(members);
+    Ok(Expansion::Expanded)
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberProfilesMiddleware {
+    groups: Arc<Groups>,
+}
+
+impl MemberProfilesMiddleware {
+    pub fn new(groups: Arc<Groups>) -> Self {
+        Self { groups }
+    }
+}
+
+impl NostrMiddleware<()> for MemberProfilesMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        mut ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if !matches!(
+            ctx.message,
+            Some(ClientMessage::Req { .. }) | Some(ClientMessage::ReqMultiFilter { .. })
+        ) {
+            return ctx.next().await;
+        }
+
+        let (authed_pubkey, scope) = {
+            let state = ctx.state.read().await;
+            (state.authed_pubkey, state.subdomain().clone())
+        };
+
+        let outcome = match ctx.message.as_mut() {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                let subscription_id = subscription_id.clone().into_owned();
+                match expand(
+                    filter.to_mut(),
+                    &self.groups,
+                    &scope,
+                    authed_pubkey.as_ref(),
+                ) {
+                    Ok(Expansion::Empty) => Some((subscription_id, None)),
+                    Ok(_) => None,
+                    Err(reason) => Some((subscription_id, Some(reason))),
+                }
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                let subscription_id = subscription_id.clone().into_owned();
+                let mut denied = None;
+                filters.retain_mut(|filter| {
+                    match expand(filter, &self.groups, &scope, authed_pubkey.as_ref()) {
+                        Ok(expansion) => expansion != Expansion::Empty,
+                        Err(reason) => {
+                            denied = Some(reason);
+                            true
+                        }
+                    }
+                });
+                if denied.is_some() {
+                    Some((subscription_id, denied))
+                } else if filters.is_empty() {
+                    Some((subscription_id, None))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        match outcome {
+            None => ctx.next().await,
+            Some((subscription_id, None)) => {
+                debug!(
+                    "[{}] No group members match subscription {}, sending EOSE",
+                    ctx.connection_id, subscription_id
+                );
+                ctx.send_message(RelayMessage::eose(subscription_id))?;
+                Ok(())
+            }
+            Some((subscription_id, Some(reason))) => {
+                ctx.send_message(RelayMessage::closed(subscription_id, reason))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_profiles_of_group_members() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_personal_kinds(MEMBER_LIST_KINDS);
+        let admin = Keys::generate();
+        let members: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+        let without_profile = Keys::generate();
+        let outsider = Keys::generate();
+        let scope = Scope::Default;
+        let h = Tag::custom(TagKind::h(), ["members_group"]);
+
+        let context = |keys: &Keys| EventContext {
+            authed_pubkey: Some(keys.public_key()),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let handle = |event: Event, context: EventContext| {
+            let processor = &processor;
+            let database = &database;
+            let relay_keys = &relay_keys;
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await
+                    .unwrap();
+                apply_store_commands(database, relay_keys, commands)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // Groups are private by default
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(create, context(&admin)).await;
+        let add = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+            .tag(h.clone())
+            .tags(
+                members
+                    .iter()
+                    .chain([&without_profile])
+                    .map(|keys| Tag::public_key(keys.public_key())),
+            )
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(add, context(&admin)).await;
+
+        for keys in members.iter().chain([&outsider]) {
+            let profile = EventBuilder::new(Kind::Metadata, r#"{"name":"member"}"#)
+                .sign_with_keys(keys)
+                .unwrap();
+            handle(profile, context(keys)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let filter = Filter::new()
+            .kind(Kind::Metadata)
+            .custom_tag(SingleLetterTag::lowercase(Alphabet::H), "members_group");
+
+        let mut expanded = filter.clone();
+        assert_eq!(
+            expand(
+                &mut expanded,
+                &groups,
+                &scope,
+                Some(&members[0].public_key())
+            ),
+            Ok(Expansion::Expanded)
+        );
+        let profiles = database.query(vec![expanded], &scope).await.unwrap();
+        let authors: BTreeSet<PublicKey> = profiles.iter().map(|event| event.pubkey).collect();
+        let expected: BTreeSet<PublicKey> = members.iter().map(|keys| keys.public_key()).collect();
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(authors, expected);
+
+        // The member list of a private group is hidden from non-members
+        let mut denied = filter.clone();
+        assert_eq!(
+            expand(&mut denied, &groups, &scope, Some(&outsider.public_key())),
+            Err(ACCESS_DENIED)
+        );
+        assert_eq!(
+            expand(&mut denied, &groups, &scope, None),
+            Err(AUTH_REQUIRED)
+        );
+        assert_eq!(denied, filter);
+
+        // Other kinds keep their group filter
+        let mut messages = Filter::new()
+            .kind(Kind::Custom(9))
+            .custom_tag(SingleLetterTag::lowercase(Alphabet::H), "members_group");
+        assert_eq!(
+            expand(&mut messages, &groups, &scope, Some(&outsider.public_key())),
+            Ok(Expansion::Unchanged)
+        );
+    }
+}
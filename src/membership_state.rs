@@ -0,0 +1,456 @@
+//! Coalesced regeneration of group membership state.
+//!
+//! Every kind 9000 and 9001 regenerates the group's admins (39001) and
+//! members (39002) lists. Importing a large community one member per event
+//! would rewrite a growing members list thousands of times. The
+//! [`MembershipCoalescer`] lets the first change to a group's membership
+//! regenerate its lists as usual, and drops the lists from changes that follow
+//! within `window`, marking the group dirty instead. Once the window has
+//! passed, the current lists of dirty groups ride along with the next event
+//! the relay stores in their scope, so they reach live subscribers like any
+//! other state event. A burst of additions ends with a single pair of lists.
+//!
+//! Groups still dirty a window later, because their scope went quiet, are
+//! written by a flusher straight to the database; subscribers read those
+//! lists on their next REQ. While the relay is read-only, dirty groups stay
+//! queued and are written once writes resume.
+
+use crate::gc::GroupState;
+use crate::groups::{Groups, KIND_GROUP_ADMINS_39001, KIND_GROUP_MEMBERS_39002};
+use crate::read_only::ReadOnlyMode;
+use crate::utils::apply_store_commands;
+use crate::RelayDatabase;
+use anyhow::Result;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+type GroupKey = (Scope, String);
+
+pub struct MembershipCoalescer {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    window: Duration,
+    read_only: Option<Arc<ReadOnlyMode>>,
+    /// When each group's lists were last written
+    regenerated_at: DashMap<GroupKey, Instant>,
+    /// Groups whose lists changed since they were last written
+    dirty: DashMap<GroupKey, ()>,
+}
+
+impl std::fmt::Debug for MembershipCoalescer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MembershipCoalescer")
+            .field("window", &self.window)
+            .field("dirty", &self.dirty.len())
+            .finish()
+    }
+}
+
+impl MembershipCoalescer {
+    pub fn new(
+        groups: Arc<Groups>,
+        database: Arc<RelayDatabase>,
+        relay_keys: Keys,
+        window: Duration,
+    ) -> Self {
+        Self {
+            groups,
+            database,
+            relay_keys,
+            window,
+            read_only: None,
+            regenerated_at: DashMap::new(),
+            dirty: DashMap::new(),
+        }
+    }
+
+    /// Hold dirty groups back while the relay is read-only
+    pub fn with_read_only(mut self, read_only: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Drops the membership lists from the commands of a membership change
+    /// if the group's lists were written less than a window ago.
+    pub fn coalesce(&self, scope: &Scope, group_id: &str, commands: &mut Vec<StoreCommand>) {
+        let key = (scope.clone(), group_id.to_string());
+        let recent = self
+            .regenerated_at
+            .get(&key)
+            .is_some_and(|at| at.elapsed() < self.window);
+
+        if !recent {
+            // The lists in the commands are current
+            self.regenerated_at.insert(key.clone(), Instant::now());
+            self.dirty.remove(&key);
+            return;
+        }
+
+        commands.retain(|command| {
+            !matches!(
+                command,
+                StoreCommand::SaveUnsignedEvent(event, _, _)
+                    if event.kind == KIND_GROUP_ADMINS_39001
+                        || event.kind == KIND_GROUP_MEMBERS_39002
+            )
+        });
+        self.dirty.insert(key, ());
+    }
+
+    /// Whether the group has membership lists waiting to be written
+    pub fn is_pending(&self, scope: &Scope, group_id: &str) -> bool {
+        self.dirty
+            .contains_key(&(scope.clone(), group_id.to_string()))
+    }
+
+    /// Takes the lists of the scope's dirty groups whose window has passed,
+    /// to be stored along with an event of the scope.
+    pub fn take_due(&self, scope: &Scope) -> Vec<StoreCommand> {
+        let due: Vec<GroupKey> = self
+            .dirty
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| &key.0 == scope && self.elapsed(key) >= self.window)
+            .collect();
+
+        let mut commands = Vec::new();
+        for key in due {
+            if self.dirty.remove(&key).is_none() {
+                continue;
+            }
+            match self.lists(&key) {
+                Ok(lists) => {
+                    self.regenerated_at.insert(key, Instant::now());
+                    commands.extend(lists);
+                }
+                Err(e) => warn!("Failed to regenerate membership lists of {}: {}", key.1, e),
+            }
+        }
+        commands
+    }
+
+    /// Writes the current membership lists of every dirty group, returning
+    /// how many groups were written.
+    pub async fn flush(&self) -> Result<usize> {
+        self.flush_older_than(Duration::ZERO).await
+    }
+
+    /// Writes the lists of dirty groups last written at least `age` ago
+    async fn flush_older_than(&self, age: Duration) -> Result<usize> {
+        if self
+            .read_only
+            .as_ref()
+            .is_some_and(|mode| mode.is_enabled())
+        {
+            return Ok(0);
+        }
+
+        let keys: Vec<GroupKey> = self
+            .dirty
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| self.elapsed(key) >= age)
+            .collect();
+        let mut flushed = 0;
+
+        for key in keys {
+            if self.dirty.remove(&key).is_none() {
+                // Taken by an event in the meantime
+                continue;
+            }
+            let commands = self.lists(&key)?;
+            if commands.is_empty() {
+                continue;
+            }
+            self.regenerated_at.insert(key.clone(), Instant::now());
+            if let Err(e) = apply_store_commands(&self.database, &self.relay_keys, commands).await {
+                // Try again on the next flush
+                self.dirty.insert(key, ());
+                return Err(e);
+            }
+            flushed += 1;
+        }
+
+        Ok(flushed)
+    }
+
+    /// The current admins and members lists of a group, none once it's gone
+    fn lists(&self, key: &GroupKey) -> Result<Vec<StoreCommand>> {
+        let (scope, group_id) = key;
+        let Some(group) = self.groups.get_group(scope, group_id) else {
+            return Ok(Vec::new());
+        };
+        let group = group.value();
+        let relay_pubkey = self.groups.relay_keys();
+        Ok(vec![
+            StoreCommand::SaveUnsignedEvent(
+                group.generate_admins_event(relay_pubkey)?,
+                scope.clone(),
+                None,
+            ),
+            StoreCommand::SaveUnsignedEvent(
+                group.generate_members_event(relay_pubkey),
+                scope.clone(),
+                None,
+            ),
+        ])
+    }
+
+    /// Time since the group's lists were last written
+    fn elapsed(&self, key: &GroupKey) -> Duration {
+        self.regenerated_at
+            .get(key)
+            .map_or(Duration::MAX, |at| at.elapsed())
+    }
+
+    /// Every window, writes the groups no event took the lists of for a
+    /// whole window, and every dirty group once more on shutdown.
+    pub fn spawn_flusher(self: Arc<Self>, cancellation_token: CancellationToken) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.window);
+            loop {
+                let shutting_down = tokio::select! {
+                    _ = cancellation_token.cancelled() => true,
+                    _ = ticker.tick() => false,
+                };
+
+                let flushed = if shutting_down {
+                    self.flush().await
+                } else {
+                    self.flush_older_than(self.window * 2).await
+                };
+                match flushed {
+                    Ok(0) => {}
+                    Ok(flushed) => debug!("Wrote coalesced membership lists of {} groups", flushed),
+                    Err(e) => warn!("Failed to write coalesced membership lists: {}", e),
+                }
+                if shutting_down {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Write times and dirty flags of deleted groups are never read again.
+impl GroupState for MembershipCoalescer {
+    fn name(&self) -> &'static str {
+        "membership_state"
+    }
+
+    fn group_keys(&self) -> Vec<(Scope, String)> {
+        self.regenerated_at
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    fn forget_group(&self, scope: &Scope, group_id: &str) -> usize {
+        let key = (scope.clone(), group_id.to_string());
+        self.dirty.remove(&key);
+        self.regenerated_at.remove(&key).map_or(0, |_| 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_bulk_additions_end_with_one_members_list() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let read_only = Arc::new(ReadOnlyMode::new(false));
+        let coalescer = Arc::new(
+            MembershipCoalescer::new(
+                groups.clone(),
+                database.clone(),
+                relay_keys.clone(),
+                Duration::from_secs(60),
+            )
+            .with_read_only(read_only.clone()),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_membership_coalescer(coalescer.clone());
+        let admin = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["bulk_group"]);
+        let members: Vec<PublicKey> = (0..500).map(|_| Keys::generate().public_key()).collect();
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h_tag.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        let commands = processor
+            .handle_event(create, Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap();
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let mut members_lists = 0;
+        for member in &members {
+            let add = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+                .tags([h_tag.clone(), Tag::public_key(*member)])
+                .sign_with_keys(&admin)
+                .unwrap();
+            let commands = processor
+                .handle_event(add, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+            members_lists += commands
+                .iter()
+                .filter(|command| {
+                    matches!(
+                        command,
+                        StoreCommand::SaveUnsignedEvent(event, _, _)
+                            if event.kind == KIND_GROUP_MEMBERS_39002
+                    )
+                })
+                .count();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+        }
+        // Only the first addition in the window regenerated the list
+        assert_eq!(members_lists, 1);
+
+        // Queued while the relay is read-only
+        read_only.set(true);
+        assert_eq!(coalescer.flush().await.unwrap(), 0);
+        read_only.set(false);
+
+        assert_eq!(coalescer.flush().await.unwrap(), 1);
+        assert_eq!(coalescer.flush().await.unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(30));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let lists = database
+            .query(
+                vec![Filter::new()
+                    .kind(KIND_GROUP_MEMBERS_39002)
+                    .identifier("bulk_group")],
+                &Scope::Default,
+            )
+            .await
+            .unwrap();
+        assert_eq!(lists.len(), 1);
+        let listed: Vec<PublicKey> = lists.first().unwrap().tags.public_keys().copied().collect();
+        assert_eq!(listed.len(), 501);
+        for member in &members {
+            assert!(listed.contains(member));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_due_lists_ride_along_with_the_next_event() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let window = Duration::from_millis(100);
+        let coalescer = Arc::new(MembershipCoalescer::new(
+            groups.clone(),
+            database.clone(),
+            relay_keys.clone(),
+            window,
+        ));
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_membership_coalescer(coalescer.clone());
+        let admin = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h_tag = Tag::custom(TagKind::h(), ["ride_along"]);
+        let handle = |event: Event| {
+            let processor = &processor;
+            let context = &context;
+            async move {
+                processor
+                    .handle_event(event, Arc::new(RwLock::new(())), context)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h_tag.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(create).await;
+
+        let first = Keys::generate().public_key();
+        let late = Keys::generate().public_key();
+        for member in [first, late] {
+            let add = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+                .tags([h_tag.clone(), Tag::public_key(member)])
+                .sign_with_keys(&admin)
+                .unwrap();
+            handle(add).await;
+        }
+        assert!(coalescer.is_pending(&Scope::Default, "ride_along"));
+
+        // The coalesced member can read the group right away
+        let readable = groups
+            .readable_group_ids(&Scope::Default, Some(&late))
+            .unwrap();
+        assert!(readable.contains("ride_along"));
+
+        tokio::time::sleep(window).await;
+        let message = EventBuilder::new(Kind::Custom(9), "hello")
+            .tag(h_tag.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        let commands = handle(message).await;
+        let members_list = commands
+            .iter()
+            .find_map(|command| match command {
+                StoreCommand::SaveUnsignedEvent(event, _, _)
+                    if event.kind == KIND_GROUP_MEMBERS_39002 =>
+                {
+                    Some(event)
+                }
+                _ => None,
+            })
+            .expect("the due members list is stored with the message");
+        let listed: Vec<PublicKey> = members_list.tags.public_keys().copied().collect();
+        assert!(listed.contains(&first));
+        assert!(listed.contains(&late));
+        assert!(!coalescer.is_pending(&Scope::Default, "ride_along"));
+        assert_eq!(coalescer.flush().await.unwrap(), 0);
+    }
+}
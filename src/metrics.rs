@@ -28,6 +28,11 @@ pub fn active_subscriptions() -> Gauge {
     metrics::gauge!("active_subscriptions")
 }
 
+/// Connections that sent a message, per scope
+pub fn connections_by_scope(scope: &str) -> Gauge {
+    metrics::gauge!("connections_by_scope", "scope" => scope.to_string())
+}
+
 /// Total groups created counter
 pub fn groups_created() -> Counter {
     metrics::counter!("groups_created")
@@ -90,6 +95,133 @@ pub fn groups_by_privacy(private: bool, closed: bool) -> Gauge {
     metrics::gauge!("groups_by_privacy", "private" => private.to_string(), "closed" => closed.to_string())
 }
 
+/// Current load shedding level (0 = normal, 3 = critical)
+pub fn load_level() -> Gauge {
+    metrics::gauge!("load_level")
+}
+
+/// Messages shed or delayed by the load shedding middleware
+pub fn load_shed(message_type: &'static str) -> Counter {
+    metrics::counter!("load_shed_total", "type" => message_type)
+}
+
+/// Current adaptive admission budget in events per second
+pub fn admission_budget() -> Gauge {
+    metrics::gauge!("admission_budget")
+}
+
+/// Events shed for exceeding the admission budget
+pub fn admission_shed() -> Counter {
+    metrics::counter!("admission_shed")
+}
+
+/// Repeated REQs given an automatic `since` after a reconnect
+pub fn replay_hints() -> Counter {
+    metrics::counter!("replay_hints")
+}
+
+/// Events rejected by the processor, by reason code and event kind
+pub fn rejected_events(reason: &'static str, kind: u16) -> Counter {
+    metrics::counter!("rejected_events", "reason" => reason, "kind" => get_kind_label(kind as u32))
+}
+
+/// Group events failing a validation check, by check and validation mode
+pub fn group_validation_violations(check: &'static str, mode: &'static str) -> Counter {
+    metrics::counter!("group_validation_violations", "check" => check, "mode" => mode)
+}
+
+/// Events successfully pushed to all peer relays
+pub fn replicated_events() -> Counter {
+    metrics::counter!("replicated_events")
+}
+
+/// Events that could not be pushed to some peer relay after retrying
+pub fn replication_failures() -> Counter {
+    metrics::counter!("replication_failures")
+}
+
+/// Events dropped because the replication outbox was full
+pub fn replication_dropped() -> Counter {
+    metrics::counter!("replication_dropped")
+}
+
+/// Events delivered to an archive relay
+pub fn archived_events(relay: &str) -> Counter {
+    metrics::counter!("archived_events", "relay" => relay.to_string())
+}
+
+/// Events dropped because an archive relay's queue was full
+pub fn archive_dropped(relay: &str) -> Counter {
+    metrics::counter!("archive_dropped", "relay" => relay.to_string())
+}
+
+/// Events waiting to be delivered to an archive relay
+pub fn archive_queue_depth(relay: &str) -> Gauge {
+    metrics::gauge!("archive_queue_depth", "relay" => relay.to_string())
+}
+
+/// Seconds between an event's creation and its delivery to an archive relay
+pub fn archive_lag_seconds(relay: &str) -> Gauge {
+    metrics::gauge!("archive_lag_seconds", "relay" => relay.to_string())
+}
+
+/// Webhook deliveries by outcome (delivered, failed, dropped)
+pub fn webhook_deliveries(status: &'static str) -> Counter {
+    metrics::counter!("webhook_deliveries", "status" => status)
+}
+
+/// Group webhook deliveries by outcome (delivered, failed, dropped,
+/// rate_limited, disabled)
+pub fn group_webhook_deliveries(status: &'static str) -> Counter {
+    metrics::counter!("group_webhook_deliveries", "status" => status)
+}
+
+/// Entries of deleted groups removed by garbage collection, by structure
+pub fn gc_removed_entries(state: &'static str) -> Counter {
+    metrics::counter!("gc_removed_entries", "state" => state)
+}
+
+/// Events counted against the storage quota of a scope
+pub fn scope_stored_events(scope: &str) -> Gauge {
+    metrics::gauge!("scope_stored_events", "scope" => scope.to_string())
+}
+
+/// Bytes counted against the storage quota of a scope
+pub fn scope_stored_bytes(scope: &str) -> Gauge {
+    metrics::gauge!("scope_stored_bytes", "scope" => scope.to_string())
+}
+
+/// Group state events regenerated by the integrity checker, by kind
+pub fn state_event_repairs(kind: u16) -> Counter {
+    metrics::counter!("state_event_repairs", "kind" => kind.to_string())
+}
+
+/// Events dropped because they were already replicated (outbound) or came
+/// back from another relay after we forwarded them (inbound)
+pub fn replication_loop_drops(direction: &'static str) -> Counter {
+    metrics::counter!("replication_loop_drops", "direction" => direction)
+}
+
+/// Time taken to load group state, for a single group or the full load
+pub fn group_load_duration(load: &'static str) -> Histogram {
+    metrics::histogram!("group_load_duration_seconds", "load" => load)
+}
+
+/// REQ filters seen by query pushdown by outcome (narrowed, empty)
+pub fn query_pushdown(outcome: &'static str) -> Counter {
+    metrics::counter!("query_pushdown_filters", "outcome" => outcome)
+}
+
+/// How long connections lasted, recorded when they close
+pub fn connection_session_duration() -> Histogram {
+    metrics::histogram!("connection_session_duration_seconds")
+}
+
+/// Bytes a client sent over its connection, recorded when it closes
+pub fn connection_session_bytes_in() -> Histogram {
+    metrics::histogram!("connection_session_bytes_in")
+}
+
 /// Sets up the Prometheus recorder and returns a handle that can be used
 /// to expose the /metrics endpoint.
 pub fn setup_metrics() -> Result<PrometheusHandle, anyhow::Error> {
@@ -123,6 +255,111 @@ pub fn setup_metrics() -> Result<PrometheusHandle, anyhow::Error> {
                 "active_subscriptions",
                 "Number of active REQ subscriptions across all connections"
             );
+            describe_gauge!(
+                "connections_by_scope",
+                "Number of live connections per scope, counted from their first message"
+            );
+
+            describe_gauge!(
+                "load_level",
+                "Current load shedding level (0 = normal, 3 = critical)"
+            );
+            describe_counter!(
+                "load_shed_total",
+                "Total number of messages shed or delayed under load by type"
+            );
+            describe_gauge!(
+                "admission_budget",
+                "Current adaptive admission budget in events per second"
+            );
+            describe_counter!(
+                "admission_shed",
+                "Total number of events shed for exceeding the admission budget"
+            );
+            describe_counter!(
+                "replay_hints",
+                "Total number of repeated REQs narrowed with an automatic since"
+            );
+            describe_counter!(
+                "rejected_events",
+                "Total number of events rejected by the processor, by reason and kind"
+            );
+            describe_counter!(
+                "group_validation_violations",
+                "Total number of group events failing a validation check, by check and mode"
+            );
+            describe_counter!(
+                "replicated_events",
+                "Total number of events pushed to all peer relays"
+            );
+            describe_counter!(
+                "replication_failures",
+                "Total number of events that failed to replicate after retries"
+            );
+            describe_counter!(
+                "replication_dropped",
+                "Total number of events dropped because the replication outbox was full"
+            );
+            describe_counter!(
+                "archived_events",
+                "Total number of events delivered to each archive relay"
+            );
+            describe_counter!(
+                "archive_dropped",
+                "Total number of events dropped because an archive queue was full"
+            );
+            describe_gauge!(
+                "archive_queue_depth",
+                "Number of events waiting to be delivered to each archive relay"
+            );
+            describe_gauge!(
+                "archive_lag_seconds",
+                "Age of the last event delivered to each archive relay"
+            );
+            describe_counter!(
+                "group_webhook_deliveries",
+                "Total number of group webhook deliveries by outcome"
+            );
+            describe_counter!(
+                "gc_removed_entries",
+                "Total number of entries of deleted groups removed by garbage collection"
+            );
+            describe_gauge!(
+                "scope_stored_events",
+                "Number of stored events counted against each scope's quota"
+            );
+            describe_gauge!(
+                "scope_stored_bytes",
+                "Bytes of stored events counted against each scope's quota"
+            );
+            describe_counter!(
+                "state_event_repairs",
+                "Total number of missing or stale group state events regenerated, by kind"
+            );
+            describe_counter!(
+                "replication_loop_drops",
+                "Total number of events dropped to break replication loops"
+            );
+            describe_counter!(
+                "webhook_deliveries",
+                "Total number of webhook deliveries by outcome"
+            );
+            describe_histogram!(
+                "group_load_duration_seconds",
+                "Time taken to load group state from the database"
+            );
+            describe_counter!(
+                "query_pushdown_filters",
+                "Total number of REQ filters narrowed to readable groups"
+            );
+            describe_histogram!(
+                "connection_session_duration_seconds",
+                "Duration of closed connections"
+            );
+            describe_histogram!(
+                "connection_session_bytes_in",
+                "Bytes received from clients over closed connections"
+            );
 
             let builder = PrometheusBuilder::new();
             let handle = builder.install_recorder()?;
@@ -0,0 +1,122 @@
+//! Group-aware NIP-70 protected events.
+//!
+//! Events carrying the `["-"]` tag may only be published by their
+//! authenticated author. Unlike a blanket NIP-70 check this lets protected
+//! events into managed groups: once the author check passes, the groups
+//! processor applies the normal group rules (protected events additionally
+//! require membership) and the event is stored with regular group visibility.
+
+use crate::rejections::{ReasonCode, Rejections};
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Checks the NIP-70 author rule, returning the reason and OK rejection
+/// message on failure.
+pub fn check_protected_event(
+    event: &Event,
+    authed_pubkey: Option<&PublicKey>,
+) -> Result<(), (ReasonCode, &'static str)> {
+    if !event.is_protected() {
+        return Ok(());
+    }
+
+    match authed_pubkey {
+        None => Err((
+            ReasonCode::AuthRequired,
+            "auth-required: this event may only be published by its author",
+        )),
+        Some(pubkey) if *pubkey != event.pubkey => Err((
+            ReasonCode::Restricted,
+            "restricted: this event may only be published by its author",
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GroupNip70Middleware {
+    rejections: Option<Arc<Rejections>>,
+}
+
+impl GroupNip70Middleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the events turned down
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+}
+
+impl NostrMiddleware<()> for GroupNip70Middleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(ClientMessage::Event(event)) = &ctx.message else {
+            return ctx.next().await;
+        };
+
+        let authed_pubkey = ctx.state.read().await.authed_pubkey;
+        if let Err((code, reason)) = check_protected_event(event, authed_pubkey.as_ref()) {
+            debug!(
+                "[{}] Rejecting protected event {}: {}",
+                ctx.connection_id, event.id, reason
+            );
+            if let Some(rejections) = &self.rejections {
+                rejections.record_event(event, code, reason);
+            }
+            ctx.send_message(RelayMessage::ok(event.id, false, reason))?;
+            return Ok(());
+        }
+
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_event, create_test_keys};
+
+    #[tokio::test]
+    async fn test_protected_event_requires_authenticated_author() {
+        let (_, author_keys, other_keys) = create_test_keys().await;
+        let event = create_test_event(
+            &author_keys,
+            9,
+            vec![
+                Tag::custom(TagKind::h(), ["private_group"]),
+                Tag::protected(),
+            ],
+        )
+        .await;
+
+        assert!(check_protected_event(&event, Some(&author_keys.public_key())).is_ok());
+        let (code, reason) = check_protected_event(&event, None).unwrap_err();
+        assert_eq!(code, ReasonCode::AuthRequired);
+        assert!(reason.starts_with("auth-required"));
+
+        // Re-published by another member
+        let (code, reason) =
+            check_protected_event(&event, Some(&other_keys.public_key())).unwrap_err();
+        assert_eq!(code, ReasonCode::Restricted);
+        assert!(reason.starts_with("restricted"));
+    }
+
+    #[tokio::test]
+    async fn test_unprotected_events_pass() {
+        let (_, author_keys, other_keys) = create_test_keys().await;
+        let event = create_test_event(&author_keys, 9, vec![]).await;
+
+        assert!(check_protected_event(&event, None).is_ok());
+        assert!(check_protected_event(&event, Some(&other_keys.public_key())).is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+//! NIP-98 HTTP authentication for the HTTP API.
+//!
+//! Requests carry `Authorization: Nostr <base64 event>`, where the event is a
+//! kind 27235 signed by the caller with `u` (absolute request URL) and
+//! `method` tags, created within [`MAX_AGE`] of now. Requests with a body must
+//! also carry a `payload` tag with the hex SHA-256 of the body, so a captured
+//! header can't authorize a different body. Each authorization event is
+//! accepted once: [`SeenAuthorizations`] remembers them until they expire.
+
+use axum::http::{HeaderMap, Method, Uri};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Maximum clock difference accepted for the auth event, in seconds
+pub const MAX_AGE: u64 = 60;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Nip98Error {
+    #[error("Missing Nostr authorization header")]
+    Missing,
+    #[error("Malformed authorization: {0}")]
+    Malformed(String),
+    #[error("Invalid authorization event signature")]
+    InvalidSignature,
+    #[error("Authorization event has the wrong kind")]
+    WrongKind,
+    #[error("Authorization event is too old or in the future")]
+    Expired,
+    #[error("Authorization event is for a different URL")]
+    UrlMismatch,
+    #[error("Authorization event is for a different method")]
+    MethodMismatch,
+    #[error("Authorization event is for a different body")]
+    PayloadMismatch,
+    #[error("Authorization event was already used")]
+    Replayed,
+}
+
+/// Authorization events already accepted, kept until they're too old to be
+/// accepted again
+#[derive(Debug, Default)]
+pub struct SeenAuthorizations {
+    seen: Mutex<HashMap<EventId, Timestamp>>,
+}
+
+impl SeenAuthorizations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the event, failing if it was seen before
+    fn insert(&self, event: &Event, now: Timestamp) -> Result<(), Nip98Error> {
+        let mut seen = self.seen.lock();
+        // Events are accepted up to MAX_AGE past their creation
+        seen.retain(|_, created_at| created_at.as_u64() + MAX_AGE >= now.as_u64());
+        if seen.insert(event.id, event.created_at).is_some() {
+            return Err(Nip98Error::Replayed);
+        }
+        Ok(())
+    }
+}
+
+/// Verifies the NIP-98 authorization of a request and returns the caller.
+///
+/// The `u` tag must match the request path and query, and its host the
+/// request's `Host` header when present. The scheme isn't compared since the
+/// relay usually runs behind a TLS-terminating proxy.
+pub fn verify_request(
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    body: &[u8],
+    seen: &SeenAuthorizations,
+) -> Result<PublicKey, Nip98Error> {
+    let header = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(Nip98Error::Missing)?;
+    let host = headers
+        .get(axum::http::header::HOST)
+        .and_then(|value| value.to_str().ok());
+
+    verify_authorization(
+        header,
+        &Request {
+            method,
+            uri,
+            host,
+            body,
+        },
+        seen,
+        Timestamp::now(),
+    )
+}
+
+/// What the authorization event has to match
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub host: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+pub fn verify_authorization(
+    header: &str,
+    request: &Request<'_>,
+    seen: &SeenAuthorizations,
+    now: Timestamp,
+) -> Result<PublicKey, Nip98Error> {
+    let Request {
+        method,
+        uri,
+        host,
+        body,
+    } = *request;
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or(Nip98Error::Missing)?
+        .trim();
+    let json = STANDARD
+        .decode(encoded)
+        .map_err(|e| Nip98Error::Malformed(e.to_string()))?;
+    let event = Event::from_json(json).map_err(|e| Nip98Error::Malformed(e.to_string()))?;
+
+    if event.kind != Kind::HttpAuth {
+        return Err(Nip98Error::WrongKind);
+    }
+    event.verify().map_err(|_| Nip98Error::InvalidSignature)?;
+
+    if now.as_u64().abs_diff(event.created_at.as_u64()) > MAX_AGE {
+        return Err(Nip98Error::Expired);
+    }
+
+    let tag_value = |name: &str| {
+        event
+            .tags
+            .iter()
+            .map(|tag| tag.as_slice())
+            .find(|tag| tag.first().is_some_and(|n| n == name))
+            .and_then(|tag| tag.get(1))
+            .cloned()
+    };
+
+    let url = tag_value("u")
+        .and_then(|u| Url::parse(&u).ok())
+        .ok_or(Nip98Error::UrlMismatch)?;
+    let expected_path = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or(uri.path());
+    let signed_path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    if signed_path != expected_path {
+        return Err(Nip98Error::UrlMismatch);
+    }
+    if let (Some(host), Some(signed_host)) = (host, url.host_str()) {
+        let signed_host = match url.port() {
+            Some(port) => format!("{signed_host}:{port}"),
+            None => signed_host.to_string(),
+        };
+        if !signed_host.eq_ignore_ascii_case(host) {
+            return Err(Nip98Error::UrlMismatch);
+        }
+    }
+
+    let signed_method = tag_value("method").ok_or(Nip98Error::MethodMismatch)?;
+    if !signed_method.eq_ignore_ascii_case(method.as_str()) {
+        return Err(Nip98Error::MethodMismatch);
+    }
+
+    match tag_value("payload") {
+        Some(payload) => {
+            if !payload.eq_ignore_ascii_case(&hex::encode(Sha256::digest(body))) {
+                return Err(Nip98Error::PayloadMismatch);
+            }
+        }
+        None if !body.is_empty() => return Err(Nip98Error::PayloadMismatch),
+        None => {}
+    }
+
+    seen.insert(&event, now)?;
+    Ok(event.pubkey)
+}
+
+#[cfg(test)]
+pub(crate) fn authorization_header(keys: &Keys, url: &str, method: &str, body: &[u8]) -> String {
+    let mut tags = vec![
+        Tag::custom(TagKind::custom("u"), [url]),
+        Tag::custom(TagKind::custom("method"), [method]),
+    ];
+    if !body.is_empty() {
+        tags.push(Tag::custom(
+            TagKind::custom("payload"),
+            [hex::encode(Sha256::digest(body))],
+        ));
+    }
+    let event = EventBuilder::new(Kind::HttpAuth, "")
+        .tags(tags)
+        .sign_with_keys(keys)
+        .unwrap();
+    format!("Nostr {}", STANDARD.encode(event.as_json()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://relay.example.com/api/groups/abc/members?limit=10";
+
+    fn uri() -> Uri {
+        "/api/groups/abc/members?limit=10".parse().unwrap()
+    }
+
+    fn request<'a>(method: &'a Method, uri: &'a Uri, host: Option<&'a str>) -> Request<'a> {
+        Request {
+            method,
+            uri,
+            host,
+            body: b"",
+        }
+    }
+
+    #[test]
+    fn test_valid_authorization() {
+        let keys = Keys::generate();
+        let header = authorization_header(&keys, URL, "GET", b"");
+
+        let pubkey = verify_authorization(
+            &header,
+            &request(&Method::GET, &uri(), Some("relay.example.com")),
+            &SeenAuthorizations::new(),
+            Timestamp::now(),
+        )
+        .unwrap();
+        assert_eq!(pubkey, keys.public_key());
+    }
+
+    #[test]
+    fn test_rejects_mismatched_requests() {
+        let keys = Keys::generate();
+        let header = authorization_header(&keys, URL, "GET", b"");
+        let now = Timestamp::now();
+        let seen = SeenAuthorizations::new();
+
+        assert_eq!(
+            verify_authorization(&header, &request(&Method::POST, &uri(), None), &seen, now),
+            Err(Nip98Error::MethodMismatch)
+        );
+        assert_eq!(
+            verify_authorization(
+                &header,
+                &request(
+                    &Method::GET,
+                    &"/api/groups/other/members?limit=10".parse().unwrap(),
+                    None
+                ),
+                &seen,
+                now
+            ),
+            Err(Nip98Error::UrlMismatch)
+        );
+        assert_eq!(
+            verify_authorization(
+                &header,
+                &request(&Method::GET, &uri(), Some("evil.example.com")),
+                &seen,
+                now
+            ),
+            Err(Nip98Error::UrlMismatch)
+        );
+        assert_eq!(
+            verify_authorization(
+                &header,
+                &request(&Method::GET, &uri(), None),
+                &seen,
+                Timestamp::from(now.as_u64() + MAX_AGE + 10)
+            ),
+            Err(Nip98Error::Expired)
+        );
+        assert_eq!(
+            verify_authorization(
+                "Bearer abc",
+                &request(&Method::GET, &uri(), None),
+                &seen,
+                now
+            ),
+            Err(Nip98Error::Missing)
+        );
+    }
+
+    #[test]
+    fn test_body_must_match_payload() {
+        let keys = Keys::generate();
+        let body = br#"{"enabled":true}"#;
+        let now = Timestamp::now();
+        let seen = SeenAuthorizations::new();
+        let uri = uri();
+        let post = |body: &'static [u8]| Request {
+            method: &Method::POST,
+            uri: &uri,
+            host: None,
+            body,
+        };
+
+        // Signed without a payload tag
+        let unbound = authorization_header(&keys, URL, "POST", b"");
+        assert_eq!(
+            verify_authorization(&unbound, &post(body), &seen, now),
+            Err(Nip98Error::PayloadMismatch)
+        );
+
+        let header = authorization_header(&keys, URL, "POST", body);
+        assert_eq!(
+            verify_authorization(&header, &post(br#"{"enabled":false}"#), &seen, now),
+            Err(Nip98Error::PayloadMismatch)
+        );
+        assert_eq!(
+            verify_authorization(&header, &post(body), &seen, now),
+            Ok(keys.public_key())
+        );
+    }
+
+    #[test]
+    fn test_rejects_replayed_authorization() {
+        let keys = Keys::generate();
+        let header = authorization_header(&keys, URL, "GET", b"");
+        let now = Timestamp::now();
+        let seen = SeenAuthorizations::new();
+        let get = request(&Method::GET, &uri(), None);
+
+        assert!(verify_authorization(&header, &get, &seen, now).is_ok());
+        assert_eq!(
+            verify_authorization(&header, &get, &seen, now),
+            Err(Nip98Error::Replayed)
+        );
+
+        // Forgotten once it can't be accepted anymore
+        let later = Timestamp::from(now.as_u64() + MAX_AGE + 1);
+        let other = EventBuilder::new(Kind::HttpAuth, "")
+            .custom_created_at(later)
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert!(seen.insert(&other, later).is_ok());
+        assert_eq!(seen.seen.lock().len(), 1);
+    }
+}
@@ -0,0 +1,378 @@
+//! Payment-gated group creation.
+//!
+//! To deter squatting, creating a group (9007), and optionally an invite
+//! (9009), can cost a fee. Once the group id checks out (free for a 9007,
+//! an existing group for a 9009), the groups event processor asks a
+//! [`PaymentVerifier`] whether the author paid for the event before it
+//! accepts it, and turns it down with a `payment-required:` OK pointing to
+//! the invoice otherwise. Whether one payment covers one creation or many is up to the
+//! verifier. The relay's own key never pays.
+//!
+//! [`CallbackPaymentVerifier`] is the reference implementation: allowlisted
+//! pubkeys never pay, and the rest are looked up with a POST to an external
+//! bridge, e.g. in front of LNbits or BTCPay:
+//!
+//! ```json
+//! request:  {"pubkey": "<hex>", "event_id": "<hex>", "action": "create_group"}
+//! response: {"paid": false, "invoice_url": "https://pay.example.com/i/123"}
+//! ```
+//!
+//! A bridge that can't be reached counts as unpaid.
+
+use crate::config::PaymentSettings;
+use crate::group::{KIND_GROUP_CREATE_9007, KIND_GROUP_CREATE_INVITE_9009};
+use crate::http_client::HttpClient;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt::{self, Debug};
+use tracing::warn;
+
+/// What a fee is charged for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaidAction {
+    CreateGroup,
+    CreateInvite,
+}
+
+impl PaidAction {
+    pub fn kind(&self) -> Kind {
+        match self {
+            PaidAction::CreateGroup => KIND_GROUP_CREATE_9007,
+            PaidAction::CreateInvite => KIND_GROUP_CREATE_INVITE_9009,
+        }
+    }
+}
+
+impl fmt::Display for PaidAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaidAction::CreateGroup => f.write_str("group creation"),
+            PaidAction::CreateInvite => f.write_str("invite creation"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Paid,
+    Unpaid { invoice_url: Option<String> },
+}
+
+#[async_trait]
+pub trait PaymentVerifier: Send + Sync + Debug {
+    /// Whether `pubkey` paid for `action`, taken by the event `event_id`
+    async fn check_paid(
+        &self,
+        pubkey: &PublicKey,
+        event_id: &EventId,
+        action: PaidAction,
+    ) -> PaymentStatus;
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackResponse {
+    paid: bool,
+    invoice_url: Option<String>,
+}
+
+/// Allowlisted pubkeys never pay, the rest are checked with the callback
+pub struct CallbackPaymentVerifier {
+    allowlist: HashSet<PublicKey>,
+    callback_url: Option<String>,
+    payments_url: Option<String>,
+    client: HttpClient,
+}
+
+impl Debug for CallbackPaymentVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackPaymentVerifier")
+            .field("allowlist", &self.allowlist.len())
+            .field("callback_url", &self.callback_url)
+            .finish()
+    }
+}
+
+impl CallbackPaymentVerifier {
+    pub fn new(settings: &PaymentSettings) -> Result<Self> {
+        let allowlist = settings
+            .allowlist
+            .iter()
+            .map(|pk| PublicKey::parse(pk))
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        Ok(Self {
+            allowlist,
+            callback_url: settings.callback_url.clone(),
+            payments_url: settings.payments_url.clone(),
+            client: HttpClient::new()?,
+        })
+    }
+
+    async fn ask_callback(
+        &self,
+        url: &str,
+        pubkey: &PublicKey,
+        event_id: &EventId,
+        action: PaidAction,
+    ) -> Result<CallbackResponse> {
+        let body = serde_json::to_vec(&json!({
+            "pubkey": pubkey.to_hex(),
+            "event_id": event_id.to_hex(),
+            "action": action,
+        }))?;
+        let (status, body) = self.client.post_json_for_body(url, body).await?;
+        if !status.is_success() {
+            anyhow::bail!("payment callback answered {status}");
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[async_trait]
+impl PaymentVerifier for CallbackPaymentVerifier {
+    async fn check_paid(
+        &self,
+        pubkey: &PublicKey,
+        event_id: &EventId,
+        action: PaidAction,
+    ) -> PaymentStatus {
+        if self.allowlist.contains(pubkey) {
+            return PaymentStatus::Paid;
+        }
+        let Some(url) = &self.callback_url else {
+            return PaymentStatus::Unpaid {
+                invoice_url: self.payments_url.clone(),
+            };
+        };
+
+        match self.ask_callback(url, pubkey, event_id, action).await {
+            Ok(CallbackResponse { paid: true, .. }) => PaymentStatus::Paid,
+            Ok(CallbackResponse { invoice_url, .. }) => PaymentStatus::Unpaid {
+                invoice_url: invoice_url.or_else(|| self.payments_url.clone()),
+            },
+            Err(e) => {
+                warn!("Payment check of {} for {} failed: {}", pubkey, action, e);
+                PaymentStatus::Unpaid {
+                    invoice_url: self.payments_url.clone(),
+                }
+            }
+        }
+    }
+}
+
+/// The actions charged for with these settings
+pub fn paid_actions(settings: &PaymentSettings) -> Vec<PaidAction> {
+    let mut actions = vec![PaidAction::CreateGroup];
+    if settings.invites {
+        actions.push(PaidAction::CreateInvite);
+    }
+    actions
+}
+
+/// The NIP-11 `fees` object advertising the charged kinds
+pub fn fees_document(settings: &PaymentSettings) -> Value {
+    let kinds: Vec<u16> = paid_actions(settings)
+        .iter()
+        .map(|action| action.kind().as_u16())
+        .collect();
+    json!({
+        "publication": [{
+            "kinds": kinds,
+            "amount": settings.amount,
+            "unit": settings.unit,
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::Groups;
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use nostr_lmdb::Scope;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[derive(Debug, Default)]
+    struct MockVerifier {
+        paid: AtomicBool,
+        checks: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl PaymentVerifier for MockVerifier {
+        async fn check_paid(
+            &self,
+            _pubkey: &PublicKey,
+            _event_id: &EventId,
+            _action: PaidAction,
+        ) -> PaymentStatus {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            if self.paid.load(Ordering::SeqCst) {
+                PaymentStatus::Paid
+            } else {
+                PaymentStatus::Unpaid {
+                    invoice_url: Some("https://pay.example.com/i/1".to_string()),
+                }
+            }
+        }
+    }
+
+    fn settings() -> PaymentSettings {
+        PaymentSettings {
+            amount: 21000,
+            unit: "msats".to_string(),
+            payments_url: Some("https://pay.example.com".to_string()),
+            callback_url: None,
+            allowlist: Vec::new(),
+            invites: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_group_creation_succeeds_once_paid() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let verifier = Arc::new(MockVerifier::default());
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_payment_verifier(verifier.clone(), [PaidAction::CreateGroup]);
+        let creator = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(creator.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(Tag::custom(TagKind::h(), ["paid_group"]))
+            .sign_with_keys(&creator)
+            .unwrap();
+
+        let error = processor
+            .handle_event(create.clone(), Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("payment-required: group creation"));
+        assert!(error.contains("https://pay.example.com/i/1"));
+        assert!(groups.get_group(&Scope::Default, "paid_group").is_none());
+
+        verifier.paid.store(true, Ordering::SeqCst);
+        processor
+            .handle_event(create, Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap();
+        assert!(groups.get_group(&Scope::Default, "paid_group").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_group_id_is_checked_before_payment() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let verifier = Arc::new(MockVerifier::default());
+        verifier.paid.store(true, Ordering::SeqCst);
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_payment_verifier(
+                verifier.clone(),
+                [PaidAction::CreateGroup, PaidAction::CreateInvite],
+            );
+        let creator = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(creator.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let no_id = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .sign_with_keys(&creator)
+            .unwrap();
+        let invite_to_missing = EventBuilder::new(KIND_GROUP_CREATE_INVITE_9009, "")
+            .tag(Tag::custom(TagKind::h(), ["missing"]))
+            .sign_with_keys(&creator)
+            .unwrap();
+        let create = || {
+            EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+                .tag(Tag::custom(TagKind::h(), ["taken"]))
+                .sign_with_keys(&creator)
+                .unwrap()
+        };
+
+        for event in [no_id, invite_to_missing] {
+            assert!(processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+                .is_err());
+        }
+        assert_eq!(verifier.checks.load(Ordering::SeqCst), 0);
+
+        processor
+            .handle_event(create(), Arc::new(RwLock::new(())), &context)
+            .await
+            .unwrap();
+        assert_eq!(verifier.checks.load(Ordering::SeqCst), 1);
+        assert!(processor
+            .handle_event(create(), Arc::new(RwLock::new(())), &context)
+            .await
+            .is_err());
+        assert_eq!(verifier.checks.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_and_fallback_invoice() {
+        let allowed = Keys::generate().public_key();
+        let mut settings = settings();
+        settings.allowlist = vec![allowed.to_hex()];
+        let verifier = CallbackPaymentVerifier::new(&settings).unwrap();
+
+        assert_eq!(
+            verifier
+                .check_paid(&allowed, &EventId::all_zeros(), PaidAction::CreateGroup)
+                .await,
+            PaymentStatus::Paid
+        );
+        assert_eq!(
+            verifier
+                .check_paid(
+                    &Keys::generate().public_key(),
+                    &EventId::all_zeros(),
+                    PaidAction::CreateGroup
+                )
+                .await,
+            PaymentStatus::Unpaid {
+                invoice_url: Some("https://pay.example.com".to_string())
+            }
+        );
+
+        assert_eq!(
+            fees_document(&settings)["publication"][0]["kinds"],
+            json!([9007])
+        );
+        settings.invites = true;
+        assert_eq!(
+            fees_document(&settings)["publication"][0]["kinds"],
+            json!([9007, 9009])
+        );
+    }
+}
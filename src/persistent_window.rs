@@ -0,0 +1,263 @@
+//! Fixed-window counters that survive restarts.
+//!
+//! Long abuse windows (hours or days) are useless if a deploy resets them, so
+//! [`PersistentWindow`] keeps its counters in a small LMDB environment owned
+//! by a [`WindowStore`], keyed by `(limiter, scope, subject)`. Counters are
+//! loaded lazily the first time a key is touched after startup and written
+//! back on a debounce by [`WindowStore::spawn_flusher`]. Short per-second
+//! limiters should stay in memory and not use this.
+
+use crate::utils::scope_name;
+use anyhow::Result;
+use dashmap::DashMap;
+use heed::types::{Bytes, Str};
+use heed::{Database, Env, EnvOpenOptions};
+use nostr_lmdb::Scope;
+use nostr_sdk::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+const MAP_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct WindowState {
+    /// Unix timestamp the current window started at
+    start: u64,
+    count: u32,
+}
+
+/// LMDB-backed storage shared by all persistent windows.
+pub struct WindowStore {
+    env: Env,
+    db: Database<Str, Bytes>,
+    dirty: Mutex<HashMap<String, WindowState>>,
+}
+
+impl std::fmt::Debug for WindowStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WindowStore").finish_non_exhaustive()
+    }
+}
+
+impl WindowStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        std::fs::create_dir_all(path.as_ref())?;
+        // SAFETY: the environment is only opened once per path by this process
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(1)
+                .open(path.as_ref())?
+        };
+
+        let mut wtxn = env.write_txn()?;
+        let db = env.create_database(&mut wtxn, Some("windows"))?;
+        wtxn.commit()?;
+
+        Ok(Arc::new(Self {
+            env,
+            db,
+            dirty: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn load(&self, key: &str) -> Result<Option<WindowState>> {
+        let rtxn = self.env.read_txn()?;
+        let Some(bytes) = self.db.get(&rtxn, key)? else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_slice(bytes)?))
+    }
+
+    fn mark_dirty(&self, key: String, state: WindowState) {
+        self.dirty
+            .lock()
+            .expect("window store lock poisoned")
+            .insert(key, state);
+    }
+
+    /// Writes pending counters to disk, returning how many were written.
+    pub fn flush(&self) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.dirty.lock().expect("window store lock poisoned"));
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut wtxn = self.env.write_txn()?;
+        for (key, state) in &pending {
+            self.db.put(&mut wtxn, key, &serde_json::to_vec(state)?)?;
+        }
+        wtxn.commit()?;
+        Ok(pending.len())
+    }
+
+    /// Flushes pending counters every `interval`, and once more on shutdown.
+    pub fn spawn_flusher(
+        self: Arc<Self>,
+        interval: Duration,
+        cancellation_token: CancellationToken,
+    ) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                let shutting_down = tokio::select! {
+                    _ = cancellation_token.cancelled() => true,
+                    _ = ticker.tick() => false,
+                };
+
+                match self.flush() {
+                    Ok(0) => {}
+                    Ok(written) => debug!("Persisted {} rate limit windows", written),
+                    Err(e) => warn!("Failed to persist rate limit windows: {}", e),
+                }
+
+                if shutting_down {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// A fixed-window counter per scope and subject, persisted in a [`WindowStore`].
+#[derive(Debug)]
+pub struct PersistentWindow {
+    name: &'static str,
+    limit: u32,
+    window: Duration,
+    store: Arc<WindowStore>,
+    counters: DashMap<String, WindowState>,
+}
+
+impl PersistentWindow {
+    pub fn new(name: &'static str, limit: u32, window: Duration, store: Arc<WindowStore>) -> Self {
+        Self {
+            name,
+            limit,
+            window,
+            store,
+            counters: DashMap::new(),
+        }
+    }
+
+    fn key(&self, scope: &Scope, subject: &str) -> String {
+        format!("{}:{}:{}", self.name, scope_name(scope), subject)
+    }
+
+    /// Current state of a key, loading it from disk the first time it's seen
+    fn current(&self, key: &str, now: u64) -> WindowState {
+        let state = match self.counters.get(key) {
+            Some(state) => *state,
+            None => {
+                let loaded = self.store.load(key).unwrap_or_else(|e| {
+                    warn!("Failed to load rate limit window {}: {}", key, e);
+                    None
+                });
+                let state = loaded.unwrap_or(WindowState {
+                    start: now,
+                    count: 0,
+                });
+                self.counters.insert(key.to_string(), state);
+                state
+            }
+        };
+
+        if now >= state.start + self.window.as_secs() {
+            WindowState {
+                start: now,
+                count: 0,
+            }
+        } else {
+            state
+        }
+    }
+
+    /// Returns how long the subject has to wait if its budget is used up.
+    pub fn check(&self, scope: &Scope, subject: &str) -> Option<Duration> {
+        let now = Timestamp::now().as_u64();
+        let state = self.current(&self.key(scope, subject), now);
+
+        (state.count >= self.limit)
+            .then(|| Duration::from_secs(state.start + self.window.as_secs() - now))
+    }
+
+    /// Counts one use of the subject's budget.
+    pub fn record(&self, scope: &Scope, subject: &str) {
+        let now = Timestamp::now().as_u64();
+        let key = self.key(scope, subject);
+        let mut state = self.current(&key, now);
+        state.count = state.count.saturating_add(1);
+
+        self.counters.insert(key.clone(), state);
+        self.store.mark_dirty(key, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_window_limits_each_subject() {
+        let tmp_dir = TempDir::new().unwrap();
+        let store = WindowStore::open(tmp_dir.path()).unwrap();
+        let window = PersistentWindow::new("test", 2, DAY, store);
+        let scope = Scope::Default;
+
+        window.record(&scope, "alice");
+        assert!(window.check(&scope, "alice").is_none());
+        window.record(&scope, "alice");
+        assert!(window.check(&scope, "alice").unwrap() <= DAY);
+
+        assert!(window.check(&scope, "bob").is_none());
+        assert!(window
+            .check(&Scope::named("other").unwrap(), "alice")
+            .is_none());
+    }
+
+    #[test]
+    fn test_cooldown_survives_reopen() {
+        let tmp_dir = TempDir::new().unwrap();
+        let scope = Scope::named("community").unwrap();
+
+        {
+            let store = WindowStore::open(tmp_dir.path()).unwrap();
+            let window = PersistentWindow::new("group_creation", 1, DAY, store.clone());
+            window.record(&scope, "alice");
+            assert!(window.check(&scope, "alice").is_some());
+            assert_eq!(store.flush().unwrap(), 1);
+        }
+
+        let store = WindowStore::open(tmp_dir.path()).unwrap();
+        let window = PersistentWindow::new("group_creation", 1, DAY, store.clone());
+        assert!(window.check(&scope, "alice").is_some());
+        assert!(window.check(&scope, "bob").is_none());
+
+        // Other limiters sharing the store don't see the counter
+        let other = PersistentWindow::new("join_cooldown", 1, DAY, store);
+        assert!(other.check(&scope, "alice").is_none());
+    }
+
+    #[test]
+    fn test_unflushed_counters_are_lost_on_restart() {
+        let tmp_dir = TempDir::new().unwrap();
+
+        {
+            let store = WindowStore::open(tmp_dir.path()).unwrap();
+            let window = PersistentWindow::new("test", 1, DAY, store);
+            window.record(&Scope::Default, "alice");
+        }
+
+        let store = WindowStore::open(tmp_dir.path()).unwrap();
+        let window = PersistentWindow::new("test", 1, DAY, store);
+        assert!(window.check(&Scope::Default, "alice").is_none());
+    }
+}
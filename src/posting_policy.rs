@@ -0,0 +1,336 @@
+//! Operator-defined posting policy.
+//!
+//! A declarative rules file lets operators enforce their published posting
+//! policy without writing Rust. Each rule optionally targets a scope and/or a
+//! group and can deny kinds, deny content matching a regex, require tags and
+//! cap the content length. Rejections reference the policy URL advertised in
+//! NIP-11. The file is polled and reloaded when it changes.
+//!
+//! ```yaml
+//! rules:
+//!   - group: "photography"
+//!     deny_kinds: [20]
+//!     deny_content: ["(?i)https?://\\S+\\.(png|jpe?g|gif)"]
+//!     max_length: 2000
+//!   - scope: "news"
+//!     require_tags: ["t"]
+//! ```
+
+use anyhow::{Context, Result};
+use config::{Config as ConfigTree, File};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+#[derive(Debug, Deserialize, Default)]
+pub struct PolicyFile {
+    #[serde(default)]
+    pub rules: Vec<PolicyRuleConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PolicyRuleConfig {
+    /// Subdomain the rule applies to ("default" for the root domain)
+    pub scope: Option<String>,
+    /// Group id the rule applies to
+    pub group: Option<String>,
+    #[serde(default)]
+    pub deny_kinds: Vec<u16>,
+    #[serde(default)]
+    pub deny_content: Vec<String>,
+    #[serde(default)]
+    pub require_tags: Vec<String>,
+    pub max_length: Option<usize>,
+}
+
+#[derive(Debug)]
+struct PolicyRule {
+    scope: Option<String>,
+    group: Option<String>,
+    deny_kinds: HashSet<u16>,
+    deny_content: Vec<Regex>,
+    require_tags: Vec<String>,
+    max_length: Option<usize>,
+}
+
+impl PolicyRule {
+    fn compile(config: PolicyRuleConfig) -> Result<Self> {
+        let deny_content = config
+            .deny_content
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Invalid policy regex '{pattern}'"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            scope: config.scope,
+            group: config.group,
+            deny_kinds: config.deny_kinds.into_iter().collect(),
+            deny_content,
+            require_tags: config.require_tags,
+            max_length: config.max_length,
+        })
+    }
+
+    fn applies_to(&self, scope: &Scope, group_id: Option<&str>) -> bool {
+        let scope_matches = match (&self.scope, scope) {
+            (None, _) => true,
+            (Some(name), Scope::Default) => name == "default",
+            (
+                Some(name),
+                Scope::Named {
+                    name: scope_name, ..
+                },
+            ) => name == scope_name,
+        };
+        let group_matches = match &self.group {
+            None => true,
+            Some(group) => group_id == Some(group.as_str()),
+        };
+        scope_matches && group_matches
+    }
+
+    /// Returns the reason the event violates this rule, if it does.
+    fn violation(&self, event: &Event) -> Option<String> {
+        if self.deny_kinds.contains(&event.kind.as_u16()) {
+            return Some(format!("kind {} is not allowed here", event.kind.as_u16()));
+        }
+
+        if self
+            .deny_content
+            .iter()
+            .any(|re| re.is_match(&event.content))
+        {
+            return Some("content is not allowed here".to_string());
+        }
+
+        if let Some(max_length) = self.max_length {
+            if event.content.chars().count() > max_length {
+                return Some(format!("content exceeds {max_length} characters"));
+            }
+        }
+
+        for tag in &self.require_tags {
+            if event.tags.find(TagKind::custom(tag.as_str())).is_none() {
+                return Some(format!("missing required '{tag}' tag"));
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug)]
+pub struct PostingPolicy {
+    url: Option<String>,
+    path: Option<PathBuf>,
+    rules: RwLock<Arc<Vec<PolicyRule>>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl PostingPolicy {
+    /// Builds a policy from already parsed rules.
+    pub fn from_rules(rules: Vec<PolicyRuleConfig>, url: Option<String>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(PolicyRule::compile)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            url,
+            path: None,
+            rules: RwLock::new(Arc::new(rules)),
+            modified: RwLock::new(None),
+        })
+    }
+
+    /// Loads a policy from a rules file (YAML, JSON or TOML by extension).
+    pub fn load(path: impl AsRef<Path>, url: Option<String>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut policy = Self::from_rules(read_rules(path)?, url)?;
+        *policy.modified.get_mut() = modified_time(path);
+        policy.path = Some(path.to_path_buf());
+        Ok(policy)
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// Reloads the rules file if it changed since the last load.
+    ///
+    /// Returns whether new rules were applied. A file that fails to parse
+    /// leaves the current rules in place.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+
+        let modified = modified_time(path);
+        if modified == *self.modified.read() {
+            return Ok(false);
+        }
+
+        let rules = read_rules(path)?
+            .into_iter()
+            .map(PolicyRule::compile)
+            .collect::<Result<Vec<_>>>()?;
+
+        *self.rules.write() = Arc::new(rules);
+        *self.modified.write() = modified;
+        Ok(true)
+    }
+
+    /// Polls the rules file until cancelled, applying changes as they appear.
+    pub fn spawn_reloader(
+        self: Arc<Self>,
+        interval: Duration,
+        cancellation_token: CancellationToken,
+    ) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        match self.reload_if_changed() {
+                            Ok(true) => info!("Reloaded posting policy"),
+                            Ok(false) => {}
+                            Err(e) => error!("Failed to reload posting policy: {:#}", e),
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Checks an event against the rules that apply to its scope and group.
+    pub fn check(&self, event: &Event, scope: &Scope) -> Result<(), relay_builder::Error> {
+        let group_id = event.tags.find(TagKind::h()).and_then(|t| t.content());
+        let rules = self.rules.read().clone();
+
+        for rule in rules.iter().filter(|rule| rule.applies_to(scope, group_id)) {
+            if let Some(reason) = rule.violation(event) {
+                let message = match &self.url {
+                    Some(url) => format!("{reason}, see posting policy: {url}"),
+                    None => reason,
+                };
+                return Err(relay_builder::Error::restricted(message));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_rules(path: &Path) -> Result<Vec<PolicyRuleConfig>> {
+    let file: PolicyFile = ConfigTree::builder()
+        .add_source(File::from(path))
+        .build()
+        .and_then(|config| config.try_deserialize())
+        .with_context(|| format!("Failed to read posting policy {}", path.display()))?;
+    Ok(file.rules)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_event, create_test_keys};
+    use std::io::Write;
+
+    const POLICY_URL: &str = "https://relay.example.com/policy";
+
+    fn write_policy(dir: &tempfile::TempDir, contents: &str) -> PathBuf {
+        let path = dir.path().join("policy.yml");
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_policy_rejects_denied_regex_and_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_policy(
+            &dir,
+            r#"
+rules:
+  - group: "photography"
+    deny_kinds: [20]
+    deny_content: ["(?i)\\.(png|jpe?g)"]
+"#,
+        );
+        let policy = PostingPolicy::load(&path, Some(POLICY_URL.to_string())).unwrap();
+        let (_, member_keys, _) = create_test_keys().await;
+        let h_tag = Tag::custom(TagKind::h(), ["photography"]);
+
+        let picture = create_test_event(&member_keys, 20, vec![h_tag.clone()]).await;
+        let err = policy.check(&picture, &Scope::Default).unwrap_err();
+        assert!(err.to_string().contains("kind 20 is not allowed"));
+        assert!(err.to_string().contains(POLICY_URL));
+
+        let image_link = EventBuilder::new(Kind::Custom(11), "look https://x.com/cat.JPG")
+            .tags(vec![h_tag.clone()])
+            .sign_with_keys(&member_keys)
+            .unwrap();
+        let err = policy.check(&image_link, &Scope::Default).unwrap_err();
+        assert!(err.to_string().contains("content is not allowed"));
+        assert!(err.to_string().contains(POLICY_URL));
+
+        let text = create_test_event(&member_keys, 11, vec![h_tag]).await;
+        assert!(policy.check(&text, &Scope::Default).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_policy_rules_are_scoped() {
+        let policy = PostingPolicy::from_rules(
+            vec![PolicyRuleConfig {
+                scope: Some("news".to_string()),
+                require_tags: vec!["t".to_string()],
+                max_length: Some(5),
+                ..Default::default()
+            }],
+            None,
+        )
+        .unwrap();
+        let (_, member_keys, _) = create_test_keys().await;
+        let event = create_test_event(&member_keys, 11, vec![]).await;
+
+        assert!(policy.check(&event, &Scope::Default).is_ok());
+
+        let news = Scope::named("news").unwrap();
+        let err = policy.check(&event, &news).unwrap_err();
+        assert!(err.to_string().contains("missing required 't' tag"));
+    }
+
+    #[tokio::test]
+    async fn test_policy_hot_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_policy(&dir, "rules: []\n");
+        let policy = PostingPolicy::load(&path, None).unwrap();
+        let (_, member_keys, _) = create_test_keys().await;
+        let event = create_test_event(&member_keys, 20, vec![]).await;
+
+        assert!(policy.check(&event, &Scope::Default).is_ok());
+        assert!(!policy.reload_if_changed().unwrap());
+
+        // Make sure the modification time moves forward
+        std::thread::sleep(Duration::from_millis(1100));
+        write_policy(&dir, "rules:\n  - deny_kinds: [20]\n");
+
+        assert!(policy.reload_if_changed().unwrap());
+        assert!(policy.check(&event, &Scope::Default).is_err());
+    }
+}
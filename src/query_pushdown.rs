@@ -0,0 +1,510 @@
+//! Narrows group content REQs to the groups the client can read.
+//!
+//! A REQ like `{"kinds":[9],"limit":50}` from a non-member matches every
+//! group's messages, and the events of private groups are only dropped by
+//! `can_see_event` after they have been fetched. On relays where most groups
+//! are private, filling the limit can take many database windows. When
+//! enabled, this middleware adds the client's readable groups as an `#h`
+//! filter, so the database only returns events the client may see.
+//!
+//! Filters that name groups with `#h` or `#d` are left to `verify_filters`,
+//! and so are filters for relay-generated kinds, which carry a `d` tag but
+//! no `h` tag.
+//! The rewrite drops content of unmanaged groups and events without an h tag
+//! from such feeds, which is why it is opt-in.
+//!
+//! The narrowed filter also drives the live subscription, so it would miss
+//! groups the client joins or that become public later. The middleware
+//! remembers each connection's narrowed subscriptions and, with the next
+//! message it handles once the readable groups differ, sends CLOSED for them
+//! so the client re-sends its REQs.
+
+use crate::groups::{Groups, NON_GROUP_ALLOWED_KINDS, RELAY_GENERATED_KINDS};
+use crate::metrics;
+use dashmap::DashMap;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::debug;
+
+/// CLOSED reason for narrowed subscriptions once the readable groups change
+pub const MEMBERSHIP_CHANGED_MESSAGE: &str = "restricted: readable groups changed, re-send REQ";
+
+/// What pushdown did to a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushdown {
+    /// Not a group content filter, or it already names groups
+    Unchanged,
+    /// Restricted to the readable groups
+    Narrowed,
+    /// The client can't read any group, so nothing can match
+    Empty,
+}
+
+/// Restricts a group content filter to the readable groups
+pub fn push_down(filter: &mut Filter, readable: &BTreeSet<String>) -> Pushdown {
+    let h = SingleLetterTag::lowercase(Alphabet::H);
+    let d = SingleLetterTag::lowercase(Alphabet::D);
+    if filter.generic_tags.contains_key(&h) || filter.generic_tags.contains_key(&d) {
+        return Pushdown::Unchanged;
+    }
+
+    let only_group_content = filter.kinds.as_ref().is_some_and(|kinds| {
+        !kinds.is_empty()
+            && kinds.iter().all(|kind| {
+                !NON_GROUP_ALLOWED_KINDS.contains(kind) && !RELAY_GENERATED_KINDS.contains(kind)
+            })
+    });
+    if !only_group_content {
+        return Pushdown::Unchanged;
+    }
+
+    if readable.is_empty() {
+        return Pushdown::Empty;
+    }
+
+    *filter = std::mem::take(filter).custom_tags(h, readable.iter().cloned());
+    Pushdown::Narrowed
+}
+
+/// A connection's narrowed subscriptions and the groups they were narrowed to
+#[derive(Debug, Default)]
+struct Narrowed {
+    readable: BTreeSet<String>,
+    subscriptions: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryPushdownMiddleware {
+    groups: Arc<Groups>,
+    enabled: bool,
+    personal_kinds: Vec<Kind>,
+    /// Narrowed subscriptions per connection
+    narrowed: Arc<DashMap<String, Narrowed>>,
+}
+
+impl QueryPushdownMiddleware {
+    pub fn new(groups: Arc<Groups>, enabled: bool) -> Self {
+        Self {
+            groups,
+            enabled,
+            personal_kinds: Vec::new(),
+            narrowed: Arc::default(),
+        }
+    }
+
+    /// Leaves filters for these kinds alone, they are never group content
+    pub fn with_personal_kinds(mut self, kinds: impl IntoIterator<Item = Kind>) -> Self {
+        self.personal_kinds = kinds.into_iter().collect();
+        self
+    }
+
+    fn push_down(&self, filter: &mut Filter, readable: &BTreeSet<String>) -> Pushdown {
+        let personal = filter
+            .kinds
+            .as_ref()
+            .is_some_and(|kinds| kinds.iter().any(|kind| self.personal_kinds.contains(kind)));
+        if personal {
+            return Pushdown::Unchanged;
+        }
+        push_down(filter, readable)
+    }
+
+    fn record(
+        &self,
+        connection_id: &str,
+        subscription_id: &SubscriptionId,
+        readable: &BTreeSet<String>,
+    ) {
+        self.narrowed
+            .entry(connection_id.to_string())
+            .or_insert_with(|| Narrowed {
+                readable: readable.clone(),
+                subscriptions: BTreeSet::new(),
+            })
+            .subscriptions
+            .insert(subscription_id.to_string());
+    }
+
+    fn forget(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        if let Some(mut narrowed) = self.narrowed.get_mut(connection_id) {
+            narrowed.subscriptions.remove(subscription_id.as_str());
+        }
+    }
+
+    /// Takes the connection's narrowed subscriptions if the groups they were
+    /// narrowed to are no longer the readable ones
+    fn take_stale(&self, connection_id: &str, readable: &BTreeSet<String>) -> Vec<String> {
+        self.narrowed
+            .remove_if(connection_id, |_, narrowed| narrowed.readable != *readable)
+            .map(|(_, narrowed)| narrowed.subscriptions.into_iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl NostrMiddleware<()> for QueryPushdownMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        mut ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if !self.enabled {
+            return ctx.next().await;
+        }
+
+        let connection_id = ctx.connection_id.to_string();
+        let request = match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id, ..
+            })
+            | Some(ClientMessage::ReqMultiFilter {
+                subscription_id, ..
+            }) => Some(subscription_id.to_string()),
+            Some(ClientMessage::Close(subscription_id)) => {
+                self.forget(&connection_id, subscription_id);
+                None
+            }
+            _ => None,
+        };
+        if request.is_none() && !self.narrowed.contains_key(&connection_id) {
+            return ctx.next().await;
+        }
+
+        let (authed_pubkey, scope) = {
+            let state = ctx.state.read().await;
+            (state.authed_pubkey, state.subdomain().clone())
+        };
+        let Some(readable) = self
+            .groups
+            .readable_group_ids(&scope, authed_pubkey.as_ref())
+        else {
+            return ctx.next().await;
+        };
+
+        // Live events only reach a subscription through its narrowed filter,
+        // so groups that became readable since need a new REQ. A REQ that
+        // replaces one of them is re-narrowed below instead.
+        for subscription_id in self.take_stale(&connection_id, &readable) {
+            if request.as_deref() == Some(subscription_id.as_str()) {
+                continue;
+            }
+            debug!(
+                "[{}] Readable groups changed, closing subscription {}",
+                connection_id, subscription_id
+            );
+            ctx.send_message(RelayMessage::closed(
+                SubscriptionId::new(subscription_id),
+                MEMBERSHIP_CHANGED_MESSAGE,
+            ))?;
+        }
+
+        let (subscription_id, outcomes, empty) = match ctx.message.as_mut() {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                let outcome = self.push_down(filter.to_mut(), &readable);
+                (
+                    subscription_id.clone().into_owned(),
+                    vec![outcome],
+                    outcome == Pushdown::Empty,
+                )
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                let mut outcomes = Vec::with_capacity(filters.len());
+                filters.retain_mut(|filter| {
+                    let outcome = self.push_down(filter, &readable);
+                    outcomes.push(outcome);
+                    outcome != Pushdown::Empty
+                });
+                (
+                    subscription_id.clone().into_owned(),
+                    outcomes,
+                    filters.is_empty(),
+                )
+            }
+            _ => return ctx.next().await,
+        };
+
+        for outcome in &outcomes {
+            match outcome {
+                Pushdown::Unchanged => {}
+                Pushdown::Narrowed => metrics::query_pushdown("narrowed").increment(1),
+                Pushdown::Empty => metrics::query_pushdown("empty").increment(1),
+            }
+        }
+        if outcomes
+            .iter()
+            .any(|outcome| *outcome != Pushdown::Unchanged)
+        {
+            self.record(&connection_id, &subscription_id, &readable);
+        } else {
+            self.forget(&connection_id, &subscription_id);
+        }
+
+        if empty {
+            debug!(
+                "[{}] No readable groups for subscription {}, sending EOSE",
+                connection_id, subscription_id
+            );
+            ctx.send_message(RelayMessage::eose(subscription_id))?;
+            return Ok(());
+        }
+
+        ctx.next().await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        self.narrowed.remove(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_DIRECTORY_39100,
+        KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_LIST_SUGGESTION_39012,
+    };
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use nostr_lmdb::Scope;
+    use relay_builder::{EventContext, EventProcessor, RelayDatabase};
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    fn readable(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_push_down_only_rewrites_group_content_filters() {
+        let groups = readable(&["public_group", "my_group"]);
+
+        let mut content = Filter::new().kind(Kind::Custom(9)).limit(50);
+        assert_eq!(push_down(&mut content, &groups), Pushdown::Narrowed);
+        let h = SingleLetterTag::lowercase(Alphabet::H);
+        assert_eq!(content.generic_tags.get(&h), Some(&groups));
+        assert_eq!(content.limit, Some(50));
+
+        let mut named = Filter::new()
+            .kind(Kind::Custom(9))
+            .custom_tag(h, "other_group");
+        let before = named.clone();
+        assert_eq!(push_down(&mut named, &groups), Pushdown::Unchanged);
+        assert_eq!(named, before);
+
+        // Relay-generated events are addressed by `d`, an `#h` would hide them
+        let me = Keys::generate().public_key();
+        let mut untouched = vec![
+            Filter::new().limit(10),
+            Filter::new().kinds([Kind::Custom(9), NON_GROUP_ALLOWED_KINDS[0]]),
+            Filter::new().kind(KIND_GROUP_DIRECTORY_39100),
+            Filter::new()
+                .kind(KIND_GROUP_LIST_SUGGESTION_39012)
+                .pubkey(me),
+        ];
+        untouched.extend(RELAY_GENERATED_KINDS.map(|kind| Filter::new().kind(kind)));
+        for mut filter in untouched {
+            let before = filter.clone();
+            assert_eq!(push_down(&mut filter, &groups), Pushdown::Unchanged);
+            assert_eq!(filter, before);
+        }
+
+        let mut content = Filter::new().kind(Kind::Custom(9));
+        assert_eq!(push_down(&mut content, &readable(&[])), Pushdown::Empty);
+    }
+
+    #[tokio::test]
+    async fn test_narrowed_subscriptions_close_when_readable_groups_change() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(Groups::new(
+            database,
+            relay_keys.public_key(),
+            "wss://test.relay".to_string(),
+        ));
+        let middleware = QueryPushdownMiddleware::new(groups, true);
+        let before = readable(&["public_group"]);
+        middleware.record("conn-1", &SubscriptionId::new("chat"), &before);
+        middleware.record("conn-1", &SubscriptionId::new("closed"), &before);
+        middleware.forget("conn-1", &SubscriptionId::new("closed"));
+        middleware.record("conn-2", &SubscriptionId::new("chat"), &before);
+
+        // Nothing to close while the same groups are readable
+        assert!(middleware.take_stale("conn-1", &before).is_empty());
+
+        let after = readable(&["public_group", "joined_group"]);
+        assert_eq!(middleware.take_stale("conn-1", &after), vec!["chat"]);
+        assert!(middleware.take_stale("conn-1", &after).is_empty());
+        assert_eq!(middleware.take_stale("conn-2", &after), vec!["chat"]);
+    }
+
+    /// Counts the database windows needed to collect `limit` visible events,
+    /// paging backwards the way subscriptions fill their limit
+    async fn windows_to_fill(
+        database: &RelayDatabase,
+        processor: &GroupsRelayProcessor,
+        context: &EventContext,
+        filter: Filter,
+        limit: usize,
+    ) -> usize {
+        let mut visible = 0;
+        let mut windows = 0;
+        let mut until = None;
+
+        while visible < limit {
+            let mut window = filter.clone().limit(limit);
+            if let Some(until) = until {
+                window = window.until(until);
+            }
+            let events = database
+                .query(vec![window], &context.subdomain)
+                .await
+                .unwrap();
+            windows += 1;
+            if events.is_empty() {
+                break;
+            }
+
+            for event in events.iter() {
+                let state = Arc::new(RwLock::new(()));
+                if processor
+                    .can_see_event(event, state, context)
+                    .unwrap_or(false)
+                {
+                    visible += 1;
+                }
+            }
+            let oldest = events.iter().map(|event| event.created_at).min().unwrap();
+            until = Some(Timestamp::from(oldest.as_u64() - 1));
+        }
+
+        windows
+    }
+
+    #[tokio::test]
+    async fn test_pushdown_reduces_windows_for_non_members() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let admin = Keys::generate();
+        let outsider = Keys::generate();
+        let h = |group_id: &str| Tag::custom(TagKind::h(), [group_id]);
+
+        let admin_context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let handle = |event: Event| {
+            let processor = &processor;
+            let context = &admin_context;
+            let database = &database;
+            let relay_keys = &relay_keys;
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), context)
+                    .await
+                    .unwrap();
+                apply_store_commands(database, relay_keys, commands)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // Public group messages are the oldest, private chatter is newer
+        let base = Timestamp::now().as_u64() - 10_000;
+        let limit = 20;
+        let create = |group_id: &str| {
+            EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+                .tag(h(group_id))
+                .custom_created_at(Timestamp::from(base))
+                .sign_with_keys(&admin)
+                .unwrap()
+        };
+
+        handle(create("public_group")).await;
+        let make_public = EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "")
+            .tags([
+                h("public_group"),
+                Tag::custom(TagKind::custom("public"), &[] as &[String]),
+            ])
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(make_public).await;
+        for i in 0..limit as u64 {
+            let event = EventBuilder::new(Kind::Custom(9), format!("public {i}"))
+                .tag(h("public_group"))
+                .custom_created_at(Timestamp::from(base + 1 + i))
+                .sign_with_keys(&admin)
+                .unwrap();
+            handle(event).await;
+        }
+        for g in 0..10u64 {
+            let group_id = format!("private_group_{g}");
+            handle(create(&group_id)).await;
+            for i in 0..limit as u64 {
+                let event = EventBuilder::new(Kind::Custom(9), format!("private {i}"))
+                    .tag(h(&group_id))
+                    .custom_created_at(Timestamp::from(base + 1_000 + g * 100 + i))
+                    .sign_with_keys(&admin)
+                    .unwrap();
+                handle(event).await;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let outsider_context = EventContext {
+            authed_pubkey: Some(outsider.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let filter = Filter::new().kind(Kind::Custom(9));
+        let without = windows_to_fill(
+            &database,
+            &processor,
+            &outsider_context,
+            filter.clone(),
+            limit,
+        )
+        .await;
+
+        let readable = groups
+            .readable_group_ids(&Scope::Default, Some(&outsider.public_key()))
+            .unwrap();
+        assert_eq!(readable, self::readable(&["public_group"]));
+        let mut narrowed = filter;
+        assert_eq!(push_down(&mut narrowed, &readable), Pushdown::Narrowed);
+        let with = windows_to_fill(&database, &processor, &outsider_context, narrowed, limit).await;
+
+        assert_eq!(with, 1);
+        assert!(with < without, "pushdown {with} windows, without {without}");
+
+        // Joining a private group makes it readable
+        let join = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+            .tags([h("private_group_0"), Tag::public_key(outsider.public_key())])
+            .sign_with_keys(&admin)
+            .unwrap();
+        handle(join).await;
+        let readable = groups
+            .readable_group_ids(&Scope::Default, Some(&outsider.public_key()))
+            .unwrap();
+        assert!(readable.contains("private_group_0"));
+    }
+}
@@ -0,0 +1,445 @@
+//! Storage quotas per scope and per group.
+//!
+//! Communities hosted on subdomains share one database, so each scope can be
+//! capped at `max_events_per_scope` stored events and `max_bytes_per_scope`
+//! bytes, estimated from the events' JSON. The relay operator can also cap a
+//! single group with a `max_events` tag on a relay-signed 9002. An event that
+//! would go over a quota is rejected with `error: storage quota exceeded`.
+//!
+//! Only regular events count, since replaceable and addressable events
+//! overwrite their previous version. Events signed by the relay, like the
+//! group state it generates, are neither counted nor limited. Group
+//! management events and deletions are counted but always accepted, so
+//! admins can still free up space in a full scope.
+//!
+//! Usage is recounted from the database in the background on startup, a page
+//! of events at a time, and kept up to date as the processor saves and
+//! deletes events. Until the recount finishes, quotas only see the events
+//! stored since startup. Events deleted some other way, like with
+//! relay_admin, are only uncounted on the next restart. Concurrent writes
+//! are checked against the same usage, so a scope can go a few events over.
+
+use crate::config::QuotaSettings;
+use crate::error::Rejection;
+use crate::groups::{Group, KIND_GENERAL_EVENT_DELETION};
+use crate::metrics;
+use crate::relay_keys::{RelayIdentity, RelayPubkeys};
+use crate::utils::scope_name;
+use crate::RelayDatabase;
+use anyhow::Result;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+pub const QUOTA_EXCEEDED: &str = "storage quota exceeded";
+
+/// Events read per database round trip while recounting
+pub const RECOUNT_PAGE_SIZE: usize = 1000;
+
+/// Stored events counted against a quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub events: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopeUsage {
+    pub scope: String,
+    pub events: u64,
+    pub bytes: u64,
+    pub max_events: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Whether events of a kind are counted against the quotas
+pub fn is_counted_kind(kind: Kind) -> bool {
+    !kind.is_replaceable() && !kind.is_addressable() && !kind.is_ephemeral()
+}
+
+fn event_size(event: &Event) -> u64 {
+    event.as_json().len() as u64
+}
+
+#[derive(Debug)]
+pub struct StorageQuotas {
+    database: Arc<RelayDatabase>,
+    relay_keys: RelayPubkeys,
+    max_events_per_scope: Option<u64>,
+    max_bytes_per_scope: Option<u64>,
+    scopes: DashMap<Scope, Usage>,
+    groups: DashMap<(Scope, String), u64>,
+}
+
+impl StorageQuotas {
+    pub fn new(
+        database: Arc<RelayDatabase>,
+        relay_keys: RelayPubkeys,
+        settings: &QuotaSettings,
+    ) -> Self {
+        Self {
+            database,
+            relay_keys,
+            max_events_per_scope: settings.max_events_per_scope,
+            max_bytes_per_scope: settings.max_bytes_per_scope,
+            scopes: DashMap::new(),
+            groups: DashMap::new(),
+        }
+    }
+
+    fn is_counted(&self, event: &Event) -> bool {
+        is_counted_kind(event.kind) && !self.relay_keys.is_relay(&event.pubkey)
+    }
+
+    /// Recounts usage in the background, logging failures
+    pub fn spawn_recount(self: &Arc<Self>) {
+        let quotas = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(e) = quotas.recount().await {
+                warn!("Failed to recount storage quota usage: {}", e);
+            }
+        });
+    }
+
+    /// Counts the stored events of every scope from scratch. Events the
+    /// processor saves or deletes meanwhile are tracked on top.
+    pub async fn recount(&self) -> Result<()> {
+        self.scopes.clear();
+        self.groups.clear();
+
+        for scope in self.database.list_scopes().await? {
+            let (usage, groups) = self.count_scope(&scope, RECOUNT_PAGE_SIZE).await?;
+            info!(
+                "Scope {} stores {} events ({} bytes)",
+                scope_name(&scope),
+                usage.events,
+                usage.bytes
+            );
+            let usage = {
+                let mut tracked = self.scopes.entry(scope.clone()).or_default();
+                tracked.events += usage.events;
+                tracked.bytes += usage.bytes;
+                *tracked
+            };
+            self.publish_usage(&scope, usage);
+            for (group_id, events) in groups {
+                *self.groups.entry((scope.clone(), group_id)).or_default() += events;
+            }
+        }
+        Ok(())
+    }
+
+    /// Usage of one scope and events per group, reading the scope newest
+    /// first a page at a time
+    async fn count_scope(
+        &self,
+        scope: &Scope,
+        page_size: usize,
+    ) -> Result<(Usage, HashMap<String, u64>)> {
+        let mut usage = Usage::default();
+        let mut groups: HashMap<String, u64> = HashMap::new();
+        let mut until = None;
+        // Pages overlap on the second they end at
+        let mut boundary: HashSet<EventId> = HashSet::new();
+
+        loop {
+            let mut filter = Filter::new().limit(page_size);
+            if let Some(until) = until {
+                filter = filter.until(until);
+            }
+            let events = self.database.query(vec![filter], scope).await?;
+            let fresh: Vec<&Event> = events
+                .iter()
+                .filter(|event| !boundary.contains(&event.id))
+                .collect();
+            let Some(oldest) = fresh.iter().map(|event| event.created_at).min() else {
+                return Ok((usage, groups));
+            };
+
+            for event in &fresh {
+                if !self.is_counted(event) {
+                    continue;
+                }
+                usage.events += 1;
+                usage.bytes += event_size(event);
+                if let Some(group_id) = Group::extract_group_h_tag(event) {
+                    *groups.entry(group_id.to_string()).or_default() += 1;
+                }
+            }
+
+            if events.len() < page_size {
+                return Ok((usage, groups));
+            }
+            if until != Some(oldest) {
+                boundary.clear();
+            }
+            boundary.extend(
+                fresh
+                    .iter()
+                    .filter(|event| event.created_at == oldest)
+                    .map(|event| event.id),
+            );
+            until = Some(oldest);
+        }
+    }
+
+    pub fn usage(&self, scope: &Scope) -> Usage {
+        self.scopes
+            .get(scope)
+            .map(|usage| *usage)
+            .unwrap_or_default()
+    }
+
+    pub fn group_events(&self, scope: &Scope, group_id: &str) -> u64 {
+        self.groups
+            .get(&(scope.clone(), group_id.to_string()))
+            .map_or(0, |events| *events)
+    }
+
+    /// Usage and limits of every scope with stored events, by name
+    pub fn scope_usage(&self) -> Vec<ScopeUsage> {
+        let mut scopes: Vec<ScopeUsage> = self
+            .scopes
+            .iter()
+            .map(|entry| ScopeUsage {
+                scope: scope_name(entry.key()).to_string(),
+                events: entry.events,
+                bytes: entry.bytes,
+                max_events: self.max_events_per_scope,
+                max_bytes: self.max_bytes_per_scope,
+            })
+            .collect();
+        scopes.sort_by(|a, b| a.scope.cmp(&b.scope));
+        scopes
+    }
+
+    /// Checks that storing an event stays within the quotas of its scope and
+    /// of its group, capped at `group_max_events`
+    pub fn check(
+        &self,
+        scope: &Scope,
+        event: &Event,
+        group_max_events: Option<u64>,
+    ) -> Result<(), relay_builder::Error> {
+        if !self.is_counted(event)
+            || Group::is_group_management_kind(event.kind)
+            || event.kind == KIND_GENERAL_EVENT_DELETION
+        {
+            return Ok(());
+        }
+
+        let usage = self.usage(scope);
+        let scope_full = self
+            .max_events_per_scope
+            .is_some_and(|max| usage.events >= max)
+            || self
+                .max_bytes_per_scope
+                .is_some_and(|max| usage.bytes + event_size(event) > max);
+        let group_full = group_max_events
+            .zip(Group::extract_group_h_tag(event))
+            .is_some_and(|(max, group_id)| self.group_events(scope, group_id) >= max);
+
+        if scope_full || group_full {
+            Err(relay_builder::Error::failed(QUOTA_EXCEEDED))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Updates usage with the events the commands save and delete. Deleted
+    /// events are looked up before the commands run.
+    pub async fn record(&self, commands: &[StoreCommand]) {
+        for command in commands {
+            match command {
+                StoreCommand::SaveSignedEvent(event, scope, _) if self.is_counted(event) => {
+                    self.update(scope, event, true);
+                }
+                StoreCommand::DeleteEvents(filter, scope, _) => {
+                    match self.database.query(vec![filter.clone()], scope).await {
+                        Ok(events) => {
+                            for event in events {
+                                if self.is_counted(&event) {
+                                    self.update(scope, &event, false);
+                                }
+                            }
+                        }
+                        Err(e) => warn!(
+                            "Failed to count events deleted from scope {}: {}",
+                            scope_name(scope),
+                            e
+                        ),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn update(&self, scope: &Scope, event: &Event, saved: bool) {
+        let size = event_size(event);
+        // Changed under the entry's lock, so concurrent updates all land
+        let usage = {
+            let mut usage = self.scopes.entry(scope.clone()).or_default();
+            if saved {
+                usage.events += 1;
+                usage.bytes += size;
+            } else {
+                usage.events = usage.events.saturating_sub(1);
+                usage.bytes = usage.bytes.saturating_sub(size);
+            }
+            *usage
+        };
+        self.publish_usage(scope, usage);
+
+        if let Some(group_id) = Group::extract_group_h_tag(event) {
+            let mut events = self
+                .groups
+                .entry((scope.clone(), group_id.to_string()))
+                .or_default();
+            *events = if saved {
+                *events + 1
+            } else {
+                events.saturating_sub(1)
+            };
+        }
+    }
+
+    fn publish_usage(&self, scope: &Scope, usage: Usage) {
+        let name = scope_name(scope);
+        metrics::scope_stored_events(name).set(usage.events as f64);
+        metrics::scope_stored_bytes(name).set(usage.bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_DELETE_EVENT_9005};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_scope_quota_rejects_until_events_are_deleted() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let quotas = Arc::new(StorageQuotas::new(
+            database.clone(),
+            groups.relay_keys().clone(),
+            &QuotaSettings {
+                max_events_per_scope: Some(4),
+                max_bytes_per_scope: None,
+            },
+        ));
+        quotas.recount().await.unwrap();
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_storage_quotas(quotas.clone());
+        let admin = Keys::generate();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h = Tag::custom(TagKind::h(), ["quota"]);
+        let message = |content: &str| {
+            EventBuilder::new(Kind::Custom(9), content)
+                .tag(h.clone())
+                .sign_with_keys(&admin)
+                .unwrap()
+        };
+        let publish = |event: Event| {
+            let processor = processor.clone();
+            let database = database.clone();
+            let relay_keys = relay_keys.clone();
+            let context = EventContext {
+                authed_pubkey: context.authed_pubkey,
+                subdomain: context.subdomain.clone(),
+                relay_pubkey: context.relay_pubkey,
+            };
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await?;
+                apply_store_commands(&database, &relay_keys, commands)
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Ok::<_, relay_builder::Error>(())
+            }
+        };
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        publish(create).await.unwrap();
+        let first = message("one");
+        let second = message("two");
+        for event in [first.clone(), second.clone(), message("three")] {
+            publish(event).await.unwrap();
+        }
+        assert_eq!(quotas.usage(&Scope::Default).events, 4);
+        assert_eq!(quotas.group_events(&Scope::Default, "quota"), 4);
+
+        let error = publish(message("four")).await.unwrap_err().to_string();
+        assert!(error.contains(QUOTA_EXCEEDED));
+
+        // Deletions are accepted in a full scope and free up space
+        let delete = EventBuilder::new(KIND_GROUP_DELETE_EVENT_9005, "")
+            .tags([h.clone(), Tag::event(first.id), Tag::event(second.id)])
+            .sign_with_keys(&admin)
+            .unwrap();
+        publish(delete).await.unwrap();
+        assert_eq!(quotas.usage(&Scope::Default).events, 3);
+        publish(message("four")).await.unwrap();
+
+        // A recount agrees with the tracked usage
+        let tracked = quotas.usage(&Scope::Default);
+        quotas.recount().await.unwrap();
+        assert_eq!(quotas.usage(&Scope::Default), tracked);
+    }
+
+    #[tokio::test]
+    async fn test_recount_pages_through_events_sharing_a_second() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let quotas = StorageQuotas::new(
+            database.clone(),
+            RelayPubkeys::new(relay_keys.public_key()),
+            &QuotaSettings {
+                max_events_per_scope: None,
+                max_bytes_per_scope: None,
+            },
+        );
+        let author = Keys::generate();
+        let base = Timestamp::now().as_u64() - 100;
+        for i in 0..7u64 {
+            let event = EventBuilder::new(Kind::Custom(9), format!("message {i}"))
+                .tag(Tag::custom(TagKind::h(), ["paged"]))
+                .custom_created_at(Timestamp::from(base + i / 2))
+                .sign_with_keys(&author)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let (usage, groups) = quotas.count_scope(&Scope::Default, 3).await.unwrap();
+        assert_eq!(usage.events, 7);
+        assert_eq!(groups.get("paged"), Some(&7));
+    }
+}
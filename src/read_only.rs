@@ -0,0 +1,119 @@
+//! Read-only mode for maintenance windows.
+//!
+//! While read-only, every EVENT is answered with [`READ_ONLY_MESSAGE`] at the
+//! front of the chain, before it reaches validation or the event processor.
+//! REQ, CLOSE and AUTH are untouched, so clients keep reading stored events.
+//! The mode is a single atomic flag checked per message, so toggling it with
+//! `PUT /admin/read_only` applies to open connections right away.
+//!
+//! Writes the relay makes on its own are paused too: coalesced membership
+//! lists stay queued until the mode is lifted, and HTTP endpoints that store
+//! events answer 503.
+
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+pub const READ_ONLY_MESSAGE: &str = "error: relay is in read-only mode";
+
+#[derive(Debug, Default)]
+pub struct ReadOnlyMode {
+    enabled: AtomicBool,
+}
+
+impl ReadOnlyMode {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns the mode on or off, returning whether it was on.
+    pub fn set(&self, enabled: bool) -> bool {
+        let previous = self.enabled.swap(enabled, Ordering::Relaxed);
+        if previous != enabled {
+            info!(
+                "Read-only mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+        previous
+    }
+
+    /// The OK reason for a client message, if it has to be rejected.
+    pub fn check(&self, message: &ClientMessage) -> Option<&'static str> {
+        match message {
+            ClientMessage::Event(_) if self.is_enabled() => Some(READ_ONLY_MESSAGE),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOnlyMiddleware {
+    mode: Arc<ReadOnlyMode>,
+}
+
+impl ReadOnlyMiddleware {
+    pub fn new(mode: Arc<ReadOnlyMode>) -> Self {
+        Self { mode }
+    }
+}
+
+impl NostrMiddleware<()> for ReadOnlyMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let Some(message) = &ctx.message else {
+            return ctx.next().await;
+        };
+        let Some(reason) = self.mode.check(message) else {
+            return ctx.next().await;
+        };
+
+        if let ClientMessage::Event(event) = message {
+            debug!(
+                "[{}] Rejecting event {} in read-only mode",
+                ctx.connection_id, event.id
+            );
+            ctx.send_message(RelayMessage::ok(event.id, false, reason))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggling_applies_to_the_next_message() {
+        let mode = Arc::new(ReadOnlyMode::new(false));
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("hi").sign_with_keys(&keys).unwrap();
+        let event = ClientMessage::event(event);
+        let req = ClientMessage::req(SubscriptionId::new("sub"), Filter::new().limit(1));
+        let close = ClientMessage::close(SubscriptionId::new("sub"));
+
+        assert_eq!(mode.check(&event), None);
+
+        // The same connection sees the flip without reconnecting
+        assert!(!mode.set(true));
+        assert_eq!(mode.check(&event), Some(READ_ONLY_MESSAGE));
+        assert_eq!(mode.check(&req), None);
+        assert_eq!(mode.check(&close), None);
+
+        assert!(mode.set(false));
+        assert_eq!(mode.check(&event), None);
+    }
+}
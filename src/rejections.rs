@@ -0,0 +1,325 @@
+//! Reasons events were rejected.
+//!
+//! The processor returns a `relay_builder::Error` for every event it turns
+//! down, and the client sees its message in the OK. [`Rejections`] counts
+//! rejections per [`ReasonCode`] and kind in the `rejected_events` metric,
+//! logs them with the reason code, and keeps the most recent ones for
+//! `GET /admin/rejections`, so operators can tell a misbehaving client from a
+//! misconfigured group without reading through logs. The code of a processor
+//! error comes from [`Rejection::reason_code`].
+//!
+//! Middlewares that turn events down before the processor, for a bad
+//! signature, the event limits, NIP-70, validation, admission or the scope
+//! policy, record them with their own code. Events turned away while the
+//! relay is read-only or shedding load aren't recorded, as that says nothing
+//! about the event.
+
+use crate::error::Rejection;
+use crate::metrics;
+use dashmap::DashMap;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use tracing::{debug, info};
+
+/// Number of rejections kept for the admin endpoint
+pub const RECENT_REJECTIONS: usize = 200;
+
+/// Why an event was rejected, matching the NIP-01 OK prefixes where one applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReasonCode {
+    AuthRequired,
+    Restricted,
+    Duplicate,
+    Invalid,
+    RateLimited,
+    PaymentRequired,
+    Internal,
+    Custom(&'static str),
+}
+
+impl ReasonCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasonCode::AuthRequired => "auth-required",
+            ReasonCode::Restricted => "restricted",
+            ReasonCode::Duplicate => "duplicate",
+            ReasonCode::Invalid => "invalid",
+            ReasonCode::RateLimited => "rate-limited",
+            ReasonCode::PaymentRequired => "payment-required",
+            ReasonCode::Internal => "internal",
+            ReasonCode::Custom(code) => code,
+        }
+    }
+
+    /// The code of a message built by [`Rejection`], from its NIP-01 prefix
+    pub fn from_prefix(message: &str) -> Self {
+        let prefix = message.split_once(':').map_or("", |(prefix, _)| prefix);
+        match prefix {
+            "auth-required" => ReasonCode::AuthRequired,
+            "restricted" => ReasonCode::Restricted,
+            "duplicate" => ReasonCode::Duplicate,
+            "invalid" => ReasonCode::Invalid,
+            "rate-limited" => ReasonCode::RateLimited,
+            "payment-required" => ReasonCode::PaymentRequired,
+            "error" => ReasonCode::Custom("error"),
+            _ => ReasonCode::Custom("other"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedEvent {
+    pub event_id: String,
+    pub kind: u16,
+    pub pubkey: String,
+    pub reason: &'static str,
+    pub message: String,
+    pub at: u64,
+}
+
+#[derive(Debug)]
+pub struct Rejections {
+    recent: Mutex<VecDeque<RejectedEvent>>,
+    capacity: usize,
+    counts: DashMap<(&'static str, u16), u64>,
+}
+
+impl Rejections {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            counts: DashMap::new(),
+        }
+    }
+
+    /// Counts, logs and remembers the processor's rejection of an event.
+    pub fn record(
+        &self,
+        event_id: EventId,
+        kind: Kind,
+        pubkey: PublicKey,
+        error: &relay_builder::Error,
+    ) -> ReasonCode {
+        let code = error.reason_code();
+        self.insert(event_id, kind, pubkey, code, error.to_string());
+        code
+    }
+
+    /// Counts, logs and remembers an event a middleware turned down
+    pub fn record_event(&self, event: &Event, code: ReasonCode, message: &str) {
+        self.insert(
+            event.id,
+            event.kind,
+            event.pubkey,
+            code,
+            message.to_string(),
+        );
+    }
+
+    fn insert(
+        &self,
+        event_id: EventId,
+        kind: Kind,
+        pubkey: PublicKey,
+        code: ReasonCode,
+        message: String,
+    ) {
+        let reason = code.as_str();
+        let kind = kind.as_u16();
+
+        *self.counts.entry((reason, kind)).or_default() += 1;
+        metrics::rejected_events(reason, kind).increment(1);
+
+        if code == ReasonCode::Internal {
+            info!(
+                reason_code = reason,
+                kind,
+                %event_id,
+                %pubkey,
+                "Rejected event: {}",
+                message
+            );
+        } else {
+            debug!(
+                reason_code = reason,
+                kind,
+                %event_id,
+                %pubkey,
+                "Rejected event: {}",
+                message
+            );
+        }
+
+        if self.capacity > 0 {
+            let mut recent = self.recent.lock();
+            if recent.len() == self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(RejectedEvent {
+                event_id: event_id.to_hex(),
+                kind,
+                pubkey: pubkey.to_hex(),
+                reason,
+                message,
+                at: Timestamp::now().as_u64(),
+            });
+        }
+    }
+
+    /// The most recent rejections, newest first
+    pub fn recent(&self) -> Vec<RejectedEvent> {
+        self.recent.lock().iter().rev().cloned().collect()
+    }
+
+    /// How many events of a kind were rejected for a reason
+    pub fn count(&self, reason: ReasonCode, kind: Kind) -> u64 {
+        self.counts
+            .get(&(reason.as_str(), kind.as_u16()))
+            .map_or(0, |count| *count)
+    }
+}
+
+impl Default for Rejections {
+    fn default() -> Self {
+        Self::new(RECENT_REJECTIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{Groups, KIND_GROUP_CREATE_9007};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use nostr_lmdb::Scope;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn test_reason_codes() {
+        let cases = [
+            (
+                relay_builder::Error::restricted("not a member"),
+                ReasonCode::Restricted,
+            ),
+            (
+                relay_builder::Error::auth_required("who are you"),
+                ReasonCode::AuthRequired,
+            ),
+            (
+                relay_builder::Error::duplicate("already a member"),
+                ReasonCode::Duplicate,
+            ),
+            (
+                relay_builder::Error::invalid("missing h tag"),
+                ReasonCode::Invalid,
+            ),
+            (
+                relay_builder::Error::rate_limited("slow down"),
+                ReasonCode::RateLimited,
+            ),
+            (
+                relay_builder::Error::payment_required("pay first"),
+                ReasonCode::PaymentRequired,
+            ),
+            (
+                relay_builder::Error::failed("group is archived"),
+                ReasonCode::Custom("error"),
+            ),
+            (
+                relay_builder::Error::event_error("Group not found", EventId::all_zeros()),
+                ReasonCode::Invalid,
+            ),
+            // Messages that merely look prefixed aren't taken at their word
+            (
+                relay_builder::Error::notice("Restricted content ahead"),
+                ReasonCode::Custom("other"),
+            ),
+        ];
+
+        for (error, code) in cases {
+            assert_eq!(error.reason_code(), code, "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_counted_per_reason_and_kind() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let rejections = Arc::new(Rejections::new(2));
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_rejections(rejections.clone());
+        let admin = Keys::generate();
+        let outsider = Keys::generate();
+        let context = |keys: &Keys| EventContext {
+            authed_pubkey: Some(keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h = Tag::custom(TagKind::h(), ["closed_group"]);
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        let commands = processor
+            .handle_event(create, Arc::new(RwLock::new(())), &context(&admin))
+            .await
+            .unwrap();
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+
+        // A non-member posting to a private group
+        let post = EventBuilder::new(Kind::Custom(9), "hello")
+            .tag(h.clone())
+            .sign_with_keys(&outsider)
+            .unwrap();
+        assert!(processor
+            .handle_event(post.clone(), Arc::new(RwLock::new(())), &context(&outsider))
+            .await
+            .is_err());
+        assert_eq!(rejections.count(ReasonCode::Restricted, Kind::Custom(9)), 1);
+
+        // A group creation without a group id
+        let invalid = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .sign_with_keys(&admin)
+            .unwrap();
+        assert!(processor
+            .handle_event(invalid.clone(), Arc::new(RwLock::new(())), &context(&admin))
+            .await
+            .is_err());
+        assert_eq!(
+            rejections.count(ReasonCode::Invalid, KIND_GROUP_CREATE_9007),
+            1
+        );
+
+        let recent = rejections.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].event_id, invalid.id.to_hex());
+        assert_eq!(recent[1].event_id, post.id.to_hex());
+        assert_eq!(recent[0].reason, "invalid");
+        assert_eq!(recent[1].reason, "restricted");
+
+        // Only the newest rejections are kept
+        assert!(processor
+            .handle_event(post, Arc::new(RwLock::new(())), &context(&outsider))
+            .await
+            .is_err());
+        assert_eq!(rejections.recent().len(), 2);
+        assert_eq!(rejections.count(ReasonCode::Restricted, Kind::Custom(9)), 2);
+    }
+}
@@ -0,0 +1,738 @@
+//! Group administration behind the `relay-admin` tool.
+//!
+//! Management events are signed with the relay key, which the relay treats as
+//! an admin of every group. They are either published to the running relay
+//! over its WebSocket, or applied directly to the database through the same
+//! [`GroupsRelayProcessor`] the relay uses, so validation is identical.
+
+use crate::groups::{
+    Groups, KIND_GROUP_ADD_USER_9000, KIND_GROUP_ADMINS_39001, KIND_GROUP_CREATE_9007,
+    KIND_GROUP_DELETE_9008, KIND_GROUP_EDIT_METADATA_9002, KIND_GROUP_MEMBERS_39002,
+    KIND_GROUP_METADATA_39000, KIND_GROUP_REMOVE_USER_9001,
+};
+use crate::groups_event_processor::GroupsRelayProcessor;
+use crate::relay_keys::RelayPubkeys;
+use crate::utils::apply_store_commands;
+use crate::{RelayDatabase, StoreCommand};
+use anyhow::{bail, Context, Result};
+use heed::{CompactionOption, EnvOpenOptions};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{EventContext, EventProcessor};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Metadata fields to change, unset fields are left as they are
+#[derive(Debug, Clone, Default)]
+pub struct MetadataChanges {
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub private: Option<bool>,
+    pub closed: Option<bool>,
+    pub broadcast: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub enum GroupAction {
+    Create {
+        group_id: String,
+    },
+    AddMember {
+        group_id: String,
+        pubkey: PublicKey,
+        role: Option<String>,
+    },
+    RemoveMember {
+        group_id: String,
+        pubkey: PublicKey,
+    },
+    SetMetadata {
+        group_id: String,
+        changes: MetadataChanges,
+    },
+    Delete {
+        group_id: String,
+    },
+}
+
+impl GroupAction {
+    /// Builds the 9000/9001/9002/9007/9008 event for this action
+    pub fn to_event_builder(&self) -> EventBuilder {
+        let h_tag = |group_id: &str| Tag::custom(TagKind::h(), [group_id]);
+        let flag = |name: &str| Tag::custom(TagKind::custom(name), &[] as &[String]);
+
+        match self {
+            Self::Create { group_id } => {
+                EventBuilder::new(KIND_GROUP_CREATE_9007, "").tag(h_tag(group_id))
+            }
+            Self::AddMember {
+                group_id,
+                pubkey,
+                role,
+            } => {
+                let mut p_tag = vec![pubkey.to_hex()];
+                p_tag.extend(role.clone());
+                EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+                    .tags([h_tag(group_id), Tag::custom(TagKind::p(), p_tag)])
+            }
+            Self::RemoveMember { group_id, pubkey } => {
+                EventBuilder::new(KIND_GROUP_REMOVE_USER_9001, "")
+                    .tags([h_tag(group_id), Tag::public_key(*pubkey)])
+            }
+            Self::SetMetadata { group_id, changes } => {
+                let mut tags = vec![h_tag(group_id)];
+                if let Some(name) = &changes.name {
+                    tags.push(Tag::custom(TagKind::Name, [name.clone()]));
+                }
+                if let Some(about) = &changes.about {
+                    tags.push(Tag::custom(TagKind::custom("about"), [about.clone()]));
+                }
+                if let Some(private) = changes.private {
+                    tags.push(flag(if private { "private" } else { "public" }));
+                }
+                if let Some(closed) = changes.closed {
+                    tags.push(flag(if closed { "closed" } else { "open" }));
+                }
+                if let Some(broadcast) = changes.broadcast {
+                    tags.push(flag(if broadcast {
+                        "broadcast"
+                    } else {
+                        "nonbroadcast"
+                    }));
+                }
+                EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "").tags(tags)
+            }
+            Self::Delete { group_id } => {
+                EventBuilder::new(KIND_GROUP_DELETE_9008, "").tag(h_tag(group_id))
+            }
+        }
+    }
+}
+
+/// A group as described by its 39000/39001/39002 events
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct GroupSummary {
+    pub id: String,
+    pub name: String,
+    pub about: Option<String>,
+    pub private: bool,
+    pub closed: bool,
+    pub broadcast: bool,
+    pub admins: Vec<String>,
+    pub members: Vec<String>,
+}
+
+/// Builds group summaries from relay-generated state events, sorted by id
+pub fn summarize(events: &[Event]) -> Vec<GroupSummary> {
+    let mut groups: BTreeMap<String, GroupSummary> = BTreeMap::new();
+
+    for event in events {
+        let Some(id) = event.tags.identifier() else {
+            continue;
+        };
+        let summary = groups
+            .entry(id.to_string())
+            .or_insert_with(|| GroupSummary {
+                id: id.to_string(),
+                ..Default::default()
+            });
+        let tags = event.tags.iter().map(|tag| tag.as_slice());
+
+        match event.kind {
+            k if k == KIND_GROUP_METADATA_39000 => {
+                for tag in tags {
+                    match (tag[0].as_str(), tag.get(1)) {
+                        ("name", Some(name)) => summary.name = name.clone(),
+                        ("about", Some(about)) => summary.about = Some(about.clone()),
+                        ("private", _) => summary.private = true,
+                        ("closed", _) => summary.closed = true,
+                        ("broadcast", _) => summary.broadcast = true,
+                        _ => {}
+                    }
+                }
+            }
+            k if k == KIND_GROUP_ADMINS_39001 => {
+                summary.admins = tags
+                    .filter(|tag| tag[0] == "p")
+                    .filter_map(|tag| tag.get(1).cloned())
+                    .collect();
+            }
+            k if k == KIND_GROUP_MEMBERS_39002 => {
+                summary.members = tags
+                    .filter(|tag| tag[0] == "p")
+                    .filter_map(|tag| tag.get(1).cloned())
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    groups.into_values().collect()
+}
+
+/// Where management events are sent
+pub enum AdminTarget {
+    /// A running relay, reached over its WebSocket
+    Remote(Client),
+    /// The relay database, written directly while the relay is stopped
+    Local {
+        database: Arc<RelayDatabase>,
+        processor: GroupsRelayProcessor,
+        scope: Scope,
+    },
+}
+
+impl AdminTarget {
+    /// Connects to a running relay, authenticating with the relay key
+    pub async fn remote(relay_url: &str, keys: &Keys) -> Result<Self> {
+        let client = ClientBuilder::default().signer(keys.clone()).build();
+        client.add_relay(RelayUrl::parse(relay_url)?).await?;
+        client.connect().await;
+        Ok(Self::Remote(client))
+    }
+
+    /// Opens the relay database and loads its groups
+    pub async fn local(
+        database: Arc<RelayDatabase>,
+        keys: &Keys,
+        relay_url: &str,
+        scope: Scope,
+    ) -> Result<Self> {
+        let groups = Arc::new(
+            Groups::load_groups(
+                Arc::clone(&database),
+                keys.public_key(),
+                relay_url.to_string(),
+            )
+            .await?,
+        );
+        let processor = GroupsRelayProcessor::new(groups, keys.public_key());
+
+        Ok(Self::Local {
+            database,
+            processor,
+            scope,
+        })
+    }
+
+    /// Signs the action's event with the relay key and publishes or applies it
+    pub async fn apply(&self, keys: &Keys, action: &GroupAction) -> Result<EventId> {
+        let event = action.to_event_builder().sign_with_keys(keys)?;
+        let event_id = event.id;
+
+        match self {
+            Self::Remote(client) => {
+                let output = client.send_event(&event).await?;
+                if output.success.is_empty() {
+                    bail!("Relay rejected the event: {:?}", output.failed);
+                }
+            }
+            Self::Local {
+                database,
+                processor,
+                scope,
+            } => {
+                let context = EventContext {
+                    authed_pubkey: Some(keys.public_key()),
+                    subdomain: Arc::new(scope.clone()),
+                    relay_pubkey: keys.public_key(),
+                };
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await?;
+                apply_store_commands(database, keys, commands).await?;
+            }
+        }
+
+        Ok(event_id)
+    }
+
+    /// Fetches the state events of one group, or of all groups
+    pub async fn group_events(&self, group_id: Option<&str>) -> Result<Vec<Event>> {
+        let mut filter = Filter::new().kinds([
+            KIND_GROUP_METADATA_39000,
+            KIND_GROUP_ADMINS_39001,
+            KIND_GROUP_MEMBERS_39002,
+        ]);
+        if let Some(group_id) = group_id {
+            filter = filter.identifier(group_id);
+        }
+
+        let events = match self {
+            Self::Remote(client) => client.fetch_events(filter, FETCH_TIMEOUT).await?,
+            Self::Local {
+                database, scope, ..
+            } => database.query(vec![filter], scope).await?,
+        };
+        Ok(events.into_iter().collect())
+    }
+
+    pub async fn disconnect(&self) {
+        if let Self::Remote(client) = self {
+            client.disconnect().await;
+        }
+    }
+}
+
+/// Re-signs the state events of every group with the active key, the first
+/// of `admin_keys`, replacing the ones signed by the previous keys. Returns
+/// the number of groups re-issued.
+pub async fn rotate_key(
+    database: Arc<RelayDatabase>,
+    admin_keys: &[Keys],
+    relay_url: &str,
+) -> Result<usize> {
+    let Some(relay_keys) = RelayPubkeys::from_keys(admin_keys) else {
+        bail!("No relay keys configured");
+    };
+    let groups = Groups::new(
+        Arc::clone(&database),
+        relay_keys.active(),
+        relay_url.to_string(),
+    )
+    .with_previous_relay_keys(relay_keys.previous().iter().copied());
+    groups.load_all().await?;
+
+    let commands = groups.reissue_state_events();
+    apply_store_commands(&database, &admin_keys[0], commands).await?;
+    Ok(groups.len())
+}
+
+/// Moves a group and its events from one scope to another, returning how
+/// many of its events were moved. A dry run only counts them.
+pub async fn move_group(
+    database: Arc<RelayDatabase>,
+    admin_keys: &[Keys],
+    relay_url: &str,
+    group_id: &str,
+    from: &Scope,
+    to: &Scope,
+    dry_run: bool,
+) -> Result<usize> {
+    let Some(relay_keys) = RelayPubkeys::from_keys(admin_keys) else {
+        bail!("No relay keys configured");
+    };
+    let groups = Groups::new(
+        Arc::clone(&database),
+        relay_keys.active(),
+        relay_url.to_string(),
+    )
+    .with_previous_relay_keys(relay_keys.previous().iter().copied());
+
+    let (moved, commands) = groups.move_group(group_id, from, to, dry_run).await?;
+    apply_store_commands(&database, &admin_keys[0], commands).await?;
+    Ok(moved)
+}
+
+/// Writes the events of a scope matching `filter` as JSON lines, oldest
+/// first, returning how many were written
+pub async fn export_events(
+    database: &RelayDatabase,
+    scope: &Scope,
+    filter: Filter,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let events: Vec<Event> = database
+        .query(vec![filter], scope)
+        .await?
+        .into_iter()
+        .collect();
+    for event in events.iter().rev() {
+        writeln!(out, "{}", event.as_json())?;
+    }
+    out.flush()?;
+    Ok(events.len())
+}
+
+/// What an import did with each line
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Already stored in the scope, or repeated in the input
+    pub duplicates: usize,
+    /// Events whose id or signature didn't verify
+    pub invalid: usize,
+}
+
+/// Stores the JSON-lines events of `input` into a scope, skipping the ones
+/// already there. Events are stored as signed, so their ids are unchanged.
+pub async fn import_events(
+    database: &RelayDatabase,
+    relay_keys: &Keys,
+    scope: &Scope,
+    input: impl BufRead,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut seen = HashSet::new();
+
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = Event::from_json(&line)
+            .with_context(|| format!("Line {} is not an event", number + 1))?;
+        if event.verify().is_err() {
+            report.invalid += 1;
+            continue;
+        }
+
+        if !seen.insert(event.id)
+            || !database
+                .query(vec![Filter::new().id(event.id)], scope)
+                .await?
+                .is_empty()
+        {
+            report.duplicates += 1;
+            continue;
+        }
+
+        let command = StoreCommand::SaveSignedEvent(Box::new(event), scope.clone(), None);
+        apply_store_commands(database, relay_keys, vec![command]).await?;
+        report.imported += 1;
+    }
+
+    Ok(report)
+}
+
+/// Rewrites the LMDB environment at `db_path` without its free pages, then
+/// swaps the compacted copy in. Returns the data file size before and after.
+///
+/// The relay must be stopped. The previous environment is kept next to it
+/// until the swap succeeded, so an interrupted compaction loses nothing.
+pub fn compact_database(db_path: &Path) -> Result<(u64, u64)> {
+    let data_file = |dir: &Path| dir.join("data.mdb");
+    let before = std::fs::metadata(data_file(db_path))
+        .with_context(|| format!("No LMDB environment at {}", db_path.display()))?
+        .len();
+
+    let sibling = |suffix: &str| {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let compacted = sibling(".compact");
+    let previous = sibling(".old");
+    if compacted.exists() || previous.exists() {
+        bail!(
+            "{} or {} exists, a previous compaction didn't finish",
+            compacted.display(),
+            previous.display()
+        );
+    }
+
+    std::fs::create_dir_all(&compacted)?;
+    {
+        // SAFETY: the relay is stopped, nothing else has the environment open
+        let env = unsafe { EnvOpenOptions::new().open(db_path)? };
+        env.copy_to_path(data_file(&compacted), CompactionOption::Enabled)?;
+    }
+    let after = std::fs::metadata(data_file(&compacted))?.len();
+
+    std::fs::rename(db_path, &previous)?;
+    std::fs::rename(&compacted, db_path)?;
+    std::fs::remove_dir_all(&previous)?;
+    Ok((before, after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_event, setup_test};
+
+    #[tokio::test]
+    async fn test_create_group_and_add_member_locally() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let member = Keys::generate().public_key();
+        let target = AdminTarget::local(database, &relay_keys, "wss://test.relay", Scope::Default)
+            .await
+            .unwrap();
+
+        let group_id = "admin_cli_group".to_string();
+        let actions = [
+            GroupAction::Create {
+                group_id: group_id.clone(),
+            },
+            GroupAction::SetMetadata {
+                group_id: group_id.clone(),
+                changes: MetadataChanges {
+                    name: Some("Operators".to_string()),
+                    private: Some(false),
+                    ..Default::default()
+                },
+            },
+            GroupAction::AddMember {
+                group_id: group_id.clone(),
+                pubkey: member,
+                role: None,
+            },
+        ];
+        for action in &actions {
+            target.apply(&relay_keys, action).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let events = target.group_events(Some(&group_id)).await.unwrap();
+        for kind in [
+            KIND_GROUP_METADATA_39000,
+            KIND_GROUP_ADMINS_39001,
+            KIND_GROUP_MEMBERS_39002,
+        ] {
+            assert!(events.iter().any(|event| event.kind == kind));
+        }
+
+        let summaries = summarize(&events);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].name, "Operators");
+        assert!(!summaries[0].private);
+        assert!(summaries[0].members.contains(&member.to_hex()));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_replaces_state_events() {
+        let (_tmp_dir, database, old_keys) = setup_test().await;
+        let new_keys = Keys::generate();
+        let target = AdminTarget::local(
+            Arc::clone(&database),
+            &old_keys,
+            "wss://test.relay",
+            Scope::Default,
+        )
+        .await
+        .unwrap();
+
+        let group_id = "rotated_group".to_string();
+        let create = GroupAction::Create {
+            group_id: group_id.clone(),
+        };
+        target.apply(&old_keys, &create).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let before = summarize(&target.group_events(Some(&group_id)).await.unwrap());
+
+        let rotated = rotate_key(
+            Arc::clone(&database),
+            &[new_keys.clone(), old_keys.clone()],
+            "wss://test.relay",
+        )
+        .await
+        .unwrap();
+        assert_eq!(rotated, 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let events = target.group_events(Some(&group_id)).await.unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|event| event.pubkey == new_keys.public_key()));
+        assert_eq!(summarize(&events), before);
+    }
+
+    #[tokio::test]
+    async fn test_move_group_between_scopes() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let from = Scope::named("old").unwrap();
+        let to = Scope::named("new").unwrap();
+        let target = AdminTarget::local(
+            Arc::clone(&database),
+            &relay_keys,
+            "wss://test.relay",
+            from.clone(),
+        )
+        .await
+        .unwrap();
+
+        let group_id = "moved_group".to_string();
+        let create = GroupAction::Create {
+            group_id: group_id.clone(),
+        };
+        target.apply(&relay_keys, &create).await.unwrap();
+        let note = EventBuilder::new(Kind::Custom(9), "hello")
+            .tag(Tag::custom(TagKind::h(), [group_id.as_str()]))
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        database.save_event(&note, &from).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let source_before = database
+            .query(vec![Filter::new()], &from)
+            .await
+            .unwrap()
+            .len();
+
+        let keys = [relay_keys.clone()];
+        let counted = move_group(
+            Arc::clone(&database),
+            &keys,
+            "wss://test.relay",
+            &group_id,
+            &from,
+            &to,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(counted, 2);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(
+            database
+                .query(vec![Filter::new()], &from)
+                .await
+                .unwrap()
+                .len(),
+            source_before
+        );
+        assert!(database
+            .query(vec![Filter::new()], &to)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let moved = move_group(
+            Arc::clone(&database),
+            &keys,
+            "wss://test.relay",
+            &group_id,
+            &from,
+            &to,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(moved, counted);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        assert!(database
+            .query(vec![Filter::new()], &from)
+            .await
+            .unwrap()
+            .is_empty());
+        let copied = database
+            .query(vec![Filter::new().id(note.id)], &to)
+            .await
+            .unwrap();
+        assert_eq!(copied.first().map(|event| event.sig), Some(note.sig));
+        let state = database
+            .query(
+                vec![Filter::new()
+                    .kind(KIND_GROUP_METADATA_39000)
+                    .identifier(&group_id)],
+                &to,
+            )
+            .await
+            .unwrap();
+        assert_eq!(state.len(), 1);
+
+        // Recreated in the source, the id is taken in the destination now
+        let target = AdminTarget::local(
+            Arc::clone(&database),
+            &relay_keys,
+            "wss://test.relay",
+            from.clone(),
+        )
+        .await
+        .unwrap();
+        target.apply(&relay_keys, &create).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let collision = move_group(
+            Arc::clone(&database),
+            &keys,
+            "wss://test.relay",
+            &group_id,
+            &from,
+            &to,
+            false,
+        )
+        .await;
+        assert!(collision.is_err());
+    }
+
+    #[test]
+    fn test_set_metadata_only_tags_given_fields() {
+        let action = GroupAction::SetMetadata {
+            group_id: "g".to_string(),
+            changes: MetadataChanges {
+                closed: Some(false),
+                broadcast: Some(true),
+                ..Default::default()
+            },
+        };
+        let event = action
+            .to_event_builder()
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        let names: Vec<&str> = event
+            .tags
+            .iter()
+            .map(|tag| tag.as_slice()[0].as_str())
+            .collect();
+        assert_eq!(names, ["h", "open", "broadcast"]);
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let scope = Scope::named("exported").unwrap();
+        let author = Keys::generate();
+        let mut events = Vec::new();
+        for kind in [1, 1, 7] {
+            let event = create_test_event(&author, kind, vec![]).await;
+            database.save_event(&event, &scope).await.unwrap();
+            events.push(event);
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let mut exported = Vec::new();
+        let written = export_events(&database, &scope, Filter::new(), &mut exported)
+            .await
+            .unwrap();
+        assert_eq!(written, 3);
+        let mut notes = Vec::new();
+        export_events(
+            &database,
+            &scope,
+            Filter::new().kind(Kind::TextNote),
+            &mut notes,
+        )
+        .await
+        .unwrap();
+        assert_eq!(String::from_utf8(notes).unwrap().lines().count(), 2);
+
+        let (_other_dir, fresh, _) = setup_test().await;
+        let report = import_events(&fresh, &relay_keys, &scope, exported.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 3,
+                ..Default::default()
+            }
+        );
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let ids =
+            |events: Events| -> HashSet<EventId> { events.into_iter().map(|e| e.id).collect() };
+        let original = ids(database.query(vec![Filter::new()], &scope).await.unwrap());
+        let imported = ids(fresh.query(vec![Filter::new()], &scope).await.unwrap());
+        assert_eq!(original, imported);
+        assert_eq!(original, events.iter().map(|e| e.id).collect());
+        assert!(fresh
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Importing again only finds duplicates
+        let report = import_events(&fresh, &relay_keys, &scope, exported.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(report.duplicates, 3);
+        assert_eq!(report.imported, 0);
+    }
+}
@@ -0,0 +1,81 @@
+//! The keys a relay acts as.
+//!
+//! The relay's active key signs every event it generates. After a rotation,
+//! the keys it used before stay recognized as the relay in authorization
+//! checks, so admin actions signed with them keep working until every
+//! deployment has moved to the new key.
+
+use nostr_sdk::prelude::*;
+
+/// Who counts as the relay in group authorization
+///
+/// A bare [`PublicKey`] is a relay with a single key. [`RelayPubkeys`] also
+/// recognizes the keys the relay was rotated away from.
+pub trait RelayIdentity {
+    /// The key that signs relay-generated events
+    fn signing_key(&self) -> PublicKey;
+
+    /// Whether `pubkey` is one of the relay's keys
+    fn is_relay(&self, pubkey: &PublicKey) -> bool;
+}
+
+impl RelayIdentity for PublicKey {
+    fn signing_key(&self) -> PublicKey {
+        *self
+    }
+
+    fn is_relay(&self, pubkey: &PublicKey) -> bool {
+        self == pubkey
+    }
+}
+
+/// The active relay key and the previous keys still recognized as the relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayPubkeys {
+    active: PublicKey,
+    previous: Vec<PublicKey>,
+}
+
+impl RelayPubkeys {
+    pub fn new(active: PublicKey) -> Self {
+        Self {
+            active,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Builds the set from configured admin keys, the first one being active
+    pub fn from_keys(keys: &[Keys]) -> Option<Self> {
+        let (active, previous) = keys.split_first()?;
+        Some(Self::new(active.public_key()).with_previous(previous.iter().map(Keys::public_key)))
+    }
+
+    /// Also recognizes `previous` as the relay
+    pub fn with_previous(mut self, previous: impl IntoIterator<Item = PublicKey>) -> Self {
+        for pubkey in previous {
+            if pubkey != self.active && !self.previous.contains(&pubkey) {
+                self.previous.push(pubkey);
+            }
+        }
+        self
+    }
+
+    pub fn active(&self) -> PublicKey {
+        self.active
+    }
+
+    /// Keys the relay was rotated away from
+    pub fn previous(&self) -> &[PublicKey] {
+        &self.previous
+    }
+}
+
+impl RelayIdentity for RelayPubkeys {
+    fn signing_key(&self) -> PublicKey {
+        self.active
+    }
+
+    fn is_relay(&self, pubkey: &PublicKey) -> bool {
+        self.active == *pubkey || self.previous.contains(pubkey)
+    }
+}
@@ -0,0 +1,169 @@
+//! The relay's own profile.
+//!
+//! Clients and other relays discover operators through the relay's kind 0
+//! profile and its kind 10002 relay list. Both are generated from the
+//! `profile` settings on startup, signed by the relay key and stored in the
+//! default scope, replacing the previous ones only when the settings changed.
+//! They can also be sent to indexer relays so the relay is found elsewhere.
+
+use crate::config::RelayProfileSettings;
+use crate::RelayDatabase;
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use tracing::{info, warn};
+
+/// The profile and relay list events described by the settings
+pub fn profile_builders(
+    settings: &RelayProfileSettings,
+    relay_url: &str,
+) -> Result<Vec<EventBuilder>> {
+    let mut metadata = Metadata::new();
+    if let Some(name) = &settings.name {
+        metadata = metadata.name(name);
+    }
+    if let Some(about) = &settings.about {
+        metadata = metadata.about(about);
+    }
+    if let Some(picture) = &settings.picture {
+        metadata = metadata.picture(Url::parse(picture)?);
+    }
+    if let Some(website) = &settings.website {
+        metadata = metadata.website(Url::parse(website)?);
+    }
+
+    Ok(vec![
+        EventBuilder::metadata(&metadata),
+        EventBuilder::relay_list([(RelayUrl::parse(relay_url)?, None)]),
+    ])
+}
+
+/// Stores the relay's profile events, returning the current ones.
+///
+/// Stored events that already match the settings are kept as they are.
+/// Changed ones are re-signed with a newer timestamp, so the replaceable
+/// event path replaces them even within the same second.
+pub async fn publish_profile(
+    database: &RelayDatabase,
+    keys: &Keys,
+    settings: &RelayProfileSettings,
+    relay_url: &str,
+) -> Result<Vec<Event>> {
+    let pubkey = keys.public_key();
+    let stored = database
+        .query(
+            vec![Filter::new()
+                .author(pubkey)
+                .kinds([Kind::Metadata, Kind::RelayList])],
+            &Scope::Default,
+        )
+        .await?;
+
+    let mut current = Vec::new();
+    for builder in profile_builders(settings, relay_url)? {
+        let unsigned = builder.clone().build(pubkey);
+        let previous = stored.iter().find(|event| event.kind == unsigned.kind);
+        if let Some(previous) = previous {
+            if previous.content == unsigned.content && previous.tags == unsigned.tags {
+                current.push(previous.clone());
+                continue;
+            }
+        }
+
+        let created_at = match previous {
+            Some(previous) if previous.created_at >= Timestamp::now() => {
+                previous.created_at + 1_u64
+            }
+            _ => Timestamp::now(),
+        };
+        let event = builder.custom_created_at(created_at).sign_with_keys(keys)?;
+        database.save_event(&event, &Scope::Default).await?;
+        info!("Published relay {} event {}", event.kind, event.id);
+        current.push(event);
+    }
+
+    Ok(current)
+}
+
+/// Sends the profile events to indexer relays in the background.
+pub fn spawn_indexer_publish(keys: Keys, indexer_relays: Vec<String>, events: Vec<Event>) {
+    if indexer_relays.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let client = ClientBuilder::default().signer(keys).build();
+        for relay in &indexer_relays {
+            if let Err(e) = client.add_relay(relay.as_str()).await {
+                warn!("Skipping indexer relay {}: {}", relay, e);
+            }
+        }
+        client.connect().await;
+
+        for event in &events {
+            match client.send_event(event).await {
+                Ok(output) if output.failed.is_empty() => {}
+                Ok(output) => warn!(
+                    "Indexer relays rejected relay event {}: {:?}",
+                    event.id, output.failed
+                ),
+                Err(e) => warn!("Failed to send relay event {} to indexers: {}", event.id, e),
+            }
+        }
+        client.disconnect().await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_profile_is_stored_and_replaced_on_change() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let mut settings = RelayProfileSettings {
+            name: Some("Groups".to_string()),
+            about: Some("A NIP-29 relay".to_string()),
+            website: Some("https://groups.example.com".to_string()),
+            ..Default::default()
+        };
+        let profile = |kind: Kind| Filter::new().author(relay_keys.public_key()).kind(kind);
+
+        let first = publish_profile(&database, &relay_keys, &settings, "wss://test.relay")
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 2);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let relay_list = database
+            .query(vec![profile(Kind::RelayList)], &Scope::Default)
+            .await
+            .unwrap();
+        let relay_tag = relay_list.first().unwrap().tags.first().unwrap();
+        assert_eq!(relay_tag.content(), Some("wss://test.relay"));
+
+        // Unchanged settings keep the stored events
+        let again = publish_profile(&database, &relay_keys, &settings, "wss://test.relay")
+            .await
+            .unwrap();
+        assert_eq!(
+            again.iter().map(|e| e.id).collect::<Vec<_>>(),
+            first.iter().map(|e| e.id).collect::<Vec<_>>()
+        );
+
+        settings.about = Some("Moved to a new home".to_string());
+        publish_profile(&database, &relay_keys, &settings, "wss://test.relay")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let profiles = database
+            .query(vec![profile(Kind::Metadata)], &Scope::Default)
+            .await
+            .unwrap();
+        assert_eq!(profiles.len(), 1);
+        let metadata = Metadata::from_json(&profiles.first().unwrap().content).unwrap();
+        assert_eq!(metadata.about.as_deref(), Some("Moved to a new home"));
+        assert_eq!(metadata.name.as_deref(), Some("Groups"));
+    }
+}
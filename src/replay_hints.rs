@@ -0,0 +1,305 @@
+//! Automatic `since` for subscriptions repeated after a reconnect.
+//!
+//! Mobile clients reconnect often and send the same REQ again, getting every
+//! stored event they already have once more. When enabled, the relay
+//! remembers until when each authenticated user held a subscription open,
+//! keyed by scope and a fingerprint of its filters. A later REQ from the same
+//! user with identical filters, none of which sets `since` or `limit`, gets
+//! `since` set to that time, so only events created since the previous
+//! session are replayed.
+//!
+//! This changes what a REQ returns: an event stored late with an older
+//! `created_at` is not sent again. That's why it's opt-in.
+//!
+//! Sessions are remembered in an in-memory LRU and forgotten on restart.
+
+use crate::metrics;
+use crate::utils::scope_name;
+use dashmap::DashMap;
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::nostr_middleware::{DisconnectContext, InboundContext, NostrMiddleware};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Number of (user, filters) sessions remembered
+pub const REPLAY_HINT_CAPACITY: usize = 10_000;
+
+/// A user's subscription to the same filters in the same scope
+type SessionKey = (PublicKey, String);
+
+#[derive(Debug)]
+pub struct ReplayHints {
+    /// Until when each session was last known to be subscribed
+    covered: Mutex<LruCache<SessionKey, Timestamp>>,
+    /// Open subscriptions per connection, by subscription id
+    open: DashMap<String, HashMap<String, SessionKey>>,
+}
+
+impl ReplayHints {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            covered: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("capacity is non-zero"),
+            )),
+            open: DashMap::new(),
+        }
+    }
+
+    fn session_key(pubkey: PublicKey, scope: &Scope, filters: &[Filter]) -> SessionKey {
+        let mut hasher = Sha256::new();
+        hasher.update(scope_name(scope).as_bytes());
+        for filter in filters {
+            hasher.update(filter.as_json().as_bytes());
+        }
+        (pubkey, hex::encode(hasher.finalize()))
+    }
+
+    /// Starts tracking a subscription and sets `since` on its filters if the
+    /// same user held the same subscription before. Returns the `since` set.
+    pub fn subscribe(
+        &self,
+        connection_id: &str,
+        subscription_id: &SubscriptionId,
+        pubkey: PublicKey,
+        scope: &Scope,
+        filters: &mut [Filter],
+    ) -> Option<Timestamp> {
+        // Replacing a subscription ends the previous one
+        self.close(connection_id, subscription_id);
+
+        let key = Self::session_key(pubkey, scope, filters);
+        let explicit = filters
+            .iter()
+            .any(|filter| filter.since.is_some() || filter.limit.is_some());
+        let since = if explicit {
+            None
+        } else {
+            self.covered.lock().get(&key).copied()
+        };
+
+        if let Some(since) = since {
+            for filter in filters.iter_mut() {
+                filter.since = Some(since);
+            }
+            metrics::replay_hints().increment(1);
+        }
+
+        self.open
+            .entry(connection_id.to_string())
+            .or_default()
+            .insert(subscription_id.to_string(), key);
+        since
+    }
+
+    pub fn close(&self, connection_id: &str, subscription_id: &SubscriptionId) {
+        self.close_at(connection_id, subscription_id, Timestamp::now());
+    }
+
+    fn close_at(&self, connection_id: &str, subscription_id: &SubscriptionId, at: Timestamp) {
+        let key = self
+            .open
+            .get_mut(connection_id)
+            .and_then(|mut subscriptions| subscriptions.remove(subscription_id.as_str()));
+        if let Some(key) = key {
+            self.covered.lock().put(key, at);
+        }
+    }
+
+    pub fn disconnect(&self, connection_id: &str) {
+        self.disconnect_at(connection_id, Timestamp::now());
+    }
+
+    fn disconnect_at(&self, connection_id: &str, at: Timestamp) {
+        let Some((_, subscriptions)) = self.open.remove(connection_id) else {
+            return;
+        };
+        let mut covered = self.covered.lock();
+        for key in subscriptions.into_values() {
+            covered.put(key, at);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayHintsMiddleware {
+    hints: Arc<ReplayHints>,
+    enabled: bool,
+}
+
+impl ReplayHintsMiddleware {
+    pub fn new(hints: Arc<ReplayHints>, enabled: bool) -> Self {
+        Self { hints, enabled }
+    }
+}
+
+impl NostrMiddleware<()> for ReplayHintsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        mut ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if !self.enabled {
+            return ctx.next().await;
+        }
+        let connection_id = ctx.connection_id.to_string();
+
+        if let Some(ClientMessage::Close(subscription_id)) = &ctx.message {
+            self.hints.close(&connection_id, subscription_id);
+            return ctx.next().await;
+        }
+        if !matches!(
+            ctx.message,
+            Some(ClientMessage::Req { .. }) | Some(ClientMessage::ReqMultiFilter { .. })
+        ) {
+            return ctx.next().await;
+        }
+
+        let (authed_pubkey, scope) = {
+            let state = ctx.state.read().await;
+            (state.authed_pubkey, state.subdomain().clone())
+        };
+        let Some(pubkey) = authed_pubkey else {
+            return ctx.next().await;
+        };
+
+        let hinted = match ctx.message.as_mut() {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => {
+                let mut filters = [filter.as_ref().clone()];
+                let since = self.hints.subscribe(
+                    &connection_id,
+                    subscription_id,
+                    pubkey,
+                    &scope,
+                    &mut filters,
+                );
+                if since.is_some() {
+                    let [hinted] = filters;
+                    *filter.to_mut() = hinted;
+                }
+                since.map(|since| (subscription_id.to_string(), since))
+            }
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => self
+                .hints
+                .subscribe(&connection_id, subscription_id, pubkey, &scope, filters)
+                .map(|since| (subscription_id.to_string(), since)),
+            _ => None,
+        };
+
+        if let Some((subscription_id, since)) = hinted {
+            debug!(
+                "[{}] Replaying subscription {} since {}",
+                connection_id, subscription_id, since
+            );
+        }
+        ctx.next().await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ()>) -> Result<(), anyhow::Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.hints.disconnect(&ctx.connection_id.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reconnect_only_replays_newer_events() {
+        let (_tmp_dir, database, _relay_keys) = setup_test().await;
+        let hints = ReplayHints::new(REPLAY_HINT_CAPACITY);
+        let author = Keys::generate();
+        let reader = Keys::generate().public_key();
+        let scope = Scope::Default;
+        let subscription_id = SubscriptionId::new("feed");
+        let filter = Filter::new()
+            .author(author.public_key())
+            .kind(Kind::TextNote);
+        let now = Timestamp::now();
+
+        let note = |content: &str, created_at: Timestamp| {
+            EventBuilder::text_note(content)
+                .custom_created_at(created_at)
+                .sign_with_keys(&author)
+                .unwrap()
+        };
+        for i in 0..3 {
+            let event = note(&format!("old {i}"), now - Duration::from_secs(100 - i));
+            database.save_event(&event, &scope).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // First session gets everything
+        let mut filters = [filter.clone()];
+        assert_eq!(
+            hints.subscribe("conn1", &subscription_id, reader, &scope, &mut filters),
+            None
+        );
+        let first = database.query(filters.to_vec(), &scope).await.unwrap();
+        assert_eq!(first.len(), 3);
+        hints.disconnect_at("conn1", now);
+
+        for i in 0..2 {
+            let event = note(&format!("new {i}"), now + Duration::from_secs(5 + i));
+            database.save_event(&event, &scope).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // After reconnecting, the same REQ only yields events from after the
+        // first session
+        let mut filters = [filter.clone()];
+        assert_eq!(
+            hints.subscribe("conn2", &subscription_id, reader, &scope, &mut filters),
+            Some(now)
+        );
+        let second = database.query(filters.to_vec(), &scope).await.unwrap();
+        assert_eq!(second.len(), 2);
+        assert!(second.iter().all(|event| event.content.starts_with("new")));
+
+        // An explicit limit keeps the usual semantics
+        let mut limited = [filter.clone().limit(10)];
+        hints.close_at("conn2", &subscription_id, now);
+        assert_eq!(
+            hints.subscribe("conn2", &subscription_id, reader, &scope, &mut limited),
+            None
+        );
+        assert_eq!(limited[0].since, None);
+
+        // Other users and other filters aren't hinted
+        let mut filters = [filter.clone()];
+        assert_eq!(
+            hints.subscribe(
+                "conn3",
+                &subscription_id,
+                Keys::generate().public_key(),
+                &scope,
+                &mut filters
+            ),
+            None
+        );
+        let mut other = [filter.kind(Kind::Reaction)];
+        assert_eq!(
+            hints.subscribe("conn3", &subscription_id, reader, &scope, &mut other),
+            None
+        );
+    }
+}
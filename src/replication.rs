@@ -0,0 +1,500 @@
+//! Push replication of group content to peer relays.
+//!
+//! When enabled, every event the groups processor stores for a replicated
+//! scope is forwarded to the configured peer relays through a nostr_sdk
+//! client signed with the relay keys, once the event is committed. Events
+//! are forwarded as stored, 39xxx state events signed by the relay, so peers
+//! receive exactly what a client reading from this relay would see.
+//!
+//! NIP-70 protected events are never forwarded: only their author may
+//! publish them to another relay.
+//!
+//! Events go through a bounded in-memory outbox; when a peer is down the
+//! outbox fills up and further events are dropped (and counted) instead of
+//! growing without bound. Events published by a peer relay, or already
+//! forwarded once, are never sent again, and events we forwarded that come
+//! back through another relay are dropped on ingestion, so relays replicating
+//! to each other do not loop. Seen ids are tracked by a [`SeenEvents`]
+//! window, persisted across restarts by the server.
+
+use crate::config::ReplicationSettings;
+use crate::metrics;
+use crate::seen_events::{MemorySeenEvents, Origin, SeenEvents};
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How many recently seen event ids are kept in memory for loop prevention
+pub const SEEN_CACHE_SIZE: usize = 10_000;
+
+#[derive(Debug)]
+pub struct Replicator {
+    keys: Keys,
+    scopes: HashSet<String>,
+    peer_pubkeys: HashSet<PublicKey>,
+    outbox: mpsc::Sender<Event>,
+    seen: Arc<dyn SeenEvents>,
+}
+
+impl Replicator {
+    /// Creates a replicator and the receiving end of its outbox.
+    pub fn new(
+        settings: &ReplicationSettings,
+        keys: Keys,
+    ) -> Result<(Self, mpsc::Receiver<Event>)> {
+        let peer_pubkeys = settings
+            .peer_pubkeys
+            .iter()
+            .map(|pk| PublicKey::parse(pk))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let (outbox, receiver) = mpsc::channel(settings.outbox_capacity.max(1));
+
+        let replicator = Self {
+            keys,
+            scopes: settings.scopes.iter().cloned().collect(),
+            peer_pubkeys,
+            outbox,
+            seen: Arc::new(MemorySeenEvents::new(SEEN_CACHE_SIZE)),
+        };
+
+        Ok((replicator, receiver))
+    }
+
+    /// Use another window of seen event ids, e.g. one that survives restarts
+    pub fn with_seen_events(mut self, seen: Arc<dyn SeenEvents>) -> Self {
+        self.seen = seen;
+        self
+    }
+
+    /// Connects to the peers and starts forwarding queued events.
+    pub async fn start(
+        settings: &ReplicationSettings,
+        keys: Keys,
+        seen: Arc<dyn SeenEvents>,
+        cancellation_token: CancellationToken,
+    ) -> Result<Arc<Self>> {
+        let (replicator, receiver) = Self::new(settings, keys.clone())?;
+        let replicator = replicator.with_seen_events(seen);
+
+        let client = ClientBuilder::default().signer(keys).build();
+        for peer in &settings.peers {
+            client.add_relay(peer.as_str()).await?;
+        }
+        client.connect().await;
+        info!("Replicating to {} peer relays", settings.peers.len());
+
+        tokio::spawn(run_outbox(
+            client,
+            receiver,
+            settings.max_retries,
+            settings.retry_backoff,
+            cancellation_token,
+        ));
+
+        Ok(Arc::new(replicator))
+    }
+
+    /// Queues the events produced by the given store commands for replication.
+    ///
+    /// `authed_pubkey` is the pubkey of the connection that sent the original
+    /// event; events sent by a peer relay are not forwarded again.
+    pub fn replicate(&self, commands: &[StoreCommand], authed_pubkey: Option<&PublicKey>) {
+        if authed_pubkey.is_some_and(|pk| self.peer_pubkeys.contains(pk)) {
+            debug!("Skipping replication of events received from a peer");
+            for command in commands {
+                if let StoreCommand::SaveSignedEvent(event, _, _) = command {
+                    self.seen.insert(event.id, Origin::Received);
+                }
+            }
+            return;
+        }
+
+        for command in commands {
+            let event = match command {
+                StoreCommand::SaveSignedEvent(event, scope, _) if self.replicates(scope) => {
+                    (**event).clone()
+                }
+                StoreCommand::SaveUnsignedEvent(unsigned, scope, _) if self.replicates(scope) => {
+                    match unsigned.clone().sign_with_keys(&self.keys) {
+                        Ok(event) => event,
+                        Err(e) => {
+                            warn!("Failed to sign state event for replication: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            };
+            if event.is_protected() {
+                debug!("Not replicating protected event {}", event.id);
+                continue;
+            }
+
+            self.enqueue(event);
+        }
+    }
+
+    fn replicates(&self, scope: &Scope) -> bool {
+        if self.scopes.is_empty() {
+            return true;
+        }
+
+        match scope {
+            Scope::Default => self.scopes.contains("default"),
+            Scope::Named { name, .. } => self.scopes.contains(name),
+        }
+    }
+
+    /// Whether an incoming event is one we forwarded coming back through
+    /// another relay.
+    ///
+    /// Relays publish mirrored events authenticated with their own key, so an
+    /// event whose author sends it again is a client retry, not a loop.
+    pub fn is_mirrored_back(&self, event: &Event, authed_pubkey: Option<&PublicKey>) -> bool {
+        if authed_pubkey == Some(&event.pubkey) {
+            return false;
+        }
+
+        let looped = self.seen.get(&event.id) == Some(Origin::Forwarded);
+        if looped {
+            debug!("Event {} came back from another relay, dropping", event.id);
+            metrics::replication_loop_drops("inbound").increment(1);
+        }
+        looped
+    }
+
+    fn enqueue(&self, event: Event) {
+        if let Some(origin) = self.seen.insert(event.id, Origin::Forwarded) {
+            debug!("Event {} already seen ({:?}), skipping", event.id, origin);
+            metrics::replication_loop_drops("outbound").increment(1);
+            return;
+        }
+
+        if let Err(e) = self.outbox.try_send(event) {
+            warn!("Replication outbox full, dropping event: {}", e);
+            metrics::replication_dropped().increment(1);
+        }
+    }
+}
+
+async fn run_outbox(
+    client: Client,
+    mut receiver: mpsc::Receiver<Event>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            event = receiver.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
+        let mut targets: Vec<RelayUrl> = client.relays().await.into_keys().collect();
+        let mut backoff = retry_backoff;
+
+        for attempt in 0..=max_retries {
+            match client.send_event_to(targets.clone(), &event).await {
+                Ok(output) if output.failed.is_empty() => {
+                    metrics::replicated_events().increment(1);
+                    targets.clear();
+                    break;
+                }
+                Ok(output) => {
+                    targets = output.failed.into_keys().collect();
+                }
+                Err(e) => {
+                    debug!(
+                        "Replication attempt {} for {} failed: {}",
+                        attempt, event.id, e
+                    );
+                }
+            }
+
+            if attempt < max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+
+        if !targets.is_empty() {
+            warn!(
+                "Giving up replicating event {} to {} peers",
+                event.id,
+                targets.len()
+            );
+            metrics::replication_failures().increment(1);
+        }
+    }
+
+    client.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{Groups, KIND_GROUP_CREATE_9007};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::{create_test_event, create_test_keys, setup_test};
+    use crate::utils::apply_store_commands;
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::RwLock;
+
+    fn settings() -> ReplicationSettings {
+        ReplicationSettings {
+            peers: vec!["wss://standby.example.com".to_string()],
+            scopes: vec![],
+            peer_pubkeys: vec![],
+            outbox_capacity: 2,
+            max_retries: 0,
+            retry_backoff: Duration::from_millis(1),
+            seen_horizon: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicates_signed_and_unsigned_events() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (replicator, mut outbox) = Replicator::new(&settings(), relay_keys.clone()).unwrap();
+
+        let event = create_test_event(&member_keys, 11, vec![]).await;
+        let unsigned = EventBuilder::new(Kind::Custom(39000), "").build(relay_keys.public_key());
+
+        replicator.replicate(
+            &[
+                StoreCommand::SaveSignedEvent(Box::new(event.clone()), Scope::Default, None),
+                StoreCommand::SaveUnsignedEvent(unsigned, Scope::Default, None),
+            ],
+            Some(&member_keys.public_key()),
+        );
+
+        assert_eq!(outbox.recv().await.unwrap().id, event.id);
+        let state_event = outbox.recv().await.unwrap();
+        assert_eq!(state_event.pubkey, relay_keys.public_key());
+        assert!(state_event.verify().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_skips_peer_events_and_duplicates() {
+        let (relay_keys, member_keys, peer_keys) = create_test_keys().await;
+        let mut settings = settings();
+        settings.peer_pubkeys = vec![peer_keys.public_key().to_hex()];
+        let (replicator, mut outbox) = Replicator::new(&settings, relay_keys).unwrap();
+
+        let event = create_test_event(&member_keys, 11, vec![]).await;
+        let command = StoreCommand::SaveSignedEvent(Box::new(event.clone()), Scope::Default, None);
+
+        // Received from a peer relay: never forwarded back
+        replicator.replicate(
+            std::slice::from_ref(&command),
+            Some(&peer_keys.public_key()),
+        );
+        assert!(outbox.try_recv().is_err());
+
+        // Forwarded once, then remembered
+        replicator.replicate(
+            std::slice::from_ref(&command),
+            Some(&member_keys.public_key()),
+        );
+        replicator.replicate(
+            std::slice::from_ref(&command),
+            Some(&member_keys.public_key()),
+        );
+        assert_eq!(outbox.try_recv().unwrap().id, event.id);
+        assert!(outbox.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_configured_scopes_are_replicated() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let mut settings = settings();
+        settings.scopes = vec!["groups".to_string()];
+        let (replicator, mut outbox) = Replicator::new(&settings, relay_keys).unwrap();
+
+        let default_event = create_test_event(&member_keys, 11, vec![]).await;
+        let scoped_event = create_test_event(&member_keys, 12, vec![]).await;
+
+        replicator.replicate(
+            &[
+                StoreCommand::SaveSignedEvent(Box::new(default_event), Scope::Default, None),
+                StoreCommand::SaveSignedEvent(
+                    Box::new(scoped_event.clone()),
+                    Scope::named("groups").unwrap(),
+                    None,
+                ),
+            ],
+            None,
+        );
+
+        assert_eq!(outbox.try_recv().unwrap().id, scoped_event.id);
+        assert!(outbox.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_protected_events_are_not_replicated() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (replicator, mut outbox) = Replicator::new(&settings(), relay_keys).unwrap();
+
+        let protected = create_test_event(&member_keys, 9, vec![Tag::protected()]).await;
+        replicator.replicate(
+            &[StoreCommand::SaveSignedEvent(
+                Box::new(protected),
+                Scope::Default,
+                None,
+            )],
+            Some(&member_keys.public_key()),
+        );
+
+        assert!(outbox.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_is_bounded() {
+        let (relay_keys, member_keys, _) = create_test_keys().await;
+        let (replicator, mut outbox) = Replicator::new(&settings(), relay_keys).unwrap();
+
+        for kind in 11..15 {
+            let event = create_test_event(&member_keys, kind, vec![]).await;
+            replicator.replicate(
+                &[StoreCommand::SaveSignedEvent(
+                    Box::new(event),
+                    Scope::Default,
+                    None,
+                )],
+                None,
+            );
+        }
+
+        // Capacity is 2, the rest was dropped
+        assert!(outbox.try_recv().is_ok());
+        assert!(outbox.try_recv().is_ok());
+        assert!(outbox.try_recv().is_err());
+    }
+
+    struct MirroredRelay {
+        _tmp_dir: tempfile::TempDir,
+        database: Arc<crate::RelayDatabase>,
+        keys: Keys,
+        processor: GroupsRelayProcessor,
+        outbox: mpsc::Receiver<Event>,
+    }
+
+    impl MirroredRelay {
+        async fn new() -> Self {
+            let (_tmp_dir, database, keys) = setup_test().await;
+            let groups = Arc::new(
+                Groups::load_groups(
+                    database.clone(),
+                    keys.public_key(),
+                    "wss://test.relay".to_string(),
+                )
+                .await
+                .unwrap(),
+            );
+            let mut settings = settings();
+            settings.outbox_capacity = 1_000;
+            let (replicator, outbox) = Replicator::new(&settings, keys.clone()).unwrap();
+            let processor = GroupsRelayProcessor::new(groups, keys.public_key())
+                .with_replicator(Arc::new(replicator));
+
+            Self {
+                _tmp_dir,
+                database,
+                keys,
+                processor,
+                outbox,
+            }
+        }
+
+        /// Handles an event as if sent by `sender`, ignoring rejections
+        async fn receive(&self, event: Event, sender: PublicKey) {
+            let context = EventContext {
+                authed_pubkey: Some(sender),
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey: self.keys.public_key(),
+            };
+            if let Ok(commands) = self
+                .processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+            {
+                apply_store_commands(&self.database, &self.keys, commands)
+                    .await
+                    .unwrap();
+            }
+            // Forwarded once the save is committed
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+
+        fn drain(&mut self) -> Vec<Event> {
+            std::iter::from_fn(|| self.outbox.try_recv().ok()).collect()
+        }
+
+        async fn count(&self, kind: Kind) -> usize {
+            self.database
+                .query(vec![Filter::new().kind(kind)], &Scope::Default)
+                .await
+                .unwrap()
+                .len()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relays_mirroring_each_other_converge() {
+        let mut relay_a = MirroredRelay::new().await;
+        let mut relay_b = MirroredRelay::new().await;
+        let user = Keys::generate();
+        let h_tag = Tag::custom(TagKind::h(), ["mirrored_group"]);
+
+        // Neither relay knows the other's pubkey, only event ids stop the loop
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h_tag.clone())
+            .sign_with_keys(&user)
+            .unwrap();
+        relay_a.receive(create, user.public_key()).await;
+        for i in 0..3 {
+            let message = EventBuilder::new(Kind::Custom(9), format!("message {i}"))
+                .tag(h_tag.clone())
+                .sign_with_keys(&user)
+                .unwrap();
+            relay_a.receive(message, user.public_key()).await;
+        }
+
+        let mut rounds = 0;
+        loop {
+            let to_b = relay_a.drain();
+            let to_a = relay_b.drain();
+            if to_a.is_empty() && to_b.is_empty() {
+                break;
+            }
+            rounds += 1;
+            assert!(
+                rounds <= 5,
+                "events still circulating after {rounds} rounds"
+            );
+
+            let (a_pubkey, b_pubkey) = (relay_a.keys.public_key(), relay_b.keys.public_key());
+            for event in to_b {
+                relay_b.receive(event, a_pubkey).await;
+            }
+            for event in to_a {
+                relay_a.receive(event, b_pubkey).await;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        assert_eq!(relay_a.count(Kind::Custom(9)).await, 3);
+        assert_eq!(relay_b.count(Kind::Custom(9)).await, 3);
+        assert_eq!(relay_b.count(KIND_GROUP_CREATE_9007).await, 1);
+    }
+}
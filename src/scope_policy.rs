@@ -0,0 +1,387 @@
+//! Which subdomains the relay serves as scopes.
+//!
+//! relay_builder turns the subdomain of the Host a client connects to into
+//! a `Scope::Named`, so by default any subdomain becomes a scope on its first
+//! write and anyone can squat a name. [`ScopePolicy`] limits that:
+//!
+//! - `open` serves every subdomain, as before
+//! - `allowlist` serves only the configured names
+//! - `claimable` serves the configured names, the scopes already stored, and
+//!   new ones claimed by a provisioner: an authenticated pubkey from the
+//!   configured list creating the first group (9007) in it
+//!
+//! The root domain is always served. Messages sent on a connection to a
+//! scope that isn't served are turned down at the front of the chain: EVENTs
+//! with an OK, REQs with a CLOSED and anything else with a NOTICE. AUTH is
+//! let through in `claimable` mode so a provisioner can authenticate first.
+//! Names are matched whole, so a nested subdomain like `a.team` has to be
+//! listed itself.
+//!
+//! Claimed scopes are kept in memory; after a restart they are served
+//! because their groups are stored.
+
+use crate::config::{ScopeMode, ScopeSettings};
+use crate::groups::{Groups, KIND_GROUP_CREATE_9007};
+use crate::rejections::{ReasonCode, Rejections};
+use crate::utils::scope_name;
+use crate::RelayDatabase;
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+pub const UNKNOWN_SCOPE_MESSAGE: &str = "restricted: this subdomain is not served by this relay";
+
+/// What to do with a message sent to a scope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeDecision {
+    Allow,
+    /// A provisioner creating the first group of a new scope
+    Claim,
+    Reject,
+}
+
+#[derive(Debug)]
+pub struct ScopePolicy {
+    mode: ScopeMode,
+    /// Configured, stored and claimed scope names
+    served: RwLock<HashSet<String>>,
+    provisioners: HashSet<PublicKey>,
+}
+
+impl ScopePolicy {
+    /// A policy serving the configured names, and in `claimable` mode the
+    /// scopes already stored too.
+    pub fn new(settings: &ScopeSettings, stored: impl IntoIterator<Item = Scope>) -> Result<Self> {
+        let mut served: HashSet<String> = settings
+            .names
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+        if settings.mode == ScopeMode::Claimable {
+            served.extend(
+                stored
+                    .into_iter()
+                    .filter(|scope| matches!(scope, Scope::Named { .. }))
+                    .map(|scope| scope_name(&scope).to_lowercase()),
+            );
+        }
+        let provisioners = settings
+            .provisioners
+            .iter()
+            .map(|pk| PublicKey::parse(pk))
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        Ok(Self {
+            mode: settings.mode,
+            served: RwLock::new(served),
+            provisioners,
+        })
+    }
+
+    pub fn mode(&self) -> ScopeMode {
+        self.mode
+    }
+
+    pub fn is_served(&self, scope: &Scope) -> bool {
+        match scope {
+            Scope::Default => true,
+            Scope::Named { name, .. } => {
+                self.mode == ScopeMode::Open || self.served.read().contains(&name.to_lowercase())
+            }
+        }
+    }
+
+    pub fn check(
+        &self,
+        scope: &Scope,
+        message: &ClientMessage,
+        authed_pubkey: Option<&PublicKey>,
+    ) -> ScopeDecision {
+        if self.is_served(scope) {
+            return ScopeDecision::Allow;
+        }
+        if self.mode != ScopeMode::Claimable {
+            return ScopeDecision::Reject;
+        }
+
+        match message {
+            ClientMessage::Auth(_) => ScopeDecision::Allow,
+            ClientMessage::Event(event)
+                if event.kind == KIND_GROUP_CREATE_9007
+                    && authed_pubkey == Some(&event.pubkey)
+                    && self.provisioners.contains(&event.pubkey) =>
+            {
+                ScopeDecision::Claim
+            }
+            _ => ScopeDecision::Reject,
+        }
+    }
+
+    /// Starts serving a scope
+    pub fn claim(&self, scope: &Scope) {
+        if let Scope::Named { name, .. } = scope {
+            if self.served.write().insert(name.to_lowercase()) {
+                info!("Scope {} claimed", name);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopeSummary {
+    pub name: String,
+    pub served: bool,
+    pub groups: usize,
+    pub events: usize,
+}
+
+/// Every stored scope with its group and event counts, by name
+pub async fn list_scopes(
+    database: &RelayDatabase,
+    groups: &Groups,
+    policy: &ScopePolicy,
+) -> Result<Vec<ScopeSummary>> {
+    let mut group_counts: HashMap<Scope, usize> = HashMap::new();
+    for (scope, _, _) in groups.list_all_groups() {
+        *group_counts.entry(scope).or_default() += 1;
+    }
+
+    let mut summaries = Vec::new();
+    for scope in database.list_scopes().await? {
+        let events = database.query(vec![Filter::new()], &scope).await?.len();
+        summaries.push(ScopeSummary {
+            name: scope_name(&scope).to_string(),
+            served: policy.is_served(&scope),
+            groups: group_counts.get(&scope).copied().unwrap_or_default(),
+            events,
+        });
+    }
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(summaries)
+}
+
+#[derive(Debug, Clone)]
+pub struct ScopePolicyMiddleware {
+    policy: Arc<ScopePolicy>,
+    rejections: Option<Arc<Rejections>>,
+}
+
+impl ScopePolicyMiddleware {
+    pub fn new(policy: Arc<ScopePolicy>) -> Self {
+        Self {
+            policy,
+            rejections: None,
+        }
+    }
+
+    /// Records the events turned down
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+}
+
+impl NostrMiddleware<()> for ScopePolicyMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        if self.policy.mode() == ScopeMode::Open {
+            return ctx.next().await;
+        }
+        let Some(message) = &ctx.message else {
+            return ctx.next().await;
+        };
+        let (authed_pubkey, scope) = {
+            let state = ctx.state.read().await;
+            (state.authed_pubkey, state.subdomain().clone())
+        };
+
+        match self.policy.check(&scope, message, authed_pubkey.as_ref()) {
+            ScopeDecision::Allow => ctx.next().await,
+            ScopeDecision::Claim => {
+                self.policy.claim(&scope);
+                ctx.next().await
+            }
+            ScopeDecision::Reject => {
+                debug!(
+                    "[{}] Turning down a message to unserved scope {}",
+                    ctx.connection_id,
+                    scope_name(&scope)
+                );
+                let reply = match message {
+                    ClientMessage::Event(event) => {
+                        if let Some(rejections) = &self.rejections {
+                            rejections.record_event(
+                                event,
+                                ReasonCode::Restricted,
+                                UNKNOWN_SCOPE_MESSAGE,
+                            );
+                        }
+                        RelayMessage::ok(event.id, false, UNKNOWN_SCOPE_MESSAGE)
+                    }
+                    ClientMessage::Req {
+                        subscription_id, ..
+                    }
+                    | ClientMessage::ReqMultiFilter {
+                        subscription_id, ..
+                    } => RelayMessage::closed(
+                        subscription_id.clone().into_owned(),
+                        UNKNOWN_SCOPE_MESSAGE,
+                    ),
+                    _ => RelayMessage::notice(UNKNOWN_SCOPE_MESSAGE),
+                };
+                ctx.send_message(reply)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test;
+    use std::time::Duration;
+
+    fn settings(mode: ScopeMode) -> ScopeSettings {
+        ScopeSettings {
+            mode,
+            names: vec!["team".to_string(), "eu.team".to_string()],
+            provisioners: Vec::new(),
+        }
+    }
+
+    fn named(name: &str) -> Scope {
+        Scope::named(name).unwrap()
+    }
+
+    fn req() -> ClientMessage<'static> {
+        ClientMessage::req(SubscriptionId::new("sub"), Filter::new().limit(1))
+    }
+
+    #[test]
+    fn test_open_serves_everything() {
+        let policy = ScopePolicy::new(&settings(ScopeMode::Open), []).unwrap();
+        assert!(policy.is_served(&Scope::Default));
+        assert!(policy.is_served(&named("whatever")));
+        assert!(policy.is_served(&named("deep.nested")));
+        assert_eq!(
+            policy.check(&named("whatever"), &req(), None),
+            ScopeDecision::Allow
+        );
+    }
+
+    #[test]
+    fn test_allowlist_serves_listed_names_only() {
+        let stored = [named("squatted")];
+        let policy = ScopePolicy::new(&settings(ScopeMode::Allowlist), stored).unwrap();
+        assert!(policy.is_served(&Scope::Default));
+        assert!(policy.is_served(&named("team")));
+        assert!(policy.is_served(&named("Team")));
+
+        // Nested subdomains are matched whole
+        assert!(policy.is_served(&named("eu.team")));
+        assert!(!policy.is_served(&named("us.team")));
+
+        // Stored scopes aren't served unless listed
+        assert!(!policy.is_served(&named("squatted")));
+        assert_eq!(
+            policy.check(&named("squatted"), &req(), None),
+            ScopeDecision::Reject
+        );
+    }
+
+    #[test]
+    fn test_claimable_needs_a_provisioner_creating_a_group() {
+        let provisioner = Keys::generate();
+        let stranger = Keys::generate();
+        let mut settings = settings(ScopeMode::Claimable);
+        settings.provisioners = vec![provisioner.public_key().to_bech32().unwrap()];
+        let policy = ScopePolicy::new(&settings, [named("existing")]).unwrap();
+        let scope = named("fresh");
+
+        // Stored scopes are already provisioned
+        assert!(policy.is_served(&named("existing")));
+        assert!(policy.is_served(&named("team")));
+        assert!(!policy.is_served(&scope));
+
+        let create = |keys: &Keys| {
+            ClientMessage::event(
+                EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+                    .tag(Tag::custom(TagKind::h(), ["first"]))
+                    .sign_with_keys(keys)
+                    .unwrap(),
+            )
+        };
+        let auth = ClientMessage::auth(
+            EventBuilder::auth("challenge", RelayUrl::parse("wss://fresh.relay").unwrap())
+                .sign_with_keys(&provisioner)
+                .unwrap(),
+        );
+
+        assert_eq!(policy.check(&scope, &auth, None), ScopeDecision::Allow);
+        assert_eq!(policy.check(&scope, &req(), None), ScopeDecision::Reject);
+        assert_eq!(
+            policy.check(&scope, &create(&stranger), Some(&stranger.public_key())),
+            ScopeDecision::Reject
+        );
+        // The provisioner has to be authenticated as the author
+        assert_eq!(
+            policy.check(&scope, &create(&provisioner), None),
+            ScopeDecision::Reject
+        );
+        assert_eq!(
+            policy.check(
+                &scope,
+                &create(&provisioner),
+                Some(&provisioner.public_key())
+            ),
+            ScopeDecision::Claim
+        );
+
+        policy.claim(&scope);
+        assert!(policy.is_served(&scope));
+        assert_eq!(policy.check(&scope, &req(), None), ScopeDecision::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_list_scopes_counts_events() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Groups::load_groups(
+            database.clone(),
+            relay_keys.public_key(),
+            "wss://test.relay".to_string(),
+        )
+        .await
+        .unwrap();
+        let policy = ScopePolicy::new(&settings(ScopeMode::Allowlist), []).unwrap();
+        let keys = Keys::generate();
+        for (scope, notes) in [(named("team"), 2), (named("squatted"), 1)] {
+            for i in 0..notes {
+                let note = EventBuilder::text_note(format!("note {i}"))
+                    .sign_with_keys(&keys)
+                    .unwrap();
+                database.save_event(&note, &scope).await.unwrap();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let scopes = list_scopes(&database, &groups, &policy).await.unwrap();
+        let summary = |name: &str| scopes.iter().find(|scope| scope.name == name).unwrap();
+        assert_eq!(summary("team").events, 2);
+        assert!(summary("team").served);
+        assert_eq!(summary("squatted").events, 1);
+        assert!(!summary("squatted").served);
+        assert_eq!(summary("squatted").groups, 0);
+    }
+}
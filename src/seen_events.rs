@@ -0,0 +1,321 @@
+//! Event ids seen by replication, used to break mirror loops.
+//!
+//! Signed events can't carry an origin tag without changing their id, so
+//! loops are detected by id instead: every event this relay forwards to its
+//! peers is remembered as [`Origin::Forwarded`], and every event a known peer
+//! sends as [`Origin::Received`]. An event forwarded once is never forwarded
+//! again, and an event coming back from another relay after we forwarded it
+//! is dropped on ingestion.
+//!
+//! [`MemorySeenEvents`] only remembers a bounded number of recent ids.
+//! [`PersistentSeenEvents`] also keeps them in a small LMDB environment for a
+//! configurable horizon, so a restart in the middle of a loop doesn't let the
+//! events circulate again.
+
+use anyhow::Result;
+use heed::types::Bytes;
+use heed::{Database, Env, EnvOpenOptions};
+use lru::LruCache;
+use nostr_sdk::{EventId, Timestamp};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+const MAP_SIZE: usize = 256 * 1024 * 1024;
+/// Pruning scans every stored id, so it runs less often than flushing
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How this relay first came across an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Origin {
+    /// We forwarded it to our peers
+    Forwarded,
+    /// A peer relay sent it to us
+    Received,
+}
+
+/// A deduplication window of event ids
+pub trait SeenEvents: Send + Sync + Debug {
+    /// Where the event was first seen, if it was seen within the window
+    fn get(&self, id: &EventId) -> Option<Origin>;
+
+    /// Remembers the event, returning where it was first seen if it already was
+    fn insert(&self, id: EventId, origin: Origin) -> Option<Origin>;
+}
+
+/// The most recently seen event ids, forgotten on restart
+#[derive(Debug)]
+pub struct MemorySeenEvents {
+    cache: Mutex<LruCache<EventId, Origin>>,
+}
+
+impl MemorySeenEvents {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("capacity is non-zero"),
+            )),
+        }
+    }
+}
+
+impl SeenEvents for MemorySeenEvents {
+    fn get(&self, id: &EventId) -> Option<Origin> {
+        self.cache.lock().get(id).copied()
+    }
+
+    fn insert(&self, id: EventId, origin: Origin) -> Option<Origin> {
+        let mut cache = self.cache.lock();
+        if let Some(seen) = cache.get(&id) {
+            return Some(*seen);
+        }
+        cache.put(id, origin);
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SeenEntry {
+    origin: Origin,
+    /// Unix timestamp the event was first seen at
+    seen_at: u64,
+}
+
+/// Seen event ids kept on disk for `horizon`, with the recent ones cached
+pub struct PersistentSeenEvents {
+    recent: MemorySeenEvents,
+    horizon: Duration,
+    env: Env,
+    db: Database<Bytes, Bytes>,
+    pending: Mutex<HashMap<EventId, SeenEntry>>,
+}
+
+impl Debug for PersistentSeenEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PersistentSeenEvents")
+            .field("horizon", &self.horizon)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PersistentSeenEvents {
+    pub fn open(
+        path: impl AsRef<Path>,
+        horizon: Duration,
+        cache_capacity: usize,
+    ) -> Result<Arc<Self>> {
+        std::fs::create_dir_all(path.as_ref())?;
+        // SAFETY: the environment is only opened once per path by this process
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(1)
+                .open(path.as_ref())?
+        };
+
+        let mut wtxn = env.write_txn()?;
+        let db = env.create_database(&mut wtxn, Some("seen"))?;
+        wtxn.commit()?;
+
+        Ok(Arc::new(Self {
+            recent: MemorySeenEvents::new(cache_capacity),
+            horizon,
+            env,
+            db,
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn is_current(&self, entry: &SeenEntry, now: u64) -> bool {
+        entry.seen_at + self.horizon.as_secs() > now
+    }
+
+    fn load(&self, id: &EventId) -> Result<Option<SeenEntry>> {
+        if let Some(entry) = self.pending.lock().get(id) {
+            return Ok(Some(*entry));
+        }
+
+        let rtxn = self.env.read_txn()?;
+        let Some(bytes) = self.db.get(&rtxn, id.as_bytes())? else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_slice(bytes)?))
+    }
+
+    /// Writes pending ids to disk, returning how many were written.
+    pub fn flush(&self) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut wtxn = self.env.write_txn()?;
+        for (id, entry) in &pending {
+            self.db
+                .put(&mut wtxn, id.as_bytes(), &serde_json::to_vec(entry)?)?;
+        }
+        wtxn.commit()?;
+        Ok(pending.len())
+    }
+
+    /// Deletes ids older than the horizon, returning how many were deleted.
+    pub fn prune(&self) -> Result<usize> {
+        let now = Timestamp::now().as_u64();
+        let expired: Vec<Vec<u8>> = {
+            let rtxn = self.env.read_txn()?;
+            let mut expired = Vec::new();
+            for item in self.db.iter(&rtxn)? {
+                let (key, bytes) = item?;
+                let current = serde_json::from_slice::<SeenEntry>(bytes)
+                    .is_ok_and(|entry| self.is_current(&entry, now));
+                if !current {
+                    expired.push(key.to_vec());
+                }
+            }
+            expired
+        };
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut wtxn = self.env.write_txn()?;
+        for key in &expired {
+            self.db.delete(&mut wtxn, key)?;
+        }
+        wtxn.commit()?;
+        Ok(expired.len())
+    }
+
+    /// Flushes pending ids every `interval` and once more on shutdown,
+    /// pruning expired ones every [`PRUNE_INTERVAL`].
+    pub fn spawn_flusher(
+        self: Arc<Self>,
+        interval: Duration,
+        cancellation_token: CancellationToken,
+    ) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut last_prune = Instant::now();
+            loop {
+                let shutting_down = tokio::select! {
+                    _ = cancellation_token.cancelled() => true,
+                    _ = ticker.tick() => false,
+                };
+
+                match self.flush() {
+                    Ok(0) => {}
+                    Ok(written) => debug!("Persisted {} seen event ids", written),
+                    Err(e) => warn!("Failed to persist seen event ids: {}", e),
+                }
+                if shutting_down {
+                    break;
+                }
+                if last_prune.elapsed() < PRUNE_INTERVAL {
+                    continue;
+                }
+
+                last_prune = Instant::now();
+                match self.prune() {
+                    Ok(0) => {}
+                    Ok(deleted) => debug!("Pruned {} expired seen event ids", deleted),
+                    Err(e) => warn!("Failed to prune seen event ids: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl SeenEvents for PersistentSeenEvents {
+    fn get(&self, id: &EventId) -> Option<Origin> {
+        if let Some(origin) = self.recent.get(id) {
+            return Some(origin);
+        }
+
+        let entry = self.load(id).unwrap_or_else(|e| {
+            warn!("Failed to load seen event id {}: {}", id, e);
+            None
+        })?;
+        self.is_current(&entry, Timestamp::now().as_u64())
+            .then_some(entry.origin)
+    }
+
+    fn insert(&self, id: EventId, origin: Origin) -> Option<Origin> {
+        if let Some(seen) = self.get(&id) {
+            return Some(seen);
+        }
+
+        self.recent.insert(id, origin);
+        self.pending.lock().insert(
+            id,
+            SeenEntry {
+                origin,
+                seen_at: Timestamp::now().as_u64(),
+            },
+        );
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn event_id(n: u8) -> EventId {
+        EventId::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn test_memory_window_is_bounded() {
+        let seen = MemorySeenEvents::new(2);
+
+        assert_eq!(seen.insert(event_id(1), Origin::Forwarded), None);
+        assert_eq!(
+            seen.insert(event_id(1), Origin::Received),
+            Some(Origin::Forwarded)
+        );
+        seen.insert(event_id(2), Origin::Received);
+        seen.insert(event_id(3), Origin::Received);
+
+        assert_eq!(seen.get(&event_id(1)), None);
+        assert_eq!(seen.get(&event_id(3)), Some(Origin::Received));
+    }
+
+    #[test]
+    fn test_seen_ids_survive_reopen_within_horizon() {
+        let tmp_dir = TempDir::new().unwrap();
+        {
+            let seen = PersistentSeenEvents::open(tmp_dir.path(), DAY, 10).unwrap();
+            seen.insert(event_id(1), Origin::Forwarded);
+            assert_eq!(seen.flush().unwrap(), 1);
+        }
+
+        let seen = PersistentSeenEvents::open(tmp_dir.path(), DAY, 10).unwrap();
+        assert_eq!(seen.get(&event_id(1)), Some(Origin::Forwarded));
+        assert_eq!(seen.get(&event_id(2)), None);
+        assert_eq!(seen.prune().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_expired_ids_are_forgotten_and_pruned() {
+        let tmp_dir = TempDir::new().unwrap();
+        {
+            let seen = PersistentSeenEvents::open(tmp_dir.path(), Duration::ZERO, 10).unwrap();
+            seen.insert(event_id(1), Origin::Forwarded);
+            seen.flush().unwrap();
+        }
+
+        let seen = PersistentSeenEvents::open(tmp_dir.path(), Duration::ZERO, 10).unwrap();
+        assert_eq!(seen.get(&event_id(1)), None);
+        assert_eq!(seen.prune().unwrap(), 1);
+    }
+}
@@ -1,15 +1,64 @@
 use crate::{
-    app_state::HttpServerState, config, groups::Groups,
-    groups_event_processor::GroupsRelayProcessor, handler, metrics,
+    account_deletion::AccountDeletions,
+    admission::{AdmissionController, AdmissionMiddleware},
+    app_state::HttpServerState,
+    archive::Archive,
+    auth_resubscribe::AuthResubscribeMiddleware,
+    bot_tokens::{BotTokenMiddleware, BotTokens},
+    capabilities::{CapabilitiesMiddleware, CapabilityRegistry},
+    config,
+    connection_stats::{ConnectionStats, ConnectionStatsMiddleware},
+    directory::GroupDirectory,
+    duplicate_events::{DuplicateEventsMiddleware, StoredEvents, DUPLICATE_CACHE_SIZE},
+    event_limits::{EventLimits, EventLimitsMiddleware},
+    gc::{GroupGc, GC_BATCH_SIZE, GC_INTERVAL},
+    group_list_sync::GroupListSync,
+    group_loading_middleware::GroupLoadingMiddleware,
+    group_metrics::{GroupMetrics, GroupMetricsMiddleware},
+    group_webhooks::GroupWebhooks,
+    groups::{Groups, KIND_DM_RELAYS_10050},
+    groups_event_processor::GroupsRelayProcessor,
+    handler,
+    health::{DatabaseWritable, GroupsLoaded, Readiness, SignerResponds, PROBE_DEADLINE},
+    introspection::{IntrospectionMiddleware, SubscriptionRegistry},
+    load_shedding::{LoadSheddingMiddleware, LoadState},
+    member_profiles::MemberProfilesMiddleware,
+    membership_state::MembershipCoalescer,
+    metrics,
     metrics_handler::PrometheusSubscriptionMetricsHandler,
-    sampled_metrics_handler::SampledMetricsHandler, RelayDatabase,
+    nip70_middleware::GroupNip70Middleware,
+    nip98::SeenAuthorizations,
+    payments::{self, CallbackPaymentVerifier},
+    persistent_window::{PersistentWindow, WindowStore},
+    posting_policy::PostingPolicy,
+    query_pushdown::QueryPushdownMiddleware,
+    quotas::StorageQuotas,
+    read_only::{ReadOnlyMiddleware, ReadOnlyMode},
+    rejections::{Rejections, RECENT_REJECTIONS},
+    relay_profile::{publish_profile, spawn_indexer_publish},
+    replay_hints::{ReplayHints, ReplayHintsMiddleware, REPLAY_HINT_CAPACITY},
+    replication::{Replicator, SEEN_CACHE_SIZE},
+    sampled_metrics_handler::SampledMetricsHandler,
+    scope_policy::{ScopePolicy, ScopePolicyMiddleware},
+    seen_events::PersistentSeenEvents,
+    simulation::Simulator,
+    spam_filter::SpamFilter,
+    state_integrity::StateIntegrityChecker,
+    subscription_limits::{SubscriptionLimits, SubscriptionLimitsMiddleware},
+    validation_middleware::ValidationMiddleware,
+    webhook::HttpWebhook,
+    RelayDatabase,
 };
 use anyhow::Result;
-use axum::{response::IntoResponse, routing::get, Router};
+use axum::{
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+    Router,
+};
+use nostr_sdk::Kind;
 use relay_builder::{handle_upgrade, HandlerFactory, WebSocketUpgrade};
 use relay_builder::{
-    CryptoHelper, Nip40ExpirationMiddleware, Nip70Middleware, RelayBuilder, RelayConfig, RelayInfo,
-    WebSocketConfig,
+    CryptoHelper, Nip40ExpirationMiddleware, RelayBuilder, RelayConfig, RelayInfo, WebSocketConfig,
 };
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicUsize;
@@ -20,7 +69,7 @@ use tokio_util::sync::CancellationToken;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tower_http::timeout::TimeoutLayer;
-use tracing::info;
+use tracing::{debug, info};
 
 pub struct ServerState {
     pub http_state: Arc<HttpServerState>,
@@ -28,6 +77,20 @@ pub struct ServerState {
     pub metrics_handle: metrics::PrometheusHandle,
     pub connection_counter: Arc<AtomicUsize>,
     pub relay_url: String,
+    pub database: Arc<RelayDatabase>,
+    pub account_deletions: Arc<AccountDeletions>,
+    pub group_webhooks: Option<Arc<GroupWebhooks>>,
+    pub bot_tokens: Arc<BotTokens>,
+    pub read_only: Arc<ReadOnlyMode>,
+    pub rejections: Arc<Rejections>,
+    pub relay_keys: config::Keys,
+    pub scope_policy: Arc<ScopePolicy>,
+    pub connection_stats: Arc<ConnectionStats>,
+    pub subscription_registry: Arc<SubscriptionRegistry>,
+    pub storage_quotas: Option<Arc<StorageQuotas>>,
+    pub simulator: Arc<Simulator>,
+    pub readiness: Arc<Readiness>,
+    pub seen_authorizations: SeenAuthorizations,
 }
 
 pub async fn run_server(
@@ -62,34 +125,392 @@ pub async fn run_server(
     };
 
     let _crypto_helper = CryptoHelper::new(Arc::new(relay_keys.clone()));
-    let mut relay_config =
-        RelayConfig::new(settings.relay_url.clone(), database, relay_keys.clone())
-            .with_subdomains_from_url(&settings.relay_url)
-            .with_websocket_config(websocket_config)
-            .with_subscription_limits(settings.max_subscriptions, settings.max_limit)
-            .with_diagnostics();
+    let mut relay_config = RelayConfig::new(
+        settings.relay_url.clone(),
+        database.clone(),
+        relay_keys.clone(),
+    )
+    .with_subdomains_from_url(&settings.relay_url)
+    .with_websocket_config(websocket_config)
+    .with_subscription_limits(settings.max_subscriptions, settings.max_limit)
+    .with_diagnostics();
 
     // Enable NIP-42 authentication
     relay_config.enable_auth = true;
 
-    let groups_processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key);
-
     // Create cancellation token and connection counter
     let cancellation_token = CancellationToken::new();
     let connection_counter = Arc::new(AtomicUsize::new(0));
 
+    let group_metrics = Arc::new(GroupMetrics::new(&settings.group_metrics));
+    let mut personal_kinds: Vec<Kind> = settings
+        .allowed_personal_kinds
+        .iter()
+        .copied()
+        .map(Kind::from)
+        .collect();
+    if settings.private_messages && !personal_kinds.contains(&KIND_DM_RELAYS_10050) {
+        personal_kinds.push(KIND_DM_RELAYS_10050);
+    }
+    let rejections = Arc::new(Rejections::new(RECENT_REJECTIONS));
+    let mut groups_processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key)
+        .with_group_metrics(group_metrics.clone())
+        .with_personal_kinds(personal_kinds.clone())
+        .with_rejections(rejections.clone());
+    if !settings.allow_ephemeral_events {
+        groups_processor = groups_processor.without_ephemeral_events();
+    }
+    if settings.private_messages {
+        groups_processor = groups_processor.with_private_messages();
+    }
+    if let Some(policy_settings) = &settings.posting_policy {
+        let posting_policy = Arc::new(PostingPolicy::load(
+            &policy_settings.path,
+            policy_settings.url.clone(),
+        )?);
+        info!("Loaded posting policy from {}", policy_settings.path);
+        posting_policy
+            .clone()
+            .spawn_reloader(policy_settings.reload_interval, cancellation_token.clone());
+        groups_processor = groups_processor.with_posting_policy(posting_policy);
+    }
+    if let Some(spam_settings) = &settings.spam_filter {
+        info!(
+            "Spam filter enabled, flagged messages are {}",
+            match spam_settings.action {
+                config::SpamAction::Reject => "rejected",
+                config::SpamAction::ShadowMute => "shadow-muted",
+            }
+        );
+        let spam_filter = Arc::new(SpamFilter::new(spam_settings));
+        let mutes = spam_filter.load(&database, groups.relay_keys()).await?;
+        info!("Loaded {} shadow-muted messages", mutes);
+        groups_processor = groups_processor.with_spam_filter(spam_filter);
+    }
+    if let Some(payment_settings) = &settings.paid_group_creation {
+        info!(
+            "Group creation costs {} {}",
+            payment_settings.amount, payment_settings.unit
+        );
+        groups_processor = groups_processor.with_payment_verifier(
+            Arc::new(CallbackPaymentVerifier::new(payment_settings)?),
+            payments::paid_actions(payment_settings),
+        );
+    }
+    let mut storage_quotas = None;
+    if let Some(quota_settings) = &settings.storage_quotas {
+        let quotas = Arc::new(StorageQuotas::new(
+            database.clone(),
+            groups.relay_keys().clone(),
+            quota_settings,
+        ));
+        quotas.spawn_recount();
+        groups_processor = groups_processor.with_storage_quotas(quotas.clone());
+        storage_quotas = Some(quotas);
+    }
+    if let Some(replication_settings) = &settings.replication {
+        let seen_events = PersistentSeenEvents::open(
+            std::path::Path::new(&settings.db_path).join("replication_seen"),
+            replication_settings.seen_horizon,
+            SEEN_CACHE_SIZE,
+        )?;
+        seen_events
+            .clone()
+            .spawn_flusher(Duration::from_secs(5), cancellation_token.clone());
+        let replicator = Replicator::start(
+            replication_settings,
+            relay_keys.clone(),
+            seen_events,
+            cancellation_token.clone(),
+        )
+        .await?;
+        groups_processor = groups_processor.with_replicator(replicator);
+    }
+    if let Some(archive_settings) = &settings.archive {
+        let archive = Archive::start(
+            archive_settings,
+            relay_keys.clone(),
+            groups.clone(),
+            std::path::Path::new(&settings.db_path).join("archive_queue.json"),
+            cancellation_token.clone(),
+        )
+        .await?;
+        groups_processor = groups_processor.with_archive(archive);
+    }
+    if let Some(webhook_settings) = &settings.webhook {
+        info!("Sending group change webhooks to {}", webhook_settings.url);
+        let webhook = HttpWebhook::start(webhook_settings, cancellation_token.clone())?;
+        groups_processor = groups_processor.with_hook(webhook);
+    }
+    if settings.group_list_sync {
+        groups_processor = groups_processor.with_hook(GroupListSync::new(
+            groups.clone(),
+            database.clone(),
+            relay_keys.clone(),
+        ));
+    }
+    let group_webhooks = match &settings.group_webhooks {
+        Some(webhook_settings) => {
+            let group_webhooks = GroupWebhooks::start(
+                webhook_settings,
+                groups.clone(),
+                database.clone(),
+                relay_keys.clone(),
+                cancellation_token.clone(),
+            )?;
+            groups_processor = groups_processor.with_group_webhooks(group_webhooks.clone());
+            Some(group_webhooks)
+        }
+        None => None,
+    };
+    let bot_tokens = BotTokens::new(
+        &settings.bot_tokens,
+        groups.clone(),
+        database.clone(),
+        relay_keys.clone(),
+    );
+    groups_processor = groups_processor.with_bot_tokens(bot_tokens.clone());
+    if let Some(limit_settings) = &settings.group_creation_limit {
+        let window_store =
+            WindowStore::open(std::path::Path::new(&settings.db_path).join("rate_limits"))?;
+        window_store
+            .clone()
+            .spawn_flusher(limit_settings.flush_interval, cancellation_token.clone());
+        groups_processor =
+            groups_processor.with_group_creation_limit(Arc::new(PersistentWindow::new(
+                "group_creation",
+                limit_settings.max_groups,
+                limit_settings.window,
+                window_store,
+            )));
+    }
+
+    let read_only = Arc::new(ReadOnlyMode::new(settings.read_only));
+    if settings.read_only {
+        info!("Starting in read-only mode");
+    }
+    let membership_coalescer = if settings.membership_state_window.is_zero() {
+        None
+    } else {
+        let coalescer = Arc::new(
+            MembershipCoalescer::new(
+                groups.clone(),
+                database.clone(),
+                relay_keys.clone(),
+                settings.membership_state_window,
+            )
+            .with_read_only(read_only.clone()),
+        );
+        coalescer.clone().spawn_flusher(cancellation_token.clone());
+        groups_processor = groups_processor.with_membership_coalescer(coalescer.clone());
+        Some(coalescer)
+    };
+
+    if !settings.directory_window.is_zero() {
+        let directory = Arc::new(
+            GroupDirectory::new(
+                groups.clone(),
+                database.clone(),
+                relay_keys.clone(),
+                settings.directory_window,
+            )
+            .with_read_only(read_only.clone()),
+        );
+        directory.clone().spawn_flusher(cancellation_token.clone());
+        groups_processor = groups_processor.with_directory(directory);
+    }
+
+    let mut group_gc =
+        GroupGc::new(groups.clone(), GC_BATCH_SIZE).with_state(group_metrics.clone());
+    if let Some(membership_coalescer) = &membership_coalescer {
+        group_gc = group_gc.with_state(membership_coalescer.clone());
+    }
+    if let Some(group_webhooks) = &group_webhooks {
+        group_gc = group_gc.with_state(group_webhooks.clone());
+    }
+    Arc::new(group_gc).spawn(GC_INTERVAL, cancellation_token.clone());
+
+    if !settings.state_check_interval.is_zero() {
+        let mut checker =
+            StateIntegrityChecker::new(groups.clone(), database.clone(), relay_keys.clone())
+                .with_read_only(read_only.clone());
+        if let Some(membership_coalescer) = &membership_coalescer {
+            checker = checker.with_membership_coalescer(membership_coalescer.clone());
+        }
+        Arc::new(checker).spawn(settings.state_check_interval, cancellation_token.clone());
+    }
+
+    if let Some(profile_settings) = &settings.profile {
+        let profile_events = publish_profile(
+            &database,
+            &relay_keys,
+            profile_settings,
+            &settings.relay_url,
+        )
+        .await?;
+        spawn_indexer_publish(
+            relay_keys.clone(),
+            profile_settings.indexer_relays.clone(),
+            profile_events,
+        );
+    }
+
+    let load_state = Arc::new(LoadState::new(settings.load_shedding.clone()));
+    load_state.clone().spawn_decay(cancellation_token.clone());
+    let load_shedding = LoadSheddingMiddleware::new(load_state.clone(), relay_keys.public_key);
+    let admission = AdmissionMiddleware::new(
+        Arc::new(AdmissionController::new(settings.admission.clone())),
+        relay_keys.public_key,
+    )
+    .with_rejections(rejections.clone());
+    let subscription_registry = Arc::new(SubscriptionRegistry::new());
+    let subscription_limits = SubscriptionLimitsMiddleware::new(
+        subscription_registry.clone(),
+        SubscriptionLimits {
+            max_subscriptions: settings.max_subscriptions,
+            max_filters_per_req: settings.max_filters_per_req,
+            max_filter_ids: settings.max_filter_ids,
+        },
+    );
+    let limits = EventLimits {
+        max_event_size: settings.max_event_size,
+        max_tags: settings.max_event_tags,
+        max_content_length: settings.max_content_length,
+        max_member_tags: settings.max_member_tags,
+    };
+    let event_limits = EventLimitsMiddleware::new(limits).with_rejections(rejections.clone());
+    let validation =
+        ValidationMiddleware::new(relay_keys.public_key, settings.group_validation.clone())
+            .with_personal_kinds(personal_kinds.clone())
+            .with_rejections(rejections.clone());
+    let stored_events = Arc::new(StoredEvents::new(database.clone(), DUPLICATE_CACHE_SIZE));
+    let duplicate_events =
+        DuplicateEventsMiddleware::new(stored_events.clone(), Arc::new(groups_processor.clone()));
+    if settings.group_validation.mode != config::ValidationMode::Off {
+        info!(
+            "Group event validation: {:?}",
+            settings.group_validation.mode
+        );
+    }
+    let capability_registry = Arc::new(CapabilityRegistry::new());
+    let capabilities = CapabilitiesMiddleware::new(capability_registry.clone());
+    let bot_token_middleware = BotTokenMiddleware::new(bot_tokens.clone());
+    let connection_stats = Arc::new(ConnectionStats::new());
+    let connection_stats_middleware = ConnectionStatsMiddleware::new(connection_stats.clone());
+    let read_only_middleware = ReadOnlyMiddleware::new(read_only.clone());
+    let scope_policy = Arc::new(ScopePolicy::new(
+        &settings.scope_policy,
+        database.list_scopes().await?,
+    )?);
+    info!("Scope policy: {:?}", settings.scope_policy.mode);
+    let scope_policy_middleware =
+        ScopePolicyMiddleware::new(scope_policy.clone()).with_rejections(rejections.clone());
+    let nip70 = GroupNip70Middleware::new().with_rejections(rejections.clone());
+    let introspection =
+        IntrospectionMiddleware::new(subscription_registry.clone(), settings.max_limit)
+            .with_capabilities(capability_registry.clone())
+            .with_connection_stats(connection_stats.clone());
+    let group_metrics_middleware = GroupMetricsMiddleware::new(group_metrics.clone());
+    let auth_resubscribe = AuthResubscribeMiddleware::new(settings.resubscribe_notice_after_auth);
+    let group_loading = GroupLoadingMiddleware::new(groups.clone());
+    let member_profiles = MemberProfilesMiddleware::new(groups.clone());
+    let query_pushdown = QueryPushdownMiddleware::new(groups.clone(), settings.query_pushdown)
+        .with_personal_kinds(personal_kinds);
+    let replay_hints = ReplayHintsMiddleware::new(
+        Arc::new(ReplayHints::new(REPLAY_HINT_CAPACITY)),
+        settings.replay_hints,
+    );
+
+    let mut supported_nips = vec![1, 9, 11, 29, 40, 42, 70];
+    if settings.private_messages {
+        supported_nips.extend([17, 59]);
+        supported_nips.sort_unstable();
+    }
+
     // Define relay information
     let _relay_info = RelayInfo {
         name: "Nostr Groups Relay".to_string(),
         description: "A specialized relay implementing NIP-29 for Nostr group management. This relay is under development and all data may be deleted in the future".to_string(),
         pubkey: relay_keys.public_key.to_string(),
         contact: "https://daniel.nos.social".to_string(),
-        supported_nips: vec![1, 9, 11, 29, 40, 42, 70],
+        supported_nips,
         software: "groups_relay".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: Some("https://pfp.nostr.build/c60f4853a6d4ae046bdbbd935f0ccd7354c9c411c324b411666d325562a5a906.png".to_string()),
     };
 
+    // NIP-11 document, with the limits enforced per connection merged into
+    // any limitation the relay info already reports
+    let mut relay_info_document = serde_json::to_value(&_relay_info)?;
+    let limitation = &mut relay_info_document["limitation"];
+    if !limitation.is_object() {
+        *limitation = serde_json::json!({});
+    }
+    for (name, value) in [
+        ("max_subscriptions", settings.max_subscriptions),
+        ("max_filters", settings.max_filters_per_req),
+        ("max_filter_ids", settings.max_filter_ids),
+        ("max_limit", settings.max_limit),
+    ] {
+        limitation[name] = value.into();
+    }
+    if let Some(url) = settings
+        .posting_policy
+        .as_ref()
+        .and_then(|policy| policy.url.clone())
+    {
+        relay_info_document["posting_policy"] = serde_json::Value::String(url);
+    }
+    if let Some(payment_settings) = &settings.paid_group_creation {
+        relay_info_document["fees"] = payments::fees_document(payment_settings);
+        if let Some(url) = &payment_settings.payments_url {
+            relay_info_document["payments_url"] = serde_json::Value::String(url.clone());
+        }
+    }
+
+    let simulator = Arc::new(Simulator::new(
+        groups_processor.clone(),
+        limits,
+        validation.clone(),
+        stored_events,
+        relay_keys.public_key,
+    ));
+
+    let readiness = Arc::new(
+        Readiness::new(PROBE_DEADLINE)
+            .with_probe(GroupsLoaded(groups.clone()))
+            .with_probe(
+                DatabaseWritable::new(database.clone(), relay_keys.clone())
+                    .with_read_only(read_only.clone()),
+            )
+            .with_probe(SignerResponds::new(
+                Arc::new(relay_keys.clone()),
+                relay_keys.public_key,
+            )),
+    );
+
+    // Connections are served while groups load, /readyz holds off traffic
+    // from orchestrators until every dependency answers
+    {
+        let readiness = readiness.clone();
+        let cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            loop {
+                match readiness.check().await {
+                    Ok(()) => {
+                        info!("All dependencies answered, relay is ready");
+                        break;
+                    }
+                    Err(failing) => debug!("Relay not ready yet: {:?}", failing),
+                }
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = time::sleep(Duration::from_secs(1)) => {}
+                }
+            }
+        });
+    }
+
     // Build the relay service
     let handler_factory = Arc::new(
         RelayBuilder::<(), GroupsRelayProcessor>::new(relay_config)
@@ -99,10 +520,28 @@ pub async fn run_server(
             .subscription_metrics(PrometheusSubscriptionMetricsHandler)
             .event_processor(groups_processor)
             .relay_info(_relay_info.clone())
-            .build_with(|chain| {
+            .build_with(move |chain| {
                 chain
+                    .with(connection_stats_middleware.clone())
+                    .with(scope_policy_middleware.clone())
+                    .with(read_only_middleware.clone())
+                    .with(load_shedding.clone())
+                    .with(admission.clone())
+                    .with(capabilities.clone())
+                    .with(subscription_limits.clone())
+                    .with(event_limits.clone())
+                    .with(validation.clone())
+                    .with(duplicate_events.clone())
+                    .with(replay_hints.clone())
+                    .with(introspection.clone())
+                    .with(auth_resubscribe.clone())
+                    .with(bot_token_middleware.clone())
+                    .with(group_metrics_middleware.clone())
+                    .with(group_loading.clone())
+                    .with(member_profiles.clone())
+                    .with(query_pushdown.clone())
                     .with(Nip40ExpirationMiddleware::new())
-                    .with(Nip70Middleware)
+                    .with(nip70.clone())
             })
             .await?,
     );
@@ -113,6 +552,25 @@ pub async fn run_server(
         metrics_handle: metrics_handle.clone(),
         connection_counter: connection_counter.clone(),
         relay_url: settings.relay_url.clone(),
+        database: database.clone(),
+        account_deletions: Arc::new(AccountDeletions::new(
+            groups.clone(),
+            database.clone(),
+            relay_keys.clone(),
+            Some(std::path::Path::new(&settings.db_path).join("account_deletions.jsonl")),
+        )),
+        group_webhooks,
+        bot_tokens,
+        read_only,
+        rejections,
+        relay_keys: relay_keys.clone(),
+        scope_policy,
+        connection_stats,
+        subscription_registry,
+        storage_quotas,
+        simulator,
+        readiness: readiness.clone(),
+        seen_authorizations: SeenAuthorizations::new(),
     });
 
     let cors = CorsLayer::new()
@@ -121,12 +579,14 @@ pub async fn run_server(
         .allow_headers(Any);
 
     // Metrics handler without state
-    let metrics_handler = move || async move { metrics_handle.render() };
+    let groups_for_scrape = groups.clone();
+    let metrics_handler =
+        move || async move { metrics_handle.render() + &group_metrics.render(&groups_for_scrape) };
 
     // Create a unified handler that supports both WebSocket and HTTP on the same route
     let root_handler = {
         let handler_factory = handler_factory.clone();
-        let relay_info = _relay_info.clone();
+        let relay_info = relay_info_document.clone();
         move |ws: Option<WebSocketUpgrade>,
               axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<SocketAddr>,
               headers: axum::http::HeaderMap| {
@@ -163,6 +623,47 @@ pub async fn run_server(
     let api_routes = Router::new()
         .route("/api/subdomains", get(handler::handle_subdomains))
         .route("/api/config", get(handler::handle_config))
+        .route("/api/directory", get(handler::handle_directory))
+        .route("/api/simulate", post(handler::handle_simulate))
+        .route("/readyz", get(handler::handle_readyz))
+        .route(
+            "/api/groups/{id}/members",
+            get(handler::handle_group_members),
+        )
+        .route(
+            "/api/groups/{id}/annotations",
+            get(handler::handle_list_annotations).post(handler::handle_create_annotation),
+        )
+        .route(
+            "/api/groups/{id}/webhooks",
+            get(handler::handle_list_group_webhooks).post(handler::handle_register_group_webhook),
+        )
+        .route(
+            "/api/groups/{id}/webhooks/{webhook_id}",
+            delete(handler::handle_delete_group_webhook),
+        )
+        .route(
+            "/api/groups/{id}/bot-tokens",
+            get(handler::handle_list_bot_tokens).post(handler::handle_issue_bot_token),
+        )
+        .route(
+            "/api/groups/{id}/bot-tokens/{token_id}",
+            delete(handler::handle_revoke_bot_token),
+        )
+        .route(
+            "/api/account/deletion",
+            post(handler::handle_request_account_deletion),
+        )
+        .route(
+            "/api/account/deletion/{id}",
+            get(handler::handle_account_deletion_status),
+        )
+        .route("/admin/connections", get(handler::handle_list_connections))
+        .route("/admin/read_only", put(handler::handle_set_read_only))
+        .route("/admin/quotas", get(handler::handle_get_quotas))
+        .route("/admin/rejections", get(handler::handle_get_rejections))
+        .route("/admin/scopes", get(handler::handle_list_scopes))
+        .route("/admin/verify_groups", post(handler::handle_verify_groups))
         .layer(TimeoutLayer::new(Duration::from_secs(30)))
         .with_state(app_state);
 
@@ -170,6 +671,7 @@ pub async fn run_server(
     let router = Router::new()
         .route("/", get(root_handler))
         .route("/health", get(|| async { "OK" }))
+        .route("/healthz", get(handler::handle_health))
         .route("/metrics", get(metrics_handler))
         .merge(api_routes)
         .nest_service("/assets", ServeDir::new("frontend/dist/assets"))
@@ -0,0 +1,304 @@
+//! Simulated publishing for client developers.
+//!
+//! `POST /api/simulate` takes a signed event and answers whether the relay
+//! would accept it, without publishing it. [`Simulator`] runs the event
+//! through the checks a published event goes through: its signature, the
+//! event limits, group validation, duplicates, then the groups processor.
+//! It reports the decision, the check that turned the event down and the
+//! store commands the processor would have returned.
+//!
+//! Nothing is stored and no group changes, as the processor applies the
+//! event to a copy of its group. Checks that keep state of their own, like
+//! the spam filter and the group creation limit, are skipped, so an
+//! accepted simulation can still be turned down once published. The event
+//! is judged as if its author had authenticated, which the endpoint requires
+//! them to do with NIP-98. At most [`MAX_CONCURRENT_SIMULATIONS`] run at once.
+
+use crate::duplicate_events::{StoredEvents, DUPLICATE_EVENT};
+use crate::error::Rejection;
+use crate::event_limits::{EventLimits, INVALID_SIGNATURE};
+use crate::groups_event_processor::GroupsRelayProcessor;
+use crate::validation_middleware::ValidationMiddleware;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{EventContext, StoreCommand};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Simulations running at once, more are turned away
+pub const MAX_CONCURRENT_SIMULATIONS: usize = 4;
+
+/// The check that turned a simulated event down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Signature,
+    EventLimits,
+    Validation,
+    Processor,
+}
+
+/// A store command the processor would have returned, by ids and kinds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum SimulatedCommand {
+    SaveSignedEvent {
+        id: EventId,
+        kind: u16,
+    },
+    /// Events generated by the relay, which have no id until signed
+    SaveUnsignedEvent {
+        kind: u16,
+    },
+    DeleteEvents {
+        ids: Vec<EventId>,
+        kinds: Vec<u16>,
+    },
+}
+
+impl From<&StoreCommand> for SimulatedCommand {
+    fn from(command: &StoreCommand) -> Self {
+        match command {
+            StoreCommand::SaveSignedEvent(event, _, _) => SimulatedCommand::SaveSignedEvent {
+                id: event.id,
+                kind: event.kind.as_u16(),
+            },
+            StoreCommand::SaveUnsignedEvent(event, _, _) => SimulatedCommand::SaveUnsignedEvent {
+                kind: event.kind.as_u16(),
+            },
+            StoreCommand::DeleteEvents(filter, _, _) => SimulatedCommand::DeleteEvents {
+                ids: filter.ids.iter().flatten().copied().collect(),
+                kinds: filter
+                    .kinds
+                    .iter()
+                    .flatten()
+                    .map(|kind| kind.as_u16())
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Simulation {
+    pub accepted: bool,
+    /// The message of the OK a published event would get
+    pub message: String,
+    /// Reason code of a rejection, like `restricted`
+    pub reason: Option<&'static str>,
+    pub rejected_by: Option<Check>,
+    pub commands: Vec<SimulatedCommand>,
+}
+
+impl Simulation {
+    fn accepted(message: &str, commands: &[StoreCommand]) -> Self {
+        Self {
+            accepted: true,
+            message: message.to_string(),
+            reason: None,
+            rejected_by: None,
+            commands: commands.iter().map(SimulatedCommand::from).collect(),
+        }
+    }
+
+    fn rejected(check: Check, reason: &'static str, message: String) -> Self {
+        Self {
+            accepted: false,
+            message,
+            reason: Some(reason),
+            rejected_by: Some(check),
+            commands: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Simulator {
+    processor: GroupsRelayProcessor,
+    limits: EventLimits,
+    validation: ValidationMiddleware,
+    stored: Arc<StoredEvents>,
+    relay_pubkey: PublicKey,
+    running: Semaphore,
+}
+
+impl Simulator {
+    pub fn new(
+        processor: GroupsRelayProcessor,
+        limits: EventLimits,
+        validation: ValidationMiddleware,
+        stored: Arc<StoredEvents>,
+        relay_pubkey: PublicKey,
+    ) -> Self {
+        Self {
+            processor,
+            limits,
+            validation,
+            stored,
+            relay_pubkey,
+            running: Semaphore::new(MAX_CONCURRENT_SIMULATIONS),
+        }
+    }
+
+    /// Reserves a slot for a simulation, none while too many are running
+    pub fn try_reserve(&self) -> Option<SemaphorePermit<'_>> {
+        self.running.try_acquire().ok()
+    }
+
+    /// What publishing the event in the scope would do
+    pub async fn simulate(&self, event: Event, scope: &Scope) -> Simulation {
+        if event.verify().is_err() {
+            return Simulation::rejected(
+                Check::Signature,
+                "invalid",
+                INVALID_SIGNATURE.to_string(),
+            );
+        }
+        if let Err(reason) = self.limits.check(&event) {
+            return Simulation::rejected(Check::EventLimits, "invalid", reason.to_string());
+        }
+        if let Some(reason) = self.validation.rejection(&event) {
+            return Simulation::rejected(Check::Validation, "invalid", reason);
+        }
+        if self.stored.contains(scope, &event.id).await {
+            return Simulation::accepted(DUPLICATE_EVENT, &[]);
+        }
+
+        let context = EventContext {
+            authed_pubkey: Some(event.pubkey),
+            subdomain: Arc::new(scope.clone()),
+            relay_pubkey: self.relay_pubkey,
+        };
+        match self.processor.simulate(event, &context).await {
+            Ok(commands) => Simulation::accepted("", &commands),
+            Err(error) => Simulation::rejected(
+                Check::Processor,
+                error.reason_code().as_str(),
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GroupValidationConfig;
+    use crate::groups::{
+        Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_EDIT_METADATA_9002,
+        KIND_GROUP_USER_JOIN_REQUEST_9021,
+    };
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use relay_builder::EventProcessor;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    async fn stored_count(database: &crate::RelayDatabase) -> usize {
+        database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn test_simulated_events_match_the_real_path_without_writes() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let simulator = Simulator::new(
+            processor.clone(),
+            EventLimits {
+                max_event_size: 64 * 1024,
+                max_tags: 100,
+                max_content_length: 8 * 1024,
+                max_member_tags: 1000,
+            },
+            ValidationMiddleware::new(relay_keys.public_key(), GroupValidationConfig::default()),
+            Arc::new(StoredEvents::new(database.clone(), 16)),
+            relay_keys.public_key(),
+        );
+        let admin = Keys::generate();
+        let joiner = Keys::generate();
+        let h = Tag::custom(TagKind::h(), ["closed_group"]);
+        let publish = |event: Event, author: &Keys| {
+            let context = EventContext {
+                authed_pubkey: Some(author.public_key()),
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey: relay_keys.public_key(),
+            };
+            let processor = processor.clone();
+            let database = database.clone();
+            let relay_keys = relay_keys.clone();
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await
+                    .unwrap();
+                let simulated: Vec<SimulatedCommand> =
+                    commands.iter().map(SimulatedCommand::from).collect();
+                apply_store_commands(&database, &relay_keys, commands)
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                simulated
+            }
+        };
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tags([
+                h.clone(),
+                Tag::custom(TagKind::custom("closed"), &[] as &[String]),
+            ])
+            .sign_with_keys(&admin)
+            .unwrap();
+        publish(create, &admin).await;
+        let stored = stored_count(&database).await;
+
+        let join = EventBuilder::new(KIND_GROUP_USER_JOIN_REQUEST_9021, "")
+            .tag(h.clone())
+            .sign_with_keys(&joiner)
+            .unwrap();
+        let rename = EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "")
+            .tags([h.clone(), Tag::custom(TagKind::Name, ["Renamed"])])
+            .sign_with_keys(&admin)
+            .unwrap();
+
+        let join_simulation = simulator.simulate(join.clone(), &Scope::Default).await;
+        let rename_simulation = simulator.simulate(rename.clone(), &Scope::Default).await;
+        assert!(join_simulation.accepted, "{join_simulation:?}");
+        assert!(rename_simulation.accepted, "{rename_simulation:?}");
+
+        // Nothing was written and the group is unchanged
+        assert_eq!(stored_count(&database).await, stored);
+        let group = groups.get_group(&Scope::Default, "closed_group").unwrap();
+        assert!(!group.join_requests.contains(&joiner.public_key()));
+        assert_ne!(group.metadata.name, "Renamed");
+        drop(group);
+
+        // The real path returns the commands the simulation reported
+        assert_eq!(publish(join, &joiner).await, join_simulation.commands);
+        assert_eq!(publish(rename, &admin).await, rename_simulation.commands);
+
+        // Only admins can edit the metadata
+        let forged = EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "")
+            .tags([h, Tag::custom(TagKind::Name, ["Mine"])])
+            .sign_with_keys(&joiner)
+            .unwrap();
+        let simulation = simulator.simulate(forged, &Scope::Default).await;
+        assert!(!simulation.accepted);
+        assert_eq!(simulation.rejected_by, Some(Check::Processor));
+        assert_eq!(simulation.reason, Some("restricted"));
+        assert!(simulation.commands.is_empty());
+    }
+}
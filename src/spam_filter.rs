@@ -0,0 +1,726 @@
+//! Spam heuristics for group messages.
+//!
+//! Open groups get spam waves, usually one key posting fast, many keys
+//! posting the same text, or messages stuffed with mentions or links. The
+//! [`SpamFilter`] scores each message to a managed group with four checks:
+//!
+//! - messages a pubkey posted to the group within a sliding window
+//! - copies of the same normalized content posted to the group within a
+//!   window, by anyone
+//! - pubkeys mentioned, as `p` tags or `nostr:` references in the content
+//! - links in the content
+//!
+//! Limits come from [`SpamFilterSettings`] and each can be overridden per
+//! group with a metadata tag: `spam_max_messages`, `spam_max_duplicates`,
+//! `spam_max_mentions` and `spam_max_links` (0 turns the check off), and
+//! `spam_action` (`reject` or `shadow_mute`). The tags are kept with the rest
+//! of the group's metadata.
+//!
+//! Flagged messages are rejected with `rate-limited:` or `invalid:`, or
+//! shadow-muted: stored and delivered only to their author, the group admins
+//! and the relay, and never replicated, archived or sent to webhooks. Admins
+//! and the relay are never scored. An admin adding a pubkey (9000) or
+//! deleting one of its messages (9005) clears its score and unmutes its
+//! messages.
+//!
+//! Each mute is persisted as a relay-signed
+//! [`KIND_GROUP_SPAM_MUTE_39013`] marker that is never served to clients,
+//! and the markers are loaded back on start, so muted messages stay hidden
+//! across restarts. Scores are kept in memory and forgotten on restart.
+
+use crate::config::{SpamAction, SpamFilterSettings};
+use crate::error::Rejection;
+use crate::group::{count_urls, GroupMetadata, KIND_GROUP_SPAM_MUTE_39013};
+use crate::relay_keys::{RelayIdentity, RelayPubkeys};
+use crate::RelayDatabase;
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::StoreCommand;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// Number of pubkeys and contents remembered
+pub const SPAM_STATE_CAPACITY: usize = 100_000;
+
+type GroupKey = (Scope, String);
+
+/// Limits applied to the messages of one group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpamLimits {
+    pub max_messages: usize,
+    pub window: Duration,
+    pub max_duplicates: usize,
+    pub duplicate_window: Duration,
+    pub max_mentions: usize,
+    pub max_links: usize,
+    pub action: SpamAction,
+}
+
+impl From<&SpamFilterSettings> for SpamLimits {
+    fn from(settings: &SpamFilterSettings) -> Self {
+        Self {
+            max_messages: settings.max_messages,
+            window: settings.window,
+            max_duplicates: settings.max_duplicates,
+            duplicate_window: settings.duplicate_window,
+            max_mentions: settings.max_mentions,
+            max_links: settings.max_links,
+            action: settings.action,
+        }
+    }
+}
+
+impl SpamLimits {
+    /// These limits with the group's metadata overrides applied
+    pub fn for_group(&self, metadata: &GroupMetadata) -> Self {
+        let mut limits = *self;
+        for tag in &metadata.unknown_tags {
+            let [name, value, ..] = tag.as_slice() else {
+                continue;
+            };
+            let limit = value.parse::<usize>().ok();
+            match (name.as_str(), limit) {
+                ("spam_max_messages", Some(limit)) => limits.max_messages = limit,
+                ("spam_max_duplicates", Some(limit)) => limits.max_duplicates = limit,
+                ("spam_max_mentions", Some(limit)) => limits.max_mentions = limit,
+                ("spam_max_links", Some(limit)) => limits.max_links = limit,
+                ("spam_action", _) => match value.as_str() {
+                    "reject" => limits.action = SpamAction::Reject,
+                    "shadow_mute" => limits.action = SpamAction::ShadowMute,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        limits
+    }
+}
+
+/// Which check flagged a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamReason {
+    Rate,
+    Duplicate,
+    Mentions,
+    Links,
+}
+
+impl SpamReason {
+    /// The OK a rejected message is answered with
+    pub fn rejection(&self) -> relay_builder::Error {
+        match self {
+            SpamReason::Rate => {
+                relay_builder::Error::rate_limited("posting too fast in this group")
+            }
+            SpamReason::Duplicate => {
+                relay_builder::Error::rate_limited("this message was already posted")
+            }
+            SpamReason::Mentions => relay_builder::Error::invalid("too many mentions"),
+            SpamReason::Links => relay_builder::Error::invalid("too many links"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    Reject(SpamReason),
+    Mute(SpamReason),
+}
+
+/// Content with case and whitespace differences removed
+pub fn normalize_content(content: &str) -> String {
+    content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Pubkeys a message mentions, by `p` tag or `nostr:` reference
+pub fn count_mentions(event: &Event) -> usize {
+    let tagged = event.tags.public_keys().count();
+    let referenced = event.content.matches("nostr:npub1").count()
+        + event.content.matches("nostr:nprofile1").count();
+    tagged.max(referenced)
+}
+
+/// Drops the times older than `window` and records `now`, returning how
+/// many times remain
+fn record(times: &mut VecDeque<Instant>, window: Duration, now: Instant) -> usize {
+    while times
+        .front()
+        .is_some_and(|at| now.duration_since(*at) >= window)
+    {
+        times.pop_front();
+    }
+    times.push_back(now);
+    times.len()
+}
+
+pub struct SpamFilter {
+    limits: SpamLimits,
+    /// Recent message times per group and pubkey
+    messages: Mutex<LruCache<(GroupKey, PublicKey), VecDeque<Instant>>>,
+    /// Recent times each normalized content was posted per group
+    duplicates: Mutex<LruCache<(GroupKey, [u8; 32]), VecDeque<Instant>>>,
+    /// Shadow-muted events with their group and author, all of them so a
+    /// muted message never becomes visible
+    muted: Mutex<HashMap<EventId, (GroupKey, PublicKey)>>,
+}
+
+impl std::fmt::Debug for SpamFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpamFilter")
+            .field("limits", &self.limits)
+            .field("muted", &self.muted.lock().len())
+            .finish()
+    }
+}
+
+impl SpamFilter {
+    pub fn new(settings: &SpamFilterSettings) -> Self {
+        let capacity = NonZeroUsize::new(SPAM_STATE_CAPACITY).expect("capacity is non-zero");
+        Self {
+            limits: SpamLimits::from(settings),
+            messages: Mutex::new(LruCache::new(capacity)),
+            duplicates: Mutex::new(LruCache::new(capacity)),
+            muted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Scores a message to a group and records it.
+    pub fn check(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        metadata: &GroupMetadata,
+        event: &Event,
+    ) -> Verdict {
+        let limits = self.limits.for_group(metadata);
+        let key = (scope.clone(), group_id.to_string());
+        match self.evaluate(&limits, key, event, Instant::now()) {
+            None => Verdict::Clean,
+            Some(reason) => match limits.action {
+                SpamAction::Reject => Verdict::Reject(reason),
+                SpamAction::ShadowMute => Verdict::Mute(reason),
+            },
+        }
+    }
+
+    fn evaluate(
+        &self,
+        limits: &SpamLimits,
+        key: GroupKey,
+        event: &Event,
+        now: Instant,
+    ) -> Option<SpamReason> {
+        if limits.max_mentions > 0 && count_mentions(event) > limits.max_mentions {
+            return Some(SpamReason::Mentions);
+        }
+        if limits.max_links > 0 && count_urls(&event.content) > limits.max_links {
+            return Some(SpamReason::Links);
+        }
+
+        if limits.max_messages > 0 {
+            let mut messages = self.messages.lock();
+            let times = messages.get_or_insert_mut((key.clone(), event.pubkey), VecDeque::new);
+            if record(times, limits.window, now) > limits.max_messages {
+                return Some(SpamReason::Rate);
+            }
+        }
+
+        let content = normalize_content(&event.content);
+        if limits.max_duplicates > 0 && !content.is_empty() {
+            let hash: [u8; 32] = Sha256::digest(content.as_bytes()).into();
+            let mut duplicates = self.duplicates.lock();
+            let times = duplicates.get_or_insert_mut((key, hash), VecDeque::new);
+            if record(times, limits.duplicate_window, now) > limits.max_duplicates {
+                return Some(SpamReason::Duplicate);
+            }
+        }
+
+        None
+    }
+
+    pub fn mute(&self, scope: &Scope, group_id: &str, event_id: EventId, author: PublicKey) {
+        self.muted
+            .lock()
+            .insert(event_id, ((scope.clone(), group_id.to_string()), author));
+    }
+
+    /// The author of a shadow-muted event
+    pub fn muted_author(&self, event_id: &EventId) -> Option<PublicKey> {
+        self.muted.lock().get(event_id).map(|(_, author)| *author)
+    }
+
+    /// An admin acted on these pubkeys, or deleted these events: forgets
+    /// the recent messages of the pubkeys and of the deleted events' authors
+    /// in the group, and unmutes their messages. Returns every pardoned
+    /// pubkey, whose mute markers are to be deleted.
+    pub fn pardon(
+        &self,
+        scope: &Scope,
+        group_id: &str,
+        pubkeys: &[PublicKey],
+        deleted: &[EventId],
+    ) -> Vec<PublicKey> {
+        let key = (scope.clone(), group_id.to_string());
+        let mut muted = self.muted.lock();
+        let mut pardoned = pubkeys.to_vec();
+        for event_id in deleted {
+            if let Some((_, author)) = muted.remove(event_id) {
+                pardoned.push(author);
+            }
+        }
+        if pardoned.is_empty() {
+            return pardoned;
+        }
+
+        muted.retain(|_, (group, author)| group != &key || !pardoned.contains(author));
+        drop(muted);
+
+        let mut messages = self.messages.lock();
+        for pubkey in &pardoned {
+            messages.pop(&(key.clone(), *pubkey));
+        }
+        pardoned
+    }
+
+    /// Loads the mutes persisted in every scope, returning how many were found
+    pub async fn load(
+        &self,
+        database: &RelayDatabase,
+        relay_keys: &RelayPubkeys,
+    ) -> anyhow::Result<usize> {
+        let mut loaded = 0;
+        for scope in database.list_scopes().await? {
+            let markers = database
+                .query(vec![Filter::new().kind(KIND_GROUP_SPAM_MUTE_39013)], &scope)
+                .await?;
+            for marker in markers {
+                if !relay_keys.is_relay(&marker.pubkey) {
+                    continue;
+                }
+                let event_id = marker
+                    .tags
+                    .identifier()
+                    .and_then(|id| EventId::from_hex(id).ok());
+                let group_id = marker.tags.find(TagKind::h()).and_then(|tag| tag.content());
+                let author = marker.tags.public_keys().next();
+                if let (Some(event_id), Some(group_id), Some(author)) = (event_id, group_id, author)
+                {
+                    self.mute(&scope, group_id, event_id, *author);
+                    loaded += 1;
+                }
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+/// The relay-signed marker persisting a mute
+pub fn mute_marker(
+    scope: &Scope,
+    group_id: &str,
+    event_id: EventId,
+    author: PublicKey,
+    relay_pubkey: PublicKey,
+) -> StoreCommand {
+    let marker = EventBuilder::new(KIND_GROUP_SPAM_MUTE_39013, "")
+        .tags([
+            Tag::identifier(event_id.to_hex()),
+            Tag::custom(TagKind::h(), [group_id]),
+            Tag::public_key(author),
+        ])
+        .build(relay_pubkey);
+    StoreCommand::SaveUnsignedEvent(marker, scope.clone(), None)
+}
+
+/// Deletes the mute markers of the pardoned pubkeys' messages in a group
+pub fn unmute_markers(scope: &Scope, group_id: &str, pardoned: &[PublicKey]) -> StoreCommand {
+    let filter = Filter::new()
+        .kind(KIND_GROUP_SPAM_MUTE_39013)
+        .custom_tag(SingleLetterTag::lowercase(Alphabet::H), group_id)
+        .pubkeys(pardoned.iter().copied());
+    StoreCommand::DeleteEvents(filter, scope.clone(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_DELETE_EVENT_9005,
+        KIND_GROUP_USER_JOIN_REQUEST_9021,
+    };
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use crate::utils::apply_store_commands;
+    use relay_builder::{EventContext, EventProcessor};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn settings() -> SpamFilterSettings {
+        SpamFilterSettings {
+            max_messages: 3,
+            window: Duration::from_secs(60),
+            max_duplicates: 2,
+            duplicate_window: Duration::from_secs(600),
+            max_mentions: 2,
+            max_links: 1,
+            action: SpamAction::Reject,
+        }
+    }
+
+    fn limits() -> SpamLimits {
+        SpamLimits::from(&settings())
+    }
+
+    fn filter() -> SpamFilter {
+        SpamFilter::new(&settings())
+    }
+
+    fn message(keys: &Keys, content: &str) -> Event {
+        EventBuilder::new(Kind::Custom(9), content)
+            .tag(Tag::custom(TagKind::h(), ["spam"]))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    fn key() -> GroupKey {
+        (Scope::Default, "spam".to_string())
+    }
+
+    #[test]
+    fn test_message_rate_slides() {
+        let filter = filter();
+        let keys = Keys::generate();
+        let start = Instant::now();
+
+        for i in 0..3 {
+            let event = message(&keys, &format!("message {i}"));
+            assert_eq!(filter.evaluate(&limits(), key(), &event, start), None);
+        }
+        let event = message(&keys, "one too many");
+        assert_eq!(
+            filter.evaluate(&limits(), key(), &event, start),
+            Some(SpamReason::Rate)
+        );
+
+        // Once the window has passed the pubkey can post again
+        let later = start + Duration::from_secs(61);
+        let event = message(&keys, "calm again");
+        assert_eq!(filter.evaluate(&limits(), key(), &event, later), None);
+    }
+
+    #[test]
+    fn test_duplicate_content_across_pubkeys() {
+        let filter = filter();
+        let now = Instant::now();
+
+        assert_eq!(
+            filter.evaluate(
+                &limits(),
+                key(),
+                &message(&Keys::generate(), "Buy now"),
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            filter.evaluate(
+                &limits(),
+                key(),
+                &message(&Keys::generate(), "  buy   NOW "),
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            filter.evaluate(
+                &limits(),
+                key(),
+                &message(&Keys::generate(), "buy now"),
+                now
+            ),
+            Some(SpamReason::Duplicate)
+        );
+
+        // Other groups keep their own counts
+        let other = (Scope::Default, "other".to_string());
+        assert_eq!(
+            filter.evaluate(
+                &limits(),
+                other,
+                &message(&Keys::generate(), "buy now"),
+                now
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mentions_and_links() {
+        let filter = filter();
+        let keys = Keys::generate();
+        let now = Instant::now();
+
+        let mentions = EventBuilder::new(Kind::Custom(9), "hey")
+            .tags((0..3).map(|_| Tag::public_key(Keys::generate().public_key())))
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(count_mentions(&mentions), 3);
+        assert_eq!(
+            filter.evaluate(&limits(), key(), &mentions, now),
+            Some(SpamReason::Mentions)
+        );
+
+        let links = message(&keys, "https://a.example and http://b.example");
+        assert_eq!(
+            filter.evaluate(&limits(), key(), &links, now),
+            Some(SpamReason::Links)
+        );
+    }
+
+    #[test]
+    fn test_group_overrides() {
+        let mut metadata = GroupMetadata::new("spam".to_string());
+        metadata.unknown_tags = vec![
+            Tag::parse(["spam_max_messages", "0"]).unwrap(),
+            Tag::parse(["spam_max_links", "10"]).unwrap(),
+            Tag::parse(["spam_action", "shadow_mute"]).unwrap(),
+        ];
+        let overridden = limits().for_group(&metadata);
+        assert_eq!(overridden.max_messages, 0);
+        assert_eq!(overridden.max_links, 10);
+        assert_eq!(overridden.max_mentions, 2);
+        assert_eq!(overridden.action, SpamAction::ShadowMute);
+
+        // With the rate check off, a pubkey can keep posting
+        let filter = filter();
+        let keys = Keys::generate();
+        for i in 0..10 {
+            assert_eq!(
+                filter.check(
+                    &Scope::Default,
+                    "spam",
+                    &metadata,
+                    &message(&keys, &i.to_string())
+                ),
+                Verdict::Clean
+            );
+        }
+    }
+
+    #[test]
+    fn test_pardon_clears_score_and_mutes() {
+        let filter = filter();
+        let keys = Keys::generate();
+        let now = Instant::now();
+        for i in 0..4 {
+            filter.evaluate(&limits(), key(), &message(&keys, &i.to_string()), now);
+        }
+        let muted = message(&keys, "muted");
+        filter.mute(&Scope::Default, "spam", muted.id, keys.public_key());
+        assert!(filter.muted_author(&muted.id).is_some());
+
+        filter.pardon(&Scope::Default, "spam", &[keys.public_key()], &[]);
+        assert!(filter.muted_author(&muted.id).is_none());
+        assert_eq!(
+            filter.evaluate(&limits(), key(), &message(&keys, "fresh start"), now),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flooding_member_is_stopped_through_processor() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let spam_filter = Arc::new(filter());
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_spam_filter(spam_filter.clone());
+        let admin = Keys::generate();
+        let flooder = Keys::generate();
+        let bystander = Keys::generate();
+        let context = |keys: &Keys| EventContext {
+            authed_pubkey: Some(keys.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h = Tag::custom(TagKind::h(), ["spam"]);
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tags([h.clone(), Tag::custom(TagKind::custom("public"), [""])])
+            .tags([Tag::custom(TagKind::custom("open"), [""])])
+            .sign_with_keys(&admin)
+            .unwrap();
+        let commands = processor
+            .handle_event(create, Arc::new(RwLock::new(())), &context(&admin))
+            .await
+            .unwrap();
+        apply_store_commands(&database, &relay_keys, commands)
+            .await
+            .unwrap();
+        for keys in [&flooder, &bystander] {
+            let join = EventBuilder::new(KIND_GROUP_USER_JOIN_REQUEST_9021, "")
+                .tag(h.clone())
+                .sign_with_keys(keys)
+                .unwrap();
+            processor
+                .handle_event(join, Arc::new(RwLock::new(())), &context(keys))
+                .await
+                .unwrap();
+        }
+
+        for i in 0..3 {
+            processor
+                .handle_event(
+                    message(&flooder, &format!("flood {i}")),
+                    Arc::new(RwLock::new(())),
+                    &context(&flooder),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(processor
+            .handle_event(
+                message(&flooder, "flood 3"),
+                Arc::new(RwLock::new(())),
+                &context(&flooder)
+            )
+            .await
+            .is_err());
+
+        // Other members aren't affected, and admins are exempt
+        processor
+            .handle_event(
+                message(&bystander, "hello"),
+                Arc::new(RwLock::new(())),
+                &context(&bystander),
+            )
+            .await
+            .unwrap();
+        for i in 0..5 {
+            processor
+                .handle_event(
+                    message(&admin, &format!("announcement {i}")),
+                    Arc::new(RwLock::new(())),
+                    &context(&admin),
+                )
+                .await
+                .unwrap();
+        }
+
+        // A shadow-muted message is only visible to its author and admins
+        let muted = message(&flooder, "muted");
+        spam_filter.mute(&Scope::Default, "spam", muted.id, flooder.public_key());
+        assert!(processor
+            .can_see_event(&muted, Arc::new(RwLock::new(())), &context(&flooder))
+            .unwrap());
+        assert!(processor
+            .can_see_event(&muted, Arc::new(RwLock::new(())), &context(&admin))
+            .unwrap());
+        assert!(!processor
+            .can_see_event(&muted, Arc::new(RwLock::new(())), &context(&bystander))
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mutes_survive_restart() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let mut settings = settings();
+        settings.action = SpamAction::ShadowMute;
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key())
+            .with_spam_filter(Arc::new(SpamFilter::new(&settings)));
+        let admin = Keys::generate();
+        let flooder = Keys::generate();
+        let h = Tag::custom(TagKind::h(), ["spam"]);
+        let send = |event: Event, keys: &Keys| {
+            let processor = processor.clone();
+            let database = database.clone();
+            let relay_keys = relay_keys.clone();
+            let context = EventContext {
+                authed_pubkey: Some(keys.public_key()),
+                subdomain: Arc::new(Scope::Default),
+                relay_pubkey: relay_keys.public_key(),
+            };
+            async move {
+                let commands = processor
+                    .handle_event(event, Arc::new(RwLock::new(())), &context)
+                    .await
+                    .unwrap();
+                apply_store_commands(&database, &relay_keys, commands)
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+        };
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tags([h.clone(), Tag::custom(TagKind::custom("public"), [""])])
+            .tags([Tag::custom(TagKind::custom("open"), [""])])
+            .sign_with_keys(&admin)
+            .unwrap();
+        send(create, &admin).await;
+        let join = EventBuilder::new(KIND_GROUP_USER_JOIN_REQUEST_9021, "")
+            .tag(h.clone())
+            .sign_with_keys(&flooder)
+            .unwrap();
+        send(join, &flooder).await;
+        for i in 0..3 {
+            send(message(&flooder, &format!("flood {i}")), &flooder).await;
+        }
+        let muted = message(&flooder, "flood 3");
+        send(muted.clone(), &flooder).await;
+
+        // A fresh filter, as after a restart, still hides the message
+        let restarted = SpamFilter::new(&settings);
+        assert_eq!(
+            restarted
+                .load(&database, groups.relay_keys())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            restarted.muted_author(&muted.id),
+            Some(flooder.public_key())
+        );
+
+        // Deleting the message unmutes the author for good
+        let delete = EventBuilder::new(KIND_GROUP_DELETE_EVENT_9005, "")
+            .tags([h.clone(), Tag::event(muted.id)])
+            .sign_with_keys(&admin)
+            .unwrap();
+        send(delete, &admin).await;
+        let restarted = SpamFilter::new(&settings);
+        assert_eq!(
+            restarted
+                .load(&database, groups.relay_keys())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
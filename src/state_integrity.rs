@@ -0,0 +1,246 @@
+//! Repair of missing or stale group state events.
+//!
+//! A crash between updating a group in memory and storing its state events
+//! can leave a group without its metadata (39000), admins (39001), members
+//! (39002) or roles (39003) in the database, so clients querying them see
+//! nothing even though the group works. [`StateIntegrityChecker`] looks up
+//! those events for every loaded group on an interval and regenerates the
+//! state of groups with one missing, or older than the group's last change.
+//!
+//! Groups whose membership lists are held back by the coalescer are skipped
+//! until they are written, and nothing is repaired while the relay is
+//! read-only.
+
+use crate::groups::{Groups, ADDRESSABLE_EVENT_KINDS};
+use crate::membership_state::MembershipCoalescer;
+use crate::metrics;
+use crate::read_only::ReadOnlyMode;
+use crate::utils::{apply_store_commands, scope_name};
+use crate::RelayDatabase;
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+pub struct StateIntegrityChecker {
+    groups: Arc<Groups>,
+    database: Arc<RelayDatabase>,
+    relay_keys: Keys,
+    membership_coalescer: Option<Arc<MembershipCoalescer>>,
+    read_only: Option<Arc<ReadOnlyMode>>,
+}
+
+impl std::fmt::Debug for StateIntegrityChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateIntegrityChecker")
+            .finish_non_exhaustive()
+    }
+}
+
+impl StateIntegrityChecker {
+    pub fn new(groups: Arc<Groups>, database: Arc<RelayDatabase>, relay_keys: Keys) -> Self {
+        Self {
+            groups,
+            database,
+            relay_keys,
+            membership_coalescer: None,
+            read_only: None,
+        }
+    }
+
+    /// Skip groups with membership lists waiting to be written
+    pub fn with_membership_coalescer(mut self, coalescer: Arc<MembershipCoalescer>) -> Self {
+        self.membership_coalescer = Some(coalescer);
+        self
+    }
+
+    /// Repair nothing while the relay is read-only
+    pub fn with_read_only(mut self, read_only: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Regenerates the state events of every group with a missing or stale
+    /// one, returning how many groups were repaired.
+    pub async fn check(&self) -> Result<usize> {
+        if !self.groups.is_loaded()
+            || self
+                .read_only
+                .as_ref()
+                .is_some_and(|mode| mode.is_enabled())
+        {
+            return Ok(0);
+        }
+
+        let now = Timestamp::now();
+        let mut repaired = 0;
+        for (scope, group_id, group) in self.groups.list_all_groups() {
+            if self
+                .membership_coalescer
+                .as_ref()
+                .is_some_and(|coalescer| coalescer.is_pending(&scope, &group_id))
+            {
+                continue;
+            }
+
+            let filter = Filter::new()
+                .kinds(ADDRESSABLE_EVENT_KINDS)
+                .identifier(group_id.clone());
+            let mut stored: HashMap<Kind, Timestamp> = HashMap::new();
+            for event in self.database.query(vec![filter], &scope).await? {
+                let created_at = stored.entry(event.kind).or_insert(event.created_at);
+                *created_at = (*created_at).max(event.created_at);
+            }
+
+            let state_events = match group
+                .generate_all_state_events(self.groups.relay_keys(), &self.groups.relay_url)
+            {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!("Can't regenerate the state of group {}: {}", group_id, e);
+                    continue;
+                }
+            };
+            // A change timestamped in the future would never look repaired
+            let changed_at = (group.updated_at <= now).then_some(group.updated_at);
+            let broken: Vec<Kind> = state_events
+                .iter()
+                .map(|event| event.kind)
+                .filter(|kind| ADDRESSABLE_EVENT_KINDS.contains(kind))
+                .filter(|kind| match (stored.get(kind), changed_at) {
+                    (None, _) => true,
+                    (Some(created_at), Some(changed_at)) => *created_at < changed_at,
+                    (Some(_), None) => false,
+                })
+                .collect();
+            if broken.is_empty() {
+                continue;
+            }
+
+            info!(
+                "Repairing state events {:?} of group {} in scope {}",
+                broken.iter().map(|kind| kind.as_u16()).collect::<Vec<_>>(),
+                group_id,
+                scope_name(&scope)
+            );
+            let commands = state_events
+                .into_iter()
+                .map(|event| StoreCommand::SaveUnsignedEvent(event, scope.clone(), None))
+                .collect();
+            apply_store_commands(&self.database, &self.relay_keys, commands).await?;
+            for kind in broken {
+                metrics::state_event_repairs(kind.as_u16()).increment(1);
+            }
+            repaired += 1;
+        }
+
+        Ok(repaired)
+    }
+
+    /// Runs a check every `interval` until cancelled.
+    pub fn spawn(self: Arc<Self>, interval: Duration, cancellation_token: CancellationToken) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        match self.check().await {
+                            Ok(0) => debug!("Group state events are intact"),
+                            Ok(repaired) => info!("Repaired the state events of {} groups", repaired),
+                            Err(e) => warn!("Group state integrity check failed: {}", e),
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{
+        KIND_GROUP_ADD_USER_9000, KIND_GROUP_CREATE_9007, KIND_GROUP_MEMBERS_39002,
+    };
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::test_utils::setup_test;
+    use nostr_lmdb::Scope;
+    use relay_builder::{EventContext, EventProcessor};
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_missing_members_list_is_restored() {
+        let (_tmp_dir, database, relay_keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                relay_keys.public_key(),
+                "wss://test.relay".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups.clone(), relay_keys.public_key());
+        let checker =
+            StateIntegrityChecker::new(groups.clone(), database.clone(), relay_keys.clone());
+        let admin = Keys::generate();
+        let member = Keys::generate().public_key();
+        let context = EventContext {
+            authed_pubkey: Some(admin.public_key()),
+            subdomain: Arc::new(Scope::Default),
+            relay_pubkey: relay_keys.public_key(),
+        };
+        let h = Tag::custom(TagKind::h(), ["intact"]);
+
+        let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+            .tag(h.clone())
+            .sign_with_keys(&admin)
+            .unwrap();
+        let add = EventBuilder::new(KIND_GROUP_ADD_USER_9000, "")
+            .tags([h, Tag::public_key(member)])
+            .sign_with_keys(&admin)
+            .unwrap();
+        for event in [create, add] {
+            let commands = processor
+                .handle_event(event, Arc::new(RwLock::new(())), &context)
+                .await
+                .unwrap();
+            apply_store_commands(&database, &relay_keys, commands)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        assert_eq!(checker.check().await.unwrap(), 0);
+
+        let members_filter = Filter::new()
+            .kind(KIND_GROUP_MEMBERS_39002)
+            .identifier("intact");
+        database
+            .delete(members_filter.clone(), &Scope::Default)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(database
+            .query(vec![members_filter.clone()], &Scope::Default)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(checker.check().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let restored = database
+            .query(vec![members_filter], &Scope::Default)
+            .await
+            .unwrap();
+        let restored = restored.into_iter().next().unwrap();
+        assert!(restored.tags.public_keys().any(|pubkey| *pubkey == member));
+
+        // Once repaired there is nothing left to do
+        assert_eq!(checker.check().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,331 @@
+//! Per-connection subscription and filter limits.
+//!
+//! REQs that would exceed `max_subscriptions`, carry more than
+//! `max_filters_per_req` filters, or list more than `max_filter_ids` ids in a
+//! single filter are answered with a CLOSED instead of reaching the database.
+//! Rejected subscriptions are never added to the [`SubscriptionRegistry`],
+//! and the registry forgets subscriptions the relay answers with a CLOSED.
+
+use crate::introspection::SubscriptionRegistry;
+use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::sync::Arc;
+use tracing::debug;
+
+pub const TOO_MANY_SUBSCRIPTIONS: &str = "error: too many subscriptions";
+pub const TOO_MANY_FILTERS: &str = "error: too many filters";
+pub const TOO_MANY_IDS: &str = "error: too many ids in filter";
+
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionLimits {
+    pub max_subscriptions: usize,
+    pub max_filters_per_req: usize,
+    pub max_filter_ids: usize,
+}
+
+impl SubscriptionLimits {
+    /// Checks a REQ against the limits, returning the CLOSED reason on failure.
+    pub fn check(
+        &self,
+        registry: &SubscriptionRegistry,
+        connection_id: &str,
+        subscription_id: &SubscriptionId,
+        filters: &[&Filter],
+    ) -> Result<(), &'static str> {
+        if filters.len() > self.max_filters_per_req {
+            return Err(TOO_MANY_FILTERS);
+        }
+
+        if filters.iter().any(|filter| {
+            filter
+                .ids
+                .as_ref()
+                .is_some_and(|ids| ids.len() > self.max_filter_ids)
+        }) {
+            return Err(TOO_MANY_IDS);
+        }
+
+        // Replacing an existing subscription doesn't count against the limit
+        if !registry.contains(connection_id, subscription_id)
+            && registry.subscription_count(connection_id) >= self.max_subscriptions
+        {
+            return Err(TOO_MANY_SUBSCRIPTIONS);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriptionLimitsMiddleware {
+    registry: Arc<SubscriptionRegistry>,
+    limits: SubscriptionLimits,
+}
+
+impl SubscriptionLimitsMiddleware {
+    pub fn new(registry: Arc<SubscriptionRegistry>, limits: SubscriptionLimits) -> Self {
+        Self { registry, limits }
+    }
+}
+
+impl NostrMiddleware<()> for SubscriptionLimitsMiddleware {
+    async fn process_inbound<Next>(
+        &self,
+        ctx: InboundContext<'_, (), Next>,
+    ) -> Result<(), anyhow::Error>
+    where
+        Next: relay_builder::nostr_middleware::InboundProcessor<()>,
+    {
+        let (subscription_id, result) = match &ctx.message {
+            Some(ClientMessage::Req {
+                subscription_id,
+                filter,
+            }) => (
+                subscription_id.clone().into_owned(),
+                self.limits.check(
+                    &self.registry,
+                    &ctx.connection_id.to_string(),
+                    subscription_id,
+                    &[&**filter],
+                ),
+            ),
+            Some(ClientMessage::ReqMultiFilter {
+                subscription_id,
+                filters,
+            }) => {
+                let filters: Vec<&Filter> = filters.iter().collect();
+                (
+                    subscription_id.clone().into_owned(),
+                    self.limits.check(
+                        &self.registry,
+                        &ctx.connection_id.to_string(),
+                        subscription_id,
+                        &filters,
+                    ),
+                )
+            }
+            _ => return ctx.next().await,
+        };
+
+        if let Err(reason) = result {
+            debug!(
+                "[{}] Rejecting REQ {}: {}",
+                ctx.connection_id, subscription_id, reason
+            );
+            ctx.send_message(RelayMessage::closed(subscription_id, reason))?;
+            return Ok(());
+        }
+
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::{Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_EDIT_METADATA_9002};
+    use crate::groups_event_processor::GroupsRelayProcessor;
+    use crate::introspection::IntrospectionMiddleware;
+    use crate::test_utils::{group_event, setup_test, TestClient, TestRelay};
+
+    fn limits() -> SubscriptionLimits {
+        SubscriptionLimits {
+            max_subscriptions: 3,
+            max_filters_per_req: 2,
+            max_filter_ids: 5,
+        }
+    }
+
+    #[test]
+    fn test_subscription_over_max_is_rejected_and_not_tracked() {
+        let registry = SubscriptionRegistry::new();
+        let limits = limits();
+        let filter = Filter::new().kind(Kind::TextNote);
+
+        for i in 0..limits.max_subscriptions {
+            let sub_id = SubscriptionId::new(format!("sub-{i}"));
+            assert!(limits.check(&registry, "conn", &sub_id, &[&filter]).is_ok());
+            registry.add("conn", &sub_id, vec![filter.clone()]);
+        }
+
+        let extra = SubscriptionId::new("sub-extra");
+        assert_eq!(
+            limits.check(&registry, "conn", &extra, &[&filter]),
+            Err(TOO_MANY_SUBSCRIPTIONS)
+        );
+        assert_eq!(
+            registry.subscription_count("conn"),
+            limits.max_subscriptions
+        );
+        assert!(!registry.contains("conn", &extra));
+
+        // The first max subscriptions are still in place
+        for i in 0..limits.max_subscriptions {
+            assert!(registry.contains("conn", &SubscriptionId::new(format!("sub-{i}"))));
+        }
+
+        // Replacing an existing subscription is still allowed
+        assert!(limits
+            .check(&registry, "conn", &SubscriptionId::new("sub-0"), &[&filter])
+            .is_ok());
+
+        // Other connections have their own budget
+        assert!(limits.check(&registry, "other", &extra, &[&filter]).is_ok());
+    }
+
+    #[test]
+    fn test_too_many_filters_rejected() {
+        let registry = SubscriptionRegistry::new();
+        let filter = Filter::new().kind(Kind::TextNote);
+
+        assert_eq!(
+            limits().check(
+                &registry,
+                "conn",
+                &SubscriptionId::new("sub"),
+                &[&filter, &filter, &filter]
+            ),
+            Err(TOO_MANY_FILTERS)
+        );
+    }
+
+    #[test]
+    fn test_too_many_ids_rejected() {
+        let registry = SubscriptionRegistry::new();
+        let keys = Keys::generate();
+        let ids: Vec<EventId> = (0..6)
+            .map(|i| {
+                EventBuilder::text_note(format!("note {i}"))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+                    .id
+            })
+            .collect();
+
+        let too_many = Filter::new().ids(ids.clone());
+        assert_eq!(
+            limits().check(&registry, "conn", &SubscriptionId::new("sub"), &[&too_many]),
+            Err(TOO_MANY_IDS)
+        );
+
+        let fine = Filter::new().ids(ids.into_iter().take(5));
+        assert!(limits()
+            .check(&registry, "conn", &SubscriptionId::new("sub"), &[&fine])
+            .is_ok());
+    }
+
+    /// Waits for the relay's answer to a REQ, true for EOSE and false for CLOSED
+    async fn req_answer(client: &mut TestClient, subscription_id: &SubscriptionId) -> bool {
+        loop {
+            match client.recv().await.unwrap() {
+                RelayMessage::EndOfStoredEvents(id) if *id == *subscription_id => return true,
+                RelayMessage::Closed {
+                    subscription_id: id,
+                    ..
+                } if *id == *subscription_id => return false,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reqs_leave_room_for_live_subscriptions() {
+        let (_tmp_dir, database, keys) = setup_test().await;
+        let groups = Arc::new(
+            Groups::load_groups(
+                database.clone(),
+                keys.public_key(),
+                "ws://127.0.0.1".to_string(),
+            )
+            .await
+            .unwrap(),
+        );
+        let processor = GroupsRelayProcessor::new(groups, keys.public_key());
+        let registry = Arc::new(SubscriptionRegistry::new());
+        let limits = SubscriptionLimits {
+            max_subscriptions: 2,
+            ..limits()
+        };
+        let relay = TestRelay::start_with_middlewares(
+            processor,
+            SubscriptionLimitsMiddleware::new(registry.clone(), limits),
+            IntrospectionMiddleware::new(registry, 500),
+            database,
+            keys,
+        )
+        .await
+        .unwrap();
+
+        let admin = Keys::generate();
+        let mut admin_client = relay.connect().await.unwrap();
+        admin_client.auth_as(&admin).await.unwrap();
+        admin_client
+            .publish(&group_event(&admin, KIND_GROUP_CREATE_9007, "public", ""))
+            .await
+            .unwrap();
+        let make_public = EventBuilder::new(KIND_GROUP_EDIT_METADATA_9002, "")
+            .tags([
+                Tag::custom(TagKind::h(), ["public"]),
+                Tag::custom(TagKind::custom("public"), &[] as &[String]),
+            ])
+            .sign_with_keys(&admin)
+            .unwrap();
+        admin_client.publish(&make_public).await.unwrap();
+
+        // Anonymous gift wrap REQs are closed by the processor, after
+        // passing the limits
+        let mut reader = relay.connect().await.unwrap();
+        for i in 0..limits.max_subscriptions {
+            let subscription_id = SubscriptionId::new(format!("wraps-{i}"));
+            reader
+                .send(ClientMessage::req(
+                    subscription_id.clone(),
+                    Filter::new().kind(Kind::GiftWrap),
+                ))
+                .await
+                .unwrap();
+            assert!(!req_answer(&mut reader, &subscription_id).await);
+        }
+
+        let feed = Filter::new()
+            .kind(Kind::Custom(9))
+            .custom_tag(SingleLetterTag::lowercase(Alphabet::H), "public");
+        let feeds: Vec<SubscriptionId> = (0..limits.max_subscriptions)
+            .map(|i| SubscriptionId::new(format!("feed-{i}")))
+            .collect();
+        for subscription_id in &feeds {
+            reader
+                .send(ClientMessage::req(subscription_id.clone(), feed.clone()))
+                .await
+                .unwrap();
+            assert!(req_answer(&mut reader, subscription_id).await);
+        }
+
+        let extra = SubscriptionId::new("feed-extra");
+        reader
+            .send(ClientMessage::req(extra.clone(), feed.clone()))
+            .await
+            .unwrap();
+        assert!(!req_answer(&mut reader, &extra).await);
+
+        // The first max subscriptions get live events
+        let message = group_event(&admin, Kind::Custom(9), "public", "hello");
+        admin_client.publish(&message).await.unwrap();
+        let mut delivered = Vec::new();
+        while delivered.len() < feeds.len() {
+            if let RelayMessage::Event {
+                subscription_id,
+                event,
+            } = reader.recv().await.unwrap()
+            {
+                assert_eq!(event.id, message.id);
+                delivered.push(subscription_id.to_string());
+            }
+        }
+        delivered.sort();
+        assert_eq!(
+            delivered,
+            feeds.iter().map(ToString::to_string).collect::<Vec<_>>()
+        );
+    }
+}
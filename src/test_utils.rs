@@ -1,11 +1,32 @@
+//! Helpers for tests, also available to other crates with the `test-utils`
+//! feature.
+//!
+//! Besides event and group builders, [`TestRelay`] runs a full relay with a
+//! given event processor on a random local port, and [`TestClient`] talks to
+//! it over a real websocket, so integration tests don't have to assemble the
+//! server themselves.
+
+use anyhow::{bail, Context};
+use axum::{extract::ConnectInfo, http::HeaderMap, routing::get, Router};
+use futures::{SinkExt, StreamExt};
 use nostr_sdk::prelude::*;
+use relay_builder::nostr_middleware::NostrMiddleware;
+use relay_builder::{handle_upgrade, EventProcessor, HandlerFactory, RelayBuilder, RelayConfig};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
 
 use crate::group::Group;
 use relay_builder::{NostrConnectionState, RelayDatabase};
 
+/// How long [`TestClient`] waits for an expected message
+pub const TEST_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn setup_test() -> (TempDir, Arc<RelayDatabase>, Keys) {
     let tmp_dir = TempDir::new().unwrap();
     let db_path = tmp_dir.path().join("test.db");
@@ -243,3 +264,327 @@ pub async fn create_test_role_event(
     ];
     create_test_event(admin_keys, 9006, tags).await
 }
+
+/// A signed event for a group, with an `h` tag
+pub fn group_event(keys: &Keys, kind: Kind, group_id: &str, content: &str) -> Event {
+    EventBuilder::new(kind, content)
+        .tag(Tag::custom(TagKind::h(), [group_id]))
+        .sign_with_keys(keys)
+        .unwrap()
+}
+
+/// A signed addressable event, with a `d` tag
+pub fn addressable_event(keys: &Keys, kind: Kind, identifier: &str, content: &str) -> Event {
+    EventBuilder::new(kind, content)
+        .tag(Tag::identifier(identifier))
+        .sign_with_keys(keys)
+        .unwrap()
+}
+
+/// A relay served on a random local port until dropped
+pub struct TestRelay {
+    pub url: String,
+    pub database: Arc<RelayDatabase>,
+    pub keys: Keys,
+    cancellation_token: CancellationToken,
+}
+
+impl TestRelay {
+    /// Serves `processor` over `database`, with NIP-42 auth enabled. Build the
+    /// processor from the database and keys returned by [`setup_test`].
+    pub async fn start<P>(
+        processor: P,
+        database: Arc<RelayDatabase>,
+        keys: Keys,
+    ) -> anyhow::Result<Self>
+    where
+        P: EventProcessor<()> + Clone + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let cancellation_token = CancellationToken::new();
+
+        let mut relay_config = RelayConfig::new(url.clone(), database.clone(), keys.clone());
+        relay_config.enable_auth = true;
+        let handler_factory = Arc::new(
+            RelayBuilder::<(), P>::new(relay_config)
+                .cancellation_token(cancellation_token.clone())
+                .event_processor(processor)
+                .build()
+                .await?,
+        );
+
+        let router = Router::new().route(
+            "/",
+            get(
+                move |ws: relay_builder::WebSocketUpgrade,
+                      ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap| {
+                    let handler = handler_factory.create(&headers);
+                    async move { handle_upgrade(ws, addr, handler).await }
+                },
+            ),
+        );
+        let shutdown = cancellation_token.clone();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+        });
+
+        Ok(Self {
+            url,
+            database,
+            keys,
+            cancellation_token,
+        })
+    }
+
+    /// Like [`TestRelay::start`], with `middleware` in front of the processor
+    pub async fn start_with_middleware<P, M>(
+        processor: P,
+        middleware: M,
+        database: Arc<RelayDatabase>,
+        keys: Keys,
+    ) -> anyhow::Result<Self>
+    where
+        P: EventProcessor<()> + Clone + Send + Sync + 'static,
+        M: NostrMiddleware<()> + Clone + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let cancellation_token = CancellationToken::new();
+
+        let mut relay_config = RelayConfig::new(url.clone(), database.clone(), keys.clone());
+        relay_config.enable_auth = true;
+        let handler_factory = Arc::new(
+            RelayBuilder::<(), P>::new(relay_config)
+                .cancellation_token(cancellation_token.clone())
+                .event_processor(processor)
+                .build_with(move |chain| chain.with(middleware.clone()))
+                .await?,
+        );
+
+        let router = Router::new().route(
+            "/",
+            get(
+                move |ws: relay_builder::WebSocketUpgrade,
+                      ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap| {
+                    let handler = handler_factory.create(&headers);
+                    async move { handle_upgrade(ws, addr, handler).await }
+                },
+            ),
+        );
+        let shutdown = cancellation_token.clone();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+        });
+
+        Ok(Self {
+            url,
+            database,
+            keys,
+            cancellation_token,
+        })
+    }
+
+    /// Like [`TestRelay::start_with_middleware`], with `outer` running before
+    /// `inner`
+    pub async fn start_with_middlewares<P, M, N>(
+        processor: P,
+        outer: M,
+        inner: N,
+        database: Arc<RelayDatabase>,
+        keys: Keys,
+    ) -> anyhow::Result<Self>
+    where
+        P: EventProcessor<()> + Clone + Send + Sync + 'static,
+        M: NostrMiddleware<()> + Clone + Send + Sync + 'static,
+        N: NostrMiddleware<()> + Clone + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let cancellation_token = CancellationToken::new();
+
+        let mut relay_config = RelayConfig::new(url.clone(), database.clone(), keys.clone());
+        relay_config.enable_auth = true;
+        let handler_factory = Arc::new(
+            RelayBuilder::<(), P>::new(relay_config)
+                .cancellation_token(cancellation_token.clone())
+                .event_processor(processor)
+                .build_with(move |chain| chain.with(outer.clone()).with(inner.clone()))
+                .await?,
+        );
+
+        let router = Router::new().route(
+            "/",
+            get(
+                move |ws: relay_builder::WebSocketUpgrade,
+                      ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap| {
+                    let handler = handler_factory.create(&headers);
+                    async move { handle_upgrade(ws, addr, handler).await }
+                },
+            ),
+        );
+        let shutdown = cancellation_token.clone();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+        });
+
+        Ok(Self {
+            url,
+            database,
+            keys,
+            cancellation_token,
+        })
+    }
+
+    pub async fn connect(&self) -> anyhow::Result<TestClient> {
+        TestClient::connect(&self.url).await
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+/// A websocket client for a [`TestRelay`]
+pub struct TestClient {
+    url: String,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// The last AUTH challenge the relay sent
+    challenge: Option<String>,
+    next_subscription: usize,
+}
+
+impl TestClient {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(Self {
+            url: url.to_string(),
+            ws,
+            challenge: None,
+            next_subscription: 0,
+        })
+    }
+
+    pub async fn send(&mut self, message: ClientMessage<'_>) -> anyhow::Result<()> {
+        self.ws.send(Message::text(message.as_json())).await?;
+        Ok(())
+    }
+
+    /// The next message from the relay, remembering AUTH challenges
+    pub async fn recv(&mut self) -> anyhow::Result<RelayMessage<'static>> {
+        loop {
+            let frame = tokio::time::timeout(TEST_CLIENT_TIMEOUT, self.ws.next())
+                .await
+                .context("timed out waiting for the relay")?
+                .context("connection closed")??;
+            let Message::Text(text) = frame else {
+                continue;
+            };
+            let message = RelayMessage::from_json(text.as_str())?;
+            if let RelayMessage::Auth { challenge } = &message {
+                self.challenge = Some(challenge.to_string());
+            }
+            return Ok(message);
+        }
+    }
+
+    pub async fn send_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        self.send(ClientMessage::event(event.clone())).await
+    }
+
+    /// Waits for the OK of an event, returning whether it was accepted and
+    /// its message
+    pub async fn expect_ok(&mut self, event_id: EventId) -> anyhow::Result<(bool, String)> {
+        loop {
+            if let RelayMessage::Ok {
+                event_id: id,
+                status,
+                message,
+            } = self.recv().await?
+            {
+                if id == event_id {
+                    return Ok((status, message.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Sends an event and fails unless the relay accepts it
+    pub async fn publish(&mut self, event: &Event) -> anyhow::Result<()> {
+        self.send_event(event).await?;
+        match self.expect_ok(event.id).await? {
+            (true, _) => Ok(()),
+            (false, message) => bail!("event {} rejected: {}", event.id, message),
+        }
+    }
+
+    /// Subscribes and collects the stored events, returning whether the
+    /// relay ended them with an EOSE rather than a CLOSED. The
+    /// subscription is closed afterwards.
+    pub async fn req_and_collect(
+        &mut self,
+        filters: Vec<Filter>,
+    ) -> anyhow::Result<(Vec<Event>, bool)> {
+        self.next_subscription += 1;
+        let subscription_id = SubscriptionId::new(format!("test-{}", self.next_subscription));
+        self.send(ClientMessage::req_multi_filter(
+            subscription_id.clone(),
+            filters,
+        ))
+        .await?;
+
+        let mut events = Vec::new();
+        let eose = loop {
+            match self.recv().await? {
+                RelayMessage::Event {
+                    subscription_id: id,
+                    event,
+                } if *id == subscription_id => events.push(event.into_owned()),
+                RelayMessage::EndOfStoredEvents(id) if *id == subscription_id => break true,
+                RelayMessage::Closed {
+                    subscription_id: id,
+                    ..
+                } if *id == subscription_id => return Ok((events, false)),
+                _ => {}
+            }
+        };
+        self.send(ClientMessage::close(subscription_id)).await?;
+        Ok((events, eose))
+    }
+
+    /// Authenticates with NIP-42, waiting for a challenge if none was sent
+    /// yet
+    pub async fn auth_as(&mut self, keys: &Keys) -> anyhow::Result<()> {
+        while self.challenge.is_none() {
+            self.recv().await?;
+        }
+        let challenge = self.challenge.clone().expect("challenge was received");
+        let auth =
+            EventBuilder::auth(challenge, RelayUrl::parse(&self.url)?).sign_with_keys(keys)?;
+        self.send(ClientMessage::auth(auth.clone())).await?;
+        match self.expect_ok(auth.id).await? {
+            (true, _) => Ok(()),
+            (false, message) => bail!("authentication failed: {}", message),
+        }
+    }
+}
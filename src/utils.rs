@@ -1,3 +1,6 @@
+use crate::{RelayDatabase, StoreCommand};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
 use std::sync::OnceLock;
 use tokio::runtime::Runtime;
 
@@ -17,3 +20,40 @@ pub fn get_blocking_runtime() -> &'static Runtime {
             .unwrap()
     })
 }
+
+/// Name used for a scope in config files and external payloads.
+///
+/// The default scope (no subdomain) is called "default".
+pub fn scope_name(scope: &Scope) -> &str {
+    match scope {
+        Scope::Default => "default",
+        Scope::Named { name, .. } => name,
+    }
+}
+
+/// Writes store commands straight to the database, signing relay-generated
+/// events with the relay keys.
+///
+/// For work done outside a relay connection (admin tools, background jobs),
+/// where commands don't go through relay_builder's write path.
+pub async fn apply_store_commands(
+    database: &RelayDatabase,
+    relay_keys: &Keys,
+    commands: Vec<StoreCommand>,
+) -> anyhow::Result<()> {
+    for command in commands {
+        match command {
+            StoreCommand::SaveSignedEvent(event, scope, _) => {
+                database.save_event(&event, &scope).await?;
+            }
+            StoreCommand::SaveUnsignedEvent(event, scope, _) => {
+                let event = event.sign_with_keys(relay_keys)?;
+                database.save_event(&event, &scope).await?;
+            }
+            StoreCommand::DeleteEvents(filter, scope, _) => {
+                database.delete(filter, &scope).await?;
+            }
+        }
+    }
+    Ok(())
+}
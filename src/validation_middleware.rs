@@ -1,6 +1,24 @@
+//! Structural checks on group events, before they reach the processor.
+//!
+//! [`GroupValidationConfig`] picks the checks: group events need an `h` tag,
+//! group commands exactly one, no event more than `max_h_tags`, and
+//! addressable events in a group a `d` tag naming the same group. Each failed
+//! check is a distinct [`Violation`] with its own `invalid:` message.
+//!
+//! In `log_only` mode violations are logged and counted in the
+//! `group_validation_violations` metric, but the event goes on, so operators
+//! can measure the impact of a check before enforcing it. Events the relay
+//! signs with a `d` tag are never checked.
+
+use crate::config::{GroupValidationConfig, ValidationMode};
+use crate::group::ALL_GROUP_KINDS_EXCEPT_DELETE_AND_ADDRESSABLE;
 use crate::groups::NON_GROUP_ALLOWED_KINDS;
+use crate::metrics;
+use crate::rejections::{ReasonCode, Rejections};
 use nostr_sdk::prelude::*;
 use relay_builder::nostr_middleware::{InboundContext, NostrMiddleware};
+use std::fmt;
+use std::sync::Arc;
 use tracing::{debug, warn};
 
 use crate::groups::{
@@ -10,31 +28,172 @@ use crate::groups::{
     KIND_GROUP_USER_JOIN_REQUEST_9021, KIND_GROUP_USER_LEAVE_REQUEST_9022,
 };
 
+/// A failed validation check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    MissingHTag(Kind),
+    /// A group command without exactly one `h` tag
+    NotOneHTag(Kind),
+    TooManyHTags {
+        kind: Kind,
+        count: usize,
+        max: usize,
+    },
+    MismatchedDTag(Kind),
+}
+
+impl Violation {
+    /// The check that failed, as labelled in metrics
+    pub fn check(&self) -> &'static str {
+        match self {
+            Violation::MissingHTag(_) => "missing_h_tag",
+            Violation::NotOneHTag(_) => "not_one_h_tag",
+            Violation::TooManyHTags { .. } => "too_many_h_tags",
+            Violation::MismatchedDTag(_) => "mismatched_d_tag",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MissingHTag(kind) => {
+                write!(f, "invalid: event kind {kind} requires an h tag")
+            }
+            Violation::NotOneHTag(kind) => {
+                write!(f, "invalid: event kind {kind} requires exactly one h tag")
+            }
+            Violation::TooManyHTags { kind, count, max } => write!(
+                f,
+                "invalid: event kind {kind} has {count} h tags, at most {max} allowed"
+            ),
+            Violation::MismatchedDTag(kind) => write!(
+                f,
+                "invalid: event kind {kind} requires a d tag matching its h tag"
+            ),
+        }
+    }
+}
+
+/// Kinds acting on a single group, which can't do without one
+fn is_group_command(kind: Kind) -> bool {
+    (kind == KIND_GROUP_DELETE_9008
+        || ALL_GROUP_KINDS_EXCEPT_DELETE_AND_ADDRESSABLE.contains(&kind))
+        && !NON_GROUP_ALLOWED_KINDS.contains(&kind)
+}
+
 #[derive(Debug, Clone)]
 pub struct ValidationMiddleware {
     relay_pubkey: PublicKey,
+    personal_kinds: Vec<Kind>,
+    config: GroupValidationConfig,
+    rejections: Option<Arc<Rejections>>,
 }
 
 impl ValidationMiddleware {
-    pub fn new(relay_pubkey: PublicKey) -> Self {
-        Self { relay_pubkey }
+    pub fn new(relay_pubkey: PublicKey, config: GroupValidationConfig) -> Self {
+        Self {
+            relay_pubkey,
+            personal_kinds: Vec::new(),
+            config,
+            rejections: None,
+        }
     }
 
-    fn validate_event(&self, event: &Event) -> Result<(), &'static str> {
+    /// Records the events turned down
+    pub fn with_rejections(mut self, rejections: Arc<Rejections>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Accepts these kinds without an 'h' tag, like profiles and contact lists
+    pub fn with_personal_kinds(mut self, kinds: impl IntoIterator<Item = Kind>) -> Self {
+        self.personal_kinds = kinds.into_iter().collect();
+        self
+    }
+
+    /// The first check the event fails, if any
+    pub fn validate_event(&self, event: &Event) -> Result<(), Violation> {
         // If the event is from the relay pubkey and has a 'd' tag, allow it.
         if event.pubkey == self.relay_pubkey && event.tags.find(TagKind::d()).is_some() {
             return Ok(());
         }
 
-        // For all other cases, require an 'h' tag for group events unless the kind is in the non-group allowed set.
-        if event.tags.find(TagKind::h()).is_none() && !NON_GROUP_ALLOWED_KINDS.contains(&event.kind)
+        let kind = event.kind;
+        let h_tags: Vec<Option<&str>> = event
+            .tags
+            .filter(TagKind::h())
+            .map(|tag| tag.content())
+            .collect();
+
+        if is_group_command(kind) && h_tags.len() != 1 {
+            return Err(Violation::NotOneHTag(kind));
+        }
+
+        if h_tags.is_empty()
+            && self.config.require_h_tag
+            && !NON_GROUP_ALLOWED_KINDS.contains(&kind)
+            && !self.personal_kinds.contains(&kind)
+            && !self.config.allowed_kinds_without_group.contains(&kind)
         {
-            return Err("invalid: group events must contain an 'h' tag");
+            return Err(Violation::MissingHTag(kind));
+        }
+
+        if h_tags.len() > self.config.max_h_tags {
+            return Err(Violation::TooManyHTags {
+                kind,
+                count: h_tags.len(),
+                max: self.config.max_h_tags,
+            });
+        }
+
+        if let Some(group_id) = h_tags.first() {
+            if self.config.reject_mismatched_d_tag
+                && kind.is_addressable()
+                && event.tags.identifier() != *group_id
+            {
+                return Err(Violation::MismatchedDTag(kind));
+            }
         }
 
         Ok(())
     }
 
+    /// Validates an event in the configured mode, returning the OK reason
+    /// when it's rejected. Violations are logged and counted in every mode.
+    pub fn judge(&self, event: &Event) -> Result<(), String> {
+        let mode = match self.config.mode {
+            ValidationMode::Off => return Ok(()),
+            ValidationMode::LogOnly => "log_only",
+            ValidationMode::Enforce => "enforce",
+        };
+        let Err(violation) = self.validate_event(event) else {
+            return Ok(());
+        };
+
+        metrics::group_validation_violations(violation.check(), mode).increment(1);
+        warn!(
+            "Event {} failed validation ({}): {}",
+            event.id, mode, violation
+        );
+        if self.config.mode == ValidationMode::Enforce {
+            Err(violation.to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The OK reason an event would be rejected with, without logging or
+    /// counting the violation
+    pub fn rejection(&self, event: &Event) -> Option<String> {
+        if self.config.mode != ValidationMode::Enforce {
+            return None;
+        }
+        self.validate_event(event)
+            .err()
+            .map(|violation| violation.to_string())
+    }
+
     // This was too much, may remove it
     #[allow(unused)]
     fn validate_filter(
@@ -97,6 +256,9 @@ impl NostrMiddleware<()> for ValidationMiddleware {
     where
         Next: relay_builder::nostr_middleware::InboundProcessor<()>,
     {
+        if self.config.mode == ValidationMode::Off {
+            return ctx.next().await;
+        }
         let Some(ClientMessage::Event(event)) = &ctx.message else {
             return ctx.next().await;
         };
@@ -106,11 +268,11 @@ impl NostrMiddleware<()> for ValidationMiddleware {
             ctx.connection_id, event.kind, event.id
         );
 
-        if let Err(reason) = self.validate_event(event) {
-            warn!(
-                "[{}] Event {} validation failed: {}",
-                ctx.connection_id, event.id, reason
-            );
+        if let Err(reason) = self.judge(event) {
+            debug!("[{}] Rejecting event {}", ctx.connection_id, event.id);
+            if let Some(rejections) = &self.rejections {
+                rejections.record_event(event, ReasonCode::Invalid, &reason);
+            }
 
             // Send error message
             ctx.send_message(RelayMessage::ok(event.id, false, reason))?;
@@ -123,9 +285,166 @@ impl NostrMiddleware<()> for ValidationMiddleware {
     }
 }
 
-// TODO: Update tests to use the new NostrMiddleware API
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     // Tests temporarily disabled during API migration
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn middleware(mode: ValidationMode) -> ValidationMiddleware {
+        let config = GroupValidationConfig {
+            mode,
+            ..Default::default()
+        };
+        ValidationMiddleware::new(Keys::generate().public_key(), config)
+            .with_personal_kinds([Kind::Metadata])
+    }
+
+    fn event(kind: Kind, tags: Vec<Tag>) -> Event {
+        EventBuilder::new(kind, "")
+            .tags(tags)
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    fn h(group_id: &str) -> Tag {
+        Tag::custom(TagKind::h(), [group_id])
+    }
+
+    #[test]
+    fn test_h_tag_checks() {
+        let validation = middleware(ValidationMode::Enforce);
+
+        let message = event(Kind::Custom(9), vec![]);
+        let violation = validation.validate_event(&message).unwrap_err();
+        assert_eq!(violation, Violation::MissingHTag(Kind::Custom(9)));
+        assert_eq!(
+            violation.to_string(),
+            "invalid: event kind 9 requires an h tag"
+        );
+        assert_eq!(
+            validation.validate_event(&event(Kind::Custom(9), vec![h("a")])),
+            Ok(())
+        );
+
+        // Personal and built-in non-group kinds don't need one
+        assert_eq!(
+            validation.validate_event(&event(Kind::Metadata, vec![])),
+            Ok(())
+        );
+        assert_eq!(
+            validation.validate_event(&event(NON_GROUP_ALLOWED_KINDS[0], vec![])),
+            Ok(())
+        );
+
+        let edit = event(KIND_GROUP_EDIT_METADATA_9002, vec![]);
+        assert_eq!(
+            validation.validate_event(&edit).unwrap_err().to_string(),
+            "invalid: event kind 9002 requires exactly one h tag"
+        );
+        let edit = event(KIND_GROUP_EDIT_METADATA_9002, vec![h("a"), h("b")]);
+        assert_eq!(
+            validation.validate_event(&edit),
+            Err(Violation::NotOneHTag(KIND_GROUP_EDIT_METADATA_9002))
+        );
+
+        let crossposted = event(Kind::Custom(9), vec![h("a"), h("b")]);
+        assert_eq!(
+            validation
+                .validate_event(&crossposted)
+                .unwrap_err()
+                .to_string(),
+            "invalid: event kind 9 has 2 h tags, at most 1 allowed"
+        );
+    }
+
+    #[test]
+    fn test_configured_h_tag_requirements() {
+        let mut validation = middleware(ValidationMode::Enforce);
+        validation.config.allowed_kinds_without_group = vec![Kind::TextNote];
+        validation.config.max_h_tags = 2;
+
+        assert_eq!(
+            validation.validate_event(&event(Kind::TextNote, vec![])),
+            Ok(())
+        );
+        assert_eq!(
+            validation.validate_event(&event(Kind::Custom(9), vec![h("a"), h("b")])),
+            Ok(())
+        );
+
+        validation.config.require_h_tag = false;
+        assert_eq!(
+            validation.validate_event(&event(Kind::Custom(9), vec![])),
+            Ok(())
+        );
+        // Group commands always need their group
+        assert_eq!(
+            validation.validate_event(&event(KIND_GROUP_CREATE_9007, vec![])),
+            Err(Violation::NotOneHTag(KIND_GROUP_CREATE_9007))
+        );
+    }
+
+    #[test]
+    fn test_d_tag_must_match_h_tag() {
+        let mut validation = middleware(ValidationMode::Enforce);
+        let emoji_set = |d: &str| event(Kind::Custom(30030), vec![h("group"), Tag::identifier(d)]);
+
+        assert_eq!(validation.validate_event(&emoji_set("group")), Ok(()));
+        assert_eq!(
+            validation
+                .validate_event(&emoji_set("other"))
+                .unwrap_err()
+                .to_string(),
+            "invalid: event kind 30030 requires a d tag matching its h tag"
+        );
+        assert_eq!(
+            validation.validate_event(&event(Kind::Custom(30030), vec![h("group")])),
+            Err(Violation::MismatchedDTag(Kind::Custom(30030)))
+        );
+
+        validation.config.reject_mismatched_d_tag = false;
+        assert_eq!(validation.validate_event(&emoji_set("other")), Ok(()));
+    }
+
+    #[test]
+    fn test_relay_state_events_skip_validation() {
+        let relay_keys = Keys::generate();
+        let validation = ValidationMiddleware::new(
+            relay_keys.public_key(),
+            GroupValidationConfig {
+                mode: ValidationMode::Enforce,
+                ..Default::default()
+            },
+        );
+        let members = EventBuilder::new(ADDRESSABLE_EVENT_KINDS[2], "")
+            .tag(Tag::identifier("group"))
+            .sign_with_keys(&relay_keys)
+            .unwrap();
+        assert_eq!(validation.validate_event(&members), Ok(()));
+    }
+
+    #[test]
+    fn test_only_enforce_mode_rejects() {
+        let violations = [
+            event(Kind::Custom(9), vec![]),
+            event(KIND_GROUP_EDIT_METADATA_9002, vec![h("a"), h("b")]),
+            event(Kind::Custom(9), vec![h("a"), h("b")]),
+            event(Kind::Custom(30030), vec![h("a"), Tag::identifier("b")]),
+        ];
+
+        for mode in [ValidationMode::Off, ValidationMode::LogOnly] {
+            let validation = middleware(mode);
+            for event in &violations {
+                assert!(validation.validate_event(event).is_err());
+                assert_eq!(validation.judge(event), Ok(()));
+            }
+        }
+
+        let validation = middleware(ValidationMode::Enforce);
+        for event in &violations {
+            assert_eq!(
+                validation.judge(event),
+                Err(validation.validate_event(event).unwrap_err().to_string())
+            );
+        }
+    }
+}
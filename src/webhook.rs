@@ -0,0 +1,251 @@
+//! HTTP JSON webhook for group lifecycle changes.
+//!
+//! Every change is posted as a JSON payload signed with HMAC-SHA256 over the
+//! raw body, sent in the [`SIGNATURE_HEADER`] header as `sha256=<hex>`.
+//! Deliveries are queued and sent from a background task with retries, so
+//! event processing never waits on the receiving server.
+
+use crate::config::WebhookSettings;
+use crate::group::GroupMetadata;
+use crate::group_hooks::GroupEventHook;
+use crate::http_client::HttpClient;
+use crate::metrics;
+use crate::utils::scope_name;
+use anyhow::Result;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+pub const SIGNATURE_HEADER: &str = "x-groups-relay-signature";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub event: &'static str,
+    pub scope: String,
+    pub group_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<GroupMetadata>,
+    pub timestamp: u64,
+}
+
+impl WebhookPayload {
+    fn new(event: &'static str, scope: &Scope, group_id: &str) -> Self {
+        Self {
+            event,
+            scope: scope_name(scope).to_string(),
+            group_id: group_id.to_string(),
+            pubkey: None,
+            metadata: None,
+            timestamp: Timestamp::now().as_u64(),
+        }
+    }
+
+    fn with_pubkey(mut self, pubkey: &PublicKey) -> Self {
+        self.pubkey = Some(pubkey.to_hex());
+        self
+    }
+}
+
+/// Computes the signature header value for a payload body.
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Debug)]
+pub struct HttpWebhook {
+    queue: mpsc::Sender<WebhookPayload>,
+}
+
+impl HttpWebhook {
+    /// Starts the delivery task and returns the hook to register.
+    pub fn start(
+        settings: &WebhookSettings,
+        cancellation_token: CancellationToken,
+    ) -> Result<Arc<Self>> {
+        let client = HttpClient::new()?;
+        let (queue, receiver) = mpsc::channel(settings.queue_capacity.max(1));
+
+        tokio::spawn(deliver(
+            client,
+            settings.clone(),
+            receiver,
+            cancellation_token,
+        ));
+
+        Ok(Arc::new(Self { queue }))
+    }
+
+    fn enqueue(&self, payload: WebhookPayload) {
+        if let Err(e) = self.queue.try_send(payload) {
+            warn!("Webhook queue full, dropping payload: {}", e);
+            metrics::webhook_deliveries("dropped").increment(1);
+        }
+    }
+}
+
+#[async_trait]
+impl GroupEventHook for HttpWebhook {
+    async fn on_group_created(&self, scope: &Scope, group_id: &str, creator: &PublicKey) {
+        self.enqueue(WebhookPayload::new("group_created", scope, group_id).with_pubkey(creator));
+    }
+
+    async fn on_group_deleted(&self, scope: &Scope, group_id: &str) {
+        self.enqueue(WebhookPayload::new("group_deleted", scope, group_id));
+    }
+
+    async fn on_member_added(&self, scope: &Scope, group_id: &str, member: &PublicKey) {
+        self.enqueue(WebhookPayload::new("member_added", scope, group_id).with_pubkey(member));
+    }
+
+    async fn on_member_removed(&self, scope: &Scope, group_id: &str, member: &PublicKey) {
+        self.enqueue(WebhookPayload::new("member_removed", scope, group_id).with_pubkey(member));
+    }
+
+    async fn on_metadata_changed(&self, scope: &Scope, group_id: &str, metadata: &GroupMetadata) {
+        let mut payload = WebhookPayload::new("metadata_changed", scope, group_id);
+        payload.metadata = Some(metadata.clone());
+        self.enqueue(payload);
+    }
+}
+
+async fn deliver(
+    client: HttpClient,
+    settings: WebhookSettings,
+    mut receiver: mpsc::Receiver<WebhookPayload>,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        let payload = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            payload = receiver.recv() => match payload {
+                Some(payload) => payload,
+                None => break,
+            },
+        };
+
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to serialize webhook payload: {}", e);
+                continue;
+            }
+        };
+        let headers = [(SIGNATURE_HEADER, sign_payload(&settings.secret, &body))];
+
+        let mut backoff = settings.retry_backoff;
+        let mut delivered = false;
+        for attempt in 0..=settings.max_retries {
+            match client
+                .post_json(&settings.url, body.clone(), &headers)
+                .await
+            {
+                Ok(status) if status.is_success() => {
+                    delivered = true;
+                    break;
+                }
+                Ok(status) => debug!("Webhook attempt {} got status {}", attempt, status),
+                Err(e) => debug!("Webhook attempt {} failed: {}", attempt, e),
+            }
+
+            if attempt < settings.max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(Duration::from_secs(60));
+            }
+        }
+
+        if delivered {
+            metrics::webhook_deliveries("delivered").increment(1);
+        } else {
+            warn!(
+                "Giving up delivering {} webhook for group {}",
+                payload.event, payload.group_id
+            );
+            metrics::webhook_deliveries("failed").increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, routing::post, Router};
+    use tokio::sync::Mutex;
+
+    type Captured = Arc<Mutex<Vec<(Option<String>, Bytes)>>>;
+
+    async fn capture(State(captured): State<Captured>, headers: HeaderMap, body: Bytes) {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        captured.lock().await.push((signature, body));
+    }
+
+    async fn start_test_server() -> (String, Captured) {
+        let captured: Captured = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/hook", post(capture))
+            .with_state(captured.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (format!("http://{addr}/hook"), captured)
+    }
+
+    #[tokio::test]
+    async fn test_webhook_delivers_signed_payload() {
+        let (url, captured) = start_test_server().await;
+        let settings = WebhookSettings {
+            url,
+            secret: "top-secret".to_string(),
+            max_retries: 2,
+            queue_capacity: 10,
+            retry_backoff: Duration::from_millis(10),
+        };
+        let webhook = HttpWebhook::start(&settings, CancellationToken::new()).unwrap();
+        let member = Keys::generate().public_key();
+
+        webhook
+            .on_member_added(&Scope::Default, "test_group", &member)
+            .await;
+
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(entry) = captured.lock().await.first().cloned() {
+                    return entry;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("webhook was not delivered");
+
+        assert_eq!(signature, Some(sign_payload("top-secret", &body)));
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["event"], "member_added");
+        assert_eq!(payload["group_id"], "test_group");
+        assert_eq!(payload["scope"], "default");
+        assert_eq!(payload["pubkey"], member.to_hex());
+    }
+
+    #[test]
+    fn test_signature_depends_on_secret_and_body() {
+        let signature = sign_payload("secret", b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_ne!(signature, sign_payload("other", b"{}"));
+        assert_ne!(signature, sign_payload("secret", b"{ }"));
+    }
+}
@@ -1,48 +1,90 @@
 //! Integration test to verify groups_relay works with relay_builder
 
 use groups_relay::{
-    config::Keys, groups::Groups, groups_event_processor::GroupsRelayProcessor, RelayDatabase,
+    groups::{
+        Groups, KIND_GROUP_CREATE_9007, KIND_GROUP_MEMBERS_39002, KIND_GROUP_USER_JOIN_REQUEST_9021,
+    },
+    groups_event_processor::GroupsRelayProcessor,
+    test_utils::{group_event, setup_test, TestRelay},
 };
-use relay_builder::{RelayBuilder, RelayConfig};
+use nostr_sdk::prelude::*;
 use std::sync::Arc;
-use tempfile::TempDir;
-
-#[tokio::test]
-async fn test_groups_relay_with_relay_builder() -> anyhow::Result<()> {
-    // Create temporary directory for database
-    let temp_dir = TempDir::new()?;
-    let db_path = temp_dir.path().join("test.db");
-
-    let keys = Keys::generate();
-
-    // groups_relay's database is used for groups management
-    let groups_database = RelayDatabase::new(db_path.to_string_lossy().to_string()).await?;
-    let groups_database = Arc::new(groups_database);
 
+async fn start_relay() -> anyhow::Result<(tempfile::TempDir, TestRelay)> {
+    let (tmp_dir, database, keys) = setup_test().await;
     let groups = Arc::new(
         Groups::load_groups(
-            groups_database.clone(),
+            database.clone(),
             keys.public_key(),
-            "wss://test.relay.com".to_string(),
+            "ws://127.0.0.1".to_string(),
         )
         .await?,
     );
+    let processor = GroupsRelayProcessor::new(groups, keys.public_key());
+    let relay = TestRelay::start(processor, database, keys).await?;
+    Ok((tmp_dir, relay))
+}
 
-    let relay_config = RelayConfig::new(
-        "wss://test.groups.relay",
-        groups_database.clone(),
-        keys.clone(),
-    )
-    .with_subdomains(2);
+#[tokio::test]
+async fn test_groups_relay_with_relay_builder() -> anyhow::Result<()> {
+    let (_tmp_dir, relay) = start_relay().await?;
+    let admin = Keys::generate();
+    let mut client = relay.connect().await?;
+    client.auth_as(&admin).await?;
 
-    let groups_processor = GroupsRelayProcessor::new(groups, keys.public_key());
+    client
+        .publish(&group_event(&admin, KIND_GROUP_CREATE_9007, "wired", ""))
+        .await?;
+    let message = group_event(&admin, Kind::Custom(9), "wired", "over the wire");
+    client.publish(&message).await?;
 
-    let _handler = RelayBuilder::<(), GroupsRelayProcessor>::new(relay_config)
-        .event_processor(groups_processor)
-        .build()
+    let (events, eose) = client
+        .req_and_collect(vec![Filter::new()
+            .kind(Kind::Custom(9))
+            .custom_tag(SingleLetterTag::lowercase(Alphabet::H), "wired")])
         .await?;
+    assert!(eose);
+    assert_eq!(events, vec![message]);
 
-    println!("✅ Successfully created groups relay using relay_builder!");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_request_over_websocket() -> anyhow::Result<()> {
+    let (_tmp_dir, relay) = start_relay().await?;
+    let admin = Keys::generate();
+    let user = Keys::generate();
+
+    let mut admin_client = relay.connect().await?;
+    admin_client.auth_as(&admin).await?;
+    let create = EventBuilder::new(KIND_GROUP_CREATE_9007, "")
+        .tags([
+            Tag::custom(TagKind::h(), ["lobby"]),
+            Tag::custom(TagKind::custom("public"), [""]),
+            Tag::custom(TagKind::custom("open"), [""]),
+        ])
+        .sign_with_keys(&admin)?;
+    admin_client.publish(&create).await?;
+
+    let mut user_client = relay.connect().await?;
+    user_client.auth_as(&user).await?;
+    let join = group_event(&user, KIND_GROUP_USER_JOIN_REQUEST_9021, "lobby", "");
+    user_client.send_event(&join).await?;
+    let (accepted, message) = user_client.expect_ok(join.id).await?;
+    assert!(accepted, "join rejected: {message}");
+
+    let (members, eose) = user_client
+        .req_and_collect(vec![Filter::new()
+            .kind(KIND_GROUP_MEMBERS_39002)
+            .identifier("lobby")])
+        .await?;
+    assert!(eose);
+    assert_eq!(members.len(), 1);
+    assert!(members[0]
+        .tags
+        .public_keys()
+        .any(|pk| *pk == user.public_key()));
+    assert_eq!(members[0].pubkey, relay.keys.public_key());
 
     Ok(())
 }